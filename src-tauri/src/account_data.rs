@@ -0,0 +1,179 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+
+use crate::ignore_list;
+use crate::matrix_api::{encode, now_millis, ApiError, MatrixClient};
+
+pub const DIRECT_TYPE: &str = "m.direct";
+pub const TAG_TYPE: &str = "m.tag";
+pub const IGNORED_USERS_TYPE: &str = "m.ignored_user_list";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountDataEntry {
+  pub account_key: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub room_id: Option<String>,
+  #[serde(rename = "type")]
+  pub data_type: String,
+  pub content: Value,
+  pub updated_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomTag {
+  pub tag: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub order: Option<f64>,
+}
+
+pub fn init_account_data_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS account_data (
+        account_key TEXT NOT NULL,
+        room_id TEXT NOT NULL DEFAULT '',
+        data_type TEXT NOT NULL,
+        content_json TEXT NOT NULL,
+        updated_at INTEGER NOT NULL,
+        PRIMARY KEY (account_key, room_id, data_type)
+      );
+    ",
+  )
+}
+
+fn entry_path(client: &MatrixClient, data_type: &str, room_id: Option<&str>) -> String {
+  match room_id {
+    Some(room_id) => format!(
+      "/user/{}/rooms/{}/account_data/{}",
+      encode(&client.user_id),
+      encode(room_id),
+      encode(data_type)
+    ),
+    None => format!("/user/{}/account_data/{}", encode(&client.user_id), encode(data_type)),
+  }
+}
+
+pub async fn fetch(client: &MatrixClient, data_type: &str, room_id: Option<&str>) -> Result<Option<Value>, ApiError> {
+  match client.get(&entry_path(client, data_type, room_id), &[]).await {
+    Ok(content) => Ok(Some(content)),
+    Err(err) if err.errcode() == Some("M_NOT_FOUND") => Ok(None),
+    Err(err) => Err(err),
+  }
+}
+
+pub async fn push(client: &MatrixClient, data_type: &str, room_id: Option<&str>, content: &Value) -> Result<(), ApiError> {
+  client.put(&entry_path(client, data_type, room_id), content).await?;
+  Ok(())
+}
+
+pub fn get_cached(conn: &Connection, account_key: &str, data_type: &str, room_id: Option<&str>) -> Result<Option<AccountDataEntry>, String> {
+  conn
+    .query_row(
+      "SELECT content_json, updated_at FROM account_data WHERE account_key = ?1 AND room_id = ?2 AND data_type = ?3",
+      params![account_key, room_id.unwrap_or_default(), data_type],
+      |row| {
+        let content_json: String = row.get(0)?;
+        Ok(AccountDataEntry {
+          account_key: account_key.to_string(),
+          room_id: room_id.map(|r| r.to_string()),
+          data_type: data_type.to_string(),
+          content: serde_json::from_str(&content_json).unwrap_or(Value::Null),
+          updated_at: row.get(1)?,
+        })
+      },
+    )
+    .optional()
+    .map_err(|e| e.to_string())
+}
+
+/// Store one entry; returns it when the content actually changed. Derived local state
+/// (the ignore list mirror) is refreshed alongside.
+pub fn store(
+  conn: &Connection,
+  account_key: &str,
+  data_type: &str,
+  room_id: Option<&str>,
+  content: &Value,
+) -> Result<Option<AccountDataEntry>, String> {
+  if let Some(existing) = get_cached(conn, account_key, data_type, room_id)? {
+    if &existing.content == content {
+      return Ok(None);
+    }
+  }
+  let entry = AccountDataEntry {
+    account_key: account_key.to_string(),
+    room_id: room_id.map(|r| r.to_string()),
+    data_type: data_type.to_string(),
+    content: content.clone(),
+    updated_at: now_millis(),
+  };
+  let content_json = serde_json::to_string(content).map_err(|e| e.to_string())?;
+  conn
+    .execute(
+      "INSERT INTO account_data (account_key, room_id, data_type, content_json, updated_at) VALUES (?1, ?2, ?3, ?4, ?5)
+       ON CONFLICT(account_key, room_id, data_type) DO UPDATE SET
+         content_json = excluded.content_json,
+         updated_at = excluded.updated_at",
+      params![account_key, room_id.unwrap_or_default(), data_type, content_json, entry.updated_at],
+    )
+    .map_err(|e| e.to_string())?;
+  if data_type == IGNORED_USERS_TYPE && room_id.is_none() {
+    let ignored: Vec<String> = content
+      .get("ignored_users")
+      .and_then(|v| v.as_object())
+      .map(|users| users.keys().cloned().collect())
+      .unwrap_or_default();
+    ignore_list::replace_local_list(conn, account_key, &ignored)?;
+  }
+  Ok(Some(entry))
+}
+
+/// Ingest `account_data.events` from sync (global when `room_id` is None, otherwise room-level).
+pub fn record_sync_events(
+  conn: &Connection,
+  account_key: &str,
+  room_id: Option<&str>,
+  events: &[Value],
+) -> Result<Vec<AccountDataEntry>, String> {
+  let mut changed = Vec::new();
+  for event in events {
+    let data_type = match event.get("type").and_then(|v| v.as_str()) {
+      Some(data_type) => data_type,
+      None => continue,
+    };
+    let content = event.get("content").cloned().unwrap_or(Value::Null);
+    if let Some(entry) = store(conn, account_key, data_type, room_id, &content)? {
+      changed.push(entry);
+    }
+  }
+  Ok(changed)
+}
+
+/// `m.direct` as user ID -> DM room IDs.
+pub fn direct_rooms(conn: &Connection, account_key: &str) -> Result<HashMap<String, Vec<String>>, String> {
+  let entry = get_cached(conn, account_key, DIRECT_TYPE, None)?;
+  Ok(entry
+    .and_then(|e| serde_json::from_value::<HashMap<String, Vec<String>>>(e.content).ok())
+    .unwrap_or_default())
+}
+
+pub fn room_tags(conn: &Connection, account_key: &str, room_id: &str) -> Result<Vec<RoomTag>, String> {
+  let entry = get_cached(conn, account_key, TAG_TYPE, Some(room_id))?;
+  let mut tags: Vec<RoomTag> = entry
+    .and_then(|e| e.content.get("tags").and_then(|v| v.as_object()).cloned())
+    .map(|tags| {
+      tags
+        .into_iter()
+        .map(|(tag, data)| RoomTag {
+          tag,
+          order: data.get("order").and_then(|v| v.as_f64()),
+        })
+        .collect()
+    })
+    .unwrap_or_default();
+  tags.sort_by(|a, b| a.tag.cmp(&b.tag));
+  Ok(tags)
+}
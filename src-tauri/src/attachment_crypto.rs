@@ -0,0 +1,89 @@
+use aes::Aes256;
+use base64::{engine::general_purpose, Engine as _};
+use ctr::cipher::{KeyIvInit, StreamCipher};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+type Aes256Ctr = ctr::Ctr64BE<Aes256>;
+
+/// The `key` of an encrypted attachment, as a JSON Web Key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JsonWebKey {
+  pub kty: String,
+  pub key_ops: Vec<String>,
+  pub alg: String,
+  pub k: String,
+  pub ext: bool,
+}
+
+/// The `file` object of an encrypted attachment (`EncryptedFile` in the spec).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptedFile {
+  pub url: String,
+  pub key: JsonWebKey,
+  pub iv: String,
+  pub hashes: HashMap<String, String>,
+  pub v: String,
+}
+
+fn decode_b64(value: &str) -> Result<Vec<u8>, String> {
+  let trimmed = value.trim_end_matches('=');
+  general_purpose::STANDARD_NO_PAD
+    .decode(trimmed)
+    .or_else(|_| general_purpose::URL_SAFE_NO_PAD.decode(trimmed))
+    .map_err(|e| format!("Invalid base64 in encrypted file info: {}", e))
+}
+
+/// Encrypt with AES-256-CTR under a fresh key and IV; the returned file info still needs
+/// its `url` set once the ciphertext has been uploaded.
+pub fn encrypt(plaintext: &[u8]) -> (Vec<u8>, EncryptedFile) {
+  let mut key = [0u8; 32];
+  OsRng.fill_bytes(&mut key);
+  // The counter occupies the low 64 bits and starts at zero, as other clients expect.
+  let mut iv = [0u8; 16];
+  OsRng.fill_bytes(&mut iv[..8]);
+
+  let mut ciphertext = plaintext.to_vec();
+  Aes256Ctr::new(&key.into(), &iv.into()).apply_keystream(&mut ciphertext);
+  let hash = Sha256::digest(&ciphertext);
+
+  let file = EncryptedFile {
+    url: String::new(),
+    key: JsonWebKey {
+      kty: "oct".to_string(),
+      key_ops: vec!["encrypt".to_string(), "decrypt".to_string()],
+      alg: "A256CTR".to_string(),
+      k: general_purpose::URL_SAFE_NO_PAD.encode(key),
+      ext: true,
+    },
+    iv: general_purpose::STANDARD_NO_PAD.encode(iv),
+    hashes: HashMap::from([("sha256".to_string(), general_purpose::STANDARD_NO_PAD.encode(hash))]),
+    v: "v2".to_string(),
+  };
+  (ciphertext, file)
+}
+
+/// Check the ciphertext hash, then decrypt.
+pub fn decrypt(ciphertext: &[u8], file: &EncryptedFile) -> Result<Vec<u8>, String> {
+  if file.key.alg != "A256CTR" || file.key.kty != "oct" {
+    return Err(format!("Unsupported attachment key algorithm: {}", file.key.alg));
+  }
+  let expected = file
+    .hashes
+    .get("sha256")
+    .ok_or("Encrypted file info has no SHA-256 hash")?;
+  if decode_b64(expected)? != Sha256::digest(ciphertext).as_slice() {
+    return Err("Encrypted attachment failed its integrity check".to_string());
+  }
+  let key: [u8; 32] = decode_b64(&file.key.k)?
+    .try_into()
+    .map_err(|_| "Attachment key has the wrong length".to_string())?;
+  let iv: [u8; 16] = decode_b64(&file.iv)?
+    .try_into()
+    .map_err(|_| "Attachment IV has the wrong length".to_string())?;
+  let mut plaintext = ciphertext.to_vec();
+  Aes256Ctr::new(&key.into(), &iv.into()).apply_keystream(&mut plaintext);
+  Ok(plaintext)
+}
@@ -0,0 +1,100 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use crate::matrix_api::now_millis;
+use crate::video::find_tool;
+use crate::voice;
+
+const FALLBACK_SAMPLE_RATE: u32 = 8_000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AudioWaveform {
+  /// MSC3246 waveform: amplitudes scaled to 0..=1024.
+  pub waveform: Vec<u16>,
+  pub duration_ms: u64,
+}
+
+pub fn init_audio_waveform_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS audio_waveforms (
+        mxc_url TEXT PRIMARY KEY,
+        waveform_json TEXT NOT NULL,
+        duration_ms INTEGER NOT NULL,
+        created_at INTEGER NOT NULL
+      );
+    ",
+  )
+}
+
+pub fn cached(conn: &Connection, mxc_url: &str) -> Result<Option<AudioWaveform>, String> {
+  let stored: Option<(String, i64)> = conn
+    .query_row(
+      "SELECT waveform_json, duration_ms FROM audio_waveforms WHERE mxc_url = ?1",
+      [mxc_url],
+      |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+    .optional()
+    .map_err(|e| e.to_string())?;
+  Ok(stored.and_then(|(json, duration_ms)| {
+    serde_json::from_str(&json).ok().map(|waveform| AudioWaveform {
+      waveform,
+      duration_ms: duration_ms as u64,
+    })
+  }))
+}
+
+pub fn store(conn: &Connection, mxc_url: &str, waveform: &AudioWaveform) -> Result<(), String> {
+  let json = serde_json::to_string(&waveform.waveform).map_err(|e| e.to_string())?;
+  conn
+    .execute(
+      "INSERT INTO audio_waveforms (mxc_url, waveform_json, duration_ms, created_at) VALUES (?1, ?2, ?3, ?4)
+       ON CONFLICT(mxc_url) DO UPDATE SET waveform_json = excluded.waveform_json, duration_ms = excluded.duration_ms",
+      params![mxc_url, json, waveform.duration_ms as i64, now_millis()],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+/// Decode other formats (MP3, AAC, ...) to mono samples at `rate` with ffmpeg.
+pub fn decode_with_ffmpeg(bytes: &[u8], work_dir: &Path, rate: u32) -> Result<(Vec<f32>, u32), String> {
+  let ffmpeg = find_tool("ffmpeg").ok_or("Only Ogg/Opus audio can be decoded without ffmpeg")?;
+  fs::create_dir_all(work_dir).map_err(|e| e.to_string())?;
+  let input = work_dir.join(format!("waveform-{}", now_millis()));
+  fs::write(&input, bytes).map_err(|e| e.to_string())?;
+  let output = Command::new(ffmpeg)
+    .args(["-v", "error", "-i"])
+    .arg(&input)
+    .args(["-vn", "-ac", "1", "-ar", &rate.to_string(), "-f", "f32le", "-"])
+    .output();
+  let _ = fs::remove_file(&input);
+  let output = output.map_err(|e| e.to_string())?;
+  if !output.status.success() {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    return Err(stderr.lines().last().unwrap_or("ffmpeg failed").to_string());
+  }
+  let samples = output
+    .stdout
+    .chunks_exact(4)
+    .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    .collect();
+  Ok((samples, rate))
+}
+
+/// Normalised peaks and duration of an audio file.
+pub fn generate(bytes: &[u8], work_dir: &Path) -> Result<AudioWaveform, String> {
+  let (waveform, duration_ms) = match voice::decode_ogg_opus(bytes) {
+    Ok(samples) => (voice::waveform(&samples), voice::duration_ms(&samples)),
+    Err(_) => {
+      let (samples, rate) = decode_with_ffmpeg(bytes, work_dir, FALLBACK_SAMPLE_RATE)?;
+      (voice::waveform(&samples), samples.len() as u64 * 1000 / rate as u64)
+    }
+  };
+  if waveform.is_empty() {
+    return Err("The audio file is empty".to_string());
+  }
+  Ok(AudioWaveform { waveform, duration_ms })
+}
@@ -0,0 +1,265 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::attachment_crypto::EncryptedFile;
+use crate::event_cache;
+
+const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AutoDownloadMode {
+  Always,
+  /// Only while the connection is not metered.
+  WifiOnly,
+  Never,
+}
+
+/// When media from new events is pre-fetched into the cache, stored globally and optionally
+/// overridden per room.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AutoDownloadPolicy {
+  pub mode: AutoDownloadMode,
+  /// Skip files larger than this (or of unknown size); thumbnails are always small enough.
+  #[serde(default)]
+  pub max_size: Option<u64>,
+}
+
+impl Default for AutoDownloadPolicy {
+  fn default() -> Self {
+    AutoDownloadPolicy {
+      mode: AutoDownloadMode::WifiOnly,
+      max_size: Some(DEFAULT_MAX_SIZE),
+    }
+  }
+}
+
+/// A media reference taken from a timeline event.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MediaCandidate {
+  pub event_id: String,
+  pub mxc_url: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub file: Option<EncryptedFile>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub size: Option<u64>,
+  pub thumbnail: bool,
+}
+
+/// Whether the current connection is metered, as reported by the webview.
+#[derive(Default)]
+pub struct NetworkState {
+  metered: AtomicBool,
+}
+
+impl NetworkState {
+  pub fn set_metered(&self, metered: bool) {
+    self.metered.store(metered, Ordering::Relaxed);
+  }
+
+  pub fn is_metered(&self) -> bool {
+    self.metered.load(Ordering::Relaxed)
+  }
+}
+
+pub fn init_auto_download_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS auto_download_policy (
+        scope TEXT PRIMARY KEY,
+        policy_json TEXT NOT NULL
+      );
+    ",
+  )
+}
+
+fn load_scope(conn: &Connection, scope: &str) -> Result<Option<AutoDownloadPolicy>, String> {
+  let stored: Option<String> = conn
+    .query_row("SELECT policy_json FROM auto_download_policy WHERE scope = ?1", [scope], |row| row.get(0))
+    .optional()
+    .map_err(|e| e.to_string())?;
+  Ok(stored.and_then(|json| serde_json::from_str(&json).ok()))
+}
+
+/// Room policy when present, otherwise the global one (scope `''`).
+pub fn policy(conn: &Connection, room_id: Option<&str>) -> Result<AutoDownloadPolicy, String> {
+  if let Some(room_id) = room_id {
+    if let Some(room) = load_scope(conn, room_id)? {
+      return Ok(room);
+    }
+  }
+  Ok(load_scope(conn, "")?.unwrap_or_default())
+}
+
+/// Store a policy for a room, or globally when `room_id` is None; `None` clears a room override.
+pub fn set_policy(conn: &Connection, room_id: Option<&str>, value: Option<&AutoDownloadPolicy>) -> Result<(), String> {
+  let scope = room_id.unwrap_or_default();
+  match value {
+    Some(value) => {
+      let json = serde_json::to_string(value).map_err(|e| e.to_string())?;
+      conn
+        .execute(
+          "INSERT INTO auto_download_policy (scope, policy_json) VALUES (?1, ?2)
+           ON CONFLICT(scope) DO UPDATE SET policy_json = excluded.policy_json",
+          params![scope, json],
+        )
+        .map_err(|e| e.to_string())?;
+    }
+    None => {
+      conn
+        .execute("DELETE FROM auto_download_policy WHERE scope = ?1", [scope])
+        .map_err(|e| e.to_string())?;
+    }
+  }
+  Ok(())
+}
+
+fn media_ref(content: &Value, url_key: &str, file_key: &str) -> Option<(String, Option<EncryptedFile>)> {
+  if let Some(file) = content.get(file_key).and_then(|f| serde_json::from_value::<EncryptedFile>(f.clone()).ok()) {
+    return Some((file.url.clone(), Some(file)));
+  }
+  content
+    .get(url_key)
+    .and_then(|v| v.as_str())
+    .filter(|url| url.starts_with("mxc://"))
+    .map(|url| (url.to_string(), None))
+}
+
+/// Media (and thumbnails) referenced by image, video, audio, file and sticker events.
+pub fn media_candidates(events: &[Value]) -> Vec<MediaCandidate> {
+  let mut candidates = Vec::new();
+  for event in events {
+    let event_type = event.get("type").and_then(|v| v.as_str()).unwrap_or_default();
+    if event_type != "m.room.message" && event_type != "m.sticker" {
+      continue;
+    }
+    let Some(event_id) = event.get("event_id").and_then(|v| v.as_str()) else {
+      continue;
+    };
+    let Some(content) = event.get("content") else {
+      continue;
+    };
+    let msgtype = content.get("msgtype").and_then(|v| v.as_str()).unwrap_or_default();
+    if event_type == "m.room.message" && !matches!(msgtype, "m.image" | "m.video" | "m.audio" | "m.file") {
+      continue;
+    }
+    let info = content.get("info");
+    if let Some((mxc_url, file)) = info.and_then(|info| media_ref(info, "thumbnail_url", "thumbnail_file")) {
+      candidates.push(MediaCandidate {
+        event_id: event_id.to_string(),
+        mxc_url,
+        file,
+        size: info.and_then(|i| i.pointer("/thumbnail_info/size")).and_then(|v| v.as_u64()),
+        thumbnail: true,
+      });
+    }
+    if let Some((mxc_url, file)) = media_ref(content, "url", "file") {
+      candidates.push(MediaCandidate {
+        event_id: event_id.to_string(),
+        mxc_url,
+        file,
+        size: info.and_then(|i| i.get("size")).and_then(|v| v.as_u64()),
+        thumbnail: false,
+      });
+    }
+  }
+  candidates
+}
+
+/// The candidates the policy allows fetching right now.
+pub fn select(policy: &AutoDownloadPolicy, metered: bool, candidates: Vec<MediaCandidate>) -> Vec<MediaCandidate> {
+  match policy.mode {
+    AutoDownloadMode::Never => return Vec::new(),
+    AutoDownloadMode::WifiOnly if metered => return Vec::new(),
+    _ => {}
+  }
+  candidates
+    .into_iter()
+    .filter(|candidate| {
+      candidate.thumbnail
+        || match (policy.max_size, candidate.size) {
+          (None, _) => true,
+          (Some(max), Some(size)) => size <= max,
+          (Some(_), None) => false,
+        }
+    })
+    .collect()
+}
+
+/// Thumbnails, then image originals, of the `count` media items either side of `media_id`
+/// in an account's gallery of a room, nearest first. Encryption info comes from the cached
+/// event when present.
+pub fn adjacent_candidates(
+  conn: &Connection,
+  account_key: &str,
+  room_id: &str,
+  media_id: &str,
+  count: usize,
+) -> Result<Vec<MediaCandidate>, String> {
+  let mut stmt = conn
+    .prepare(
+      "SELECT id, event_id, media_type, mxc_url, thumbnail_mxc, size FROM media_index
+       WHERE account_key = ?1 AND room_id = ?2 AND mxc_url IS NOT NULL ORDER BY timestamp ASC",
+    )
+    .map_err(|e| e.to_string())?;
+  let items: Vec<(String, String, String, String, Option<String>, Option<u64>)> = stmt
+    .query_map([account_key, room_id], |row| {
+      Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?))
+    })
+    .map_err(|e| e.to_string())?
+    .flatten()
+    .collect();
+  let Some(position) = items.iter().position(|item| item.0 == media_id) else {
+    return Ok(Vec::new());
+  };
+  let mut nearest = Vec::new();
+  for distance in 1..=count {
+    if let Some(next) = items.get(position + distance) {
+      nearest.push(next);
+    }
+    if let Some(previous) = position.checked_sub(distance).and_then(|i| items.get(i)) {
+      nearest.push(previous);
+    }
+  }
+
+  let mut thumbnails = Vec::new();
+  let mut originals = Vec::new();
+  for (_, event_id, media_type, mxc_url, thumbnail_mxc, size) in nearest {
+    let cached = event_cache::get_event(conn, account_key, room_id, event_id)?;
+    let candidates = match cached {
+      Some(event) => media_candidates(&[event.to_raw()]),
+      None => {
+        let mut candidates = Vec::new();
+        if let Some(thumbnail_mxc) = thumbnail_mxc {
+          candidates.push(MediaCandidate {
+            event_id: event_id.clone(),
+            mxc_url: thumbnail_mxc.clone(),
+            file: None,
+            size: None,
+            thumbnail: true,
+          });
+        }
+        candidates.push(MediaCandidate {
+          event_id: event_id.clone(),
+          mxc_url: mxc_url.clone(),
+          file: None,
+          size: *size,
+          thumbnail: false,
+        });
+        candidates
+      }
+    };
+    for candidate in candidates {
+      if candidate.thumbnail {
+        thumbnails.push(candidate);
+      } else if media_type == "image" {
+        originals.push(candidate);
+      }
+    }
+  }
+  thumbnails.extend(originals);
+  Ok(thumbnails)
+}
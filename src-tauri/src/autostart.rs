@@ -0,0 +1,217 @@
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Passed by the login entry so the app starts hidden in the tray.
+pub const MINIMIZED_ARG: &str = "--minimized";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AutostartStatus {
+  pub enabled: bool,
+  pub start_minimized: bool,
+}
+
+pub fn launched_minimized() -> bool {
+  std::env::args().skip(1).any(|arg| arg == MINIMIZED_ARG)
+}
+
+fn arguments(exe: &Path, minimized: bool) -> Vec<String> {
+  let mut args = vec![exe.display().to_string()];
+  if minimized {
+    args.push(MINIMIZED_ARG.to_string());
+  }
+  args
+}
+
+/// Register (or with `enabled` false, remove) the login entry for `exe`.
+pub fn set(exe: &Path, enabled: bool, start_minimized: bool) -> Result<AutostartStatus, String> {
+  let args = arguments(exe, start_minimized);
+  platform::set(enabled.then_some(args.as_slice()))?;
+  status()
+}
+
+pub fn status() -> Result<AutostartStatus, String> {
+  Ok(match platform::registered()? {
+    Some(command) => AutostartStatus {
+      enabled: true,
+      start_minimized: command.contains(MINIMIZED_ARG),
+    },
+    None => AutostartStatus {
+      enabled: false,
+      start_minimized: false,
+    },
+  })
+}
+
+/// XDG autostart desktop entry.
+#[cfg(all(unix, not(target_os = "macos")))]
+mod platform {
+  use std::fs;
+  use std::path::PathBuf;
+
+  fn entry_path() -> Result<PathBuf, String> {
+    let config = std::env::var_os("XDG_CONFIG_HOME")
+      .filter(|dir| !dir.is_empty())
+      .map(PathBuf::from)
+      .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+      .ok_or("Unable to resolve the config directory")?;
+    Ok(config.join("autostart").join("matrix-messenger.desktop"))
+  }
+
+  /// Quote per the desktop entry spec: spaces and reserved characters need double quotes.
+  fn quote(arg: &str) -> String {
+    if arg.chars().any(|c| c.is_whitespace() || "\"'\\><~|&;$*?#()`".contains(c)) {
+      let escaped = arg.replace('\\', "\\\\\\\\").replace('"', "\\\"").replace('`', "\\`").replace('$', "\\$");
+      format!("\"{}\"", escaped)
+    } else {
+      arg.to_string()
+    }
+  }
+
+  pub fn set(args: Option<&[String]>) -> Result<(), String> {
+    let path = entry_path()?;
+    let Some(args) = args else {
+      if path.exists() {
+        fs::remove_file(&path).map_err(|e| e.to_string())?;
+      }
+      return Ok(());
+    };
+    let exec: Vec<String> = args.iter().map(|arg| quote(arg)).collect();
+    let entry = format!(
+      "[Desktop Entry]\nType=Application\nName=Matrix Messenger\nComment=Start Matrix Messenger on login\nExec={}\nTerminal=false\nX-GNOME-Autostart-enabled=true\n",
+      exec.join(" ")
+    );
+    if let Some(dir) = path.parent() {
+      fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+    fs::write(&path, entry).map_err(|e| e.to_string())
+  }
+
+  pub fn registered() -> Result<Option<String>, String> {
+    let path = entry_path()?;
+    if !path.exists() {
+      return Ok(None);
+    }
+    let entry = fs::read_to_string(&path).map_err(|e| e.to_string())?;
+    if entry.lines().any(|line| line.trim() == "Hidden=true" || line.trim() == "X-GNOME-Autostart-enabled=false") {
+      return Ok(None);
+    }
+    Ok(entry.lines().find_map(|line| line.strip_prefix("Exec=")).map(|s| s.to_string()))
+  }
+}
+
+/// Per-user LaunchAgent.
+#[cfg(target_os = "macos")]
+mod platform {
+  use std::fs;
+  use std::path::PathBuf;
+
+  use crate::unified_push::APP_ID;
+
+  fn agent_path() -> Result<PathBuf, String> {
+    let home = std::env::var_os("HOME").ok_or("Unable to resolve the home directory")?;
+    Ok(PathBuf::from(home).join("Library/LaunchAgents").join(format!("{}.plist", APP_ID)))
+  }
+
+  fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+  }
+
+  pub fn set(args: Option<&[String]>) -> Result<(), String> {
+    let path = agent_path()?;
+    let Some(args) = args else {
+      if path.exists() {
+        fs::remove_file(&path).map_err(|e| e.to_string())?;
+      }
+      return Ok(());
+    };
+    let program: String = args.iter().map(|arg| format!("    <string>{}</string>\n", escape(arg))).collect();
+    let plist = format!(
+      "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+       <!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n\
+       <plist version=\"1.0\">\n<dict>\n  <key>Label</key>\n  <string>{}</string>\n  <key>ProgramArguments</key>\n  <array>\n{}  </array>\n  <key>RunAtLoad</key>\n  <true/>\n  <key>ProcessType</key>\n  <string>Interactive</string>\n</dict>\n</plist>\n",
+      APP_ID, program
+    );
+    if let Some(dir) = path.parent() {
+      fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+    fs::write(&path, plist).map_err(|e| e.to_string())
+  }
+
+  pub fn registered() -> Result<Option<String>, String> {
+    let path = agent_path()?;
+    if !path.exists() {
+      return Ok(None);
+    }
+    fs::read_to_string(&path).map(Some).map_err(|e| e.to_string())
+  }
+}
+
+/// `Run` value under the current user's registry hive.
+#[cfg(windows)]
+mod platform {
+  use windows::core::HSTRING;
+  use windows::Win32::System::Registry::{
+    RegDeleteKeyValueW, RegGetValueW, RegSetKeyValueW, HKEY_CURRENT_USER, REG_SZ, RRF_RT_REG_SZ,
+  };
+
+  const RUN_KEY: &str = "Software\\Microsoft\\Windows\\CurrentVersion\\Run";
+  const ENTRY_NAME: &str = "Matrix Messenger";
+
+  fn command_line(args: &[String]) -> String {
+    args.iter().map(|arg| format!("\"{}\"", arg)).collect::<Vec<_>>().join(" ")
+  }
+
+  pub fn set(args: Option<&[String]>) -> Result<(), String> {
+    let key = HSTRING::from(RUN_KEY);
+    let name = HSTRING::from(ENTRY_NAME);
+    unsafe {
+      match args {
+        Some(args) => {
+          let value: Vec<u16> = command_line(args).encode_utf16().chain(std::iter::once(0)).collect();
+          RegSetKeyValueW(
+            HKEY_CURRENT_USER,
+            &key,
+            &name,
+            REG_SZ.0,
+            Some(value.as_ptr() as *const _),
+            (value.len() * 2) as u32,
+          )
+          .ok()
+          .map_err(|e| format!("Failed to write the Run key: {}", e))
+        }
+        None => {
+          let result = RegDeleteKeyValueW(HKEY_CURRENT_USER, &key, &name);
+          if result.is_ok() || result == windows::Win32::Foundation::ERROR_FILE_NOT_FOUND {
+            Ok(())
+          } else {
+            Err(format!("Failed to remove the Run key: {:?}", result))
+          }
+        }
+      }
+    }
+  }
+
+  pub fn registered() -> Result<Option<String>, String> {
+    let key = HSTRING::from(RUN_KEY);
+    let name = HSTRING::from(ENTRY_NAME);
+    let mut buffer = [0u16; 1024];
+    let mut size = (buffer.len() * 2) as u32;
+    let result = unsafe {
+      RegGetValueW(
+        HKEY_CURRENT_USER,
+        &key,
+        &name,
+        RRF_RT_REG_SZ,
+        None,
+        Some(buffer.as_mut_ptr() as *mut _),
+        Some(&mut size),
+      )
+    };
+    if result.is_err() {
+      return Ok(None);
+    }
+    let len = (size as usize / 2).saturating_sub(1);
+    Ok(Some(String::from_utf16_lossy(&buffer[..len])))
+  }
+}
@@ -0,0 +1,244 @@
+use rusqlite::{Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::connectivity::WakeSignal;
+use crate::matrix_api::MatrixClient;
+use crate::push_events;
+use crate::unified_push::PushNotification;
+
+const LONG_POLL_TIMEOUT_MS: u64 = 30_000;
+const MAX_RETRY_DELAY_SECS: u64 = 120;
+const NOTIFY_TYPES: &[&str] = &["m.room.message", "m.room.encrypted", "m.sticker", "m.call.invite"];
+
+/// Whether closing the window keeps a notifications-only sync running.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackgroundSettings {
+  pub enabled: bool,
+}
+
+impl Default for BackgroundSettings {
+  fn default() -> Self {
+    BackgroundSettings { enabled: false }
+  }
+}
+
+pub fn init_background_sync_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS background_settings (
+        scope TEXT PRIMARY KEY,
+        settings_json TEXT NOT NULL
+      );
+    ",
+  )
+}
+
+pub fn settings(conn: &Connection) -> Result<BackgroundSettings, String> {
+  let stored: Option<String> = conn
+    .query_row("SELECT settings_json FROM background_settings WHERE scope = ''", [], |row| row.get(0))
+    .optional()
+    .map_err(|e| e.to_string())?;
+  Ok(stored.and_then(|json| serde_json::from_str(&json).ok()).unwrap_or_default())
+}
+
+pub fn set_settings(conn: &Connection, value: &BackgroundSettings) -> Result<(), String> {
+  let json = serde_json::to_string(value).map_err(|e| e.to_string())?;
+  conn
+    .execute(
+      "INSERT INTO background_settings (scope, settings_json) VALUES ('', ?1)
+       ON CONFLICT(scope) DO UPDATE SET settings_json = excluded.settings_json",
+      [json],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+/// Sync tokens handed over by the webview and the background loops started from them.
+#[derive(Default)]
+pub struct BackgroundSyncState {
+  tokens: Mutex<HashMap<String, String>>,
+  active: Mutex<HashMap<String, Arc<AtomicBool>>>,
+}
+
+impl BackgroundSyncState {
+  pub fn record_token(&self, account_key: &str, since: &str) {
+    if let Ok(mut tokens) = self.tokens.lock() {
+      tokens.insert(account_key.to_string(), since.to_string());
+    }
+  }
+
+  pub fn token(&self, account_key: &str) -> Option<String> {
+    self.tokens.lock().ok().and_then(|tokens| tokens.get(account_key).cloned())
+  }
+
+  /// A fresh stop flag for `account_key`, stopping any loop already running for it.
+  pub fn begin(&self, account_key: &str) -> Arc<AtomicBool> {
+    let flag = Arc::new(AtomicBool::new(false));
+    if let Ok(mut active) = self.active.lock() {
+      if let Some(previous) = active.insert(account_key.to_string(), flag.clone()) {
+        previous.store(true, Ordering::Relaxed);
+      }
+    }
+    flag
+  }
+
+  /// Stop every loop; returns how many were running.
+  pub fn stop_all(&self) -> usize {
+    match self.active.lock() {
+      Ok(mut active) => {
+        let count = active.len();
+        for (_, flag) in active.drain() {
+          flag.store(true, Ordering::Relaxed);
+        }
+        count
+      }
+      Err(_) => 0,
+    }
+  }
+}
+
+/// Timeline types that can notify, no presence, account data, ephemeral events or member lists.
+pub fn notification_filter() -> Value {
+  json!({
+    "presence": { "not_types": ["*"] },
+    "account_data": { "not_types": ["*"] },
+    "room": {
+      "timeline": { "limit": 10, "types": NOTIFY_TYPES },
+      "state": { "lazy_load_members": true, "types": ["m.room.name"] },
+      "ephemeral": { "not_types": ["*"] },
+      "account_data": { "not_types": ["*"] }
+    }
+  })
+}
+
+/// The newest notifying event per room that the server counts as a notification.
+pub fn notifications_from_sync(response: &Value, own_user_id: &str) -> Vec<PushNotification> {
+  let Some(rooms) = response.pointer("/rooms/join").and_then(|v| v.as_object()) else {
+    return Vec::new();
+  };
+  let mut notifications = Vec::new();
+  for (room_id, room) in rooms {
+    let unread = room.pointer("/unread_notifications/notification_count").and_then(|v| v.as_u64()).unwrap_or(0);
+    if unread == 0 {
+      continue;
+    }
+    let events = room.pointer("/timeline/events").and_then(|v| v.as_array()).cloned().unwrap_or_default();
+    let newest = events.iter().rev().find(|event| {
+      let event_type = event.get("type").and_then(|v| v.as_str()).unwrap_or_default();
+      NOTIFY_TYPES.contains(&event_type) && event.get("sender").and_then(|v| v.as_str()) != Some(own_user_id)
+    });
+    let Some(event) = newest else {
+      continue;
+    };
+    let room_name = room
+      .pointer("/state/events")
+      .and_then(|v| v.as_array())
+      .and_then(|state| {
+        state
+          .iter()
+          .find(|e| e.get("type").and_then(|v| v.as_str()) == Some("m.room.name"))
+          .and_then(|e| e.pointer("/content/name"))
+          .and_then(|v| v.as_str())
+          .map(|s| s.to_string())
+      });
+    let event_type = event.get("type").and_then(|v| v.as_str()).map(|s| s.to_string());
+    // Encrypted events are left without a body so they get decrypted before showing.
+    let body = match event_type.as_deref() {
+      Some("m.room.encrypted") => None,
+      _ => push_events::preview(event),
+    };
+    notifications.push(PushNotification {
+      event_id: event.get("event_id").and_then(|v| v.as_str()).map(|s| s.to_string()),
+      room_id: Some(room_id.clone()),
+      event_type,
+      sender: event.get("sender").and_then(|v| v.as_str()).map(|s| s.to_string()),
+      sender_display_name: None,
+      room_name,
+      body,
+      unread: Some(unread),
+    });
+  }
+  notifications
+}
+
+/// Long-poll `/sync` from `since` until `stop` is set, handing notifying events to `notify`.
+/// Without a token the first response only sets the position. Polling is parked while `wake`
+/// is suspended; waking abandons the current request and skips any backoff.
+///
+/// Moving `since` past a response makes the server drop its to-device events (room keys,
+/// verification), so each batch goes to `keep_to_device` first and the position only
+/// advances once that succeeds.
+pub async fn run<F, Fut>(
+  client: MatrixClient,
+  mut since: Option<String>,
+  stop: Arc<AtomicBool>,
+  wake: Arc<WakeSignal>,
+  keep_to_device: F,
+  notify: impl Fn(Vec<PushNotification>),
+) where
+  F: Fn(Vec<Value>) -> Fut,
+  Fut: std::future::Future<Output = Result<(), String>>,
+{
+  let filter = notification_filter().to_string();
+  let mut delay = 1;
+  while !stop.load(Ordering::Relaxed) {
+    if wake.is_suspended() {
+      wake.wait_resumed().await;
+      delay = 1;
+      continue;
+    }
+    let mut query = vec![("filter", filter.clone()), ("set_presence", "offline".to_string())];
+    match &since {
+      Some(since) => {
+        query.push(("since", since.clone()));
+        query.push(("timeout", LONG_POLL_TIMEOUT_MS.to_string()));
+      }
+      None => query.push(("timeout", "0".to_string())),
+    }
+    let result = tokio::select! {
+      result = client.get("/sync", &query) => result,
+      // The connection is likely dead after a suspend or network switch.
+      _ = wake.woken() => continue,
+    };
+    match result {
+      Ok(response) => {
+        delay = 1;
+        if stop.load(Ordering::Relaxed) {
+          return;
+        }
+        let to_device = response.pointer("/to_device/events").and_then(|v| v.as_array()).cloned().unwrap_or_default();
+        if !to_device.is_empty() {
+          if let Err(err) = keep_to_device(to_device).await {
+            tracing::warn!("Background sync could not keep to-device events: {}", err);
+            tokio::select! {
+              _ = tokio::time::sleep(Duration::from_secs(delay)) => delay = (delay * 2).min(MAX_RETRY_DELAY_SECS),
+              _ = wake.woken() => delay = 1,
+            }
+            continue;
+          }
+        }
+        if since.is_some() {
+          let notifications = notifications_from_sync(&response, &client.user_id);
+          if !notifications.is_empty() {
+            notify(notifications);
+          }
+        }
+        if let Some(next) = response.get("next_batch").and_then(|v| v.as_str()) {
+          since = Some(next.to_string());
+        }
+      }
+      Err(err) => {
+        tracing::warn!("Background sync failed: {}", String::from(err));
+        tokio::select! {
+          _ = tokio::time::sleep(Duration::from_secs(delay)) => delay = (delay * 2).min(MAX_RETRY_DELAY_SECS),
+          _ = wake.woken() => delay = 1,
+        }
+      }
+    }
+  }
+}
@@ -0,0 +1,122 @@
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::event_cache;
+use crate::notification_rules::{self, RoomNotifyMode};
+use crate::push_rules;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnreadSummary {
+  pub total: u64,
+  /// Unread events the push rules highlight (mentions, keywords).
+  pub highlights: u64,
+  pub rooms: HashMap<String, u64>,
+}
+
+/// The count last shown, so unchanged totals don't touch the window.
+#[derive(Default)]
+pub struct BadgeState {
+  shown: Mutex<Option<u64>>,
+}
+
+impl BadgeState {
+  /// Record `count` and return whether it differs from what is shown.
+  pub fn update(&self, count: u64) -> bool {
+    match self.shown.lock() {
+      Ok(mut shown) if *shown != Some(count) => {
+        *shown = Some(count);
+        true
+      }
+      _ => false,
+    }
+  }
+}
+
+/// Notifying events after each account's read receipt, evaluated with its cached push rules,
+/// summed per room. Rooms the account has not joined and locally muted rooms are skipped.
+pub fn unread_counts(conn: &Connection, accounts: &[(String, String)]) -> Result<UnreadSummary, String> {
+  let mut rooms: HashMap<String, u64> = HashMap::new();
+  let mut highlights = 0;
+  for (account_key, user_id) in accounts {
+    let Some(rules) = push_rules::cached_rules(conn, account_key)? else {
+      continue;
+    };
+    for room_id in &event_cache::cached_room_ids(conn, account_key)? {
+      let joined = event_cache::get_state_event(conn, account_key, room_id, "m.room.member", user_id)?
+        .is_some_and(|member| member.content.get("membership").and_then(|v| v.as_str()) == Some("join"));
+      if !joined || notification_rules::get_rule(conn, room_id)?.mode == RoomNotifyMode::Mute {
+        continue;
+      }
+      let counts = push_rules::room_counts(conn, account_key, &rules, room_id, user_id)?;
+      if counts.notification_count > 0 {
+        *rooms.entry(room_id.clone()).or_default() += counts.notification_count as u64;
+        highlights += counts.highlight_count as u64;
+      }
+    }
+  }
+  Ok(UnreadSummary {
+    total: rooms.values().sum(),
+    highlights,
+    rooms,
+  })
+}
+
+/// 3x5 glyphs for the overlay icon, one row per entry, high bit on the left.
+#[cfg(windows)]
+fn glyph(c: char) -> [u8; 5] {
+  match c {
+    '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+    '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+    '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+    '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+    '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+    '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+    '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+    '7' => [0b111, 0b001, 0b010, 0b010, 0b010],
+    '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+    '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+    _ => [0b000, 0b010, 0b111, 0b010, 0b000],
+  }
+}
+
+/// RGBA pixels of a red disc with the count in white, for the Windows taskbar overlay.
+#[cfg(windows)]
+pub fn overlay_icon(count: u64, size: u32) -> Vec<u8> {
+  let label = if count > 99 { "99+".to_string() } else { count.to_string() };
+  let chars: Vec<char> = label.chars().collect();
+  let scale = (if chars.len() > 2 { size / 11 } else { size / 8 }).max(1);
+  let gap = scale / 2;
+  let text_w = chars.len() as u32 * 3 * scale + (chars.len() as u32 - 1) * gap;
+  let text_h = 5 * scale;
+  let (left, top) = (size.saturating_sub(text_w) / 2, size.saturating_sub(text_h) / 2);
+  let radius = size as f32 / 2.0;
+  let mut pixels = vec![0u8; (size * size * 4) as usize];
+  for y in 0..size {
+    for x in 0..size {
+      let (dx, dy) = (x as f32 + 0.5 - radius, y as f32 + 0.5 - radius);
+      let coverage = (radius - (dx * dx + dy * dy).sqrt()).clamp(0.0, 1.0);
+      if coverage == 0.0 {
+        continue;
+      }
+      let mut rgb = [0xE0, 0x24, 0x24];
+      if x >= left && y >= top && y < top + text_h {
+        let column = x - left;
+        let index = (column / (3 * scale + gap)) as usize;
+        let within = column % (3 * scale + gap);
+        if index < chars.len() && within < 3 * scale {
+          let row = glyph(chars[index])[((y - top) / scale) as usize];
+          if row & (0b100 >> (within / scale)) != 0 {
+            rgb = [0xFF, 0xFF, 0xFF];
+          }
+        }
+      }
+      let offset = ((y * size + x) * 4) as usize;
+      pixels[offset..offset + 3].copy_from_slice(&rgb);
+      pixels[offset + 3] = (coverage * 255.0) as u8;
+    }
+  }
+  pixels
+}
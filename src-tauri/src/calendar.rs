@@ -0,0 +1,305 @@
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, NaiveTime, TimeZone, Utc, Weekday};
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+
+use crate::event_cache::CachedEvent;
+
+const MONTHS: [&str; 12] = ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+const WEEKDAYS: [(&str, Weekday); 7] = [
+  ("monday", Weekday::Mon),
+  ("tuesday", Weekday::Tue),
+  ("wednesday", Weekday::Wed),
+  ("thursday", Weekday::Thu),
+  ("friday", Weekday::Fri),
+  ("saturday", Weekday::Sat),
+  ("sunday", Weekday::Sun),
+];
+const SUMMARY_LEN: usize = 80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CandidateKind {
+  /// An attached `.ics` / `text/calendar` file.
+  Invite,
+  /// A message that names a date (and maybe a time).
+  DateMention,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CalendarCandidate {
+  pub kind: CandidateKind,
+  pub summary: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub start: Option<i64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub end: Option<i64>,
+  pub all_day: bool,
+  /// The part of the message the date was read from.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub matched_text: Option<String>,
+}
+
+fn date_pattern() -> &'static Regex {
+  static PATTERN: OnceLock<Regex> = OnceLock::new();
+  PATTERN.get_or_init(|| {
+    Regex::new(
+      r"(?ix)
+        \b(?P<iy>\d{4})-(?P<im>\d{1,2})-(?P<id>\d{1,2})\b
+      | \b(?P<na>\d{1,2})(?P<sep>[./])(?P<nb>\d{1,2})(?:[./](?P<ny>\d{2,4}))?\b
+      | \b(?P<dd>\d{1,2})(?:st|nd|rd|th)?\s+(?:of\s+)?(?P<dm>jan|feb|mar|apr|may|jun|jul|aug|sep|oct|nov|dec)[a-z]*\.?(?:,?\s+(?P<dy>\d{4}))?\b
+      | \b(?P<mm>jan|feb|mar|apr|may|jun|jul|aug|sep|oct|nov|dec)[a-z]*\.?\s+(?P<md>\d{1,2})(?:st|nd|rd|th)?(?:,?\s+(?P<my>\d{4}))?\b
+      | \b(?P<rel>today|tonight|tomorrow)\b
+      | \b(?:(?P<next>next)\s+|on\s+)?(?P<wd>monday|tuesday|wednesday|thursday|friday|saturday|sunday)\b
+      ",
+    )
+    .expect("valid date pattern")
+  })
+}
+
+fn time_pattern() -> &'static Regex {
+  static PATTERN: OnceLock<Regex> = OnceLock::new();
+  PATTERN.get_or_init(|| {
+    Regex::new(
+      r"(?ix)
+        \b(?P<h1>\d{1,2})(?::(?P<m1>\d{2}))?\s*(?P<ap1>am|pm)?
+        (?:\s*(?:-|–|to|until|till)\s*(?P<h2>\d{1,2})(?::(?P<m2>\d{2}))?\s*(?P<ap2>am|pm)?)?\b
+      ",
+    )
+    .expect("valid time pattern")
+  })
+}
+
+fn number(caps: &Captures, name: &str) -> Option<u32> {
+  caps.name(name)?.as_str().parse().ok()
+}
+
+fn month(caps: &Captures, name: &str) -> Option<u32> {
+  let prefix = caps.name(name)?.as_str().to_ascii_lowercase();
+  MONTHS.iter().position(|m| prefix.starts_with(m)).map(|i| i as u32 + 1)
+}
+
+/// Dates without a year that already passed when the message was sent mean next year.
+fn with_year(year: Option<u32>, month: u32, day: u32, sent: NaiveDate) -> Option<NaiveDate> {
+  match year {
+    Some(year) => NaiveDate::from_ymd_opt(if year < 100 { year + 2000 } else { year } as i32, month, day),
+    None => {
+      let date = NaiveDate::from_ymd_opt(sent.year(), month, day)?;
+      if date < sent {
+        NaiveDate::from_ymd_opt(sent.year() + 1, month, day)
+      } else {
+        Some(date)
+      }
+    }
+  }
+}
+
+fn parse_date(caps: &Captures, sent: NaiveDate) -> Option<NaiveDate> {
+  if let (Some(y), Some(m), Some(d)) = (number(caps, "iy"), number(caps, "im"), number(caps, "id")) {
+    return NaiveDate::from_ymd_opt(y as i32, m, d);
+  }
+  if let (Some(a), Some(b)) = (number(caps, "na"), number(caps, "nb")) {
+    // Dots are day-first everywhere; slashes are month-first unless that can't be.
+    let day_first = caps.name("sep").is_some_and(|s| s.as_str() == ".") || a > 12;
+    let (day, month) = if day_first { (a, b) } else { (b, a) };
+    return with_year(number(caps, "ny"), month, day, sent);
+  }
+  if let (Some(d), Some(m)) = (number(caps, "dd"), month(caps, "dm")) {
+    return with_year(number(caps, "dy"), m, d, sent);
+  }
+  if let (Some(m), Some(d)) = (month(caps, "mm"), number(caps, "md")) {
+    return with_year(number(caps, "my"), m, d, sent);
+  }
+  if let Some(rel) = caps.name("rel") {
+    return Some(match rel.as_str().to_ascii_lowercase().as_str() {
+      "tomorrow" => sent + Duration::days(1),
+      _ => sent,
+    });
+  }
+  let name = caps.name("wd")?.as_str().to_ascii_lowercase();
+  let weekday = WEEKDAYS.iter().find(|(n, _)| *n == name)?.1;
+  let ahead = (weekday.num_days_from_monday() as i64 - sent.weekday().num_days_from_monday() as i64).rem_euclid(7);
+  // "Friday" sent on a Friday means today, "next Friday" a week later.
+  let ahead = if ahead == 0 && caps.name("next").is_some() { 7 } else { ahead };
+  Some(sent + Duration::days(ahead))
+}
+
+fn to_time(hour: u32, minute: u32, meridiem: Option<&str>) -> Option<NaiveTime> {
+  let hour = match meridiem.map(|m| m.to_ascii_lowercase()) {
+    Some(m) if m == "pm" && hour < 12 => hour + 12,
+    Some(m) if m == "am" && hour == 12 => 0,
+    _ => hour,
+  };
+  NaiveTime::from_hms_opt(hour, minute, 0)
+}
+
+/// A start and optional end time near the date. Bare numbers only count with a colon or am/pm.
+fn parse_times(text: &str) -> Option<(NaiveTime, Option<NaiveTime>)> {
+  time_pattern().captures_iter(text).find_map(|caps| {
+    let explicit = caps.name("m1").is_some() || caps.name("ap1").is_some();
+    if !explicit {
+      return None;
+    }
+    let end_meridiem = caps.name("ap2").map(|m| m.as_str());
+    let start_meridiem = caps.name("ap1").map(|m| m.as_str()).or(end_meridiem);
+    let start = to_time(number(&caps, "h1")?, number(&caps, "m1").unwrap_or(0), start_meridiem)?;
+    let end = number(&caps, "h2").and_then(|h| to_time(h, number(&caps, "m2").unwrap_or(0), end_meridiem.or(start_meridiem)));
+    Some((start, end.filter(|end| *end > start)))
+  })
+}
+
+fn local_millis(date: NaiveDate, time: NaiveTime) -> Option<i64> {
+  Local.from_local_datetime(&date.and_time(time)).earliest().map(|dt| dt.timestamp_millis())
+}
+
+fn summary(body: &str) -> String {
+  let line = body.lines().find(|line| !line.trim().is_empty()).unwrap_or_default().trim();
+  if line.chars().count() > SUMMARY_LEN {
+    format!("{}…", line.chars().take(SUMMARY_LEN - 1).collect::<String>())
+  } else {
+    line.to_string()
+  }
+}
+
+fn is_calendar_file(event: &CachedEvent) -> bool {
+  let mimetype = event.content.pointer("/info/mimetype").and_then(|v| v.as_str()).unwrap_or_default();
+  let name = event
+    .content
+    .get("filename")
+    .or_else(|| event.content.get("body"))
+    .and_then(|v| v.as_str())
+    .unwrap_or_default()
+    .to_ascii_lowercase();
+  mimetype.eq_ignore_ascii_case("text/calendar") || name.ends_with(".ics")
+}
+
+/// A calendar invite attachment or a date named in the message text, resolved against the
+/// time the message was sent.
+pub fn detect(event: &CachedEvent) -> Option<CalendarCandidate> {
+  if event.event_type != "m.room.message" {
+    return None;
+  }
+  let body = event.content.get("body").and_then(|v| v.as_str()).unwrap_or_default();
+  if is_calendar_file(event) {
+    return Some(CalendarCandidate {
+      kind: CandidateKind::Invite,
+      summary: summary(body),
+      start: None,
+      end: None,
+      all_day: false,
+      matched_text: None,
+    });
+  }
+  let msgtype = event.content.get("msgtype").and_then(|v| v.as_str()).unwrap_or_default();
+  if !matches!(msgtype, "m.text" | "m.notice" | "m.emote") {
+    return None;
+  }
+  let sent = Local.timestamp_millis_opt(event.origin_server_ts).single()?.date_naive();
+  let caps = date_pattern().captures(body)?;
+  let matched = caps.get(0)?;
+  let date = parse_date(&caps, sent)?;
+  // Times are looked for around the date, e.g. "Friday 3pm" or "at 14:00 on 20.10.".
+  let window_start = body[..matched.start()].char_indices().rev().nth(24).map(|(i, _)| i).unwrap_or(0);
+  let window_end = body[matched.end()..]
+    .char_indices()
+    .nth(32)
+    .map(|(i, _)| matched.end() + i)
+    .unwrap_or(body.len());
+  let around = format!("{} {}", &body[window_start..matched.start()], &body[matched.end()..window_end]);
+  let (start, end, all_day) = match parse_times(&around) {
+    Some((start_time, end_time)) => {
+      let start = local_millis(date, start_time)?;
+      let end = end_time.and_then(|end| local_millis(date, end)).unwrap_or(start + 60 * 60 * 1000);
+      (start, end, false)
+    }
+    None => {
+      let start = local_millis(date, NaiveTime::MIN)?;
+      (start, local_millis(date + Duration::days(1), NaiveTime::MIN)?, true)
+    }
+  };
+  Some(CalendarCandidate {
+    kind: CandidateKind::DateMention,
+    summary: summary(body),
+    start: Some(start),
+    end: Some(end),
+    all_day,
+    matched_text: Some(body[window_start..window_end].trim().to_string()),
+  })
+}
+
+/// RFC 5545 text escaping.
+fn escape(text: &str) -> String {
+  text
+    .replace('\\', "\\\\")
+    .replace(';', "\\;")
+    .replace(',', "\\,")
+    .replace("\r\n", "\\n")
+    .replace('\n', "\\n")
+}
+
+/// Content lines are folded at 75 octets with CRLF and a leading space.
+fn fold(line: &str) -> String {
+  let mut out = String::new();
+  let mut len = 0;
+  for c in line.chars() {
+    if len + c.len_utf8() > 75 {
+      out.push_str("\r\n ");
+      len = 1;
+    }
+    out.push(c);
+    len += c.len_utf8();
+  }
+  out.push_str("\r\n");
+  out
+}
+
+fn utc_stamp(millis: i64) -> String {
+  DateTime::<Utc>::from_timestamp_millis(millis)
+    .unwrap_or_default()
+    .format("%Y%m%dT%H%M%SZ")
+    .to_string()
+}
+
+fn local_date(millis: i64) -> String {
+  Local
+    .timestamp_millis_opt(millis)
+    .single()
+    .map(|dt| dt.format("%Y%m%d").to_string())
+    .unwrap_or_default()
+}
+
+/// A single-event calendar for `candidate`, linking back to the message.
+pub fn to_ics(event: &CachedEvent, candidate: &CalendarCandidate, now: i64) -> Result<String, String> {
+  let start = candidate.start.ok_or("No date was found in this message")?;
+  let end = candidate.end.unwrap_or(start + 60 * 60 * 1000);
+  let body = event.content.get("body").and_then(|v| v.as_str()).unwrap_or_default();
+  let link = format!(
+    "https://matrix.to/#/{}/{}",
+    urlencoding::encode(&event.room_id),
+    urlencoding::encode(&event.event_id)
+  );
+  let mut lines = vec![
+    "BEGIN:VCALENDAR".to_string(),
+    "VERSION:2.0".to_string(),
+    "PRODID:-//Matrix Messenger//Chat event//EN".to_string(),
+    "CALSCALE:GREGORIAN".to_string(),
+    "METHOD:PUBLISH".to_string(),
+    "BEGIN:VEVENT".to_string(),
+    format!("UID:{}@matrix-messenger", escape(event.event_id.trim_start_matches('$'))),
+    format!("DTSTAMP:{}", utc_stamp(now)),
+  ];
+  if candidate.all_day {
+    lines.push(format!("DTSTART;VALUE=DATE:{}", local_date(start)));
+    lines.push(format!("DTEND;VALUE=DATE:{}", local_date(end)));
+  } else {
+    lines.push(format!("DTSTART:{}", utc_stamp(start)));
+    lines.push(format!("DTEND:{}", utc_stamp(end)));
+  }
+  lines.push(format!("SUMMARY:{}", escape(&candidate.summary)));
+  lines.push(format!("DESCRIPTION:{}", escape(&format!("{}\n\n{} in {}", body, event.sender, link))));
+  lines.push(format!("URL:{}", link));
+  lines.push("END:VEVENT".to_string());
+  lines.push("END:VCALENDAR".to_string());
+  Ok(lines.iter().map(|line| fold(line)).collect())
+}
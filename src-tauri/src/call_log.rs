@@ -0,0 +1,258 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::matrix_api::now_millis;
+
+/// Invites that don't say how long they ring for are treated as unanswered after this.
+const DEFAULT_LIFETIME_MS: i64 = 60_000;
+/// Calls missed longer ago than this (e.g. found while back-paginating) are logged silently.
+const NOTIFY_WINDOW_MS: i64 = 60 * 60 * 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CallStatus {
+  Ringing,
+  Answered,
+  Declined,
+  Missed,
+  Ended,
+}
+
+impl CallStatus {
+  fn as_str(self) -> &'static str {
+    match self {
+      CallStatus::Ringing => "ringing",
+      CallStatus::Answered => "answered",
+      CallStatus::Declined => "declined",
+      CallStatus::Missed => "missed",
+      CallStatus::Ended => "ended",
+    }
+  }
+
+  fn parse(value: &str) -> CallStatus {
+    match value {
+      "ringing" => CallStatus::Ringing,
+      "answered" => CallStatus::Answered,
+      "declined" => CallStatus::Declined,
+      "missed" => CallStatus::Missed,
+      _ => CallStatus::Ended,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CallEntry {
+  pub room_id: String,
+  pub call_id: String,
+  pub caller: String,
+  pub incoming: bool,
+  pub video: bool,
+  pub status: CallStatus,
+  pub started_at: i64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub ended_at: Option<i64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub invite_event_id: Option<String>,
+}
+
+pub fn init_call_log_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS call_log (
+        account_key TEXT NOT NULL,
+        room_id TEXT NOT NULL,
+        call_id TEXT NOT NULL,
+        caller TEXT NOT NULL,
+        incoming INTEGER NOT NULL,
+        video INTEGER NOT NULL,
+        status TEXT NOT NULL,
+        started_at INTEGER NOT NULL,
+        lifetime_ms INTEGER NOT NULL,
+        ended_at INTEGER,
+        invite_event_id TEXT,
+        PRIMARY KEY (account_key, room_id, call_id)
+      );
+      CREATE INDEX IF NOT EXISTS idx_call_log_started ON call_log(account_key, started_at);
+    ",
+  )
+}
+
+const ENTRY_COLUMNS: &str = "room_id, call_id, caller, incoming, video, status, started_at, ended_at, invite_event_id";
+
+fn row_to_entry(row: &rusqlite::Row) -> rusqlite::Result<CallEntry> {
+  Ok(CallEntry {
+    room_id: row.get(0)?,
+    call_id: row.get(1)?,
+    caller: row.get(2)?,
+    incoming: row.get::<_, i64>(3)? != 0,
+    video: row.get::<_, i64>(4)? != 0,
+    status: CallStatus::parse(&row.get::<_, String>(5)?),
+    started_at: row.get(6)?,
+    ended_at: row.get(7)?,
+    invite_event_id: row.get(8)?,
+  })
+}
+
+fn entry(conn: &Connection, account_key: &str, room_id: &str, call_id: &str) -> Result<Option<CallEntry>, String> {
+  conn
+    .query_row(
+      &format!(
+        "SELECT {} FROM call_log WHERE account_key = ?1 AND room_id = ?2 AND call_id = ?3",
+        ENTRY_COLUMNS
+      ),
+      params![account_key, room_id, call_id],
+      row_to_entry,
+    )
+    .optional()
+    .map_err(|e| e.to_string())
+}
+
+/// Move a ringing call to `status`; returns the entry when it changed.
+fn finish(
+  conn: &Connection,
+  account_key: &str,
+  room_id: &str,
+  call_id: &str,
+  status: CallStatus,
+  at: i64,
+) -> Result<Option<CallEntry>, String> {
+  let changed = conn
+    .execute(
+      "UPDATE call_log SET status = ?4, ended_at = ?5
+       WHERE account_key = ?1 AND room_id = ?2 AND call_id = ?3 AND status IN ('ringing', 'answered')",
+      params![account_key, room_id, call_id, status.as_str(), at],
+    )
+    .map_err(|e| e.to_string())?;
+  if changed == 0 {
+    return Ok(None);
+  }
+  entry(conn, account_key, room_id, call_id)
+}
+
+fn is_missed(entry: &CallEntry) -> bool {
+  entry.incoming && entry.status == CallStatus::Missed
+}
+
+/// Follow `m.call.*` events through invite, answer, reject and hangup. Returns calls that just
+/// became missed recently enough to notify about.
+pub fn track(conn: &Connection, account_key: &str, own_user_id: &str, room_id: &str, events: &[Value]) -> Result<Vec<CallEntry>, String> {
+  let mut missed = Vec::new();
+  for raw in events {
+    let event_type = raw.get("type").and_then(|v| v.as_str()).unwrap_or_default();
+    if !event_type.starts_with("m.call.") {
+      continue;
+    }
+    let Some(content) = raw.get("content") else {
+      continue;
+    };
+    let Some(call_id) = content.get("call_id").and_then(|v| v.as_str()) else {
+      continue;
+    };
+    let sender = raw.get("sender").and_then(|v| v.as_str()).unwrap_or_default();
+    let ts = raw.get("origin_server_ts").and_then(|v| v.as_i64()).unwrap_or_else(now_millis);
+    let own = sender == own_user_id;
+    match event_type {
+      "m.call.invite" => {
+        let video = content
+          .pointer("/offer/sdp")
+          .and_then(|v| v.as_str())
+          .is_some_and(|sdp| sdp.contains("m=video"));
+        let lifetime = content.get("lifetime").and_then(|v| v.as_i64()).unwrap_or(DEFAULT_LIFETIME_MS);
+        conn
+          .execute(
+            "INSERT OR IGNORE INTO call_log
+               (account_key, room_id, call_id, caller, incoming, video, status, started_at, lifetime_ms, invite_event_id)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, 'ringing', ?7, ?8, ?9)",
+            params![
+              account_key,
+              room_id,
+              call_id,
+              sender,
+              !own as i64,
+              video as i64,
+              ts,
+              lifetime,
+              raw.get("event_id").and_then(|v| v.as_str()),
+            ],
+          )
+          .map_err(|e| e.to_string())?;
+      }
+      "m.call.answer" => {
+        conn
+          .execute(
+            "UPDATE call_log SET status = 'answered'
+             WHERE account_key = ?1 AND room_id = ?2 AND call_id = ?3 AND status = 'ringing'",
+            params![account_key, room_id, call_id],
+          )
+          .map_err(|e| e.to_string())?;
+      }
+      "m.call.reject" => {
+        finish(conn, account_key, room_id, call_id, CallStatus::Declined, ts)?;
+      }
+      "m.call.hangup" => {
+        let Some(current) = entry(conn, account_key, room_id, call_id)? else {
+          continue;
+        };
+        let status = match current.status {
+          CallStatus::Ringing if current.incoming && !own => CallStatus::Missed,
+          CallStatus::Ringing if current.incoming => CallStatus::Declined,
+          _ => CallStatus::Ended,
+        };
+        if let Some(updated) = finish(conn, account_key, room_id, call_id, status, ts)? {
+          if is_missed(&updated) && now_millis() - updated.started_at < NOTIFY_WINDOW_MS {
+            missed.push(updated);
+          }
+        }
+      }
+      _ => {}
+    }
+  }
+  missed.extend(expire(conn, account_key)?);
+  Ok(missed)
+}
+
+/// Mark incoming calls still ringing past their lifetime as missed.
+pub fn expire(conn: &Connection, account_key: &str) -> Result<Vec<CallEntry>, String> {
+  let now = now_millis();
+  let mut stmt = conn
+    .prepare(&format!(
+      "SELECT {} FROM call_log WHERE account_key = ?1 AND status = 'ringing' AND started_at + lifetime_ms < ?2",
+      ENTRY_COLUMNS
+    ))
+    .map_err(|e| e.to_string())?;
+  let stale: Vec<CallEntry> = stmt
+    .query_map(params![account_key, now], row_to_entry)
+    .map_err(|e| e.to_string())?
+    .flatten()
+    .collect();
+  let mut missed = Vec::new();
+  for call in stale {
+    let status = if call.incoming { CallStatus::Missed } else { CallStatus::Ended };
+    if let Some(updated) = finish(conn, account_key, &call.room_id, &call.call_id, status, now)? {
+      if is_missed(&updated) && now - updated.started_at < NOTIFY_WINDOW_MS {
+        missed.push(updated);
+      }
+    }
+  }
+  Ok(missed)
+}
+
+/// Newest calls first, optionally only missed ones.
+pub fn list(conn: &Connection, account_key: &str, missed_only: bool, limit: usize) -> Result<Vec<CallEntry>, String> {
+  let mut stmt = conn
+    .prepare(&format!(
+      "SELECT {} FROM call_log WHERE account_key = ?1 AND (?2 = 0 OR (status = 'missed' AND incoming = 1))
+       ORDER BY started_at DESC LIMIT ?3",
+      ENTRY_COLUMNS
+    ))
+    .map_err(|e| e.to_string())?;
+  let rows = stmt
+    .query_map(params![account_key, missed_only as i64, limit as i64], row_to_entry)
+    .map_err(|e| e.to_string())?;
+  let mut out = Vec::new();
+  for row in rows {
+    out.push(row.map_err(|e| e.to_string())?);
+  }
+  Ok(out)
+}
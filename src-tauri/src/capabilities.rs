@@ -0,0 +1,230 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::matrix_api::{now_millis, ApiError, MatrixClient};
+
+const CAPABILITIES_TTL_MS: i64 = 6 * 60 * 60 * 1000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerFeatures {
+  pub supports_threads: bool,
+  pub supports_sliding_sync: bool,
+  pub supports_oidc: bool,
+  pub supports_authenticated_media: bool,
+  pub can_change_password: bool,
+  pub can_set_displayname: bool,
+  pub can_set_avatar_url: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub max_upload_size: Option<u64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub default_room_version: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerCapabilities {
+  pub homeserver_url: String,
+  pub versions: Vec<String>,
+  pub unstable_features: Value,
+  pub capabilities: Value,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub oidc_issuer: Option<String>,
+  pub features: ServerFeatures,
+  pub fetched_at: i64,
+  pub stale: bool,
+}
+
+pub fn init_capabilities_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS server_capabilities (
+        homeserver_url TEXT PRIMARY KEY,
+        versions_json TEXT NOT NULL,
+        capabilities_json TEXT NOT NULL,
+        media_config_json TEXT NOT NULL,
+        oidc_issuer TEXT,
+        fetched_at INTEGER NOT NULL
+      );
+    ",
+  )
+}
+
+fn has_version(versions: &[String], minimum_minor: u32) -> bool {
+  versions.iter().any(|v| {
+    v.strip_prefix("v1.")
+      .and_then(|minor| minor.parse::<u32>().ok())
+      .map(|minor| minor >= minimum_minor)
+      .unwrap_or(false)
+  })
+}
+
+fn unstable(unstable_features: &Value, key: &str) -> bool {
+  unstable_features.get(key).and_then(|v| v.as_bool()).unwrap_or(false)
+}
+
+fn capability_enabled(capabilities: &Value, key: &str) -> bool {
+  capabilities
+    .get(key)
+    .and_then(|c| c.get("enabled"))
+    .and_then(|v| v.as_bool())
+    .unwrap_or(true)
+}
+
+fn build(
+  homeserver_url: &str,
+  versions_json: &Value,
+  capabilities_json: &Value,
+  media_config_json: &Value,
+  oidc_issuer: Option<String>,
+  fetched_at: i64,
+) -> ServerCapabilities {
+  let versions: Vec<String> = versions_json
+    .get("versions")
+    .and_then(|v| v.as_array())
+    .map(|items| items.iter().filter_map(|v| v.as_str().map(|s| s.to_string())).collect())
+    .unwrap_or_default();
+  let unstable_features = versions_json.get("unstable_features").cloned().unwrap_or_else(|| json!({}));
+  let capabilities = capabilities_json.get("capabilities").cloned().unwrap_or_else(|| json!({}));
+  let features = ServerFeatures {
+    supports_threads: has_version(&versions, 4) || unstable(&unstable_features, "org.matrix.msc3440.stable"),
+    supports_sliding_sync: unstable(&unstable_features, "org.matrix.simplified_msc3575")
+      || unstable(&unstable_features, "org.matrix.msc3575"),
+    supports_oidc: oidc_issuer.is_some(),
+    supports_authenticated_media: has_version(&versions, 11) || unstable(&unstable_features, "org.matrix.msc3916.stable"),
+    can_change_password: capability_enabled(&capabilities, "m.change_password"),
+    can_set_displayname: capability_enabled(&capabilities, "m.set_displayname"),
+    can_set_avatar_url: capability_enabled(&capabilities, "m.set_avatar_url"),
+    max_upload_size: media_config_json.get("m.upload.size").and_then(|v| v.as_u64()),
+    default_room_version: capabilities
+      .pointer("/m.room_versions/default")
+      .and_then(|v| v.as_str())
+      .map(|s| s.to_string()),
+  };
+  ServerCapabilities {
+    homeserver_url: homeserver_url.to_string(),
+    versions,
+    unstable_features,
+    capabilities,
+    oidc_issuer,
+    features,
+    fetched_at,
+    stale: now_millis() - fetched_at > CAPABILITIES_TTL_MS,
+  }
+}
+
+pub fn cached(conn: &Connection, homeserver_url: &str) -> Result<Option<ServerCapabilities>, String> {
+  conn
+    .query_row(
+      "SELECT versions_json, capabilities_json, media_config_json, oidc_issuer, fetched_at
+       FROM server_capabilities WHERE homeserver_url = ?1",
+      [homeserver_url],
+      |row| {
+        let versions_json: String = row.get(0)?;
+        let capabilities_json: String = row.get(1)?;
+        let media_config_json: String = row.get(2)?;
+        Ok(build(
+          homeserver_url,
+          &serde_json::from_str(&versions_json).unwrap_or_else(|_| json!({})),
+          &serde_json::from_str(&capabilities_json).unwrap_or_else(|_| json!({})),
+          &serde_json::from_str(&media_config_json).unwrap_or_else(|_| json!({})),
+          row.get(3)?,
+          row.get(4)?,
+        ))
+      },
+    )
+    .optional()
+    .map_err(|e| e.to_string())
+}
+
+async fn fetch_oidc_issuer(client: &MatrixClient) -> Option<String> {
+  let metadata_url = format!("{}/_matrix/client/v1/auth_metadata", client.homeserver_url);
+  if let Ok(metadata) = client.request(reqwest::Method::GET, &metadata_url, &[], None).await {
+    if let Some(issuer) = metadata.get("issuer").and_then(|v| v.as_str()) {
+      return Some(issuer.to_string());
+    }
+  }
+  let legacy_url = format!(
+    "{}/_matrix/client/unstable/org.matrix.msc2965/auth_issuer",
+    client.homeserver_url
+  );
+  client
+    .request(reqwest::Method::GET, &legacy_url, &[], None)
+    .await
+    .ok()
+    .and_then(|v| v.get("issuer").and_then(|i| i.as_str()).map(|s| s.to_string()))
+}
+
+pub struct FetchedCapabilities {
+  versions: Value,
+  capabilities: Value,
+  media_config: Value,
+  oidc_issuer: Option<String>,
+}
+
+/// Fetch `/versions`, `/capabilities`, the media config and OIDC discovery.
+pub async fn fetch(client: &MatrixClient) -> Result<FetchedCapabilities, ApiError> {
+  let versions = client
+    .request(
+      reqwest::Method::GET,
+      &format!("{}/_matrix/client/versions", client.homeserver_url),
+      &[],
+      None,
+    )
+    .await?;
+  let capabilities = client.get("/capabilities", &[]).await.unwrap_or_else(|_| json!({}));
+  let media_config = match client
+    .request(
+      reqwest::Method::GET,
+      &format!("{}/_matrix/client/v1/media/config", client.homeserver_url),
+      &[],
+      None,
+    )
+    .await
+  {
+    Ok(config) => config,
+    Err(_) => client
+      .request(reqwest::Method::GET, &client.media_url("/config"), &[], None)
+      .await
+      .unwrap_or_else(|_| json!({})),
+  };
+  let oidc_issuer = fetch_oidc_issuer(client).await;
+  Ok(FetchedCapabilities {
+    versions,
+    capabilities,
+    media_config,
+    oidc_issuer,
+  })
+}
+
+pub fn store(conn: &Connection, homeserver_url: &str, fetched: &FetchedCapabilities) -> Result<ServerCapabilities, String> {
+  let fetched_at = now_millis();
+  conn
+    .execute(
+      "INSERT INTO server_capabilities (homeserver_url, versions_json, capabilities_json, media_config_json, oidc_issuer, fetched_at)
+       VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+       ON CONFLICT(homeserver_url) DO UPDATE SET
+         versions_json = excluded.versions_json,
+         capabilities_json = excluded.capabilities_json,
+         media_config_json = excluded.media_config_json,
+         oidc_issuer = excluded.oidc_issuer,
+         fetched_at = excluded.fetched_at",
+      params![
+        homeserver_url,
+        fetched.versions.to_string(),
+        fetched.capabilities.to_string(),
+        fetched.media_config.to_string(),
+        fetched.oidc_issuer,
+        fetched_at,
+      ],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(build(
+    homeserver_url,
+    &fetched.versions,
+    &fetched.capabilities,
+    &fetched.media_config,
+    fetched.oidc_issuer.clone(),
+    fetched_at,
+  ))
+}
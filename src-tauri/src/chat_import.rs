@@ -0,0 +1,401 @@
+use chrono::{Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// Key under which posted messages keep their original sender and time.
+pub const IMPORT_FIELD: &str = "com.matrix_messenger.import";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ArchiveSource {
+  Telegram,
+  WhatsApp,
+}
+
+impl ArchiveSource {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      ArchiveSource::Telegram => "telegram",
+      ArchiveSource::WhatsApp => "whatsapp",
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ImportMode {
+  /// Send every message into the room, attributed in the body the way bridges do.
+  #[default]
+  Post,
+  /// Keep the history on this device only, searchable through the local index.
+  Local,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ArchivedMessage {
+  pub id: String,
+  pub sender: String,
+  pub timestamp: i64,
+  pub text: String,
+  /// Attachment path relative to the archive folder.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub attachment: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatArchive {
+  pub source: ArchiveSource,
+  pub name: String,
+  pub root: PathBuf,
+  pub messages: Vec<ArchivedMessage>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportProgress {
+  pub import_id: String,
+  pub room_id: String,
+  pub completed: usize,
+  pub failed: usize,
+  pub total: usize,
+  pub done: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportResult {
+  pub import_id: String,
+  pub source: ArchiveSource,
+  pub chat_name: String,
+  pub messages: usize,
+  pub media_files: usize,
+  pub failed: usize,
+}
+
+/// Open a Telegram `result.json`, a WhatsApp `.txt`, a folder holding either, or a zipped
+/// WhatsApp export (unpacked into `extract_dir`).
+pub fn open(path: &Path, extract_dir: &Path) -> Result<ChatArchive, String> {
+  if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("zip")) {
+    unzip(path, extract_dir)?;
+    return open(extract_dir, extract_dir);
+  }
+  let file = if path.is_dir() { find_chat_file(path)? } else { path.to_path_buf() };
+  let root = file.parent().map(Path::to_path_buf).unwrap_or_default();
+  let text = fs::read_to_string(&file).map_err(|e| format!("Failed to read {}: {}", file.display(), e))?;
+  if file.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("json")) {
+    let (name, messages) = parse_telegram(&text)?;
+    return Ok(ChatArchive { source: ArchiveSource::Telegram, name, root, messages });
+  }
+  let name = file
+    .file_stem()
+    .map(|stem| stem.to_string_lossy().trim_start_matches("WhatsApp Chat with ").trim_start_matches('_').to_string())
+    .unwrap_or_default();
+  Ok(ChatArchive {
+    source: ArchiveSource::WhatsApp,
+    name,
+    root,
+    messages: parse_whatsapp(&text)?,
+  })
+}
+
+fn find_chat_file(dir: &Path) -> Result<PathBuf, String> {
+  let telegram = dir.join("result.json");
+  if telegram.exists() {
+    return Ok(telegram);
+  }
+  fs::read_dir(dir)
+    .map_err(|e| e.to_string())?
+    .flatten()
+    .map(|entry| entry.path())
+    .find(|path| path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("txt")))
+    .ok_or_else(|| "No Telegram result.json or WhatsApp chat .txt in this folder".to_string())
+}
+
+fn unzip(path: &Path, dest: &Path) -> Result<(), String> {
+  let file = fs::File::open(path).map_err(|e| e.to_string())?;
+  let mut archive = zip::ZipArchive::new(file).map_err(|e| format!("Invalid zip archive: {}", e))?;
+  for index in 0..archive.len() {
+    let mut entry = archive.by_index(index).map_err(|e| e.to_string())?;
+    let Some(relative) = entry.enclosed_name() else {
+      continue;
+    };
+    let target = dest.join(relative);
+    if entry.is_dir() {
+      fs::create_dir_all(&target).map_err(|e| e.to_string())?;
+      continue;
+    }
+    if let Some(parent) = target.parent() {
+      fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    let mut bytes = Vec::new();
+    entry.read_to_end(&mut bytes).map_err(|e| e.to_string())?;
+    fs::write(&target, bytes).map_err(|e| e.to_string())?;
+  }
+  Ok(())
+}
+
+/// Telegram's `text` is either a string or a list of strings and `{ type, text }` entities.
+fn telegram_text(value: Option<&Value>) -> String {
+  match value {
+    Some(Value::String(text)) => text.clone(),
+    Some(Value::Array(parts)) => parts
+      .iter()
+      .map(|part| match part {
+        Value::String(text) => text.as_str(),
+        other => other.get("text").and_then(|v| v.as_str()).unwrap_or_default(),
+      })
+      .collect(),
+    _ => String::new(),
+  }
+}
+
+/// A single-chat Telegram Desktop export ("Export chat history" as JSON).
+pub fn parse_telegram(text: &str) -> Result<(String, Vec<ArchivedMessage>), String> {
+  let root: Value = serde_json::from_str(text).map_err(|e| format!("Invalid Telegram export: {}", e))?;
+  let chat = match root.pointer("/chats/list").and_then(|v| v.as_array()) {
+    Some(chats) if chats.len() == 1 => &chats[0],
+    Some(_) => return Err("This is a full account export; export a single chat instead".into()),
+    None => &root,
+  };
+  let items = chat
+    .get("messages")
+    .and_then(|v| v.as_array())
+    .ok_or("Invalid Telegram export: no messages")?;
+  let messages = items
+    .iter()
+    .filter(|item| item.get("type").and_then(|v| v.as_str()) == Some("message"))
+    .map(|item| {
+      let timestamp = item
+        .get("date_unixtime")
+        .and_then(|v| v.as_str())
+        .and_then(|s| s.parse::<i64>().ok())
+        .map(|secs| secs * 1000)
+        .or_else(|| {
+          let date = item.get("date")?.as_str()?;
+          let naive = NaiveDateTime::parse_from_str(date, "%Y-%m-%dT%H:%M:%S").ok()?;
+          Local.from_local_datetime(&naive).earliest().map(|dt| dt.timestamp_millis())
+        })
+        .unwrap_or_default();
+      // Files left out of the export are recorded as a placeholder sentence.
+      let attachment = ["photo", "file"]
+        .iter()
+        .find_map(|key| item.get(*key).and_then(|v| v.as_str()))
+        .filter(|path| !path.starts_with('('))
+        .map(|path| path.to_string());
+      ArchivedMessage {
+        id: item.get("id").map(|v| v.to_string()).unwrap_or_default(),
+        sender: item
+          .get("from")
+          .and_then(|v| v.as_str())
+          .or_else(|| item.get("actor").and_then(|v| v.as_str()))
+          .unwrap_or("Unknown")
+          .to_string(),
+        timestamp,
+        text: telegram_text(item.get("text")),
+        attachment,
+      }
+    })
+    .filter(|message| !message.text.is_empty() || message.attachment.is_some())
+    .collect();
+  let name = chat.get("name").and_then(|v| v.as_str()).unwrap_or("Telegram chat").to_string();
+  Ok((name, messages))
+}
+
+fn line_pattern() -> &'static Regex {
+  static PATTERN: OnceLock<Regex> = OnceLock::new();
+  PATTERN.get_or_init(|| {
+    Regex::new(
+      r"^\u{200e}?\[?(\d{1,4})[./-](\d{1,2})[./-](\d{1,4}),?\s(\d{1,2}):(\d{2})(?::(\d{2}))?(?:\s?([AaPp])\.?\s?[Mm]\.?)?\]?(?:\s-)?\s(.*)$",
+    )
+    .expect("valid WhatsApp line pattern")
+  })
+}
+
+fn attachment_pattern() -> &'static Regex {
+  static PATTERN: OnceLock<Regex> = OnceLock::new();
+  PATTERN.get_or_init(|| {
+    Regex::new(r"^\u{200e}?(?:<attached: (.+)>|(.+?) \(file attached\))\s*$").expect("valid attachment pattern")
+  })
+}
+
+struct WhatsAppLine {
+  parts: [u32; 3],
+  time: (u32, u32, u32),
+  pm: Option<bool>,
+  rest: String,
+}
+
+/// Exports use the phone's date format, so day/month order is inferred from the whole file.
+fn to_timestamp(line: &WhatsAppLine, day_first: bool) -> Option<i64> {
+  let [a, b, c] = line.parts;
+  let (year, month, day) = if a > 999 {
+    (a, b, c)
+  } else {
+    let year = if c < 100 { c + 2000 } else { c };
+    if day_first { (year, b, a) } else { (year, a, b) }
+  };
+  let (mut hour, minute, second) = line.time;
+  match line.pm {
+    Some(true) if hour < 12 => hour += 12,
+    Some(false) if hour == 12 => hour = 0,
+    _ => {}
+  }
+  let naive = NaiveDate::from_ymd_opt(year as i32, month, day)?.and_time(NaiveTime::from_hms_opt(hour, minute, second)?);
+  Local.from_local_datetime(&naive).earliest().map(|dt| dt.timestamp_millis())
+}
+
+/// WhatsApp's "Export chat" text, from Android (`31/12/23, 21:41 - Name: text`) or iOS
+/// (`[31.12.23, 21:41:05] Name: text`). Lines without a header continue the previous message.
+pub fn parse_whatsapp(text: &str) -> Result<Vec<ArchivedMessage>, String> {
+  let mut lines: Vec<WhatsAppLine> = Vec::new();
+  for raw in text.lines() {
+    match line_pattern().captures(raw) {
+      Some(caps) => {
+        let number = |i: usize| caps.get(i).and_then(|m| m.as_str().parse::<u32>().ok()).unwrap_or(0);
+        lines.push(WhatsAppLine {
+          parts: [number(1), number(2), number(3)],
+          time: (number(4), number(5), number(6)),
+          pm: caps.get(7).map(|m| m.as_str().eq_ignore_ascii_case("p")),
+          rest: caps.get(8).map(|m| m.as_str().to_string()).unwrap_or_default(),
+        });
+      }
+      None => {
+        if let Some(last) = lines.last_mut() {
+          last.rest.push('\n');
+          last.rest.push_str(raw);
+        }
+      }
+    }
+  }
+  if lines.is_empty() {
+    return Err("Not a WhatsApp chat export".into());
+  }
+  let day_first = lines.iter().any(|line| line.parts[0] > 12 && line.parts[0] < 100)
+    || !lines.iter().any(|line| line.parts[1] > 12);
+  let messages = lines
+    .iter()
+    .enumerate()
+    .filter_map(|(index, line)| {
+      // Lines without `Name: ` are system notices (encryption, group changes).
+      let (sender, body) = line.rest.split_once(": ")?;
+      let body = body.trim_start_matches('\u{200e}');
+      let attachment = attachment_pattern().captures(body.lines().next().unwrap_or_default()).and_then(|caps| {
+        caps.get(1).or_else(|| caps.get(2)).map(|m| m.as_str().trim().to_string())
+      });
+      let text = match &attachment {
+        Some(_) => body.split_once('\n').map(|(_, caption)| caption.trim().to_string()).unwrap_or_default(),
+        None => body.to_string(),
+      };
+      Some(ArchivedMessage {
+        id: index.to_string(),
+        sender: sender.trim_start_matches('\u{200e}').trim().to_string(),
+        timestamp: to_timestamp(line, day_first).unwrap_or_default(),
+        text,
+        attachment,
+      })
+    })
+    .collect();
+  Ok(messages)
+}
+
+/// Matches the webview's index tokenizer, so imported history is found by the same queries.
+pub fn tokenize(text: &str, sender: &str) -> Vec<String> {
+  let mut tokens: Vec<String> = Vec::new();
+  for token in text
+    .to_lowercase()
+    .split(|c: char| !(c.is_alphanumeric() || matches!(c, '@' | '#' | ':' | '+')))
+    .filter(|part| !part.is_empty())
+    .map(str::to_string)
+    .chain(std::iter::once(sender.to_lowercase()))
+  {
+    if !tokens.contains(&token) {
+      tokens.push(token);
+    }
+  }
+  tokens
+}
+
+/// `m.image`, `m.video`, `m.audio` or `m.file`, with the file's MIME type.
+pub fn media_kind(path: &Path) -> (&'static str, String) {
+  let mime = mime_guess::from_path(path).first_or_octet_stream().essence_str().to_string();
+  let kind = match mime.split('/').next() {
+    Some("image") => "m.image",
+    Some("video") => "m.video",
+    Some("audio") => "m.audio",
+    _ => "m.file",
+  };
+  (kind, mime)
+}
+
+/// Stable ID for a message kept in the local index only.
+pub fn local_event_id(source: ArchiveSource, chat_name: &str, message: &ArchivedMessage) -> String {
+  format!("$import-{}-{:x}-{}", source.as_str(), sha_prefix(chat_name), message.id)
+}
+
+fn sha_prefix(value: &str) -> u64 {
+  use sha2::{Digest, Sha256};
+  let digest = Sha256::digest(value.as_bytes());
+  u64::from_be_bytes(digest[..8].try_into().unwrap_or_default())
+}
+
+fn escape(text: &str) -> String {
+  text
+    .replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+}
+
+fn provenance(source: ArchiveSource, message: &ArchivedMessage) -> Value {
+  json!({
+    "source": source.as_str(),
+    "sender": message.sender,
+    "timestamp": message.timestamp,
+    "id": message.id,
+  })
+}
+
+/// A text message attributed to its original sender, as bridges post them.
+pub fn text_content(source: ArchiveSource, message: &ArchivedMessage) -> Value {
+  let time = Local
+    .timestamp_millis_opt(message.timestamp)
+    .single()
+    .map(|dt| dt.format("%Y-%m-%d %H:%M").to_string())
+    .unwrap_or_default();
+  json!({
+    "msgtype": "m.text",
+    "body": format!("[{}] {}: {}", time, message.sender, message.text),
+    "format": "org.matrix.custom.html",
+    "formatted_body": format!(
+      "<small>{}</small> <strong>{}</strong>: {}",
+      escape(&time),
+      escape(&message.sender),
+      escape(&message.text).replace('\n', "<br>")
+    ),
+    IMPORT_FIELD: provenance(source, message),
+  })
+}
+
+pub fn media_content(
+  source: ArchiveSource,
+  message: &ArchivedMessage,
+  kind: &str,
+  mime: &str,
+  mxc_url: &str,
+  file_name: &str,
+  size: u64,
+) -> Value {
+  json!({
+    "msgtype": kind,
+    "body": file_name,
+    "url": mxc_url,
+    "info": { "mimetype": mime, "size": size },
+    IMPORT_FIELD: provenance(source, message),
+  })
+}
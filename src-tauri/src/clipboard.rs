@@ -0,0 +1,50 @@
+use image::{DynamicImage, ImageFormat, RgbaImage};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+
+use crate::matrix_api::now_millis;
+use crate::media_upload::{self, ImageMetadata};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClipboardImage {
+  pub path: String,
+  pub file_name: String,
+  pub mimetype: String,
+  pub size: u64,
+  #[serde(flatten)]
+  pub metadata: ImageMetadata,
+}
+
+/// Grab the image on the OS clipboard as a PNG in `out_dir`. Returns `None` when the
+/// clipboard holds no image.
+pub fn read_image(out_dir: &Path) -> Result<Option<ClipboardImage>, String> {
+  let mut clipboard = arboard::Clipboard::new().map_err(|e| format!("Clipboard is unavailable: {}", e))?;
+  let data = match clipboard.get_image() {
+    Ok(data) => data,
+    Err(arboard::Error::ContentNotAvailable) => return Ok(None),
+    Err(err) => return Err(format!("Failed to read the clipboard: {}", err)),
+  };
+  let rgba = RgbaImage::from_raw(data.width as u32, data.height as u32, data.bytes.into_owned())
+    .ok_or("Clipboard image has an unexpected size")?;
+  let img = DynamicImage::ImageRgba8(rgba);
+  let mut png = Vec::new();
+  img
+    .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+    .map_err(|e| format!("Failed to encode image: {}", e))?;
+  let metadata = media_upload::image_metadata(&png).ok_or("Failed to read the clipboard image")?;
+
+  fs::create_dir_all(out_dir).map_err(|e| e.to_string())?;
+  let file_name = format!("clipboard-{}.png", now_millis());
+  let path = out_dir.join(&file_name);
+  fs::write(&path, &png).map_err(|e| e.to_string())?;
+  Ok(Some(ClipboardImage {
+    path: path.display().to_string(),
+    file_name,
+    mimetype: "image/png".to_string(),
+    size: png.len() as u64,
+    metadata,
+  }))
+}
@@ -0,0 +1,145 @@
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+use crate::matrix_api::MatrixClient;
+
+/// A wall clock jump this much larger than the poll interval means the machine slept.
+pub const RESUME_GAP_MS: i64 = 30_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ConnectivityEvent {
+  Suspending,
+  Resumed,
+  NetworkChanged,
+  Offline,
+}
+
+/// Shared with the sync loops: parks them while suspended and cuts their backoff short on wake.
+#[derive(Default)]
+pub struct WakeSignal {
+  suspended: AtomicBool,
+  notify: Notify,
+}
+
+impl WakeSignal {
+  pub fn suspend(&self) {
+    self.suspended.store(true, Ordering::SeqCst);
+    self.notify.notify_waiters();
+  }
+
+  /// Resume (or note a network switch): parked loops continue and pending retries fire now.
+  pub fn wake(&self) {
+    self.suspended.store(false, Ordering::SeqCst);
+    self.notify.notify_waiters();
+  }
+
+  pub fn is_suspended(&self) -> bool {
+    self.suspended.load(Ordering::SeqCst)
+  }
+
+  pub async fn woken(&self) {
+    self.notify.notified().await
+  }
+
+  pub async fn wait_resumed(&self) {
+    loop {
+      let notified = self.notify.notified();
+      tokio::pin!(notified);
+      notified.as_mut().enable();
+      if !self.is_suspended() {
+        return;
+      }
+      notified.await;
+    }
+  }
+}
+
+#[derive(Default)]
+pub struct ConnectivityState {
+  pub signal: Arc<WakeSignal>,
+  route: Mutex<Option<Option<IpAddr>>>,
+}
+
+impl ConnectivityState {
+  /// Record the current route and return the event it implies, if it changed.
+  pub fn update_route(&self, address: Option<IpAddr>) -> Option<ConnectivityEvent> {
+    let mut route = self.route.lock().ok()?;
+    let previous = route.replace(address)?;
+    if previous == address {
+      return None;
+    }
+    Some(match address {
+      Some(_) => ConnectivityEvent::NetworkChanged,
+      None => ConnectivityEvent::Offline,
+    })
+  }
+}
+
+/// The local address of the default route; `None` when there is no route at all. Connecting a
+/// UDP socket sends nothing, it only selects the interface.
+pub fn default_route() -> Option<IpAddr> {
+  let probe = |bind: &str, target: &str| {
+    let socket = UdpSocket::bind(bind).ok()?;
+    socket.connect(target).ok()?;
+    socket.local_addr().ok().map(|addr| addr.ip())
+  };
+  probe("0.0.0.0:0", "192.0.2.1:9").or_else(|| probe("[::]:0", "[2001:db8::1]:9"))
+}
+
+/// Exchange the refresh token when the server no longer accepts the access token. Returns the
+/// new access token and, when rotated, the new refresh token.
+pub async fn refresh_if_expired(
+  client: &MatrixClient,
+  refresh_token: &str,
+) -> Result<Option<(String, Option<String>)>, String> {
+  match client.get("/account/whoami", &[]).await {
+    Ok(_) => return Ok(None),
+    Err(err) if err.errcode() != Some("M_UNKNOWN_TOKEN") => return Err(err.to_string()),
+    Err(_) => {}
+  }
+  let response = client
+    .http()
+    .post(client.client_url("/refresh"))
+    .json(&serde_json::json!({ "refresh_token": refresh_token }))
+    .send()
+    .await
+    .map_err(|e| e.to_string())?;
+  if !response.status().is_success() {
+    return Err(format!("Token refresh failed: HTTP {}", response.status()));
+  }
+  let body: serde_json::Value = response.json().await.map_err(|e| e.to_string())?;
+  let access_token = body
+    .get("access_token")
+    .and_then(|v| v.as_str())
+    .ok_or("Token refresh returned no access token")?;
+  let refresh_token = body.get("refresh_token").and_then(|v| v.as_str()).map(|s| s.to_string());
+  Ok(Some((access_token.to_string(), refresh_token)))
+}
+
+/// Suspend and resume notifications from logind, so sync is parked before the machine sleeps.
+#[cfg(target_os = "linux")]
+pub async fn watch_sleep(on_event: impl Fn(ConnectivityEvent)) -> Result<(), String> {
+  use futures_util::StreamExt;
+
+  let connection = zbus::Connection::system().await.map_err(|e| format!("D-Bus system bus unavailable: {}", e))?;
+  let proxy = zbus::Proxy::new(
+    &connection,
+    "org.freedesktop.login1",
+    "/org/freedesktop/login1",
+    "org.freedesktop.login1.Manager",
+  )
+  .await
+  .map_err(|e| e.to_string())?;
+  let mut signals = proxy.receive_signal("PrepareForSleep").await.map_err(|e| e.to_string())?;
+  while let Some(message) = signals.next().await {
+    let Ok(sleeping) = message.body().deserialize::<bool>() else {
+      continue;
+    };
+    on_event(if sleeping { ConnectivityEvent::Suspending } else { ConnectivityEvent::Resumed });
+  }
+  Ok(())
+}
@@ -0,0 +1,456 @@
+use base64::{engine::general_purpose, Engine as _};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+use crate::account_data;
+use crate::directory;
+use crate::matrix_api::{encode, MatrixClient};
+
+/// Identity server lookups are sent in batches of this many hashes.
+const LOOKUP_BATCH: usize = 500;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Contact {
+  pub name: String,
+  #[serde(default)]
+  pub emails: Vec<String>,
+  #[serde(default)]
+  pub phones: Vec<String>,
+}
+
+/// A contact that resolved to a Matrix user.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SuggestedContact {
+  pub contact_name: String,
+  pub user_id: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub display_name: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub avatar_url: Option<String>,
+  /// `email` or `msisdn` for identity server matches.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub medium: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub address: Option<String>,
+  /// `identityServer`, or `directory` when only the name matched a directory entry.
+  pub source: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ContactMatchResult {
+  pub contacts: usize,
+  pub suggestions: Vec<SuggestedContact>,
+  pub unmatched: Vec<Contact>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub identity_server: Option<String>,
+  /// Why identity server matching was skipped or failed; directory matching still ran.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub identity_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InviteOutcome {
+  pub user_id: String,
+  pub invited: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub error: Option<String>,
+}
+
+fn unescape(value: &str) -> String {
+  let mut out = String::with_capacity(value.len());
+  let mut chars = value.chars();
+  while let Some(c) = chars.next() {
+    if c != '\\' {
+      out.push(c);
+      continue;
+    }
+    match chars.next() {
+      Some('n') | Some('N') => out.push('\n'),
+      Some(other) => out.push(other),
+      None => {}
+    }
+  }
+  out
+}
+
+/// vCard 2.1 `ENCODING=QUOTED-PRINTABLE` values.
+fn decode_quoted_printable(value: &str) -> String {
+  let bytes = value.as_bytes();
+  let mut out = Vec::with_capacity(bytes.len());
+  let mut i = 0;
+  while i < bytes.len() {
+    if bytes[i] == b'=' {
+      if let Some(byte) = value.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+        out.push(byte);
+        i += 3;
+        continue;
+      }
+    }
+    out.push(bytes[i]);
+    i += 1;
+  }
+  String::from_utf8_lossy(&out).to_string()
+}
+
+/// Unfold continuation lines (RFC 6350 §3.2) and vCard 2.1 soft line breaks.
+fn unfold(text: &str) -> Vec<String> {
+  let mut lines: Vec<String> = Vec::new();
+  for raw in text.lines() {
+    let line = raw.trim_end_matches('\r');
+    match lines.last_mut() {
+      Some(last) if line.starts_with(' ') || line.starts_with('\t') => last.push_str(&line[1..]),
+      Some(last) if last.ends_with('=') && last.to_ascii_uppercase().contains("QUOTED-PRINTABLE") => {
+        last.pop();
+        last.push_str(line);
+      }
+      _ => lines.push(line.to_string()),
+    }
+  }
+  lines
+}
+
+/// Contacts from one or more vCards (versions 2.1, 3.0 and 4.0). Cards without a name, email
+/// or phone number are dropped.
+pub fn parse_vcards(text: &str) -> Vec<Contact> {
+  let mut contacts = Vec::new();
+  let mut current: Option<(Contact, Option<String>)> = None;
+  for line in unfold(text) {
+    let Some((head, value)) = line.split_once(':') else {
+      continue;
+    };
+    let mut params = head.split(';');
+    let name = params.next().unwrap_or_default();
+    // Apple and Google prefix grouped properties, e.g. `item1.EMAIL`.
+    let name = name.rsplit('.').next().unwrap_or(name).to_ascii_uppercase();
+    let quoted = params.any(|p| p.to_ascii_uppercase().contains("QUOTED-PRINTABLE"));
+    let value = if quoted { decode_quoted_printable(value) } else { value.to_string() };
+    match name.as_str() {
+      "BEGIN" if value.eq_ignore_ascii_case("VCARD") => current = Some((Contact::default(), None)),
+      "END" if value.eq_ignore_ascii_case("VCARD") => {
+        if let Some((mut contact, structured)) = current.take() {
+          if contact.name.is_empty() {
+            contact.name = structured
+              .or_else(|| contact.emails.first().cloned())
+              .or_else(|| contact.phones.first().cloned())
+              .unwrap_or_default();
+          }
+          if !contact.name.is_empty() || !contact.emails.is_empty() || !contact.phones.is_empty() {
+            contacts.push(contact);
+          }
+        }
+      }
+      "FN" => {
+        if let Some((contact, _)) = current.as_mut() {
+          contact.name = unescape(&value).trim().to_string();
+        }
+      }
+      "N" => {
+        if let Some((_, structured)) = current.as_mut() {
+          // Family;Given;Additional;Prefix;Suffix
+          let parts: Vec<String> = value.split(';').map(|p| unescape(p).trim().to_string()).collect();
+          let given = parts.get(1).cloned().unwrap_or_default();
+          let family = parts.first().cloned().unwrap_or_default();
+          let full = format!("{} {}", given, family).trim().to_string();
+          if !full.is_empty() {
+            *structured = Some(full);
+          }
+        }
+      }
+      "EMAIL" => {
+        if let Some((contact, _)) = current.as_mut() {
+          let email = unescape(&value).trim().to_string();
+          if email.contains('@') && !contact.emails.contains(&email) {
+            contact.emails.push(email);
+          }
+        }
+      }
+      "TEL" => {
+        if let Some((contact, _)) = current.as_mut() {
+          let phone = unescape(value.trim_start_matches("tel:")).trim().to_string();
+          if !phone.is_empty() && !contact.phones.contains(&phone) {
+            contact.phones.push(phone);
+          }
+        }
+      }
+      _ => {}
+    }
+  }
+  contacts
+}
+
+/// vCards of everyone in the system address book. Only macOS exposes one to command-line tools;
+/// elsewhere contacts have to be exported to a .vcf file first.
+#[cfg(target_os = "macos")]
+pub fn read_address_book() -> Result<String, String> {
+  let output = std::process::Command::new("osascript")
+    .args(["-e", "tell application \"Contacts\" to get vcard of every person"])
+    .output()
+    .map_err(|e| e.to_string())?;
+  if !output.status.success() {
+    return Err(format!(
+      "Contacts access was denied: {}",
+      String::from_utf8_lossy(&output.stderr).trim()
+    ));
+  }
+  // AppleScript joins the list items with ", ".
+  Ok(
+    String::from_utf8_lossy(&output.stdout)
+      .lines()
+      .map(|line| line.trim_start_matches(", "))
+      .collect::<Vec<_>>()
+      .join("\n"),
+  )
+}
+
+#[cfg(not(target_os = "macos"))]
+pub fn read_address_book() -> Result<String, String> {
+  Err("The system address book isn't available on this platform; export your contacts to a .vcf file".into())
+}
+
+pub fn normalize_email(email: &str) -> Option<String> {
+  let email = email.trim().trim_start_matches("mailto:").to_lowercase();
+  (email.contains('@') && !email.contains(' ')).then_some(email)
+}
+
+/// E.164 digits without the plus, as identity servers expect for `msisdn`. Numbers without an
+/// international prefix need `default_country_code` (e.g. `49`); a national leading 0 is dropped.
+pub fn normalize_phone(phone: &str, default_country_code: Option<&str>) -> Option<String> {
+  let trimmed = phone.trim();
+  let digits: String = trimmed.chars().filter(|c| c.is_ascii_digit()).collect();
+  let international = if trimmed.starts_with('+') {
+    digits
+  } else if let Some(rest) = digits.strip_prefix("00") {
+    rest.to_string()
+  } else {
+    let code: String = default_country_code?.chars().filter(|c| c.is_ascii_digit()).collect();
+    if code.is_empty() {
+      return None;
+    }
+    format!("{}{}", code, digits.trim_start_matches('0'))
+  };
+  (8..=15).contains(&international.len()).then_some(international)
+}
+
+/// The identity server the account uses: its `m.identity_server` account data, then the
+/// homeserver's `.well-known/matrix/client`.
+pub async fn identity_server(client: &MatrixClient) -> Result<Option<String>, String> {
+  if let Some(content) = account_data::fetch(client, "m.identity_server", None).await? {
+    // An explicit `null` means the user opted out of identity servers.
+    return Ok(content
+      .get("base_url")
+      .and_then(|v| v.as_str())
+      .map(|s| s.trim_end_matches('/').to_string()));
+  }
+  let well_known: Option<Value> = match client
+    .http()
+    .get(format!("{}/.well-known/matrix/client", client.homeserver_url))
+    .send()
+    .await
+  {
+    Ok(response) if response.status().is_success() => response.json().await.ok(),
+    _ => None,
+  };
+  Ok(well_known
+    .and_then(|v| v.get("m.identity_server")?.get("base_url")?.as_str().map(|s| s.trim_end_matches('/').to_string())))
+}
+
+async fn identity_request(request: reqwest::RequestBuilder, base_url: &str) -> Result<Value, String> {
+  let response = request.send().await.map_err(|e| format!("{}: {}", base_url, e))?;
+  let status = response.status();
+  let body: Value = response.json().await.unwrap_or(Value::Null);
+  if !status.is_success() {
+    let errcode = body.get("errcode").and_then(|v| v.as_str()).unwrap_or_default();
+    if errcode == "M_TERMS_NOT_SIGNED" {
+      return Err("Accept the identity server's terms of service first".into());
+    }
+    return Err(format!(
+      "Identity server error {}: {}",
+      status.as_u16(),
+      body.get("error").and_then(|v| v.as_str()).unwrap_or(errcode)
+    ));
+  }
+  Ok(body)
+}
+
+/// Matrix user IDs bound to the given `(medium, address)` pairs, via the identity server v2
+/// hashed lookup. Addresses never leave the device in plain text.
+pub async fn lookup(
+  client: &MatrixClient,
+  base_url: &str,
+  threepids: &[(String, String)],
+) -> Result<HashMap<(String, String), String>, String> {
+  let http = client.http();
+  let openid = client
+    .post(&format!("/user/{}/openid/request_token", encode(&client.user_id)), &json!({}))
+    .await?;
+  let registered = identity_request(
+    http.post(format!("{}/_matrix/identity/v2/account/register", base_url)).json(&openid),
+    base_url,
+  )
+  .await?;
+  let token = registered
+    .get("token")
+    .or_else(|| registered.get("access_token"))
+    .and_then(|v| v.as_str())
+    .ok_or("Identity server did not return a token")?
+    .to_string();
+  let details = identity_request(
+    http
+      .get(format!("{}/_matrix/identity/v2/hash_details", base_url))
+      .bearer_auth(&token),
+    base_url,
+  )
+  .await?;
+  let pepper = details.get("lookup_pepper").and_then(|v| v.as_str()).unwrap_or_default().to_string();
+  let supports_sha256 = details
+    .get("algorithms")
+    .and_then(|v| v.as_array())
+    .is_some_and(|algorithms| algorithms.iter().any(|a| a.as_str() == Some("sha256")));
+  if !supports_sha256 {
+    return Err("The identity server doesn't support hashed lookups".into());
+  }
+  let hashed: Vec<(String, &(String, String))> = threepids
+    .iter()
+    .map(|pair| {
+      let digest = Sha256::digest(format!("{} {} {}", pair.1, pair.0, pepper).as_bytes());
+      (general_purpose::URL_SAFE_NO_PAD.encode(digest), pair)
+    })
+    .collect();
+  let mut matches = HashMap::new();
+  for batch in hashed.chunks(LOOKUP_BATCH) {
+    let addresses: Vec<&str> = batch.iter().map(|(hash, _)| hash.as_str()).collect();
+    let response = identity_request(
+      http
+        .post(format!("{}/_matrix/identity/v2/lookup", base_url))
+        .bearer_auth(&token)
+        .json(&json!({ "addresses": addresses, "algorithm": "sha256", "pepper": pepper })),
+      base_url,
+    )
+    .await?;
+    let mappings = response.get("mappings").and_then(|v| v.as_object()).cloned().unwrap_or_default();
+    for (hash, pair) in batch {
+      if let Some(user_id) = mappings.get(hash).and_then(|v| v.as_str()) {
+        matches.insert((*pair).clone(), user_id.to_string());
+      }
+    }
+  }
+  let _ = http
+    .post(format!("{}/_matrix/identity/v2/account/logout", base_url))
+    .bearer_auth(&token)
+    .send()
+    .await;
+  Ok(matches)
+}
+
+/// Match contacts against the identity server, then look the rest up in the user directory by
+/// exact display name.
+pub async fn match_contacts(
+  client: &MatrixClient,
+  contacts: Vec<Contact>,
+  default_country_code: Option<&str>,
+) -> ContactMatchResult {
+  let mut threepids: Vec<(String, String)> = Vec::new();
+  for contact in &contacts {
+    threepids.extend(contact.emails.iter().filter_map(|e| normalize_email(e)).map(|e| ("email".to_string(), e)));
+    threepids.extend(
+      contact
+        .phones
+        .iter()
+        .filter_map(|p| normalize_phone(p, default_country_code))
+        .map(|p| ("msisdn".to_string(), p)),
+    );
+  }
+  threepids.sort();
+  threepids.dedup();
+  let (identity_server, mut identity_error) = match identity_server(client).await {
+    Ok(Some(url)) => (Some(url), None),
+    Ok(None) => (None, Some("No identity server is configured".to_string())),
+    Err(err) => (None, Some(err)),
+  };
+  let mut bound = HashMap::new();
+  if let (Some(base_url), false) = (identity_server.as_deref(), threepids.is_empty()) {
+    match lookup(client, base_url, &threepids).await {
+      Ok(found) => bound = found,
+      Err(err) => identity_error = Some(err),
+    }
+  }
+  let total = contacts.len();
+  let mut suggestions: Vec<SuggestedContact> = Vec::new();
+  let mut unmatched = Vec::new();
+  for contact in contacts {
+    let pairs = contact
+      .emails
+      .iter()
+      .filter_map(|e| normalize_email(e).map(|e| ("email".to_string(), e)))
+      .chain(
+        contact
+          .phones
+          .iter()
+          .filter_map(|p| normalize_phone(p, default_country_code).map(|p| ("msisdn".to_string(), p))),
+      );
+    let mut matched = false;
+    for pair in pairs {
+      if let Some(user_id) = bound.get(&pair) {
+        if !suggestions.iter().any(|s| &s.user_id == user_id) {
+          suggestions.push(SuggestedContact {
+            contact_name: contact.name.clone(),
+            user_id: user_id.clone(),
+            display_name: None,
+            avatar_url: None,
+            medium: Some(pair.0),
+            address: Some(pair.1),
+            source: "identityServer".to_string(),
+          });
+        }
+        matched = true;
+      }
+    }
+    if matched {
+      continue;
+    }
+    let name = contact.name.trim().to_lowercase();
+    let by_name = if name.is_empty() {
+      None
+    } else {
+      directory::fetch_user_directory(client, &contact.name)
+        .await
+        .ok()
+        .and_then(|(results, _)| {
+          let mut exact = results
+            .into_iter()
+            .filter(|r| r.display_name.as_deref().is_some_and(|d| d.trim().to_lowercase() == name));
+          // Several people sharing the name can't be told apart; leave the contact unmatched.
+          match (exact.next(), exact.next()) {
+            (Some(only), None) => Some(only),
+            _ => None,
+          }
+        })
+    };
+    match by_name {
+      Some(result) if !suggestions.iter().any(|s| s.user_id == result.user_id) => suggestions.push(SuggestedContact {
+        contact_name: contact.name.clone(),
+        user_id: result.user_id,
+        display_name: result.display_name,
+        avatar_url: result.avatar_url,
+        medium: None,
+        address: None,
+        source: "directory".to_string(),
+      }),
+      Some(_) => {}
+      None => unmatched.push(contact),
+    }
+  }
+  ContactMatchResult {
+    contacts: total,
+    suggestions,
+    unmatched,
+    identity_server,
+    identity_error,
+  }
+}
@@ -0,0 +1,223 @@
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::event_cache::{self, CachedEvent};
+use crate::matrix_api::now_millis;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DecryptionFailure {
+  pub event: Value,
+  #[serde(default)]
+  pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReceivedRoomKey {
+  pub room_id: String,
+  pub session_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UndecryptableEvent {
+  pub room_id: String,
+  pub event_id: String,
+  pub session_id: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub sender_key: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub error: Option<String>,
+  pub failed_at: i64,
+  pub attempts: i64,
+  /// The original `m.room.encrypted` event, ready to hand back to the crypto layer.
+  pub event: Value,
+}
+
+pub fn init_decryption_retry_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS undecryptable_events (
+        account_key TEXT NOT NULL,
+        room_id TEXT NOT NULL,
+        event_id TEXT NOT NULL,
+        session_id TEXT NOT NULL,
+        sender_key TEXT,
+        error TEXT,
+        failed_at INTEGER NOT NULL,
+        attempts INTEGER NOT NULL DEFAULT 0,
+        last_attempt_at INTEGER,
+        PRIMARY KEY (account_key, room_id, event_id)
+      );
+      CREATE INDEX IF NOT EXISTS idx_undecryptable_session ON undecryptable_events(account_key, room_id, session_id);
+    ",
+  )
+}
+
+/// Remember events that could not be decrypted, keyed by the Megolm session they need.
+/// The encrypted event is kept in the event cache so it can be retried without a refetch.
+pub fn record_failures(conn: &Connection, account_key: &str, room_id: &str, failures: &[DecryptionFailure]) -> Result<usize, String> {
+  let tx = conn.unchecked_transaction().map_err(|e| e.to_string())?;
+  let mut recorded = 0;
+  for failure in failures {
+    let event = match CachedEvent::from_raw(room_id, &failure.event) {
+      Some(event) if event.event_type == "m.room.encrypted" => event,
+      _ => continue,
+    };
+    let session_id = match event.content.get("session_id").and_then(|v| v.as_str()) {
+      Some(session_id) => session_id.to_string(),
+      None => continue,
+    };
+    let sender_key = event.content.get("sender_key").and_then(|v| v.as_str());
+    event_cache::upsert_event(&tx, account_key, &event)?;
+    tx.execute(
+      "INSERT INTO undecryptable_events (account_key, room_id, event_id, session_id, sender_key, error, failed_at)
+       VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+       ON CONFLICT(account_key, room_id, event_id) DO UPDATE SET error = excluded.error",
+      params![account_key, room_id, event.event_id, session_id, sender_key, failure.error, now_millis()],
+    )
+    .map_err(|e| e.to_string())?;
+    recorded += 1;
+  }
+  tx.commit().map_err(|e| e.to_string())?;
+  Ok(recorded)
+}
+
+fn load(
+  conn: &Connection,
+  account_key: &str,
+  sql: &str,
+  args: &[&dyn rusqlite::ToSql],
+) -> Result<Vec<UndecryptableEvent>, String> {
+  let mut stmt = conn.prepare(sql).map_err(|e| e.to_string())?;
+  let rows = stmt
+    .query_map(args, |row| {
+      Ok(UndecryptableEvent {
+        room_id: row.get(0)?,
+        event_id: row.get(1)?,
+        session_id: row.get(2)?,
+        sender_key: row.get(3)?,
+        error: row.get(4)?,
+        failed_at: row.get(5)?,
+        attempts: row.get(6)?,
+        event: Value::Null,
+      })
+    })
+    .map_err(|e| e.to_string())?;
+  let mut out = Vec::new();
+  for row in rows.flatten() {
+    let mut item = row;
+    if let Some(cached) = event_cache::get_event(conn, account_key, &item.room_id, &item.event_id)? {
+      item.event = cached.to_raw();
+      out.push(item);
+    }
+  }
+  Ok(out)
+}
+
+pub fn list(conn: &Connection, account_key: &str, room_id: Option<&str>) -> Result<Vec<UndecryptableEvent>, String> {
+  load(
+    conn,
+    account_key,
+    "SELECT room_id, event_id, session_id, sender_key, error, failed_at, attempts
+     FROM undecryptable_events
+     WHERE account_key = ?1 AND (?2 IS NULL OR room_id = ?2)
+     ORDER BY failed_at DESC",
+    &[&account_key, &room_id],
+  )
+}
+
+/// Events waiting on any of the received sessions; their attempt counters are bumped.
+pub fn take_retryable(conn: &Connection, account_key: &str, keys: &[ReceivedRoomKey]) -> Result<Vec<UndecryptableEvent>, String> {
+  let mut out = Vec::new();
+  for key in keys {
+    let pending = load(
+      conn,
+      account_key,
+      "SELECT room_id, event_id, session_id, sender_key, error, failed_at, attempts
+       FROM undecryptable_events
+       WHERE account_key = ?1 AND room_id = ?2 AND session_id = ?3
+       ORDER BY failed_at ASC",
+      &[&account_key, &key.room_id, &key.session_id],
+    )?;
+    if pending.is_empty() {
+      continue;
+    }
+    conn
+      .execute(
+        "UPDATE undecryptable_events SET attempts = attempts + 1, last_attempt_at = ?4
+         WHERE account_key = ?1 AND room_id = ?2 AND session_id = ?3",
+        params![account_key, key.room_id, key.session_id, now_millis()],
+      )
+      .map_err(|e| e.to_string())?;
+    out.extend(pending);
+  }
+  Ok(out)
+}
+
+/// Drop decrypted events from the queue and return the ids that were actually pending.
+/// Only the ciphertext stays cached: plaintext from encrypted rooms is never written to disk.
+pub fn resolve(conn: &Connection, account_key: &str, room_id: &str, decrypted: &[Value]) -> Result<Vec<String>, String> {
+  let tx = conn.unchecked_transaction().map_err(|e| e.to_string())?;
+  let mut resolved = Vec::new();
+  for raw in decrypted {
+    let event = match CachedEvent::from_raw(room_id, raw) {
+      Some(event) if event.event_type != "m.room.encrypted" => event,
+      _ => continue,
+    };
+    let removed = tx
+      .execute(
+        "DELETE FROM undecryptable_events WHERE account_key = ?1 AND room_id = ?2 AND event_id = ?3",
+        params![account_key, room_id, event.event_id],
+      )
+      .map_err(|e| e.to_string())?;
+    if removed > 0 {
+      resolved.push(event.event_id);
+    }
+  }
+  tx.commit().map_err(|e| e.to_string())?;
+  Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn resolving_keeps_plaintext_out_of_the_cache() {
+    let conn = Connection::open_in_memory().unwrap();
+    event_cache::init_event_cache_db(&conn).unwrap();
+    init_decryption_retry_db(&conn).unwrap();
+    let encrypted = json!({
+      "event_id": "$secret",
+      "type": "m.room.encrypted",
+      "sender": "@bob:example.org",
+      "origin_server_ts": 1,
+      "content": { "algorithm": "m.megolm.v1.aes-sha2", "session_id": "s1", "ciphertext": "..." },
+    });
+    let failure = DecryptionFailure { event: encrypted, error: None };
+    assert_eq!(record_failures(&conn, "alice", "!room", &[failure]).unwrap(), 1);
+
+    let decrypted = |event_id: &str| {
+      json!({
+        "event_id": event_id,
+        "type": "m.room.message",
+        "sender": "@bob:example.org",
+        "origin_server_ts": 1,
+        "content": { "msgtype": "m.text", "body": "top secret" },
+      })
+    };
+    let resolved = resolve(&conn, "alice", "!room", &[decrypted("$secret"), decrypted("$stranger")]).unwrap();
+    assert_eq!(resolved, vec!["$secret".to_string()]);
+
+    let cached = event_cache::get_event(&conn, "alice", "!room", "$secret").unwrap().unwrap();
+    assert_eq!(cached.event_type, "m.room.encrypted");
+    assert!(event_cache::get_event(&conn, "alice", "!room", "$stranger").unwrap().is_none());
+    let pending: i64 = conn
+      .query_row("SELECT COUNT(*) FROM undecryptable_events", [], |row| row.get(0))
+      .unwrap();
+    assert_eq!(pending, 0);
+  }
+}
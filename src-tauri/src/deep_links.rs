@@ -0,0 +1,165 @@
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+
+pub const SCHEME: &str = "matrix";
+const MATRIX_TO: &str = "https://matrix.to/#/";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum MatrixTarget {
+  #[serde(rename_all = "camelCase")]
+  Room { room_id: String },
+  Alias { alias: String },
+  #[serde(rename_all = "camelCase")]
+  User { user_id: String },
+}
+
+/// A room, alias or user from a `matrix:` URI or matrix.to link.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MatrixLink {
+  pub target: MatrixTarget,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub event_id: Option<String>,
+  /// Servers to join through, from `via` parameters.
+  pub via: Vec<String>,
+  /// `join` or `chat` from `matrix:` URIs.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub action: Option<String>,
+}
+
+/// A link that arrived before the webview was listening.
+#[derive(Default)]
+pub struct DeepLinkState {
+  pending: Mutex<Option<MatrixLink>>,
+}
+
+impl DeepLinkState {
+  pub fn set_pending(&self, link: MatrixLink) {
+    if let Ok(mut pending) = self.pending.lock() {
+      *pending = Some(link);
+    }
+  }
+
+  pub fn take_pending(&self) -> Option<MatrixLink> {
+    self.pending.lock().ok()?.take()
+  }
+}
+
+fn decode(part: &str) -> Option<String> {
+  urlencoding::decode(part).ok().map(|s| s.into_owned())
+}
+
+/// `sigil` + localpart + `:` + server name.
+fn valid_id(id: &str, sigil: char) -> bool {
+  id.strip_prefix(sigil)
+    .and_then(|rest| rest.split_once(':'))
+    .is_some_and(|(local, server)| !local.is_empty() && !server.is_empty() && !server.contains('/'))
+}
+
+fn target_for(id: &str) -> Option<MatrixTarget> {
+  match id.chars().next()? {
+    '!' if valid_id(id, '!') => Some(MatrixTarget::Room { room_id: id.to_string() }),
+    '#' if valid_id(id, '#') => Some(MatrixTarget::Alias { alias: id.to_string() }),
+    '@' if valid_id(id, '@') => Some(MatrixTarget::User { user_id: id.to_string() }),
+    _ => None,
+  }
+}
+
+/// `via` values and the `action` from a query string.
+fn query(query: Option<&str>) -> (Vec<String>, Option<String>) {
+  let mut via = Vec::new();
+  let mut action = None;
+  for pair in query.unwrap_or("").split('&') {
+    let Some((key, value)) = pair.split_once('=') else {
+      continue;
+    };
+    let Some(value) = decode(value).filter(|v| !v.is_empty()) else {
+      continue;
+    };
+    match key {
+      "via" => via.push(value),
+      "action" if value == "join" || value == "chat" => action = Some(value),
+      _ => {}
+    }
+  }
+  (via, action)
+}
+
+/// `matrix:r/alias:server`, `matrix:roomid/id:server/e/event`, `matrix:u/user:server?action=chat`.
+fn parse_uri(rest: &str) -> Option<MatrixLink> {
+  let rest = rest.trim_start_matches('/');
+  let rest = rest.split('#').next().unwrap_or(rest);
+  let (path, params) = match rest.split_once('?') {
+    Some((path, params)) => (path, Some(params)),
+    None => (rest, None),
+  };
+  let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+  let (sigil, id) = match segments.as_slice() {
+    [kind, id, ..] => (
+      match *kind {
+        "r" => '#',
+        "roomid" => '!',
+        "u" => '@',
+        _ => return None,
+      },
+      decode(id)?,
+    ),
+    _ => return None,
+  };
+  let target = target_for(&format!("{}{}", sigil, id))?;
+  let event_id = match &segments[2..] {
+    [] => None,
+    ["e", event] if !matches!(target, MatrixTarget::User { .. }) => Some(format!("${}", decode(event)?)),
+    _ => return None,
+  };
+  let (via, action) = query(params);
+  Some(MatrixLink {
+    target,
+    event_id,
+    via,
+    action,
+  })
+}
+
+/// `https://matrix.to/#/!room:server/$event?via=server`.
+fn parse_matrix_to(fragment: &str) -> Option<MatrixLink> {
+  let (path, params) = match fragment.split_once('?') {
+    Some((path, params)) => (path, Some(params)),
+    None => (fragment, None),
+  };
+  let mut segments = path.splitn(2, '/');
+  let target = target_for(&decode(segments.next()?)?)?;
+  let event_id = match segments.next().filter(|s| !s.is_empty()) {
+    Some(event) => {
+      let event = decode(event)?;
+      if !event.starts_with('$') || matches!(target, MatrixTarget::User { .. }) {
+        return None;
+      }
+      Some(event)
+    }
+    None => None,
+  };
+  let (via, _) = query(params);
+  Some(MatrixLink {
+    target,
+    event_id,
+    via,
+    action: None,
+  })
+}
+
+/// Parse a `matrix:` URI or a matrix.to link; anything else is `None`.
+pub fn parse(link: &str) -> Option<MatrixLink> {
+  let link = link.trim();
+  if let Some((scheme, rest)) = link.split_once(':') {
+    if scheme.eq_ignore_ascii_case(SCHEME) {
+      return parse_uri(rest);
+    }
+  }
+  let fragment = link
+    .strip_prefix(MATRIX_TO)
+    .or_else(|| link.strip_prefix("http://matrix.to/#/"))
+    .or_else(|| link.strip_prefix("https://www.matrix.to/#/"))?;
+  parse_matrix_to(fragment)
+}
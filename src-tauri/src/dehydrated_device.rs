@@ -0,0 +1,152 @@
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::matrix_api::{encode, ApiError, MatrixClient};
+
+const MSC3814_PREFIX: &str = "/_matrix/client/unstable/org.matrix.msc3814.v1";
+
+/// A device parked on the server (MSC3814) to collect room keys while no client is logged in.
+/// This module is only the transport: generating the device keys, pickling the Olm account
+/// with the dehydration key and unpickling it to decrypt `fetch_events` are left to the
+/// crypto layer that calls it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DehydratedDevice {
+  pub device_id: String,
+  /// Opaque pickle produced by the crypto layer; only it can rehydrate the device.
+  pub device_data: Value,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DehydratedDeviceUpload {
+  pub device_id: String,
+  pub device_data: Value,
+  pub device_keys: Value,
+  #[serde(default)]
+  pub one_time_keys: Option<Value>,
+  #[serde(default)]
+  pub fallback_keys: Option<Value>,
+  #[serde(default)]
+  pub initial_device_display_name: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DehydratedDeviceEvents {
+  pub device_id: String,
+  pub events: Vec<Value>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub next_batch: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Rehydration {
+  pub device: DehydratedDevice,
+  pub to_device: DehydratedDeviceEvents,
+}
+
+fn url(client: &MatrixClient, path: &str) -> String {
+  format!("{}{}{}", client.homeserver_url, MSC3814_PREFIX, path)
+}
+
+/// The account's current dehydrated device, or `None` when the server has none stored.
+pub async fn get(client: &MatrixClient) -> Result<Option<DehydratedDevice>, ApiError> {
+  match client
+    .request(Method::GET, &url(client, "/dehydrated_device"), &[], None)
+    .await
+  {
+    Ok(response) => Ok(Some(DehydratedDevice {
+      device_id: response
+        .get("device_id")
+        .and_then(|v| v.as_str())
+        .unwrap_or_default()
+        .to_string(),
+      device_data: response.get("device_data").cloned().unwrap_or(Value::Null),
+    })),
+    Err(err) if err.errcode() == Some("M_NOT_FOUND") => Ok(None),
+    Err(err) => Err(err),
+  }
+}
+
+/// Whether `device_data` looks like a pickle the crypto layer produced; MSC3814 requires
+/// an object naming its `algorithm`.
+pub fn is_pickled(device_data: &Value) -> bool {
+  device_data.get("algorithm").and_then(|v| v.as_str()).map(|a| !a.is_empty()).unwrap_or(false)
+}
+
+/// Store a freshly pickled device; the server replaces any previous dehydrated device.
+pub async fn upload(client: &MatrixClient, device: &DehydratedDeviceUpload) -> Result<String, ApiError> {
+  let mut body = json!({
+    "device_id": device.device_id,
+    "device_data": device.device_data,
+    "device_keys": device.device_keys,
+    "initial_device_display_name": device
+      .initial_device_display_name
+      .clone()
+      .unwrap_or_else(|| "Dehydrated device".to_string()),
+  });
+  if let Some(keys) = &device.one_time_keys {
+    body["one_time_keys"] = keys.clone();
+  }
+  if let Some(keys) = &device.fallback_keys {
+    body["fallback_keys"] = keys.clone();
+  }
+  let response = client
+    .request(Method::PUT, &url(client, "/dehydrated_device"), &[], Some(&body))
+    .await?;
+  Ok(response
+    .get("device_id")
+    .and_then(|v| v.as_str())
+    .unwrap_or(&device.device_id)
+    .to_string())
+}
+
+pub async fn delete(client: &MatrixClient) -> Result<(), ApiError> {
+  match client
+    .request(Method::DELETE, &url(client, "/dehydrated_device"), &[], None)
+    .await
+  {
+    Ok(_) => Ok(()),
+    Err(err) if err.errcode() == Some("M_NOT_FOUND") => Ok(()),
+    Err(err) => Err(err),
+  }
+}
+
+/// Drain the to-device events (room keys) queued for the dehydrated device while no client
+/// was online, following `next_batch` until the server returns an empty page.
+pub async fn fetch_events(client: &MatrixClient, device_id: &str, since: Option<String>) -> Result<DehydratedDeviceEvents, ApiError> {
+  let path = format!("/dehydrated_device/{}/events", encode(device_id));
+  let mut next_batch = since;
+  let mut events = Vec::new();
+  loop {
+    let body = match &next_batch {
+      Some(token) => json!({ "next_batch": token }),
+      None => json!({}),
+    };
+    let response = client
+      .request(Method::POST, &url(client, &path), &[], Some(&body))
+      .await?;
+    let page: Vec<Value> = response
+      .get("events")
+      .and_then(|v| v.as_array())
+      .cloned()
+      .unwrap_or_default();
+    let token = response.get("next_batch").and_then(|v| v.as_str()).map(|s| s.to_string());
+    let done = page.is_empty() || token.is_none() || token == next_batch;
+    events.extend(page);
+    if token.is_some() {
+      next_batch = token;
+    }
+    if done {
+      break;
+    }
+  }
+  Ok(DehydratedDeviceEvents {
+    device_id: device_id.to_string(),
+    events,
+    next_batch,
+  })
+}
@@ -1,7 +1,6 @@
 use serde::{Deserialize, Serialize};
 use ssh2::Session;
-use std::io::Read;
-use std::net::TcpStream;
+use std::io::{BufRead, BufReader};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeploymentConfig {
@@ -11,6 +10,106 @@ pub struct DeploymentConfig {
     pub domain: Option<String>,
     pub admin_username: String,
     pub admin_password: String,
+    /// Also set up coturn as the TURN/STUN server for voice and video calls.
+    #[serde(default)]
+    pub install_turn: bool,
+}
+
+/// UDP range coturn relays media through; opened in the firewall.
+const TURN_RELAY_PORTS: (u16, u16) = (49160, 49200);
+
+/// Peer ranges coturn refuses to relay to, so TURN can't be used to reach the host itself,
+/// its private network or cloud metadata endpoints.
+const TURN_DENIED_PEERS: &[&str] = &[
+    "0.0.0.0-0.255.255.255",
+    "10.0.0.0-10.255.255.255",
+    "100.64.0.0-100.127.255.255",
+    "127.0.0.0-127.255.255.255",
+    "169.254.0.0-169.254.255.255",
+    "172.16.0.0-172.31.255.255",
+    "192.0.0.0-192.0.0.255",
+    "192.0.2.0-192.0.2.255",
+    "192.88.99.0-192.88.99.255",
+    "192.168.0.0-192.168.255.255",
+    "198.18.0.0-198.19.255.255",
+    "198.51.100.0-198.51.100.255",
+    "203.0.113.0-203.0.113.255",
+    "240.0.0.0-255.255.255.255",
+    "::1",
+    "64:ff9b::-64:ff9b::ffff:ffff",
+    "::ffff:0.0.0.0-::ffff:255.255.255.255",
+    "100::-100::ffff:ffff:ffff:ffff",
+    "2001::-2001:1ff:ffff:ffff:ffff:ffff:ffff:ffff",
+    "2002::-2002:ffff:ffff:ffff:ffff:ffff:ffff:ffff",
+    "fc00::-fdff:ffff:ffff:ffff:ffff:ffff:ffff:ffff",
+    "fe80::-febf:ffff:ffff:ffff:ffff:ffff:ffff:ffff",
+];
+
+/// Shell steps that install and configure coturn. The shared secret is generated on the
+/// server into `$TURN_SECRET`, which the homeserver.yaml below picks up. No certificate is
+/// provisioned, so only plain TURN on 3478 is offered.
+fn turn_install_section(domain: &str) -> String {
+    let denied_peers: String = TURN_DENIED_PEERS
+        .iter()
+        .map(|range| format!("denied-peer-ip={}\n", range))
+        .collect();
+    format!(
+        r#"
+# Install coturn
+echo "[+] Installing coturn (TURN/STUN)..."
+sudo DEBIAN_FRONTEND=noninteractive apt install -y coturn openssl
+TURN_SECRET=$(openssl rand -hex 32)
+# Behind NAT the relay has to announce the public address the domain points to.
+TURN_PUBLIC_IP=$(getent ahostsv4 {} | awk 'NR==1 {{print $1}}')
+TURN_PRIVATE_IP=$(hostname -I | awk '{{print $1}}')
+TURN_EXTERNAL_IP=""
+if [ -n "$TURN_PUBLIC_IP" ] && [ -n "$TURN_PRIVATE_IP" ] && [ "$TURN_PUBLIC_IP" != "$TURN_PRIVATE_IP" ]; then
+    TURN_EXTERNAL_IP="external-ip=$TURN_PUBLIC_IP/$TURN_PRIVATE_IP"
+fi
+sudo tee /etc/turnserver.conf > /dev/null <<EOF
+use-auth-secret
+static-auth-secret=$TURN_SECRET
+realm={}
+listening-port=3478
+no-tls
+no-dtls
+$TURN_EXTERNAL_IP
+min-port={}
+max-port={}
+fingerprint
+no-cli
+no-tcp-relay
+no-multicast-peers
+{}log-file=syslog
+EOF
+sudo sed -i 's/^#\?TURNSERVER_ENABLED=.*/TURNSERVER_ENABLED=1/' /etc/default/coturn 2>/dev/null || true
+sudo systemctl enable coturn
+sudo systemctl restart coturn
+"#,
+        domain, domain, TURN_RELAY_PORTS.0, TURN_RELAY_PORTS.1, denied_peers
+    )
+}
+
+/// TURN settings appended to homeserver.yaml so Synapse hands out call credentials.
+fn turn_homeserver_config(domain: &str) -> String {
+    format!(
+        r#"
+turn_uris:
+  - "turn:{0}:3478?transport=udp"
+  - "turn:{0}:3478?transport=tcp"
+turn_shared_secret: "$TURN_SECRET"
+turn_user_lifetime: 86400000
+turn_allow_guests: true
+"#,
+        domain
+    )
+}
+
+fn turn_firewall_rules() -> String {
+    format!(
+        "sudo ufw allow 3478/udp\nsudo ufw allow 3478/tcp\nsudo ufw allow {}:{}/udp\n",
+        TURN_RELAY_PORTS.0, TURN_RELAY_PORTS.1
+    )
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -21,10 +120,49 @@ pub struct DeploymentStatus {
     pub success: bool,
 }
 
+/// Emitted while a deployment runs: step changes and every line the remote side prints.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum DeploymentProgress {
+    Status(DeploymentStatus),
+    Output { step: String, line: String },
+}
+
+/// Collects the statuses returned at the end and forwards each one as it happens.
+struct Reporter<F: FnMut(DeploymentProgress)> {
+    statuses: Vec<DeploymentStatus>,
+    on_progress: F,
+}
+
+impl<F: FnMut(DeploymentProgress)> Reporter<F> {
+    fn status(&mut self, step: &str, progress: u8, message: impl Into<String>, success: bool) {
+        let status = DeploymentStatus {
+            step: step.to_string(),
+            progress,
+            message: message.into(),
+            success,
+        };
+        self.statuses.push(status.clone());
+        (self.on_progress)(DeploymentProgress::Status(status));
+    }
+
+    fn line(&mut self, step: &str, line: &str) {
+        (self.on_progress)(DeploymentProgress::Output {
+            step: step.to_string(),
+            line: line.to_string(),
+        });
+    }
+}
+
 pub fn create_synapse_install_script(config: &DeploymentConfig) -> String {
     let domain = config.domain.as_ref().unwrap_or(&config.server_ip);
     let admin_user = &config.admin_username;
     let admin_pass = &config.admin_password;
+    let (turn_install, turn_config, turn_firewall) = if config.install_turn {
+        (turn_install_section(domain), turn_homeserver_config(domain), turn_firewall_rules())
+    } else {
+        (String::new(), String::new(), String::new())
+    };
 
     // Build script with proper variable substitution
     let script = format!(
@@ -53,7 +191,7 @@ sudo apt update
 echo "matrix-synapse matrix-synapse/server-name string {}" | sudo debconf-set-selections
 echo "matrix-synapse matrix-synapse/report-stats boolean false" | sudo debconf-set-selections
 sudo DEBIAN_FRONTEND=noninteractive apt install -y matrix-synapse-py3
-
+{}
 # Configure Synapse
 echo "[5/8] Configuring Synapse..."
 sudo tee /etc/matrix-synapse/homeserver.yaml > /dev/null <<EOF
@@ -85,7 +223,7 @@ allow_public_rooms_without_auth: false
 
 media_store_path: /var/lib/matrix-synapse/media
 max_upload_size: 50M
-EOF
+{}EOF
 
 # Configure Nginx
 echo "[6/8] Configuring Nginx..."
@@ -131,7 +269,7 @@ echo "Configuring firewall..."
 sudo ufw allow 80/tcp
 sudo ufw allow 443/tcp
 sudo ufw allow 8008/tcp
-sudo ufw --force enable
+{}sudo ufw --force enable
 
 # Verify installation
 echo "Verifying installation..."
@@ -147,7 +285,7 @@ echo "Next steps:"
 echo "1. Configure SSL certificate (optional): sudo certbot --nginx -d {}"
 echo "2. Connect from your Matrix client"
 "#,
-        domain, domain, domain, domain, domain, domain, admin_user, admin_pass, domain, domain, admin_user, domain
+        domain, domain, turn_install, domain, turn_config, domain, admin_user, admin_pass, turn_firewall, domain, domain, admin_user, domain
     );
 
     script
@@ -156,6 +294,15 @@ echo "2. Connect from your Matrix client"
 pub fn execute_remote_command(
     config: &DeploymentConfig,
     command: &str,
+) -> Result<String, String> {
+    execute_remote_command_streaming(config, command, |_| {})
+}
+
+/// Like `execute_remote_command`, calling `on_line` for each line of output as it arrives.
+pub fn execute_remote_command_streaming(
+    config: &DeploymentConfig,
+    command: &str,
+    mut on_line: impl FnMut(&str),
 ) -> Result<String, String> {
     // Clean IP address (remove protocol if present)
     let clean_ip = config.server_ip
@@ -168,9 +315,8 @@ pub fn execute_remote_command(
         .trim()
         .to_string();
 
-    // Connect to SSH
-    let tcp = TcpStream::connect(format!("{}:22", clean_ip))
-        .map_err(|e| format!("Failed to connect to {}:22 - {}", clean_ip, e))?;
+    // Connect to SSH (through the system proxy when one applies)
+    let tcp = crate::proxy::connect_tcp(&clean_ip, 22)?;
 
     let mut sess = Session::new().map_err(|e| format!("Failed to create SSH session: {}", e))?;
     sess.set_tcp_stream(tcp);
@@ -189,125 +335,116 @@ pub fn execute_remote_command(
         .exec(command)
         .map_err(|e| format!("Failed to execute command: {}", e))?;
 
-    // Read output
+    // Read output line by line
     let mut output = String::new();
-    channel
-        .read_to_string(&mut output)
-        .map_err(|e| format!("Failed to read output: {}", e))?;
+    {
+        let mut reader = BufReader::new(&mut channel);
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            let read = reader
+                .read_until(b'\n', &mut buf)
+                .map_err(|e| format!("Failed to read output: {}", e))?;
+            if read == 0 {
+                break;
+            }
+            let line = String::from_utf8_lossy(&buf);
+            on_line(line.trim_end_matches(['\r', '\n']));
+            output.push_str(&line);
+        }
+    }
 
     channel.wait_close().ok();
 
     Ok(output)
 }
 
-pub fn deploy_synapse_server(config: DeploymentConfig) -> Result<Vec<DeploymentStatus>, String> {
-    let mut statuses = Vec::new();
+pub fn deploy_synapse_server(
+    config: DeploymentConfig,
+    on_progress: impl FnMut(DeploymentProgress),
+) -> Result<Vec<DeploymentStatus>, String> {
+    let mut report = Reporter {
+        statuses: Vec::new(),
+        on_progress,
+    };
 
-    println!("=== Starting Matrix Synapse Deployment ===");
-    println!("Target server: {}", config.server_ip);
+    tracing::info!("=== Starting Matrix Synapse Deployment ===");
+    tracing::info!("Target server: {}", config.server_ip);
 
     // Step 1: Test connection
-    statuses.push(DeploymentStatus {
-        step: "connection".to_string(),
-        progress: 10,
-        message: "Testing SSH connection...".to_string(),
-        success: false,
-    });
-
-    println!("Testing SSH connection to {}...", config.server_ip);
+    report.status("connection", 10, "Testing SSH connection...", false);
+
+    tracing::info!("Testing SSH connection to {}...", config.server_ip);
     let test_result = execute_remote_command(&config, "echo 'Connection OK' && whoami");
     if let Err(e) = test_result {
-        println!("❌ Connection failed: {}", e);
-        statuses.push(DeploymentStatus {
-            step: "connection".to_string(),
-            progress: 10,
-            message: format!("Connection failed: {}", e),
-            success: false,
-        });
+        tracing::error!("Connection failed: {}", e);
+        report.status("connection", 10, format!("Connection failed: {}", e), false);
         return Err(e);
     }
 
-    println!("✓ SSH connection established");
-    println!("Connected as: {}", test_result.as_ref().unwrap());
-    statuses.push(DeploymentStatus {
-        step: "connection".to_string(),
-        progress: 10,
-        message: "SSH connection established".to_string(),
-        success: true,
-    });
+    tracing::info!("SSH connection established");
+    tracing::info!("Connected as: {}", test_result.as_ref().unwrap());
+    report.status("connection", 10, "SSH connection established", true);
 
     // Step 2: Upload installation script
-    statuses.push(DeploymentStatus {
-        step: "upload_script".to_string(),
-        progress: 20,
-        message: "Uploading installation script...".to_string(),
-        success: false,
-    });
-
-    println!("Generating installation script...");
+    report.status("upload_script", 20, "Uploading installation script...", false);
+
+    tracing::info!("Generating installation script...");
     let script = create_synapse_install_script(&config);
     let script_path = "/tmp/install_synapse.sh";
 
-    println!("Uploading script to server ({} bytes)...", script.len());
+    tracing::info!("Uploading script to server ({} bytes)...", script.len());
     let upload_cmd = format!("cat > {} << 'EOFSCRIPT'\n{}\nEOFSCRIPT\nchmod +x {}",
                             script_path, script, script_path);
 
-    execute_remote_command(&config, &upload_cmd)
-        .map_err(|e| {
-            println!("❌ Failed to upload script: {}", e);
-            format!("Failed to upload script: {}", e)
-        })?;
+    if let Err(e) = execute_remote_command(&config, &upload_cmd) {
+        tracing::error!("Failed to upload script: {}", e);
+        report.status("upload_script", 20, format!("Failed to upload script: {}", e), false);
+        return Err(format!("Failed to upload script: {}", e));
+    }
 
-    println!("✓ Installation script uploaded to {}", script_path);
-    statuses.push(DeploymentStatus {
-        step: "upload_script".to_string(),
-        progress: 20,
-        message: "Installation script uploaded".to_string(),
-        success: true,
-    });
+    tracing::info!("Installation script uploaded to {}", script_path);
+    report.status("upload_script", 20, "Installation script uploaded", true);
 
     // Step 3: Execute installation
-    statuses.push(DeploymentStatus {
-        step: "install".to_string(),
-        progress: 30,
-        message: "Running installation (this may take 5-10 minutes)...".to_string(),
-        success: false,
-    });
-
-    println!("Starting installation process (this will take 5-10 minutes)...");
-    println!("Installing Matrix Synapse, Nginx, and configuring services...");
-
-    let install_output = execute_remote_command(&config, &format!("sudo bash {}", script_path))
-        .map_err(|e| {
-            println!("❌ Installation failed: {}", e);
-            format!("Installation failed: {}", e)
-        })?;
-
-    println!("Installation output (last 500 chars):");
+    report.status("install", 30, "Running installation (this may take 5-10 minutes)...", false);
+
+    tracing::info!("Starting installation process (this will take 5-10 minutes)...");
+    tracing::info!("Installing Matrix Synapse, Nginx, and configuring services...");
+
+    // stderr is merged so apt and pip progress shows up in the live log too.
+    let install_result = execute_remote_command_streaming(
+        &config,
+        &format!("sudo bash {} 2>&1", script_path),
+        |line| report.line("install", line),
+    );
+    let install_output = match install_result {
+        Ok(output) => output,
+        Err(e) => {
+            tracing::error!("Installation failed: {}", e);
+            report.status("install", 30, format!("Installation failed: {}", e), false);
+            return Err(format!("Installation failed: {}", e));
+        }
+    };
+
+    tracing::info!("Installation output (last 500 chars):");
     let output_len = install_output.len();
     if output_len > 500 {
-        println!("...{}", &install_output[output_len-500..]);
+        let start = (output_len - 500..output_len)
+            .find(|i| install_output.is_char_boundary(*i))
+            .unwrap_or(output_len);
+        tracing::info!("...{}", &install_output[start..]);
     } else {
-        println!("{}", install_output);
+        tracing::info!("{}", install_output);
     }
 
-    println!("✓ Installation completed successfully");
-    statuses.push(DeploymentStatus {
-        step: "install".to_string(),
-        progress: 90,
-        message: "Installation completed".to_string(),
-        success: true,
-    });
+    tracing::info!("Installation completed successfully");
+    report.status("install", 90, "Installation completed", true);
 
     // Step 4: Verify
-    statuses.push(DeploymentStatus {
-        step: "verify".to_string(),
-        progress: 95,
-        message: "Verifying installation...".to_string(),
-        success: false,
-    });
-
-    println!("Verifying Matrix Synapse installation...");
+    report.status("verify", 95, "Verifying installation...", false);
+
+    tracing::info!("Verifying Matrix Synapse installation...");
     let verify_result = execute_remote_command(
         &config,
         "curl -s http://localhost:8008/_matrix/client/versions",
@@ -315,34 +452,47 @@ pub fn deploy_synapse_server(config: DeploymentConfig) -> Result<Vec<DeploymentS
 
     match verify_result {
         Ok(output) if output.contains("versions") => {
-            println!("✓ Verification successful!");
-            println!("Server response: {}", output);
+            tracing::info!("Verification successful!");
+            tracing::info!("Server response: {}", output);
             let server_url = config.domain.as_ref().unwrap_or(&config.server_ip);
-            println!("=== Deployment Complete! ===");
-            println!("Homeserver URL: http://{}:8008", server_url);
-            println!("Admin user: @{}:{}", config.admin_username, server_url);
-
-            statuses.push(DeploymentStatus {
-                step: "verify".to_string(),
-                progress: 100,
-                message: format!(
+            tracing::info!("=== Deployment Complete! ===");
+            tracing::info!("Homeserver URL: http://{}:8008", server_url);
+            tracing::info!("Admin user: @{}:{}", config.admin_username, server_url);
+
+            report.status(
+                "verify",
+                100,
+                format!(
                     "Synapse server successfully deployed at http://{}:8008",
                     server_url
                 ),
-                success: true,
-            });
+                true,
+            );
         }
         _ => {
-            println!("⚠️ Verification failed, but installation may have succeeded");
-            statuses.push(DeploymentStatus {
-                step: "verify".to_string(),
-                progress: 100,
-                message: "Installation completed but verification failed. Check server manually."
-                    .to_string(),
-                success: false,
-            });
+            tracing::warn!("Verification failed, but installation may have succeeded");
+            report.status(
+                "verify",
+                100,
+                "Installation completed but verification failed. Check server manually.",
+                false,
+            );
+        }
+    }
+
+    if config.install_turn {
+        tracing::info!("Checking coturn...");
+        match execute_remote_command(&config, "systemctl is-active coturn") {
+            Ok(output) if output.trim() == "active" => {
+                let server_url = config.domain.as_ref().unwrap_or(&config.server_ip);
+                report.status("turn", 100, format!("TURN server running at turn:{}:3478", server_url), true);
+            }
+            _ => {
+                tracing::warn!("coturn is not running");
+                report.status("turn", 100, "coturn was installed but is not running. Check server manually.", false);
+            }
         }
     }
 
-    Ok(statuses)
+    Ok(report.statuses)
 }
@@ -0,0 +1,270 @@
+use regex::Regex;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use zip::write::SimpleFileOptions;
+
+use crate::matrix_api::now_millis;
+
+/// Present while the app runs; finding it at startup means the last run ended in a native crash.
+const RUNNING_MARKER: &str = "running";
+const MAX_REPORTS: usize = 20;
+/// JSON keys whose values are message content or secrets.
+const SCRUBBED_KEYS: &[&str] = &[
+  "access_token",
+  "refresh_token",
+  "password",
+  "passphrase",
+  "pickle",
+  "key",
+  "session_key",
+  "body",
+  "formatted_body",
+  "content",
+  "text",
+  "title",
+  "topic",
+  "displayname",
+  "avatar_url",
+  "url",
+];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static CRASH_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CrashSettings {
+  /// Write a report when the app panics or exits uncleanly. Reports stay on disk until the user
+  /// attaches them to a bug report.
+  pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CrashReport {
+  pub id: String,
+  pub kind: String,
+  pub message: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub location: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub backtrace: Option<String>,
+  pub thread: String,
+  pub version: String,
+  pub os: String,
+  pub created_at: i64,
+}
+
+fn patterns() -> &'static [(Regex, &'static str)] {
+  static PATTERNS: OnceLock<Vec<(Regex, &'static str)>> = OnceLock::new();
+  PATTERNS.get_or_init(|| {
+    [
+      (r"(syt|syr|mct|mat)_[A-Za-z0-9_\-]+", "<token>"),
+      (r"(?i)bearer\s+[A-Za-z0-9._\-~+/]+=*", "Bearer <token>"),
+      (r"(?i)(access_token|refresh_token|password)=[^&\s]+", "$1=<redacted>"),
+      (r#"(?i)"(access_token|refresh_token|password)"\s*:\s*"[^"]*""#, "\"$1\":\"<redacted>\""),
+      (r"@[A-Za-z0-9._=\-/+]+:[A-Za-z0-9.\-]+(:[0-9]+)?", "@<user>"),
+      (r"![A-Za-z0-9._=\-/+]+:[A-Za-z0-9.\-]+(:[0-9]+)?", "!<room>"),
+      (r"#[A-Za-z0-9._=\-/+]+:[A-Za-z0-9.\-]+(:[0-9]+)?", "#<alias>"),
+      (r"mxc://[^\s\x22']+", "mxc://<media>"),
+      (r"[A-Za-z0-9._%+\-]+@[A-Za-z0-9.\-]+\.[A-Za-z]{2,}", "<email>"),
+    ]
+    .into_iter()
+    .filter_map(|(pattern, replacement)| Regex::new(pattern).ok().map(|re| (re, replacement)))
+    .collect()
+  })
+}
+
+/// Redact tokens, passwords, Matrix IDs, media URLs and email addresses from free text.
+pub fn scrub_text(text: &str) -> String {
+  patterns()
+    .iter()
+    .fold(text.to_string(), |text, (re, replacement)| re.replace_all(&text, *replacement).into_owned())
+}
+
+/// Drop message content and secrets by key, then scrub what is left.
+pub fn scrub_json(value: &Value) -> Value {
+  match value {
+    Value::Object(map) => Value::Object(
+      map
+        .iter()
+        .map(|(key, value)| {
+          let value = if SCRUBBED_KEYS.contains(&key.as_str()) { json!("<redacted>") } else { scrub_json(value) };
+          (key.clone(), value)
+        })
+        .collect(),
+    ),
+    Value::Array(items) => Value::Array(items.iter().map(scrub_json).collect()),
+    Value::String(text) => Value::String(scrub_text(text)),
+    other => other.clone(),
+  }
+}
+
+fn os() -> String {
+  format!("{} {}", std::env::consts::OS, std::env::consts::ARCH)
+}
+
+fn write_report(dir: &Path, report: &CrashReport) {
+  let _ = fs::create_dir_all(dir);
+  if let Ok(json) = serde_json::to_string_pretty(report) {
+    let _ = fs::write(dir.join(format!("{}.json", report.id)), json);
+  }
+}
+
+/// Install the panic hook and check how the previous run ended. The hook keeps the default
+/// output and only writes a report while crash reporting is enabled.
+pub fn install(crash_dir: PathBuf, enabled: bool) {
+  ENABLED.store(enabled, Ordering::SeqCst);
+  let marker = crash_dir.join(RUNNING_MARKER);
+  if enabled && marker.exists() {
+    let created_at = fs::metadata(&marker)
+      .and_then(|meta| meta.modified())
+      .ok()
+      .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+      .map(|since| since.as_millis() as i64)
+      .unwrap_or_else(now_millis);
+    write_report(
+      &crash_dir,
+      &CrashReport {
+        id: format!("unclean-{}", created_at),
+        kind: "uncleanExit".into(),
+        message: "The previous session ended without shutting down (native crash, kill or power loss)".into(),
+        location: None,
+        backtrace: None,
+        thread: String::new(),
+        version: env!("CARGO_PKG_VERSION").into(),
+        os: os(),
+        created_at,
+      },
+    );
+  }
+  let _ = fs::create_dir_all(&crash_dir);
+  let _ = fs::write(&marker, now_millis().to_string());
+  let _ = CRASH_DIR.set(crash_dir);
+
+  let default_hook = std::panic::take_hook();
+  std::panic::set_hook(Box::new(move |info| {
+    if ENABLED.load(Ordering::SeqCst) {
+      if let Some(dir) = CRASH_DIR.get() {
+        let message = info
+          .payload()
+          .downcast_ref::<&str>()
+          .map(|s| s.to_string())
+          .or_else(|| info.payload().downcast_ref::<String>().cloned())
+          .unwrap_or_else(|| "panic".into());
+        let created_at = now_millis();
+        write_report(
+          dir,
+          &CrashReport {
+            id: format!("panic-{}", created_at),
+            kind: "panic".into(),
+            message: scrub_text(&message),
+            location: info.location().map(|l| format!("{}:{}:{}", l.file(), l.line(), l.column())),
+            backtrace: Some(scrub_text(&std::backtrace::Backtrace::force_capture().to_string())),
+            thread: std::thread::current().name().unwrap_or("unnamed").to_string(),
+            version: env!("CARGO_PKG_VERSION").into(),
+            os: os(),
+            created_at,
+          },
+        );
+      }
+    }
+    default_hook(info);
+  }));
+}
+
+pub fn set_enabled(enabled: bool) {
+  ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+/// Clean shutdown: the next start won't report an unclean exit.
+pub fn mark_clean_exit() {
+  if let Some(dir) = CRASH_DIR.get() {
+    let _ = fs::remove_file(dir.join(RUNNING_MARKER));
+  }
+}
+
+/// Stored reports, newest first; older ones beyond the limit are deleted.
+pub fn reports(dir: &Path) -> Result<Vec<CrashReport>, String> {
+  let Ok(entries) = fs::read_dir(dir) else {
+    return Ok(Vec::new());
+  };
+  let mut reports: Vec<(PathBuf, CrashReport)> = entries
+    .flatten()
+    .map(|entry| entry.path())
+    .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+    .filter_map(|path| {
+      let report = serde_json::from_slice(&fs::read(&path).ok()?).ok()?;
+      Some((path, report))
+    })
+    .collect();
+  reports.sort_by(|a, b| b.1.created_at.cmp(&a.1.created_at));
+  for (path, _) in reports.iter().skip(MAX_REPORTS) {
+    let _ = fs::remove_file(path);
+  }
+  Ok(reports.into_iter().take(MAX_REPORTS).map(|(_, report)| report).collect())
+}
+
+pub fn clear_reports(dir: &Path) -> Result<(), String> {
+  for report in reports(dir)? {
+    let _ = fs::remove_file(dir.join(format!("{}.json", report.id)));
+  }
+  Ok(())
+}
+
+/// Row counts per table and the database size; no row content.
+pub fn index_stats(conn: &Connection, db_path: &Path) -> Result<Value, String> {
+  let mut stmt = conn
+    .prepare("SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%' ORDER BY name")
+    .map_err(|e| e.to_string())?;
+  let tables: Vec<String> = stmt
+    .query_map([], |row| row.get(0))
+    .map_err(|e| e.to_string())?
+    .flatten()
+    .collect();
+  let mut counts = serde_json::Map::new();
+  for table in tables {
+    let count: i64 = conn
+      .query_row(&format!("SELECT COUNT(*) FROM \"{}\"", table.replace('"', "\"\"")), [], |row| row.get(0))
+      .unwrap_or(-1);
+    counts.insert(table, json!(count));
+  }
+  Ok(json!({
+    "sizeBytes": fs::metadata(db_path).map(|meta| meta.len()).unwrap_or(0),
+    "tables": counts,
+  }))
+}
+
+pub fn version_info(tauri_version: &str) -> Value {
+  json!({
+    "version": env!("CARGO_PKG_VERSION"),
+    "tauri": tauri_version,
+    "os": std::env::consts::OS,
+    "arch": std::env::consts::ARCH,
+    "family": std::env::consts::FAMILY,
+    "createdAt": now_millis(),
+  })
+}
+
+/// Write a zip of scrubbed entries. Text entries are scrubbed here once more, so callers can't
+/// leak secrets by forgetting to.
+pub fn write_bundle(path: &Path, entries: Vec<(String, String)>) -> Result<(), String> {
+  if let Some(dir) = path.parent() {
+    fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+  }
+  let file = fs::File::create(path).map_err(|e| e.to_string())?;
+  let mut zip = zip::ZipWriter::new(file);
+  let options = SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+  for (name, contents) in entries {
+    zip.start_file(name, options).map_err(|e| e.to_string())?;
+    zip.write_all(scrub_text(&contents).as_bytes()).map_err(|e| e.to_string())?;
+  }
+  zip.finish().map_err(|e| e.to_string())?;
+  Ok(())
+}
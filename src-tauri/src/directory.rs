@@ -0,0 +1,289 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+
+use crate::matrix_api::{now_millis, ApiError, MatrixClient};
+
+const PUBLIC_ROOMS_TTL_MS: i64 = 10 * 60 * 1000;
+const PUBLIC_ROOMS_PAGE_SIZE: u32 = 30;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PublicRoomsQuery {
+  pub server: Option<String>,
+  pub term: Option<String>,
+  pub since: Option<String>,
+  pub third_party_instance_id: Option<String>,
+  #[serde(default)]
+  pub include_all_networks: bool,
+  pub limit: Option<u32>,
+}
+
+impl PublicRoomsQuery {
+  pub fn cache_key(&self, homeserver_url: &str) -> String {
+    format!(
+      "{}|{}|{}|{}|{}|{}",
+      self.server.as_deref().unwrap_or(homeserver_url),
+      self.term.as_deref().map(|t| t.trim().to_lowercase()).unwrap_or_default(),
+      self.since.as_deref().unwrap_or_default(),
+      self.third_party_instance_id.as_deref().unwrap_or_default(),
+      self.include_all_networks,
+      self.limit.unwrap_or(PUBLIC_ROOMS_PAGE_SIZE),
+    )
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PublicRoomsPage {
+  pub rooms: Vec<Value>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub next_batch: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub prev_batch: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub total_room_count_estimate: Option<u64>,
+  pub fetched_at: i64,
+  pub from_cache: bool,
+  pub stale: bool,
+}
+
+impl PublicRoomsPage {
+  fn from_response(response: &Value, fetched_at: i64) -> PublicRoomsPage {
+    PublicRoomsPage {
+      rooms: response.get("chunk").and_then(|v| v.as_array()).cloned().unwrap_or_default(),
+      next_batch: response.get("next_batch").and_then(|v| v.as_str()).map(|s| s.to_string()),
+      prev_batch: response.get("prev_batch").and_then(|v| v.as_str()).map(|s| s.to_string()),
+      total_room_count_estimate: response.get("total_room_count_estimate").and_then(|v| v.as_u64()),
+      fetched_at,
+      from_cache: false,
+      stale: false,
+    }
+  }
+}
+
+pub fn init_directory_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS public_rooms_cache (
+        cache_key TEXT PRIMARY KEY,
+        response_json TEXT NOT NULL,
+        fetched_at INTEGER NOT NULL
+      );
+    ",
+  )
+}
+
+/// Cached page for the query, with `stale` set once the TTL has passed.
+pub fn cached_page(conn: &Connection, cache_key: &str) -> Result<Option<PublicRoomsPage>, String> {
+  let row: Option<(String, i64)> = conn
+    .query_row(
+      "SELECT response_json, fetched_at FROM public_rooms_cache WHERE cache_key = ?1",
+      [cache_key],
+      |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+    .optional()
+    .map_err(|e| e.to_string())?;
+  Ok(row.map(|(response_json, fetched_at)| {
+    let response = serde_json::from_str::<Value>(&response_json).unwrap_or_else(|_| json!({}));
+    let mut page = PublicRoomsPage::from_response(&response, fetched_at);
+    page.from_cache = true;
+    page.stale = now_millis() - fetched_at > PUBLIC_ROOMS_TTL_MS;
+    page
+  }))
+}
+
+pub fn store_page(conn: &Connection, cache_key: &str, response: &Value) -> Result<(), String> {
+  let response_json = serde_json::to_string(response).map_err(|e| e.to_string())?;
+  let now = now_millis();
+  conn
+    .execute(
+      "INSERT INTO public_rooms_cache (cache_key, response_json, fetched_at) VALUES (?1, ?2, ?3)
+       ON CONFLICT(cache_key) DO UPDATE SET response_json = excluded.response_json, fetched_at = excluded.fetched_at",
+      params![cache_key, response_json, now],
+    )
+    .map_err(|e| e.to_string())?;
+  conn
+    .execute(
+      "DELETE FROM public_rooms_cache WHERE fetched_at < ?1",
+      [now - PUBLIC_ROOMS_TTL_MS * 6],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+pub async fn fetch_public_rooms(client: &MatrixClient, query: &PublicRoomsQuery) -> Result<Value, ApiError> {
+  let mut body = json!({ "limit": query.limit.unwrap_or(PUBLIC_ROOMS_PAGE_SIZE) });
+  if let Some(since) = &query.since {
+    body["since"] = json!(since);
+  }
+  if let Some(term) = query.term.as_deref().map(str::trim).filter(|t| !t.is_empty()) {
+    body["filter"] = json!({ "generic_search_term": term });
+  }
+  if let Some(instance) = &query.third_party_instance_id {
+    body["third_party_instance_id"] = json!(instance);
+  } else if query.include_all_networks {
+    body["include_all_networks"] = json!(true);
+  }
+  let mut params = Vec::new();
+  if let Some(server) = query.server.as_deref().map(str::trim).filter(|s| !s.is_empty()) {
+    params.push(("server", server.to_string()));
+  }
+  client
+    .request(reqwest::Method::POST, &client.client_url("/publicRooms"), &params, Some(&body))
+    .await
+}
+
+pub fn page_from_response(response: &Value) -> PublicRoomsPage {
+  PublicRoomsPage::from_response(response, now_millis())
+}
+
+/// Whether a failed lookup should fall back to whatever is cached.
+pub fn should_serve_stale(err: &ApiError) -> bool {
+  match err {
+    ApiError::Network(_) => true,
+    ApiError::Http { status, .. } => *status >= 500 || *status == 429,
+  }
+}
+
+const USER_SEARCH_LIMIT: usize = 30;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserSearchResult {
+  pub user_id: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub display_name: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub avatar_url: Option<String>,
+  /// `dm`, `member` or `directory`, in ranking order.
+  pub source: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub last_active_ts: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserSearchResponse {
+  pub results: Vec<UserSearchResult>,
+  pub limited: bool,
+  pub directory_available: bool,
+}
+
+pub async fn fetch_user_directory(client: &MatrixClient, term: &str) -> Result<(Vec<UserSearchResult>, bool), ApiError> {
+  let response = client
+    .post(
+      "/user_directory/search",
+      &json!({ "search_term": term, "limit": USER_SEARCH_LIMIT }),
+    )
+    .await?;
+  let limited = response.get("limited").and_then(|v| v.as_bool()).unwrap_or(false);
+  let results = response
+    .get("results")
+    .and_then(|v| v.as_array())
+    .map(|items| {
+      items
+        .iter()
+        .filter_map(|item| {
+          Some(UserSearchResult {
+            user_id: item.get("user_id")?.as_str()?.to_string(),
+            display_name: item.get("display_name").and_then(|v| v.as_str()).map(|s| s.to_string()),
+            avatar_url: item.get("avatar_url").and_then(|v| v.as_str()).map(|s| s.to_string()),
+            source: "directory".to_string(),
+            last_active_ts: None,
+          })
+        })
+        .collect()
+    })
+    .unwrap_or_default();
+  Ok((results, limited))
+}
+
+/// Members seen in the account's cached room state matching `term`. Users sharing a two-person
+/// room rank as DMs, everyone else by how recently they spoke.
+pub fn search_local_members(
+  conn: &Connection,
+  account_key: &str,
+  own_user_id: &str,
+  term: &str,
+) -> Result<Vec<UserSearchResult>, String> {
+  let pattern = format!("%{}%", term.trim().to_lowercase());
+  let mut stmt = conn
+    .prepare(&format!(
+      "WITH joined AS (
+          SELECT room_id, state_key FROM event_cache
+          WHERE account_key = ?3 AND event_type = 'm.room.member'
+            AND json_extract(content_json, '$.membership') = 'join'
+          GROUP BY room_id, state_key
+        ),
+        room_sizes AS (
+          SELECT room_id, COUNT(*) AS members FROM joined GROUP BY room_id
+        )
+        SELECT m.state_key,
+               json_extract(m.content_json, '$.displayname'),
+               json_extract(m.content_json, '$.avatar_url'),
+               MAX(CASE WHEN rs.members <= 2 THEN 1 ELSE 0 END),
+               (SELECT MAX(origin_server_ts) FROM event_cache e
+                WHERE e.account_key = ?3 AND e.sender = m.state_key AND e.event_type IN ('m.room.message', 'm.room.encrypted'))
+        FROM event_cache m
+        JOIN joined j ON j.room_id = m.room_id AND j.state_key = m.state_key
+        JOIN room_sizes rs ON rs.room_id = m.room_id
+        WHERE m.account_key = ?3 AND m.event_type = 'm.room.member'
+          AND m.state_key != ?1
+          AND m.state_key NOT IN (SELECT user_id FROM ignored_users WHERE ignored_users.account_key = m.account_key)
+          AND (LOWER(m.state_key) LIKE ?2 OR LOWER(IFNULL(json_extract(m.content_json, '$.displayname'), '')) LIKE ?2)
+        GROUP BY m.state_key
+        ORDER BY 4 DESC, 5 DESC
+        LIMIT {}",
+      USER_SEARCH_LIMIT
+    ))
+    .map_err(|e| e.to_string())?;
+  let rows = stmt
+    .query_map(params![own_user_id, pattern, account_key], |row| {
+      let is_dm: i64 = row.get(3)?;
+      Ok(UserSearchResult {
+        user_id: row.get(0)?,
+        display_name: row.get(1)?,
+        avatar_url: row.get(2)?,
+        source: if is_dm == 1 { "dm" } else { "member" }.to_string(),
+        last_active_ts: row.get(4)?,
+      })
+    })
+    .map_err(|e| e.to_string())?;
+  let mut out = Vec::new();
+  for row in rows {
+    if let Ok(result) = row { out.push(result); }
+  }
+  Ok(out)
+}
+
+/// Local matches first, then directory hits not already listed; local entries borrow
+/// directory profile data when the cached state lacks it. Anyone listed in `m.direct`
+/// is promoted to the DM tier.
+pub fn merge_user_results(
+  local: Vec<UserSearchResult>,
+  remote: Vec<UserSearchResult>,
+  direct_contacts: &HashSet<String>,
+) -> Vec<UserSearchResult> {
+  let mut merged = local;
+  for result in remote {
+    if let Some(existing) = merged.iter_mut().find(|r| r.user_id == result.user_id) {
+      if existing.display_name.is_none() {
+        existing.display_name = result.display_name;
+      }
+      if existing.avatar_url.is_none() {
+        existing.avatar_url = result.avatar_url;
+      }
+      continue;
+    }
+    merged.push(result);
+  }
+  for result in merged.iter_mut() {
+    if direct_contacts.contains(&result.user_id) {
+      result.source = "dm".to_string();
+    }
+  }
+  merged.sort_by_key(|r| r.source != "dm");
+  merged.truncate(USER_SEARCH_LIMIT);
+  merged
+}
@@ -0,0 +1,277 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{Cursor, Read};
+use std::path::Path;
+use std::process::Command;
+
+use crate::matrix_api::now_millis;
+use crate::video::find_tool;
+
+const MAX_ZIP_ENTRY_BYTES: u64 = 8 * 1024 * 1024;
+
+/// Icon family for a file message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DocumentKind {
+  Pdf,
+  Document,
+  Spreadsheet,
+  Presentation,
+  Text,
+  Archive,
+  Other,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DocumentInfo {
+  pub kind: DocumentKind,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub mimetype: Option<String>,
+  /// Pages, slides or sheets, depending on the kind.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub page_count: Option<u32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub title: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub author: Option<String>,
+  /// Whether a first-page (or embedded) preview image exists.
+  pub has_preview: bool,
+}
+
+pub fn init_document_preview_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS document_info (
+        source TEXT PRIMARY KEY,
+        info_json TEXT NOT NULL,
+        created_at INTEGER NOT NULL
+      );
+    ",
+  )
+}
+
+pub fn cached_info(conn: &Connection, source: &str) -> Result<Option<DocumentInfo>, String> {
+  let stored: Option<String> = conn
+    .query_row("SELECT info_json FROM document_info WHERE source = ?1", [source], |row| row.get(0))
+    .optional()
+    .map_err(|e| e.to_string())?;
+  Ok(stored.and_then(|json| serde_json::from_str(&json).ok()))
+}
+
+pub fn store_info(conn: &Connection, source: &str, info: &DocumentInfo) -> Result<(), String> {
+  let json = serde_json::to_string(info).map_err(|e| e.to_string())?;
+  conn
+    .execute(
+      "INSERT INTO document_info (source, info_json, created_at) VALUES (?1, ?2, ?3)
+       ON CONFLICT(source) DO UPDATE SET info_json = excluded.info_json, created_at = excluded.created_at",
+      params![source, json, now_millis()],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+/// Classify by MIME type, falling back to the file extension.
+pub fn kind_for(mimetype: Option<&str>, file_name: Option<&str>) -> DocumentKind {
+  let extension = file_name
+    .and_then(|name| Path::new(name).extension())
+    .map(|e| e.to_string_lossy().to_ascii_lowercase())
+    .unwrap_or_default();
+  let mimetype = mimetype.unwrap_or_default();
+  match (mimetype, extension.as_str()) {
+    ("application/pdf", _) | (_, "pdf") => DocumentKind::Pdf,
+    (m, _) if m.contains("wordprocessingml") || m.contains("opendocument.text") || m == "application/msword" || m == "application/rtf" => {
+      DocumentKind::Document
+    }
+    (m, _) if m.contains("spreadsheetml") || m.contains("opendocument.spreadsheet") || m == "application/vnd.ms-excel" || m == "text/csv" => {
+      DocumentKind::Spreadsheet
+    }
+    (m, _) if m.contains("presentationml") || m.contains("opendocument.presentation") || m == "application/vnd.ms-powerpoint" => {
+      DocumentKind::Presentation
+    }
+    (_, "doc" | "docx" | "odt" | "rtf" | "pages") => DocumentKind::Document,
+    (_, "xls" | "xlsx" | "ods" | "csv" | "numbers") => DocumentKind::Spreadsheet,
+    (_, "ppt" | "pptx" | "odp" | "key") => DocumentKind::Presentation,
+    (m, _) if m.starts_with("text/") => DocumentKind::Text,
+    (_, "txt" | "md" | "log" | "json" | "xml") => DocumentKind::Text,
+    (m, _) if m.contains("zip") || m.contains("compressed") || m.contains("x-tar") || m.contains("x-7z") => DocumentKind::Archive,
+    (_, "zip" | "tar" | "gz" | "tgz" | "7z" | "rar" | "xz" | "bz2") => DocumentKind::Archive,
+    _ => DocumentKind::Other,
+  }
+}
+
+/// Text of the first `<name>` (or `<name attr=...>`) element, entities left as is.
+fn element_text(xml: &str, name: &str) -> Option<String> {
+  let open = format!("<{}", name);
+  let mut offset = 0;
+  while let Some(found) = xml[offset..].find(&open) {
+    let start = offset + found + open.len();
+    offset = start;
+    match xml[start..].chars().next() {
+      Some('>') | Some(' ') | Some('\t') | Some('\n') | Some('\r') => {}
+      _ => continue,
+    }
+    let body_start = start + xml[start..].find('>')? + 1;
+    if xml[..body_start].ends_with("/>") {
+      continue;
+    }
+    let body_end = body_start + xml[body_start..].find(&format!("</{}", name))?;
+    let text = xml[body_start..body_end].trim();
+    return (!text.is_empty()).then(|| text.to_string());
+  }
+  None
+}
+
+/// Value of `attribute="..."` anywhere in the document.
+fn attribute_value(xml: &str, attribute: &str) -> Option<String> {
+  let needle = format!("{}=\"", attribute);
+  let start = xml.find(&needle)? + needle.len();
+  let end = start + xml[start..].find('"')?;
+  Some(xml[start..end].to_string())
+}
+
+fn zip_entry(archive: &mut zip::ZipArchive<Cursor<&[u8]>>, name: &str) -> Option<Vec<u8>> {
+  let entry = archive.by_name(name).ok()?;
+  if entry.size() > MAX_ZIP_ENTRY_BYTES {
+    return None;
+  }
+  let mut bytes = Vec::with_capacity(entry.size() as usize);
+  entry.take(MAX_ZIP_ENTRY_BYTES).read_to_end(&mut bytes).ok()?;
+  Some(bytes)
+}
+
+/// Metadata and the embedded thumbnail of an Office Open XML or OpenDocument file.
+fn office(bytes: &[u8], info: &mut DocumentInfo) -> Option<Vec<u8>> {
+  let mut archive = zip::ZipArchive::new(Cursor::new(bytes)).ok()?;
+  if let Some(meta) = zip_entry(&mut archive, "meta.xml") {
+    let meta = String::from_utf8_lossy(&meta);
+    info.title = element_text(&meta, "dc:title");
+    info.author = element_text(&meta, "meta:initial-creator").or_else(|| element_text(&meta, "dc:creator"));
+    info.page_count = ["meta:page-count", "meta:table-count"]
+      .iter()
+      .find_map(|attribute| attribute_value(&meta, attribute))
+      .and_then(|count| count.parse().ok());
+    return zip_entry(&mut archive, "Thumbnails/thumbnail.png");
+  }
+  if let Some(app) = zip_entry(&mut archive, "docProps/app.xml") {
+    let app = String::from_utf8_lossy(&app);
+    info.page_count = ["Pages", "Slides"]
+      .iter()
+      .find_map(|name| element_text(&app, name))
+      .and_then(|count| count.parse().ok());
+  }
+  if let Some(core) = zip_entry(&mut archive, "docProps/core.xml") {
+    let core = String::from_utf8_lossy(&core);
+    info.title = element_text(&core, "dc:title");
+    info.author = element_text(&core, "dc:creator");
+  }
+  ["docProps/thumbnail.jpeg", "docProps/thumbnail.jpg", "docProps/thumbnail.png"]
+    .iter()
+    .find_map(|name| zip_entry(&mut archive, name))
+}
+
+/// Rough page count from the raw PDF: `/Type /Page` objects, not counting `/Pages` nodes.
+/// Compressed object streams hide these, so this is only used when `pdfinfo` is missing.
+fn pdf_page_count(bytes: &[u8]) -> Option<u32> {
+  let needle = b"/Type";
+  let mut count = 0;
+  let mut i = 0;
+  while i + needle.len() < bytes.len() {
+    if &bytes[i..i + needle.len()] == needle {
+      let rest = &bytes[i + needle.len()..bytes.len().min(i + needle.len() + 8)];
+      let rest: Vec<u8> = rest.iter().copied().skip_while(|b| b.is_ascii_whitespace()).collect();
+      if rest.starts_with(b"/Page") && !rest.starts_with(b"/Pages") {
+        count += 1;
+      }
+    }
+    i += 1;
+  }
+  (count > 0).then_some(count)
+}
+
+/// Page count, title and author from `pdfinfo`.
+fn pdf_info(path: &Path, info: &mut DocumentInfo) -> bool {
+  let Some(pdfinfo) = find_tool("pdfinfo") else {
+    return false;
+  };
+  let Ok(output) = Command::new(pdfinfo).arg(path).output() else {
+    return false;
+  };
+  if !output.status.success() {
+    return false;
+  }
+  for line in String::from_utf8_lossy(&output.stdout).lines() {
+    let Some((key, value)) = line.split_once(':') else {
+      continue;
+    };
+    let value = value.trim();
+    if value.is_empty() {
+      continue;
+    }
+    match key.trim() {
+      "Pages" => info.page_count = value.parse().ok(),
+      "Title" => info.title = Some(value.to_string()),
+      "Author" => info.author = Some(value.to_string()),
+      _ => {}
+    }
+  }
+  true
+}
+
+/// Render the first page with poppler's `pdftoppm`, scaled to fit `max_size` pixels.
+fn render_pdf_page(path: &Path, work_dir: &Path, max_size: u32) -> Result<Vec<u8>, String> {
+  let pdftoppm = find_tool("pdftoppm").ok_or("pdftoppm was not found; install poppler to preview PDFs")?;
+  let prefix = work_dir.join(format!("page-{}", now_millis()));
+  let output = Command::new(pdftoppm)
+    .args(["-f", "1", "-l", "1", "-singlefile", "-png", "-scale-to"])
+    .arg(max_size.max(1).to_string())
+    .arg(path)
+    .arg(&prefix)
+    .output()
+    .map_err(|e| e.to_string())?;
+  let rendered = prefix.with_extension("png");
+  if !output.status.success() {
+    let _ = fs::remove_file(&rendered);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    return Err(stderr.lines().last().unwrap_or("pdftoppm failed").to_string());
+  }
+  let bytes = fs::read(&rendered).map_err(|e| e.to_string());
+  let _ = fs::remove_file(&rendered);
+  bytes
+}
+
+/// Document metadata and, where one can be produced, an encoded preview image (first PDF page,
+/// or the thumbnail Office and LibreOffice embed when saving).
+pub fn inspect(
+  bytes: &[u8],
+  mimetype: Option<&str>,
+  file_name: Option<&str>,
+  work_dir: &Path,
+  max_size: u32,
+) -> Result<(DocumentInfo, Option<Vec<u8>>), String> {
+  let mut info = DocumentInfo {
+    kind: kind_for(mimetype, file_name),
+    mimetype: mimetype.map(|m| m.to_string()),
+    page_count: None,
+    title: None,
+    author: None,
+    has_preview: false,
+  };
+  let preview = match info.kind {
+    DocumentKind::Pdf => {
+      fs::create_dir_all(work_dir).map_err(|e| e.to_string())?;
+      let path = work_dir.join(format!("source-{}.pdf", now_millis()));
+      fs::write(&path, bytes).map_err(|e| e.to_string())?;
+      if !pdf_info(&path, &mut info) {
+        info.page_count = pdf_page_count(bytes);
+      }
+      let rendered = render_pdf_page(&path, work_dir, max_size).ok();
+      let _ = fs::remove_file(&path);
+      rendered
+    }
+    DocumentKind::Document | DocumentKind::Spreadsheet | DocumentKind::Presentation => office(bytes, &mut info),
+    _ => None,
+  };
+  info.has_preview = preview.is_some();
+  Ok((info, preview))
+}
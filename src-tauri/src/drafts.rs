@@ -0,0 +1,149 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::matrix_api::now_millis;
+use crate::upload_staging::StagedUpload;
+
+/// A half-written message, per account and room.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Draft {
+  pub account_key: String,
+  pub room_id: String,
+  /// Composer content, e.g. `body` plus `formatted_body`.
+  pub content: Value,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub reply_to: Option<String>,
+  #[serde(default)]
+  pub attachments: Vec<StagedUpload>,
+  /// Attached files that no longer exist on disk; dropped from the draft.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub missing_attachments: Vec<String>,
+  pub updated_at: i64,
+}
+
+pub fn init_drafts_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS drafts (
+        account_key TEXT NOT NULL,
+        room_id TEXT NOT NULL,
+        content_json TEXT NOT NULL,
+        reply_to TEXT,
+        attachments_json TEXT NOT NULL,
+        updated_at INTEGER NOT NULL,
+        PRIMARY KEY (account_key, room_id)
+      );
+    ",
+  )
+}
+
+fn is_empty(content: &Value, reply_to: Option<&str>, attachments: &[StagedUpload]) -> bool {
+  let body = content.get("body").and_then(|v| v.as_str()).unwrap_or_default();
+  body.trim().is_empty() && reply_to.is_none() && attachments.is_empty()
+}
+
+/// Store the draft, or clear it when there is nothing left in the composer.
+pub fn save(
+  conn: &Connection,
+  account_key: &str,
+  room_id: &str,
+  content: &Value,
+  reply_to: Option<&str>,
+  attachments: &[StagedUpload],
+) -> Result<Option<Draft>, String> {
+  if is_empty(content, reply_to, attachments) {
+    clear(conn, account_key, room_id)?;
+    return Ok(None);
+  }
+  let updated_at = now_millis();
+  conn
+    .execute(
+      "INSERT INTO drafts (account_key, room_id, content_json, reply_to, attachments_json, updated_at)
+       VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+       ON CONFLICT(account_key, room_id) DO UPDATE SET content_json = excluded.content_json,
+         reply_to = excluded.reply_to, attachments_json = excluded.attachments_json, updated_at = excluded.updated_at",
+      params![
+        account_key,
+        room_id,
+        serde_json::to_string(content).map_err(|e| e.to_string())?,
+        reply_to,
+        serde_json::to_string(attachments).map_err(|e| e.to_string())?,
+        updated_at
+      ],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(Some(Draft {
+    account_key: account_key.to_string(),
+    room_id: room_id.to_string(),
+    content: content.clone(),
+    reply_to: reply_to.map(|s| s.to_string()),
+    attachments: attachments.to_vec(),
+    missing_attachments: Vec::new(),
+    updated_at,
+  }))
+}
+
+fn row_to_draft(row: &rusqlite::Row) -> rusqlite::Result<Draft> {
+  let content: String = row.get(2)?;
+  let attachments: String = row.get(4)?;
+  Ok(Draft {
+    account_key: row.get(0)?,
+    room_id: row.get(1)?,
+    content: serde_json::from_str(&content).unwrap_or(Value::Null),
+    reply_to: row.get(3)?,
+    attachments: serde_json::from_str(&attachments).unwrap_or_default(),
+    missing_attachments: Vec::new(),
+    updated_at: row.get(5)?,
+  })
+}
+
+pub fn get(conn: &Connection, account_key: &str, room_id: &str) -> Result<Option<Draft>, String> {
+  conn
+    .query_row(
+      "SELECT account_key, room_id, content_json, reply_to, attachments_json, updated_at FROM drafts
+       WHERE account_key = ?1 AND room_id = ?2",
+      params![account_key, room_id],
+      row_to_draft,
+    )
+    .optional()
+    .map_err(|e| e.to_string())
+}
+
+/// Every draft of an account, newest first, for room list indicators.
+pub fn list(conn: &Connection, account_key: &str) -> Result<Vec<Draft>, String> {
+  let mut stmt = conn
+    .prepare(
+      "SELECT account_key, room_id, content_json, reply_to, attachments_json, updated_at FROM drafts
+       WHERE account_key = ?1 ORDER BY updated_at DESC",
+    )
+    .map_err(|e| e.to_string())?;
+  let rows = stmt.query_map(params![account_key], row_to_draft).map_err(|e| e.to_string())?;
+  Ok(rows.flatten().collect())
+}
+
+pub fn clear(conn: &Connection, account_key: &str, room_id: &str) -> Result<(), String> {
+  conn
+    .execute(
+      "DELETE FROM drafts WHERE account_key = ?1 AND room_id = ?2",
+      params![account_key, room_id],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+/// Split off attachments whose file was moved or deleted since the draft was saved.
+pub fn drop_missing(draft: &mut Draft) {
+  let (present, missing): (Vec<StagedUpload>, Vec<StagedUpload>) = draft
+    .attachments
+    .drain(..)
+    .partition(|upload| std::path::Path::new(&upload.path).is_file());
+  draft.attachments = present;
+  for upload in &mut draft.attachments {
+    // Thumbnails live in the cache and may have been cleaned up.
+    if upload.thumbnail_path.as_deref().is_some_and(|path| !std::path::Path::new(path).is_file()) {
+      upload.thumbnail_path = None;
+    }
+  }
+  draft.missing_attachments = missing.into_iter().map(|upload| upload.file_name).collect();
+}
@@ -0,0 +1,308 @@
+use aes::Aes256;
+use base64::{engine::general_purpose, Engine as _};
+use ctr::cipher::{KeyIvInit, StreamCipher};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use pbkdf2::pbkdf2_hmac;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Sha256, Sha512};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+type Aes256Ctr64 = ctr::Ctr64BE<Aes256>;
+type Aes256Ctr128 = ctr::Ctr128BE<Aes256>;
+
+const KEY_EXPORT_HEADER: &str = "-----BEGIN MEGOLM SESSION DATA-----";
+const KEY_EXPORT_FOOTER: &str = "-----END MEGOLM SESSION DATA-----";
+/// Element Desktop keeps the pickle key in the OS keychain under this service.
+const DESKTOP_KEYCHAIN_SERVICE: &str = "element.io";
+/// IndexedDB databases of Element Web / matrix-js-sdk.
+const REACT_SDK_DB: &str = "matrix-react-sdk";
+const LEGACY_CRYPTO_DB: &str = "matrix-js-sdk:crypto";
+
+/// The login Element was using, so this app can continue as the same device.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ElementSession {
+  pub homeserver_url: String,
+  pub user_id: String,
+  pub device_id: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub identity_server_url: Option<String>,
+  #[serde(skip)]
+  pub access_token: String,
+  #[serde(skip)]
+  pub refresh_token: Option<String>,
+}
+
+/// End-to-end encryption state taken over from Element, handed to the webview's crypto layer.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ElementCrypto {
+  /// Key the legacy crypto store pickles were encrypted with.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub pickle_key: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub olm_account_pickle: Option<String>,
+  pub olm_sessions: Vec<Value>,
+  pub inbound_group_sessions: Vec<Value>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub cross_signing_keys: Option<Value>,
+  /// Cached secrets (`m.megolm_backup.v1`, cross-signing private keys) from secret storage.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub secrets_cache: Option<Value>,
+  /// Sessions from an "Export E2E room keys" file, in the spec's export format.
+  pub exported_room_keys: Vec<Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ElementImportPreview {
+  pub session: ElementSession,
+  /// The access token is encrypted and no pickle key was given or found.
+  pub needs_pickle_key: bool,
+  pub has_olm_account: bool,
+  pub olm_sessions: usize,
+  pub inbound_group_sessions: usize,
+  pub has_backup_key: bool,
+}
+
+/// Crypto state waiting for the webview to take it over, per account key.
+#[derive(Default)]
+pub struct ElementImportState {
+  pending: Mutex<HashMap<String, ElementCrypto>>,
+}
+
+impl ElementImportState {
+  pub fn stage(&self, account_key: &str, crypto: ElementCrypto) {
+    if let Ok(mut pending) = self.pending.lock() {
+      pending.insert(account_key.to_string(), crypto);
+    }
+  }
+
+  pub fn take(&self, account_key: &str) -> Option<ElementCrypto> {
+    self.pending.lock().ok()?.remove(account_key)
+  }
+}
+
+/// A parsed export: localStorage plus the IndexedDB stores this import reads.
+pub struct ElementExport {
+  local_storage: Map<String, Value>,
+  indexed_db: Map<String, Value>,
+}
+
+/// Accepts `{ "localStorage": {...}, "indexedDB": { db: { store: [...] } } }` as written by
+/// the export snippet, or a flat session JSON of `mx_*` keys.
+pub fn parse_export(text: &str) -> Result<ElementExport, String> {
+  let value: Value = serde_json::from_str(text).map_err(|e| format!("Not an Element export: {}", e))?;
+  let Value::Object(mut root) = value else {
+    return Err("Not an Element export: expected a JSON object".into());
+  };
+  let indexed_db = match root.remove("indexedDB").or_else(|| root.remove("indexedDb")) {
+    Some(Value::Object(dbs)) => dbs,
+    _ => Map::new(),
+  };
+  let local_storage = match root.remove("localStorage") {
+    Some(Value::Object(storage)) => storage,
+    _ => root,
+  };
+  Ok(ElementExport { local_storage, indexed_db })
+}
+
+/// Records of an object store, exported either as `[{ key, value }]`, a plain array of values
+/// or a `{ key: value }` map. Returned as (key, value) pairs.
+fn store_records(export: &ElementExport, db: &str, store: &str) -> Vec<(Option<String>, Value)> {
+  let Some(records) = export.indexed_db.get(db).and_then(|db| db.get(store)) else {
+    return Vec::new();
+  };
+  match records {
+    Value::Array(items) => items
+      .iter()
+      .map(|item| match (item.get("key"), item.get("value")) {
+        (Some(key), Some(value)) => (key.as_str().map(|s| s.to_string()), value.clone()),
+        _ => (None, item.clone()),
+      })
+      .collect(),
+    Value::Object(map) => map.iter().map(|(key, value)| (Some(key.clone()), value.clone())).collect(),
+    _ => Vec::new(),
+  }
+}
+
+fn store_value(export: &ElementExport, db: &str, store: &str, key: &str) -> Option<Value> {
+  store_records(export, db, store)
+    .into_iter()
+    .find(|(record_key, _)| record_key.as_deref() == Some(key))
+    .map(|(_, value)| value)
+}
+
+fn local(export: &ElementExport, keys: &[&str]) -> Option<String> {
+  keys.iter().find_map(|key| {
+    export
+      .local_storage
+      .get(*key)
+      .and_then(|v| v.as_str())
+      .map(|s| s.trim().to_string())
+      .filter(|s| !s.is_empty())
+  })
+}
+
+/// Element turns the pickle key into an AES key with HKDF before encrypting tokens with it.
+fn pickle_key_to_aes_key(pickle_key: &str) -> Result<[u8; 32], String> {
+  let bytes: Vec<u8> = pickle_key.chars().map(|c| c as u8).collect();
+  let mut key = [0u8; 32];
+  Hkdf::<Sha256>::new(Some(&[0u8; 32]), &bytes)
+    .expand(&[], &mut key)
+    .map_err(|_| "HKDF expansion failed".to_string())?;
+  Ok(key)
+}
+
+fn decode_b64(value: &str) -> Result<Vec<u8>, String> {
+  general_purpose::STANDARD_NO_PAD
+    .decode(value.trim().trim_end_matches('='))
+    .map_err(|e| format!("Invalid base64 in Element export: {}", e))
+}
+
+/// Decrypt matrix-js-sdk's `{ iv, ciphertext, mac }` AES-CTR + HMAC-SHA256 envelope.
+fn decrypt_aes_envelope(envelope: &Value, key: &[u8; 32], name: &str) -> Result<String, String> {
+  let field = |f: &str| envelope.get(f).and_then(|v| v.as_str()).ok_or(format!("Encrypted {} lacks {}", name, f));
+  let iv = decode_b64(field("iv")?)?;
+  let mut ciphertext = decode_b64(field("ciphertext")?)?;
+  let mac = decode_b64(field("mac")?)?;
+  let mut keys = [0u8; 64];
+  Hkdf::<Sha256>::new(Some(&[0u8; 8]), key)
+    .expand(name.as_bytes(), &mut keys)
+    .map_err(|_| "HKDF expansion failed".to_string())?;
+  let mut hmac = Hmac::<Sha256>::new_from_slice(&keys[32..]).map_err(|e| e.to_string())?;
+  hmac.update(&ciphertext);
+  hmac.verify_slice(&mac).map_err(|_| "Wrong pickle key".to_string())?;
+  let iv: [u8; 16] = iv.try_into().map_err(|_| "Invalid IV in Element export".to_string())?;
+  let aes_key: [u8; 32] = keys[..32].try_into().map_err(|_| "Invalid key length".to_string())?;
+  Aes256Ctr64::new(&aes_key.into(), &iv.into()).apply_keystream(&mut ciphertext);
+  String::from_utf8(ciphertext).map_err(|e| e.to_string())
+}
+
+/// Element Desktop's pickle key for a session, from the OS keychain.
+pub fn desktop_pickle_key(user_id: &str, device_id: &str) -> Option<String> {
+  keyring::Entry::new(DESKTOP_KEYCHAIN_SERVICE, &format!("{}|{}", user_id, device_id))
+    .ok()?
+    .get_password()
+    .ok()
+}
+
+/// A token stored either in localStorage (older Element) or encrypted in IndexedDB.
+fn token(export: &ElementExport, name: &str, pickle_key: Option<&str>) -> Result<Option<String>, String> {
+  let storage_key = format!("mx_{}", name);
+  if let Some(token) = local(export, &[storage_key.as_str()]) {
+    return Ok(Some(token));
+  }
+  let Some(stored) = store_value(export, REACT_SDK_DB, "account", &storage_key) else {
+    return Ok(None);
+  };
+  if let Some(token) = stored.as_str() {
+    return Ok(Some(token.to_string()));
+  }
+  let pickle_key = pickle_key.ok_or("The access token is encrypted; the pickle key is needed")?;
+  decrypt_aes_envelope(&stored, &pickle_key_to_aes_key(pickle_key)?, name).map(Some)
+}
+
+/// The session without its tokens: enough to find the pickle key and show what will be imported.
+pub fn session_info(export: &ElementExport) -> Result<ElementSession, String> {
+  let homeserver_url = local(export, &["mx_hs_url", "homeserverUrl", "homeserver_url"])
+    .ok_or("The export contains no homeserver URL")?;
+  let user_id = local(export, &["mx_user_id", "userId", "user_id"]).ok_or("The export contains no user ID")?;
+  let device_id = local(export, &["mx_device_id", "deviceId", "device_id"]).ok_or("The export contains no device ID")?;
+  Ok(ElementSession {
+    homeserver_url,
+    user_id,
+    device_id,
+    identity_server_url: local(export, &["mx_is_url", "identityServerUrl"]),
+    access_token: String::new(),
+    refresh_token: None,
+  })
+}
+
+/// The session with its tokens decrypted.
+pub fn session(export: &ElementExport, pickle_key: Option<&str>) -> Result<ElementSession, String> {
+  let mut session = session_info(export)?;
+  session.access_token = match local(export, &["accessToken", "access_token"]) {
+    Some(token) => token,
+    None => token(export, "access_token", pickle_key)?.ok_or("The export contains no access token")?,
+  };
+  session.refresh_token = match local(export, &["refreshToken", "refresh_token"]) {
+    Some(token) => Some(token),
+    None => token(export, "refresh_token", pickle_key).ok().flatten(),
+  };
+  Ok(session)
+}
+
+pub fn token_encrypted(export: &ElementExport) -> bool {
+  local(export, &["mx_access_token", "accessToken", "access_token"]).is_none()
+    && store_value(export, REACT_SDK_DB, "account", "mx_access_token").is_some_and(|v| v.is_object())
+}
+
+/// The legacy crypto store's account pickle, Olm sessions, Megolm sessions and cached secrets.
+pub fn crypto(export: &ElementExport, pickle_key: Option<&str>) -> ElementCrypto {
+  let records = |store: &str| -> Vec<Value> {
+    store_records(export, LEGACY_CRYPTO_DB, store)
+      .into_iter()
+      .map(|(_, value)| value)
+      .collect()
+  };
+  ElementCrypto {
+    pickle_key: pickle_key.map(|key| key.to_string()),
+    olm_account_pickle: store_value(export, LEGACY_CRYPTO_DB, "account", "-")
+      .and_then(|v| v.as_str().map(|s| s.to_string())),
+    olm_sessions: records("sessions"),
+    inbound_group_sessions: records("inbound_group_sessions"),
+    cross_signing_keys: store_value(export, LEGACY_CRYPTO_DB, "account", "crossSigningKeys"),
+    secrets_cache: store_value(export, LEGACY_CRYPTO_DB, "account", "ssss_cache"),
+    exported_room_keys: Vec::new(),
+  }
+}
+
+pub fn preview(export: &ElementExport, pickle_key: Option<&str>) -> Result<ElementImportPreview, String> {
+  let session = session_info(export)?;
+  let crypto = crypto(export, pickle_key);
+  Ok(ElementImportPreview {
+    needs_pickle_key: pickle_key.is_none() && token_encrypted(export),
+    has_olm_account: crypto.olm_account_pickle.is_some(),
+    olm_sessions: crypto.olm_sessions.len(),
+    inbound_group_sessions: crypto.inbound_group_sessions.len(),
+    has_backup_key: crypto
+      .secrets_cache
+      .as_ref()
+      .is_some_and(|cache| cache.get("m.megolm_backup.v1").is_some()),
+    session,
+  })
+}
+
+/// Decrypt an "Export E2E room keys" file (PBKDF2-SHA512, AES-256-CTR, HMAC-SHA256).
+pub fn decrypt_key_export(armored: &str, passphrase: &str) -> Result<Vec<Value>, String> {
+  let body: String = armored
+    .lines()
+    .map(str::trim)
+    .skip_while(|line| *line != KEY_EXPORT_HEADER)
+    .skip(1)
+    .take_while(|line| *line != KEY_EXPORT_FOOTER)
+    .collect();
+  let data = general_purpose::STANDARD
+    .decode(body)
+    .map_err(|e| format!("Invalid room key export: {}", e))?;
+  if data.len() < 1 + 16 + 16 + 4 + 32 || data[0] != 1 {
+    return Err("Unsupported room key export".into());
+  }
+  let (signed, mac) = data.split_at(data.len() - 32);
+  let salt = &signed[1..17];
+  let iv: [u8; 16] = signed[17..33].try_into().map_err(|_| "Invalid room key export".to_string())?;
+  let rounds = u32::from_be_bytes([signed[33], signed[34], signed[35], signed[36]]);
+  let mut keys = [0u8; 64];
+  pbkdf2_hmac::<Sha512>(passphrase.as_bytes(), salt, rounds, &mut keys);
+  let mut hmac = Hmac::<Sha256>::new_from_slice(&keys[32..]).map_err(|e| e.to_string())?;
+  hmac.update(signed);
+  hmac.verify_slice(mac).map_err(|_| "Wrong passphrase for the room key export".to_string())?;
+  let aes_key: [u8; 32] = keys[..32].try_into().map_err(|_| "Invalid key length".to_string())?;
+  let mut plaintext = signed[37..].to_vec();
+  Aes256Ctr128::new(&aes_key.into(), &iv.into()).apply_keystream(&mut plaintext);
+  serde_json::from_slice(&plaintext).map_err(|e| format!("Invalid room key export: {}", e))
+}
@@ -0,0 +1,276 @@
+use lettre::message::{header::ContentType, Mailbox, MultiPart, SinglePart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::event_cache;
+use crate::keyword_alerts;
+use crate::matrix_api::now_millis;
+use crate::push_events;
+use crate::push_rules;
+
+const MAX_ITEMS: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SmtpSecurity {
+  /// Implicit TLS, usually port 465.
+  Tls,
+  #[default]
+  StartTls,
+  None,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SmtpConfig {
+  pub host: String,
+  pub port: u16,
+  #[serde(default)]
+  pub security: SmtpSecurity,
+  #[serde(default)]
+  pub username: Option<String>,
+  /// Never sent back to the webview; `None` on save keeps the stored password.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub password: Option<String>,
+  pub from: String,
+  pub to: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DigestDelivery {
+  #[default]
+  Email,
+  /// Write the HTML to `save_dir` instead of mailing it.
+  SaveLocally,
+}
+
+/// Opt-in digest of unread highlights, sent when the app hasn't been opened for a while.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DigestSettings {
+  pub enabled: bool,
+  /// Hours without opening the app before a digest goes out, and between digests after that.
+  pub absence_hours: u32,
+  #[serde(default)]
+  pub delivery: DigestDelivery,
+  #[serde(default)]
+  pub smtp: Option<SmtpConfig>,
+  #[serde(default)]
+  pub save_dir: Option<String>,
+}
+
+impl Default for DigestSettings {
+  fn default() -> Self {
+    DigestSettings {
+      enabled: false,
+      absence_hours: 48,
+      delivery: DigestDelivery::Email,
+      smtp: None,
+      save_dir: None,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DigestItem {
+  pub account_key: String,
+  pub room_id: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub room_name: Option<String>,
+  pub sender: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub sender_name: Option<String>,
+  pub body: String,
+  pub ts: i64,
+}
+
+/// Where a digest went: the recipient address or the saved file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DigestReport {
+  pub items: usize,
+  pub delivered_to: String,
+}
+
+pub fn init_email_digest_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS digest_state (
+        scope TEXT PRIMARY KEY,
+        last_active INTEGER NOT NULL,
+        last_sent INTEGER
+      );
+    ",
+  )
+}
+
+/// Note that the user has the app open.
+pub fn record_active(conn: &Connection) -> Result<(), String> {
+  conn
+    .execute(
+      "INSERT INTO digest_state (scope, last_active) VALUES ('', ?1)
+       ON CONFLICT(scope) DO UPDATE SET last_active = excluded.last_active",
+      [now_millis()],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+/// The time a digest would cover from, when one is due now.
+pub fn due_since(conn: &Connection, settings: &DigestSettings) -> Result<Option<i64>, String> {
+  if !settings.enabled {
+    return Ok(None);
+  }
+  let stored: Option<(i64, Option<i64>)> = conn
+    .query_row("SELECT last_active, last_sent FROM digest_state WHERE scope = ''", [], |row| {
+      Ok((row.get(0)?, row.get(1)?))
+    })
+    .optional()
+    .map_err(|e| e.to_string())?;
+  let Some((last_active, last_sent)) = stored else {
+    return Ok(None);
+  };
+  let since = last_sent.unwrap_or(0).max(last_active);
+  let absence = settings.absence_hours.max(1) as i64 * 60 * 60 * 1000;
+  Ok((now_millis() - since >= absence).then_some(since))
+}
+
+pub fn mark_sent(conn: &Connection) -> Result<(), String> {
+  conn
+    .execute("UPDATE digest_state SET last_sent = ?1 WHERE scope = ''", params![now_millis()])
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+/// Unread highlights (push rule highlights and keyword matches) newer than `since`, newest first.
+pub fn collect(conn: &Connection, accounts: &[(String, String)], since: i64) -> Result<Vec<DigestItem>, String> {
+  let mut seen = HashSet::new();
+  let mut items = Vec::new();
+  for (account_key, user_id) in accounts {
+    let mut push = |room_id: &str, event_id: &str, sender: &str, body: String, ts: i64| -> Result<(), String> {
+      if !seen.insert((account_key.clone(), event_id.to_string())) {
+        return Ok(());
+      }
+      items.push(DigestItem {
+        account_key: account_key.clone(),
+        room_id: room_id.to_string(),
+        room_name: event_cache::get_state_event(conn, account_key, room_id, "m.room.name", "")?
+          .and_then(|event| event.content.get("name").and_then(|v| v.as_str()).map(|s| s.to_string())),
+        sender: sender.to_string(),
+        sender_name: event_cache::member_profile(conn, account_key, room_id, sender)?.0,
+        body,
+        ts,
+      });
+      Ok(())
+    };
+    if let Some(rules) = push_rules::cached_rules(conn, account_key)? {
+      for room_id in &event_cache::cached_room_ids(conn, account_key)? {
+        let joined = event_cache::get_state_event(conn, account_key, room_id, "m.room.member", user_id)?
+          .is_some_and(|member| member.content.get("membership").and_then(|v| v.as_str()) == Some("join"));
+        if !joined {
+          continue;
+        }
+        for event in push_rules::unread_highlights(conn, account_key, &rules, room_id, user_id, since)? {
+          let body = push_events::preview(&event.to_raw()).unwrap_or_default();
+          push(room_id, &event.event_id, &event.sender, body, event.origin_server_ts)?;
+        }
+      }
+    }
+    for hit in keyword_alerts::highlights(conn, account_key, MAX_ITEMS)? {
+      if hit.ts > since {
+        push(&hit.room_id, &hit.event_id, &hit.sender, hit.body, hit.ts)?;
+      }
+    }
+  }
+  items.sort_by(|a, b| b.ts.cmp(&a.ts));
+  items.truncate(MAX_ITEMS);
+  Ok(items)
+}
+
+fn escape(text: &str) -> String {
+  text
+    .replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+}
+
+fn format_time(ts: i64) -> String {
+  chrono::DateTime::from_timestamp_millis(ts)
+    .map(|time| time.with_timezone(&chrono::Local).format("%a %d %b %H:%M").to_string())
+    .unwrap_or_default()
+}
+
+/// HTML and plain-text bodies, grouped by room.
+pub fn render(items: &[DigestItem]) -> (String, String) {
+  let mut rooms: Vec<(&str, Vec<&DigestItem>)> = Vec::new();
+  for item in items {
+    match rooms.iter_mut().find(|(room_id, _)| *room_id == item.room_id) {
+      Some((_, entries)) => entries.push(item),
+      None => rooms.push((&item.room_id, vec![item])),
+    }
+  }
+  let mut html = String::from(
+    "<!DOCTYPE html><html><body style=\"font-family:sans-serif;max-width:640px\">\
+     <h2>Messages waiting for you</h2>",
+  );
+  let mut text = String::from("Messages waiting for you\n");
+  for (room_id, entries) in rooms {
+    let room = entries[0].room_name.as_deref().unwrap_or(room_id);
+    html.push_str(&format!("<h3>{}</h3><ul>", escape(room)));
+    text.push_str(&format!("\n{}\n", room));
+    for item in entries {
+      let sender = item.sender_name.as_deref().unwrap_or(&item.sender);
+      html.push_str(&format!(
+        "<li><b>{}</b> <span style=\"color:#888\">{}</span><br>{}</li>",
+        escape(sender),
+        format_time(item.ts),
+        escape(&item.body)
+      ));
+      text.push_str(&format!("  {} ({}): {}\n", sender, format_time(item.ts), item.body));
+    }
+    html.push_str("</ul>");
+  }
+  html.push_str("<p style=\"color:#888\">Open Matrix Messenger to reply.</p></body></html>");
+  (html, text)
+}
+
+pub async fn send(config: &SmtpConfig, items: usize, html: String, text: String) -> Result<(), String> {
+  let parse = |address: &str| address.parse::<Mailbox>().map_err(|e| format!("Invalid address {}: {}", address, e));
+  let message = Message::builder()
+    .from(parse(&config.from)?)
+    .to(parse(&config.to)?)
+    .subject(format!("{} unread highlight{} on Matrix", items, if items == 1 { "" } else { "s" }))
+    .multipart(
+      MultiPart::alternative()
+        .singlepart(SinglePart::builder().header(ContentType::TEXT_PLAIN).body(text))
+        .singlepart(SinglePart::builder().header(ContentType::TEXT_HTML).body(html)),
+    )
+    .map_err(|e| e.to_string())?;
+  let builder = match config.security {
+    SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host),
+    SmtpSecurity::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host),
+    SmtpSecurity::None => Ok(AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host)),
+  }
+  .map_err(|e| e.to_string())?;
+  let mut builder = builder.port(config.port);
+  if let (Some(username), Some(password)) = (&config.username, &config.password) {
+    builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+  }
+  builder.build().send(message).await.map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+/// Write the HTML digest to `dir`, named after the current time.
+pub fn save(dir: &Path, html: &str) -> Result<PathBuf, String> {
+  fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+  let path = dir.join(format!("matrix-digest-{}.html", chrono::Local::now().format("%Y%m%d-%H%M")));
+  fs::write(&path, html).map_err(|e| e.to_string())?;
+  Ok(path)
+}
@@ -0,0 +1,497 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::ignore_list::not_ignored;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CachedEvent {
+  pub event_id: String,
+  pub room_id: String,
+  #[serde(rename = "type")]
+  pub event_type: String,
+  pub sender: String,
+  pub origin_server_ts: i64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub state_key: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub rel_type: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub relates_to: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub thread_root: Option<String>,
+  pub content: Value,
+}
+
+impl CachedEvent {
+  /// Parse a raw client-server event. Ephemeral events without an ID are rejected.
+  pub fn from_raw(room_id: &str, raw: &Value) -> Option<CachedEvent> {
+    let event_id = raw.get("event_id")?.as_str()?.to_string();
+    let event_type = raw.get("type")?.as_str()?.to_string();
+    let sender = raw.get("sender").and_then(|v| v.as_str()).unwrap_or_default().to_string();
+    let origin_server_ts = raw.get("origin_server_ts").and_then(|v| v.as_i64()).unwrap_or_default();
+    let state_key = raw.get("state_key").and_then(|v| v.as_str()).map(|s| s.to_string());
+    let content = raw.get("content").cloned().unwrap_or_else(|| json!({}));
+    let relation = content.get("m.relates_to");
+    let rel_type = relation
+      .and_then(|r| r.get("rel_type"))
+      .and_then(|v| v.as_str())
+      .map(|s| s.to_string());
+    let relates_to = relation
+      .and_then(|r| r.get("event_id"))
+      .and_then(|v| v.as_str())
+      .map(|s| s.to_string());
+    let thread_root = if rel_type.as_deref() == Some("m.thread") { relates_to.clone() } else { None };
+    Some(CachedEvent {
+      event_id,
+      room_id: room_id.to_string(),
+      event_type,
+      sender,
+      origin_server_ts,
+      state_key,
+      rel_type,
+      relates_to,
+      thread_root,
+      content,
+    })
+  }
+
+  /// Render back into the client-server event shape handed to the webview.
+  pub fn to_raw(&self) -> Value {
+    let mut raw = json!({
+      "event_id": self.event_id,
+      "room_id": self.room_id,
+      "type": self.event_type,
+      "sender": self.sender,
+      "origin_server_ts": self.origin_server_ts,
+      "content": self.content,
+    });
+    if let Some(state_key) = &self.state_key {
+      raw["state_key"] = json!(state_key);
+    }
+    raw
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadReceipt {
+  pub room_id: String,
+  pub user_id: String,
+  pub event_id: String,
+  pub thread_id: String,
+  pub ts: i64,
+}
+
+/// Caches written before rows were keyed by account can't be attributed to one, so they are
+/// dropped and refilled by the next sync.
+pub fn drop_unscoped(conn: &Connection, tables: &[&str]) -> Result<(), rusqlite::Error> {
+  for table in tables {
+    let unscoped: bool = conn.query_row(
+      "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)
+         AND NOT EXISTS(SELECT 1 FROM pragma_table_info(?1) WHERE name = 'account_key')",
+      [table],
+      |row| row.get(0),
+    )?;
+    if unscoped {
+      conn.execute_batch(&format!("DROP TABLE {};", table))?;
+    }
+  }
+  Ok(())
+}
+
+pub fn init_event_cache_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  drop_unscoped(conn, &["event_cache", "read_receipts"])?;
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS event_cache (
+        account_key TEXT NOT NULL,
+        room_id TEXT NOT NULL,
+        event_id TEXT NOT NULL,
+        event_type TEXT NOT NULL,
+        sender TEXT NOT NULL,
+        origin_server_ts INTEGER NOT NULL,
+        state_key TEXT,
+        rel_type TEXT,
+        relates_to TEXT,
+        thread_root TEXT,
+        content_json TEXT NOT NULL,
+        PRIMARY KEY (account_key, room_id, event_id)
+      );
+      DROP INDEX IF EXISTS idx_event_cache_relates;
+      DROP INDEX IF EXISTS idx_event_cache_thread;
+      DROP INDEX IF EXISTS idx_event_cache_state;
+      CREATE INDEX IF NOT EXISTS idx_event_cache_account_relates ON event_cache(account_key, room_id, relates_to);
+      CREATE INDEX IF NOT EXISTS idx_event_cache_account_thread ON event_cache(account_key, room_id, thread_root);
+      CREATE INDEX IF NOT EXISTS idx_event_cache_account_state ON event_cache(account_key, room_id, event_type, state_key);
+      CREATE INDEX IF NOT EXISTS idx_event_cache_event ON event_cache(account_key, event_id);
+      CREATE TABLE IF NOT EXISTS read_receipts (
+        account_key TEXT NOT NULL,
+        room_id TEXT NOT NULL,
+        user_id TEXT NOT NULL,
+        thread_id TEXT NOT NULL,
+        event_id TEXT NOT NULL,
+        ts INTEGER NOT NULL,
+        PRIMARY KEY (account_key, room_id, user_id, thread_id)
+      );
+    ",
+  )
+}
+
+pub fn upsert_event(conn: &Connection, account_key: &str, event: &CachedEvent) -> Result<(), String> {
+  let content_json = serde_json::to_string(&event.content).map_err(|e| e.to_string())?;
+  conn
+    .execute(
+      "INSERT INTO event_cache (
+          account_key, room_id, event_id, event_type, sender, origin_server_ts, state_key, rel_type, relates_to, thread_root,
+          content_json
+        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
+        ON CONFLICT(account_key, room_id, event_id) DO UPDATE SET
+          event_type = excluded.event_type,
+          sender = excluded.sender,
+          origin_server_ts = excluded.origin_server_ts,
+          state_key = excluded.state_key,
+          rel_type = excluded.rel_type,
+          relates_to = excluded.relates_to,
+          thread_root = excluded.thread_root,
+          content_json = excluded.content_json",
+      params![
+        account_key,
+        event.room_id,
+        event.event_id,
+        event.event_type,
+        event.sender,
+        event.origin_server_ts,
+        event.state_key,
+        event.rel_type,
+        event.relates_to,
+        event.thread_root,
+        content_json,
+      ],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+/// Store `m.receipt` EDUs, keeping the newest receipt per user and thread.
+pub fn record_receipts(conn: &Connection, account_key: &str, room_id: &str, receipt: &Value) -> Result<(), String> {
+  let content = match receipt.get("content").and_then(|v| v.as_object()) {
+    Some(content) => content,
+    None => return Ok(()),
+  };
+  for (event_id, kinds) in content {
+    for kind in ["m.read", "m.read.private"] {
+      let users = match kinds.get(kind).and_then(|v| v.as_object()) {
+        Some(users) => users,
+        None => continue,
+      };
+      for (user_id, data) in users {
+        let ts = data.get("ts").and_then(|v| v.as_i64()).unwrap_or_default();
+        let thread_id = data.get("thread_id").and_then(|v| v.as_str()).unwrap_or("unthreaded");
+        conn
+          .execute(
+            "INSERT INTO read_receipts (account_key, room_id, user_id, thread_id, event_id, ts)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT(account_key, room_id, user_id, thread_id) DO UPDATE SET
+               event_id = excluded.event_id,
+               ts = excluded.ts
+             WHERE excluded.ts >= read_receipts.ts",
+            params![account_key, room_id, user_id, thread_id, event_id, ts],
+          )
+          .map_err(|e| e.to_string())?;
+      }
+    }
+  }
+  Ok(())
+}
+
+pub fn cache_events(conn: &Connection, account_key: &str, room_id: &str, events: &[Value]) -> Result<usize, String> {
+  let tx = conn.unchecked_transaction().map_err(|e| e.to_string())?;
+  let mut stored = 0;
+  for raw in events {
+    if raw.get("type").and_then(|v| v.as_str()) == Some("m.receipt") {
+      record_receipts(&tx, account_key, room_id, raw)?;
+      continue;
+    }
+    if let Some(event) = CachedEvent::from_raw(room_id, raw) {
+      upsert_event(&tx, account_key, &event)?;
+      stored += 1;
+    }
+  }
+  tx.commit().map_err(|e| e.to_string())?;
+  Ok(stored)
+}
+
+const EVENT_COLUMNS: &str =
+  "room_id, event_id, event_type, sender, origin_server_ts, state_key, rel_type, relates_to, thread_root, content_json";
+
+fn row_to_event(row: &rusqlite::Row) -> rusqlite::Result<CachedEvent> {
+  let content_json: String = row.get(9)?;
+  Ok(CachedEvent {
+    room_id: row.get(0)?,
+    event_id: row.get(1)?,
+    event_type: row.get(2)?,
+    sender: row.get(3)?,
+    origin_server_ts: row.get(4)?,
+    state_key: row.get(5)?,
+    rel_type: row.get(6)?,
+    relates_to: row.get(7)?,
+    thread_root: row.get(8)?,
+    content: serde_json::from_str(&content_json).unwrap_or_else(|_| json!({})),
+  })
+}
+
+pub fn get_event(conn: &Connection, account_key: &str, room_id: &str, event_id: &str) -> Result<Option<CachedEvent>, String> {
+  conn
+    .query_row(
+      &format!(
+        "SELECT {} FROM event_cache WHERE account_key = ?1 AND room_id = ?2 AND event_id = ?3 AND {}",
+        EVENT_COLUMNS, not_ignored("event_cache")
+      ),
+      params![account_key, room_id, event_id],
+      row_to_event,
+    )
+    .optional()
+    .map_err(|e| e.to_string())
+}
+
+/// Look up an event by ID without its room; event IDs are unique across rooms.
+pub fn find_event(conn: &Connection, account_key: &str, event_id: &str) -> Result<Option<CachedEvent>, String> {
+  conn
+    .query_row(
+      &format!(
+        "SELECT {} FROM event_cache WHERE account_key = ?1 AND event_id = ?2 AND {} LIMIT 1",
+        EVENT_COLUMNS, not_ignored("event_cache")
+      ),
+      params![account_key, event_id],
+      row_to_event,
+    )
+    .optional()
+    .map_err(|e| e.to_string())
+}
+
+/// All cached events relating to `event_id`, optionally narrowed to one relation type.
+pub fn get_relations(
+  conn: &Connection,
+  account_key: &str,
+  room_id: &str,
+  event_id: &str,
+  rel_type: Option<&str>,
+) -> Result<Vec<CachedEvent>, String> {
+  let mut stmt = conn
+    .prepare(&format!(
+      "SELECT {} FROM event_cache
+       WHERE account_key = ?1 AND room_id = ?2 AND relates_to = ?3 AND (?4 IS NULL OR rel_type = ?4) AND {}
+       ORDER BY origin_server_ts ASC",
+      EVENT_COLUMNS, not_ignored("event_cache")
+    ))
+    .map_err(|e| e.to_string())?;
+  let rows = stmt
+    .query_map(params![account_key, room_id, event_id, rel_type], row_to_event)
+    .map_err(|e| e.to_string())?;
+  let mut out = Vec::new();
+  for row in rows {
+    if let Ok(event) = row { out.push(event); }
+  }
+  Ok(out)
+}
+
+pub fn get_state_event(
+  conn: &Connection,
+  account_key: &str,
+  room_id: &str,
+  event_type: &str,
+  state_key: &str,
+) -> Result<Option<CachedEvent>, String> {
+  conn
+    .query_row(
+      &format!(
+        "SELECT {} FROM event_cache WHERE account_key = ?1 AND room_id = ?2 AND event_type = ?3 AND state_key = ?4
+         ORDER BY origin_server_ts DESC LIMIT 1",
+        EVENT_COLUMNS
+      ),
+      params![account_key, room_id, event_type, state_key],
+      row_to_event,
+    )
+    .optional()
+    .map_err(|e| e.to_string())
+}
+
+pub fn get_receipt(
+  conn: &Connection,
+  account_key: &str,
+  room_id: &str,
+  user_id: &str,
+  thread_id: &str,
+) -> Result<Option<ReadReceipt>, String> {
+  conn
+    .query_row(
+      "SELECT room_id, user_id, thread_id, event_id, ts FROM read_receipts
+       WHERE account_key = ?1 AND room_id = ?2 AND user_id = ?3 AND thread_id = ?4",
+      params![account_key, room_id, user_id, thread_id],
+      |row| {
+        Ok(ReadReceipt {
+          room_id: row.get(0)?,
+          user_id: row.get(1)?,
+          thread_id: row.get(2)?,
+          event_id: row.get(3)?,
+          ts: row.get(4)?,
+        })
+      },
+    )
+    .optional()
+    .map_err(|e| e.to_string())
+}
+
+/// Display name and avatar for a room member, as last seen in cached state.
+pub fn member_profile(
+  conn: &Connection,
+  account_key: &str,
+  room_id: &str,
+  user_id: &str,
+) -> Result<(Option<String>, Option<String>), String> {
+  let member = get_state_event(conn, account_key, room_id, "m.room.member", user_id)?;
+  Ok(match member {
+    Some(event) => (
+      event.content.get("displayname").and_then(|v| v.as_str()).map(|s| s.to_string()),
+      event.content.get("avatar_url").and_then(|v| v.as_str()).map(|s| s.to_string()),
+    ),
+    None => (None, None),
+  })
+}
+
+/// Timeline events newer than `after_ts`, oldest first.
+pub fn events_since(conn: &Connection, account_key: &str, room_id: &str, after_ts: i64) -> Result<Vec<CachedEvent>, String> {
+  let mut stmt = conn
+    .prepare(&format!(
+      "SELECT {} FROM event_cache
+       WHERE account_key = ?1 AND room_id = ?2 AND origin_server_ts > ?3 AND {}
+       ORDER BY origin_server_ts ASC",
+      EVENT_COLUMNS, not_ignored("event_cache")
+    ))
+    .map_err(|e| e.to_string())?;
+  let rows = stmt
+    .query_map(params![account_key, room_id, after_ts], row_to_event)
+    .map_err(|e| e.to_string())?;
+  Ok(rows.flatten().collect())
+}
+
+/// Events between two timestamps (either bound optional), oldest first.
+pub fn events_between(
+  conn: &Connection,
+  account_key: &str,
+  room_id: &str,
+  since: Option<i64>,
+  until: Option<i64>,
+) -> Result<Vec<CachedEvent>, String> {
+  let mut stmt = conn
+    .prepare(&format!(
+      "SELECT {} FROM event_cache
+       WHERE account_key = ?1 AND room_id = ?2 AND (?3 IS NULL OR origin_server_ts >= ?3)
+         AND (?4 IS NULL OR origin_server_ts <= ?4) AND {}
+       ORDER BY origin_server_ts ASC",
+      EVENT_COLUMNS, not_ignored("event_cache")
+    ))
+    .map_err(|e| e.to_string())?;
+  let rows = stmt
+    .query_map(params![account_key, room_id, since, until], row_to_event)
+    .map_err(|e| e.to_string())?;
+  Ok(rows.flatten().collect())
+}
+
+/// Joined members according to the newest cached `m.room.member` event per user.
+pub fn joined_member_count(conn: &Connection, account_key: &str, room_id: &str) -> Result<usize, String> {
+  conn
+    .query_row(
+      "SELECT COUNT(*) FROM event_cache e
+       WHERE e.account_key = ?1 AND e.room_id = ?2 AND e.event_type = 'm.room.member'
+         AND json_extract(e.content_json, '$.membership') = 'join'
+         AND e.origin_server_ts = (
+           SELECT MAX(origin_server_ts) FROM event_cache
+           WHERE account_key = e.account_key AND room_id = e.room_id AND event_type = 'm.room.member'
+             AND state_key = e.state_key
+         )",
+      [account_key, room_id],
+      |row| row.get::<_, i64>(0),
+    )
+    .map(|count| count as usize)
+    .map_err(|e| e.to_string())
+}
+
+/// Rooms with anything cached for the account.
+pub fn cached_room_ids(conn: &Connection, account_key: &str) -> Result<Vec<String>, String> {
+  let mut stmt = conn
+    .prepare("SELECT DISTINCT room_id FROM event_cache WHERE account_key = ?1")
+    .map_err(|e| e.to_string())?;
+  let rows = stmt
+    .query_map([account_key], |row| row.get::<_, String>(0))
+    .map_err(|e| e.to_string())?;
+  Ok(rows.flatten().collect())
+}
+
+/// Rooms ordered by their newest cached timeline event, most recent first.
+pub fn recent_room_ids(conn: &Connection, account_key: &str, limit: usize) -> Result<Vec<String>, String> {
+  let mut stmt = conn
+    .prepare(&format!(
+      "SELECT room_id FROM event_cache WHERE account_key = ?1 AND state_key IS NULL AND {}
+       GROUP BY room_id ORDER BY MAX(origin_server_ts) DESC LIMIT ?2",
+      not_ignored("event_cache")
+    ))
+    .map_err(|e| e.to_string())?;
+  let rows = stmt
+    .query_map(params![account_key, limit as i64], |row| row.get::<_, String>(0))
+    .map_err(|e| e.to_string())?;
+  Ok(rows.flatten().collect())
+}
+
+/// The newest cached timeline event in the room.
+pub fn latest_event(conn: &Connection, account_key: &str, room_id: &str) -> Result<Option<CachedEvent>, String> {
+  conn
+    .query_row(
+      &format!(
+        "SELECT {} FROM event_cache WHERE account_key = ?1 AND room_id = ?2 AND state_key IS NULL AND {}
+         ORDER BY origin_server_ts DESC LIMIT 1",
+        EVENT_COLUMNS, not_ignored("event_cache")
+      ),
+      [account_key, room_id],
+      row_to_event,
+    )
+    .optional()
+    .map_err(|e| e.to_string())
+}
+
+/// The `file` (or thumbnail `file`) object of a cached encrypted attachment stored at `mxc_url`.
+pub fn encrypted_file(conn: &Connection, account_key: &str, mxc_url: &str) -> Result<Option<Value>, String> {
+  let file: Option<String> = conn
+    .query_row(
+      "SELECT CASE WHEN json_extract(content_json, '$.file.url') = ?2
+                THEN json_extract(content_json, '$.file')
+                ELSE json_extract(content_json, '$.info.thumbnail_file') END
+       FROM event_cache
+       WHERE account_key = ?1
+         AND (json_extract(content_json, '$.file.url') = ?2 OR json_extract(content_json, '$.info.thumbnail_file.url') = ?2)
+       LIMIT 1",
+      params![account_key, mxc_url],
+      |row| row.get(0),
+    )
+    .optional()
+    .map_err(|e| e.to_string())?
+    .flatten();
+  Ok(file.and_then(|file| serde_json::from_str(&file).ok()))
+}
+
+/// Whether `mxc_url` was indexed from a room the account has cached as encrypted.
+pub fn in_encrypted_room(conn: &Connection, account_key: &str, mxc_url: &str) -> Result<bool, String> {
+  conn
+    .query_row(
+      "SELECT EXISTS(
+         SELECT 1 FROM media_index m
+         JOIN event_cache e
+           ON e.account_key = m.account_key AND e.room_id = m.room_id AND e.event_type = 'm.room.encryption'
+         WHERE m.account_key = ?1 AND (m.mxc_url = ?2 OR m.thumbnail_mxc = ?2)
+       )",
+      params![account_key, mxc_url],
+      |row| row.get(0),
+    )
+    .map_err(|e| e.to_string())
+}
@@ -0,0 +1,449 @@
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+use crate::matrix_api::now_millis;
+
+pub const MIN_INTERVAL_MINUTES: u32 = 5;
+pub const DEFAULT_INTERVAL_MINUTES: u32 = 30;
+/// New entries posted per poll; older unseen ones are skipped so a long outage doesn't flood the room.
+pub const MAX_POSTS_PER_POLL: usize = 10;
+/// Entries posted when a feed is first added, to show it works.
+pub const INITIAL_POSTS: usize = 1;
+const SUMMARY_CHARS: usize = 400;
+const MAX_FEED_BYTES: usize = 5 * 1024 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FeedSubscription {
+  pub id: i64,
+  pub account_key: String,
+  pub room_id: String,
+  pub url: String,
+  pub title: String,
+  pub interval_minutes: u32,
+  pub enabled: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub etag: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub last_modified: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub last_checked_at: Option<i64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub last_error: Option<String>,
+  pub created_at: i64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FeedEntry {
+  /// guid / id, or the link when the feed has neither.
+  pub id: String,
+  pub title: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub link: Option<String>,
+  /// Plain text, shortened.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub summary: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ParsedFeed {
+  pub title: String,
+  /// Newest first, as feeds list them.
+  pub entries: Vec<FeedEntry>,
+}
+
+pub enum FetchOutcome {
+  NotModified,
+  Fetched {
+    feed: ParsedFeed,
+    etag: Option<String>,
+    last_modified: Option<String>,
+  },
+}
+
+pub fn init_feeds_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS feed_subscriptions (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        account_key TEXT NOT NULL,
+        room_id TEXT NOT NULL,
+        url TEXT NOT NULL,
+        title TEXT NOT NULL,
+        interval_minutes INTEGER NOT NULL,
+        enabled INTEGER NOT NULL DEFAULT 1,
+        etag TEXT,
+        last_modified TEXT,
+        last_checked_at INTEGER,
+        last_error TEXT,
+        created_at INTEGER NOT NULL,
+        UNIQUE (room_id, url)
+      );
+      CREATE TABLE IF NOT EXISTS feed_seen (
+        subscription_id INTEGER NOT NULL,
+        entry_id TEXT NOT NULL,
+        seen_at INTEGER NOT NULL,
+        PRIMARY KEY (subscription_id, entry_id)
+      );
+    ",
+  )
+}
+
+fn row_to_subscription(row: &rusqlite::Row) -> rusqlite::Result<FeedSubscription> {
+  Ok(FeedSubscription {
+    id: row.get(0)?,
+    account_key: row.get(1)?,
+    room_id: row.get(2)?,
+    url: row.get(3)?,
+    title: row.get(4)?,
+    interval_minutes: row.get(5)?,
+    enabled: row.get::<_, i64>(6)? != 0,
+    etag: row.get(7)?,
+    last_modified: row.get(8)?,
+    last_checked_at: row.get(9)?,
+    last_error: row.get(10)?,
+    created_at: row.get(11)?,
+  })
+}
+
+const COLUMNS: &str = "id, account_key, room_id, url, title, interval_minutes, enabled, etag, last_modified, last_checked_at, last_error, created_at";
+
+pub fn list(conn: &Connection, room_id: Option<&str>) -> Result<Vec<FeedSubscription>, String> {
+  let mut stmt = conn
+    .prepare(&format!(
+      "SELECT {} FROM feed_subscriptions WHERE ?1 IS NULL OR room_id = ?1 ORDER BY created_at",
+      COLUMNS
+    ))
+    .map_err(|e| e.to_string())?;
+  let rows = stmt.query_map(params![room_id], row_to_subscription).map_err(|e| e.to_string())?;
+  Ok(rows.flatten().collect())
+}
+
+pub fn get(conn: &Connection, id: i64) -> Result<Option<FeedSubscription>, String> {
+  conn
+    .query_row(
+      &format!("SELECT {} FROM feed_subscriptions WHERE id = ?1", COLUMNS),
+      params![id],
+      row_to_subscription,
+    )
+    .optional()
+    .map_err(|e| e.to_string())
+}
+
+/// Enabled subscriptions whose interval has passed.
+pub fn due(conn: &Connection, now: i64) -> Result<Vec<FeedSubscription>, String> {
+  let mut stmt = conn
+    .prepare(&format!(
+      "SELECT {} FROM feed_subscriptions
+       WHERE enabled = 1 AND (last_checked_at IS NULL OR last_checked_at + interval_minutes * 60000 <= ?1)",
+      COLUMNS
+    ))
+    .map_err(|e| e.to_string())?;
+  let rows = stmt.query_map(params![now], row_to_subscription).map_err(|e| e.to_string())?;
+  Ok(rows.flatten().collect())
+}
+
+pub fn insert(
+  conn: &Connection,
+  account_key: &str,
+  room_id: &str,
+  url: &str,
+  title: &str,
+  interval_minutes: u32,
+) -> Result<i64, String> {
+  conn
+    .execute(
+      "INSERT INTO feed_subscriptions (account_key, room_id, url, title, interval_minutes, enabled, created_at)
+       VALUES (?1, ?2, ?3, ?4, ?5, 1, ?6)",
+      params![account_key, room_id, url, title, interval_minutes.max(MIN_INTERVAL_MINUTES), now_millis()],
+    )
+    .map_err(|e| match e {
+      rusqlite::Error::SqliteFailure(err, _) if err.code == rusqlite::ErrorCode::ConstraintViolation => {
+        "This room is already subscribed to the feed".to_string()
+      }
+      other => other.to_string(),
+    })?;
+  Ok(conn.last_insert_rowid())
+}
+
+pub fn update(conn: &Connection, id: i64, enabled: bool, interval_minutes: u32) -> Result<(), String> {
+  conn
+    .execute(
+      "UPDATE feed_subscriptions SET enabled = ?2, interval_minutes = ?3 WHERE id = ?1",
+      params![id, enabled as i64, interval_minutes.max(MIN_INTERVAL_MINUTES)],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+pub fn remove(conn: &Connection, id: i64) -> Result<(), String> {
+  conn
+    .execute("DELETE FROM feed_seen WHERE subscription_id = ?1", params![id])
+    .map_err(|e| e.to_string())?;
+  conn
+    .execute("DELETE FROM feed_subscriptions WHERE id = ?1", params![id])
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+/// Record a poll: new cache validators on success, the error otherwise.
+pub fn record_check(
+  conn: &Connection,
+  id: i64,
+  etag: Option<&str>,
+  last_modified: Option<&str>,
+  error: Option<&str>,
+) -> Result<(), String> {
+  conn
+    .execute(
+      "UPDATE feed_subscriptions SET last_checked_at = ?2, last_error = ?5,
+         etag = COALESCE(?3, etag), last_modified = COALESCE(?4, last_modified)
+       WHERE id = ?1",
+      params![id, now_millis(), etag, last_modified, error],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+/// Entries of `feed` not posted before, oldest first.
+pub fn unseen(conn: &Connection, subscription_id: i64, feed: &ParsedFeed) -> Result<Vec<FeedEntry>, String> {
+  let mut stmt = conn
+    .prepare("SELECT 1 FROM feed_seen WHERE subscription_id = ?1 AND entry_id = ?2")
+    .map_err(|e| e.to_string())?;
+  let mut out = Vec::new();
+  for entry in feed.entries.iter().rev() {
+    if !stmt.exists(params![subscription_id, entry.id]).map_err(|e| e.to_string())? {
+      out.push(entry.clone());
+    }
+  }
+  Ok(out)
+}
+
+pub fn mark_seen(conn: &Connection, subscription_id: i64, entry_ids: &[String]) -> Result<(), String> {
+  let now = now_millis();
+  for entry_id in entry_ids {
+    conn
+      .execute(
+        "INSERT OR IGNORE INTO feed_seen (subscription_id, entry_id, seen_at) VALUES (?1, ?2, ?3)",
+        params![subscription_id, entry_id, now],
+      )
+      .map_err(|e| e.to_string())?;
+  }
+  Ok(())
+}
+
+fn attribute(element: &BytesStart, name: &[u8]) -> Option<String> {
+  element
+    .attributes()
+    .flatten()
+    .find(|a| a.key.local_name().as_ref() == name)
+    .and_then(|a| a.unescape_value().ok().map(|v| v.to_string()))
+}
+
+/// Text of HTML summaries, with tags dropped and whitespace collapsed.
+fn plain_text(html: &str) -> String {
+  let mut text = String::with_capacity(html.len());
+  let mut in_tag = false;
+  for c in html.chars() {
+    match c {
+      '<' => in_tag = true,
+      '>' if in_tag => {
+        in_tag = false;
+        text.push(' ');
+      }
+      _ if !in_tag => text.push(c),
+      _ => {}
+    }
+  }
+  let text = text
+    .replace("&nbsp;", " ")
+    .replace("&quot;", "\"")
+    .replace("&#39;", "'")
+    .replace("&lt;", "<")
+    .replace("&gt;", ">")
+    .replace("&amp;", "&");
+  text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn shorten(text: &str) -> String {
+  if text.chars().count() <= SUMMARY_CHARS {
+    return text.to_string();
+  }
+  let cut: String = text.chars().take(SUMMARY_CHARS).collect();
+  let cut = cut.rsplit_once(' ').map(|(head, _)| head).unwrap_or(&cut);
+  format!("{}…", cut)
+}
+
+/// RSS 2.0, RSS 1.0 (RDF) and Atom feeds.
+pub fn parse(xml: &str) -> Result<ParsedFeed, String> {
+  let mut reader = Reader::from_str(xml);
+  reader.config_mut().trim_text(true);
+  let mut feed = ParsedFeed::default();
+  let mut path: Vec<String> = Vec::new();
+  let mut entry: Option<FeedEntry> = None;
+  let mut summary = String::new();
+  let mut recognized = false;
+  loop {
+    let event = reader.read_event().map_err(|e| format!("Not a valid feed: {}", e))?;
+    let (element, is_empty) = match &event {
+      Event::Start(e) => (Some(e.clone()), false),
+      Event::Empty(e) => (Some(e.clone()), true),
+      _ => (None, false),
+    };
+    if let Some(element) = element {
+      let name = String::from_utf8_lossy(element.local_name().as_ref()).to_string();
+      match name.as_str() {
+        "rss" | "feed" | "RDF" => recognized = true,
+        "item" | "entry" => {
+          entry = Some(FeedEntry::default());
+          summary.clear();
+        }
+        // Atom links are attributes; prefer rel="alternate" (the default).
+        "link" if entry.is_some() => {
+          if let (Some(href), Some(current)) = (attribute(&element, b"href"), entry.as_mut()) {
+            let rel = attribute(&element, b"rel").unwrap_or_else(|| "alternate".into());
+            if rel == "alternate" || current.link.is_none() {
+              current.link = Some(href);
+            }
+          }
+        }
+        _ => {}
+      }
+      if !is_empty {
+        path.push(name);
+      }
+      continue;
+    }
+    let text = match event {
+      Event::Text(t) => t.unescape().map(|s| s.to_string()).unwrap_or_default(),
+      Event::CData(c) => String::from_utf8_lossy(&c.into_inner()).to_string(),
+      Event::End(e) => {
+        let name = String::from_utf8_lossy(e.local_name().as_ref()).to_string();
+        path.pop();
+        if name == "item" || name == "entry" {
+          if let Some(mut done) = entry.take() {
+            if done.id.is_empty() {
+              done.id = done.link.clone().unwrap_or_else(|| done.title.clone());
+            }
+            let text = plain_text(&summary);
+            done.summary = (!text.is_empty()).then(|| shorten(&text));
+            if done.title.is_empty() {
+              done.title = done.summary.clone().unwrap_or_default();
+            }
+            if !done.id.is_empty() {
+              feed.entries.push(done);
+            }
+          }
+        }
+        continue;
+      }
+      Event::Eof => break,
+      _ => continue,
+    };
+    let Some(current_name) = path.last().map(|s| s.as_str()) else {
+      continue;
+    };
+    match entry.as_mut() {
+      Some(current) => match current_name {
+        "title" => current.title.push_str(text.trim()),
+        "link" if current.link.is_none() => current.link = Some(text.trim().to_string()),
+        "guid" | "id" => current.id = text.trim().to_string(),
+        "description" | "summary" | "content" | "encoded" if summary.is_empty() => summary.push_str(&text),
+        _ => {}
+      },
+      None => {
+        let parent = path.len().checked_sub(2).and_then(|i| path.get(i)).map(|s| s.as_str());
+        if current_name == "title" && matches!(parent, Some("channel") | Some("feed")) && feed.title.is_empty() {
+          feed.title = text.trim().to_string();
+        }
+      }
+    }
+  }
+  if !recognized {
+    return Err("Not an RSS or Atom feed".into());
+  }
+  Ok(feed)
+}
+
+/// Fetch a feed, sending the stored validators so unchanged feeds cost a 304.
+pub async fn fetch(url: &str, etag: Option<&str>, last_modified: Option<&str>) -> Result<FetchOutcome, String> {
+  let parsed = reqwest::Url::parse(url).map_err(|e| format!("Invalid feed URL: {}", e))?;
+  if !matches!(parsed.scheme(), "http" | "https") {
+    return Err("Only http and https feeds are supported".into());
+  }
+  let http = crate::proxy::apply(reqwest::Client::builder())
+    .user_agent(concat!("matrix-messenger/", env!("CARGO_PKG_VERSION")))
+    .timeout(Duration::from_secs(30))
+    .build()
+    .map_err(|e| format!("Failed to create HTTP client: {}", e))?;
+  let mut request = http
+    .get(parsed)
+    .header(reqwest::header::ACCEPT, "application/rss+xml, application/atom+xml, application/xml;q=0.9, */*;q=0.8");
+  if let Some(etag) = etag {
+    request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+  }
+  if let Some(last_modified) = last_modified {
+    request = request.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+  }
+  let response = request.send().await.map_err(|e| e.to_string())?;
+  if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+    return Ok(FetchOutcome::NotModified);
+  }
+  if !response.status().is_success() {
+    return Err(format!("Feed returned HTTP {}", response.status().as_u16()));
+  }
+  let header = |name: reqwest::header::HeaderName| {
+    response.headers().get(name).and_then(|v| v.to_str().ok()).map(|s| s.to_string())
+  };
+  let etag = header(reqwest::header::ETAG);
+  let last_modified = header(reqwest::header::LAST_MODIFIED);
+  if response.content_length().is_some_and(|len| len as usize > MAX_FEED_BYTES) {
+    return Err("Feed is too large".into());
+  }
+  let bytes = response.bytes().await.map_err(|e| e.to_string())?;
+  if bytes.len() > MAX_FEED_BYTES {
+    return Err("Feed is too large".into());
+  }
+  let feed = parse(&String::from_utf8_lossy(&bytes))?;
+  Ok(FetchOutcome::Fetched {
+    feed,
+    etag,
+    last_modified,
+  })
+}
+
+fn escape(text: &str) -> String {
+  text
+    .replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+}
+
+/// An `m.notice` with the linked title, the summary and the feed name.
+pub fn entry_content(feed_title: &str, entry: &FeedEntry) -> Value {
+  let mut body = entry.title.clone();
+  let mut html = match &entry.link {
+    Some(link) => format!("<strong><a href=\"{}\">{}</a></strong>", escape(link), escape(&entry.title)),
+    None => format!("<strong>{}</strong>", escape(&entry.title)),
+  };
+  if let Some(link) = &entry.link {
+    body.push_str(&format!("\n{}", link));
+  }
+  if let Some(summary) = &entry.summary {
+    body.push_str(&format!("\n\n{}", summary));
+    html.push_str(&format!("<br>{}", escape(summary)));
+  }
+  body.push_str(&format!("\n— {}", feed_title));
+  html.push_str(&format!("<br><em>{}</em>", escape(feed_title)));
+  json!({
+    "msgtype": "m.notice",
+    "body": body,
+    "format": "org.matrix.custom.html",
+    "formatted_body": html,
+  })
+}
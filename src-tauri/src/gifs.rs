@@ -0,0 +1,284 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::matrix_api::now_millis;
+
+const SEARCH_TTL_MS: i64 = 60 * 60 * 1000;
+const CLIENT_KEY: &str = "matrix-messenger";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum GifProvider {
+  Tenor,
+  Giphy,
+}
+
+/// Provider settings kept in the credential store so the API key never ships in the frontend.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GifProviderConfig {
+  pub provider: GifProvider,
+  pub api_key: String,
+  /// Content rating passed to the provider (`g`, `pg`, `pg-13`, `r`).
+  #[serde(default)]
+  pub rating: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Gif {
+  pub id: String,
+  pub title: String,
+  pub url: String,
+  pub width: u32,
+  pub height: u32,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub size: Option<u64>,
+  pub preview_url: String,
+  /// Local copy of the preview, filled in once downloaded.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub preview_path: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GifPage {
+  pub results: Vec<Gif>,
+  /// Cursor for the next page, when there is one.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub next: Option<String>,
+}
+
+pub fn init_gifs_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS gif_search_cache (
+        query_key TEXT PRIMARY KEY,
+        page_json TEXT NOT NULL,
+        fetched_at INTEGER NOT NULL
+      );
+    ",
+  )
+}
+
+pub fn query_key(provider: GifProvider, term: &str, page: Option<&str>, limit: usize) -> String {
+  format!("{:?}|{}|{}|{}", provider, term.trim().to_lowercase(), page.unwrap_or_default(), limit)
+}
+
+pub fn cached_page(conn: &Connection, query_key: &str) -> Result<Option<GifPage>, String> {
+  let stored: Option<(String, i64)> = conn
+    .query_row(
+      "SELECT page_json, fetched_at FROM gif_search_cache WHERE query_key = ?1",
+      [query_key],
+      |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+    .optional()
+    .map_err(|e| e.to_string())?;
+  Ok(stored
+    .filter(|(_, fetched_at)| now_millis() - fetched_at < SEARCH_TTL_MS)
+    .and_then(|(json, _)| serde_json::from_str(&json).ok()))
+}
+
+pub fn store_page(conn: &Connection, query_key: &str, page: &GifPage) -> Result<(), String> {
+  let json = serde_json::to_string(page).map_err(|e| e.to_string())?;
+  conn
+    .execute(
+      "INSERT INTO gif_search_cache (query_key, page_json, fetched_at) VALUES (?1, ?2, ?3)
+       ON CONFLICT(query_key) DO UPDATE SET page_json = excluded.page_json, fetched_at = excluded.fetched_at",
+      params![query_key, json, now_millis()],
+    )
+    .map_err(|e| e.to_string())?;
+  conn
+    .execute(
+      "DELETE FROM gif_search_cache WHERE fetched_at < ?1",
+      [now_millis() - SEARCH_TTL_MS],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+fn dims(format: &Value) -> (u32, u32) {
+  let dims = format.get("dims").and_then(|v| v.as_array());
+  let at = |i: usize| dims.and_then(|d| d.get(i)).and_then(|v| v.as_u64()).unwrap_or_default() as u32;
+  (at(0), at(1))
+}
+
+fn number(value: Option<&Value>) -> Option<u64> {
+  value.and_then(|v| v.as_u64().or_else(|| v.as_str().and_then(|s| s.parse().ok())))
+}
+
+fn parse_tenor(body: &Value) -> GifPage {
+  let results = body
+    .get("results")
+    .and_then(|v| v.as_array())
+    .map(|items| {
+      items
+        .iter()
+        .filter_map(|item| {
+          let formats = item.get("media_formats")?;
+          let full = formats.get("gif")?;
+          let preview = formats.get("tinygif").unwrap_or(full);
+          let (width, height) = dims(full);
+          Some(Gif {
+            id: item.get("id")?.as_str()?.to_string(),
+            title: item.get("content_description").and_then(|v| v.as_str()).unwrap_or_default().to_string(),
+            url: full.get("url")?.as_str()?.to_string(),
+            width,
+            height,
+            size: number(full.get("size")),
+            preview_url: preview.get("url")?.as_str()?.to_string(),
+            preview_path: None,
+          })
+        })
+        .collect()
+    })
+    .unwrap_or_default();
+  GifPage {
+    results,
+    next: body
+      .get("next")
+      .and_then(|v| v.as_str())
+      .filter(|next| !next.is_empty() && *next != "0")
+      .map(|s| s.to_string()),
+  }
+}
+
+fn parse_giphy(body: &Value, offset: u64) -> GifPage {
+  let results: Vec<Gif> = body
+    .get("data")
+    .and_then(|v| v.as_array())
+    .map(|items| {
+      items
+        .iter()
+        .filter_map(|item| {
+          let images = item.get("images")?;
+          let full = images.get("original")?;
+          let preview = images.get("fixed_width_small").or_else(|| images.get("fixed_width")).unwrap_or(full);
+          Some(Gif {
+            id: item.get("id")?.as_str()?.to_string(),
+            title: item.get("title").and_then(|v| v.as_str()).unwrap_or_default().to_string(),
+            url: full.get("url")?.as_str()?.to_string(),
+            width: number(full.get("width")).unwrap_or_default() as u32,
+            height: number(full.get("height")).unwrap_or_default() as u32,
+            size: number(full.get("size")),
+            preview_url: preview.get("url")?.as_str()?.to_string(),
+            preview_path: None,
+          })
+        })
+        .collect()
+    })
+    .unwrap_or_default();
+  let total = number(body.pointer("/pagination/total_count")).unwrap_or_default();
+  let next_offset = offset + results.len() as u64;
+  GifPage {
+    next: (!results.is_empty() && next_offset < total).then(|| next_offset.to_string()),
+    results,
+  }
+}
+
+/// Search (or list trending GIFs for an empty term) through the configured provider.
+pub async fn search(http: &reqwest::Client, config: &GifProviderConfig, term: &str, page: Option<&str>, limit: usize) -> Result<GifPage, String> {
+  let term = term.trim();
+  let limit = limit.clamp(1, 50).to_string();
+  let rating = config.rating.clone().unwrap_or_else(|| "pg-13".to_string());
+  let request = match config.provider {
+    GifProvider::Tenor => {
+      let endpoint = if term.is_empty() { "featured" } else { "search" };
+      let contentfilter = match rating.as_str() {
+        "g" => "high",
+        "pg" => "medium",
+        "r" => "off",
+        _ => "low",
+      };
+      let mut query = vec![
+        ("key", config.api_key.clone()),
+        ("client_key", CLIENT_KEY.to_string()),
+        ("limit", limit),
+        ("media_filter", "gif,tinygif".to_string()),
+        ("contentfilter", contentfilter.to_string()),
+      ];
+      if !term.is_empty() {
+        query.push(("q", term.to_string()));
+      }
+      if let Some(pos) = page {
+        query.push(("pos", pos.to_string()));
+      }
+      http.get(format!("https://tenor.googleapis.com/v2/{}", endpoint)).query(&query)
+    }
+    GifProvider::Giphy => {
+      let endpoint = if term.is_empty() { "trending" } else { "search" };
+      let mut query = vec![
+        ("api_key", config.api_key.clone()),
+        ("limit", limit),
+        ("offset", page.unwrap_or("0").to_string()),
+        ("rating", rating),
+      ];
+      if !term.is_empty() {
+        query.push(("q", term.to_string()));
+      }
+      http.get(format!("https://api.giphy.com/v1/gifs/{}", endpoint)).query(&query)
+    }
+  };
+  let response = request.send().await.map_err(|e| format!("GIF search failed: {}", e))?;
+  let status = response.status();
+  if !status.is_success() {
+    return Err(format!("GIF provider returned HTTP {}", status.as_u16()));
+  }
+  let body: Value = response.json().await.map_err(|e| e.to_string())?;
+  Ok(match config.provider {
+    GifProvider::Tenor => parse_tenor(&body),
+    GifProvider::Giphy => parse_giphy(&body, page.and_then(|p| p.parse().ok()).unwrap_or(0)),
+  })
+}
+
+pub fn http_client() -> Result<reqwest::Client, String> {
+  crate::proxy::apply(reqwest::Client::builder())
+    .user_agent(concat!("matrix-messenger/", env!("CARGO_PKG_VERSION")))
+    .timeout(Duration::from_secs(20))
+    .build()
+    .map_err(|e| format!("Failed to create HTTP client: {}", e))
+}
+
+/// Only media hosted by the providers may be fetched through `fetch_to_disk`.
+pub fn is_provider_url(url: &str) -> bool {
+  let Ok(parsed) = reqwest::Url::parse(url) else {
+    return false;
+  };
+  parsed.scheme() == "https"
+    && parsed
+      .host_str()
+      .map(|host| ["tenor.com", "giphy.com"].iter().any(|domain| host == *domain || host.ends_with(&format!(".{}", domain))))
+      .unwrap_or(false)
+}
+
+/// Where a GIF fetched from `url` is kept on disk.
+pub fn cached_path(dir: &Path, url: &str) -> PathBuf {
+  let digest = Sha256::digest(url.as_bytes());
+  let name: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+  dir.join(format!("{}.gif", name))
+}
+
+/// Download `url` into `dir` unless it is already there.
+pub async fn fetch_to_disk(http: &reqwest::Client, dir: &Path, url: &str) -> Result<PathBuf, String> {
+  if !is_provider_url(url) {
+    return Err("Not a GIF provider URL".to_string());
+  }
+  let path = cached_path(dir, url);
+  if path.is_file() {
+    return Ok(path);
+  }
+  let response = http.get(url).send().await.map_err(|e| e.to_string())?;
+  if !response.status().is_success() {
+    return Err(format!("Failed to fetch GIF: HTTP {}", response.status().as_u16()));
+  }
+  let bytes = response.bytes().await.map_err(|e| e.to_string())?;
+  fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+  let part = path.with_extension("part");
+  fs::write(&part, &bytes).map_err(|e| e.to_string())?;
+  fs::rename(&part, &path).map_err(|e| e.to_string())?;
+  Ok(path)
+}
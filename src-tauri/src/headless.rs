@@ -0,0 +1,191 @@
+use rusqlite::Connection;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::deployment::{deploy_synapse_server, DeploymentConfig, DeploymentProgress};
+use crate::matrix_api::{encode, now_millis, MatrixClient};
+use crate::{init_index_db, room_export, security_key, unseal_store_value, Credentials, ACCOUNTS_KEY, STORE_FILE};
+
+pub const FLAG: &str = "--headless";
+
+const USAGE: &str = "Usage: matrix-messenger --headless <command> [options]
+
+Commands:
+  accounts                              List signed-in accounts
+  rooms [--account KEY]                 List joined rooms
+  send ROOM_ID MESSAGE... [--notice] [--account KEY]
+                                        Send a message to an unencrypted room
+  export ROOM_ID DEST_DIR [--format html|json|ndjson]
+                                        Export cached room history
+  deploy CONFIG.json                    Deploy a Synapse server over SSH
+";
+
+/// The data directory the app uses, from the identifier in tauri.conf.json.
+fn app_data_dir() -> Result<PathBuf, String> {
+  let config: Value = serde_json::from_str(include_str!("../tauri.conf.json")).map_err(|e| e.to_string())?;
+  let identifier = config
+    .get("identifier")
+    .and_then(|v| v.as_str())
+    .ok_or("tauri.conf.json has no identifier")?;
+  dirs::data_dir()
+    .map(|dir| dir.join(identifier))
+    .ok_or_else(|| "Unable to resolve application data directory".to_string())
+}
+
+/// The accounts saved by the desktop app.
+fn accounts() -> Result<HashMap<String, Credentials>, String> {
+  let path = app_data_dir()?.join(STORE_FILE);
+  let text = fs::read_to_string(&path).map_err(|_| "No saved accounts; sign in with the desktop app first".to_string())?;
+  let store: Value = serde_json::from_str(&text).map_err(|e| format!("Corrupt store: {}", e))?;
+  let Some(accounts) = store.get(ACCOUNTS_KEY) else {
+    return Ok(HashMap::new());
+  };
+  let accounts = unseal_store_value(accounts.clone(), None).map_err(|err| {
+    if err == security_key::LOCKED {
+      "The accounts are locked with a security key; unlock them in the desktop app".to_string()
+    } else {
+      err
+    }
+  })?;
+  serde_json::from_value(accounts).map_err(|e| format!("Corrupt store: {}", e))
+}
+
+fn client(account: Option<&str>) -> Result<MatrixClient, String> {
+  let accounts = accounts()?;
+  let creds = match account {
+    Some(key) => accounts
+      .iter()
+      .find(|(k, c)| k.as_str() == key || c.user_id == key)
+      .map(|(_, c)| c)
+      .ok_or_else(|| format!("Unknown account {}", key))?,
+    None => {
+      let mut keys: Vec<&String> = accounts.keys().collect();
+      keys.sort();
+      keys.first().and_then(|key| accounts.get(*key)).ok_or("No saved accounts")?
+    }
+  };
+  MatrixClient::new(&creds.homeserver_url, &creds.user_id, &creds.access_token)
+}
+
+/// Split `--name value` options and `--flag`s from positional arguments.
+fn parse_options(args: &[String]) -> (Vec<String>, HashMap<String, Option<String>>) {
+  let mut positional = Vec::new();
+  let mut options = HashMap::new();
+  let mut iter = args.iter().peekable();
+  while let Some(arg) = iter.next() {
+    match arg.strip_prefix("--") {
+      Some(name @ ("account" | "format")) => {
+        options.insert(name.to_string(), iter.next().cloned());
+      }
+      Some(name) => {
+        options.insert(name.to_string(), None);
+      }
+      None => positional.push(arg.clone()),
+    }
+  }
+  (positional, options)
+}
+
+async fn rooms(account: Option<&str>) -> Result<(), String> {
+  let client = client(account)?;
+  let joined = client.get("/joined_rooms", &[]).await?;
+  for room_id in joined.get("joined_rooms").and_then(|v| v.as_array()).into_iter().flatten().filter_map(|v| v.as_str()) {
+    let name = client
+      .get(&format!("/rooms/{}/state/m.room.name/", encode(room_id)), &[])
+      .await
+      .ok()
+      .and_then(|state| state.get("name").and_then(|v| v.as_str()).map(|s| s.to_string()))
+      .unwrap_or_default();
+    println!("{}\t{}", room_id, name);
+  }
+  Ok(())
+}
+
+async fn send(account: Option<&str>, room_id: &str, text: &str, notice: bool) -> Result<(), String> {
+  let client = client(account)?;
+  // Encryption lives in the app's webview, which headless mode doesn't run.
+  if client
+    .get(&format!("/rooms/{}/state/m.room.encryption/", encode(room_id)), &[])
+    .await
+    .is_ok()
+  {
+    return Err("The room is encrypted; headless mode can only send to unencrypted rooms".into());
+  }
+  let content = json!({ "msgtype": if notice { "m.notice" } else { "m.text" }, "body": text });
+  let event_id = client.send_event(room_id, "m.room.message", &content).await?;
+  println!("{}", event_id);
+  Ok(())
+}
+
+fn export(room_id: &str, dest_dir: &str, format: Option<&str>) -> Result<(), String> {
+  let format = match format.unwrap_or("html") {
+    "html" => room_export::ExportFormat::Html,
+    "json" => room_export::ExportFormat::Json,
+    "ndjson" => room_export::ExportFormat::Ndjson,
+    other => return Err(format!("Unknown format {}", other)),
+  };
+  let conn = Connection::open(app_data_dir()?.join("search_index.sqlite3")).map_err(|e| e.to_string())?;
+  init_index_db(&conn).map_err(|e| e.to_string())?;
+  let exported_at = now_millis();
+  let archive = room_export::load(&conn, room_id, &Default::default(), exported_at)?;
+  let folder = format!("{} - {}", crate::media_download::sanitize_file_name(&archive.room_name), exported_at);
+  let path = room_export::write(&archive, format, &PathBuf::from(dest_dir).join(folder))?;
+  println!("{}", path);
+  Ok(())
+}
+
+fn deploy(config_path: &str) -> Result<(), String> {
+  let text = fs::read_to_string(config_path).map_err(|e| format!("Failed to read {}: {}", config_path, e))?;
+  let config: DeploymentConfig = serde_json::from_str(&text).map_err(|e| format!("Invalid deployment config: {}", e))?;
+  deploy_synapse_server(config, |progress| match progress {
+    DeploymentProgress::Status(status) => println!("[{:>3}%] {}: {}", status.progress, status.step, status.message),
+    DeploymentProgress::Output { line, .. } => println!("       {}", line),
+  })?;
+  Ok(())
+}
+
+async fn dispatch(args: &[String]) -> Result<(), String> {
+  let (positional, options) = parse_options(args);
+  let account = options.get("account").cloned().flatten();
+  let account = account.as_deref();
+  match positional.iter().map(|s| s.as_str()).collect::<Vec<_>>().as_slice() {
+    ["accounts"] => {
+      let mut keys: Vec<String> = accounts()?.into_keys().collect();
+      keys.sort();
+      keys.iter().for_each(|key| println!("{}", key));
+      Ok(())
+    }
+    ["rooms"] => rooms(account).await,
+    ["send", room_id, words @ ..] if !words.is_empty() => {
+      send(account, room_id, &words.join(" "), options.contains_key("notice")).await
+    }
+    ["export", room_id, dest_dir] => export(room_id, dest_dir, options.get("format").cloned().flatten().as_deref()),
+    ["deploy", config] => tokio::task::spawn_blocking({
+      let config = config.to_string();
+      move || deploy(&config)
+    })
+    .await
+    .map_err(|e| e.to_string())?,
+    _ => Err(USAGE.to_string()),
+  }
+}
+
+/// Run one CLI command without starting the UI. Returns the process exit code.
+pub fn run(args: Vec<String>) -> i32 {
+  let runtime = match tokio::runtime::Runtime::new() {
+    Ok(runtime) => runtime,
+    Err(err) => {
+      eprintln!("{}", err);
+      return 1;
+    }
+  };
+  match runtime.block_on(dispatch(&args)) {
+    Ok(()) => 0,
+    Err(err) => {
+      eprintln!("{}", err.trim_end());
+      1
+    }
+  }
+}
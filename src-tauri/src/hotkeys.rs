@@ -0,0 +1,153 @@
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tauri_plugin_global_shortcut::Shortcut;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum HotkeyAction {
+  /// Show and focus the main window, or hide it when it is focused.
+  ToggleWindow,
+  /// Open the quick-reply popover for the latest notification.
+  QuickReply,
+  /// Mute or unmute the microphone in the current call.
+  ToggleMute,
+  /// Log out everywhere and securely delete all local data, without confirmation.
+  PanicWipe,
+}
+
+impl HotkeyAction {
+  pub const ALL: [HotkeyAction; 4] = [
+    HotkeyAction::ToggleWindow,
+    HotkeyAction::QuickReply,
+    HotkeyAction::ToggleMute,
+    HotkeyAction::PanicWipe,
+  ];
+
+  fn as_str(self) -> &'static str {
+    match self {
+      HotkeyAction::ToggleWindow => "toggleWindow",
+      HotkeyAction::QuickReply => "quickReply",
+      HotkeyAction::ToggleMute => "toggleMute",
+      HotkeyAction::PanicWipe => "panicWipe",
+    }
+  }
+
+  fn default_accelerator(self) -> Option<&'static str> {
+    match self {
+      HotkeyAction::ToggleWindow => Some("CmdOrCtrl+Shift+M"),
+      HotkeyAction::QuickReply => Some("CmdOrCtrl+Shift+R"),
+      HotkeyAction::ToggleMute | HotkeyAction::PanicWipe => None,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HotkeyBinding {
+  pub action: HotkeyAction,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub accelerator: Option<String>,
+  /// False when the OS or another application holds the shortcut.
+  pub registered: bool,
+}
+
+/// Registered shortcuts and the action each one triggers.
+#[derive(Default)]
+pub struct HotkeyState {
+  active: Mutex<HashMap<u32, (Shortcut, HotkeyAction)>>,
+}
+
+impl HotkeyState {
+  pub fn action_for(&self, shortcut: &Shortcut) -> Option<HotkeyAction> {
+    self.active.lock().ok()?.get(&shortcut.id()).map(|(_, action)| *action)
+  }
+
+  pub fn shortcut_for(&self, action: HotkeyAction) -> Option<Shortcut> {
+    let active = self.active.lock().ok()?;
+    active.values().find(|(_, bound)| *bound == action).map(|(shortcut, _)| *shortcut)
+  }
+
+  pub fn insert(&self, shortcut: Shortcut, action: HotkeyAction) {
+    if let Ok(mut active) = self.active.lock() {
+      active.retain(|_, (_, bound)| *bound != action);
+      active.insert(shortcut.id(), (shortcut, action));
+    }
+  }
+
+  pub fn remove(&self, action: HotkeyAction) {
+    if let Ok(mut active) = self.active.lock() {
+      active.retain(|_, (_, bound)| *bound != action);
+    }
+  }
+
+  pub fn is_registered(&self, action: HotkeyAction) -> bool {
+    self.shortcut_for(action).is_some()
+  }
+}
+
+pub fn init_hotkeys_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS hotkeys (
+        action TEXT PRIMARY KEY,
+        accelerator TEXT
+      );
+    ",
+  )
+}
+
+pub fn parse(accelerator: &str) -> Result<Shortcut, String> {
+  accelerator
+    .parse::<Shortcut>()
+    .map_err(|e| format!("Invalid shortcut {}: {}", accelerator, e))
+}
+
+/// Configured accelerator per action; actions never configured use their default. A stored
+/// NULL means the user cleared it.
+pub fn bindings(conn: &Connection) -> Result<Vec<(HotkeyAction, Option<String>)>, String> {
+  let mut stmt = conn.prepare("SELECT action, accelerator FROM hotkeys").map_err(|e| e.to_string())?;
+  let stored: HashMap<String, Option<String>> = stmt
+    .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?)))
+    .map_err(|e| e.to_string())?
+    .flatten()
+    .collect();
+  Ok(
+    HotkeyAction::ALL
+      .iter()
+      .map(|action| {
+        let accelerator = match stored.get(action.as_str()) {
+          Some(accelerator) => accelerator.clone(),
+          None => action.default_accelerator().map(|s| s.to_string()),
+        };
+        (*action, accelerator)
+      })
+      .collect(),
+  )
+}
+
+pub fn set_binding(conn: &Connection, action: HotkeyAction, accelerator: Option<&str>) -> Result<(), String> {
+  conn
+    .execute(
+      "INSERT INTO hotkeys (action, accelerator) VALUES (?1, ?2)
+       ON CONFLICT(action) DO UPDATE SET accelerator = excluded.accelerator",
+      params![action.as_str(), accelerator],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+/// The other action already bound to the same key combination, if any.
+pub fn conflict(
+  bindings: &[(HotkeyAction, Option<String>)],
+  action: HotkeyAction,
+  shortcut: &Shortcut,
+) -> Option<HotkeyAction> {
+  bindings.iter().find_map(|(other, accelerator)| {
+    let same = accelerator
+      .as_deref()
+      .and_then(|accelerator| parse(accelerator).ok())
+      .is_some_and(|bound| bound.id() == shortcut.id());
+    (*other != action && same).then_some(*other)
+  })
+}
@@ -0,0 +1,137 @@
+use rusqlite::{Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::matrix_api::{encode, MatrixClient};
+
+/// When the user counts as away and what changes while they are.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IdleSettings {
+  pub enabled: bool,
+  /// Minutes without keyboard or mouse input before presence becomes `unavailable`.
+  pub idle_minutes: u32,
+  /// Don't send read receipts for messages that arrive while idle.
+  pub suppress_read_receipts: bool,
+}
+
+impl Default for IdleSettings {
+  fn default() -> Self {
+    IdleSettings {
+      enabled: true,
+      idle_minutes: 5,
+      suppress_read_receipts: true,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IdleStatus {
+  pub idle: bool,
+  pub idle_seconds: u64,
+  pub suppress_read_receipts: bool,
+}
+
+/// Presence each account had before going idle, restored on activity.
+#[derive(Default)]
+pub struct IdleState {
+  idle: Mutex<bool>,
+  previous: Mutex<HashMap<String, Value>>,
+}
+
+impl IdleState {
+  pub fn is_idle(&self) -> bool {
+    self.idle.lock().map(|idle| *idle).unwrap_or(false)
+  }
+
+  /// Record the new state and return whether it changed.
+  pub fn set_idle(&self, value: bool) -> bool {
+    match self.idle.lock() {
+      Ok(mut idle) if *idle != value => {
+        *idle = value;
+        true
+      }
+      _ => false,
+    }
+  }
+
+  pub fn remember(&self, account_key: &str, presence: Value) {
+    if let Ok(mut previous) = self.previous.lock() {
+      previous.insert(account_key.to_string(), presence);
+    }
+  }
+
+  pub fn take_previous(&self) -> HashMap<String, Value> {
+    self.previous.lock().map(|mut previous| std::mem::take(&mut *previous)).unwrap_or_default()
+  }
+}
+
+pub fn init_idle_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS idle_settings (
+        scope TEXT PRIMARY KEY,
+        settings_json TEXT NOT NULL
+      );
+    ",
+  )
+}
+
+pub fn settings(conn: &Connection) -> Result<IdleSettings, String> {
+  let stored: Option<String> = conn
+    .query_row("SELECT settings_json FROM idle_settings WHERE scope = ''", [], |row| row.get(0))
+    .optional()
+    .map_err(|e| e.to_string())?;
+  Ok(stored.and_then(|json| serde_json::from_str(&json).ok()).unwrap_or_default())
+}
+
+pub fn set_settings(conn: &Connection, value: &IdleSettings) -> Result<(), String> {
+  let json = serde_json::to_string(value).map_err(|e| e.to_string())?;
+  conn
+    .execute(
+      "INSERT INTO idle_settings (scope, settings_json) VALUES ('', ?1)
+       ON CONFLICT(scope) DO UPDATE SET settings_json = excluded.settings_json",
+      [json],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+/// Seconds since the last keyboard or mouse input anywhere on the system.
+pub fn idle_seconds() -> Result<u64, String> {
+  user_idle::UserIdle::get_time()
+    .map(|idle| idle.as_seconds())
+    .map_err(|e| format!("Idle time unavailable: {}", e))
+}
+
+fn presence_path(client: &MatrixClient) -> String {
+  format!("/presence/{}/status", encode(&client.user_id))
+}
+
+/// The account's current presence, or `None` when the server has presence disabled.
+pub async fn presence(client: &MatrixClient) -> Option<Value> {
+  let current = client.get(&presence_path(client), &[]).await.ok()?;
+  let presence = current.get("presence")?.as_str()?.to_string();
+  let mut body = json!({ "presence": presence });
+  if let Some(status) = current.get("status_msg").and_then(|v| v.as_str()) {
+    body["status_msg"] = json!(status);
+  }
+  Some(body)
+}
+
+/// Mark the account `unavailable`, keeping its status message.
+pub async fn set_unavailable(client: &MatrixClient, previous: &Value) -> Result<(), String> {
+  let mut body = json!({ "presence": "unavailable" });
+  if let Some(status) = previous.get("status_msg") {
+    body["status_msg"] = status.clone();
+  }
+  client.put(&presence_path(client), &body).await.map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+pub async fn restore(client: &MatrixClient, previous: &Value) -> Result<(), String> {
+  client.put(&presence_path(client), previous).await.map_err(|e| e.to_string())?;
+  Ok(())
+}
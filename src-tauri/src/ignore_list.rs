@@ -0,0 +1,119 @@
+use rusqlite::{params, Connection};
+use serde_json::{json, Map, Value};
+
+use crate::matrix_api::{encode, now_millis, ApiError, MatrixClient};
+
+const IGNORED_USERS_TYPE: &str = "m.ignored_user_list";
+
+/// SQL predicate appended to index and event cache queries to hide senders the row's own
+/// account ignores. `table` is the name or alias the query selects rows from.
+pub fn not_ignored(table: &str) -> String {
+  format!(
+    "{0}.sender NOT IN (SELECT user_id FROM ignored_users WHERE ignored_users.account_key = {0}.account_key)",
+    table
+  )
+}
+
+pub fn init_ignore_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS ignored_users (
+        account_key TEXT NOT NULL,
+        user_id TEXT NOT NULL,
+        ignored_at INTEGER NOT NULL,
+        PRIMARY KEY (account_key, user_id)
+      );
+      CREATE INDEX IF NOT EXISTS idx_ignored_user ON ignored_users(user_id);
+    ",
+  )
+}
+
+fn account_data_path(client: &MatrixClient) -> String {
+  format!("/user/{}/account_data/{}", encode(&client.user_id), IGNORED_USERS_TYPE)
+}
+
+pub async fn fetch_ignored_users(client: &MatrixClient) -> Result<Map<String, Value>, ApiError> {
+  match client.get(&account_data_path(client), &[]).await {
+    Ok(content) => Ok(content
+      .get("ignored_users")
+      .and_then(|v| v.as_object())
+      .cloned()
+      .unwrap_or_default()),
+    Err(err) if err.errcode() == Some("M_NOT_FOUND") => Ok(Map::new()),
+    Err(err) => Err(err),
+  }
+}
+
+/// Read-modify-write of `m.ignored_user_list`; returns the resulting list.
+pub async fn update_ignored_users(client: &MatrixClient, user_id: &str, ignore: bool) -> Result<Vec<String>, ApiError> {
+  let mut ignored = fetch_ignored_users(client).await?;
+  let changed = if ignore {
+    ignored.insert(user_id.to_string(), json!({})).is_none()
+  } else {
+    ignored.remove(user_id).is_some()
+  };
+  if changed {
+    client
+      .put(&account_data_path(client), &json!({ "ignored_users": ignored }))
+      .await?;
+  }
+  Ok(ignored.keys().cloned().collect())
+}
+
+/// Mirror the server-side list locally so queries can filter without a round trip.
+pub fn replace_local_list(conn: &Connection, account_key: &str, user_ids: &[String]) -> Result<(), String> {
+  let tx = conn.unchecked_transaction().map_err(|e| e.to_string())?;
+  tx.execute("DELETE FROM ignored_users WHERE account_key = ?1", [account_key])
+    .map_err(|e| e.to_string())?;
+  let now = now_millis();
+  for user_id in user_ids {
+    tx.execute(
+      "INSERT OR IGNORE INTO ignored_users (account_key, user_id, ignored_at) VALUES (?1, ?2, ?3)",
+      params![account_key, user_id, now],
+    )
+    .map_err(|e| e.to_string())?;
+  }
+  tx.commit().map_err(|e| e.to_string())
+}
+
+pub fn list_local(conn: &Connection, account_key: &str) -> Result<Vec<String>, String> {
+  let mut stmt = conn
+    .prepare("SELECT user_id FROM ignored_users WHERE account_key = ?1 ORDER BY user_id")
+    .map_err(|e| e.to_string())?;
+  let rows = stmt
+    .query_map([account_key], |row| row.get::<_, String>(0))
+    .map_err(|e| e.to_string())?;
+  let mut out = Vec::new();
+  for row in rows {
+    if let Ok(user_id) = row { out.push(user_id); }
+  }
+  Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::event_cache::{self, CachedEvent};
+
+  #[test]
+  fn ignoring_is_scoped_to_the_account() {
+    let conn = Connection::open_in_memory().unwrap();
+    init_ignore_db(&conn).unwrap();
+    event_cache::init_event_cache_db(&conn).unwrap();
+    let raw = json!({
+      "event_id": "$spam",
+      "type": "m.room.message",
+      "sender": "@spammer:example.org",
+      "origin_server_ts": 1,
+      "content": { "msgtype": "m.text", "body": "hi" },
+    });
+    let event = CachedEvent::from_raw("!room:example.org", &raw).unwrap();
+    for account in ["example.org/@alice:example.org", "example.org/@bob:example.org"] {
+      event_cache::upsert_event(&conn, account, &event).unwrap();
+    }
+    replace_local_list(&conn, "example.org/@alice:example.org", &["@spammer:example.org".to_string()]).unwrap();
+
+    let seen_by = |account| event_cache::get_event(&conn, account, "!room:example.org", "$spam").unwrap();
+    assert!(seen_by("example.org/@alice:example.org").is_none());
+    assert!(seen_by("example.org/@bob:example.org").is_some());
+  }
+}
@@ -0,0 +1,466 @@
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::account_data;
+use crate::matrix_api::{encode, now_millis, ApiError, MatrixClient};
+
+/// MSC2545 image packs: the user's personal pack, packs stored as room state, and the list
+/// of room packs the user enabled globally.
+pub const USER_EMOTES_TYPE: &str = "im.ponies.user_emotes";
+pub const ROOM_EMOTES_TYPE: &str = "im.ponies.room_emotes";
+pub const EMOTE_ROOMS_TYPE: &str = "im.ponies.emote_rooms";
+
+const USER_PACK_KEY: &str = "user";
+
+/// Where a pack lives: `{ "kind": "user" }` or `{ "kind": "room", "roomId": ..., "stateKey": ... }`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum PackTarget {
+  User,
+  Room {
+    #[serde(rename = "roomId")]
+    room_id: String,
+    #[serde(rename = "stateKey")]
+    state_key: String,
+  },
+}
+
+impl PackTarget {
+  pub fn pack_key(&self) -> String {
+    match self {
+      PackTarget::User => USER_PACK_KEY.to_string(),
+      PackTarget::Room { room_id, state_key } => format!("{}|{}", room_id, state_key),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PackImage {
+  pub pack_key: String,
+  pub shortcode: String,
+  pub url: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub body: Option<String>,
+  /// `emoticon`, `sticker`, or both; inherited from the pack when the image sets none.
+  pub usage: Vec<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub info: Option<Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImagePack {
+  pub pack_key: String,
+  pub target: PackTarget,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub display_name: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub avatar_url: Option<String>,
+  pub usage: Vec<String>,
+  pub images: Vec<PackImage>,
+  pub updated_at: i64,
+}
+
+pub fn init_image_packs_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS image_packs (
+        account_key TEXT NOT NULL,
+        pack_key TEXT NOT NULL,
+        room_id TEXT NOT NULL DEFAULT '',
+        state_key TEXT NOT NULL DEFAULT '',
+        content_json TEXT NOT NULL,
+        updated_at INTEGER NOT NULL,
+        PRIMARY KEY (account_key, pack_key)
+      );
+      CREATE TABLE IF NOT EXISTS image_pack_images (
+        account_key TEXT NOT NULL,
+        pack_key TEXT NOT NULL,
+        shortcode TEXT NOT NULL,
+        url TEXT NOT NULL,
+        body TEXT,
+        usage_json TEXT NOT NULL,
+        info_json TEXT,
+        PRIMARY KEY (account_key, pack_key, shortcode)
+      );
+      CREATE INDEX IF NOT EXISTS idx_image_pack_shortcode ON image_pack_images(account_key, shortcode);
+    ",
+  )
+}
+
+fn string_list(value: Option<&Value>) -> Vec<String> {
+  value
+    .and_then(|v| v.as_array())
+    .map(|items| items.iter().filter_map(|i| i.as_str().map(|s| s.to_string())).collect())
+    .unwrap_or_default()
+}
+
+/// Read a pack event's content, skipping images without an mxc URL.
+pub fn parse_pack(target: PackTarget, content: &Value, updated_at: i64) -> ImagePack {
+  let pack_key = target.pack_key();
+  let meta = content.get("pack");
+  let mut usage = string_list(meta.and_then(|m| m.get("usage")));
+  if usage.is_empty() {
+    usage = vec!["emoticon".to_string(), "sticker".to_string()];
+  }
+  let mut images: Vec<PackImage> = content
+    .get("images")
+    .and_then(|v| v.as_object())
+    .map(|images| {
+      images
+        .iter()
+        .filter_map(|(shortcode, image)| {
+          let url = image.get("url")?.as_str()?.to_string();
+          let own_usage = string_list(image.get("usage"));
+          Some(PackImage {
+            pack_key: pack_key.clone(),
+            shortcode: shortcode.clone(),
+            url,
+            body: image.get("body").and_then(|v| v.as_str()).map(|s| s.to_string()),
+            usage: if own_usage.is_empty() { usage.clone() } else { own_usage },
+            info: image.get("info").cloned(),
+          })
+        })
+        .collect()
+    })
+    .unwrap_or_default();
+  images.sort_by(|a, b| a.shortcode.cmp(&b.shortcode));
+  ImagePack {
+    pack_key,
+    target,
+    display_name: meta.and_then(|m| m.get("display_name")).and_then(|v| v.as_str()).map(|s| s.to_string()),
+    avatar_url: meta.and_then(|m| m.get("avatar_url")).and_then(|v| v.as_str()).map(|s| s.to_string()),
+    usage,
+    images,
+    updated_at,
+  }
+}
+
+/// Replace the stored pack and its image index.
+pub fn store_pack(conn: &Connection, account_key: &str, target: &PackTarget, content: &Value) -> Result<ImagePack, String> {
+  let pack = parse_pack(target.clone(), content, now_millis());
+  let (room_id, state_key) = match target {
+    PackTarget::User => ("", ""),
+    PackTarget::Room { room_id, state_key } => (room_id.as_str(), state_key.as_str()),
+  };
+  let tx = conn.unchecked_transaction().map_err(|e| e.to_string())?;
+  tx.execute(
+    "INSERT INTO image_packs (account_key, pack_key, room_id, state_key, content_json, updated_at)
+     VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+     ON CONFLICT(account_key, pack_key) DO UPDATE SET
+       content_json = excluded.content_json,
+       updated_at = excluded.updated_at",
+    params![account_key, pack.pack_key, room_id, state_key, content.to_string(), pack.updated_at],
+  )
+  .map_err(|e| e.to_string())?;
+  tx.execute(
+    "DELETE FROM image_pack_images WHERE account_key = ?1 AND pack_key = ?2",
+    params![account_key, pack.pack_key],
+  )
+  .map_err(|e| e.to_string())?;
+  for image in &pack.images {
+    tx.execute(
+      "INSERT INTO image_pack_images (account_key, pack_key, shortcode, url, body, usage_json, info_json)
+       VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+      params![
+        account_key,
+        pack.pack_key,
+        image.shortcode,
+        image.url,
+        image.body,
+        serde_json::to_string(&image.usage).map_err(|e| e.to_string())?,
+        image.info.as_ref().map(|info| info.to_string()),
+      ],
+    )
+    .map_err(|e| e.to_string())?;
+  }
+  tx.commit().map_err(|e| e.to_string())?;
+  Ok(pack)
+}
+
+/// Drop packs of a room that no longer has them in its state.
+pub fn prune_room_packs(conn: &Connection, account_key: &str, room_id: &str, keep: &[String]) -> Result<(), String> {
+  let mut stmt = conn
+    .prepare("SELECT pack_key FROM image_packs WHERE account_key = ?1 AND room_id = ?2")
+    .map_err(|e| e.to_string())?;
+  let stale: Vec<String> = stmt
+    .query_map(params![account_key, room_id], |row| row.get(0))
+    .map_err(|e| e.to_string())?
+    .flatten()
+    .filter(|pack_key: &String| !keep.contains(pack_key))
+    .collect();
+  for pack_key in stale {
+    conn
+      .execute(
+        "DELETE FROM image_packs WHERE account_key = ?1 AND pack_key = ?2",
+        params![account_key, pack_key],
+      )
+      .map_err(|e| e.to_string())?;
+    conn
+      .execute(
+        "DELETE FROM image_pack_images WHERE account_key = ?1 AND pack_key = ?2",
+        params![account_key, pack_key],
+      )
+      .map_err(|e| e.to_string())?;
+  }
+  Ok(())
+}
+
+/// Room IDs and state keys enabled in `im.ponies.emote_rooms`.
+pub fn enabled_rooms(content: &Value) -> Vec<(String, String)> {
+  content
+    .get("rooms")
+    .and_then(|v| v.as_object())
+    .map(|rooms| {
+      rooms
+        .iter()
+        .flat_map(|(room_id, packs)| {
+          packs
+            .as_object()
+            .map(|packs| packs.keys().map(|key| (room_id.clone(), key.clone())).collect::<Vec<_>>())
+            .unwrap_or_default()
+        })
+        .collect()
+    })
+    .unwrap_or_default()
+}
+
+fn stored_packs(conn: &Connection, account_key: &str) -> Result<Vec<ImagePack>, String> {
+  let mut stmt = conn
+    .prepare("SELECT room_id, state_key, content_json, updated_at FROM image_packs WHERE account_key = ?1")
+    .map_err(|e| e.to_string())?;
+  let rows = stmt
+    .query_map([account_key], |row| {
+      Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?, row.get::<_, i64>(3)?))
+    })
+    .map_err(|e| e.to_string())?;
+  Ok(rows
+    .flatten()
+    .map(|(room_id, state_key, content_json, updated_at)| {
+      let target = if room_id.is_empty() {
+        PackTarget::User
+      } else {
+        PackTarget::Room { room_id, state_key }
+      };
+      let content = serde_json::from_str(&content_json).unwrap_or_else(|_| json!({}));
+      parse_pack(target, &content, updated_at)
+    })
+    .collect())
+}
+
+/// Packs usable in `room_id`: the personal pack, globally enabled room packs and the room's own.
+pub fn list(conn: &Connection, account_key: &str, room_id: Option<&str>) -> Result<Vec<ImagePack>, String> {
+  let enabled = account_data::get_cached(conn, account_key, EMOTE_ROOMS_TYPE, None)?
+    .map(|entry| enabled_rooms(&entry.content))
+    .unwrap_or_default();
+  let mut packs: Vec<ImagePack> = stored_packs(conn, account_key)?
+    .into_iter()
+    .filter(|pack| match &pack.target {
+      PackTarget::User => true,
+      PackTarget::Room { room_id: pack_room, state_key } => {
+        Some(pack_room.as_str()) == room_id || enabled.iter().any(|(r, k)| r == pack_room && k == state_key)
+      }
+    })
+    .collect();
+  packs.sort_by_key(|pack| (pack.target != PackTarget::User, pack.display_name.clone().unwrap_or_default()));
+  Ok(packs)
+}
+
+fn image_from_row(row: &Row) -> rusqlite::Result<PackImage> {
+  let usage_json: String = row.get(4)?;
+  let info_json: Option<String> = row.get(5)?;
+  Ok(PackImage {
+    pack_key: row.get(0)?,
+    shortcode: row.get(1)?,
+    url: row.get(2)?,
+    body: row.get(3)?,
+    usage: serde_json::from_str(&usage_json).unwrap_or_default(),
+    info: info_json.and_then(|json| serde_json::from_str(&json).ok()),
+  })
+}
+
+/// Autocomplete from the image index: shortcodes starting with `query`, then those containing it,
+/// limited to packs usable in `room_id`.
+pub fn search(
+  conn: &Connection,
+  account_key: &str,
+  room_id: Option<&str>,
+  query: &str,
+  usage: Option<&str>,
+  limit: usize,
+) -> Result<Vec<PackImage>, String> {
+  let usable: Vec<String> = list(conn, account_key, room_id)?.into_iter().map(|pack| pack.pack_key).collect();
+  let query = query.trim_matches(':').replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+  let mut stmt = conn
+    .prepare(
+      "SELECT pack_key, shortcode, url, body, usage_json, info_json FROM image_pack_images
+       WHERE account_key = ?1 AND shortcode LIKE '%' || ?2 || '%' ESCAPE '\\'
+       ORDER BY shortcode LIKE ?2 || '%' ESCAPE '\\' DESC, shortcode ASC",
+    )
+    .map_err(|e| e.to_string())?;
+  let images = stmt
+    .query_map(params![account_key, query], image_from_row)
+    .map_err(|e| e.to_string())?
+    .flatten()
+    .filter(|image| usable.contains(&image.pack_key))
+    .filter(|image| usage.map(|u| image.usage.iter().any(|own| own == u)).unwrap_or(true))
+    .take(limit)
+    .collect();
+  Ok(images)
+}
+
+pub fn find_image(conn: &Connection, account_key: &str, pack_key: &str, shortcode: &str) -> Result<Option<PackImage>, String> {
+  conn
+    .query_row(
+      "SELECT pack_key, shortcode, url, body, usage_json, info_json FROM image_pack_images
+       WHERE account_key = ?1 AND pack_key = ?2 AND shortcode = ?3",
+      params![account_key, pack_key, shortcode],
+      image_from_row,
+    )
+    .optional()
+    .map_err(|e| e.to_string())
+}
+
+/// Current pack content on the homeserver (empty when the pack does not exist yet).
+pub async fn fetch_content(client: &MatrixClient, target: &PackTarget) -> Result<Value, ApiError> {
+  match target {
+    PackTarget::User => Ok(account_data::fetch(client, USER_EMOTES_TYPE, None).await?.unwrap_or_else(|| json!({}))),
+    PackTarget::Room { room_id, state_key } => {
+      let path = format!("/rooms/{}/state/{}/{}", encode(room_id), ROOM_EMOTES_TYPE, encode(state_key));
+      match client.get(&path, &[]).await {
+        Ok(content) => Ok(content),
+        Err(err) if err.errcode() == Some("M_NOT_FOUND") => Ok(json!({})),
+        Err(err) => Err(err),
+      }
+    }
+  }
+}
+
+pub async fn save_content(client: &MatrixClient, target: &PackTarget, content: &Value) -> Result<(), ApiError> {
+  match target {
+    PackTarget::User => account_data::push(client, USER_EMOTES_TYPE, None, content).await,
+    PackTarget::Room { room_id, state_key } => client
+      .send_state(room_id, ROOM_EMOTES_TYPE, state_key, content)
+      .await
+      .map(|_| ()),
+  }
+}
+
+/// All image pack state events of a room, by state key.
+pub async fn fetch_room_packs(client: &MatrixClient, room_id: &str) -> Result<Vec<(String, Value)>, ApiError> {
+  let state = client.get(&format!("/rooms/{}/state", encode(room_id)), &[]).await?;
+  Ok(state
+    .as_array()
+    .map(|events| {
+      events
+        .iter()
+        .filter(|event| event.get("type").and_then(|v| v.as_str()) == Some(ROOM_EMOTES_TYPE))
+        .filter_map(|event| {
+          let state_key = event.get("state_key")?.as_str()?.to_string();
+          let content = event.get("content")?.clone();
+          let has_images = content.get("images").and_then(|v| v.as_object()).is_some();
+          has_images.then_some((state_key, content))
+        })
+        .collect()
+    })
+    .unwrap_or_default())
+}
+
+/// Apply metadata changes to pack content, keeping fields this client does not know about.
+pub fn with_metadata(mut content: Value, display_name: Option<&str>, avatar_url: Option<&str>, usage: Option<&[String]>) -> Value {
+  if !content.is_object() {
+    content = json!({});
+  }
+  if content.get("images").is_none() {
+    content["images"] = json!({});
+  }
+  if !content.get("pack").map(|p| p.is_object()).unwrap_or(false) {
+    content["pack"] = json!({});
+  }
+  if let Some(display_name) = display_name {
+    content["pack"]["display_name"] = json!(display_name);
+  }
+  if let Some(avatar_url) = avatar_url {
+    content["pack"]["avatar_url"] = json!(avatar_url);
+  }
+  if let Some(usage) = usage {
+    content["pack"]["usage"] = json!(usage);
+  }
+  content
+}
+
+pub fn with_image(mut content: Value, shortcode: &str, url: &str, body: Option<&str>, info: &Value, usage: Option<&[String]>) -> Value {
+  content = with_metadata(content, None, None, None);
+  let mut image = json!({ "url": url, "info": info });
+  if let Some(body) = body {
+    image["body"] = json!(body);
+  }
+  if let Some(usage) = usage {
+    image["usage"] = json!(usage);
+  }
+  content["images"][shortcode] = image;
+  content
+}
+
+pub fn without_image(mut content: Value, shortcode: &str) -> Value {
+  if let Some(images) = content.get_mut("images").and_then(|v| v.as_object_mut()) {
+    images.remove(shortcode);
+  }
+  content
+}
+
+/// `m.sticker` content for a pack image.
+pub fn sticker_content(image: &PackImage) -> Value {
+  json!({
+    "body": image.body.clone().unwrap_or_else(|| image.shortcode.clone()),
+    "url": image.url,
+    "info": image.info.clone().unwrap_or_else(|| json!({})),
+  })
+}
+
+fn escape_html(text: &str) -> String {
+  text
+    .replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+}
+
+/// Replace `:shortcode:` with custom emoji images, returning the `formatted_body` when any
+/// were found.
+pub fn render_emoji(body: &str, images: &[PackImage]) -> Option<String> {
+  let mut html = String::new();
+  let mut replaced = false;
+  let mut rest = body;
+  while let Some(start) = rest.find(':') {
+    let after = &rest[start + 1..];
+    let candidate = after.find(':').map(|end| &after[..end]);
+    let image = candidate
+      .filter(|code| !code.is_empty() && !code.contains(char::is_whitespace))
+      .and_then(|code| {
+        images
+          .iter()
+          .find(|image| image.shortcode == code && image.usage.iter().any(|u| u == "emoticon"))
+      });
+    match (candidate, image) {
+      (Some(code), Some(image)) => {
+        html.push_str(&escape_html(&rest[..start]));
+        html.push_str(&format!(
+          "<img data-mx-emoticon height=\"32\" src=\"{}\" alt=\":{}:\" title=\":{}:\">",
+          escape_html(&image.url),
+          escape_html(code),
+          escape_html(code)
+        ));
+        rest = &after[code.len() + 1..];
+        replaced = true;
+      }
+      _ => {
+        html.push_str(&escape_html(&rest[..=start]));
+        rest = after;
+      }
+    }
+  }
+  html.push_str(&escape_html(rest));
+  replaced.then(|| html.replace('\n', "<br>"))
+}
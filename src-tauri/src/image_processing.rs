@@ -0,0 +1,277 @@
+use image::{
+  codecs::{gif::GifDecoder, jpeg::JpegEncoder},
+  imageops::FilterType,
+  AnimationDecoder, DynamicImage, ImageDecoder, ImageFormat, ImageReader,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Cursor;
+
+const THUMBNAIL_JPEG_QUALITY: u8 = 80;
+
+/// Decode an image and rotate/flip it according to its EXIF orientation so previews
+/// match what the camera showed.
+pub fn decode_oriented(bytes: &[u8]) -> Result<DynamicImage, String> {
+  let mut decoder = ImageReader::new(Cursor::new(bytes))
+    .with_guessed_format()
+    .map_err(|e| format!("Failed to read image: {}", e))?
+    .into_decoder()
+    .map_err(|e| format!("Unsupported image: {}", e))?;
+  let orientation = decoder
+    .orientation()
+    .map_err(|e| format!("Failed to read image orientation: {}", e))?;
+  let mut img = DynamicImage::from_decoder(decoder).map_err(|e| format!("Failed to decode image: {}", e))?;
+  img.apply_orientation(orientation);
+  Ok(img)
+}
+
+/// Width and height of an encoded image without decoding the pixels.
+pub fn dimensions(bytes: &[u8]) -> Result<(u32, u32), String> {
+  ImageReader::new(Cursor::new(bytes))
+    .with_guessed_format()
+    .map_err(|e| e.to_string())?
+    .into_dimensions()
+    .map_err(|e| format!("Failed to read image size: {}", e))
+}
+
+/// PNG when the image has transparency, JPEG otherwise.
+pub fn encode_preview(img: &DynamicImage, jpeg_quality: u8) -> Result<(Vec<u8>, &'static str), String> {
+  let mut out = Vec::new();
+  if img.color().has_alpha() {
+    img
+      .write_to(&mut Cursor::new(&mut out), ImageFormat::Png)
+      .map_err(|e| format!("Failed to encode image: {}", e))?;
+    Ok((out, "image/png"))
+  } else {
+    JpegEncoder::new_with_quality(&mut out, jpeg_quality)
+      .encode_image(&img.to_rgb8())
+      .map_err(|e| format!("Failed to encode image: {}", e))?;
+    Ok((out, "image/jpeg"))
+  }
+}
+
+/// Scale down to fit within `max_width` x `max_height`, keeping the aspect ratio.
+pub fn thumbnail(bytes: &[u8], max_width: u32, max_height: u32) -> Result<(Vec<u8>, &'static str, u32, u32), String> {
+  let img = decode_oriented(bytes)?;
+  let img = if img.width() > max_width || img.height() > max_height {
+    img.thumbnail(max_width.max(1), max_height.max(1))
+  } else {
+    img
+  };
+  let (data, content_type) = encode_preview(&img, THUMBNAIL_JPEG_QUALITY)?;
+  Ok((data, content_type, img.width(), img.height()))
+}
+
+/// Blurhash placeholder (4x3 components) computed on a downscaled copy.
+pub fn blurhash(img: &DynamicImage) -> Result<String, String> {
+  let small = img.thumbnail(64, 64).to_rgba8();
+  blurhash::encode(4, 3, small.width(), small.height(), small.as_raw()).map_err(|e| format!("Failed to compute blurhash: {:?}", e))
+}
+
+/// Most common colour after coarse quantisation, as `#rrggbb`.
+pub fn dominant_color(img: &DynamicImage) -> String {
+  let small = img.thumbnail(32, 32).to_rgba8();
+  let mut buckets: HashMap<(u8, u8, u8), (u32, [u32; 3])> = HashMap::new();
+  for pixel in small.pixels() {
+    let [r, g, b, a] = pixel.0;
+    if a < 128 {
+      continue;
+    }
+    let bucket = buckets.entry((r >> 4, g >> 4, b >> 4)).or_insert((0, [0; 3]));
+    bucket.0 += 1;
+    bucket.1[0] += r as u32;
+    bucket.1[1] += g as u32;
+    bucket.1[2] += b as u32;
+  }
+  match buckets.values().max_by_key(|(count, _)| *count) {
+    Some((count, sums)) => format!(
+      "#{:02x}{:02x}{:02x}",
+      sums[0] / count,
+      sums[1] / count,
+      sums[2] / count
+    ),
+    None => "#000000".to_string(),
+  }
+}
+
+fn strip_jpeg(bytes: &[u8]) -> Option<Vec<u8>> {
+  if bytes.len() < 4 || bytes[0] != 0xFF || bytes[1] != 0xD8 {
+    return None;
+  }
+  let mut out = vec![0xFF, 0xD8];
+  let mut pos = 2;
+  while pos + 4 <= bytes.len() {
+    if bytes[pos] != 0xFF {
+      return None;
+    }
+    let marker = bytes[pos + 1];
+    if marker == 0xDA {
+      out.extend_from_slice(&bytes[pos..]);
+      return Some(out);
+    }
+    if (0xD0..=0xD7).contains(&marker) || marker == 0x01 {
+      out.extend_from_slice(&bytes[pos..pos + 2]);
+      pos += 2;
+      continue;
+    }
+    let len = u16::from_be_bytes([bytes[pos + 2], bytes[pos + 3]]) as usize;
+    let end = pos + 2 + len;
+    if end > bytes.len() {
+      return None;
+    }
+    let payload = &bytes[pos + 4..end];
+    let metadata = match marker {
+      0xE1 => payload.starts_with(b"Exif\0") || payload.starts_with(b"http://ns.adobe.com/"),
+      0xED | 0xFE => true,
+      _ => false,
+    };
+    if !metadata {
+      out.extend_from_slice(&bytes[pos..end]);
+    }
+    pos = end;
+  }
+  None
+}
+
+fn strip_png(bytes: &[u8]) -> Option<Vec<u8>> {
+  const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+  if !bytes.starts_with(SIGNATURE) {
+    return None;
+  }
+  let mut out = SIGNATURE.to_vec();
+  let mut pos = SIGNATURE.len();
+  while pos + 12 <= bytes.len() {
+    let len = u32::from_be_bytes(bytes[pos..pos + 4].try_into().ok()?) as usize;
+    let end = pos + 12 + len;
+    if end > bytes.len() {
+      return None;
+    }
+    let kind = &bytes[pos + 4..pos + 8];
+    if !matches!(kind, b"eXIf" | b"tEXt" | b"zTXt" | b"iTXt" | b"tIME") {
+      out.extend_from_slice(&bytes[pos..end]);
+    }
+    pos = end;
+  }
+  Some(out)
+}
+
+fn strip_webp(bytes: &[u8]) -> Option<Vec<u8>> {
+  if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WEBP" {
+    return None;
+  }
+  let mut body = b"WEBP".to_vec();
+  let mut pos = 12;
+  while pos + 8 <= bytes.len() {
+    let kind = &bytes[pos..pos + 4];
+    let len = u32::from_le_bytes(bytes[pos + 4..pos + 8].try_into().ok()?) as usize;
+    let end = (pos + 8 + len + (len & 1)).min(bytes.len());
+    match kind {
+      b"EXIF" | b"XMP " => {}
+      b"VP8X" if end > pos + 8 => {
+        let start = body.len();
+        body.extend_from_slice(&bytes[pos..end]);
+        // Clear the EXIF and XMP presence flags.
+        body[start + 8] &= !0x0C;
+      }
+      _ => body.extend_from_slice(&bytes[pos..end]),
+    }
+    pos = end;
+  }
+  let mut out = b"RIFF".to_vec();
+  out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+  out.extend(body);
+  Some(out)
+}
+
+/// Remove EXIF, XMP, IPTC and text metadata (GPS position, camera model, timestamps).
+/// JPEG, PNG and WebP are rewritten losslessly; a rotated JPEG is re-encoded upright first so
+/// dropping its orientation tag does not change how it displays. Other formats are returned as-is.
+pub fn strip_metadata(bytes: &[u8]) -> Result<Vec<u8>, String> {
+  let format = match image::guess_format(bytes) {
+    Ok(format) => format,
+    Err(_) => return Ok(bytes.to_vec()),
+  };
+  let stripped = match format {
+    ImageFormat::Jpeg => {
+      let mut decoder = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .map_err(|e| e.to_string())?
+        .into_decoder()
+        .map_err(|e| e.to_string())?;
+      let rotated = decoder
+        .orientation()
+        .map(|o| o != image::metadata::Orientation::NoTransforms)
+        .unwrap_or(false);
+      if rotated {
+        let img = decode_oriented(bytes)?;
+        let mut out = Vec::new();
+        JpegEncoder::new_with_quality(&mut out, 92)
+          .encode_image(&img.to_rgb8())
+          .map_err(|e| format!("Failed to encode image: {}", e))?;
+        Some(out)
+      } else {
+        strip_jpeg(bytes)
+      }
+    }
+    ImageFormat::Png => strip_png(bytes),
+    ImageFormat::WebP => strip_webp(bytes),
+    _ => None,
+  };
+  Ok(stripped.unwrap_or_else(|| bytes.to_vec()))
+}
+
+/// Target encoding for compressed uploads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum OutputFormat {
+  /// JPEG for opaque images, PNG when transparency must be kept.
+  Auto,
+  Jpeg,
+  Webp,
+}
+
+/// Animated GIF and WebP are passed through untouched so they keep moving.
+pub fn is_animated(bytes: &[u8]) -> bool {
+  match image::guess_format(bytes) {
+    Ok(ImageFormat::Gif) => GifDecoder::new(Cursor::new(bytes))
+      .map(|decoder| decoder.into_frames().take(2).count() > 1)
+      .unwrap_or(false),
+    Ok(ImageFormat::WebP) => bytes.len() > 20 && &bytes[12..16] == b"VP8X" && bytes[20] & 0x02 != 0,
+    _ => false,
+  }
+}
+
+/// Downscale to `max_dimension` and re-encode at `quality`. Returns `None` for animations and
+/// undecodable input so the caller keeps the original.
+pub fn compress(bytes: &[u8], max_dimension: u32, quality: u8, format: OutputFormat) -> Result<Option<(Vec<u8>, &'static str)>, String> {
+  if is_animated(bytes) {
+    return Ok(None);
+  }
+  let img = match decode_oriented(bytes) {
+    Ok(img) => img,
+    Err(_) => return Ok(None),
+  };
+  let max_dimension = max_dimension.max(1);
+  let img = if img.width() > max_dimension || img.height() > max_dimension {
+    img.resize(max_dimension, max_dimension, FilterType::Lanczos3)
+  } else {
+    img
+  };
+  let quality = quality.clamp(1, 100);
+  let encoded = match format {
+    OutputFormat::Auto => encode_preview(&img, quality)?,
+    OutputFormat::Jpeg => {
+      let mut out = Vec::new();
+      JpegEncoder::new_with_quality(&mut out, quality)
+        .encode_image(&img.to_rgb8())
+        .map_err(|e| format!("Failed to encode image: {}", e))?;
+      (out, "image/jpeg")
+    }
+    OutputFormat::Webp => {
+      let rgba = DynamicImage::ImageRgba8(img.to_rgba8());
+      let encoder = webp::Encoder::from_image(&rgba).map_err(|e| format!("Failed to encode image: {}", e))?;
+      (encoder.encode(quality as f32).to_vec(), "image/webp")
+    }
+  };
+  Ok(Some(encoded))
+}
@@ -0,0 +1,253 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::matrix_api::{encode, now_millis, ApiError, MatrixClient};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingInvite {
+  pub account_key: String,
+  pub room_id: String,
+  pub inviter: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub inviter_display_name: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub inviter_avatar_url: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub room_name: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub room_avatar_url: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub room_topic: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub canonical_alias: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub join_rule: Option<String>,
+  pub is_direct: bool,
+  pub encrypted: bool,
+  pub received_at: i64,
+}
+
+impl PendingInvite {
+  /// Short human-readable label for notifications.
+  pub fn describe(&self) -> (String, String) {
+    let inviter = self
+      .inviter_display_name
+      .clone()
+      .unwrap_or_else(|| self.inviter.clone());
+    if self.is_direct {
+      (inviter, "wants to chat with you".to_string())
+    } else {
+      let room = self
+        .room_name
+        .clone()
+        .or_else(|| self.canonical_alias.clone())
+        .unwrap_or_else(|| self.room_id.clone());
+      (room, format!("{} invited you", inviter))
+    }
+  }
+}
+
+pub fn init_invites_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS pending_invites (
+        account_key TEXT NOT NULL,
+        room_id TEXT NOT NULL,
+        invite_json TEXT NOT NULL,
+        received_at INTEGER NOT NULL,
+        PRIMARY KEY (account_key, room_id)
+      );
+    ",
+  )
+}
+
+fn state_content<'a>(events: &'a [Value], event_type: &str, state_key: &str) -> Option<&'a Value> {
+  events.iter().rev().find_map(|event| {
+    let matches = event.get("type").and_then(|v| v.as_str()) == Some(event_type)
+      && event.get("state_key").and_then(|v| v.as_str()).unwrap_or_default() == state_key;
+    if matches { event.get("content") } else { None }
+  })
+}
+
+fn content_str(content: Option<&Value>, key: &str) -> Option<String> {
+  content
+    .and_then(|c| c.get(key))
+    .and_then(|v| v.as_str())
+    .filter(|s| !s.is_empty())
+    .map(|s| s.to_string())
+}
+
+/// Build a preview from the stripped `invite_state` the homeserver sends with an invite.
+pub fn parse_invite(account_key: &str, own_user_id: &str, room_id: &str, invite: &Value) -> Option<PendingInvite> {
+  let events: Vec<Value> = invite
+    .pointer("/invite_state/events")
+    .and_then(|v| v.as_array())
+    .cloned()
+    .unwrap_or_default();
+  let own_member = events.iter().rev().find(|event| {
+    event.get("type").and_then(|v| v.as_str()) == Some("m.room.member")
+      && event.get("state_key").and_then(|v| v.as_str()) == Some(own_user_id)
+  })?;
+  if own_member.pointer("/content/membership").and_then(|v| v.as_str()) != Some("invite") {
+    return None;
+  }
+  let inviter = own_member.get("sender").and_then(|v| v.as_str()).unwrap_or_default().to_string();
+  let inviter_member = state_content(&events, "m.room.member", &inviter);
+  Some(PendingInvite {
+    account_key: account_key.to_string(),
+    room_id: room_id.to_string(),
+    inviter_display_name: content_str(inviter_member, "displayname"),
+    inviter_avatar_url: content_str(inviter_member, "avatar_url"),
+    inviter,
+    room_name: content_str(state_content(&events, "m.room.name", ""), "name"),
+    room_avatar_url: content_str(state_content(&events, "m.room.avatar", ""), "url"),
+    room_topic: content_str(state_content(&events, "m.room.topic", ""), "topic"),
+    canonical_alias: content_str(state_content(&events, "m.room.canonical_alias", ""), "alias"),
+    join_rule: content_str(state_content(&events, "m.room.join_rules", ""), "join_rule"),
+    is_direct: own_member
+      .pointer("/content/is_direct")
+      .and_then(|v| v.as_bool())
+      .unwrap_or(false),
+    encrypted: state_content(&events, "m.room.encryption", "").is_some(),
+    received_at: now_millis(),
+  })
+}
+
+/// Store invites from a sync `rooms.invite` section and drop rooms that were joined or left.
+/// Returns only invites that were not known before.
+pub fn record_sync_invites(
+  conn: &Connection,
+  account_key: &str,
+  own_user_id: &str,
+  invites: &Value,
+  resolved_room_ids: &[String],
+) -> Result<Vec<PendingInvite>, String> {
+  let tx = conn.unchecked_transaction().map_err(|e| e.to_string())?;
+  for room_id in resolved_room_ids {
+    tx.execute(
+      "DELETE FROM pending_invites WHERE account_key = ?1 AND room_id = ?2",
+      params![account_key, room_id],
+    )
+    .map_err(|e| e.to_string())?;
+  }
+  let mut fresh = Vec::new();
+  if let Some(rooms) = invites.as_object() {
+    for (room_id, invite) in rooms {
+      let parsed = match parse_invite(account_key, own_user_id, room_id, invite) {
+        Some(parsed) => parsed,
+        None => continue,
+      };
+      let known: Option<i64> = tx
+        .query_row(
+          "SELECT received_at FROM pending_invites WHERE account_key = ?1 AND room_id = ?2",
+          params![account_key, room_id],
+          |row| row.get(0),
+        )
+        .optional()
+        .map_err(|e| e.to_string())?;
+      let mut stored = parsed.clone();
+      if let Some(received_at) = known {
+        stored.received_at = received_at;
+      }
+      let invite_json = serde_json::to_string(&stored).map_err(|e| e.to_string())?;
+      tx.execute(
+        "INSERT INTO pending_invites (account_key, room_id, invite_json, received_at) VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT(account_key, room_id) DO UPDATE SET invite_json = excluded.invite_json",
+        params![account_key, room_id, invite_json, stored.received_at],
+      )
+      .map_err(|e| e.to_string())?;
+      if known.is_none() {
+        fresh.push(stored);
+      }
+    }
+  }
+  tx.commit().map_err(|e| e.to_string())?;
+  Ok(fresh)
+}
+
+pub fn list_invites(conn: &Connection, account_key: &str) -> Result<Vec<PendingInvite>, String> {
+  let mut stmt = conn
+    .prepare("SELECT invite_json FROM pending_invites WHERE account_key = ?1 ORDER BY received_at DESC")
+    .map_err(|e| e.to_string())?;
+  let rows = stmt
+    .query_map([account_key], |row| row.get::<_, String>(0))
+    .map_err(|e| e.to_string())?;
+  let mut out = Vec::new();
+  for row in rows {
+    if let Ok(json) = row {
+      if let Ok(invite) = serde_json::from_str::<PendingInvite>(&json) { out.push(invite); }
+    }
+  }
+  Ok(out)
+}
+
+pub fn get_invite(conn: &Connection, account_key: &str, room_id: &str) -> Result<Option<PendingInvite>, String> {
+  let json: Option<String> = conn
+    .query_row(
+      "SELECT invite_json FROM pending_invites WHERE account_key = ?1 AND room_id = ?2",
+      params![account_key, room_id],
+      |row| row.get(0),
+    )
+    .optional()
+    .map_err(|e| e.to_string())?;
+  Ok(json.and_then(|json| serde_json::from_str(&json).ok()))
+}
+
+pub fn remove_invite(conn: &Connection, account_key: &str, room_id: &str) -> Result<(), String> {
+  conn
+    .execute(
+      "DELETE FROM pending_invites WHERE account_key = ?1 AND room_id = ?2",
+      params![account_key, room_id],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+fn server_of(user_or_room_id: &str) -> Option<String> {
+  user_or_room_id.split_once(':').map(|(_, server)| server.to_string())
+}
+
+pub async fn accept(client: &MatrixClient, room_id: &str, inviter: Option<&str>) -> Result<Value, ApiError> {
+  let via: Vec<(&str, String)> = inviter
+    .and_then(server_of)
+    .map(|server| vec![("server_name", server)])
+    .unwrap_or_default();
+  client
+    .request(
+      reqwest::Method::POST,
+      &client.client_url(&format!("/join/{}", encode(room_id))),
+      &via,
+      Some(&json!({})),
+    )
+    .await
+}
+
+pub async fn reject(client: &MatrixClient, room_id: &str, reason: Option<&str>) -> Result<Value, ApiError> {
+  let body = match reason {
+    Some(reason) => json!({ "reason": reason }),
+    None => json!({}),
+  };
+  client.post(&format!("/rooms/{}/leave", encode(room_id)), &body).await
+}
+
+pub async fn knock(client: &MatrixClient, room_id_or_alias: &str, reason: Option<&str>, via: &[String]) -> Result<Value, ApiError> {
+  let mut servers: Vec<(&str, String)> = via.iter().map(|s| ("server_name", s.clone())).collect();
+  if servers.is_empty() {
+    if let Some(server) = server_of(room_id_or_alias) {
+      servers.push(("server_name", server));
+    }
+  }
+  let body = match reason {
+    Some(reason) => json!({ "reason": reason }),
+    None => json!({}),
+  };
+  client
+    .request(
+      reqwest::Method::POST,
+      &client.client_url(&format!("/knock/{}", encode(room_id_or_alias))),
+      &servers,
+      Some(&body),
+    )
+    .await
+}
@@ -0,0 +1,225 @@
+use futures_util::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
+
+#[cfg(not(target_os = "linux"))]
+use serde_json::json;
+#[cfg(not(target_os = "linux"))]
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+
+/// Bus name, object path and interface on the session bus (Linux).
+pub const BUS_NAME: &str = "com.matrix_messenger.Messenger";
+pub const OBJECT_PATH: &str = "/com/matrix_messenger/Messenger";
+/// Pipe clients connect to on Windows.
+pub const PIPE_NAME: &str = r"\\.\pipe\matrix-messenger";
+
+#[cfg(not(target_os = "linux"))]
+const MAX_LINE: usize = 64 * 1024;
+
+/// One call from another program.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "camelCase")]
+pub enum IpcRequest {
+  /// `{ total, highlights, rooms }`
+  UnreadCounts,
+  /// `{ active }`
+  DoNotDisturb,
+  /// `{ active }` after the change. Switched on here it stays on until switched off.
+  SetDoNotDisturb { enabled: bool },
+  /// Room ID or alias; `{ opened }`.
+  OpenRoom {
+    #[serde(rename = "roomId")]
+    room_id: String,
+  },
+}
+
+pub type Handler = Arc<dyn Fn(IpcRequest) -> BoxFuture<'static, Result<Value, String>> + Send + Sync>;
+
+#[cfg(target_os = "linux")]
+mod dbus {
+  use super::{Handler, IpcRequest};
+  use serde_json::Value;
+  use std::collections::HashMap;
+  use zbus::object_server::SignalContext;
+
+  pub struct Messenger {
+    pub handler: Handler,
+  }
+
+  impl Messenger {
+    async fn call(&self, request: IpcRequest) -> zbus::fdo::Result<Value> {
+      (self.handler)(request).await.map_err(zbus::fdo::Error::Failed)
+    }
+  }
+
+  #[zbus::interface(name = "com.matrix_messenger.Messenger1")]
+  impl Messenger {
+    /// Total unread notifications and how many of them are highlights.
+    async fn unread_count(&self) -> zbus::fdo::Result<(u64, u64)> {
+      let result = self.call(IpcRequest::UnreadCounts).await?;
+      let field = |name: &str| result.get(name).and_then(|v| v.as_u64()).unwrap_or(0);
+      Ok((field("total"), field("highlights")))
+    }
+
+    /// Unread notifications per room ID.
+    async fn unread_rooms(&self) -> zbus::fdo::Result<HashMap<String, u64>> {
+      let result = self.call(IpcRequest::UnreadCounts).await?;
+      Ok(result.get("rooms").and_then(|v| serde_json::from_value(v.clone()).ok()).unwrap_or_default())
+    }
+
+    async fn do_not_disturb(&self) -> zbus::fdo::Result<bool> {
+      let result = self.call(IpcRequest::DoNotDisturb).await?;
+      Ok(result.get("active").and_then(|v| v.as_bool()).unwrap_or(false))
+    }
+
+    async fn set_do_not_disturb(&self, enabled: bool) -> zbus::fdo::Result<bool> {
+      let result = self.call(IpcRequest::SetDoNotDisturb { enabled }).await?;
+      Ok(result.get("active").and_then(|v| v.as_bool()).unwrap_or(false))
+    }
+
+    async fn open_room(&self, room_id: String) -> zbus::fdo::Result<bool> {
+      let result = self.call(IpcRequest::OpenRoom { room_id }).await?;
+      Ok(result.get("opened").and_then(|v| v.as_bool()).unwrap_or(false))
+    }
+
+    #[zbus(signal)]
+    pub async fn unread_changed(ctxt: &SignalContext<'_>, total: u64, highlights: u64) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    pub async fn do_not_disturb_changed(ctxt: &SignalContext<'_>, active: bool) -> zbus::Result<()>;
+  }
+
+  pub async fn connect(handler: Handler) -> Result<zbus::Connection, String> {
+    zbus::connection::Builder::session()
+      .and_then(|builder| builder.name(super::BUS_NAME))
+      .and_then(|builder| builder.serve_at(super::OBJECT_PATH, Messenger { handler }))
+      .map_err(|e| e.to_string())?
+      .build()
+      .await
+      .map_err(|e| format!("D-Bus session unavailable: {}", e))
+  }
+}
+
+/// Socket clients connect to on macOS and other Unix systems; only the current user can open it.
+#[cfg(all(unix, not(target_os = "linux")))]
+pub fn socket_path() -> std::path::PathBuf {
+  std::env::temp_dir().join("matrix-messenger.sock")
+}
+
+/// Answer newline-delimited JSON requests on one pipe connection, one JSON line per request:
+/// `{ "result": ... }` or `{ "error": "..." }`.
+#[cfg(not(target_os = "linux"))]
+async fn serve_stream<S: AsyncRead + AsyncWrite + Unpin>(stream: S, handler: Handler) {
+  let mut reader = BufReader::new(stream);
+  let mut line = String::new();
+  loop {
+    line.clear();
+    match reader.read_line(&mut line).await {
+      Ok(0) | Err(_) => return,
+      Ok(_) if line.len() > MAX_LINE => return,
+      Ok(_) => {}
+    }
+    if line.trim().is_empty() {
+      continue;
+    }
+    let reply = match serde_json::from_str::<IpcRequest>(line.trim()) {
+      Ok(request) => match handler(request).await {
+        Ok(result) => json!({ "result": result }),
+        Err(err) => json!({ "error": err }),
+      },
+      Err(err) => json!({ "error": format!("Invalid request: {}", err) }),
+    };
+    let mut bytes = reply.to_string().into_bytes();
+    bytes.push(b'\n');
+    if reader.get_mut().write_all(&bytes).await.is_err() {
+      return;
+    }
+  }
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+async fn listen(handler: Handler) -> Result<(), String> {
+  use std::os::unix::fs::PermissionsExt;
+  let path = socket_path();
+  let _ = std::fs::remove_file(&path);
+  let listener = tokio::net::UnixListener::bind(&path).map_err(|e| format!("Can't listen on {}: {}", path.display(), e))?;
+  std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).map_err(|e| e.to_string())?;
+  tokio::spawn(async move {
+    while let Ok((stream, _)) = listener.accept().await {
+      tokio::spawn(serve_stream(stream, handler.clone()));
+    }
+  });
+  Ok(())
+}
+
+#[cfg(windows)]
+async fn listen(handler: Handler) -> Result<(), String> {
+  use tokio::net::windows::named_pipe::ServerOptions;
+  let mut server = ServerOptions::new()
+    .first_pipe_instance(true)
+    .create(PIPE_NAME)
+    .map_err(|e| format!("Can't create {}: {}", PIPE_NAME, e))?;
+  tokio::spawn(async move {
+    loop {
+      if server.connect().await.is_err() {
+        continue;
+      }
+      let next = match ServerOptions::new().create(PIPE_NAME) {
+        Ok(next) => next,
+        Err(err) => {
+          tracing::warn!("IPC pipe closed: {}", err);
+          return;
+        }
+      };
+      tokio::spawn(serve_stream(std::mem::replace(&mut server, next), handler.clone()));
+    }
+  });
+  Ok(())
+}
+
+/// The IPC endpoint while the app runs.
+#[derive(Default)]
+pub struct IpcState {
+  #[cfg(target_os = "linux")]
+  connection: tokio::sync::Mutex<Option<zbus::Connection>>,
+}
+
+impl IpcState {
+  #[cfg(target_os = "linux")]
+  pub async fn start(&self, handler: Handler) -> Result<(), String> {
+    let connection = dbus::connect(handler).await?;
+    *self.connection.lock().await = Some(connection);
+    Ok(())
+  }
+
+  #[cfg(not(target_os = "linux"))]
+  pub async fn start(&self, handler: Handler) -> Result<(), String> {
+    listen(handler).await
+  }
+
+  /// Emit `UnreadChanged` to D-Bus listeners. Pipe clients poll instead.
+  #[cfg(target_os = "linux")]
+  pub async fn unread_changed(&self, total: u64, highlights: u64) {
+    if let Some(connection) = self.connection.lock().await.as_ref() {
+      if let Ok(ctxt) = zbus::object_server::SignalContext::new(connection, OBJECT_PATH) {
+        let _ = dbus::Messenger::unread_changed(&ctxt, total, highlights).await;
+      }
+    }
+  }
+
+  #[cfg(not(target_os = "linux"))]
+  pub async fn unread_changed(&self, _total: u64, _highlights: u64) {}
+
+  #[cfg(target_os = "linux")]
+  pub async fn dnd_changed(&self, active: bool) {
+    if let Some(connection) = self.connection.lock().await.as_ref() {
+      if let Ok(ctxt) = zbus::object_server::SignalContext::new(connection, OBJECT_PATH) {
+        let _ = dbus::Messenger::do_not_disturb_changed(&ctxt, active).await;
+      }
+    }
+  }
+
+  #[cfg(not(target_os = "linux"))]
+  pub async fn dnd_changed(&self, _active: bool) {}
+}
@@ -0,0 +1,372 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::matrix_api::{encode, next_txn_id, now_millis, ApiError, MatrixClient};
+
+/// How incoming `m.room_key_request`s from the user's own devices are answered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum KeySharePolicy {
+  /// Share automatically with own devices the user has verified; queue the rest for review.
+  VerifiedDevices,
+  /// Queue every request for manual approval.
+  Manual,
+  /// Drop all requests.
+  Never,
+}
+
+impl KeySharePolicy {
+  fn as_str(&self) -> &'static str {
+    match self {
+      KeySharePolicy::VerifiedDevices => "verifiedDevices",
+      KeySharePolicy::Manual => "manual",
+      KeySharePolicy::Never => "never",
+    }
+  }
+
+  fn parse(value: &str) -> KeySharePolicy {
+    match value {
+      "manual" => KeySharePolicy::Manual,
+      "never" => KeySharePolicy::Never,
+      _ => KeySharePolicy::VerifiedDevices,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MissingSession {
+  pub room_id: String,
+  pub session_id: String,
+  #[serde(default)]
+  pub sender_key: Option<String>,
+  #[serde(default)]
+  pub algorithm: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IncomingKeyRequest {
+  pub request_id: String,
+  pub requesting_device_id: String,
+  pub user_id: String,
+  pub room_id: String,
+  pub session_id: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub sender_key: Option<String>,
+  pub algorithm: String,
+  pub received_at: i64,
+}
+
+/// What to do with a request after applying the share policy.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "decision", rename_all = "camelCase")]
+pub enum KeyRequestDecision {
+  Share { request: IncomingKeyRequest },
+  Pending { request: IncomingKeyRequest },
+  Ignored { request_id: String },
+}
+
+pub fn init_key_requests_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS key_share_policy (
+        account_key TEXT PRIMARY KEY,
+        policy TEXT NOT NULL
+      );
+      CREATE TABLE IF NOT EXISTS outgoing_key_requests (
+        account_key TEXT NOT NULL,
+        room_id TEXT NOT NULL,
+        session_id TEXT NOT NULL,
+        request_id TEXT NOT NULL,
+        sent_at INTEGER NOT NULL,
+        PRIMARY KEY (account_key, room_id, session_id)
+      );
+      CREATE TABLE IF NOT EXISTS incoming_key_requests (
+        account_key TEXT NOT NULL,
+        request_id TEXT NOT NULL,
+        requesting_device_id TEXT NOT NULL,
+        user_id TEXT NOT NULL,
+        room_id TEXT NOT NULL,
+        session_id TEXT NOT NULL,
+        sender_key TEXT,
+        algorithm TEXT NOT NULL,
+        received_at INTEGER NOT NULL,
+        PRIMARY KEY (account_key, requesting_device_id, request_id)
+      );
+    ",
+  )
+}
+
+pub fn get_policy(conn: &Connection, account_key: &str) -> Result<KeySharePolicy, String> {
+  let stored: Option<String> = conn
+    .query_row(
+      "SELECT policy FROM key_share_policy WHERE account_key = ?1",
+      [account_key],
+      |row| row.get(0),
+    )
+    .optional()
+    .map_err(|e| e.to_string())?;
+  Ok(stored.map(|p| KeySharePolicy::parse(&p)).unwrap_or(KeySharePolicy::VerifiedDevices))
+}
+
+pub fn set_policy(conn: &Connection, account_key: &str, policy: KeySharePolicy) -> Result<(), String> {
+  conn
+    .execute(
+      "INSERT INTO key_share_policy (account_key, policy) VALUES (?1, ?2)
+       ON CONFLICT(account_key) DO UPDATE SET policy = excluded.policy",
+      params![account_key, policy.as_str()],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+/// Sessions not yet requested; a session is only asked for once until the request is cleared.
+pub fn unrequested(conn: &Connection, account_key: &str, sessions: &[MissingSession]) -> Result<Vec<MissingSession>, String> {
+  let mut out = Vec::new();
+  for session in sessions {
+    let known: Option<String> = conn
+      .query_row(
+        "SELECT request_id FROM outgoing_key_requests WHERE account_key = ?1 AND room_id = ?2 AND session_id = ?3",
+        params![account_key, session.room_id, session.session_id],
+        |row| row.get(0),
+      )
+      .optional()
+      .map_err(|e| e.to_string())?;
+    if known.is_none() {
+      out.push(session.clone());
+    }
+  }
+  Ok(out)
+}
+
+pub fn record_outgoing(conn: &Connection, account_key: &str, requests: &[(MissingSession, String)]) -> Result<(), String> {
+  let tx = conn.unchecked_transaction().map_err(|e| e.to_string())?;
+  for (session, request_id) in requests {
+    tx.execute(
+      "INSERT OR REPLACE INTO outgoing_key_requests (account_key, room_id, session_id, request_id, sent_at)
+       VALUES (?1, ?2, ?3, ?4, ?5)",
+      params![account_key, session.room_id, session.session_id, request_id, now_millis()],
+    )
+    .map_err(|e| e.to_string())?;
+  }
+  tx.commit().map_err(|e| e.to_string())
+}
+
+/// Forget outgoing requests for sessions that have since arrived and return their request IDs
+/// so they can be cancelled on the other devices.
+pub fn clear_outgoing(conn: &Connection, account_key: &str, room_id: &str, session_id: &str) -> Result<Option<String>, String> {
+  let request_id: Option<String> = conn
+    .query_row(
+      "SELECT request_id FROM outgoing_key_requests WHERE account_key = ?1 AND room_id = ?2 AND session_id = ?3",
+      params![account_key, room_id, session_id],
+      |row| row.get(0),
+    )
+    .optional()
+    .map_err(|e| e.to_string())?;
+  conn
+    .execute(
+      "DELETE FROM outgoing_key_requests WHERE account_key = ?1 AND room_id = ?2 AND session_id = ?3",
+      params![account_key, room_id, session_id],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(request_id)
+}
+
+async fn own_device_id(client: &MatrixClient) -> Result<String, ApiError> {
+  let whoami = client.get("/account/whoami", &[]).await?;
+  Ok(whoami
+    .get("device_id")
+    .and_then(|v| v.as_str())
+    .unwrap_or_default()
+    .to_string())
+}
+
+async fn send_to_own_devices(client: &MatrixClient, device_ids: &[String], content: &Value) -> Result<(), ApiError> {
+  let messages: serde_json::Map<String, Value> = device_ids
+    .iter()
+    .map(|device_id| (device_id.clone(), content.clone()))
+    .collect();
+  let path = format!("/sendToDevice/m.room_key_request/{}", encode(&next_txn_id()));
+  client
+    .put(&path, &json!({ "messages": { client.user_id.clone(): messages } }))
+    .await?;
+  Ok(())
+}
+
+/// Ask the user's own verified devices for each missing session; returns `(session, request_id)` pairs.
+pub async fn send_requests(
+  client: &MatrixClient,
+  verified_device_ids: &[String],
+  sessions: Vec<MissingSession>,
+) -> Result<Vec<(MissingSession, String)>, ApiError> {
+  let requesting_device_id = own_device_id(client).await?;
+  let targets: Vec<String> = verified_device_ids
+    .iter()
+    .filter(|id| **id != requesting_device_id)
+    .cloned()
+    .collect();
+  let mut sent = Vec::new();
+  if targets.is_empty() {
+    return Ok(sent);
+  }
+  for session in sessions {
+    let request_id = next_txn_id();
+    let content = json!({
+      "action": "request",
+      "request_id": request_id,
+      "requesting_device_id": requesting_device_id,
+      "body": {
+        "algorithm": session.algorithm.clone().unwrap_or_else(|| "m.megolm.v1.aes-sha2".to_string()),
+        "room_id": session.room_id,
+        "session_id": session.session_id,
+        "sender_key": session.sender_key,
+      },
+    });
+    send_to_own_devices(client, &targets, &content).await?;
+    sent.push((session, request_id));
+  }
+  Ok(sent)
+}
+
+pub async fn send_cancellation(client: &MatrixClient, verified_device_ids: &[String], request_id: &str) -> Result<(), ApiError> {
+  let requesting_device_id = own_device_id(client).await?;
+  let content = json!({
+    "action": "request_cancellation",
+    "request_id": request_id,
+    "requesting_device_id": requesting_device_id,
+  });
+  send_to_own_devices(client, verified_device_ids, &content).await
+}
+
+/// Apply the share policy to incoming `m.room_key_request` to-device events. Requests from
+/// other users are always ignored; cancellations remove the matching pending request.
+pub fn handle_incoming(
+  conn: &Connection,
+  account_key: &str,
+  own_user_id: &str,
+  verified_device_ids: &[String],
+  events: &[Value],
+) -> Result<Vec<KeyRequestDecision>, String> {
+  let policy = get_policy(conn, account_key)?;
+  let mut decisions = Vec::new();
+  for event in events {
+    let sender = event.get("sender").and_then(|v| v.as_str()).unwrap_or_default();
+    let content = match event.get("content") {
+      Some(content) => content,
+      None => continue,
+    };
+    let request_id = content.get("request_id").and_then(|v| v.as_str()).unwrap_or_default().to_string();
+    let device_id = content
+      .get("requesting_device_id")
+      .and_then(|v| v.as_str())
+      .unwrap_or_default()
+      .to_string();
+    if request_id.is_empty() || device_id.is_empty() {
+      continue;
+    }
+    if content.get("action").and_then(|v| v.as_str()) == Some("request_cancellation") {
+      remove_incoming(conn, account_key, &device_id, &request_id)?;
+      decisions.push(KeyRequestDecision::Ignored { request_id });
+      continue;
+    }
+    let body = content.get("body").cloned().unwrap_or(Value::Null);
+    let (room_id, session_id) = match (
+      body.get("room_id").and_then(|v| v.as_str()),
+      body.get("session_id").and_then(|v| v.as_str()),
+    ) {
+      (Some(room_id), Some(session_id)) => (room_id.to_string(), session_id.to_string()),
+      _ => continue,
+    };
+    if sender != own_user_id || policy == KeySharePolicy::Never {
+      decisions.push(KeyRequestDecision::Ignored { request_id });
+      continue;
+    }
+    let request = IncomingKeyRequest {
+      request_id,
+      requesting_device_id: device_id,
+      user_id: sender.to_string(),
+      room_id,
+      session_id,
+      sender_key: body.get("sender_key").and_then(|v| v.as_str()).map(|s| s.to_string()),
+      algorithm: body
+        .get("algorithm")
+        .and_then(|v| v.as_str())
+        .unwrap_or("m.megolm.v1.aes-sha2")
+        .to_string(),
+      received_at: now_millis(),
+    };
+    if policy == KeySharePolicy::VerifiedDevices && verified_device_ids.contains(&request.requesting_device_id) {
+      decisions.push(KeyRequestDecision::Share { request });
+    } else {
+      store_incoming(conn, account_key, &request)?;
+      decisions.push(KeyRequestDecision::Pending { request });
+    }
+  }
+  Ok(decisions)
+}
+
+fn store_incoming(conn: &Connection, account_key: &str, request: &IncomingKeyRequest) -> Result<(), String> {
+  conn
+    .execute(
+      "INSERT OR REPLACE INTO incoming_key_requests (
+          account_key, request_id, requesting_device_id, user_id, room_id, session_id, sender_key, algorithm, received_at
+        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+      params![
+        account_key,
+        request.request_id,
+        request.requesting_device_id,
+        request.user_id,
+        request.room_id,
+        request.session_id,
+        request.sender_key,
+        request.algorithm,
+        request.received_at,
+      ],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+pub fn list_incoming(conn: &Connection, account_key: &str) -> Result<Vec<IncomingKeyRequest>, String> {
+  let mut stmt = conn
+    .prepare(
+      "SELECT request_id, requesting_device_id, user_id, room_id, session_id, sender_key, algorithm, received_at
+       FROM incoming_key_requests WHERE account_key = ?1 ORDER BY received_at DESC",
+    )
+    .map_err(|e| e.to_string())?;
+  let rows = stmt
+    .query_map([account_key], |row| {
+      Ok(IncomingKeyRequest {
+        request_id: row.get(0)?,
+        requesting_device_id: row.get(1)?,
+        user_id: row.get(2)?,
+        room_id: row.get(3)?,
+        session_id: row.get(4)?,
+        sender_key: row.get(5)?,
+        algorithm: row.get(6)?,
+        received_at: row.get(7)?,
+      })
+    })
+    .map_err(|e| e.to_string())?;
+  Ok(rows.flatten().collect())
+}
+
+/// Remove a pending request and return it, e.g. once the user approved or denied it.
+pub fn take_incoming(conn: &Connection, account_key: &str, requesting_device_id: &str, request_id: &str) -> Result<Option<IncomingKeyRequest>, String> {
+  let request = list_incoming(conn, account_key)?
+    .into_iter()
+    .find(|r| r.requesting_device_id == requesting_device_id && r.request_id == request_id);
+  remove_incoming(conn, account_key, requesting_device_id, request_id)?;
+  Ok(request)
+}
+
+fn remove_incoming(conn: &Connection, account_key: &str, requesting_device_id: &str, request_id: &str) -> Result<(), String> {
+  conn
+    .execute(
+      "DELETE FROM incoming_key_requests WHERE account_key = ?1 AND requesting_device_id = ?2 AND request_id = ?3",
+      params![account_key, requesting_device_id, request_id],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
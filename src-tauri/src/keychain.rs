@@ -0,0 +1,67 @@
+use base64::{engine::general_purpose, Engine as _};
+use rand::{rngs::OsRng, RngCore};
+use std::sync::Mutex;
+
+const SERVICE: &str = "matrix-messenger";
+const ENTRY: &str = "credentials-store";
+
+/// Wrapper field of store values encrypted with the keychain key.
+pub const VAULT: &str = "vault";
+
+static KEY: Mutex<Option<[u8; 32]>> = Mutex::new(None);
+/// Set while the credential store was last written in plaintext because the keychain failed.
+static PLAINTEXT_REASON: Mutex<Option<String>> = Mutex::new(None);
+
+fn entry() -> Result<keyring::Entry, String> {
+  keyring::Entry::new(SERVICE, ENTRY).map_err(|e| format!("OS keychain unavailable: {}", e))
+}
+
+/// The key the credential store is encrypted with at rest, kept in the OS keychain and
+/// created on first use. Fails where no keychain is available (e.g. Linux without a Secret
+/// Service), in which case the store stays in plaintext.
+pub fn master_key() -> Result<[u8; 32], String> {
+  let mut cached = KEY.lock().map_err(|e| e.to_string())?;
+  if let Some(key) = *cached {
+    return Ok(key);
+  }
+  let entry = entry()?;
+  let key = match entry.get_password() {
+    Ok(encoded) => general_purpose::STANDARD
+      .decode(encoded.trim())
+      .ok()
+      .and_then(|bytes| <[u8; 32]>::try_from(bytes.as_slice()).ok())
+      .ok_or("Corrupt credential store key in the OS keychain")?,
+    Err(keyring::Error::NoEntry) => {
+      let mut key = [0u8; 32];
+      OsRng.fill_bytes(&mut key);
+      entry
+        .set_password(&general_purpose::STANDARD.encode(key))
+        .map_err(|e| format!("Failed to save the credential store key: {}", e))?;
+      key
+    }
+    Err(err) => return Err(format!("OS keychain unavailable: {}", err)),
+  };
+  *cached = Some(key);
+  Ok(key)
+}
+
+pub fn set_plaintext_reason(reason: Option<String>) {
+  if let Ok(mut current) = PLAINTEXT_REASON.lock() {
+    *current = reason;
+  }
+}
+
+pub fn plaintext_reason() -> Option<String> {
+  PLAINTEXT_REASON.lock().ok().and_then(|reason| reason.clone())
+}
+
+/// Remove the key from the keychain; whatever it encrypted becomes unreadable.
+pub fn forget() -> Result<(), String> {
+  if let Ok(mut cached) = KEY.lock() {
+    *cached = None;
+  }
+  match entry()?.delete_credential() {
+    Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+    Err(err) => Err(err.to_string()),
+  }
+}
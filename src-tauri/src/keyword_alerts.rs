@@ -0,0 +1,216 @@
+use regex::{Regex, RegexBuilder};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::event_cache;
+use crate::matrix_api::now_millis;
+
+/// Matches older than this (e.g. from back-pagination) are highlighted but do not notify.
+const NOTIFY_WINDOW_MS: i64 = 60 * 60 * 1000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KeywordAlert {
+  pub id: i64,
+  pub pattern: String,
+  pub is_regex: bool,
+  pub case_sensitive: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KeywordHit {
+  pub room_id: String,
+  pub event_id: String,
+  pub sender: String,
+  pub body: String,
+  pub matched: String,
+  pub ts: i64,
+}
+
+pub fn init_keyword_alerts_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS keyword_alerts (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        account_key TEXT NOT NULL,
+        pattern TEXT NOT NULL,
+        is_regex INTEGER NOT NULL,
+        case_sensitive INTEGER NOT NULL,
+        created_at INTEGER NOT NULL
+      );
+      CREATE INDEX IF NOT EXISTS idx_keyword_alerts_account ON keyword_alerts(account_key);
+      CREATE TABLE IF NOT EXISTS keyword_highlights (
+        account_key TEXT NOT NULL,
+        room_id TEXT NOT NULL,
+        event_id TEXT NOT NULL,
+        sender TEXT NOT NULL,
+        body TEXT NOT NULL,
+        matched TEXT NOT NULL,
+        ts INTEGER NOT NULL,
+        PRIMARY KEY (account_key, room_id, event_id)
+      );
+      CREATE INDEX IF NOT EXISTS idx_keyword_highlights_event ON keyword_highlights(room_id, event_id);
+    ",
+  )
+}
+
+fn compile(pattern: &str, is_regex: bool, case_sensitive: bool) -> Result<Regex, String> {
+  let source = if is_regex {
+    pattern.to_string()
+  } else {
+    let keyword = pattern.trim();
+    let boundary = |c: Option<char>| if c.is_some_and(|c| c.is_alphanumeric() || c == '_') { r"\b" } else { "" };
+    format!("{}{}{}", boundary(keyword.chars().next()), regex::escape(keyword), boundary(keyword.chars().last()))
+  };
+  RegexBuilder::new(&source)
+    .case_insensitive(!case_sensitive)
+    .size_limit(1 << 20)
+    .build()
+    .map_err(|e| format!("Invalid pattern: {}", e))
+}
+
+pub fn list(conn: &Connection, account_key: &str) -> Result<Vec<KeywordAlert>, String> {
+  let mut stmt = conn
+    .prepare("SELECT id, pattern, is_regex, case_sensitive FROM keyword_alerts WHERE account_key = ?1 ORDER BY id")
+    .map_err(|e| e.to_string())?;
+  let rows = stmt
+    .query_map([account_key], |row| {
+      Ok(KeywordAlert {
+        id: row.get(0)?,
+        pattern: row.get(1)?,
+        is_regex: row.get::<_, i64>(2)? != 0,
+        case_sensitive: row.get::<_, i64>(3)? != 0,
+      })
+    })
+    .map_err(|e| e.to_string())?;
+  let mut out = Vec::new();
+  for row in rows {
+    out.push(row.map_err(|e| e.to_string())?);
+  }
+  Ok(out)
+}
+
+pub fn add(conn: &Connection, account_key: &str, pattern: &str, is_regex: bool, case_sensitive: bool) -> Result<KeywordAlert, String> {
+  if pattern.trim().is_empty() {
+    return Err("The keyword is empty".to_string());
+  }
+  compile(pattern, is_regex, case_sensitive)?;
+  conn
+    .execute(
+      "INSERT INTO keyword_alerts (account_key, pattern, is_regex, case_sensitive, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+      params![account_key, pattern, is_regex as i64, case_sensitive as i64, now_millis()],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(KeywordAlert {
+    id: conn.last_insert_rowid(),
+    pattern: pattern.to_string(),
+    is_regex,
+    case_sensitive,
+  })
+}
+
+pub fn remove(conn: &Connection, account_key: &str, id: i64) -> Result<(), String> {
+  conn
+    .execute("DELETE FROM keyword_alerts WHERE account_key = ?1 AND id = ?2", params![account_key, id])
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+/// Match message bodies of events not yet in the event cache against the account's keywords,
+/// record the highlights, and return the recent ones that should notify. Call this before the
+/// events are cached.
+pub fn scan(
+  conn: &Connection,
+  account_key: &str,
+  own_user_id: &str,
+  room_id: &str,
+  events: &[Value],
+) -> Result<Vec<KeywordHit>, String> {
+  let matchers: Vec<Regex> = list(conn, account_key)?
+    .iter()
+    .filter_map(|alert| compile(&alert.pattern, alert.is_regex, alert.case_sensitive).ok())
+    .collect();
+  if matchers.is_empty() {
+    return Ok(Vec::new());
+  }
+  let mut hits = Vec::new();
+  for raw in events {
+    if raw.get("type").and_then(|v| v.as_str()) != Some("m.room.message") {
+      continue;
+    }
+    let Some(event_id) = raw.get("event_id").and_then(|v| v.as_str()) else {
+      continue;
+    };
+    let sender = raw.get("sender").and_then(|v| v.as_str()).unwrap_or_default();
+    if sender == own_user_id || event_cache::get_event(conn, account_key, room_id, event_id)?.is_some() {
+      continue;
+    }
+    let content = raw.get("content");
+    let body = content
+      .and_then(|c| c.pointer("/m.new_content/body").or_else(|| c.get("body")))
+      .and_then(|v| v.as_str())
+      .unwrap_or_default();
+    let Some(matched) = matchers.iter().find_map(|m| m.find(body)).map(|m| m.as_str().to_string()) else {
+      continue;
+    };
+    let ts = raw.get("origin_server_ts").and_then(|v| v.as_i64()).unwrap_or_default();
+    let inserted = conn
+      .execute(
+        "INSERT OR IGNORE INTO keyword_highlights (account_key, room_id, event_id, sender, body, matched, ts)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![account_key, room_id, event_id, sender, body, matched, ts],
+      )
+      .map_err(|e| e.to_string())?;
+    if inserted > 0 && now_millis() - ts < NOTIFY_WINDOW_MS {
+      hits.push(KeywordHit {
+        room_id: room_id.to_string(),
+        event_id: event_id.to_string(),
+        sender: sender.to_string(),
+        body: body.to_string(),
+        matched,
+        ts,
+      });
+    }
+  }
+  Ok(hits)
+}
+
+pub fn is_highlighted(conn: &Connection, room_id: &str, event_id: &str) -> Result<bool, String> {
+  conn
+    .query_row(
+      "SELECT 1 FROM keyword_highlights WHERE room_id = ?1 AND event_id = ?2 LIMIT 1",
+      params![room_id, event_id],
+      |_| Ok(()),
+    )
+    .optional()
+    .map(|found| found.is_some())
+    .map_err(|e| e.to_string())
+}
+
+/// Newest highlights first.
+pub fn highlights(conn: &Connection, account_key: &str, limit: usize) -> Result<Vec<KeywordHit>, String> {
+  let mut stmt = conn
+    .prepare(
+      "SELECT room_id, event_id, sender, body, matched, ts FROM keyword_highlights
+       WHERE account_key = ?1 ORDER BY ts DESC LIMIT ?2",
+    )
+    .map_err(|e| e.to_string())?;
+  let rows = stmt
+    .query_map(params![account_key, limit as i64], |row| {
+      Ok(KeywordHit {
+        room_id: row.get(0)?,
+        event_id: row.get(1)?,
+        sender: row.get(2)?,
+        body: row.get(3)?,
+        matched: row.get(4)?,
+        ts: row.get(5)?,
+      })
+    })
+    .map_err(|e| e.to_string())?;
+  let mut out = Vec::new();
+  for row in rows {
+    out.push(row.map_err(|e| e.to_string())?);
+  }
+  Ok(out)
+}
@@ -0,0 +1,297 @@
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::event_cache::{self, CachedEvent};
+use crate::matrix_api::{now_millis, ApiError, MatrixClient};
+
+pub const BEACON_INFO_TYPE: &str = "org.matrix.msc3672.beacon_info";
+pub const BEACON_TYPE: &str = "org.matrix.msc3672.beacon";
+const BEACON_INFO_TYPES: [&str; 2] = ["m.beacon_info", BEACON_INFO_TYPE];
+const BEACON_TYPES: [&str; 2] = ["m.beacon", BEACON_TYPE];
+const DEFAULT_INTERVAL_SECS: u64 = 30;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GeoPosition {
+  pub latitude: f64,
+  pub longitude: f64,
+  #[serde(default)]
+  pub accuracy: Option<f64>,
+  #[serde(default)]
+  pub timestamp: Option<i64>,
+}
+
+impl GeoPosition {
+  pub fn geo_uri(&self) -> String {
+    match self.accuracy {
+      Some(accuracy) => format!("geo:{},{};u={}", self.latitude, self.longitude, accuracy),
+      None => format!("geo:{},{}", self.latitude, self.longitude),
+    }
+  }
+
+  pub fn from_geo_uri(uri: &str, timestamp: Option<i64>) -> Option<GeoPosition> {
+    let rest = uri.strip_prefix("geo:")?;
+    let mut parts = rest.split(';');
+    let mut coords = parts.next()?.split(',');
+    let latitude = coords.next()?.trim().parse().ok()?;
+    let longitude = coords.next()?.trim().parse().ok()?;
+    let accuracy = parts
+      .find_map(|p| p.strip_prefix("u="))
+      .and_then(|u| u.parse().ok());
+    Some(GeoPosition { latitude, longitude, accuracy, timestamp })
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LiveBeaconSession {
+  pub account_key: String,
+  pub room_id: String,
+  pub beacon_info_event_id: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub description: Option<String>,
+  pub started_at: i64,
+  pub expires_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomBeacon {
+  pub user_id: String,
+  pub beacon_info_event_id: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub description: Option<String>,
+  pub live: bool,
+  pub started_at: i64,
+  pub expires_at: i64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub latest: Option<GeoPosition>,
+}
+
+struct ActiveBeacon {
+  session: LiveBeaconSession,
+  task: tauri::async_runtime::JoinHandle<()>,
+}
+
+/// Outgoing beacons and the most recent position reported by the OS hook or the user.
+#[derive(Default)]
+pub struct LiveLocationState {
+  position: Arc<Mutex<Option<GeoPosition>>>,
+  active: Mutex<HashMap<String, ActiveBeacon>>,
+}
+
+fn session_key(account_key: &str, room_id: &str) -> String {
+  format!("{}|{}", account_key, room_id)
+}
+
+impl LiveLocationState {
+  pub fn set_position(&self, mut position: GeoPosition) {
+    if position.timestamp.is_none() {
+      position.timestamp = Some(now_millis());
+    }
+    if let Ok(mut current) = self.position.lock() {
+      *current = Some(position);
+    }
+  }
+
+  pub fn sessions(&self) -> Vec<LiveBeaconSession> {
+    self
+      .active
+      .lock()
+      .map(|active| active.values().map(|a| a.session.clone()).collect())
+      .unwrap_or_default()
+  }
+
+  fn take(&self, account_key: &str, room_id: &str) -> Option<LiveBeaconSession> {
+    let removed = self.active.lock().ok()?.remove(&session_key(account_key, room_id))?;
+    removed.task.abort();
+    Some(removed.session)
+  }
+}
+
+fn beacon_info_content(description: Option<&str>, live: bool, timeout_ms: i64, started_at: i64) -> Value {
+  json!({
+    "description": description,
+    "live": live,
+    "timeout": timeout_ms,
+    "org.matrix.msc3488.ts": started_at,
+    "org.matrix.msc3488.asset": { "type": "m.self" },
+  })
+}
+
+pub async fn publish_position(client: &MatrixClient, room_id: &str, beacon_info_event_id: &str, position: &GeoPosition) -> Result<String, ApiError> {
+  let content = json!({
+    "m.relates_to": { "rel_type": "m.reference", "event_id": beacon_info_event_id },
+    "org.matrix.msc3488.location": { "uri": position.geo_uri() },
+    "org.matrix.msc3488.ts": position.timestamp.unwrap_or_else(now_millis),
+  });
+  client.send_event(room_id, BEACON_TYPE, &content).await
+}
+
+/// Announce a live beacon and start publishing the shared position every `interval_secs`
+/// until the beacon expires or is stopped.
+pub async fn start(
+  state: &LiveLocationState,
+  client: MatrixClient,
+  account_key: &str,
+  room_id: &str,
+  duration_ms: i64,
+  description: Option<String>,
+  interval_secs: Option<u64>,
+) -> Result<LiveBeaconSession, String> {
+  if let Some(previous) = state.take(account_key, room_id) {
+    let _ = stop_beacon(&client, &previous).await;
+  }
+  let started_at = now_millis();
+  let content = beacon_info_content(description.as_deref(), true, duration_ms, started_at);
+  let beacon_info_event_id = client
+    .send_state(room_id, BEACON_INFO_TYPE, &client.user_id, &content)
+    .await?;
+  let session = LiveBeaconSession {
+    account_key: account_key.to_string(),
+    room_id: room_id.to_string(),
+    beacon_info_event_id,
+    description,
+    started_at,
+    expires_at: started_at + duration_ms,
+  };
+
+  let position = state.position.clone();
+  let task_session = session.clone();
+  let interval = Duration::from_secs(interval_secs.unwrap_or(DEFAULT_INTERVAL_SECS).max(5));
+  let task = tauri::async_runtime::spawn(async move {
+    let mut last_sent: Option<i64> = None;
+    while now_millis() < task_session.expires_at {
+      let current = position.lock().ok().and_then(|p| p.clone());
+      if let Some(current) = current {
+        if current.timestamp != last_sent {
+          if publish_position(&client, &task_session.room_id, &task_session.beacon_info_event_id, &current)
+            .await
+            .is_ok()
+          {
+            last_sent = current.timestamp;
+          }
+        }
+      }
+      tokio::time::sleep(interval).await;
+    }
+    let _ = stop_beacon(&client, &task_session).await;
+  });
+
+  if let Ok(mut active) = state.active.lock() {
+    active.insert(
+      session_key(account_key, room_id),
+      ActiveBeacon { session: session.clone(), task },
+    );
+  }
+  Ok(session)
+}
+
+async fn stop_beacon(client: &MatrixClient, session: &LiveBeaconSession) -> Result<(), ApiError> {
+  let content = beacon_info_content(
+    session.description.as_deref(),
+    false,
+    session.expires_at - session.started_at,
+    session.started_at,
+  );
+  client
+    .send_state(&session.room_id, BEACON_INFO_TYPE, &client.user_id, &content)
+    .await?;
+  Ok(())
+}
+
+pub async fn stop(state: &LiveLocationState, client: &MatrixClient, account_key: &str, room_id: &str) -> Result<bool, String> {
+  match state.take(account_key, room_id) {
+    Some(session) => {
+      stop_beacon(client, &session).await?;
+      Ok(true)
+    }
+    None => Ok(false),
+  }
+}
+
+fn latest_position(
+  conn: &Connection,
+  account_key: &str,
+  room_id: &str,
+  beacon_info_event_id: &str,
+) -> Result<Option<GeoPosition>, String> {
+  let updates = event_cache::get_relations(conn, account_key, room_id, beacon_info_event_id, Some("m.reference"))?;
+  Ok(updates
+    .iter()
+    .rev()
+    .filter(|e| BEACON_TYPES.contains(&e.event_type.as_str()))
+    .find_map(|e| {
+      let uri = e
+        .content
+        .pointer("/org.matrix.msc3488.location/uri")
+        .or_else(|| e.content.pointer("/m.location/uri"))?
+        .as_str()?;
+      let ts = e
+        .content
+        .get("org.matrix.msc3488.ts")
+        .and_then(|v| v.as_i64())
+        .unwrap_or(e.origin_server_ts);
+      GeoPosition::from_geo_uri(uri, Some(ts))
+    }))
+}
+
+fn to_room_beacon(conn: &Connection, account_key: &str, info: &CachedEvent) -> Result<RoomBeacon, String> {
+  let started_at = info
+    .content
+    .get("org.matrix.msc3488.ts")
+    .and_then(|v| v.as_i64())
+    .unwrap_or(info.origin_server_ts);
+  let timeout = info.content.get("timeout").and_then(|v| v.as_i64()).unwrap_or_default();
+  let expires_at = started_at + timeout;
+  let live = info.content.get("live").and_then(|v| v.as_bool()).unwrap_or(false) && expires_at > now_millis();
+  Ok(RoomBeacon {
+    user_id: info.state_key.clone().unwrap_or_else(|| info.sender.clone()),
+    beacon_info_event_id: info.event_id.clone(),
+    description: info.content.get("description").and_then(|v| v.as_str()).map(|s| s.to_string()),
+    live,
+    started_at,
+    expires_at,
+    latest: latest_position(conn, account_key, &info.room_id, &info.event_id)?,
+  })
+}
+
+/// Current beacon per user in a room as the account saw it, with the newest reported position,
+/// for the live map.
+pub fn room_beacons(conn: &Connection, account_key: &str, room_id: &str, live_only: bool) -> Result<Vec<RoomBeacon>, String> {
+  let mut stmt = conn
+    .prepare(
+      "SELECT event_id, state_key FROM event_cache
+       WHERE account_key = ?1 AND room_id = ?2 AND event_type IN (?3, ?4) AND state_key IS NOT NULL
+       ORDER BY origin_server_ts DESC",
+    )
+    .map_err(|e| e.to_string())?;
+  let rows = stmt
+    .query_map(params![account_key, room_id, BEACON_INFO_TYPES[0], BEACON_INFO_TYPES[1]], |row| {
+      Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+    })
+    .map_err(|e| e.to_string())?;
+  let mut seen_users = std::collections::HashSet::new();
+  let mut out = Vec::new();
+  for row in rows {
+    let (event_id, state_key) = match row {
+      Ok(row) => row,
+      Err(_) => continue,
+    };
+    if !seen_users.insert(state_key) {
+      continue;
+    }
+    if let Some(info) = event_cache::get_event(conn, account_key, room_id, &event_id)? {
+      let beacon = to_room_beacon(conn, account_key, &info)?;
+      if live_only && !beacon.live {
+        continue;
+      }
+      out.push(beacon);
+    }
+  }
+  Ok(out)
+}
@@ -0,0 +1,287 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::filter::{filter_fn, EnvFilter};
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, Layer, Registry};
+
+use crate::diagnostics;
+use crate::matrix_api::now_millis;
+
+const CRATE: &str = "matrix_messenger";
+const RECENT_CAPACITY: usize = 2_000;
+const KEPT_FILES: usize = 7;
+pub const DEFAULT_SUBSYSTEM: &str = "app";
+
+/// Log files and the modules (or explicit `target:`s) that write to each.
+pub const SUBSYSTEMS: &[(&str, &[&str])] = &[
+  ("sync", &["sync", "background_sync", "unified_push", "connectivity", "push_events", "push_rules"]),
+  ("crypto", &["crypto", "attachment_crypto", "dehydrated_device", "key_requests", "decryption_retry"]),
+  ("deployment", &["deployment"]),
+  ("index", &["index", "event_cache", "room_upgrade"]),
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogEntry {
+  pub ts: i64,
+  pub level: String,
+  pub subsystem: String,
+  pub target: String,
+  pub message: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogFilter {
+  #[serde(default)]
+  pub subsystem: Option<String>,
+  /// Minimum level, e.g. `warn` shows warnings and errors.
+  #[serde(default)]
+  pub level: Option<String>,
+  #[serde(default)]
+  pub contains: Option<String>,
+  #[serde(default)]
+  pub limit: Option<usize>,
+}
+
+struct Logging {
+  recent: Mutex<VecDeque<LogEntry>>,
+  levels: Mutex<BTreeMap<String, String>>,
+  filter: reload::Handle<EnvFilter, Registry>,
+}
+
+static LOGGING: OnceLock<Logging> = OnceLock::new();
+
+/// `matrix_messenger::deployment::ssh` -> `deployment`; explicit targets like `sync` map directly.
+pub fn subsystem_of(target: &str) -> &'static str {
+  let module = target
+    .strip_prefix(CRATE)
+    .map(|rest| rest.trim_start_matches("::"))
+    .unwrap_or(target);
+  let module = module.split("::").next().unwrap_or(module);
+  SUBSYSTEMS
+    .iter()
+    .find(|(_, modules)| modules.contains(&module))
+    .map(|(name, _)| *name)
+    .unwrap_or(DEFAULT_SUBSYSTEM)
+}
+
+fn level_rank(level: &str) -> u8 {
+  match level.to_ascii_lowercase().as_str() {
+    "trace" => 0,
+    "debug" => 1,
+    "info" => 2,
+    "warn" => 3,
+    _ => 4,
+  }
+}
+
+fn parse_level(level: &str) -> Result<String, String> {
+  let level = level.to_ascii_lowercase();
+  match level.as_str() {
+    "trace" | "debug" | "info" | "warn" | "error" | "off" => Ok(level),
+    _ => Err(format!("Unknown log level: {}", level)),
+  }
+}
+
+/// Env filter directives: `info` for the crate plus per-subsystem or per-target overrides.
+fn directives(levels: &BTreeMap<String, String>) -> String {
+  let mut directives = vec!["warn".to_string(), format!("{}=info", CRATE)];
+  for (name, modules) in SUBSYSTEMS {
+    directives.push(format!("{}=info", name));
+    if let Some(level) = levels.get(*name) {
+      for module in modules.iter() {
+        directives.push(format!("{}={}", module, level));
+        directives.push(format!("{}::{}={}", CRATE, module, level));
+      }
+    }
+  }
+  for (module, level) in levels {
+    if !SUBSYSTEMS.iter().any(|(name, _)| name == module) {
+      directives.push(format!("{}={}", module, level));
+    }
+  }
+  directives.join(",")
+}
+
+#[derive(Default)]
+struct MessageVisitor {
+  message: String,
+  fields: String,
+}
+
+impl Visit for MessageVisitor {
+  fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+    if field.name() == "message" {
+      let _ = write!(self.message, "{:?}", value);
+    } else {
+      let _ = write!(self.fields, " {}={:?}", field.name(), value);
+    }
+  }
+
+  fn record_str(&mut self, field: &Field, value: &str) {
+    if field.name() == "message" {
+      self.message.push_str(value);
+    } else {
+      let _ = write!(self.fields, " {}={}", field.name(), value);
+    }
+  }
+}
+
+/// Keeps recent entries in memory for the in-app viewer.
+struct RecentLayer;
+
+impl<S: Subscriber> Layer<S> for RecentLayer {
+  fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+    let Some(logging) = LOGGING.get() else {
+      return;
+    };
+    let mut visitor = MessageVisitor::default();
+    event.record(&mut visitor);
+    let target = event.metadata().target();
+    let entry = LogEntry {
+      ts: now_millis(),
+      level: event.metadata().level().as_str().to_ascii_lowercase(),
+      subsystem: subsystem_of(target).to_string(),
+      target: target.to_string(),
+      message: diagnostics::scrub_text(&format!("{}{}", visitor.message, visitor.fields)),
+    };
+    if let Ok(mut recent) = logging.recent.lock() {
+      if recent.len() >= RECENT_CAPACITY {
+        recent.pop_front();
+      }
+      recent.push_back(entry);
+    }
+  }
+}
+
+fn file_layer<S>(dir: &Path, subsystem: &'static str) -> Option<Box<dyn Layer<S> + Send + Sync>>
+where
+  S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+  let appender = RollingFileAppender::builder()
+    .rotation(Rotation::DAILY)
+    .filename_prefix(subsystem)
+    .filename_suffix("log")
+    .max_log_files(KEPT_FILES)
+    .build(dir)
+    .ok()?;
+  Some(
+    tracing_subscriber::fmt::layer()
+      .with_ansi(false)
+      .with_writer(appender)
+      .with_filter(filter_fn(move |meta| subsystem_of(meta.target()) == subsystem))
+      .boxed(),
+  )
+}
+
+/// Route `tracing` output to one daily-rotated file per subsystem in `dir`, stderr and the
+/// in-memory buffer. `levels` are the overrides saved by `set_level`.
+pub fn init(dir: &Path, levels: BTreeMap<String, String>) -> Result<(), String> {
+  fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+  let (filter, handle) = reload::Layer::new(EnvFilter::new(directives(&levels)));
+  let mut files: Vec<Box<dyn Layer<_> + Send + Sync>> = Vec::new();
+  for name in SUBSYSTEMS.iter().map(|(name, _)| *name).chain(std::iter::once(DEFAULT_SUBSYSTEM)) {
+    if let Some(layer) = file_layer(dir, name) {
+      files.push(layer);
+    }
+  }
+  LOGGING
+    .set(Logging {
+      recent: Mutex::new(VecDeque::with_capacity(RECENT_CAPACITY)),
+      levels: Mutex::new(levels),
+      filter: handle,
+    })
+    .map_err(|_| "Logging is already initialized".to_string())?;
+  tracing_subscriber::registry()
+    .with(filter)
+    .with(files)
+    .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
+    .with(RecentLayer)
+    .try_init()
+    .map_err(|e| e.to_string())
+}
+
+/// Change the level of a subsystem (`sync`, `crypto`, `deployment`, `index`, `app`) or any
+/// target; `None` restores the default. Returns all overrides for persisting.
+pub fn set_level(module: &str, level: Option<&str>) -> Result<BTreeMap<String, String>, String> {
+  let logging = LOGGING.get().ok_or("Logging is not initialized")?;
+  let mut levels = logging.levels.lock().map_err(|e| e.to_string())?;
+  let module = if module == DEFAULT_SUBSYSTEM { CRATE } else { module };
+  match level {
+    Some(level) => {
+      levels.insert(module.to_string(), parse_level(level)?);
+    }
+    None => {
+      levels.remove(module);
+    }
+  }
+  logging
+    .filter
+    .reload(EnvFilter::new(directives(&levels)))
+    .map_err(|e| e.to_string())?;
+  Ok(levels.clone())
+}
+
+pub fn levels() -> BTreeMap<String, String> {
+  LOGGING
+    .get()
+    .and_then(|logging| logging.levels.lock().ok().map(|levels| levels.clone()))
+    .unwrap_or_default()
+}
+
+/// Buffered entries matching `filter`, newest last.
+pub fn recent(filter: &LogFilter) -> Vec<LogEntry> {
+  let Some(logging) = LOGGING.get() else {
+    return Vec::new();
+  };
+  let Ok(recent) = logging.recent.lock() else {
+    return Vec::new();
+  };
+  let min_rank = filter.level.as_deref().map(level_rank).unwrap_or(0);
+  let needle = filter.contains.as_deref().map(|s| s.to_lowercase());
+  let mut matched: Vec<LogEntry> = recent
+    .iter()
+    .rev()
+    .filter(|entry| filter.subsystem.as_deref().map_or(true, |s| s == entry.subsystem))
+    .filter(|entry| level_rank(&entry.level) >= min_rank)
+    .filter(|entry| needle.as_deref().map_or(true, |n| entry.message.to_lowercase().contains(n)))
+    .take(filter.limit.unwrap_or(500))
+    .cloned()
+    .collect();
+  matched.reverse();
+  matched
+}
+
+/// The last `max_bytes` of each current log file, for the diagnostics bundle.
+pub fn tails(dir: &Path, max_bytes: u64) -> Vec<(PathBuf, String)> {
+  let Ok(entries) = fs::read_dir(dir) else {
+    return Vec::new();
+  };
+  let mut files: Vec<PathBuf> = entries
+    .flatten()
+    .map(|entry| entry.path())
+    .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+    .collect();
+  files.sort();
+  files
+    .into_iter()
+    .filter_map(|path| {
+      let mut file = fs::File::open(&path).ok()?;
+      let len = file.metadata().ok()?.len();
+      file.seek(SeekFrom::Start(len.saturating_sub(max_bytes))).ok()?;
+      let mut bytes = Vec::new();
+      file.read_to_end(&mut bytes).ok()?;
+      Some((path, String::from_utf8_lossy(&bytes).into_owned()))
+    })
+    .collect()
+}
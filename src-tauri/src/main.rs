@@ -1,27 +1,137 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+mod account_data;
+mod attachment_crypto;
+mod audio_waveform;
+mod auto_download;
+mod autostart;
+mod background_sync;
+mod badge;
+mod calendar;
+mod call_log;
+mod capabilities;
+mod chat_import;
+mod clipboard;
+mod connectivity;
+mod contacts;
+mod decryption_retry;
+mod deep_links;
+mod dehydrated_device;
 mod deployment;
+mod diagnostics;
+mod directory;
+mod document_preview;
+mod drafts;
+mod element_import;
+mod email_digest;
+mod event_cache;
+mod feeds;
+mod gifs;
+mod headless;
+mod hotkeys;
+mod idle;
+mod ipc;
+mod ignore_list;
+mod image_packs;
+mod image_processing;
+mod invites;
+mod keyword_alerts;
+mod key_requests;
+mod keychain;
+mod location;
+mod logging;
+mod malware_scan;
+mod matrix_api;
+mod media_cache;
+mod media_download;
+mod media_export;
+mod media_upload;
+mod message_search;
+mod network_diagnostics;
+mod notification_actions;
+mod notification_dispatch;
+mod notification_history;
+mod notification_rules;
+mod notification_sounds;
+mod panic_wipe;
+mod polls;
+mod popout_windows;
+mod profiles;
+mod proxy;
+mod push_events;
+mod push_rules;
+mod quiet_hours;
+mod rate_limit;
+mod read_later;
+mod reminders;
+mod reports;
+mod retention;
+mod rich_notifications;
+mod room_export;
+mod room_upgrade;
+mod scheduled_messages;
+mod screen_share;
+mod screenshot;
+mod security_key;
+mod snooze;
+mod storage;
+mod sync_engine;
+mod threads;
+mod threepid;
+mod translation;
+mod tray;
+mod unified_push;
+mod updates;
+mod upload_staging;
+mod url_preview;
+mod video;
+mod voice;
+mod wasm_plugins;
+mod webhook;
 
 use deployment::{deploy_synapse_server, DeploymentConfig, DeploymentStatus};
+use matrix_api::{now_millis, ApiError, MatrixClient};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::{collections::HashMap, fs, path::PathBuf, time::{SystemTime, UNIX_EPOCH}};
-use tauri::AppHandle;
+use tauri::{AppHandle, Manager};
+use tauri_plugin_deep_link::DeepLinkExt;
+use tauri_plugin_global_shortcut::GlobalShortcutExt;
 use tauri_plugin_notification::NotificationExt;
+use tauri_plugin_opener::OpenerExt;
 use tauri_plugin_store::StoreBuilder;
+use tauri_plugin_updater::UpdaterExt;
 use aes_gcm::{aead::{Aead, KeyInit}, Aes256Gcm, Nonce};
+use argon2::Argon2;
 use base64::{engine::general_purpose, Engine as _};
 use pbkdf2::pbkdf2_hmac;
 use rand::{rngs::OsRng, RngCore};
-use sha2::Sha256;
-use rusqlite::{params, params_from_iter, types::Value, Connection};
+use sha2::{Digest, Sha256};
+use rusqlite::{params, params_from_iter, types::Value, Connection, OptionalExtension};
 
 const STORE_FILE: &str = "secure_credentials.store";
 const ACCOUNTS_KEY: &str = "accounts";
 const BACKUP_STORE_FILE: &str = "secure_key_backups.store";
 const BACKUP_KEY: &str = "backups";
 const PASSKEYS_KEY: &str = "passkey_devices";
+const MEDIA_CACHE_KEY: &str = "media_cache_key";
+const GIF_PROVIDER_KEY: &str = "gif_provider";
+const UNIFIED_PUSH_KEY: &str = "unified_push";
+const EMAIL_DIGEST_KEY: &str = "email_digest";
+const UPDATES_KEY: &str = "updates";
+const CRASH_REPORTS_KEY: &str = "crash_reports";
+const LOG_LEVELS_KEY: &str = "log_levels";
+const WEBHOOK_KEY: &str = "webhook";
+const TRANSLATION_KEY: &str = "translation";
 const PBKDF2_ITERATIONS: u32 = 120_000;
+const ARGON2_MEMORY_KIB: u32 = 64 * 1024;
+const ARGON2_ITERATIONS: u32 = 3;
+const ARGON2_LANES: u32 = 1;
+/// Upper bounds for Argon2id parameters read back from a backup file, so a crafted or
+/// corrupted backup can't make a restore allocate gigabytes or run for hours.
+const ARGON2_MAX_MEMORY_KIB: u32 = 1024 * 1024;
+const ARGON2_MAX_ITERATIONS: u32 = 10;
+const ARGON2_MAX_LANES: u32 = 8;
 const SALT_LEN: usize = 16;
 const NONCE_LEN: usize = 12;
 
@@ -44,6 +154,12 @@ pub struct Credentials {
   pub access_token: String,
   #[serde(default)]
   #[serde(skip_serializing_if = "Option::is_none")]
+  pub refresh_token: Option<String>,
+  #[serde(default)]
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub device_id: Option<String>,
+  #[serde(default)]
+  #[serde(skip_serializing_if = "Option::is_none")]
   pub push_subscription: Option<StoredPushSubscription>,
 }
 
@@ -55,6 +171,12 @@ pub struct StoredAccount {
   pub access_token: String,
   #[serde(default)]
   #[serde(skip_serializing_if = "Option::is_none")]
+  pub refresh_token: Option<String>,
+  #[serde(default)]
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub device_id: Option<String>,
+  #[serde(default)]
+  #[serde(skip_serializing_if = "Option::is_none")]
   pub push_subscription: Option<StoredPushSubscription>,
 }
 
@@ -74,8 +196,32 @@ struct StoredPasskeyDevice {
   device_type: String,
 }
 
+/// How a seed backup's key is derived from its passphrase.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "name", rename_all = "lowercase")]
+enum BackupKdf {
+  /// PBKDF2-HMAC-SHA256 with `PBKDF2_ITERATIONS`; backups saved before Argon2id have no
+  /// `kdf` field and mean this.
+  #[default]
+  Pbkdf2,
+  /// Parameters are stored so they can be raised later without breaking older backups.
+  Argon2id { memory_kib: u32, iterations: u32, lanes: u32 },
+}
+
+impl BackupKdf {
+  fn current() -> Self {
+    BackupKdf::Argon2id {
+      memory_kib: ARGON2_MEMORY_KIB,
+      iterations: ARGON2_ITERATIONS,
+      lanes: ARGON2_LANES,
+    }
+  }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct EncryptedBackup {
+  #[serde(default)]
+  kdf: BackupKdf,
   salt: String,
   nonce: String,
   ciphertext: String,
@@ -95,6 +241,88 @@ fn make_key(homeserver_url: &str, user_id: &str) -> String {
   format!("{}/{}", norm_hs(homeserver_url), user_id)
 }
 
+/// The user ID half of an account key built by `make_key`.
+fn user_id_for_key(account_key: &str) -> &str {
+  account_key.rsplit_once('/').map(|(_, user_id)| user_id).unwrap_or(account_key)
+}
+
+fn security_keys_path(app: &AppHandle) -> Result<PathBuf, String> {
+  app
+    .path_resolver()
+    .app_data_dir()
+    .map(|dir| dir.join("security_keys.json"))
+    .ok_or_else(|| "Unable to resolve application data directory".to_string())
+}
+
+/// Decrypt a store value: sealed with the security-key master key (`LOCKED` without
+/// `unlocked_key`) or with the OS keychain key. Plaintext from older versions passes through.
+fn unseal_store_value(value: serde_json::Value, unlocked_key: Option<[u8; 32]>) -> Result<serde_json::Value, String> {
+  if security_key::is_sealed_as(keychain::VAULT, &value) {
+    return security_key::open_as(keychain::VAULT, &keychain::master_key()?, &value);
+  }
+  if !security_key::is_sealed(&value) {
+    return Ok(value);
+  }
+  let key = unlocked_key.ok_or_else(|| security_key::LOCKED.to_string())?;
+  security_key::open(&key, &value)
+}
+
+fn open_store_value(app: &AppHandle, value: serde_json::Value) -> Result<serde_json::Value, String> {
+  unseal_store_value(value, app.state::<security_key::SecurityKeyState>().key())
+}
+
+/// Encrypt a store value at rest: with the security key while one is enrolled (refusing to
+/// write while locked), otherwise with the OS keychain key. Without a keychain the value is
+/// stored as is and the UI is told (`security://plaintext-store`) so it can warn the user.
+fn seal_store_value(app: &AppHandle, value: serde_json::Value) -> Result<serde_json::Value, String> {
+  let sealed = match app.state::<security_key::SecurityKeyState>().key() {
+    Some(key) => security_key::seal(&key, &value)?,
+    None if !security_key::load(&security_keys_path(app)?)?.is_empty() => return Err(security_key::LOCKED.to_string()),
+    None => match keychain::master_key() {
+      Ok(key) => security_key::seal_as(keychain::VAULT, &key, &value)?,
+      Err(err) => {
+        tracing::warn!("Credential store left unencrypted: {}", err);
+        keychain::set_plaintext_reason(Some(err.clone()));
+        let _ = app.emit_all("security://plaintext-store", json!({ "reason": err }));
+        return Ok(value);
+      }
+    },
+  };
+  keychain::set_plaintext_reason(None);
+  Ok(sealed)
+}
+
+/// Why credentials were last saved unencrypted, if they were; shown until a sealed save.
+#[tauri::command]
+fn get_plaintext_store_warning() -> Option<String> {
+  keychain::plaintext_reason()
+}
+
+/// Re-save a store written in plaintext by an older version so tokens are encrypted at rest.
+/// Skipped while a security key is enrolled: that path seals on enrollment.
+async fn encrypt_plaintext_store(app: &AppHandle) -> Result<(), String> {
+  if !security_key::load(&security_keys_path(app)?)?.is_empty() {
+    return Ok(());
+  }
+  let store = StoreBuilder::new(app, STORE_FILE)
+      .build()
+      .map_err(|e| e.to_string())?;
+  let plaintext = store
+    .get(ACCOUNTS_KEY)
+    .is_some_and(|v| !security_key::is_sealed_as(keychain::VAULT, &v) && !security_key::is_sealed(&v));
+  if !plaintext {
+    return Ok(());
+  }
+  if let Err(err) = keychain::master_key() {
+    keychain::set_plaintext_reason(Some(err.clone()));
+    return Err(err);
+  }
+  let values = read_sealed_values(app).await?;
+  write_sealed_values(app, values).await?;
+  tracing::info!("Encrypted the credential store with the OS keychain key");
+  Ok(())
+}
+
 async fn read_accounts_map(app: &AppHandle) -> Result<HashMap<String, Credentials>, String> {
   let store = StoreBuilder::new(app, STORE_FILE)
       .build()
@@ -102,7 +330,7 @@ async fn read_accounts_map(app: &AppHandle) -> Result<HashMap<String, Credential
 
   let value = store.get(ACCOUNTS_KEY);
   if let Some(v) = value {
-    serde_json::from_value::<HashMap<String, Credentials>>(v.clone())
+    serde_json::from_value::<HashMap<String, Credentials>>(open_store_value(app, v.clone())?)
       .map_err(|e| format!("Corrupt store: {}", e))
   } else {
     Ok(HashMap::new())
@@ -113,7 +341,7 @@ async fn write_accounts_map(app: &AppHandle, map: &HashMap<String, Credentials>)
   let store = StoreBuilder::new(app, STORE_FILE)
       .build()
       .map_err(|e| e.to_string())?;
-  let v = serde_json::to_value(map).map_err(|e| e.to_string())?;
+  let v = seal_store_value(app, serde_json::to_value(map).map_err(|e| e.to_string())?)?;
   store.set(ACCOUNTS_KEY.to_string(), v);
   store.save().map_err(|e| e.to_string())
 }
@@ -140,10 +368,21 @@ async fn write_passkey_map(app: &AppHandle, map: &HashMap<String, Vec<StoredPass
   store.save().map_err(|e| e.to_string())
 }
 
-fn derive_key(passphrase: &str, salt: &[u8]) -> [u8; 32] {
+fn derive_key(passphrase: &str, salt: &[u8], kdf: BackupKdf) -> Result<[u8; 32], String> {
   let mut key = [0u8; 32];
-  pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, PBKDF2_ITERATIONS, &mut key);
-  key
+  match kdf {
+    BackupKdf::Pbkdf2 => pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, PBKDF2_ITERATIONS, &mut key),
+    BackupKdf::Argon2id { memory_kib, iterations, lanes } => {
+      if memory_kib > ARGON2_MAX_MEMORY_KIB || iterations > ARGON2_MAX_ITERATIONS || lanes > ARGON2_MAX_LANES {
+        return Err("The backup's key derivation parameters are out of range".into());
+      }
+      let params = argon2::Params::new(memory_kib, iterations, lanes, Some(key.len())).map_err(|e| e.to_string())?;
+      Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| e.to_string())?;
+    }
+  }
+  Ok(key)
 }
 
 fn encrypt_payload(passphrase: &str, payload: &str) -> Result<EncryptedBackup, String> {
@@ -152,7 +391,8 @@ fn encrypt_payload(passphrase: &str, payload: &str) -> Result<EncryptedBackup, S
   OsRng.fill_bytes(&mut salt);
   OsRng.fill_bytes(&mut nonce_bytes);
 
-  let key = derive_key(passphrase, &salt);
+  let kdf = BackupKdf::current();
+  let key = derive_key(passphrase, &salt, kdf)?;
   let cipher = Aes256Gcm::new_from_slice(&key).map_err(|e| e.to_string())?;
   let nonce = Nonce::from_slice(&nonce_bytes);
   let ciphertext = cipher
@@ -165,6 +405,7 @@ fn encrypt_payload(passphrase: &str, payload: &str) -> Result<EncryptedBackup, S
     .as_secs();
 
   Ok(EncryptedBackup {
+    kdf,
     salt: general_purpose::STANDARD.encode(salt),
     nonce: general_purpose::STANDARD.encode(nonce_bytes),
     ciphertext: general_purpose::STANDARD.encode(ciphertext),
@@ -183,7 +424,7 @@ fn decrypt_payload(passphrase: &str, backup: &EncryptedBackup) -> Result<String,
     .decode(&backup.ciphertext)
     .map_err(|e| e.to_string())?;
 
-  let key = derive_key(passphrase, &salt);
+  let key = derive_key(passphrase, &salt, backup.kdf)?;
   let cipher = Aes256Gcm::new_from_slice(&key).map_err(|e| e.to_string())?;
   let nonce = Nonce::from_slice(&nonce_bytes);
   let plaintext = cipher
@@ -235,6 +476,11 @@ struct IndexedMessageRecord {
   has_media: bool,
   #[serde(rename = "mediaTypes")]
   media_types: Vec<String>,
+  /// Event this one relates to (`m.relates_to`), e.g. the root of a thread reply.
+  #[serde(rename = "relatesTo", default, skip_serializing_if = "Option::is_none")]
+  relates_to: Option<String>,
+  #[serde(rename = "threadRoot", default, skip_serializing_if = "Option::is_none")]
+  thread_root: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -290,12 +536,35 @@ struct LocalSearchQueryPayload {
   limit: Option<usize>,
   #[serde(rename = "mediaTypes")]
   media_types: Option<Vec<String>>,
+  order: Option<message_search::SearchOrder>,
+  /// `nextCursor` from the previous page; requires `limit`.
+  cursor: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LocalSearchPage {
+  records: Vec<IndexedMessageRecord>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  next_cursor: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct PersistedRoomIndexResponse {
   media: Vec<MediaItemRecord>,
   messages: Vec<IndexedMessageRecord>,
+  /// Messages removed from the index since the requested point, to drop from a cached copy.
+  #[serde(rename = "deletedEventIds", default, skip_serializing_if = "Vec::is_empty")]
+  deleted_event_ids: Vec<String>,
+  #[serde(rename = "deletedMediaIds", default, skip_serializing_if = "Vec::is_empty")]
+  deleted_media_ids: Vec<String>,
+  /// The whole room history rather than a delta: nothing was requested, or the requested
+  /// sequence is from another database. Replaces a cached copy instead of merging into it.
+  #[serde(default)]
+  full: bool,
+  /// Change sequence the response is complete up to; the next incremental load passes it back.
+  #[serde(rename = "changeSeq", default)]
+  change_seq: i64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -305,6 +574,9 @@ struct SmartCollectionSummaryResponse {
   description: String,
   count: usize,
   token: String,
+  /// Rooms the collection points at, when it is about rooms rather than single messages.
+  #[serde(rename = "roomIds", default, skip_serializing_if = "Vec::is_empty")]
+  room_ids: Vec<String>,
 }
 
 fn index_db_path(app: &AppHandle) -> Result<PathBuf, String> {
@@ -316,9 +588,205 @@ fn index_db_path(app: &AppHandle) -> Result<PathBuf, String> {
   Ok(dir.join("search_index.sqlite3"))
 }
 
+fn plugins_dir(app: &AppHandle) -> Result<PathBuf, String> {
+  app
+    .path_resolver()
+    .app_data_dir()
+    .map(|dir| dir.join("plugins"))
+    .ok_or_else(|| "Unable to resolve application data directory".to_string())
+}
+
+fn read_later_dir(app: &AppHandle) -> Result<PathBuf, String> {
+  app
+    .path_resolver()
+    .app_data_dir()
+    .map(|dir| dir.join("read_later"))
+    .ok_or_else(|| "Unable to resolve application data directory".to_string())
+}
+
+fn sounds_dir(app: &AppHandle) -> Result<PathBuf, String> {
+  app
+    .path_resolver()
+    .app_data_dir()
+    .map(|dir| dir.join("sounds"))
+    .ok_or_else(|| "Unable to resolve application data directory".to_string())
+}
+
+/// Downscaled avatars and pictures handed to the notification server by path.
+fn notification_images_dir(app: &AppHandle) -> Result<PathBuf, String> {
+  app
+    .path_resolver()
+    .app_cache_dir()
+    .map(|dir| dir.join("notification-images"))
+    .ok_or_else(|| "Unable to resolve application cache directory".to_string())
+}
+
+fn media_cache_dir(app: &AppHandle) -> Result<PathBuf, String> {
+  let resolver = app.path_resolver();
+  let dir = resolver
+    .app_cache_dir()
+    .ok_or_else(|| "Unable to resolve application cache directory".to_string())?;
+  let dir = dir.join("media");
+  fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+  Ok(dir)
+}
+
+/// The media cache encryption key, created on first use and kept in the credential store.
+async fn media_cache_key(app: &AppHandle) -> Result<[u8; 32], String> {
+  let store = StoreBuilder::new(app, STORE_FILE)
+      .build()
+      .map_err(|e| e.to_string())?;
+  let existing = match store.get(MEDIA_CACHE_KEY) {
+    Some(v) => open_store_value(app, v.clone())?.as_str().map(|s| s.to_string()),
+    None => None,
+  };
+  if let Some(existing) = existing {
+    let bytes = general_purpose::STANDARD.decode(existing).map_err(|e| e.to_string())?;
+    if let Ok(key) = <[u8; 32]>::try_from(bytes.as_slice()) {
+      return Ok(key);
+    }
+  }
+  let mut key = [0u8; 32];
+  OsRng.fill_bytes(&mut key);
+  let v = seal_store_value(app, json!(general_purpose::STANDARD.encode(key)))?;
+  store.set(MEDIA_CACHE_KEY.to_string(), v);
+  store.save().map_err(|e| e.to_string())?;
+  Ok(key)
+}
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool, rusqlite::Error> {
+  let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+  let names = stmt.query_map([], |row| row.get::<_, String>(1))?;
+  Ok(names.flatten().any(|name| name == column))
+}
+
+/// Indexes created before rows were keyed by account are moved aside and copied into the new
+/// tables under the empty account key; the full-text index is rebuilt from them.
+fn migrate_index_accounts(conn: &Connection) -> Result<(), rusqlite::Error> {
+  let mut legacy = Vec::new();
+  for table in ["message_index", "media_index"] {
+    let exists: bool = conn.query_row(
+      "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+      params![table],
+      |row| row.get(0),
+    )?;
+    if exists && !has_column(conn, table, "account_key")? {
+      legacy.push(table);
+    }
+  }
+  if legacy.is_empty() {
+    return Ok(());
+  }
+  conn.execute_batch(
+    "DROP TRIGGER IF EXISTS message_fts_insert;
+     DROP TRIGGER IF EXISTS message_fts_delete;
+     DROP TRIGGER IF EXISTS message_fts_update;
+     DROP TRIGGER IF EXISTS message_fts_update_content;
+     DROP TABLE IF EXISTS message_fts;",
+  )?;
+  for table in &legacy {
+    conn.execute_batch(&format!("ALTER TABLE {0} RENAME TO {0}_legacy;", table))?;
+  }
+  // Index names are global; free them for the new tables.
+  conn.execute_batch(
+    "DROP INDEX IF EXISTS idx_message_room;
+     DROP INDEX IF EXISTS idx_message_sender;
+     DROP INDEX IF EXISTS idx_media_room;",
+  )
+}
+
+fn copy_legacy_index(conn: &Connection) -> Result<(), rusqlite::Error> {
+  for table in ["message_index", "media_index"] {
+    let legacy = format!("{}_legacy", table);
+    let exists: bool = conn.query_row(
+      "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+      params![legacy],
+      |row| row.get(0),
+    )?;
+    if exists {
+      // Named columns: the new tables have grown columns the legacy ones lack.
+      let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", legacy))?;
+      let legacy_columns = std::iter::once("account_key".to_string())
+        .chain(stmt.query_map([], |row| row.get::<_, String>(1))?.flatten())
+        .collect::<Vec<_>>()
+        .join(", ");
+      conn.execute_batch(&format!(
+        "INSERT OR IGNORE INTO {0} ({2}) SELECT '', * FROM {1}; DROP TABLE {1};",
+        table, legacy, legacy_columns
+      ))?;
+    }
+  }
+  Ok(())
+}
+
+/// Every insert, content update and delete in the message and media index takes the next
+/// value of one counter, stored on the row or its tombstone. Incremental loads ask for
+/// everything past the sequence they last saw, which unlike timestamps never goes back.
+fn init_index_change_seq(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS index_change_seq (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        seq INTEGER NOT NULL
+      );
+      INSERT OR IGNORE INTO index_change_seq (id, seq) VALUES (0, 0);
+      CREATE INDEX IF NOT EXISTS idx_message_change ON message_index(account_key, room_id, change_seq);
+      CREATE INDEX IF NOT EXISTS idx_media_change ON media_index(account_key, room_id, change_seq);
+      CREATE INDEX IF NOT EXISTS idx_tombstones_change ON index_tombstones(account_key, room_id, change_seq);
+      DROP TRIGGER IF EXISTS message_index_tombstone;
+      DROP TRIGGER IF EXISTS media_index_tombstone;
+      CREATE TRIGGER IF NOT EXISTS message_index_inserted AFTER INSERT ON message_index BEGIN
+        UPDATE index_change_seq SET seq = seq + 1;
+        UPDATE message_index SET change_seq = (SELECT seq FROM index_change_seq) WHERE rowid = new.rowid;
+      END;
+      CREATE TRIGGER IF NOT EXISTS message_index_updated AFTER UPDATE OF
+          sender, timestamp, body, search_tokens, tokens_json, tags_json, reactions_json, has_media, media_types_json,
+          relates_to, thread_root
+        ON message_index
+        WHEN old.sender IS NOT new.sender OR old.timestamp IS NOT new.timestamp OR old.body IS NOT new.body
+          OR old.tags_json IS NOT new.tags_json OR old.reactions_json IS NOT new.reactions_json
+          OR old.has_media IS NOT new.has_media OR old.media_types_json IS NOT new.media_types_json
+          OR old.relates_to IS NOT new.relates_to OR old.thread_root IS NOT new.thread_root
+      BEGIN
+        UPDATE index_change_seq SET seq = seq + 1;
+        UPDATE message_index SET change_seq = (SELECT seq FROM index_change_seq) WHERE rowid = new.rowid;
+      END;
+      CREATE TRIGGER IF NOT EXISTS message_index_deleted AFTER DELETE ON message_index BEGIN
+        UPDATE index_change_seq SET seq = seq + 1;
+        INSERT OR REPLACE INTO index_tombstones (account_key, room_id, kind, record_id, deleted_at, change_seq)
+          VALUES (old.account_key, old.room_id, 'message', old.event_id,
+            CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER), (SELECT seq FROM index_change_seq));
+      END;
+      CREATE TRIGGER IF NOT EXISTS media_index_inserted AFTER INSERT ON media_index BEGIN
+        UPDATE index_change_seq SET seq = seq + 1;
+        UPDATE media_index SET change_seq = (SELECT seq FROM index_change_seq) WHERE rowid = new.rowid;
+      END;
+      CREATE TRIGGER IF NOT EXISTS media_index_updated AFTER UPDATE OF
+          event_id, room_id, media_type, mxc_url, thumbnail_mxc, file_name, size, mimetype, sender, timestamp, body, url
+        ON media_index
+        WHEN old.event_id IS NOT new.event_id OR old.room_id IS NOT new.room_id OR old.media_type IS NOT new.media_type
+          OR old.mxc_url IS NOT new.mxc_url OR old.thumbnail_mxc IS NOT new.thumbnail_mxc
+          OR old.file_name IS NOT new.file_name OR old.size IS NOT new.size OR old.mimetype IS NOT new.mimetype
+          OR old.sender IS NOT new.sender OR old.timestamp IS NOT new.timestamp OR old.body IS NOT new.body
+          OR old.url IS NOT new.url
+      BEGIN
+        UPDATE index_change_seq SET seq = seq + 1;
+        UPDATE media_index SET change_seq = (SELECT seq FROM index_change_seq) WHERE rowid = new.rowid;
+      END;
+      CREATE TRIGGER IF NOT EXISTS media_index_deleted AFTER DELETE ON media_index BEGIN
+        UPDATE index_change_seq SET seq = seq + 1;
+        INSERT OR REPLACE INTO index_tombstones (account_key, room_id, kind, record_id, deleted_at, change_seq)
+          VALUES (old.account_key, old.room_id, 'media', old.id,
+            CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER), (SELECT seq FROM index_change_seq));
+      END;
+    ",
+  )
+}
+
 fn init_index_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  migrate_index_accounts(conn)?;
   conn.execute_batch(
     "CREATE TABLE IF NOT EXISTS message_index (
+        account_key TEXT NOT NULL DEFAULT '',
         room_id TEXT NOT NULL,
         event_id TEXT NOT NULL,
         sender TEXT NOT NULL,
@@ -330,12 +798,16 @@ fn init_index_db(conn: &Connection) -> Result<(), rusqlite::Error> {
         reactions_json TEXT,
         has_media INTEGER NOT NULL,
         media_types_json TEXT,
-        PRIMARY KEY (room_id, event_id)
+        relates_to TEXT,
+        thread_root TEXT,
+        change_seq INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (account_key, room_id, event_id)
       );
       CREATE INDEX IF NOT EXISTS idx_message_room ON message_index(room_id);
       CREATE INDEX IF NOT EXISTS idx_message_sender ON message_index(sender);
       CREATE TABLE IF NOT EXISTS media_index (
-        id TEXT PRIMARY KEY,
+        account_key TEXT NOT NULL DEFAULT '',
+        id TEXT NOT NULL,
         event_id TEXT NOT NULL,
         room_id TEXT NOT NULL,
         media_type TEXT NOT NULL,
@@ -347,22 +819,102 @@ fn init_index_db(conn: &Connection) -> Result<(), rusqlite::Error> {
         sender TEXT,
         timestamp INTEGER,
         body TEXT,
-        url TEXT
+        url TEXT,
+        change_seq INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (account_key, id)
       );
       CREATE INDEX IF NOT EXISTS idx_media_room ON media_index(room_id);
+      CREATE TABLE IF NOT EXISTS index_tombstones (
+        account_key TEXT NOT NULL,
+        room_id TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        record_id TEXT NOT NULL,
+        deleted_at INTEGER NOT NULL,
+        change_seq INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (account_key, kind, record_id)
+      );
+      CREATE INDEX IF NOT EXISTS idx_tombstones_room ON index_tombstones(account_key, room_id, deleted_at);
+      CREATE TRIGGER IF NOT EXISTS message_index_revive AFTER INSERT ON message_index BEGIN
+        DELETE FROM index_tombstones WHERE account_key = new.account_key AND kind = 'message' AND record_id = new.event_id;
+      END;
+      CREATE TRIGGER IF NOT EXISTS media_index_revive AFTER INSERT ON media_index BEGIN
+        DELETE FROM index_tombstones WHERE account_key = new.account_key AND kind = 'media' AND record_id = new.id;
+      END;
     ",
-  )
+  )?;
+  for column in ["relates_to", "thread_root"] {
+    if !has_column(conn, "message_index", column)? {
+      conn.execute_batch(&format!("ALTER TABLE message_index ADD COLUMN {} TEXT;", column))?;
+    }
+  }
+  for table in ["message_index", "media_index", "index_tombstones"] {
+    if !has_column(conn, table, "change_seq")? {
+      conn.execute_batch(&format!("ALTER TABLE {} ADD COLUMN change_seq INTEGER NOT NULL DEFAULT 0;", table))?;
+    }
+  }
+  init_index_change_seq(conn)?;
+  conn.execute_batch(
+    "CREATE INDEX IF NOT EXISTS idx_message_thread ON message_index(account_key, room_id, thread_root);",
+  )?;
+  copy_legacy_index(conn)?;
+  message_search::init_message_search_db(conn)?;
+  event_cache::init_event_cache_db(conn)?;
+  account_data::init_account_data_db(conn)?;
+  auto_download::init_auto_download_db(conn)?;
+  call_log::init_call_log_db(conn)?;
+  capabilities::init_capabilities_db(conn)?;
+  decryption_retry::init_decryption_retry_db(conn)?;
+  directory::init_directory_db(conn)?;
+  ignore_list::init_ignore_db(conn)?;
+  image_packs::init_image_packs_db(conn)?;
+  gifs::init_gifs_db(conn)?;
+  url_preview::init_url_preview_db(conn)?;
+  document_preview::init_document_preview_db(conn)?;
+  malware_scan::init_malware_scan_db(conn)?;
+  audio_waveform::init_audio_waveform_db(conn)?;
+  background_sync::init_background_sync_db(conn)?;
+  invites::init_invites_db(conn)?;
+  keyword_alerts::init_keyword_alerts_db(conn)?;
+  key_requests::init_key_requests_db(conn)?;
+  media_cache::init_media_cache_db(conn)?;
+  media_download::init_downloads_db(conn)?;
+  notification_rules::init_notification_rules_db(conn)?;
+  notification_history::init_notification_history_db(conn)?;
+  rich_notifications::init_rich_notifications_db(conn)?;
+  email_digest::init_email_digest_db(conn)?;
+  tray::init_tray_db(conn)?;
+  hotkeys::init_hotkeys_db(conn)?;
+  popout_windows::init_popout_windows_db(conn)?;
+  idle::init_idle_db(conn)?;
+  notification_sounds::init_notification_sounds_db(conn)?;
+  quiet_hours::init_quiet_hours_db(conn)?;
+  snooze::init_snooze_db(conn)?;
+  media_upload::init_upload_settings_db(conn)?;
+  profiles::init_profiles_db(conn)?;
+  reports::init_reports_db(conn)?;
+  wasm_plugins::init_wasm_plugins_db(conn)?;
+  feeds::init_feeds_db(conn)?;
+  scheduled_messages::init_scheduled_messages_db(conn)?;
+  drafts::init_drafts_db(conn)?;
+  reminders::init_reminders_db(conn)?;
+  read_later::init_read_later_db(conn)?;
+  translation::init_translation_db(conn)?;
+  retention::init_retention_db(conn)?;
+  sync_engine::init_sync_engine_db(conn)?;
+  room_upgrade::init_room_upgrade_db(conn)
 }
 
 fn to_json_string(values: &Vec<String>) -> Result<String, String> {
   serde_json::to_string(values).map_err(|e| e.to_string())
 }
 
-fn insert_index_records(conn: &Connection, payload: &IndexUpsertPayload) -> Result<(), String> {
+/// Rows are keyed by account so several signed-in users never see each other's messages;
+/// the empty key holds rows indexed before that (and by callers that pass no account).
+fn insert_index_records(conn: &Connection, account_key: &str, payload: &IndexUpsertPayload) -> Result<(), String> {
   if payload.messages.is_empty() && payload.media_items.is_empty() {
     return Ok(());
   }
-  let tx = conn.transaction().map_err(|e| e.to_string())?;
+  let tx = conn.unchecked_transaction().map_err(|e| e.to_string())?;
   for message in &payload.messages {
     let tokens_json = to_json_string(&message.tokens)?;
     let tags_json = to_json_string(&message.tags)?;
@@ -371,9 +923,10 @@ fn insert_index_records(conn: &Connection, payload: &IndexUpsertPayload) -> Resu
     let search_tokens = format!(" {} ", message.tokens.join(" "));
     tx.execute(
       "INSERT INTO message_index (
-          room_id, event_id, sender, timestamp, body, search_tokens, tokens_json, tags_json, reactions_json, has_media, media_types_json
-        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
-        ON CONFLICT(room_id, event_id) DO UPDATE SET
+          room_id, event_id, sender, timestamp, body, search_tokens, tokens_json, tags_json, reactions_json, has_media, media_types_json, account_key,
+          relates_to, thread_root
+        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)
+        ON CONFLICT(account_key, room_id, event_id) DO UPDATE SET
           sender = excluded.sender,
           timestamp = excluded.timestamp,
           body = excluded.body,
@@ -382,7 +935,9 @@ fn insert_index_records(conn: &Connection, payload: &IndexUpsertPayload) -> Resu
           tags_json = excluded.tags_json,
           reactions_json = excluded.reactions_json,
           has_media = excluded.has_media,
-          media_types_json = excluded.media_types_json",
+          media_types_json = excluded.media_types_json,
+          relates_to = COALESCE(excluded.relates_to, relates_to),
+          thread_root = COALESCE(excluded.thread_root, thread_root)",
       params![
         message.room_id,
         message.event_id,
@@ -395,6 +950,9 @@ fn insert_index_records(conn: &Connection, payload: &IndexUpsertPayload) -> Resu
         reactions_json,
         if message.has_media { 1 } else { 0 },
         media_types_json,
+        account_key,
+        message.relates_to,
+        message.thread_root,
       ],
     )
     .map_err(|e| e.to_string())?;
@@ -402,9 +960,9 @@ fn insert_index_records(conn: &Connection, payload: &IndexUpsertPayload) -> Resu
   for item in &payload.media_items {
     tx.execute(
       "INSERT INTO media_index (
-          id, event_id, room_id, media_type, mxc_url, thumbnail_mxc, file_name, size, mimetype, sender, timestamp, body, url
-        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)
-        ON CONFLICT(id) DO UPDATE SET
+          id, event_id, room_id, media_type, mxc_url, thumbnail_mxc, file_name, size, mimetype, sender, timestamp, body, url, account_key
+        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)
+        ON CONFLICT(account_key, id) DO UPDATE SET
           event_id = excluded.event_id,
           room_id = excluded.room_id,
           media_type = excluded.media_type,
@@ -431,6 +989,7 @@ fn insert_index_records(conn: &Connection, payload: &IndexUpsertPayload) -> Resu
         item.timestamp,
         item.body,
         item.url,
+        account_key,
       ],
     )
     .map_err(|e| e.to_string())?;
@@ -438,22 +997,158 @@ fn insert_index_records(conn: &Connection, payload: &IndexUpsertPayload) -> Resu
   tx.commit().map_err(|e| e.to_string())
 }
 
+/// Search index rows for plaintext messages synced by the backend. Encrypted events are left
+/// to the webview, which indexes them once decrypted.
+fn index_payload_from_timeline(room_id: &str, events: &[serde_json::Value]) -> IndexUpsertPayload {
+  let mut payload = IndexUpsertPayload {
+    room_id: room_id.to_string(),
+    messages: Vec::new(),
+    media_items: Vec::new(),
+  };
+  for event in events {
+    let Some(cached) = event_cache::CachedEvent::from_raw(room_id, event) else {
+      continue;
+    };
+    if cached.event_type != "m.room.message" || cached.rel_type.as_deref() == Some("m.replace") {
+      continue;
+    }
+    let content = &cached.content;
+    let body = content.get("body").and_then(|v| v.as_str()).map(|s| s.to_string());
+    let media_type = match content.get("msgtype").and_then(|v| v.as_str()) {
+      Some("m.image") => Some("image"),
+      Some("m.video") => Some("video"),
+      Some("m.file") | Some("m.audio") => Some("file"),
+      _ => None,
+    };
+    if let Some(media_type) = media_type {
+      let info = content.get("info");
+      payload.media_items.push(MediaItemRecord {
+        id: format!("{}:0", cached.event_id),
+        event_id: cached.event_id.clone(),
+        room_id: room_id.to_string(),
+        media_type: media_type.to_string(),
+        mxc_url: content
+          .pointer("/file/url")
+          .or_else(|| content.get("url"))
+          .and_then(|v| v.as_str())
+          .map(|s| s.to_string()),
+        thumbnail_mxc: info
+          .and_then(|info| info.pointer("/thumbnail_file/url").or_else(|| info.get("thumbnail_url")))
+          .and_then(|v| v.as_str())
+          .map(|s| s.to_string()),
+        file_name: body.clone(),
+        size: info.and_then(|info| info.get("size")).and_then(|v| v.as_i64()),
+        mimetype: info.and_then(|info| info.get("mimetype")).and_then(|v| v.as_str()).map(|s| s.to_string()),
+        sender: cached.sender.clone(),
+        timestamp: cached.origin_server_ts,
+        body: body.clone(),
+        url: None,
+      });
+    }
+    payload.messages.push(IndexedMessageRecord {
+      event_id: cached.event_id.clone(),
+      room_id: room_id.to_string(),
+      sender: cached.sender.clone(),
+      timestamp: cached.origin_server_ts,
+      tokens: chat_import::tokenize(body.as_deref().unwrap_or_default(), &cached.sender),
+      body,
+      tags: Vec::new(),
+      reactions: Vec::new(),
+      has_media: media_type.is_some(),
+      media_types: media_type.map(|kind| vec![kind.to_string()]).unwrap_or_default(),
+      relates_to: cached.relates_to.clone(),
+      thread_root: cached.thread_root.clone(),
+    });
+  }
+  payload
+}
+
+/// Drop rows the webview already indexed; its copies carry tags and reactions.
+fn retain_unindexed(conn: &Connection, account_key: &str, payload: &mut IndexUpsertPayload) -> Result<(), String> {
+  let mut stmt = conn
+    .prepare("SELECT 1 FROM message_index WHERE account_key = ?1 AND room_id = ?2 AND event_id = ?3")
+    .map_err(|e| e.to_string())?;
+  let mut indexed = std::collections::HashSet::new();
+  for message in &payload.messages {
+    if stmt.exists(params![account_key, payload.room_id, message.event_id]).map_err(|e| e.to_string())? {
+      indexed.insert(message.event_id.clone());
+    }
+  }
+  payload.messages.retain(|message| !indexed.contains(&message.event_id));
+  payload.media_items.retain(|item| !indexed.contains(&item.event_id));
+  Ok(())
+}
+
 fn parse_vec(json_value: &str) -> Vec<String> {
   serde_json::from_str::<Vec<String>>(json_value).unwrap_or_default()
 }
 
+const INDEX_RECORD_COLUMNS: &str =
+  "room_id, event_id, sender, timestamp, body, tokens_json, tags_json, reactions_json, has_media, media_types_json, relates_to, thread_root";
+
+fn index_record_from_row(row: &rusqlite::Row) -> rusqlite::Result<IndexedMessageRecord> {
+  let tokens_json: String = row.get(5)?;
+  let tags_json: String = row.get(6)?;
+  let reactions_json: String = row.get(7)?;
+  let media_types_json: String = row.get(9)?;
+  Ok(IndexedMessageRecord {
+    event_id: row.get(1)?,
+    room_id: row.get(0)?,
+    sender: row.get(2)?,
+    timestamp: row.get(3)?,
+    body: row.get(4)?,
+    tokens: parse_vec(&tokens_json),
+    tags: parse_vec(&tags_json),
+    reactions: parse_vec(&reactions_json),
+    has_media: row.get::<_, i64>(8)? != 0,
+    media_types: parse_vec(&media_types_json),
+    relates_to: row.get(10)?,
+    thread_root: row.get(11)?,
+  })
+}
+
 fn query_index_records(
   conn: &Connection,
+  account_key: &str,
   query: &LocalSearchQueryPayload,
   mention_target: Option<&str>,
 ) -> Result<Vec<IndexedMessageRecord>, String> {
-  let mut sql = String::from(
-    "SELECT room_id, event_id, sender, timestamp, body, tokens_json, tags_json, reactions_json, has_media, media_types_json FROM message_index WHERE 1=1",
-  );
+  let term = query
+    .term
+    .as_deref()
+    .map(str::trim)
+    .filter(|term| !term.is_empty());
+  let fts_match = term.and_then(message_search::match_expression);
   let mut params: Vec<Value> = Vec::new();
+  let mut sql = match &fts_match {
+    Some(expression) => {
+      params.push(Value::from(expression.clone()));
+      format!(
+        "SELECT {}
+         FROM message_index
+         JOIN (SELECT rowid AS fts_rowid, {} AS relevance FROM message_fts WHERE message_fts MATCH ?) fts
+           ON fts.fts_rowid = message_index.rowid
+         WHERE {}",
+        INDEX_RECORD_COLUMNS,
+        message_search::RELEVANCE,
+        ignore_list::not_ignored("message_index")
+      )
+    }
+    None => format!(
+      "SELECT {} FROM message_index WHERE {}",
+      INDEX_RECORD_COLUMNS,
+      ignore_list::not_ignored("message_index")
+    ),
+  };
+  sql.push_str(" AND account_key = ?");
+  params.push(Value::from(account_key.to_string()));
   if let Some(room_id) = &query.room_id {
-    sql.push_str(" AND room_id = ?");
-    params.push(Value::from(room_id.clone()));
+    let rooms = room_upgrade::linked_room_ids(conn, room_id)?;
+    let placeholders: Vec<String> = rooms.iter().map(|_| "?".to_string()).collect();
+    sql.push_str(&format!(" AND room_id IN ({})", placeholders.join(",")));
+    for room in rooms {
+      params.push(Value::from(room));
+    }
   }
   if let Some(senders) = &query.senders {
     if !senders.is_empty() {
@@ -489,44 +1184,40 @@ fn query_index_records(
     sql.push_str(" AND search_tokens LIKE ?");
     params.push(Value::from(like));
   }
-  if let Some(term) = &query.term {
-    let trimmed = term.trim();
-    if !trimmed.is_empty() {
-      let lower = trimmed.to_lowercase();
-      let like = format!("%{}%", lower);
-      sql.push_str(" AND (LOWER(IFNULL(body,'')) LIKE ? OR LOWER(sender) LIKE ? OR LOWER(tags_json) LIKE ? OR LOWER(reactions_json) LIKE ? OR search_tokens LIKE ?)");
-      params.push(Value::from(like.clone()));
-      params.push(Value::from(like.clone()));
-      params.push(Value::from(like.clone()));
-      params.push(Value::from(like.clone()));
-      params.push(Value::from(format!("% {} %", lower)));
-    }
+  if let (Some(term), None) = (term, &fts_match) {
+    // Nothing the full-text index can match, e.g. a reaction emoji.
+    let like = format!("%{}%", term.to_lowercase());
+    sql.push_str(" AND (LOWER(IFNULL(body,'')) LIKE ? OR LOWER(reactions_json) LIKE ?)");
+    params.push(Value::from(like.clone()));
+    params.push(Value::from(like));
+  }
+  let cursor = query
+    .cursor
+    .as_deref()
+    .map(message_search::SearchCursor::decode)
+    .transpose()?;
+  if let Some(message_search::SearchCursor::After { timestamp, event_id }) = &cursor {
+    sql.push_str(" AND (timestamp < ? OR (timestamp = ? AND event_id < ?))");
+    params.push(Value::from(*timestamp));
+    params.push(Value::from(*timestamp));
+    params.push(Value::from(event_id.clone()));
+  }
+  if fts_match.is_some() && query.order == Some(message_search::SearchOrder::Relevance) {
+    sql.push_str(" ORDER BY fts.relevance, timestamp DESC, event_id DESC");
+  } else {
+    sql.push_str(" ORDER BY timestamp DESC, event_id DESC");
   }
-  sql.push_str(" ORDER BY timestamp DESC");
   if let Some(limit) = query.limit {
     sql.push_str(" LIMIT ?");
     params.push(Value::from(limit as i64));
+    if let Some(message_search::SearchCursor::Offset(offset)) = cursor {
+      sql.push_str(" OFFSET ?");
+      params.push(Value::from(offset as i64));
+    }
   }
   let mut stmt = conn.prepare(&sql).map_err(|e| e.to_string())?;
   let rows = stmt
-    .query_map(params_from_iter(params.iter()), |row| {
-      let tokens_json: String = row.get(5)?;
-      let tags_json: String = row.get(6)?;
-      let reactions_json: String = row.get(7)?;
-      let media_types_json: String = row.get(9)?;
-      Ok(IndexedMessageRecord {
-        event_id: row.get(1)?,
-        room_id: row.get(0)?,
-        sender: row.get(2)?,
-        timestamp: row.get(3)?,
-        body: row.get(4)?,
-        tokens: parse_vec(&tokens_json),
-        tags: parse_vec(&tags_json),
-        reactions: parse_vec(&reactions_json),
-        has_media: row.get::<_, i64>(8)? != 0,
-        media_types: parse_vec(&media_types_json),
-      })
-    })
+    .query_map(params_from_iter(params.iter()), index_record_from_row)
     .map_err(|e| e.to_string())?;
   let mut out: Vec<IndexedMessageRecord> = Vec::new();
   for row in rows {
@@ -537,32 +1228,84 @@ fn query_index_records(
   Ok(out)
 }
 
-fn load_room_index_from_conn(conn: &Connection, room_id: &str) -> Result<PersistedRoomIndexResponse, String> {
+/// One page of `query_index_records`: fetches a record past `limit` to tell whether
+/// another page follows.
+fn query_index_page(
+  conn: &Connection,
+  account_key: &str,
+  query: &LocalSearchQueryPayload,
+  mention_target: Option<&str>,
+) -> Result<LocalSearchPage, String> {
+  let Some(limit) = query.limit.filter(|limit| *limit > 0) else {
+    return Ok(LocalSearchPage {
+      records: query_index_records(conn, account_key, query, mention_target)?,
+      next_cursor: None,
+    });
+  };
+  let probe = LocalSearchQueryPayload {
+    limit: Some(limit + 1),
+    ..query.clone()
+  };
+  let mut records = query_index_records(conn, account_key, &probe, mention_target)?;
+  if records.len() <= limit {
+    return Ok(LocalSearchPage { records, next_cursor: None });
+  }
+  records.truncate(limit);
+  let ranked = query.order == Some(message_search::SearchOrder::Relevance)
+    && query.term.as_deref().and_then(message_search::match_expression).is_some();
+  let next = if ranked {
+    let offset = match query.cursor.as_deref().map(message_search::SearchCursor::decode).transpose()? {
+      Some(message_search::SearchCursor::Offset(offset)) => offset,
+      _ => 0,
+    };
+    message_search::SearchCursor::Offset(offset + limit)
+  } else {
+    let last = &records[records.len() - 1];
+    message_search::SearchCursor::After {
+      timestamp: last.timestamp,
+      event_id: last.event_id.clone(),
+    }
+  };
+  Ok(LocalSearchPage {
+    records,
+    next_cursor: Some(next.encode()),
+  })
+}
+
+/// The room's indexed messages and media, newest first. With `since_seq`, only records
+/// added or changed after that change sequence plus the IDs of records deleted after it. A
+/// sequence ahead of the database (it was recreated) falls back to the full history.
+fn load_room_index_from_conn(
+  conn: &Connection,
+  account_key: &str,
+  room_id: &str,
+  since_seq: Option<i64>,
+) -> Result<PersistedRoomIndexResponse, String> {
+  // Read first: anything changing while the rows are read is sent again next time, not lost.
+  let change_seq: i64 = conn
+    .query_row("SELECT seq FROM index_change_seq WHERE id = 0", [], |row| row.get(0))
+    .map_err(|e| e.to_string())?;
+  let since = since_seq.filter(|since| *since <= change_seq);
+  let rooms = room_upgrade::linked_room_ids(conn, room_id)?;
+  let placeholders = rooms.iter().map(|_| "?").collect::<Vec<_>>().join(",");
+  let mut params: Vec<Value> = rooms.iter().map(|room| Value::from(room.clone())).collect();
+  params.push(Value::from(account_key.to_string()));
+  let mut newer = String::new();
+  if let Some(since) = since {
+    newer.push_str(" AND change_seq > ?");
+    params.push(Value::from(since));
+  }
   let mut stmt = conn
-    .prepare(
-      "SELECT room_id, event_id, sender, timestamp, body, tokens_json, tags_json, reactions_json, has_media, media_types_json
-       FROM message_index WHERE room_id = ? ORDER BY timestamp DESC",
-    )
+    .prepare(&format!(
+      "SELECT {} FROM message_index WHERE room_id IN ({}) AND account_key = ? AND {}{} ORDER BY timestamp DESC, event_id DESC",
+      INDEX_RECORD_COLUMNS,
+      placeholders,
+      ignore_list::not_ignored("message_index"),
+      newer
+    ))
     .map_err(|e| e.to_string())?;
   let rows = stmt
-    .query_map([room_id], |row| {
-      let tokens_json: String = row.get(5)?;
-      let tags_json: String = row.get(6)?;
-      let reactions_json: String = row.get(7)?;
-      let media_types_json: String = row.get(9)?;
-      Ok(IndexedMessageRecord {
-        event_id: row.get(1)?,
-        room_id: row.get(0)?,
-        sender: row.get(2)?,
-        timestamp: row.get(3)?,
-        body: row.get(4)?,
-        tokens: parse_vec(&tokens_json),
-        tags: parse_vec(&tags_json),
-        reactions: parse_vec(&reactions_json),
-        has_media: row.get::<_, i64>(8)? != 0,
-        media_types: parse_vec(&media_types_json),
-      })
-    })
+    .query_map(params_from_iter(params.iter()), index_record_from_row)
     .map_err(|e| e.to_string())?;
   let mut messages = Vec::new();
   for row in rows {
@@ -570,13 +1313,16 @@ fn load_room_index_from_conn(conn: &Connection, room_id: &str) -> Result<Persist
   }
 
   let mut media_stmt = conn
-    .prepare(
+    .prepare(&format!(
       "SELECT id, event_id, room_id, media_type, mxc_url, thumbnail_mxc, file_name, size, mimetype, sender, timestamp, body, url
-       FROM media_index WHERE room_id = ? ORDER BY timestamp DESC",
-    )
+       FROM media_index WHERE room_id IN ({}) AND account_key = ? AND {}{} ORDER BY timestamp DESC",
+      placeholders,
+      ignore_list::not_ignored("media_index"),
+      newer
+    ))
     .map_err(|e| e.to_string())?;
   let media_rows = media_stmt
-    .query_map([room_id], |row| {
+    .query_map(params_from_iter(params.iter()), |row| {
       Ok(MediaItemRecord {
         id: row.get(0)?,
         event_id: row.get(1)?,
@@ -598,7 +1344,38 @@ fn load_room_index_from_conn(conn: &Connection, room_id: &str) -> Result<Persist
   for row in media_rows {
     if let Ok(item) = row { media.push(item); }
   }
-  Ok(PersistedRoomIndexResponse { media, messages })
+
+  let mut deleted_event_ids = Vec::new();
+  let mut deleted_media_ids = Vec::new();
+  if let Some(since) = since {
+    let mut params: Vec<Value> = rooms.iter().map(|room| Value::from(room.clone())).collect();
+    params.push(Value::from(account_key.to_string()));
+    params.push(Value::from(since));
+    let mut tombstones = conn
+      .prepare(&format!(
+        "SELECT kind, record_id FROM index_tombstones WHERE room_id IN ({}) AND account_key = ? AND change_seq > ?",
+        placeholders
+      ))
+      .map_err(|e| e.to_string())?;
+    let rows = tombstones
+      .query_map(params_from_iter(params.iter()), |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))
+      .map_err(|e| e.to_string())?;
+    for (kind, record_id) in rows.flatten() {
+      if kind == "media" {
+        deleted_media_ids.push(record_id);
+      } else {
+        deleted_event_ids.push(record_id);
+      }
+    }
+  }
+  Ok(PersistedRoomIndexResponse {
+    media,
+    messages,
+    deleted_event_ids,
+    deleted_media_ids,
+    full: since.is_none(),
+    change_seq,
+  })
 }
 
 fn normalized_localpart(user_id: &str) -> String {
@@ -609,12 +1386,16 @@ fn normalized_localpart(user_id: &str) -> String {
 
 fn compute_smart_collections(
   conn: &Connection,
+  account_key: &str,
   user_id: &str,
 ) -> Result<Vec<SmartCollectionSummaryResponse>, String> {
   let important_count: usize = conn
     .query_row(
-      "SELECT COUNT(*) FROM message_index WHERE tags_json LIKE '%\"important\"%' OR reactions_json LIKE '%\"⭐\"%' OR reactions_json LIKE '%\"🔥\"%' OR reactions_json LIKE '%\"❗\"%'",
-      [],
+      &format!(
+        "SELECT COUNT(*) FROM message_index WHERE (tags_json LIKE '%\"important\"%' OR reactions_json LIKE '%\"⭐\"%' OR reactions_json LIKE '%\"🔥\"%' OR reactions_json LIKE '%\"❗\"%') AND account_key = ?1 AND {}",
+        ignore_list::not_ignored("message_index")
+      ),
+      params![account_key],
       |row| row.get(0),
     )
     .unwrap_or(0);
@@ -626,6 +1407,7 @@ fn compute_smart_collections(
       description: "Сообщения с тегом important или популярными реакциями".to_string(),
       count: important_count,
       token: "smart:important".to_string(),
+      room_ids: Vec::new(),
     });
   }
   let local = normalized_localpart(user_id);
@@ -634,8 +1416,11 @@ fn compute_smart_collections(
     let mention_pattern = format!("%@{}%", local);
     let mentions_count: usize = conn
       .query_row(
-        "SELECT COUNT(*) FROM message_index WHERE search_tokens LIKE ?1 OR LOWER(IFNULL(body,'')) LIKE ?2",
-        params![token_pattern, mention_pattern],
+        &format!(
+          "SELECT COUNT(*) FROM message_index WHERE (search_tokens LIKE ?1 OR LOWER(IFNULL(body,'')) LIKE ?2) AND account_key = ?3 AND {}",
+          ignore_list::not_ignored("message_index")
+        ),
+        params![token_pattern, mention_pattern, account_key],
         |row| row.get(0),
       )
       .unwrap_or(0);
@@ -646,19 +1431,107 @@ fn compute_smart_collections(
         description: "Сообщения с упоминанием вашего аккаунта".to_string(),
         count: mentions_count,
         token: "smart:mentions".to_string(),
+        room_ids: Vec::new(),
+      });
+    }
+    let threads = threads::unanswered_threads(conn, account_key, user_id, &local).unwrap_or_default();
+    if !threads.is_empty() {
+      let mut room_ids: Vec<String> = Vec::new();
+      for thread in &threads {
+        if !room_ids.contains(&thread.room_id) {
+          room_ids.push(thread.room_id.clone());
+        }
+      }
+      out.push(SmartCollectionSummaryResponse {
+        id: "threads".to_string(),
+        label: "Ответы в тредах".to_string(),
+        description: "Треды, где вам ответили, а вы ещё нет".to_string(),
+        count: threads.len(),
+        token: threads::COLLECTION_TOKEN.to_string(),
+        room_ids,
       });
     }
   }
+  let read_later_count = read_later::unread_count(conn, Some(account_key).filter(|key| !key.is_empty())).unwrap_or(0);
+  if read_later_count > 0 {
+    out.push(SmartCollectionSummaryResponse {
+      id: "read-later".to_string(),
+      label: "Прочитать позже".to_string(),
+      description: "Отложенные сообщения и ссылки".to_string(),
+      count: read_later_count,
+      token: read_later::COLLECTION_TOKEN.to_string(),
+      room_ids: Vec::new(),
+    });
+  }
   Ok(out)
 }
 
+/// How many recent messages plugin collection providers get to pick from.
+const PLUGIN_COLLECTION_WINDOW: usize = 2000;
+
+fn plugin_collection_candidates(conn: &Connection, account_key: &str) -> Result<Vec<IndexedMessageRecord>, String> {
+  let query = LocalSearchQueryPayload {
+    limit: Some(PLUGIN_COLLECTION_WINDOW),
+    ..Default::default()
+  };
+  query_index_records(conn, account_key, &query, None)
+}
+
+fn plugin_collection_members(
+  conn: &Connection,
+  account_key: &str,
+  runtime: &wasm_plugins::PluginRuntime,
+  plugin_id: &str,
+  collection_id: &str,
+) -> Result<Vec<IndexedMessageRecord>, String> {
+  let candidates = plugin_collection_candidates(conn, account_key)?;
+  let messages: Vec<Value> = candidates
+    .iter()
+    .filter_map(|record| serde_json::to_value(record).ok())
+    .collect();
+  let members: std::collections::HashSet<String> = runtime
+    .collection_members(plugin_id, collection_id, &messages)?
+    .into_iter()
+    .collect();
+  Ok(candidates.into_iter().filter(|record| members.contains(&record.event_id)).collect())
+}
+
+fn compute_plugin_collections(
+  conn: &Connection,
+  account_key: &str,
+  runtime: &wasm_plugins::PluginRuntime,
+) -> Vec<SmartCollectionSummaryResponse> {
+  let mut out = Vec::new();
+  for (plugin_id, collection) in runtime.collections() {
+    let count = match plugin_collection_members(conn, account_key, runtime, &plugin_id, &collection.id) {
+      Ok(members) => members.len(),
+      Err(err) => {
+        tracing::warn!(target: "plugins", "{}", err);
+        continue;
+      }
+    };
+    if count > 0 {
+      out.push(SmartCollectionSummaryResponse {
+        id: format!("{}:{}", plugin_id, collection.id),
+        label: collection.label,
+        description: collection.description,
+        count,
+        token: wasm_plugins::collection_token(&plugin_id, &collection.id),
+        room_ids: Vec::new(),
+      });
+    }
+  }
+  out
+}
+
 #[tauri::command]
-async fn upsert_index_records(app: AppHandle, payload: IndexUpsertPayload) -> Result<(), String> {
+async fn upsert_index_records(app: AppHandle, account_key: Option<String>, payload: IndexUpsertPayload) -> Result<(), String> {
   let path = index_db_path(&app)?;
+  let account_key = account_key.unwrap_or_default();
   tauri::async_runtime::spawn_blocking(move || -> Result<(), String> {
     let conn = Connection::open(path).map_err(|e| e.to_string())?;
     init_index_db(&conn).map_err(|e| e.to_string())?;
-    insert_index_records(&conn, &payload)
+    insert_index_records(&conn, &account_key, &payload)
   })
   .await
   .map_err(|e| e.to_string())??;
@@ -668,61 +1541,7453 @@ async fn upsert_index_records(app: AppHandle, payload: IndexUpsertPayload) -> Re
 #[tauri::command]
 async fn query_local_index(
   app: AppHandle,
+  account_key: Option<String>,
   query: LocalSearchQueryPayload,
   mention_target: Option<String>,
-) -> Result<Vec<IndexedMessageRecord>, String> {
+) -> Result<LocalSearchPage, String> {
   let path = index_db_path(&app)?;
-  tauri::async_runtime::spawn_blocking(move || -> Result<Vec<IndexedMessageRecord>, String> {
+  let account_key = account_key.unwrap_or_default();
+  tauri::async_runtime::spawn_blocking(move || -> Result<LocalSearchPage, String> {
     let conn = Connection::open(path).map_err(|e| e.to_string())?;
     init_index_db(&conn).map_err(|e| e.to_string())?;
-    query_index_records(&conn, &query, mention_target.as_deref())
+    query_index_page(&conn, &account_key, &query, mention_target.as_deref())
   })
   .await
   .map_err(|e| e.to_string())?
 }
 
 #[tauri::command]
-async fn load_room_index(app: AppHandle, room_id: String) -> Result<Option<PersistedRoomIndexResponse>, String> {
+async fn load_room_index(
+  app: AppHandle,
+  account_key: Option<String>,
+  room_id: String,
+  since_seq: Option<i64>,
+) -> Result<Option<PersistedRoomIndexResponse>, String> {
   let path = index_db_path(&app)?;
+  let account_key = account_key.unwrap_or_default();
   tauri::async_runtime::spawn_blocking(move || -> Result<Option<PersistedRoomIndexResponse>, String> {
     let conn = Connection::open(path).map_err(|e| e.to_string())?;
     init_index_db(&conn).map_err(|e| e.to_string())?;
-    load_room_index_from_conn(&conn, &room_id).map(Some)
+    load_room_index_from_conn(&conn, &account_key, &room_id, since_seq).map(Some)
   })
   .await
   .map_err(|e| e.to_string())?
 }
 
+/// Drop an account's indexed messages and media for one room. Other accounts in the same
+/// room keep theirs; the delete triggers leave tombstones so open windows drop the rows too.
+#[tauri::command]
+async fn purge_room_index(app: AppHandle, account_key: Option<String>, room_id: String) -> Result<(), String> {
+  let account_key = account_key.unwrap_or_default();
+  with_index_db(&app, move |conn| {
+    conn
+      .execute(
+        "DELETE FROM message_index WHERE account_key = ?1 AND room_id = ?2",
+        params![account_key, room_id],
+      )
+      .map_err(|e| e.to_string())?;
+    conn
+      .execute(
+        "DELETE FROM media_index WHERE account_key = ?1 AND room_id = ?2",
+        params![account_key, room_id],
+      )
+      .map_err(|e| e.to_string())?;
+    Ok(())
+  })
+  .await
+}
+
+/// Drop everything an account has indexed, tombstones included.
+#[tauri::command]
+async fn clear_index_store(app: AppHandle, account_key: Option<String>) -> Result<(), String> {
+  let account_key = account_key.unwrap_or_default();
+  with_index_db(&app, move |conn| {
+    for table in ["message_index", "media_index", "index_tombstones"] {
+      conn
+        .execute(&format!("DELETE FROM {} WHERE account_key = ?1", table), [&account_key])
+        .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+  })
+  .await
+}
+
 #[tauri::command]
-async fn get_smart_collections(app: AppHandle, user_id: String) -> Result<Vec<SmartCollectionSummaryResponse>, String> {
+async fn get_smart_collections(
+  app: AppHandle,
+  account_key: Option<String>,
+  user_id: String,
+) -> Result<Vec<SmartCollectionSummaryResponse>, String> {
   let path = index_db_path(&app)?;
+  let account_key = account_key.unwrap_or_default();
   tauri::async_runtime::spawn_blocking(move || -> Result<Vec<SmartCollectionSummaryResponse>, String> {
     let conn = Connection::open(path).map_err(|e| e.to_string())?;
     init_index_db(&conn).map_err(|e| e.to_string())?;
-    compute_smart_collections(&conn, &user_id)
+    let mut collections = compute_smart_collections(&conn, &account_key, &user_id)?;
+    collections.extend(compute_plugin_collections(&conn, &account_key, &app.state::<wasm_plugins::PluginRuntime>()));
+    Ok(collections)
   })
   .await
   .map_err(|e| e.to_string())?
 }
 
-async fn read_backups_map(app: &AppHandle) -> Result<HashMap<String, EncryptedBackup>, String> {
-  let store = StoreBuilder::new(app, BACKUP_STORE_FILE)
-    .build()
-    .map_err(|e| e.to_string())?;
-  let value = store.get(BACKUP_KEY);
-  if let Some(v) = value {
-    serde_json::from_value::<HashMap<String, EncryptedBackup>>(v.clone())
-      .map_err(|e| e.to_string())
-  } else {
-    Ok(HashMap::new())
-  }
+/// Messages in a plugin-provided collection, by its `smart:plugin:` token.
+#[tauri::command]
+async fn query_plugin_collection(
+  app: AppHandle,
+  account_key: Option<String>,
+  token: String,
+) -> Result<Vec<IndexedMessageRecord>, String> {
+  let account_key = account_key.unwrap_or_default();
+  let (plugin_id, collection_id) = wasm_plugins::parse_collection_token(&token)
+    .map(|(plugin, collection)| (plugin.to_string(), collection.to_string()))
+    .ok_or_else(|| format!("Not a plugin collection: {}", token))?;
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<Vec<IndexedMessageRecord>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    plugin_collection_members(&conn, &account_key, &app.state::<wasm_plugins::PluginRuntime>(), &plugin_id, &collection_id)
+  })
+  .await
+  .map_err(|e| e.to_string())?
 }
 
-async fn write_backups_map(app: &AppHandle, map: &HashMap<String, EncryptedBackup>) -> Result<(), String> {
-  let store = StoreBuilder::new(app, BACKUP_STORE_FILE)
-    .build()
-    .map_err(|e| e.to_string())?;
+/// Newest unanswered reply of each thread in the `smart:threads` collection.
+#[tauri::command]
+async fn query_thread_collection(
+  app: AppHandle,
+  account_key: Option<String>,
+  user_id: String,
+) -> Result<Vec<IndexedMessageRecord>, String> {
+  let account_key = account_key.unwrap_or_default();
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<Vec<IndexedMessageRecord>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    let local = normalized_localpart(&user_id);
+    let mut stmt = conn
+      .prepare(&format!(
+        "SELECT {} FROM message_index WHERE account_key = ?1 AND room_id = ?2 AND event_id = ?3",
+        INDEX_RECORD_COLUMNS
+      ))
+      .map_err(|e| e.to_string())?;
+    let mut out = Vec::new();
+    for thread in threads::unanswered_threads(&conn, &account_key, &user_id, &local)? {
+      let record = stmt
+        .query_row(params![account_key, thread.room_id, thread.latest_event_id], index_record_from_row)
+        .optional()
+        .map_err(|e| e.to_string())?;
+      out.extend(record);
+    }
+    Ok(out)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+type CachedRoomEvents = (
+  usize,
+  Vec<room_upgrade::RoomUpgrade>,
+  Vec<auto_download::MediaCandidate>,
+  Vec<keyword_alerts::KeywordHit>,
+  Vec<call_log::CallEntry>,
+);
+
+/// Feed raw timeline, state and receipt events into the native event cache. With `account_key`,
+/// media in the events is pre-fetched in the background according to the auto-download policy.
+#[tauri::command]
+async fn cache_room_events(
+  app: AppHandle,
+  network: tauri::State<'_, auto_download::NetworkState>,
+  room_id: String,
+  events: Vec<serde_json::Value>,
+  account_key: Option<String>,
+) -> Result<usize, String> {
+  let path = index_db_path(&app)?;
+  let metered = network.is_metered();
+  let policy_room = room_id.clone();
+  let scan_account = account_key.clone();
+  let (stored, upgrades, prefetch, hits, missed_calls) = tauri::async_runtime::spawn_blocking(
+    move || -> Result<CachedRoomEvents, String> {
+      let conn = Connection::open(path).map_err(|e| e.to_string())?;
+      init_index_db(&conn).map_err(|e| e.to_string())?;
+      let (hits, missed_calls) = match &scan_account {
+        Some(account_key) => {
+          let user_id = user_id_for_key(account_key);
+          (
+            keyword_alerts::scan(&conn, account_key, user_id, &policy_room, &events)?,
+            call_log::track(&conn, account_key, user_id, &policy_room, &events)?,
+          )
+        }
+        None => (Vec::new(), Vec::new()),
+      };
+      // The event cache is per account; without one there is nothing to file the events under.
+      let stored = match &scan_account {
+        Some(account_key) => event_cache::cache_events(&conn, account_key, &policy_room, &events)?,
+        None => 0,
+      };
+      let upgrades = room_upgrade::detect_tombstones(&conn, &policy_room, &events)?;
+      let policy = auto_download::policy(&conn, Some(&policy_room))?;
+      let prefetch = auto_download::select(&policy, metered, auto_download::media_candidates(&events));
+      Ok((stored, upgrades, prefetch, hits, missed_calls))
+    },
+  )
+  .await
+  .map_err(|e| e.to_string())??;
+  if let (Some(account_key), false) = (&account_key, hits.is_empty()) {
+    let _ = app.emit_all("keywords://highlight", json!({ "accountKey": account_key, "hits": hits }));
+    tauri::async_runtime::spawn(notify_keyword_hits(app.clone(), account_key.clone(), hits));
+  }
+  if let Some(account_key) = &account_key {
+    for call in missed_calls {
+      tauri::async_runtime::spawn(notify_missed_call(app.clone(), account_key.clone(), call));
+    }
+  }
+  for upgrade in upgrades {
+    app
+      .emit_all("rooms://tombstone", &upgrade)
+      .map_err(|e| format!("Failed to emit room tombstone: {}", e))?;
+  }
+  if let (Some(account_key), false) = (account_key, prefetch.is_empty()) {
+    tauri::async_runtime::spawn(prefetch_media(app.clone(), account_key, room_id, prefetch));
+  }
+  let badge_app = app.clone();
+  tauri::async_runtime::spawn(async move {
+    let _ = refresh_badge(&badge_app).await;
+  });
+  Ok(stored)
+}
+
+/// Download media into the cache one item at a time, announcing each with `media://prefetched`.
+async fn prefetch_media(app: AppHandle, account_key: String, room_id: String, candidates: Vec<auto_download::MediaCandidate>) {
+  for candidate in candidates {
+    match load_media(&app, &account_key, &candidate.mxc_url, candidate.file.as_ref(), false).await {
+      Ok(Some(_)) => {
+        let _ = app.emit_all(
+          "media://prefetched",
+          json!({ "roomId": room_id, "eventId": candidate.event_id, "mxcUrl": candidate.mxc_url, "thumbnail": candidate.thumbnail }),
+        );
+      }
+      Ok(None) => {}
+      Err(err) => tracing::warn!("Failed to prefetch {}: {}", candidate.mxc_url, err),
+    }
+  }
+}
+
+/// Warm the cache with the media around the one open in the viewer: thumbnails of the `count`
+/// neighbours on each side first, then image originals. Returns immediately; items are
+/// announced with `media://prefetched`.
+#[tauri::command]
+async fn prefetch_adjacent_media(
+  app: AppHandle,
+  account_key: String,
+  room_id: String,
+  media_id: String,
+  count: Option<usize>,
+) -> Result<usize, String> {
+  let path = index_db_path(&app)?;
+  let query_room = room_id.clone();
+  let candidates = tauri::async_runtime::spawn_blocking(move || -> Result<Vec<auto_download::MediaCandidate>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    auto_download::adjacent_candidates(&conn, &account_key, &query_room, &media_id, count.unwrap_or(3))
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  let queued = candidates.len();
+  if queued > 0 {
+    tauri::async_runtime::spawn(prefetch_media(app.clone(), account_key, room_id, candidates));
+  }
+  Ok(queued)
+}
+
+#[tauri::command]
+async fn get_auto_download_policy(app: AppHandle, room_id: Option<String>) -> Result<auto_download::AutoDownloadPolicy, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<auto_download::AutoDownloadPolicy, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    auto_download::policy(&conn, room_id.as_deref())
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Save the global auto-download policy, or a room override when `room_id` is given (`None` policy removes it).
+#[tauri::command]
+async fn set_auto_download_policy(
+  app: AppHandle,
+  room_id: Option<String>,
+  policy: Option<auto_download::AutoDownloadPolicy>,
+) -> Result<(), String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<(), String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    auto_download::set_policy(&conn, room_id.as_deref(), policy.as_ref())
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Report whether the current connection is metered (cellular, tethered); `wifiOnly` policies pause while it is.
+#[tauri::command]
+fn set_network_metered(network: tauri::State<'_, auto_download::NetworkState>, metered: bool) {
+  network.set_metered(metered);
+}
+
+/// Join the replacement of an upgraded room and move local data over to it.
+#[tauri::command]
+async fn follow_room_upgrade(app: AppHandle, account_key: String, old_room_id: String) -> Result<room_upgrade::RoomUpgrade, String> {
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  let path = index_db_path(&app)?;
+  let lookup_path = path.clone();
+  let lookup_room = old_room_id.clone();
+  let known = tauri::async_runtime::spawn_blocking(move || -> Result<Option<room_upgrade::RoomUpgrade>, String> {
+    let conn = Connection::open(lookup_path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    room_upgrade::get_upgrade(&conn, &lookup_room)
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  let upgrade = match known {
+    Some(upgrade) => upgrade,
+    None => room_upgrade::fetch_tombstone(&client, &old_room_id)
+      .await?
+      .ok_or_else(|| format!("Room {} has not been upgraded", old_room_id))?,
+  };
+  room_upgrade::join_replacement(&client, &upgrade).await?;
+  let followed = tauri::async_runtime::spawn_blocking(move || -> Result<room_upgrade::RoomUpgrade, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    room_upgrade::detect_tombstones(
+      &conn,
+      &upgrade.old_room_id,
+      &[json!({
+        "type": "m.room.tombstone",
+        "state_key": "",
+        "sender": upgrade.sender,
+        "content": { "replacement_room": upgrade.replacement_room_id, "body": upgrade.body },
+      })],
+    )?;
+    room_upgrade::migrate_room_data(&conn, &upgrade)?;
+    room_upgrade::get_upgrade(&conn, &upgrade.old_room_id)?
+      .ok_or_else(|| "Room upgrade record missing after migration".to_string())
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  app
+    .emit_all("rooms://upgraded", &followed)
+    .map_err(|e| format!("Failed to emit room upgrade: {}", e))?;
+  Ok(followed)
+}
+
+/// List thread summaries for a room. Falls back to cached threads when the homeserver is unreachable.
+#[tauri::command]
+async fn get_threads(
+  app: AppHandle,
+  account_key: String,
+  room_id: String,
+  filter: Option<String>,
+  from: Option<String>,
+) -> Result<threads::ThreadListResponse, String> {
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  let include = threads::include_param(filter.as_deref());
+  let fetched = match threads::fetch_thread_roots(&client, &room_id, include, from.as_deref()).await {
+    Ok(result) => Some(result),
+    Err(err) if err.is_network() => None,
+    Err(err) => return Err(err.into()),
+  };
+  let path = index_db_path(&app)?;
+  let user_id = client.user_id.clone();
+  tauri::async_runtime::spawn_blocking(move || -> Result<threads::ThreadListResponse, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    let (root_ids, roots, next_batch) = match fetched {
+      Some((roots, next_batch)) => (threads::cache_thread_roots(&conn, &account_key, &room_id, &roots)?, roots, next_batch),
+      None => (threads::cached_thread_roots(&conn, &account_key, &room_id)?, Vec::new(), None),
+    };
+    let summaries = threads::summarize_threads(
+      &conn,
+      &account_key,
+      &room_id,
+      &user_id,
+      &root_ids,
+      &roots,
+      include == "participated",
+    )?;
+    Ok(threads::ThreadListResponse { threads: summaries, next_batch })
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+async fn store_ignored_users(app: &AppHandle, account_key: String, user_ids: Vec<String>) -> Result<Vec<String>, String> {
+  let path = index_db_path(app)?;
+  let content = json!({
+    "ignored_users": user_ids.iter().map(|id| (id.clone(), json!({}))).collect::<serde_json::Map<_, _>>(),
+  });
+  let (ignored, changed) = tauri::async_runtime::spawn_blocking(
+    move || -> Result<(Vec<String>, Option<account_data::AccountDataEntry>), String> {
+      let conn = Connection::open(path).map_err(|e| e.to_string())?;
+      init_index_db(&conn).map_err(|e| e.to_string())?;
+      ignore_list::replace_local_list(&conn, &account_key, &user_ids)?;
+      let changed = account_data::store(&conn, &account_key, account_data::IGNORED_USERS_TYPE, None, &content)?;
+      Ok((ignore_list::list_local(&conn, &account_key)?, changed))
+    },
+  )
+  .await
+  .map_err(|e| e.to_string())??;
+  emit_account_data_changes(app, changed.into_iter().collect())?;
+  Ok(ignored)
+}
+
+/// Add a user to `m.ignored_user_list` and hide their messages from local search and caches.
+#[tauri::command]
+async fn ignore_user(app: AppHandle, account_key: String, user_id: String) -> Result<Vec<String>, String> {
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  let ignored = ignore_list::update_ignored_users(&client, &user_id, true).await?;
+  store_ignored_users(&app, account_key, ignored).await
+}
+
+#[tauri::command]
+async fn unignore_user(app: AppHandle, account_key: String, user_id: String) -> Result<Vec<String>, String> {
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  let ignored = ignore_list::update_ignored_users(&client, &user_id, false).await?;
+  store_ignored_users(&app, account_key, ignored).await
+}
+
+/// Refresh the ignore list from account data. Returns the local copy if the server is unreachable.
+#[tauri::command]
+async fn get_ignored_users(app: AppHandle, account_key: String) -> Result<Vec<String>, String> {
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  match ignore_list::fetch_ignored_users(&client).await {
+    Ok(ignored) => store_ignored_users(&app, account_key, ignored.keys().cloned().collect()).await,
+    Err(err) if err.is_network() => {
+      let path = index_db_path(&app)?;
+      tauri::async_runtime::spawn_blocking(move || -> Result<Vec<String>, String> {
+        let conn = Connection::open(path).map_err(|e| e.to_string())?;
+        init_index_db(&conn).map_err(|e| e.to_string())?;
+        ignore_list::list_local(&conn, &account_key)
+      })
+      .await
+      .map_err(|e| e.to_string())?
+    }
+    Err(err) => Err(err.into()),
+  }
+}
+
+async fn submit_report(
+  app: &AppHandle,
+  account_key: String,
+  room_id: String,
+  event_id: Option<String>,
+  score: Option<i64>,
+  reason: Option<String>,
+) -> Result<reports::ReportOutcome, String> {
+  let client = MatrixClient::for_account(app, &account_key).await?;
+  match reports::send_report(&client, &room_id, event_id.as_deref(), score, reason.as_deref()).await {
+    Ok(_) => {
+      let handle = app.clone();
+      tauri::async_runtime::spawn(async move {
+        let _ = flush_report_queue(handle).await;
+      });
+      Ok(reports::ReportOutcome { delivered: true, queued: false })
+    }
+    Err(err) if err.is_network() => {
+      let path = index_db_path(app)?;
+      let message = err.to_string();
+      tauri::async_runtime::spawn_blocking(move || -> Result<(), String> {
+        let conn = Connection::open(path).map_err(|e| e.to_string())?;
+        init_index_db(&conn).map_err(|e| e.to_string())?;
+        reports::enqueue(&conn, &account_key, &room_id, event_id.as_deref(), score, reason.as_deref(), &message)
+      })
+      .await
+      .map_err(|e| e.to_string())??;
+      Ok(reports::ReportOutcome { delivered: false, queued: true })
+    }
+    Err(err) => Err(err.into()),
+  }
+}
+
+/// Report an event to the homeserver admins. Queued for later delivery when offline.
+#[tauri::command]
+async fn report_event(
+  app: AppHandle,
+  account_key: String,
+  room_id: String,
+  event_id: String,
+  score: Option<i64>,
+  reason: Option<String>,
+) -> Result<reports::ReportOutcome, String> {
+  submit_report(&app, account_key, room_id, Some(event_id), score, reason).await
+}
+
+/// Report a whole room to the homeserver admins. Queued for later delivery when offline.
+#[tauri::command]
+async fn report_room(app: AppHandle, account_key: String, room_id: String, reason: Option<String>) -> Result<reports::ReportOutcome, String> {
+  submit_report(&app, account_key, room_id, None, None, reason).await
+}
+
+/// Deliver queued reports. Stops at the first connectivity failure; returns how many were sent.
+#[tauri::command]
+async fn flush_report_queue(app: AppHandle) -> Result<usize, String> {
+  let path = index_db_path(&app)?;
+  let queue_path = path.clone();
+  let queued = tauri::async_runtime::spawn_blocking(move || -> Result<Vec<reports::QueuedReport>, String> {
+    let conn = Connection::open(queue_path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    reports::pending(&conn)
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  let mut clients: HashMap<String, MatrixClient> = HashMap::new();
+  let mut delivered = 0;
+  for report in queued {
+    if !clients.contains_key(&report.account_key) {
+      match MatrixClient::for_account(&app, &report.account_key).await {
+        Ok(client) => { clients.insert(report.account_key.clone(), client); }
+        Err(_) => continue,
+      }
+    }
+    let client = &clients[&report.account_key];
+    let result = reports::send_report(
+      client,
+      &report.room_id,
+      report.event_id.as_deref(),
+      report.score,
+      report.reason.as_deref(),
+    )
+    .await;
+    let conn = Connection::open(&path).map_err(|e| e.to_string())?;
+    match result {
+      Ok(_) => {
+        reports::remove(&conn, report.id)?;
+        delivered += 1;
+      }
+      Err(err) if err.is_network() => break,
+      Err(ApiError::Http { status, .. }) if status == 429 || status >= 500 => {
+        reports::mark_failed(&conn, report.id, &format!("HTTP {}", status))?;
+      }
+      Err(_) => reports::remove(&conn, report.id)?,
+    }
+  }
+  Ok(delivered)
+}
+
+/// Search the public room directory of the homeserver or a federated server, cached with a TTL.
+#[tauri::command]
+async fn search_public_rooms(
+  app: AppHandle,
+  account_key: String,
+  query: directory::PublicRoomsQuery,
+) -> Result<directory::PublicRoomsPage, String> {
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  let cache_key = query.cache_key(&client.homeserver_url);
+  let path = index_db_path(&app)?;
+  let lookup_path = path.clone();
+  let lookup_key = cache_key.clone();
+  let cached = tauri::async_runtime::spawn_blocking(move || -> Result<Option<directory::PublicRoomsPage>, String> {
+    let conn = Connection::open(lookup_path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    directory::cached_page(&conn, &lookup_key)
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  if let Some(page) = &cached {
+    if !page.stale {
+      return Ok(page.clone());
+    }
+  }
+  match directory::fetch_public_rooms(&client, &query).await {
+    Ok(response) => {
+      let page = directory::page_from_response(&response);
+      tauri::async_runtime::spawn_blocking(move || -> Result<(), String> {
+        let conn = Connection::open(path).map_err(|e| e.to_string())?;
+        init_index_db(&conn).map_err(|e| e.to_string())?;
+        directory::store_page(&conn, &cache_key, &response)
+      })
+      .await
+      .map_err(|e| e.to_string())??;
+      Ok(page)
+    }
+    Err(err) if directory::should_serve_stale(&err) && cached.is_some() => Ok(cached.unwrap()),
+    Err(err) => Err(err.into()),
+  }
+}
+
+/// Search users for the "start chat" dialog: local DM partners and room members first, then the server directory.
+#[tauri::command]
+async fn search_users(app: AppHandle, account_key: String, term: String) -> Result<directory::UserSearchResponse, String> {
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  let (remote, limited, directory_available) = match directory::fetch_user_directory(&client, &term).await {
+    Ok((results, limited)) => (results, limited, true),
+    Err(err) if directory::should_serve_stale(&err) => (Vec::new(), false, false),
+    Err(err) => return Err(err.into()),
+  };
+  let path = index_db_path(&app)?;
+  let own_user_id = client.user_id.clone();
+  let (local, direct) = tauri::async_runtime::spawn_blocking(
+    move || -> Result<(Vec<directory::UserSearchResult>, std::collections::HashSet<String>), String> {
+      let conn = Connection::open(path).map_err(|e| e.to_string())?;
+      init_index_db(&conn).map_err(|e| e.to_string())?;
+      let local = directory::search_local_members(&conn, &account_key, &own_user_id, &term)?;
+      let direct = account_data::direct_rooms(&conn, &account_key)?.into_keys().collect();
+      Ok((local, direct))
+    },
+  )
+  .await
+  .map_err(|e| e.to_string())??;
+  Ok(directory::UserSearchResponse {
+    results: directory::merge_user_results(local, remote, &direct),
+    limited,
+    directory_available,
+  })
+}
+
+/// Track invites from a sync response. New invites are announced via `invites://new` and a native notification.
+#[tauri::command]
+async fn record_sync_invites(
+  app: AppHandle,
+  account_key: String,
+  invites: serde_json::Value,
+  resolved_room_ids: Option<Vec<String>>,
+) -> Result<Vec<invites::PendingInvite>, String> {
+  let map = read_accounts_map(&app).await?;
+  let own_user_id = map
+    .get(&account_key)
+    .map(|c| c.user_id.clone())
+    .ok_or_else(|| format!("Unknown account: {}", account_key))?;
+  let path = index_db_path(&app)?;
+  let fresh = tauri::async_runtime::spawn_blocking(move || -> Result<Vec<invites::PendingInvite>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    invites::record_sync_invites(&conn, &account_key, &own_user_id, &invites, &resolved_room_ids.unwrap_or_default())
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  for invite in &fresh {
+    app
+      .emit_all("invites://new", invite)
+      .map_err(|e| format!("Failed to emit invite: {}", e))?;
+    let (title, body) = invite.describe();
+    let _ = app.notification().builder().title(title).body(body).show();
+  }
+  Ok(fresh)
+}
+
+#[tauri::command]
+async fn list_pending_invites(app: AppHandle, account_key: String) -> Result<Vec<invites::PendingInvite>, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<Vec<invites::PendingInvite>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    invites::list_invites(&conn, &account_key)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+async fn resolve_invite(app: &AppHandle, account_key: String, room_id: String) -> Result<Option<invites::PendingInvite>, String> {
+  let path = index_db_path(app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<Option<invites::PendingInvite>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    let invite = invites::get_invite(&conn, &account_key, &room_id)?;
+    invites::remove_invite(&conn, &account_key, &room_id)?;
+    Ok(invite)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+async fn accept_invite(app: AppHandle, account_key: String, room_id: String) -> Result<(), String> {
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  let path = index_db_path(&app)?;
+  let (lookup_key, lookup_room) = (account_key.clone(), room_id.clone());
+  let invite = tauri::async_runtime::spawn_blocking(move || -> Result<Option<invites::PendingInvite>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    invites::get_invite(&conn, &lookup_key, &lookup_room)
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  invites::accept(&client, &room_id, invite.as_ref().map(|i| i.inviter.as_str())).await?;
+  resolve_invite(&app, account_key, room_id).await?;
+  Ok(())
+}
+
+#[tauri::command]
+async fn reject_invite(app: AppHandle, account_key: String, room_id: String, reason: Option<String>) -> Result<(), String> {
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  invites::reject(&client, &room_id, reason.as_deref()).await?;
+  resolve_invite(&app, account_key, room_id).await?;
+  Ok(())
+}
+
+#[tauri::command]
+async fn knock_on_room(
+  app: AppHandle,
+  account_key: String,
+  room_id_or_alias: String,
+  reason: Option<String>,
+  via: Option<Vec<String>>,
+) -> Result<serde_json::Value, String> {
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  Ok(invites::knock(&client, &room_id_or_alias, reason.as_deref(), &via.unwrap_or_default()).await?)
+}
+
+fn emit_account_data_changes(app: &AppHandle, entries: Vec<account_data::AccountDataEntry>) -> Result<(), String> {
+  for entry in entries {
+    app
+      .emit_all("account-data://changed", &entry)
+      .map_err(|e| format!("Failed to emit account data change: {}", e))?;
+  }
+  Ok(())
+}
+
+/// Read account data from the local cache, fetching from the homeserver on a miss or when `refresh` is set.
+#[tauri::command]
+async fn get_account_data(
+  app: AppHandle,
+  account_key: String,
+  data_type: String,
+  room_id: Option<String>,
+  refresh: Option<bool>,
+) -> Result<Option<account_data::AccountDataEntry>, String> {
+  let path = index_db_path(&app)?;
+  if !refresh.unwrap_or(false) {
+    let (key, kind, room) = (account_key.clone(), data_type.clone(), room_id.clone());
+    let lookup_path = path.clone();
+    let cached = tauri::async_runtime::spawn_blocking(move || -> Result<Option<account_data::AccountDataEntry>, String> {
+      let conn = Connection::open(lookup_path).map_err(|e| e.to_string())?;
+      init_index_db(&conn).map_err(|e| e.to_string())?;
+      account_data::get_cached(&conn, &key, &kind, room.as_deref())
+    })
+    .await
+    .map_err(|e| e.to_string())??;
+    if cached.is_some() {
+      return Ok(cached);
+    }
+  }
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  let content = match account_data::fetch(&client, &data_type, room_id.as_deref()).await? {
+    Some(content) => content,
+    None => return Ok(None),
+  };
+  let (entry, changed) = tauri::async_runtime::spawn_blocking(
+    move || -> Result<(Option<account_data::AccountDataEntry>, Option<account_data::AccountDataEntry>), String> {
+      let conn = Connection::open(path).map_err(|e| e.to_string())?;
+      init_index_db(&conn).map_err(|e| e.to_string())?;
+      let changed = account_data::store(&conn, &account_key, &data_type, room_id.as_deref(), &content)?;
+      Ok((account_data::get_cached(&conn, &account_key, &data_type, room_id.as_deref())?, changed))
+    },
+  )
+  .await
+  .map_err(|e| e.to_string())??;
+  emit_account_data_changes(&app, changed.into_iter().collect())?;
+  Ok(entry)
+}
+
+/// Write account data to the homeserver and update the local cache.
+#[tauri::command]
+async fn set_account_data(
+  app: AppHandle,
+  account_key: String,
+  data_type: String,
+  content: serde_json::Value,
+  room_id: Option<String>,
+) -> Result<(), String> {
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  account_data::push(&client, &data_type, room_id.as_deref(), &content).await?;
+  let path = index_db_path(&app)?;
+  let changed = tauri::async_runtime::spawn_blocking(move || -> Result<Option<account_data::AccountDataEntry>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    account_data::store(&conn, &account_key, &data_type, room_id.as_deref(), &content)
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  emit_account_data_changes(&app, changed.into_iter().collect())
+}
+
+/// Ingest `account_data` events from sync (global, or for one room when `room_id` is set).
+#[tauri::command]
+async fn record_sync_account_data(
+  app: AppHandle,
+  account_key: String,
+  events: Vec<serde_json::Value>,
+  room_id: Option<String>,
+) -> Result<usize, String> {
+  let path = index_db_path(&app)?;
+  let changed = tauri::async_runtime::spawn_blocking(move || -> Result<Vec<account_data::AccountDataEntry>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    account_data::record_sync_events(&conn, &account_key, room_id.as_deref(), &events)
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  let count = changed.len();
+  emit_account_data_changes(&app, changed)?;
+  Ok(count)
+}
+
+#[tauri::command]
+async fn get_direct_rooms(app: AppHandle, account_key: String) -> Result<HashMap<String, Vec<String>>, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<HashMap<String, Vec<String>>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    account_data::direct_rooms(&conn, &account_key)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+async fn get_room_tags(app: AppHandle, account_key: String, room_id: String) -> Result<Vec<account_data::RoomTag>, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<Vec<account_data::RoomTag>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    account_data::room_tags(&conn, &account_key, &room_id)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+async fn update_own_profile_cache(
+  app: &AppHandle,
+  account_key: String,
+  user_id: String,
+  display_name: Option<String>,
+  avatar_url: Option<String>,
+) -> Result<(), String> {
+  let path = index_db_path(app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<(), String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    profiles::update_cached_field(&conn, &account_key, &user_id, display_name.as_deref(), avatar_url.as_deref())
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+async fn set_display_name(app: AppHandle, account_key: String, display_name: String) -> Result<(), String> {
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  profiles::set_display_name(&client, &display_name).await?;
+  update_own_profile_cache(&app, account_key, client.user_id.clone(), Some(display_name), None).await
+}
+
+/// Resize an image file natively, upload it and set it as the account avatar. Returns the new `mxc://` URI.
+#[tauri::command]
+async fn set_avatar(app: AppHandle, account_key: String, path: String) -> Result<String, String> {
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  let (bytes, content_type) = tauri::async_runtime::spawn_blocking(move || profiles::prepare_avatar(std::path::Path::new(&path)))
+    .await
+    .map_err(|e| e.to_string())??;
+  let file_name = if content_type == "image/png" { "avatar.png" } else { "avatar.jpg" };
+  let mxc = client.upload(bytes, content_type, Some(file_name)).await?;
+  profiles::set_avatar_url(&client, &mxc).await?;
+  update_own_profile_cache(&app, account_key, client.user_id.clone(), None, Some(mxc.clone())).await?;
+  Ok(mxc)
+}
+
+/// Look up a profile through the local cache; stale entries are refreshed and served as-is when offline.
+#[tauri::command]
+async fn get_profile(app: AppHandle, account_key: String, user_id: String, refresh: Option<bool>) -> Result<profiles::UserProfile, String> {
+  let path = index_db_path(&app)?;
+  let (lookup_path, lookup_account, lookup_user) = (path.clone(), account_key.clone(), user_id.clone());
+  let cached = tauri::async_runtime::spawn_blocking(move || -> Result<Option<profiles::UserProfile>, String> {
+    let conn = Connection::open(lookup_path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    profiles::cached_profile(&conn, &lookup_account, &lookup_user)
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  if let Some(profile) = &cached {
+    if !profile.stale && !refresh.unwrap_or(false) {
+      return Ok(profile.clone());
+    }
+  }
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  match profiles::fetch_profile(&client, &user_id).await {
+    Ok(profile) => {
+      let stored = profile.clone();
+      tauri::async_runtime::spawn_blocking(move || -> Result<(), String> {
+        let conn = Connection::open(path).map_err(|e| e.to_string())?;
+        init_index_db(&conn).map_err(|e| e.to_string())?;
+        profiles::store_profile(&conn, &account_key, &stored)
+      })
+      .await
+      .map_err(|e| e.to_string())??;
+      Ok(profile)
+    }
+    Err(err) if err.is_network() && cached.is_some() => Ok(cached.unwrap()),
+    Err(err) => Err(err.into()),
+  }
+}
+
+#[tauri::command]
+async fn list_threepids(app: AppHandle, account_key: String) -> Result<Vec<threepid::ThreePid>, String> {
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  Ok(threepid::list(&client).await?)
+}
+
+/// Start validation of a new email or phone number. The returned session feeds `add_threepid`.
+#[tauri::command]
+async fn request_threepid_token(
+  app: AppHandle,
+  account_key: String,
+  request: threepid::ThreePidTokenRequest,
+) -> Result<threepid::ThreePidTokenSession, String> {
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  threepid::request_token(&client, &request).await
+}
+
+#[tauri::command]
+async fn submit_threepid_token(
+  app: AppHandle,
+  account_key: String,
+  session: threepid::ThreePidTokenSession,
+  token: String,
+) -> Result<bool, String> {
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  Ok(threepid::submit_token(&client, &session, &token).await?)
+}
+
+/// Bind a validated identifier. Returns `authRequired` with the UIAA flows until `auth` satisfies them.
+#[tauri::command]
+async fn add_threepid(
+  app: AppHandle,
+  account_key: String,
+  sid: String,
+  client_secret: String,
+  auth: Option<serde_json::Value>,
+) -> Result<threepid::UiaaOutcome, String> {
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  Ok(threepid::add(&client, &sid, &client_secret, auth.as_ref()).await?)
+}
+
+#[tauri::command]
+async fn remove_threepid(app: AppHandle, account_key: String, medium: String, address: String) -> Result<(), String> {
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  threepid::remove(&client, &medium, &address).await?;
+  Ok(())
+}
+
+/// Server versions, capabilities and derived feature flags for an account's homeserver, cached per homeserver.
+#[tauri::command]
+async fn get_server_capabilities(
+  app: AppHandle,
+  account_key: String,
+  refresh: Option<bool>,
+) -> Result<capabilities::ServerCapabilities, String> {
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  let path = index_db_path(&app)?;
+  let (lookup_path, homeserver_url) = (path.clone(), client.homeserver_url.clone());
+  let cached = tauri::async_runtime::spawn_blocking(move || -> Result<Option<capabilities::ServerCapabilities>, String> {
+    let conn = Connection::open(lookup_path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    capabilities::cached(&conn, &homeserver_url)
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  if let Some(caps) = &cached {
+    if !caps.stale && !refresh.unwrap_or(false) {
+      return Ok(caps.clone());
+    }
+  }
+  match capabilities::fetch(&client).await {
+    Ok(fetched) => {
+      let homeserver_url = client.homeserver_url.clone();
+      tauri::async_runtime::spawn_blocking(move || -> Result<capabilities::ServerCapabilities, String> {
+        let conn = Connection::open(path).map_err(|e| e.to_string())?;
+        init_index_db(&conn).map_err(|e| e.to_string())?;
+        capabilities::store(&conn, &homeserver_url, &fetched)
+      })
+      .await
+      .map_err(|e| e.to_string())?
+    }
+    Err(err) if err.is_network() && cached.is_some() => Ok(cached.unwrap()),
+    Err(err) => Err(err.into()),
+  }
+}
+
+/// Start sharing live location in a room for `duration_ms`, publishing the latest reported position periodically.
+#[tauri::command]
+async fn start_live_location(
+  app: AppHandle,
+  state: tauri::State<'_, location::LiveLocationState>,
+  account_key: String,
+  room_id: String,
+  duration_ms: i64,
+  description: Option<String>,
+  interval_secs: Option<u64>,
+) -> Result<location::LiveBeaconSession, String> {
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  location::start(&state, client, &account_key, &room_id, duration_ms, description, interval_secs).await
+}
+
+#[tauri::command]
+async fn stop_live_location(
+  app: AppHandle,
+  state: tauri::State<'_, location::LiveLocationState>,
+  account_key: String,
+  room_id: String,
+) -> Result<bool, String> {
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  location::stop(&state, &client, &account_key, &room_id).await
+}
+
+/// Feed a position from the OS geolocation hook or manual input into active beacons.
+#[tauri::command]
+fn report_location(state: tauri::State<'_, location::LiveLocationState>, position: location::GeoPosition) {
+  state.set_position(position);
+}
+
+#[tauri::command]
+fn list_live_location_sessions(state: tauri::State<'_, location::LiveLocationState>) -> Vec<location::LiveBeaconSession> {
+  state.sessions()
+}
+
+/// Aggregate received beacons in a room for the live map view.
+#[tauri::command]
+async fn get_room_beacons(
+  app: AppHandle,
+  account_key: String,
+  room_id: String,
+  live_only: Option<bool>,
+) -> Result<Vec<location::RoomBeacon>, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<Vec<location::RoomBeacon>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    location::room_beacons(&conn, &account_key, &room_id, live_only.unwrap_or(true))
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+async fn create_poll(
+  app: AppHandle,
+  account_key: String,
+  room_id: String,
+  question: String,
+  answers: Vec<String>,
+  disclosed: Option<bool>,
+  max_selections: Option<usize>,
+) -> Result<String, String> {
+  let answers: Vec<String> = answers
+    .into_iter()
+    .map(|a| a.trim().to_string())
+    .filter(|a| !a.is_empty())
+    .collect();
+  if question.trim().is_empty() || answers.len() < 2 {
+    return Err("A poll needs a question and at least two answers".to_string());
+  }
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  Ok(polls::create(&client, &room_id, question.trim(), &answers, disclosed.unwrap_or(true), max_selections).await?)
+}
+
+#[tauri::command]
+async fn respond_to_poll(
+  app: AppHandle,
+  account_key: String,
+  room_id: String,
+  poll_event_id: String,
+  answer_ids: Vec<String>,
+) -> Result<String, String> {
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  Ok(polls::respond(&client, &room_id, &poll_event_id, &answer_ids).await?)
+}
+
+#[tauri::command]
+async fn end_poll(app: AppHandle, account_key: String, room_id: String, poll_event_id: String) -> Result<String, String> {
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  Ok(polls::end(&client, &room_id, &poll_event_id).await?)
+}
+
+/// Aggregated tallies for a poll from the cached response and end relations.
+#[tauri::command]
+async fn get_poll_results(
+  app: AppHandle,
+  account_key: String,
+  room_id: String,
+  poll_event_id: String,
+) -> Result<Option<polls::PollResults>, String> {
+  let own_user_id = read_accounts_map(&app)
+    .await?
+    .get(&account_key)
+    .map(|creds| creds.user_id.clone())
+    .unwrap_or_default();
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<Option<polls::PollResults>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    polls::results(&conn, &account_key, &room_id, &poll_event_id, &own_user_id)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+async fn record_decryption_failures(
+  app: AppHandle,
+  account_key: String,
+  room_id: String,
+  failures: Vec<decryption_retry::DecryptionFailure>,
+) -> Result<usize, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<usize, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    decryption_retry::record_failures(&conn, &account_key, &room_id, &failures)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+async fn list_undecryptable_events(
+  app: AppHandle,
+  account_key: String,
+  room_id: Option<String>,
+) -> Result<Vec<decryption_retry::UndecryptableEvent>, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<Vec<decryption_retry::UndecryptableEvent>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    decryption_retry::list(&conn, &account_key, room_id.as_deref())
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Called when room keys arrive by to-device forward or backup restore; queued events for
+/// those sessions are handed back to the crypto layer via `crypto://retry-decryption`.
+#[tauri::command]
+async fn notify_room_keys_received(
+  app: AppHandle,
+  account_key: String,
+  keys: Vec<decryption_retry::ReceivedRoomKey>,
+  source: Option<String>,
+) -> Result<usize, String> {
+  let path = index_db_path(&app)?;
+  let retry_account = account_key.clone();
+  let retryable = tauri::async_runtime::spawn_blocking(move || -> Result<Vec<decryption_retry::UndecryptableEvent>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    decryption_retry::take_retryable(&conn, &retry_account, &keys)
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  if !retryable.is_empty() {
+    app
+      .emit_all(
+        "crypto://retry-decryption",
+        serde_json::json!({ "accountKey": account_key, "source": source, "events": retryable }),
+      )
+      .map_err(|e| format!("Failed to emit decryption retry: {}", e))?;
+  }
+  Ok(retryable.len())
+}
+
+/// Store successfully retried events in the cache and search index and tell the UI to
+/// replace its "unable to decrypt" placeholders.
+#[tauri::command]
+async fn complete_decryption_retry(
+  app: AppHandle,
+  account_key: String,
+  room_id: String,
+  events: Vec<serde_json::Value>,
+  index: Option<IndexUpsertPayload>,
+) -> Result<Vec<String>, String> {
+  let path = index_db_path(&app)?;
+  let resolve_room = room_id.clone();
+  let resolved = tauri::async_runtime::spawn_blocking(move || -> Result<Vec<String>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    let resolved = decryption_retry::resolve(&conn, &account_key, &resolve_room, &events)?;
+    if let Some(mut index) = index {
+      // Only index what was actually pending retry in this room.
+      let is_resolved = |room: &str, event_id: &str| room == resolve_room && resolved.iter().any(|id| id == event_id);
+      index.messages.retain(|message| is_resolved(&message.room_id, &message.event_id));
+      index.media_items.retain(|item| is_resolved(&item.room_id, &item.event_id));
+      index.room_id = resolve_room.clone();
+      insert_index_records(&conn, &account_key, &index)?;
+    }
+    Ok(resolved)
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  if !resolved.is_empty() {
+    app
+      .emit_all(
+        "crypto://decrypted",
+        serde_json::json!({ "roomId": room_id, "eventIds": resolved }),
+      )
+      .map_err(|e| format!("Failed to emit decrypted events: {}", e))?;
+  }
+  Ok(resolved)
+}
+
+#[tauri::command]
+async fn get_dehydrated_device(app: AppHandle, account_key: String) -> Result<Option<dehydrated_device::DehydratedDevice>, String> {
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  Ok(dehydrated_device::get(&client).await?)
+}
+
+/// Upload a device pickled by the crypto layer so room keys keep arriving while logged out
+/// everywhere. The pickle is passed through as is; nothing here creates or encrypts it.
+#[tauri::command]
+async fn upload_dehydrated_device(
+  app: AppHandle,
+  account_key: String,
+  device: dehydrated_device::DehydratedDeviceUpload,
+) -> Result<String, String> {
+  if !dehydrated_device::is_pickled(&device.device_data) {
+    return Err("device_data must be a pickle with an algorithm from the crypto layer".to_string());
+  }
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  Ok(dehydrated_device::upload(&client, &device).await?)
+}
+
+/// Fetch the dehydrated device and everything sent to it. The events are still Olm-encrypted;
+/// the crypto layer has to unpickle the device with its dehydration key to import the keys.
+#[tauri::command]
+async fn rehydrate_device(
+  app: AppHandle,
+  account_key: String,
+  since: Option<String>,
+) -> Result<Option<dehydrated_device::Rehydration>, String> {
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  let device = match dehydrated_device::get(&client).await? {
+    Some(device) => device,
+    None => return Ok(None),
+  };
+  let to_device = dehydrated_device::fetch_events(&client, &device.device_id, since).await?;
+  Ok(Some(dehydrated_device::Rehydration { device, to_device }))
+}
+
+#[tauri::command]
+async fn delete_dehydrated_device(app: AppHandle, account_key: String) -> Result<(), String> {
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  Ok(dehydrated_device::delete(&client).await?)
+}
+
+#[tauri::command]
+async fn get_key_share_policy(app: AppHandle, account_key: String) -> Result<key_requests::KeySharePolicy, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<key_requests::KeySharePolicy, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    key_requests::get_policy(&conn, &account_key)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+async fn set_key_share_policy(app: AppHandle, account_key: String, policy: key_requests::KeySharePolicy) -> Result<(), String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<(), String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    key_requests::set_policy(&conn, &account_key, policy)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Send `m.room_key_request` to the user's verified devices for sessions not requested yet.
+#[tauri::command]
+async fn request_missing_room_keys(
+  app: AppHandle,
+  account_key: String,
+  sessions: Vec<key_requests::MissingSession>,
+  verified_device_ids: Vec<String>,
+) -> Result<usize, String> {
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  let path = index_db_path(&app)?;
+  let lookup_path = path.clone();
+  let lookup_account = account_key.clone();
+  let pending = tauri::async_runtime::spawn_blocking(move || -> Result<Vec<key_requests::MissingSession>, String> {
+    let conn = Connection::open(lookup_path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    key_requests::unrequested(&conn, &lookup_account, &sessions)
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  let sent = key_requests::send_requests(&client, &verified_device_ids, pending).await?;
+  let count = sent.len();
+  tauri::async_runtime::spawn_blocking(move || -> Result<(), String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    key_requests::record_outgoing(&conn, &account_key, &sent)
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  Ok(count)
+}
+
+/// Cancel an outgoing request once the session arrived from elsewhere.
+#[tauri::command]
+async fn cancel_room_key_request(
+  app: AppHandle,
+  account_key: String,
+  room_id: String,
+  session_id: String,
+  verified_device_ids: Vec<String>,
+) -> Result<bool, String> {
+  let path = index_db_path(&app)?;
+  let clear_account = account_key.clone();
+  let request_id = tauri::async_runtime::spawn_blocking(move || -> Result<Option<String>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    key_requests::clear_outgoing(&conn, &clear_account, &room_id, &session_id)
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  match request_id {
+    Some(request_id) => {
+      let client = MatrixClient::for_account(&app, &account_key).await?;
+      key_requests::send_cancellation(&client, &verified_device_ids, &request_id).await?;
+      Ok(true)
+    }
+    None => Ok(false),
+  }
+}
+
+/// Evaluate incoming key requests against the share policy. Approved requests are handed to
+/// the crypto layer via `crypto://share-room-key`; the rest are queued for review.
+#[tauri::command]
+async fn handle_room_key_requests(
+  app: AppHandle,
+  account_key: String,
+  events: Vec<serde_json::Value>,
+  verified_device_ids: Vec<String>,
+) -> Result<Vec<key_requests::KeyRequestDecision>, String> {
+  let own_user_id = read_accounts_map(&app)
+    .await?
+    .get(&account_key)
+    .map(|creds| creds.user_id.clone())
+    .unwrap_or_default();
+  let path = index_db_path(&app)?;
+  let decisions = tauri::async_runtime::spawn_blocking(move || -> Result<Vec<key_requests::KeyRequestDecision>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    key_requests::handle_incoming(&conn, &account_key, &own_user_id, &verified_device_ids, &events)
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  for decision in &decisions {
+    match decision {
+      key_requests::KeyRequestDecision::Share { request } => app
+        .emit_all("crypto://share-room-key", request)
+        .map_err(|e| format!("Failed to emit key share: {}", e))?,
+      key_requests::KeyRequestDecision::Pending { request } => app
+        .emit_all("crypto://key-request", request)
+        .map_err(|e| format!("Failed to emit key request: {}", e))?,
+      key_requests::KeyRequestDecision::Ignored { .. } => {}
+    }
+  }
+  Ok(decisions)
+}
+
+#[tauri::command]
+async fn list_pending_key_requests(app: AppHandle, account_key: String) -> Result<Vec<key_requests::IncomingKeyRequest>, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<Vec<key_requests::IncomingKeyRequest>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    key_requests::list_incoming(&conn, &account_key)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Manually approve or deny a queued key request.
+#[tauri::command]
+async fn resolve_key_request(
+  app: AppHandle,
+  account_key: String,
+  requesting_device_id: String,
+  request_id: String,
+  approve: bool,
+) -> Result<bool, String> {
+  let path = index_db_path(&app)?;
+  let request = tauri::async_runtime::spawn_blocking(move || -> Result<Option<key_requests::IncomingKeyRequest>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    key_requests::take_incoming(&conn, &account_key, &requesting_device_id, &request_id)
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  match request {
+    Some(request) if approve => {
+      app
+        .emit_all("crypto://share-room-key", &request)
+        .map_err(|e| format!("Failed to emit key share: {}", e))?;
+      Ok(true)
+    }
+    Some(_) => Ok(true),
+    None => Ok(false),
+  }
+}
+
+/// Push rules from the account data cache, fetched once from the homeserver when missing.
+async fn push_rules_for(app: &AppHandle, account_key: &str, refresh: bool) -> Result<serde_json::Value, String> {
+  let path = index_db_path(app)?;
+  let lookup_account = account_key.to_string();
+  let cached = tauri::async_runtime::spawn_blocking(move || -> Result<Option<serde_json::Value>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    push_rules::cached_rules(&conn, &lookup_account)
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  if let (Some(rules), false) = (&cached, refresh) {
+    return Ok(rules.clone());
+  }
+  let client = MatrixClient::for_account(app, account_key).await?;
+  let rules = match push_rules::fetch_rules(&client).await {
+    Ok(rules) => rules,
+    Err(err) if err.is_network() => return cached.ok_or_else(|| err.to_string()),
+    Err(err) => return Err(err.to_string()),
+  };
+  let path = index_db_path(app)?;
+  let store_account = account_key.to_string();
+  let stored = rules.clone();
+  let changed = tauri::async_runtime::spawn_blocking(move || -> Result<Option<account_data::AccountDataEntry>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    account_data::store(&conn, &store_account, push_rules::PUSH_RULES_TYPE, None, &stored)
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  emit_account_data_changes(app, changed.into_iter().collect())?;
+  Ok(rules)
+}
+
+/// Per-room notification and highlight counts computed locally from push rules, `m.mentions`
+/// and the cached timeline, so badges stay correct while the webview is suspended.
+#[tauri::command]
+async fn get_notification_counts(
+  app: AppHandle,
+  account_key: String,
+  room_ids: Option<Vec<String>>,
+  refresh_rules: Option<bool>,
+) -> Result<Vec<push_rules::RoomNotificationCounts>, String> {
+  let user_id = read_accounts_map(&app)
+    .await?
+    .get(&account_key)
+    .map(|creds| creds.user_id.clone())
+    .ok_or_else(|| format!("Unknown account: {}", account_key))?;
+  let rules = push_rules_for(&app, &account_key, refresh_rules.unwrap_or(false)).await?;
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<Vec<push_rules::RoomNotificationCounts>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    let room_ids = match room_ids {
+      Some(room_ids) => room_ids,
+      None => event_cache::cached_room_ids(&conn, &account_key)?,
+    };
+    room_ids
+      .iter()
+      .map(|room_id| push_rules::room_counts(&conn, &account_key, &rules, room_id, &user_id))
+      .collect()
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CachedMediaResponse {
+  #[serde(flatten)]
+  entry: media_cache::MediaCacheEntry,
+  data_base64: String,
+}
+
+/// Media bytes from the encrypted cache, downloading and caching them on a miss unless `cache_only` is set.
+/// With `file` the download is an encrypted attachment and only its plaintext is cached.
+async fn load_media(
+  app: &AppHandle,
+  account_key: &str,
+  mxc_url: &str,
+  file: Option<&attachment_crypto::EncryptedFile>,
+  cache_only: bool,
+) -> Result<Option<(media_cache::MediaCacheEntry, Vec<u8>)>, String> {
+  let key = media_cache_key(app).await?;
+  let dir = media_cache_dir(app)?;
+  let path = index_db_path(app)?;
+  let (lookup_path, lookup_dir, lookup_mxc) = (path.clone(), dir.clone(), mxc_url.to_string());
+  let cached = tauri::async_runtime::spawn_blocking(move || -> Result<Option<(media_cache::MediaCacheEntry, Vec<u8>)>, String> {
+    let conn = Connection::open(lookup_path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    media_cache::get(&conn, &lookup_dir, &key, &lookup_mxc)
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  if cached.is_some() || cache_only {
+    return Ok(cached);
+  }
+  let client = MatrixClient::for_account(app, account_key).await?;
+  let (mut bytes, mut content_type) = client.download(mxc_url).await?;
+  let mxc_url = mxc_url.to_string();
+  let file = file.cloned();
+  tauri::async_runtime::spawn_blocking(move || -> Result<Option<(media_cache::MediaCacheEntry, Vec<u8>)>, String> {
+    if let Some(file) = &file {
+      bytes = attachment_crypto::decrypt(&bytes, file)?;
+      content_type = image::guess_format(&bytes).ok().map(|f| f.to_mime_type().to_string());
+    }
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    let entry = media_cache::put(&conn, &dir, &key, &mxc_url, content_type.as_deref(), &bytes)?;
+    Ok(Some((entry, bytes)))
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+async fn get_cached_media(
+  app: AppHandle,
+  account_key: String,
+  mxc_url: String,
+  file: Option<attachment_crypto::EncryptedFile>,
+  cache_only: Option<bool>,
+) -> Result<Option<CachedMediaResponse>, String> {
+  let loaded = load_media(&app, &account_key, &mxc_url, file.as_ref(), cache_only.unwrap_or(false)).await?;
+  Ok(loaded.map(|(entry, bytes)| CachedMediaResponse {
+    entry,
+    data_base64: general_purpose::STANDARD.encode(bytes),
+  }))
+}
+
+#[tauri::command]
+async fn clear_media_cache(app: AppHandle) -> Result<usize, String> {
+  let dir = media_cache_dir(&app)?;
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<usize, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    media_cache::clear(&conn, &dir)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+async fn get_media_cache_stats(app: AppHandle) -> Result<media_cache::MediaCacheStats, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<media_cache::MediaCacheStats, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    media_cache::stats(&conn)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+async fn set_media_cache_limit(app: AppHandle, limit_bytes: i64) -> Result<media_cache::MediaCacheStats, String> {
+  let dir = media_cache_dir(&app)?;
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<media_cache::MediaCacheStats, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    media_cache::set_limit(&conn, &dir, limit_bytes)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Managed directory downloads without an explicit destination are written to.
+fn downloads_dir(app: &AppHandle) -> Result<PathBuf, String> {
+  app
+    .path_resolver()
+    .app_data_dir()
+    .map(|dir| dir.join("downloads"))
+    .ok_or_else(|| "Unable to resolve application data directory".to_string())
+}
+
+/// Stream media to disk with resume support, emitting `media://download-progress`. With
+/// download scanning enabled the file is scanned in a staging directory first; infected files
+/// (and unscanned risky ones when scanning is required) go to quarantine, emitting
+/// `media://quarantined`, and the download fails. Every download is recorded for
+/// `list_downloads`, emitting `media://downloads-changed` when it starts and ends.
+#[tauri::command]
+async fn download_media(
+  app: AppHandle,
+  state: tauri::State<'_, media_download::DownloadState>,
+  account_key: String,
+  request: media_download::DownloadRequest,
+) -> Result<media_download::DownloadResult, String> {
+  run_media_download(&app, &state, account_key, request).await
+}
+
+/// Continue a cancelled, failed or interrupted download from its partial file.
+#[tauri::command]
+async fn resume_download(
+  app: AppHandle,
+  state: tauri::State<'_, media_download::DownloadState>,
+  download_id: String,
+) -> Result<media_download::DownloadResult, String> {
+  let id = download_id.clone();
+  let (account_key, request) = with_index_db(&app, move |conn| media_download::stored_request(conn, &id))
+    .await?
+    .ok_or_else(|| format!("Unknown download {}", download_id))?;
+  run_media_download(&app, &state, account_key, request).await
+}
+
+async fn run_media_download(
+  app: &AppHandle,
+  state: &media_download::DownloadState,
+  account_key: String,
+  mut request: media_download::DownloadRequest,
+) -> Result<media_download::DownloadResult, String> {
+  let download_id = media_download::download_id(&request);
+  request.download_id = Some(download_id.clone());
+  let dest = media_download::destination(&request, &downloads_dir(app)?)?;
+  let (start_key, start_request, start_id, start_dest) = (account_key.clone(), request.clone(), download_id.clone(), dest.clone());
+  with_index_db(app, move |conn| media_download::record_start(conn, &start_key, &start_request, &start_id, &start_dest)).await?;
+  let _ = app.emit_all("media://downloads-changed", ());
+
+  let last_progress = std::sync::Arc::new(std::sync::Mutex::new(None));
+  let outcome = fetch_media_download(app, state, &account_key, &request, dest, last_progress.clone()).await;
+  let progress = last_progress.lock().ok().and_then(|last| last.clone());
+  let outcome = with_index_db(app, move |conn| {
+    if let Err(err) = media_download::record_finish(conn, &download_id, &outcome, progress.as_ref()) {
+      tracing::warn!("Failed to record download {}: {}", download_id, err);
+    }
+    Ok(outcome)
+  })
+  .await?;
+  let _ = app.emit_all("media://downloads-changed", ());
+  outcome
+}
+
+async fn fetch_media_download(
+  app: &AppHandle,
+  state: &media_download::DownloadState,
+  account_key: &str,
+  request: &media_download::DownloadRequest,
+  dest: PathBuf,
+  last_progress: std::sync::Arc<std::sync::Mutex<Option<media_download::DownloadProgress>>>,
+) -> Result<media_download::DownloadResult, String> {
+  let client = MatrixClient::for_account(app, account_key).await?;
+  let path = index_db_path(app)?;
+  let settings_path = path.clone();
+  let settings = tauri::async_runtime::spawn_blocking(move || -> Result<malware_scan::ScanSettings, String> {
+    let conn = Connection::open(settings_path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    malware_scan::settings(&conn)
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  let emitter = app.clone();
+  let on_progress = move |progress: &media_download::DownloadProgress| {
+    if let Ok(mut last) = last_progress.lock() {
+      *last = Some(progress.clone());
+    }
+    let _ = emitter.emit_all("media://download-progress", progress);
+  };
+  if !settings.enabled {
+    return media_download::download(state, &client, request, dest, on_progress).await;
+  }
+
+  let digest = Sha256::digest(format!("{}|{}", request.mxc_url, dest.display()).as_bytes());
+  let staging_name = format!(
+    "{}-{}",
+    digest.iter().take(8).map(|b| format!("{:02x}", b)).collect::<String>(),
+    dest.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default()
+  );
+  let staging = media_cache_dir(app)?.join("scanning").join(staging_name);
+  let mut result = media_download::download(state, &client, request, staging.clone(), on_progress).await?;
+  let quarantine_dir = app
+    .path_resolver()
+    .app_data_dir()
+    .ok_or_else(|| "Unable to resolve application data directory".to_string())?
+    .join("quarantine");
+  let mxc_url = request.mxc_url.clone();
+  let final_path = dest.clone();
+  let (verdict, quarantined) = tauri::async_runtime::spawn_blocking(
+    move || -> Result<(malware_scan::ScanVerdict, Option<malware_scan::QuarantinedFile>), String> {
+      let verdict = malware_scan::scan(&settings, &staging);
+      let file_name = final_path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+      if !malware_scan::must_quarantine(&settings, &verdict, &file_name) {
+        malware_scan::move_file(&staging, &final_path)?;
+        return Ok((verdict, None));
+      }
+      let conn = Connection::open(path).map_err(|e| e.to_string())?;
+      init_index_db(&conn).map_err(|e| e.to_string())?;
+      let entry = malware_scan::quarantine(&conn, &quarantine_dir, &staging, &final_path, Some(&mxc_url), &verdict)?;
+      Ok((verdict, Some(entry)))
+    },
+  )
+  .await
+  .map_err(|e| e.to_string())??;
+  if let Some(entry) = quarantined {
+    let _ = app.emit_all("media://quarantined", &entry);
+    return Err(match verdict {
+      malware_scan::ScanVerdict::Infected { signature, .. } => format!("{} was quarantined: {}", entry.file_name, signature),
+      _ => format!("{} was quarantined because it could not be scanned", entry.file_name),
+    });
+  }
+  result.path = dest.display().to_string();
+  result.scan = Some(verdict);
+  Ok(result)
+}
+
+#[tauri::command]
+async fn get_scan_settings(app: AppHandle) -> Result<malware_scan::ScanSettings, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<malware_scan::ScanSettings, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    malware_scan::settings(&conn)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+async fn set_scan_settings(app: AppHandle, settings: malware_scan::ScanSettings) -> Result<(), String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<(), String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    malware_scan::set_settings(&conn, &settings)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Scan a local file on demand, e.g. to test the scanner configuration.
+#[tauri::command]
+async fn scan_file(app: AppHandle, path: String) -> Result<malware_scan::ScanVerdict, String> {
+  let db_path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<malware_scan::ScanVerdict, String> {
+    let conn = Connection::open(db_path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    let settings = malware_scan::settings(&conn)?;
+    Ok(malware_scan::scan(&settings, std::path::Path::new(&path)))
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+async fn list_quarantine(app: AppHandle) -> Result<Vec<malware_scan::QuarantinedFile>, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<Vec<malware_scan::QuarantinedFile>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    malware_scan::list(&conn)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Move a quarantined file to `dest` (or where it was going) after the user accepts the risk.
+#[tauri::command]
+async fn release_quarantined(app: AppHandle, id: String, dest: Option<String>) -> Result<String, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<String, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    malware_scan::release(&conn, &id, dest.as_deref().map(std::path::Path::new))
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+async fn delete_quarantined(app: AppHandle, id: String) -> Result<(), String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<(), String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    malware_scan::delete(&conn, &id)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+fn cancel_media_download(state: tauri::State<'_, media_download::DownloadState>, download_id: String) -> bool {
+  state.cancel(&download_id)
+}
+
+#[tauri::command]
+async fn list_downloads(
+  app: AppHandle,
+  account_key: Option<String>,
+  room_id: Option<String>,
+) -> Result<Vec<media_download::DownloadRecord>, String> {
+  with_index_db(&app, move |conn| media_download::list(conn, account_key.as_deref(), room_id.as_deref())).await
+}
+
+/// Open a finished download with the system's default application.
+#[tauri::command]
+async fn open_downloaded_file(app: AppHandle, download_id: String) -> Result<(), String> {
+  let id = download_id.clone();
+  let record = with_index_db(&app, move |conn| media_download::get(conn, &id))
+    .await?
+    .ok_or_else(|| format!("Unknown download {}", download_id))?;
+  if record.status != media_download::DownloadStatus::Completed || !std::path::Path::new(&record.path).is_file() {
+    return Err(format!("{} has not been downloaded", record.file_name));
+  }
+  app.opener().open_path(record.path, None::<&str>).map_err(|e| e.to_string())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ThumbnailResponse {
+  cache_key: String,
+  content_type: String,
+  width: u32,
+  height: u32,
+  data_base64: String,
+}
+
+/// Original bytes for a local path or an `mxc://` URI, going through the media cache for the latter.
+async fn media_source_bytes(
+  app: &AppHandle,
+  account_key: Option<&str>,
+  source: &str,
+  file: Option<&attachment_crypto::EncryptedFile>,
+) -> Result<Vec<u8>, String> {
+  if !source.starts_with("mxc://") {
+    return fs::read(source).map_err(|e| format!("Failed to read {}: {}", source, e));
+  }
+  let account_key = account_key.ok_or("An account is required to fetch mxc media")?;
+  load_media(app, account_key, source, file, false)
+    .await?
+    .map(|(_, bytes)| bytes)
+    .ok_or_else(|| format!("Media {} is unavailable", source))
+}
+
+/// Downscale a photo natively (honouring EXIF orientation) and keep the result in the media cache.
+#[tauri::command]
+async fn generate_thumbnail(
+  app: AppHandle,
+  account_key: Option<String>,
+  source: String,
+  file: Option<attachment_crypto::EncryptedFile>,
+  max_width: u32,
+  max_height: u32,
+) -> Result<ThumbnailResponse, String> {
+  let key = media_cache_key(&app).await?;
+  let dir = media_cache_dir(&app)?;
+  let path = index_db_path(&app)?;
+  let cache_key = format!("{}#thumbnail={}x{}", source, max_width, max_height);
+  let (lookup_path, lookup_dir, lookup_key) = (path.clone(), dir.clone(), cache_key.clone());
+  let cached = tauri::async_runtime::spawn_blocking(move || -> Result<Option<(media_cache::MediaCacheEntry, Vec<u8>)>, String> {
+    let conn = Connection::open(lookup_path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    media_cache::get(&conn, &lookup_dir, &key, &lookup_key)
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  if let Some((entry, bytes)) = cached {
+    let (width, height) = image_processing::dimensions(&bytes)?;
+    return Ok(ThumbnailResponse {
+      cache_key,
+      content_type: entry.content_type.unwrap_or_else(|| "image/jpeg".to_string()),
+      width,
+      height,
+      data_base64: general_purpose::STANDARD.encode(bytes),
+    });
+  }
+  let original = media_source_bytes(&app, account_key.as_deref(), &source, file.as_ref()).await?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<ThumbnailResponse, String> {
+    let (bytes, content_type, width, height) = image_processing::thumbnail(&original, max_width, max_height)?;
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    media_cache::put(&conn, &dir, &key, &cache_key, Some(content_type), &bytes)?;
+    Ok(ThumbnailResponse {
+      cache_key,
+      content_type: content_type.to_string(),
+      width,
+      height,
+      data_base64: general_purpose::STANDARD.encode(bytes),
+    })
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DocumentPreviewResponse {
+  info: document_preview::DocumentInfo,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  thumbnail: Option<ThumbnailResponse>,
+}
+
+/// Kind, page count and title of a file message, plus a first-page render for PDFs (via poppler)
+/// or the thumbnail embedded in Office/OpenDocument files. Both are cached like image thumbnails.
+#[tauri::command]
+async fn generate_document_preview(
+  app: AppHandle,
+  account_key: Option<String>,
+  source: String,
+  file: Option<attachment_crypto::EncryptedFile>,
+  mimetype: Option<String>,
+  file_name: Option<String>,
+  max_width: u32,
+  max_height: u32,
+) -> Result<DocumentPreviewResponse, String> {
+  let key = media_cache_key(&app).await?;
+  let dir = media_cache_dir(&app)?;
+  let path = index_db_path(&app)?;
+  let cache_key = format!("{}#thumbnail={}x{}", source, max_width, max_height);
+  let (lookup_path, lookup_dir, lookup_key, lookup_source) = (path.clone(), dir.clone(), cache_key.clone(), source.clone());
+  let cached = tauri::async_runtime::spawn_blocking(
+    move || -> Result<Option<(document_preview::DocumentInfo, Option<(media_cache::MediaCacheEntry, Vec<u8>)>)>, String> {
+      let conn = Connection::open(lookup_path).map_err(|e| e.to_string())?;
+      init_index_db(&conn).map_err(|e| e.to_string())?;
+      let Some(info) = document_preview::cached_info(&conn, &lookup_source)? else {
+        return Ok(None);
+      };
+      let thumbnail = if info.has_preview {
+        match media_cache::get(&conn, &lookup_dir, &key, &lookup_key)? {
+          Some(thumbnail) => Some(thumbnail),
+          None => return Ok(None),
+        }
+      } else {
+        None
+      };
+      Ok(Some((info, thumbnail)))
+    },
+  )
+  .await
+  .map_err(|e| e.to_string())??;
+  if let Some((info, thumbnail)) = cached {
+    let thumbnail = match thumbnail {
+      Some((entry, bytes)) => {
+        let (width, height) = image_processing::dimensions(&bytes)?;
+        Some(ThumbnailResponse {
+          cache_key,
+          content_type: entry.content_type.unwrap_or_else(|| "image/jpeg".to_string()),
+          width,
+          height,
+          data_base64: general_purpose::STANDARD.encode(bytes),
+        })
+      }
+      None => None,
+    };
+    return Ok(DocumentPreviewResponse { info, thumbnail });
+  }
+
+  let original = media_source_bytes(&app, account_key.as_deref(), &source, file.as_ref()).await?;
+  let work_dir = dir.join("documents");
+  tauri::async_runtime::spawn_blocking(move || -> Result<DocumentPreviewResponse, String> {
+    let render_size = max_width.max(max_height);
+    let (mut info, preview) =
+      document_preview::inspect(&original, mimetype.as_deref(), file_name.as_deref(), &work_dir, render_size)?;
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    let thumbnail = match preview.map(|bytes| image_processing::thumbnail(&bytes, max_width, max_height)) {
+      Some(Ok((bytes, content_type, width, height))) => {
+        media_cache::put(&conn, &dir, &key, &cache_key, Some(content_type), &bytes)?;
+        Some(ThumbnailResponse {
+          cache_key,
+          content_type: content_type.to_string(),
+          width,
+          height,
+          data_base64: general_purpose::STANDARD.encode(bytes),
+        })
+      }
+      _ => None,
+    };
+    info.has_preview = thumbnail.is_some();
+    document_preview::store_info(&conn, &source, &info)?;
+    Ok(DocumentPreviewResponse { info, thumbnail })
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Upload a local file, returning its `mxc://` URI with an `info` block that already carries
+/// dimensions, blurhash and dominant colour for images. Room or global upload settings
+/// (metadata stripping, compression) apply; `strip_metadata` overrides them for this send.
+/// With `encrypted` the file is AES-CTR encrypted before upload and the result carries the
+/// `file` block for an encrypted room instead of a plain `url`. The transfer streams in chunks,
+/// emits `media://upload-progress`, retries transient failures and can be cancelled by `upload_id`.
+#[tauri::command]
+async fn upload_media(
+  app: AppHandle,
+  state: tauri::State<'_, media_upload::UploadState>,
+  account_key: String,
+  path: String,
+  room_id: Option<String>,
+  strip_metadata: Option<bool>,
+  encrypted: Option<bool>,
+  upload_id: Option<String>,
+) -> Result<media_upload::UploadedMedia, String> {
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  let db_path = index_db_path(&app)?;
+  let homeserver_url = client.homeserver_url.clone();
+  let prepared = tauri::async_runtime::spawn_blocking(move || -> Result<media_upload::PreparedUpload, String> {
+    let conn = Connection::open(db_path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    let mut settings = media_upload::settings(&conn, room_id.as_deref())?;
+    if let Some(strip) = strip_metadata {
+      settings.strip_metadata = strip;
+    }
+    let max_upload_size = capabilities::cached(&conn, &homeserver_url)?.and_then(|c| c.features.max_upload_size);
+    media_upload::prepare(std::path::Path::new(&path), &settings, max_upload_size)
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  let emitter = app.clone();
+  let on_progress = move |progress: &media_upload::UploadProgress| {
+    let _ = emitter.emit_all("media://upload-progress", progress);
+  };
+  if encrypted.unwrap_or(false) {
+    let (ciphertext, mut file) = attachment_crypto::encrypt(&prepared.bytes);
+    let content_uri =
+      media_upload::transfer(&state, &client, upload_id, ciphertext, "application/octet-stream", None, on_progress).await?;
+    file.url = content_uri.clone();
+    return Ok(media_upload::uploaded(&prepared, content_uri, Some(file)));
+  }
+  let content_uri = media_upload::transfer(
+    &state,
+    &client,
+    upload_id,
+    prepared.bytes.clone(),
+    &prepared.mimetype,
+    Some(&prepared.file_name),
+    on_progress,
+  )
+  .await?;
+  Ok(media_upload::uploaded(&prepared, content_uri, None))
+}
+
+#[tauri::command]
+fn cancel_media_upload(state: tauri::State<'_, media_upload::UploadState>, upload_id: String) -> bool {
+  state.cancel(&upload_id)
+}
+
+#[tauri::command]
+async fn get_upload_settings(app: AppHandle, room_id: Option<String>) -> Result<media_upload::UploadSettings, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<media_upload::UploadSettings, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    media_upload::settings(&conn, room_id.as_deref())
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Save global upload settings, or a room override when `room_id` is given (`None` settings remove it).
+#[tauri::command]
+async fn set_upload_settings(
+  app: AppHandle,
+  room_id: Option<String>,
+  settings: Option<media_upload::UploadSettings>,
+) -> Result<(), String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<(), String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    media_upload::set_settings(&conn, room_id.as_deref(), settings.as_ref())
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Extract a poster frame, duration and dimensions with ffmpeg, transcoding to H.264/AAC when
+/// requested or when the file exceeds the homeserver's upload limit.
+#[tauri::command]
+async fn process_video(
+  app: AppHandle,
+  account_key: Option<String>,
+  path: String,
+  transcode: Option<bool>,
+) -> Result<video::ProcessedVideo, String> {
+  let homeserver_url = match &account_key {
+    Some(account_key) => read_accounts_map(&app).await?.get(account_key).map(|creds| norm_hs(&creds.homeserver_url)),
+    None => None,
+  };
+  let work_dir = media_cache_dir(&app)?.join("video");
+  let db_path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<video::ProcessedVideo, String> {
+    let max_upload_size = match homeserver_url {
+      Some(homeserver_url) => {
+        let conn = Connection::open(db_path).map_err(|e| e.to_string())?;
+        init_index_db(&conn).map_err(|e| e.to_string())?;
+        capabilities::cached(&conn, &homeserver_url)?.and_then(|c| c.features.max_upload_size)
+      }
+      None => None,
+    };
+    video::process(std::path::Path::new(&path), &work_dir, transcode.unwrap_or(false), max_upload_size)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Start capturing a voice message from the default microphone; returns the start timestamp.
+#[tauri::command]
+fn start_voice_recording(state: tauri::State<'_, voice::VoiceRecorderState>) -> Result<i64, String> {
+  state.start()
+}
+
+/// Stop capturing and return the Ogg/Opus file with duration and waveform, ready to send as an
+/// `m.audio` event with `org.matrix.msc3245.voice`.
+#[tauri::command]
+async fn stop_voice_recording(app: AppHandle) -> Result<voice::VoiceRecording, String> {
+  let out_dir = media_cache_dir(&app)?.join("voice");
+  tauri::async_runtime::spawn_blocking(move || {
+    let state = app.state::<voice::VoiceRecorderState>();
+    state.stop(&out_dir)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+fn cancel_voice_recording(state: tauri::State<'_, voice::VoiceRecorderState>) -> Result<bool, String> {
+  state.cancel()
+}
+
+/// Save the image on the OS clipboard to the media cache for the composer preview; `None` when
+/// the clipboard holds no image. The returned path can be passed straight to `upload_media`.
+#[tauri::command]
+async fn read_clipboard_image(app: AppHandle) -> Result<Option<clipboard::ClipboardImage>, String> {
+  let out_dir = media_cache_dir(&app)?.join("clipboard");
+  tauri::async_runtime::spawn_blocking(move || clipboard::read_image(&out_dir))
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+/// Inspect dropped files in parallel (hash, type, size against the server limit, preview
+/// thumbnail) and stage them for the composer. Each file is announced with `media://staged` as
+/// soon as it is ready; failures are reported with `media://stage-failed`.
+#[tauri::command]
+async fn stage_files(
+  app: AppHandle,
+  state: tauri::State<'_, upload_staging::StagingState>,
+  account_key: Option<String>,
+  room_id: Option<String>,
+  paths: Vec<String>,
+) -> Result<Vec<upload_staging::StagedUpload>, String> {
+  let homeserver_url = match &account_key {
+    Some(account_key) => read_accounts_map(&app).await?.get(account_key).map(|creds| norm_hs(&creds.homeserver_url)),
+    None => None,
+  };
+  let db_path = index_db_path(&app)?;
+  let max_upload_size = tauri::async_runtime::spawn_blocking(move || -> Result<Option<u64>, String> {
+    let Some(homeserver_url) = homeserver_url else {
+      return Ok(None);
+    };
+    let conn = Connection::open(db_path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    Ok(capabilities::cached(&conn, &homeserver_url)?.and_then(|c| c.features.max_upload_size))
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  let thumb_dir = media_cache_dir(&app)?.join("staging");
+  let tasks: Vec<_> = paths
+    .into_iter()
+    .map(|path| {
+      let (room_id, thumb_dir) = (room_id.clone(), thumb_dir.clone());
+      let handle = tauri::async_runtime::spawn_blocking({
+        let path = path.clone();
+        move || upload_staging::inspect(std::path::Path::new(&path), room_id.as_deref(), max_upload_size, &thumb_dir)
+      });
+      (path, handle)
+    })
+    .collect();
+  let mut staged = Vec::new();
+  for (path, handle) in tasks {
+    match handle.await.map_err(|e| e.to_string())? {
+      Ok(upload) => {
+        let upload = state.add(upload)?;
+        let _ = app.emit_all("media://staged", &upload);
+        staged.push(upload);
+      }
+      Err(error) => {
+        let _ = app.emit_all("media://stage-failed", json!({ "path": path, "error": error }));
+      }
+    }
+  }
+  Ok(staged)
+}
+
+/// Save the composer state of a room. `staged_attachments` are staged upload IDs; their records
+/// are stored with the draft so they can be staged again after a restart.
+#[tauri::command]
+async fn save_draft(
+  app: AppHandle,
+  state: tauri::State<'_, upload_staging::StagingState>,
+  account_key: String,
+  room_id: String,
+  content: serde_json::Value,
+  reply_to: Option<String>,
+  staged_attachments: Vec<String>,
+) -> Result<Option<drafts::Draft>, String> {
+  let mut attachments = Vec::new();
+  for staged_id in &staged_attachments {
+    attachments.push(
+      state
+        .get(staged_id)?
+        .ok_or_else(|| format!("Unknown staged upload: {}", staged_id))?,
+    );
+  }
+  with_index_db(&app, move |conn| {
+    drafts::save(conn, &account_key, &room_id, &content, reply_to.as_deref(), &attachments)
+  })
+  .await
+}
+
+/// The saved draft of a room, with its attachments staged again for the composer.
+#[tauri::command]
+async fn get_draft(
+  app: AppHandle,
+  state: tauri::State<'_, upload_staging::StagingState>,
+  account_key: String,
+  room_id: String,
+) -> Result<Option<drafts::Draft>, String> {
+  let Some(mut draft) = with_index_db(&app, move |conn| drafts::get(conn, &account_key, &room_id)).await? else {
+    return Ok(None);
+  };
+  drafts::drop_missing(&mut draft);
+  for upload in &draft.attachments {
+    if state.get(&upload.staged_id)?.is_none() {
+      state.add(upload.clone())?;
+    }
+  }
+  Ok(Some(draft))
+}
+
+#[tauri::command]
+async fn list_drafts(app: AppHandle, account_key: String) -> Result<Vec<drafts::Draft>, String> {
+  with_index_db(&app, move |conn| drafts::list(conn, &account_key)).await
+}
+
+#[tauri::command]
+async fn clear_draft(app: AppHandle, account_key: String, room_id: String) -> Result<(), String> {
+  with_index_db(&app, move |conn| drafts::clear(conn, &account_key, &room_id)).await
+}
+
+#[tauri::command]
+fn list_staged_uploads(
+  state: tauri::State<'_, upload_staging::StagingState>,
+  room_id: Option<String>,
+) -> Result<Vec<upload_staging::StagedUpload>, String> {
+  state.list(room_id.as_deref())
+}
+
+#[tauri::command]
+fn discard_staged_upload(state: tauri::State<'_, upload_staging::StagingState>, staged_id: String) -> Result<bool, String> {
+  state.discard(&staged_id)
+}
+
+/// Upload a staged file through the normal upload pipeline and drop it from staging.
+#[tauri::command]
+async fn send_staged_upload(
+  app: AppHandle,
+  state: tauri::State<'_, upload_staging::StagingState>,
+  upload_state: tauri::State<'_, media_upload::UploadState>,
+  account_key: String,
+  staged_id: String,
+  strip_metadata: Option<bool>,
+  encrypted: Option<bool>,
+) -> Result<media_upload::UploadedMedia, String> {
+  let staged = state
+    .get(&staged_id)?
+    .ok_or_else(|| format!("Unknown staged upload: {}", staged_id))?;
+  let uploaded = upload_media(
+    app,
+    upload_state,
+    account_key,
+    staged.path,
+    staged.room_id,
+    strip_metadata,
+    encrypted,
+    Some(staged_id.clone()),
+  )
+  .await?;
+  state.discard(&staged_id)?;
+  Ok(uploaded)
+}
+
+/// Working directories counted in the storage report besides the media cache itself.
+fn storage_dirs(app: &AppHandle) -> Result<Vec<(&'static str, PathBuf)>, String> {
+  let cache_dir = media_cache_dir(app)?;
+  let exports_dir = app
+    .path_resolver()
+    .app_data_dir()
+    .ok_or_else(|| "Unable to resolve application data directory".to_string())?
+    .join("exports");
+  Ok(vec![
+    ("video", cache_dir.join("video")),
+    ("voice", cache_dir.join("voice")),
+    ("clipboard", cache_dir.join("clipboard")),
+    ("staging", cache_dir.join("staging")),
+    ("gifs", cache_dir.join("gifs")),
+    ("exports", exports_dir),
+  ])
+}
+
+/// Disk usage broken down by room, media type and working directory; rooms are limited to
+/// `account_key` when given.
+#[tauri::command]
+async fn get_storage_report(app: AppHandle, account_key: Option<String>) -> Result<storage::StorageReport, String> {
+  let dirs = storage_dirs(&app)?;
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<storage::StorageReport, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    storage::report(&conn, account_key.as_deref(), &dirs)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Evict cached media by age, room or total size and empty selected working directories.
+#[tauri::command]
+async fn cleanup_storage(app: AppHandle, policy: storage::CleanupPolicy) -> Result<storage::CleanupResult, String> {
+  let cache_dir = media_cache_dir(&app)?;
+  let dirs = storage_dirs(&app)?;
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<storage::CleanupResult, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    storage::cleanup(&conn, &cache_dir, &dirs, &policy)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Custom scheme the webview uses for `<img>`/`<video>` sources:
+/// `app-media://localhost/<server>/<media_id>?account=<account key>`.
+const MEDIA_SCHEME: &str = "app-media";
+
+/// Resolve an `app-media://` request through the media cache and authenticated download
+/// pipeline. Encrypted attachments are served once their plaintext is in the cache.
+async fn resolve_media_request(app: &AppHandle, uri: &tauri::http::Uri) -> Result<(Vec<u8>, Option<String>), String> {
+  let mut segments = uri.path().trim_start_matches('/').splitn(2, '/');
+  let (server, media_id) = match (segments.next(), segments.next()) {
+    (Some(server), Some(media_id)) if !server.is_empty() && !media_id.is_empty() => (
+      urlencoding::decode(server).map_err(|e| e.to_string())?.into_owned(),
+      urlencoding::decode(media_id).map_err(|e| e.to_string())?.into_owned(),
+    ),
+    _ => return Err(format!("Invalid media path: {}", uri.path())),
+  };
+  let query: HashMap<String, String> = uri
+    .query()
+    .unwrap_or_default()
+    .split('&')
+    .filter_map(|pair| pair.split_once('='))
+    .filter_map(|(k, v)| Some((k.to_string(), urlencoding::decode(v).ok()?.into_owned())))
+    .collect();
+  let account_key = match query.get("account") {
+    Some(account_key) => account_key.clone(),
+    None => {
+      let accounts = read_accounts_map(app).await?;
+      match accounts.keys().next() {
+        Some(key) if accounts.len() == 1 => key.clone(),
+        _ => return Err("The media URL must name an account".to_string()),
+      }
+    }
+  };
+  let mxc_url = format!("mxc://{}/{}", server, media_id);
+  if let Some((entry, bytes)) = load_media(app, &account_key, &mxc_url, None, true).await? {
+    return Ok((bytes, entry.content_type));
+  }
+  // The URL carries no key material. Encrypted attachments are decrypted with the cached
+  // event's `file`; without it they are refused rather than cached as ciphertext.
+  let (lookup_account, lookup_mxc) = (account_key.clone(), mxc_url.clone());
+  let (file, refused) = with_index_db(app, move |conn| {
+    let file = event_cache::encrypted_file(conn, &lookup_account, &lookup_mxc)?
+      .and_then(|file| serde_json::from_value::<attachment_crypto::EncryptedFile>(file).ok());
+    let refused = file.is_none() && event_cache::in_encrypted_room(conn, &lookup_account, &lookup_mxc)?;
+    Ok((file, refused))
+  })
+  .await?;
+  if refused {
+    return Err(format!("Media {} is encrypted and not cached", mxc_url));
+  }
+  let (entry, bytes) = load_media(app, &account_key, &mxc_url, file.as_ref(), false)
+    .await?
+    .ok_or_else(|| format!("Media {} is unavailable", mxc_url))?;
+  Ok((bytes, entry.content_type))
+}
+
+/// Origins the app's own pages are served from. Anything else loaded in the webview (link
+/// previews, iframes) gets no CORS access to decrypted media.
+const APP_ORIGINS: &[&str] = &["tauri://localhost", "http://tauri.localhost", "https://tauri.localhost"];
+const DEV_ORIGIN: &str = "http://localhost:1420";
+
+fn is_app_origin(origin: &str) -> bool {
+  APP_ORIGINS.contains(&origin) || (cfg!(debug_assertions) && origin == DEV_ORIGIN)
+}
+
+/// The inclusive byte range a single-range `Range: bytes=...` header asks for out of `len`
+/// bytes. `None` means the header is ignored and the whole body is served; `Some(None)` means
+/// the range can't be satisfied.
+fn parse_byte_range(header: &str, len: usize) -> Option<Option<(usize, usize)>> {
+  let spec = header.trim().strip_prefix("bytes=")?;
+  if spec.contains(',') {
+    return None;
+  }
+  let (start, end) = spec.split_once('-')?;
+  let (start, end) = (start.trim(), end.trim());
+  if start.is_empty() {
+    let suffix: usize = end.parse().ok()?;
+    if suffix == 0 || len == 0 {
+      return Some(None);
+    }
+    return Some(Some((len.saturating_sub(suffix), len - 1)));
+  }
+  let start: usize = start.parse().ok()?;
+  let end = match end {
+    "" => None,
+    end => Some(end.parse::<usize>().ok()?),
+  };
+  if end.is_some_and(|end| end < start) {
+    return None;
+  }
+  if start >= len {
+    return Some(None);
+  }
+  Some(Some((start, end.map_or(len - 1, |end| end.min(len - 1)))))
+}
+
+fn media_response(
+  origin: Option<&str>,
+  range: Option<&str>,
+  result: Result<(Vec<u8>, Option<String>), String>,
+) -> tauri::http::Response<Vec<u8>> {
+  let mut builder = tauri::http::Response::builder().header("Vary", "Origin");
+  if let Some(origin) = origin.filter(|origin| is_app_origin(origin)) {
+    builder = builder.header("Access-Control-Allow-Origin", origin);
+  }
+  let response = match result {
+    Ok((bytes, content_type)) => {
+      let len = bytes.len();
+      let builder = builder
+        .header("Content-Type", content_type.unwrap_or_else(|| "application/octet-stream".to_string()))
+        .header("Cache-Control", "private, max-age=31536000, immutable")
+        .header("Accept-Ranges", "bytes");
+      // Video elements seek with range requests.
+      match range.and_then(|range| parse_byte_range(range, len)) {
+        None => builder.status(200).body(bytes),
+        Some(Some((start, end))) => builder
+          .status(206)
+          .header("Content-Range", format!("bytes {}-{}/{}", start, end, len))
+          .body(bytes[start..=end].to_vec()),
+        Some(None) => builder
+          .status(416)
+          .header("Content-Range", format!("bytes */{}", len))
+          .body(Vec::new()),
+      }
+    }
+    Err(err) => builder
+      .status(404)
+      .header("Content-Type", "text/plain")
+      .body(err.into_bytes()),
+  };
+  response.unwrap_or_default()
+}
+
+/// Write one indexed media item, decrypted, under its original file name. `dest` may be a
+/// file path or a directory.
+#[tauri::command]
+async fn save_media_as(
+  app: AppHandle,
+  account_key: String,
+  media_id: String,
+  dest: String,
+) -> Result<media_export::ExportedFile, String> {
+  let path = index_db_path(&app)?;
+  let item_account = account_key.clone();
+  let item = tauri::async_runtime::spawn_blocking(move || -> Result<Option<media_export::ExportItem>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    media_export::item(&conn, &item_account, &media_id)
+  })
+  .await
+  .map_err(|e| e.to_string())??
+  .ok_or_else(|| "Unknown media item".to_string())?;
+  let (_, bytes) = load_media(&app, &account_key, &item.mxc_url, item.file.as_ref(), false)
+    .await?
+    .ok_or_else(|| format!("Media {} is unavailable", item.mxc_url))?;
+  tauri::async_runtime::spawn_blocking(move || {
+    let dest = PathBuf::from(dest);
+    let target = if dest.is_dir() {
+      media_export::unique_path(&dest, &media_export::original_name(&item))
+    } else {
+      dest
+    };
+    media_export::write_with_sidecar(&target, &bytes, &item)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Export every media item of a room matching `filters` into `dest_dir`, emitting
+/// `media://export-progress`. Items that fail are skipped and counted.
+#[tauri::command]
+async fn export_room_media(
+  app: AppHandle,
+  account_key: String,
+  room_id: String,
+  dest_dir: String,
+  filters: Option<media_export::ExportFilters>,
+  export_id: Option<String>,
+) -> Result<Vec<media_export::ExportedFile>, String> {
+  let path = index_db_path(&app)?;
+  let query_room = room_id.clone();
+  let query_account = account_key.clone();
+  let items = tauri::async_runtime::spawn_blocking(move || -> Result<Vec<media_export::ExportItem>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    media_export::room_items(&conn, &query_account, &query_room, &filters.unwrap_or_default())
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  let dest_dir = PathBuf::from(dest_dir);
+  fs::create_dir_all(&dest_dir).map_err(|e| e.to_string())?;
+  let mut progress = media_export::ExportProgress {
+    export_id: export_id.unwrap_or_else(|| format!("export-{}", now_millis())),
+    room_id,
+    completed: 0,
+    failed: 0,
+    total: items.len(),
+    current: None,
+    done: false,
+  };
+  let mut exported = Vec::new();
+  for item in items {
+    progress.current = Some(item.id.clone());
+    let _ = app.emit_all("media://export-progress", &progress);
+    let written = match load_media(&app, &account_key, &item.mxc_url, item.file.as_ref(), false).await {
+      Ok(Some((_, bytes))) => {
+        let target = media_export::unique_path(&dest_dir, &media_export::original_name(&item));
+        media_export::write_with_sidecar(&target, &bytes, &item)
+      }
+      Ok(None) => Err(format!("Media {} is unavailable", item.mxc_url)),
+      Err(err) => Err(err),
+    };
+    match written {
+      Ok(file) => {
+        exported.push(file);
+        progress.completed += 1;
+      }
+      Err(err) => {
+        tracing::warn!("Failed to export {}: {}", item.id, err);
+        progress.failed += 1;
+      }
+    }
+  }
+  progress.current = None;
+  progress.done = true;
+  let _ = app.emit_all("media://export-progress", &progress);
+  Ok(exported)
+}
+
+/// Refresh the personal image pack, the globally enabled room packs and the packs of `room_ids`
+/// into the local index.
+#[tauri::command]
+async fn sync_image_packs(
+  app: AppHandle,
+  account_key: String,
+  room_ids: Option<Vec<String>>,
+) -> Result<Vec<image_packs::ImagePack>, String> {
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  let user_pack = account_data::fetch(&client, image_packs::USER_EMOTES_TYPE, None).await?;
+  let emote_rooms = account_data::fetch(&client, image_packs::EMOTE_ROOMS_TYPE, None).await?;
+  let mut rooms: Vec<String> = room_ids.unwrap_or_default();
+  if let Some(content) = &emote_rooms {
+    for (room_id, _) in image_packs::enabled_rooms(content) {
+      if !rooms.contains(&room_id) {
+        rooms.push(room_id);
+      }
+    }
+  }
+  let mut room_packs = Vec::new();
+  for room_id in rooms {
+    match image_packs::fetch_room_packs(&client, &room_id).await {
+      Ok(packs) => room_packs.push((room_id, packs)),
+      Err(err) => tracing::warn!("Failed to fetch image packs for {}: {}", room_id, err),
+    }
+  }
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<Vec<image_packs::ImagePack>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    if let Some(content) = &emote_rooms {
+      account_data::store(&conn, &account_key, image_packs::EMOTE_ROOMS_TYPE, None, content)?;
+    }
+    if let Some(content) = &user_pack {
+      image_packs::store_pack(&conn, &account_key, &image_packs::PackTarget::User, content)?;
+    }
+    for (room_id, packs) in &room_packs {
+      let mut keep = Vec::new();
+      for (state_key, content) in packs {
+        let target = image_packs::PackTarget::Room { room_id: room_id.clone(), state_key: state_key.clone() };
+        keep.push(image_packs::store_pack(&conn, &account_key, &target, content)?.pack_key);
+      }
+      image_packs::prune_room_packs(&conn, &account_key, room_id, &keep)?;
+    }
+    image_packs::list(&conn, &account_key, None)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+async fn list_image_packs(
+  app: AppHandle,
+  account_key: String,
+  room_id: Option<String>,
+) -> Result<Vec<image_packs::ImagePack>, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<Vec<image_packs::ImagePack>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    image_packs::list(&conn, &account_key, room_id.as_deref())
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Shortcode autocomplete over the locally indexed packs; `usage` is `emoticon` or `sticker`.
+#[tauri::command]
+async fn search_image_packs(
+  app: AppHandle,
+  account_key: String,
+  query: String,
+  room_id: Option<String>,
+  usage: Option<String>,
+  limit: Option<usize>,
+) -> Result<Vec<image_packs::PackImage>, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<Vec<image_packs::PackImage>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    image_packs::search(&conn, &account_key, room_id.as_deref(), &query, usage.as_deref(), limit.unwrap_or(20))
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Read-modify-write a pack on the homeserver and re-index it.
+async fn update_image_pack(
+  app: &AppHandle,
+  account_key: &str,
+  target: image_packs::PackTarget,
+  update: impl FnOnce(serde_json::Value) -> serde_json::Value,
+) -> Result<image_packs::ImagePack, String> {
+  let client = MatrixClient::for_account(app, account_key).await?;
+  let content = update(image_packs::fetch_content(&client, &target).await?);
+  image_packs::save_content(&client, &target, &content).await?;
+  let path = index_db_path(app)?;
+  let account_key = account_key.to_string();
+  tauri::async_runtime::spawn_blocking(move || -> Result<image_packs::ImagePack, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    image_packs::store_pack(&conn, &account_key, &target, &content)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Create a pack (or update its name, avatar and usage) in account data or room state.
+#[tauri::command]
+async fn save_image_pack(
+  app: AppHandle,
+  account_key: String,
+  target: image_packs::PackTarget,
+  display_name: Option<String>,
+  avatar_url: Option<String>,
+  usage: Option<Vec<String>>,
+) -> Result<image_packs::ImagePack, String> {
+  update_image_pack(&app, &account_key, target, |content| {
+    image_packs::with_metadata(content, display_name.as_deref(), avatar_url.as_deref(), usage.as_deref())
+  })
+  .await
+}
+
+/// Upload an image file and add it to a pack under `shortcode`.
+#[tauri::command]
+async fn add_image_to_pack(
+  app: AppHandle,
+  account_key: String,
+  target: image_packs::PackTarget,
+  shortcode: String,
+  path: String,
+  body: Option<String>,
+  usage: Option<Vec<String>>,
+) -> Result<image_packs::ImagePack, String> {
+  let shortcode = shortcode.trim_matches(':').to_string();
+  if shortcode.is_empty() || shortcode.contains(char::is_whitespace) {
+    return Err("Shortcodes must be non-empty and contain no spaces".to_string());
+  }
+  let (bytes, mimetype, image) = tauri::async_runtime::spawn_blocking(move || -> Result<(Vec<u8>, String, Option<media_upload::ImageMetadata>), String> {
+    let path = std::path::Path::new(&path);
+    let bytes = fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let mimetype = media_upload::guess_mimetype(path, &bytes);
+    if !mimetype.starts_with("image/") {
+      return Err(format!("{} is not an image", path.display()));
+    }
+    let image = media_upload::image_metadata(&bytes);
+    Ok((bytes, mimetype, image))
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  let mut info = json!({ "mimetype": mimetype, "size": bytes.len() });
+  if let Some(image) = image {
+    info["w"] = json!(image.width);
+    info["h"] = json!(image.height);
+  }
+  let url = client.upload(bytes, &mimetype, Some(&shortcode)).await?;
+  update_image_pack(&app, &account_key, target, |content| {
+    image_packs::with_image(content, &shortcode, &url, body.as_deref(), &info, usage.as_deref())
+  })
+  .await
+}
+
+#[tauri::command]
+async fn remove_image_from_pack(
+  app: AppHandle,
+  account_key: String,
+  target: image_packs::PackTarget,
+  shortcode: String,
+) -> Result<image_packs::ImagePack, String> {
+  update_image_pack(&app, &account_key, target, |content| image_packs::without_image(content, &shortcode)).await
+}
+
+/// Send a pack image as an `m.sticker` event.
+#[tauri::command]
+async fn send_sticker(
+  app: AppHandle,
+  account_key: String,
+  room_id: String,
+  pack_key: String,
+  shortcode: String,
+) -> Result<String, String> {
+  let path = index_db_path(&app)?;
+  let lookup_key = account_key.clone();
+  let image = tauri::async_runtime::spawn_blocking(move || -> Result<Option<image_packs::PackImage>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    image_packs::find_image(&conn, &lookup_key, &pack_key, &shortcode)
+  })
+  .await
+  .map_err(|e| e.to_string())??
+  .ok_or_else(|| "Unknown sticker".to_string())?;
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  Ok(client.send_event(&room_id, "m.sticker", &image_packs::sticker_content(&image)).await?)
+}
+
+/// `formatted_body` with `:shortcode:` replaced by custom emoji available in the room, or
+/// `None` when the text uses none.
+#[tauri::command]
+async fn render_custom_emoji(
+  app: AppHandle,
+  account_key: String,
+  room_id: Option<String>,
+  body: String,
+) -> Result<Option<String>, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<Option<String>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    let images: Vec<image_packs::PackImage> = image_packs::list(&conn, &account_key, room_id.as_deref())?
+      .into_iter()
+      .flat_map(|pack| pack.images)
+      .collect();
+    Ok(image_packs::render_emoji(&body, &images))
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+async fn read_gif_provider(app: &AppHandle) -> Result<Option<gifs::GifProviderConfig>, String> {
+  let store = StoreBuilder::new(app, STORE_FILE)
+      .build()
+      .map_err(|e| e.to_string())?;
+  Ok(store.get(GIF_PROVIDER_KEY).and_then(|v| serde_json::from_value(v.clone()).ok()))
+}
+
+/// Store the GIF provider and its API key in the credential store; `None` disables GIF search.
+#[tauri::command]
+async fn set_gif_provider(app: AppHandle, config: Option<gifs::GifProviderConfig>) -> Result<(), String> {
+  let store = StoreBuilder::new(&app, STORE_FILE)
+      .build()
+      .map_err(|e| e.to_string())?;
+  match config {
+    Some(config) => {
+      let v = serde_json::to_value(&config).map_err(|e| e.to_string())?;
+      store.set(GIF_PROVIDER_KEY.to_string(), v);
+    }
+    None => {
+      store.delete(GIF_PROVIDER_KEY);
+    }
+  }
+  store.save().map_err(|e| e.to_string())
+}
+
+/// The configured provider, without its API key.
+#[tauri::command]
+async fn get_gif_provider(app: AppHandle) -> Result<Option<gifs::GifProvider>, String> {
+  Ok(read_gif_provider(&app).await?.map(|config| config.provider))
+}
+
+/// Search GIFs (trending for an empty term). `page` is the `next` cursor of the previous page.
+/// Results are cached for an hour and previews are downloaded to disk.
+#[tauri::command]
+async fn search_gifs(
+  app: AppHandle,
+  term: String,
+  page: Option<String>,
+  limit: Option<usize>,
+) -> Result<gifs::GifPage, String> {
+  let config = read_gif_provider(&app)
+    .await?
+    .ok_or_else(|| "No GIF provider configured".to_string())?;
+  let limit = limit.unwrap_or(24);
+  let query_key = gifs::query_key(config.provider, &term, page.as_deref(), limit);
+  let path = index_db_path(&app)?;
+  let lookup_path = path.clone();
+  let lookup_key = query_key.clone();
+  let cached = tauri::async_runtime::spawn_blocking(move || -> Result<Option<gifs::GifPage>, String> {
+    let conn = Connection::open(lookup_path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    gifs::cached_page(&conn, &lookup_key)
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+
+  let http = gifs::http_client()?;
+  let mut page = match cached {
+    Some(page) => page,
+    None => {
+      let page = gifs::search(&http, &config, &term, page.as_deref(), limit).await?;
+      let stored = page.clone();
+      tauri::async_runtime::spawn_blocking(move || -> Result<(), String> {
+        let conn = Connection::open(path).map_err(|e| e.to_string())?;
+        init_index_db(&conn).map_err(|e| e.to_string())?;
+        gifs::store_page(&conn, &query_key, &stored)
+      })
+      .await
+      .map_err(|e| e.to_string())??;
+      page
+    }
+  };
+
+  let dir = media_cache_dir(&app)?.join("gifs");
+  let previews = futures_util::future::join_all(
+    page.results.iter().map(|gif| gifs::fetch_to_disk(&http, &dir, &gif.preview_url)),
+  )
+  .await;
+  for (gif, preview) in page.results.iter_mut().zip(previews) {
+    gif.preview_path = preview.ok().map(|p| p.display().to_string());
+  }
+  Ok(page)
+}
+
+/// Download the full GIF so it can be sent with `upload_media`.
+#[tauri::command]
+async fn fetch_gif(app: AppHandle, url: String) -> Result<String, String> {
+  let dir = media_cache_dir(&app)?.join("gifs");
+  let http = gifs::http_client()?;
+  let path = gifs::fetch_to_disk(&http, &dir, &url).await?;
+  Ok(path.display().to_string())
+}
+
+#[tauri::command]
+async fn get_url_preview_settings(app: AppHandle, room_id: Option<String>) -> Result<url_preview::UrlPreviewSettings, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<url_preview::UrlPreviewSettings, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    url_preview::settings(&conn, room_id.as_deref())
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Save the global URL preview settings, or a room override when `room_id` is given (`None` removes it).
+#[tauri::command]
+async fn set_url_preview_settings(
+  app: AppHandle,
+  room_id: Option<String>,
+  settings: Option<url_preview::UrlPreviewSettings>,
+) -> Result<(), String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<(), String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    url_preview::set_settings(&conn, room_id.as_deref(), settings.as_ref())
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Link card data for `url`, or `None` when previews are off for the room. Cached previews are
+/// served while fresh, and stale ones when a refresh fails (e.g. offline).
+#[tauri::command]
+async fn get_url_preview(
+  app: AppHandle,
+  account_key: String,
+  room_id: Option<String>,
+  url: String,
+) -> Result<Option<url_preview::UrlPreview>, String> {
+  let path = index_db_path(&app)?;
+  let lookup_path = path.clone();
+  let (settings, url, cached) = tauri::async_runtime::spawn_blocking(
+    move || -> Result<(url_preview::UrlPreviewSettings, String, Option<url_preview::UrlPreview>), String> {
+      let conn = Connection::open(lookup_path).map_err(|e| e.to_string())?;
+      init_index_db(&conn).map_err(|e| e.to_string())?;
+      let settings = url_preview::settings(&conn, room_id.as_deref())?;
+      let url = if settings.strip_trackers { url_preview::strip_trackers(&url) } else { url };
+      let cached = url_preview::cached(&conn, &url)?;
+      Ok((settings, url, cached))
+    },
+  )
+  .await
+  .map_err(|e| e.to_string())??;
+  if settings.mode == url_preview::UrlPreviewMode::Off {
+    return Ok(None);
+  }
+  if let Some(preview) = cached.as_ref().filter(|preview| !preview.stale) {
+    return Ok(Some(preview.clone()));
+  }
+
+  let fetched = match settings.mode {
+    url_preview::UrlPreviewMode::Homeserver => {
+      let client = MatrixClient::for_account(&app, &account_key).await?;
+      client
+        .preview_url(&url)
+        .await
+        .map(|og| url_preview::from_og(&url, &og))
+        .map_err(String::from)
+    }
+    _ => url_preview::fetch_direct(&url).await,
+  };
+  match fetched {
+    Ok(preview) => {
+      let stored = preview.clone();
+      tauri::async_runtime::spawn_blocking(move || -> Result<(), String> {
+        let conn = Connection::open(path).map_err(|e| e.to_string())?;
+        init_index_db(&conn).map_err(|e| e.to_string())?;
+        url_preview::store(&conn, &stored)
+      })
+      .await
+      .map_err(|e| e.to_string())??;
+      Ok(Some(preview))
+    }
+    Err(_) if cached.is_some() => Ok(cached),
+    Err(e) => Err(e),
+  }
+}
+
+/// Waveform for a received voice message or audio file that arrived without one, decoded
+/// natively (Ogg/Opus) or with ffmpeg and cached per mxc URI.
+#[tauri::command]
+async fn get_audio_waveform(
+  app: AppHandle,
+  account_key: String,
+  mxc_url: String,
+  file: Option<attachment_crypto::EncryptedFile>,
+) -> Result<audio_waveform::AudioWaveform, String> {
+  let path = index_db_path(&app)?;
+  let (lookup_path, lookup_url) = (path.clone(), mxc_url.clone());
+  let cached = tauri::async_runtime::spawn_blocking(move || -> Result<Option<audio_waveform::AudioWaveform>, String> {
+    let conn = Connection::open(lookup_path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    audio_waveform::cached(&conn, &lookup_url)
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  if let Some(waveform) = cached {
+    return Ok(waveform);
+  }
+  let bytes = media_source_bytes(&app, Some(&account_key), &mxc_url, file.as_ref()).await?;
+  let work_dir = media_cache_dir(&app)?.join("voice");
+  tauri::async_runtime::spawn_blocking(move || -> Result<audio_waveform::AudioWaveform, String> {
+    let waveform = audio_waveform::generate(&bytes, &work_dir)?;
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    audio_waveform::store(&conn, &mxc_url, &waveform)?;
+    Ok(waveform)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Export cached room history as a single HTML page, JSON or NDJSON into a new folder under
+/// `dest_dir`, with media copied to `media/` next to it. Emits `room://export-progress` and can
+/// be cancelled by `export_id`.
+#[tauri::command]
+async fn export_room(
+  app: AppHandle,
+  state: tauri::State<'_, room_export::RoomExportState>,
+  account_key: String,
+  room_id: String,
+  format: room_export::ExportFormat,
+  range: Option<room_export::ExportRange>,
+  dest_dir: String,
+  include_media: Option<bool>,
+  export_id: Option<String>,
+) -> Result<room_export::RoomExportResult, String> {
+  let export_id = export_id.unwrap_or_else(|| format!("room-export-{}", now_millis()));
+  let cancelled = state.register(&export_id);
+  let result = run_room_export(&app, &cancelled, &export_id, &account_key, &room_id, format, range, &dest_dir, include_media.unwrap_or(true)).await;
+  state.finish(&export_id);
+  result
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn run_room_export(
+  app: &AppHandle,
+  cancelled: &std::sync::atomic::AtomicBool,
+  export_id: &str,
+  account_key: &str,
+  room_id: &str,
+  format: room_export::ExportFormat,
+  range: Option<room_export::ExportRange>,
+  dest_dir: &str,
+  include_media: bool,
+) -> Result<room_export::RoomExportResult, String> {
+  let path = index_db_path(app)?;
+  let exported_at = now_millis();
+  let query_room = room_id.to_string();
+  let query_account = account_key.to_string();
+  let mut archive = tauri::async_runtime::spawn_blocking(move || -> Result<room_export::RoomArchive, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    room_export::load(&conn, &query_account, &query_room, &range.unwrap_or_default(), exported_at)
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  let folder = format!("{} - {}", media_download::sanitize_file_name(&archive.room_name), exported_at);
+  let out_dir = PathBuf::from(dest_dir).join(folder);
+  let media_dir = out_dir.join("media");
+
+  let mut progress = media_export::ExportProgress {
+    export_id: export_id.to_string(),
+    room_id: room_id.to_string(),
+    completed: 0,
+    failed: 0,
+    total: if include_media { archive.messages.iter().filter(|m| m.media.is_some()).count() } else { 0 },
+    current: None,
+    done: false,
+  };
+  if include_media {
+    fs::create_dir_all(&media_dir).map_err(|e| e.to_string())?;
+    for message in archive.messages.iter_mut() {
+      let Some(media) = message.media.as_mut() else {
+        continue;
+      };
+      if cancelled.load(std::sync::atomic::Ordering::Relaxed) {
+        let _ = fs::remove_dir_all(&out_dir);
+        return Err(format!("Export {} was cancelled", export_id));
+      }
+      progress.current = Some(message.event_id.clone());
+      let _ = app.emit_all("room://export-progress", &progress);
+      let written = match load_media(app, account_key, &media.mxc_url, media.file.as_ref(), false).await {
+        Ok(Some((_, bytes))) => {
+          let target = media_export::unique_path(&media_dir, &media.file_name);
+          fs::write(&target, &bytes).map(|_| target).map_err(|e| e.to_string())
+        }
+        Ok(None) => Err(format!("Media {} is unavailable", media.mxc_url)),
+        Err(err) => Err(err),
+      };
+      match written {
+        Ok(target) => {
+          let name = target.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+          media.path = Some(format!("media/{}", name));
+          progress.completed += 1;
+        }
+        Err(err) => {
+          tracing::warn!("Failed to export media of {}: {}", message.event_id, err);
+          progress.failed += 1;
+        }
+      }
+    }
+  }
+  if cancelled.load(std::sync::atomic::Ordering::Relaxed) {
+    let _ = fs::remove_dir_all(&out_dir);
+    return Err(format!("Export {} was cancelled", export_id));
+  }
+
+  let messages = archive.messages.len();
+  let (media_files, failed_media) = (progress.completed, progress.failed);
+  let archive_path = tauri::async_runtime::spawn_blocking(move || room_export::write(&archive, format, &out_dir))
+    .await
+    .map_err(|e| e.to_string())??;
+  progress.current = None;
+  progress.done = true;
+  let _ = app.emit_all("room://export-progress", &progress);
+  Ok(room_export::RoomExportResult {
+    export_id: export_id.to_string(),
+    path: archive_path,
+    messages,
+    media_files,
+    failed_media,
+  })
+}
+
+#[tauri::command]
+fn cancel_room_export(state: tauri::State<'_, room_export::RoomExportState>, export_id: String) -> bool {
+  state.cancel(&export_id)
+}
+
+async fn read_unified_push_map(app: &AppHandle) -> Result<HashMap<String, unified_push::UnifiedPushRegistration>, String> {
+  let store = StoreBuilder::new(app, STORE_FILE)
+      .build()
+      .map_err(|e| e.to_string())?;
+  match store.get(UNIFIED_PUSH_KEY) {
+    Some(v) => serde_json::from_value(v.clone()).map_err(|e| format!("Corrupt store: {}", e)),
+    None => Ok(HashMap::new()),
+  }
+}
+
+async fn write_unified_push_map(app: &AppHandle, map: &HashMap<String, unified_push::UnifiedPushRegistration>) -> Result<(), String> {
+  let store = StoreBuilder::new(app, STORE_FILE)
+      .build()
+      .map_err(|e| e.to_string())?;
   let v = serde_json::to_value(map).map_err(|e| e.to_string())?;
+  store.set(UNIFIED_PUSH_KEY.to_string(), v);
+  store.save().map_err(|e| e.to_string())
+}
+
+fn unified_push_status(registration: Option<&unified_push::UnifiedPushRegistration>) -> unified_push::UnifiedPushStatus {
+  unified_push::UnifiedPushStatus {
+    registered: registration.is_some(),
+    distributor: registration.map(|r| r.distributor.clone()),
+    endpoint: registration.and_then(|r| r.endpoint.clone()),
+    gateway: registration.and_then(|r| r.gateway.clone()),
+  }
+}
+
+fn pushkey_for(registration: &unified_push::UnifiedPushRegistration, endpoint: &str) -> String {
+  match registration.config.pusher_kind {
+    unified_push::PusherKind::Http => endpoint.to_string(),
+    unified_push::PusherKind::WebPush => registration.keys.p256dh.clone(),
+  }
+}
+
+/// Point the homeserver pusher at a new endpoint and record it as the account's push subscription.
+async fn apply_push_endpoint(app: &AppHandle, account_key: &str, endpoint: &str) -> Result<(), String> {
+  let mut map = read_unified_push_map(app).await?;
+  let mut registration = map
+    .get(account_key)
+    .cloned()
+    .ok_or_else(|| format!("No UnifiedPush registration for {}", account_key))?;
+  let gateway = unified_push::discover_gateway(endpoint).await;
+  let client = MatrixClient::for_account(app, account_key).await?;
+  client
+    .post("/pushers/set", &unified_push::pusher_body(&registration, endpoint, &gateway))
+    .await?;
+  if let Some(previous) = registration.endpoint.as_deref().filter(|previous| *previous != endpoint) {
+    let old_key = pushkey_for(&registration, previous);
+    if old_key != pushkey_for(&registration, endpoint) {
+      let _ = client.post("/pushers/set", &unified_push::removal_body(&old_key)).await;
+    }
+  }
+  registration.endpoint = Some(endpoint.to_string());
+  registration.gateway = Some(gateway);
+  let subscription = StoredPushSubscription {
+    endpoint: endpoint.to_string(),
+    auth: registration.keys.auth.clone(),
+    p256dh: registration.keys.p256dh.clone(),
+    push_key: pushkey_for(&registration, endpoint),
+    expiration_time: None,
+    updated_at: Some(now_millis() as f64),
+  };
+  map.insert(account_key.to_string(), registration.clone());
+  write_unified_push_map(app, &map).await?;
+  let mut accounts = read_accounts_map(app).await?;
+  if let Some(creds) = accounts.get_mut(account_key) {
+    creds.push_subscription = Some(subscription);
+    write_accounts_map(app, &accounts).await?;
+  }
+  let _ = app.emit_all("push://endpoint", json!({ "accountKey": account_key, "status": unified_push_status(Some(&registration)) }));
+  Ok(())
+}
+
+/// Register for push through UnifiedPush: a D-Bus distributor when installed (or required),
+/// otherwise an ntfy topic followed by the app itself. The homeserver pusher is set once the
+/// endpoint is known, which for D-Bus distributors happens asynchronously (`push://endpoint`).
+#[tauri::command]
+async fn register_unified_push(
+  app: AppHandle,
+  state: tauri::State<'_, unified_push::UnifiedPushState>,
+  account_key: String,
+  config: Option<unified_push::UnifiedPushConfig>,
+) -> Result<unified_push::UnifiedPushStatus, String> {
+  let mut map = read_unified_push_map(&app).await?;
+  let existing = map.get(&account_key).cloned();
+  let config = config.or_else(|| existing.as_ref().map(|r| r.config.clone())).unwrap_or_default();
+  let token = existing.as_ref().map(|r| r.token.clone()).unwrap_or_else(unified_push::random_token);
+  let keys = existing.as_ref().map(|r| r.keys.clone()).unwrap_or_else(unified_push::generate_keys);
+
+  let preferred = match existing.as_ref().map(|r| &r.distributor) {
+    Some(unified_push::ActiveDistributor::Dbus { name }) => Some(name.clone()),
+    _ => None,
+  };
+  let dbus_name = match config.distributor {
+    unified_push::DistributorChoice::Embedded => None,
+    unified_push::DistributorChoice::Auto => state.register_dbus(&token, preferred.as_deref()).await.unwrap_or(None),
+    unified_push::DistributorChoice::Dbus => Some(
+      state
+        .register_dbus(&token, preferred.as_deref())
+        .await?
+        .ok_or("No UnifiedPush distributor is installed")?,
+    ),
+  };
+  let (distributor, endpoint) = match dbus_name {
+    Some(name) => {
+      state.stop_embedded(&token);
+      (unified_push::ActiveDistributor::Dbus { name }, None)
+    }
+    None => {
+      let server = config
+        .ntfy_server
+        .clone()
+        .unwrap_or_else(|| unified_push::DEFAULT_NTFY_SERVER.to_string())
+        .trim_end_matches('/')
+        .to_string();
+      let topic = match existing.as_ref().map(|r| &r.distributor) {
+        Some(unified_push::ActiveDistributor::Embedded { server: previous, topic }) if *previous == server => topic.clone(),
+        _ => format!("mm{}", unified_push::random_token()),
+      };
+      state.start_embedded(&server, &topic, &token);
+      let endpoint = format!("{}/{}?up=1", server, topic);
+      (unified_push::ActiveDistributor::Embedded { server, topic }, Some(endpoint))
+    }
+  };
+  let registration = unified_push::UnifiedPushRegistration {
+    token,
+    config,
+    distributor,
+    endpoint: existing.as_ref().and_then(|r| r.endpoint.clone()),
+    gateway: existing.as_ref().and_then(|r| r.gateway.clone()),
+    keys,
+  };
+  map.insert(account_key.clone(), registration.clone());
+  write_unified_push_map(&app, &map).await?;
+  if let Some(endpoint) = endpoint {
+    apply_push_endpoint(&app, &account_key, &endpoint).await?;
+  }
+  let map = read_unified_push_map(&app).await?;
+  Ok(unified_push_status(map.get(&account_key)))
+}
+
+/// Remove the homeserver pusher and the distributor registration.
+#[tauri::command]
+async fn unregister_unified_push(
+  app: AppHandle,
+  state: tauri::State<'_, unified_push::UnifiedPushState>,
+  account_key: String,
+) -> Result<(), String> {
+  let mut map = read_unified_push_map(&app).await?;
+  let Some(registration) = map.remove(&account_key) else {
+    return Ok(());
+  };
+  if let Some(endpoint) = &registration.endpoint {
+    let client = MatrixClient::for_account(&app, &account_key).await?;
+    client
+      .post("/pushers/set", &unified_push::removal_body(&pushkey_for(&registration, endpoint)))
+      .await?;
+  }
+  match &registration.distributor {
+    unified_push::ActiveDistributor::Dbus { name } => state.unregister_dbus(name, &registration.token).await?,
+    unified_push::ActiveDistributor::Embedded { .. } => state.stop_embedded(&registration.token),
+  }
+  write_unified_push_map(&app, &map).await?;
+  let mut accounts = read_accounts_map(&app).await?;
+  if let Some(creds) = accounts.get_mut(&account_key) {
+    creds.push_subscription = None;
+    write_accounts_map(&app, &accounts).await?;
+  }
+  Ok(())
+}
+
+#[tauri::command]
+async fn get_unified_push_status(app: AppHandle, account_key: String) -> Result<unified_push::UnifiedPushStatus, String> {
+  let map = read_unified_push_map(&app).await?;
+  Ok(unified_push_status(map.get(&account_key)))
+}
+
+/// Forward a push payload as `push://notification` and, when the room's local rule allows it,
+/// show it as a native notification with the room's or account's sound. Snoozed rooms and
+/// accounts stay silent; during quiet hours it is dropped or held for the summary.
+async fn show_push_notification(app: &AppHandle, account_key: &str, notification: &unified_push::PushNotification) {
+  let _ = app.emit_all("push://notification", json!({ "accountKey": account_key, "notification": notification }));
+  let sound = match index_db_path(app) {
+    Ok(path) => {
+      let account_key = account_key.to_string();
+      let user_id = user_id_for_key(&account_key).to_string();
+      let candidate = notification.clone();
+      tauri::async_runtime::spawn_blocking(move || -> Result<Option<String>, String> {
+        let conn = Connection::open(path).map_err(|e| e.to_string())?;
+        init_index_db(&conn).map_err(|e| e.to_string())?;
+        let skip = |outcome, reason| {
+          notification_history::record(&conn, &account_key, &candidate, None, None, outcome, Some(reason)).map(|_| None)
+        };
+        if snooze::is_snoozed(&conn, &account_key, candidate.room_id.as_deref())? {
+          return skip(notification_history::NotificationOutcome::Suppressed, "snoozed");
+        }
+        if !notification_rules::evaluate(&conn, &account_key, &candidate, &user_id)? {
+          return skip(notification_history::NotificationOutcome::Suppressed, "roomRule");
+        }
+        let dnd = quiet_hours::settings(&conn)?;
+        if quiet_hours::is_quiet(&dnd, chrono::Local::now()) {
+          if dnd.mode == quiet_hours::QuietMode::Batch {
+            quiet_hours::hold(&conn, &account_key, &candidate)?;
+            return skip(notification_history::NotificationOutcome::Held, "quietHours");
+          }
+          return skip(notification_history::NotificationOutcome::Suppressed, "quietHours");
+        }
+        notification_sounds::resolve(&conn, &account_key, candidate.room_id.as_deref()).map(Some)
+      })
+      .await
+      .map_err(|e| e.to_string())
+      .and_then(|result| result)
+      .unwrap_or_else(|_| Some(notification_sounds::DEFAULT_SOUND.to_string()))
+    }
+    Err(_) => Some(notification_sounds::DEFAULT_SOUND.to_string()),
+  };
+  let Some(sound) = sound else {
+    return;
+  };
+  let Some(grouped) = app.state::<notification_dispatch::NotificationDispatcher>().offer(account_key, notification) else {
+    log_notification(app, account_key, notification, None, notification_history::NotificationOutcome::Grouped).await;
+    return;
+  };
+  if sound != notification_sounds::DEFAULT_SOUND && sound != notification_sounds::SILENT_SOUND {
+    match sounds_dir(app).and_then(|dir| notification_sounds::load(&dir, &sound)) {
+      Ok(samples) => notification_sounds::play(samples),
+      Err(err) => tracing::warn!("Notification sound {} unavailable: {}", sound, err),
+    }
+  }
+  present_notification(app, account_key, grouped).await;
+}
+
+/// Show a room's grouped notification, replacing the previous one where the platform allows.
+async fn present_notification(app: &AppHandle, account_key: &str, grouped: notification_dispatch::GroupedNotification) {
+  let (title, body) = (grouped.title(), grouped.body());
+  log_notification(
+    app,
+    account_key,
+    &grouped.latest,
+    Some((&title, &body)),
+    notification_history::NotificationOutcome::Shown,
+  )
+  .await;
+  let media = notification_media(app, account_key, &grouped.latest).await;
+  if let Some(room_id) = &grouped.latest.room_id {
+    let target = notification_actions::NotificationTarget {
+      account_key: account_key.to_string(),
+      room_id: room_id.clone(),
+      event_id: grouped.latest.event_id.clone(),
+    };
+    let actions = app.state::<notification_actions::NotificationActionState>();
+    if let Some(id) = actions
+      .show(notification_actions::NotificationKind::Message, &title, &body, target, grouped.replaces, &media)
+      .await
+    {
+      app.state::<notification_dispatch::NotificationDispatcher>().displayed(account_key, room_id, id);
+      return;
+    }
+  }
+  show_plain_notification(app, &title, &body, &media);
+}
+
+/// Notification through the plugin, which takes at most one picture as its icon (macOS
+/// always shows the app icon instead).
+fn show_plain_notification(app: &AppHandle, title: &str, body: &str, media: &rich_notifications::NotificationMedia) {
+  let mut builder = app.notification().builder().title(title).body(body);
+  if let Some(icon) = media.icon() {
+    builder = builder.icon(icon.to_string_lossy());
+  }
+  let _ = builder.show();
+}
+
+/// Sender avatar and attached picture for a notification, from the media cache where
+/// possible. Gives up after a few seconds so slow media never delays the notification much.
+async fn notification_media(
+  app: &AppHandle,
+  account_key: &str,
+  notification: &unified_push::PushNotification,
+) -> rich_notifications::NotificationMedia {
+  let (Ok(path), Ok(dir)) = (index_db_path(app), notification_images_dir(app)) else {
+    return rich_notifications::NotificationMedia::default();
+  };
+  let candidate = notification.clone();
+  let lookup_account = account_key.to_string();
+  let lookup_dir = dir.clone();
+  let sources = tauri::async_runtime::spawn_blocking(move || -> Result<_, String> {
+    rich_notifications::prune(&lookup_dir);
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    let settings = rich_notifications::settings(&conn)?;
+    rich_notifications::sources(&conn, &lookup_account, &settings, &candidate)
+  })
+  .await
+  .map_err(|e| e.to_string())
+  .and_then(|result| result);
+  let (avatar, image) = match sources {
+    Ok(sources) => sources,
+    Err(err) => {
+      tracing::warn!("Notification media unavailable: {}", err);
+      return rich_notifications::NotificationMedia::default();
+    }
+  };
+  let fetch = |source: Option<rich_notifications::MediaSource>, avatar: bool| {
+    let dir = dir.clone();
+    async move {
+      let source = source?;
+      let (_, bytes) = load_media(app, account_key, &source.mxc, source.file.as_ref(), false).await.ok()??;
+      tauri::async_runtime::spawn_blocking(move || rich_notifications::write_image(&dir, &source.mxc, &bytes, avatar))
+        .await
+        .ok()?
+        .ok()
+    }
+  };
+  let loaded = tokio::time::timeout(std::time::Duration::from_secs(4), async {
+    let (avatar, image) = futures_util::future::join(fetch(avatar, true), fetch(image, false)).await;
+    rich_notifications::NotificationMedia { avatar, image }
+  })
+  .await;
+  loaded.unwrap_or_default()
+}
+
+/// Add a notification to the history log; failures are only reported on stderr.
+async fn log_notification(
+  app: &AppHandle,
+  account_key: &str,
+  notification: &unified_push::PushNotification,
+  shown: Option<(&str, &str)>,
+  outcome: notification_history::NotificationOutcome,
+) {
+  let Ok(path) = index_db_path(app) else {
+    return;
+  };
+  let account_key = account_key.to_string();
+  let notification = notification.clone();
+  let (title, body) = shown.map(|(t, b)| (t.to_string(), b.to_string())).unzip();
+  let result = tauri::async_runtime::spawn_blocking(move || -> Result<(), String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    notification_history::record(&conn, &account_key, &notification, title.as_deref(), body.as_deref(), outcome, None)
+  })
+  .await
+  .map_err(|e| e.to_string())
+  .and_then(|result| result);
+  if let Err(err) = result {
+    tracing::warn!("Failed to log notification: {}", err);
+  }
+}
+
+/// Release the summaries of rooms whose messages were folded together or held back by the
+/// burst limit.
+async fn run_notification_dispatch(app: AppHandle) {
+  loop {
+    tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+    let due = app.state::<notification_dispatch::NotificationDispatcher>().due();
+    for (account_key, grouped) in due {
+      present_notification(&app, &account_key, grouped).await;
+    }
+  }
+}
+
+fn show_main_window(app: &AppHandle) {
+  if let Some(window) = app.get_webview_window("main") {
+    let _ = window.show();
+    let _ = window.unminimize();
+    let _ = window.set_focus();
+  }
+}
+
+/// Carry out a notification action. Replies to encrypted rooms are handed to the webview,
+/// which holds the room keys.
+async fn perform_notification_action(
+  app: &AppHandle,
+  target: notification_actions::NotificationTarget,
+  action: notification_actions::NotificationAction,
+) -> Result<(), String> {
+  let interaction = match &action {
+    notification_actions::NotificationAction::Reply { .. } => notification_history::NotificationInteraction::Replied,
+    notification_actions::NotificationAction::MarkRead => notification_history::NotificationInteraction::MarkedRead,
+    notification_actions::NotificationAction::OpenRoom | notification_actions::NotificationAction::CallBack => {
+      notification_history::NotificationInteraction::Clicked
+    }
+    notification_actions::NotificationAction::Dismissed => notification_history::NotificationInteraction::Dismissed,
+  };
+  let path = index_db_path(app)?;
+  let logged = target.clone();
+  tauri::async_runtime::spawn_blocking(move || -> Result<(), String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    notification_history::record_interaction(&conn, &logged.account_key, &logged.room_id, logged.event_id.as_deref(), interaction)
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  match action {
+    notification_actions::NotificationAction::Dismissed => Ok(()),
+    notification_actions::NotificationAction::OpenRoom => {
+      show_main_window(app);
+      let _ = app.emit_all("notification://open-room", &target);
+      Ok(())
+    }
+    notification_actions::NotificationAction::CallBack => {
+      show_main_window(app);
+      let _ = app.emit_all("call://call-back", &target);
+      Ok(())
+    }
+    notification_actions::NotificationAction::MarkRead => {
+      let Some(event_id) = target.event_id.as_deref() else {
+        return Ok(());
+      };
+      let client = MatrixClient::for_account(app, &target.account_key).await?;
+      notification_actions::mark_read(&client, &target.room_id, event_id).await?;
+      record_own_receipt(app, &target.account_key, &target.room_id, event_id).await?;
+      app.state::<notification_dispatch::NotificationDispatcher>().clear(&target.account_key, &target.room_id);
+      let _ = refresh_badge(app).await;
+      let _ = app.emit_all("notification://read", &target);
+      Ok(())
+    }
+    notification_actions::NotificationAction::Reply { text } => {
+      if text.trim().is_empty() {
+        return Ok(());
+      }
+      let client = MatrixClient::for_account(app, &target.account_key).await?;
+      // Encryption lives in the webview; open the room so the reply can be sent from there.
+      if room_is_encrypted(app, &target.account_key, &client, &target.room_id).await {
+        show_main_window(app);
+        let _ = app.emit_all("notification://open-room", &target);
+        return Err("Quick reply isn't available in encrypted rooms; reply from the chat instead".into());
+      }
+      let sent = notification_actions::send_reply(&client, &target.room_id, target.event_id.as_deref(), &text).await?;
+      if let Some(event_id) = target.event_id.as_deref() {
+        notification_actions::mark_read(&client, &target.room_id, event_id).await?;
+      }
+      app.state::<notification_dispatch::NotificationDispatcher>().clear(&target.account_key, &target.room_id);
+      let _ = app.emit_all("notification://replied", json!({ "target": target, "eventId": sent }));
+      Ok(())
+    }
+  }
+}
+
+async fn run_notification_actions(app: AppHandle) {
+  let Some(mut events) = app.state::<notification_actions::NotificationActionState>().take_receiver() else {
+    return;
+  };
+  while let Some(event) = events.recv().await {
+    if let Err(err) = perform_notification_action(&app, event.target, event.action).await {
+      tracing::warn!("Notification action failed: {}", err);
+      let _ = app.notification().builder().title("Action failed").body(err).show();
+    }
+  }
+}
+
+/// Store the receipt we just sent so the native unread count drops before sync echoes it.
+async fn record_own_receipt(app: &AppHandle, account_key: &str, room_id: &str, event_id: &str) -> Result<(), String> {
+  let path = index_db_path(app)?;
+  let room_id = room_id.to_string();
+  let receipt = json!({
+    "content": { event_id: { "m.read": { user_id_for_key(account_key): { "ts": now_millis() } } } }
+  });
+  let account_key = account_key.to_string();
+  tauri::async_runtime::spawn_blocking(move || -> Result<(), String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    event_cache::record_receipts(&conn, &account_key, &room_id, &receipt)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Recount unread messages and, when the total changed, update the dock/launcher badge or
+/// taskbar overlay and emit `badge://changed`.
+async fn refresh_badge(app: &AppHandle) -> Result<badge::UnreadSummary, String> {
+  let accounts: Vec<(String, String)> = read_accounts_map(app)
+    .await?
+    .into_iter()
+    .map(|(key, creds)| (key, creds.user_id))
+    .collect();
+  let path = index_db_path(app)?;
+  let counted = accounts.clone();
+  let summary = tauri::async_runtime::spawn_blocking(move || -> Result<badge::UnreadSummary, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    badge::unread_counts(&conn, &counted)
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  if let Err(err) = refresh_tray(app, accounts, &summary).await {
+    tracing::warn!("Failed to update tray: {}", err);
+  }
+  if app.state::<badge::BadgeState>().update(summary.total) {
+    if let Some(window) = app.get_webview_window("main") {
+      #[cfg(windows)]
+      let _ = window.set_overlay_icon(
+        (summary.total > 0).then(|| tauri::image::Image::new_owned(badge::overlay_icon(summary.total, 32), 32, 32)),
+      );
+      #[cfg(not(windows))]
+      let _ = window.set_badge_count((summary.total > 0).then_some(summary.total as i64));
+    }
+    let _ = app.emit_all("badge://changed", &summary);
+    app.state::<ipc::IpcState>().unread_changed(summary.total, summary.highlights).await;
+  }
+  Ok(summary)
+}
+
+#[tauri::command]
+async fn get_unread_summary(app: AppHandle) -> Result<badge::UnreadSummary, String> {
+  refresh_badge(&app).await
+}
+
+/// Run a notification action on behalf of the webview, e.g. from an in-app toast.
+#[tauri::command]
+async fn handle_notification_action(
+  app: AppHandle,
+  target: notification_actions::NotificationTarget,
+  action: notification_actions::NotificationAction,
+) -> Result<(), String> {
+  perform_notification_action(&app, target, action).await
+}
+
+/// Resume stored registrations, then route distributor messages to their accounts.
+async fn run_unified_push(app: AppHandle) {
+  let state = app.state::<unified_push::UnifiedPushState>();
+  let Some(mut events) = state.take_receiver() else {
+    return;
+  };
+  if let Ok(map) = read_unified_push_map(&app).await {
+    for registration in map.values() {
+      match &registration.distributor {
+        unified_push::ActiveDistributor::Dbus { name } => {
+          if let Err(err) = state.register_dbus(&registration.token, Some(name)).await {
+            tracing::warn!(target: "sync", "Failed to resume UnifiedPush registration: {}", err);
+          }
+        }
+        unified_push::ActiveDistributor::Embedded { server, topic } => state.start_embedded(server, topic, &registration.token),
+      }
+    }
+  }
+  while let Some(event) = events.recv().await {
+    let Ok(map) = read_unified_push_map(&app).await else {
+      continue;
+    };
+    let token = match &event {
+      unified_push::ConnectorEvent::Message { token, .. }
+      | unified_push::ConnectorEvent::NewEndpoint { token, .. }
+      | unified_push::ConnectorEvent::Unregistered { token } => token.clone(),
+    };
+    let Some((account_key, registration)) = map.iter().find(|(_, r)| r.token == token) else {
+      continue;
+    };
+    match event {
+      unified_push::ConnectorEvent::Message { payload, .. } => {
+        match unified_push::open_payload(&registration.keys, &payload) {
+          Ok(plaintext) => {
+            if let Some(notification) = unified_push::parse_notification(&plaintext) {
+              let app = app.clone();
+              let account_key = account_key.clone();
+              tauri::async_runtime::spawn(async move {
+                if let Some(notification) = resolve_push_notification(&app, &account_key, notification).await {
+                  show_push_notification(&app, &account_key, &notification).await;
+                }
+              });
+            }
+          }
+          Err(err) => tracing::warn!(target: "sync", "Dropped push message: {}", err),
+        }
+      }
+      unified_push::ConnectorEvent::NewEndpoint { endpoint, .. } => {
+        if let Err(err) = apply_push_endpoint(&app, account_key, &endpoint).await {
+          tracing::warn!(target: "sync", "Failed to update pusher: {}", err);
+        }
+      }
+      unified_push::ConnectorEvent::Unregistered { .. } => {
+        let mut map = map.clone();
+        map.remove(account_key);
+        let _ = write_unified_push_map(&app, &map).await;
+        let _ = app.emit_all("push://unregistered", json!({ "accountKey": account_key }));
+      }
+    }
+  }
+}
+
+#[tauri::command]
+async fn get_background_settings(app: AppHandle) -> Result<background_sync::BackgroundSettings, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<background_sync::BackgroundSettings, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    background_sync::settings(&conn)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+async fn set_background_settings(app: AppHandle, settings: background_sync::BackgroundSettings) -> Result<(), String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<(), String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    background_sync::set_settings(&conn, &settings)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Remember the webview's latest `next_batch` so a background sync can pick up from it.
+#[tauri::command]
+fn record_sync_token(state: tauri::State<'_, background_sync::BackgroundSyncState>, account_key: String, next_batch: String) {
+  state.record_token(&account_key, &next_batch);
+}
+
+/// Turn an `event_id_only` or encrypted push into something worth showing: fetch the event,
+/// have the webview's crypto decrypt it over `crypto://decrypt-push`, and drop it when the
+/// account's push rules don't notify for the cleartext. Falls back to the push as received.
+async fn resolve_push_notification(
+  app: &AppHandle,
+  account_key: &str,
+  notification: unified_push::PushNotification,
+) -> Option<unified_push::PushNotification> {
+  if !push_events::needs_content(&notification) {
+    return Some(notification);
+  }
+  let (Some(room_id), Some(event_id)) = (notification.room_id.clone(), notification.event_id.clone()) else {
+    return Some(notification);
+  };
+  let Ok(path) = index_db_path(app) else {
+    return Some(notification);
+  };
+  let (cache_path, cache_account) = (path.clone(), account_key.to_string());
+  let (cache_room, cache_event) = (room_id.clone(), event_id.clone());
+  let cached = tauri::async_runtime::spawn_blocking(move || -> Result<Option<serde_json::Value>, String> {
+    let conn = Connection::open(cache_path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    Ok(
+      event_cache::get_event(&conn, &cache_account, &cache_room, &cache_event)?
+        .filter(|event| event.event_type != push_events::ENCRYPTED_TYPE)
+        .map(|event| event.to_raw()),
+    )
+  })
+  .await
+  .map_err(|e| e.to_string())
+  .and_then(|result| result)
+  .ok()
+  .flatten();
+  let event = match cached {
+    Some(event) => event,
+    None => {
+      let client = match MatrixClient::for_account(app, account_key).await {
+        Ok(client) => client,
+        Err(err) => {
+          tracing::warn!(target: "sync", "Failed to fetch pushed event: {}", err);
+          return Some(notification);
+        }
+      };
+      let fetched = match push_events::fetch_event(&client, &room_id, &event_id).await {
+        Ok(event) => event,
+        Err(err) => {
+          tracing::warn!(target: "sync", "Failed to fetch pushed event: {}", String::from(err));
+          return Some(notification);
+        }
+      };
+      if fetched.get("type").and_then(|v| v.as_str()) == Some(push_events::ENCRYPTED_TYPE) {
+        decrypt_pushed_event(app, account_key, &room_id, fetched).await
+      } else {
+        fetched
+      }
+    }
+  };
+  let rules_account = account_key.to_string();
+  let user_id = user_id_for_key(account_key).to_string();
+  let (rules_room, rules_event) = (room_id.clone(), event.clone());
+  let candidate = notification.clone();
+  let notify = tauri::async_runtime::spawn_blocking(move || -> Result<bool, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    let Some(rules) = push_rules::cached_rules(&conn, &rules_account)? else {
+      return Ok(true);
+    };
+    // Still-encrypted events were already matched by the server's rules.
+    let Some(cached) = event_cache::CachedEvent::from_raw(&rules_room, &rules_event)
+      .filter(|event| event.event_type != push_events::ENCRYPTED_TYPE)
+    else {
+      return Ok(true);
+    };
+    let ctx = push_rules::context_for_room(&conn, &rules_account, &rules_room, &user_id)?;
+    if push_rules::evaluate(&rules, &cached, &ctx).notify {
+      return Ok(true);
+    }
+    notification_history::record(
+      &conn,
+      &rules_account,
+      &candidate,
+      None,
+      None,
+      notification_history::NotificationOutcome::Suppressed,
+      Some("pushRules"),
+    )?;
+    Ok(false)
+  })
+  .await
+  .map_err(|e| e.to_string())
+  .and_then(|result| result)
+  .unwrap_or(true);
+  if !notify {
+    return None;
+  }
+  let mut notification = notification;
+  push_events::fill(&mut notification, &event);
+  name_notifications(app, account_key, vec![notification]).await.into_iter().next()
+}
+
+/// Ask the webview to decrypt `event`; returns it unchanged when that fails or times out.
+async fn decrypt_pushed_event(app: &AppHandle, account_key: &str, room_id: &str, event: serde_json::Value) -> serde_json::Value {
+  let state = app.state::<push_events::PushDecryptState>();
+  let (request_id, receiver) = state.request();
+  let emitted = app.emit_all(
+    "crypto://decrypt-push",
+    json!({ "requestId": request_id, "accountKey": account_key, "roomId": room_id, "event": event }),
+  );
+  if emitted.is_ok() {
+    if let Ok(Ok(Some(decrypted))) = tokio::time::timeout(push_events::DECRYPT_TIMEOUT, receiver).await {
+      return decrypted;
+    }
+  }
+  state.cancel(&request_id);
+  event
+}
+
+/// The webview's answer to `crypto://decrypt-push`: the cleartext event, or `None` when the
+/// keys are missing.
+#[tauri::command]
+fn complete_push_decryption(
+  state: tauri::State<'_, push_events::PushDecryptState>,
+  request_id: String,
+  event: Option<serde_json::Value>,
+) -> bool {
+  state.complete(&request_id, event)
+}
+
+/// Fill in room and sender names from the account's cached state where the sync response
+/// lacked them.
+async fn name_notifications(
+  app: &AppHandle,
+  account_key: &str,
+  notifications: Vec<unified_push::PushNotification>,
+) -> Vec<unified_push::PushNotification> {
+  let Ok(path) = index_db_path(app) else {
+    return notifications;
+  };
+  let fallback = notifications.clone();
+  let account_key = account_key.to_string();
+  tauri::async_runtime::spawn_blocking(move || -> Result<Vec<unified_push::PushNotification>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    let mut notifications = notifications;
+    for notification in notifications.iter_mut() {
+      let Some(room_id) = notification.room_id.clone() else {
+        continue;
+      };
+      if notification.room_name.is_none() {
+        notification.room_name = event_cache::get_state_event(&conn, &account_key, &room_id, "m.room.name", "")?
+          .and_then(|event| event.content.get("name").and_then(|v| v.as_str()).map(|s| s.to_string()));
+      }
+      if let Some(sender) = &notification.sender {
+        notification.sender_display_name = event_cache::member_profile(&conn, &account_key, &room_id, sender)?.0;
+      }
+    }
+    Ok(notifications)
+  })
+  .await
+  .map_err(|e| e.to_string())
+  .and_then(|result| result)
+  .unwrap_or(fallback)
+}
+
+/// Notify about keyword matches; highlights pass the room's local rule even when it is muted.
+async fn notify_keyword_hits(app: AppHandle, account_key: String, hits: Vec<keyword_alerts::KeywordHit>) {
+  let notifications = hits
+    .into_iter()
+    .map(|hit| unified_push::PushNotification {
+      event_id: Some(hit.event_id),
+      room_id: Some(hit.room_id),
+      event_type: Some("m.room.message".to_string()),
+      sender: Some(hit.sender),
+      sender_display_name: None,
+      room_name: None,
+      body: Some(hit.body),
+      unread: None,
+    })
+    .collect();
+  for notification in name_notifications(&app, &account_key, notifications).await {
+    show_push_notification(&app, &account_key, &notification).await;
+  }
+}
+
+/// Announce a missed call with `call://missed` and a notification offering to call back.
+async fn notify_missed_call(app: AppHandle, account_key: String, call: call_log::CallEntry) {
+  let _ = app.emit_all("call://missed", json!({ "accountKey": account_key, "call": call }));
+  let notification = unified_push::PushNotification {
+    event_id: call.invite_event_id.clone(),
+    room_id: Some(call.room_id.clone()),
+    event_type: Some("m.call.invite".to_string()),
+    sender: Some(call.caller.clone()),
+    sender_display_name: None,
+    room_name: None,
+    body: None,
+    unread: None,
+  };
+  let Some(named) = name_notifications(&app, &account_key, vec![notification]).await.into_iter().next() else {
+    return;
+  };
+  let named_notification = named.clone();
+  let caller = named.sender_display_name.unwrap_or(call.caller);
+  let title = if call.video { "Missed video call" } else { "Missed call" };
+  let body = match named.room_name {
+    Some(room) if room != caller => format!("{} in {}", caller, room),
+    _ => caller,
+  };
+  let target = notification_actions::NotificationTarget {
+    account_key,
+    room_id: call.room_id,
+    event_id: call.invite_event_id,
+  };
+  log_notification(
+    &app,
+    &target.account_key,
+    &named_notification,
+    Some((title, &body)),
+    notification_history::NotificationOutcome::Shown,
+  )
+  .await;
+  let media = notification_media(&app, &target.account_key, &named_notification).await;
+  let actions = app.state::<notification_actions::NotificationActionState>();
+  if actions
+    .show(notification_actions::NotificationKind::MissedCall, title, &body, target, None, &media)
+    .await
+    .is_none()
+  {
+    show_plain_notification(&app, title, &body, &media);
+  }
+}
+
+async fn deliver_background_notifications(
+  app: &AppHandle,
+  account_key: &str,
+  notifications: Vec<unified_push::PushNotification>,
+) {
+  for notification in name_notifications(app, account_key, notifications).await {
+    if let Some(notification) = resolve_push_notification(app, account_key, notification).await {
+      show_push_notification(app, account_key, &notification).await;
+    }
+  }
+}
+
+/// Start a notifications-only sync for every account after the window was hidden. Accounts
+/// whose sync engine is running notify from the engine instead.
+async fn enter_background(app: AppHandle) {
+  app.state::<sync_engine::SyncEngineState>().set_background(true);
+  let accounts = match read_accounts_map(&app).await {
+    Ok(map) => map,
+    Err(err) => {
+      tracing::warn!(target: "sync", "Background sync unavailable: {}", err);
+      return;
+    }
+  };
+  let state = app.state::<background_sync::BackgroundSyncState>();
+  let engines = app.state::<sync_engine::SyncEngineState>();
+  for (account_key, creds) in accounts {
+    if engines.is_running(&account_key) {
+      continue;
+    }
+    let client = match MatrixClient::new(&creds.homeserver_url, &creds.user_id, &creds.access_token) {
+      Ok(client) => client,
+      Err(err) => {
+        tracing::warn!(target: "sync", "Background sync unavailable for {}: {}", account_key, err);
+        continue;
+      }
+    };
+    let since = state.token(&account_key);
+    let stop = state.begin(&account_key);
+    let wake = app.state::<connectivity::ConnectivityState>().signal.clone();
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+      let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+      let notify_app = app.clone();
+      let notify_key = account_key.clone();
+      tauri::async_runtime::spawn(async move {
+        while let Some(notifications) = receiver.recv().await {
+          deliver_background_notifications(&notify_app, &notify_key, notifications).await;
+        }
+      });
+      let queue_app = app.clone();
+      let queue_key = account_key.clone();
+      background_sync::run(
+        client,
+        since,
+        stop,
+        wake,
+        move |events| {
+          let (app, key) = (queue_app.clone(), queue_key.clone());
+          async move {
+            let (count, account) = (events.len(), key.clone());
+            with_index_db(&app, move |conn| sync_engine::queue_to_device(conn, &account, &events)).await?;
+            let _ = app.emit_all("sync://to-device", json!({ "accountKey": key, "count": count }));
+            Ok(())
+          }
+        },
+        move |notifications| {
+          let _ = sender.send(notifications);
+        },
+      )
+      .await;
+    });
+  }
+  let _ = app.emit_all("app://background", json!({}));
+}
+
+#[tauri::command]
+async fn get_notification_rule(app: AppHandle, room_id: String) -> Result<notification_rules::NotificationRule, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<notification_rules::NotificationRule, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    notification_rules::get_rule(&conn, &room_id)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Replace a room's local notification rule; `None` restores the default.
+#[tauri::command]
+async fn set_notification_rule(
+  app: AppHandle,
+  room_id: String,
+  rule: Option<notification_rules::NotificationRule>,
+) -> Result<(), String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<(), String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    notification_rules::set_rule(&conn, &room_id, rule.as_ref())
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+async fn list_notification_rules(app: AppHandle) -> Result<Vec<notification_rules::RoomNotificationRule>, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<Vec<notification_rules::RoomNotificationRule>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    notification_rules::list_rules(&conn)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Let the webview check its own notifications against the local rules.
+#[tauri::command]
+async fn evaluate_notification(
+  app: AppHandle,
+  account_key: String,
+  notification: unified_push::PushNotification,
+) -> Result<bool, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<bool, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    notification_rules::evaluate(&conn, &account_key, &notification, user_id_for_key(&account_key))
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+async fn get_dnd_settings(app: AppHandle) -> Result<quiet_hours::DndSettings, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<quiet_hours::DndSettings, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    quiet_hours::settings(&conn)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+async fn set_dnd_settings(app: AppHandle, settings: quiet_hours::DndSettings) -> Result<quiet_hours::DndStatus, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<quiet_hours::DndStatus, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    quiet_hours::set_settings(&conn, &settings)?;
+    quiet_hours::status(&conn)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Turn manual Do Not Disturb on for `minutes`, or off when `None`.
+#[tauri::command]
+async fn set_dnd_manual(app: AppHandle, minutes: Option<u32>) -> Result<quiet_hours::DndStatus, String> {
+  apply_dnd_manual(&app, minutes).await
+}
+
+async fn apply_dnd_manual(app: &AppHandle, minutes: Option<u32>) -> Result<quiet_hours::DndStatus, String> {
+  let path = index_db_path(app)?;
+  let status = tauri::async_runtime::spawn_blocking(move || -> Result<quiet_hours::DndStatus, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    let mut settings = quiet_hours::settings(&conn)?;
+    settings.manual_until = minutes.map(|m| now_millis() + m as i64 * 60_000);
+    quiet_hours::set_settings(&conn, &settings)?;
+    quiet_hours::status(&conn)
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  let _ = app.emit_all("dnd://changed", &status);
+  app.state::<ipc::IpcState>().dnd_changed(status.active).await;
+  Ok(status)
+}
+
+#[tauri::command]
+async fn get_dnd_status(app: AppHandle) -> Result<quiet_hours::DndStatus, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<quiet_hours::DndStatus, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    quiet_hours::status(&conn)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Watch for quiet periods starting and ending; when one ends, release what was held as a
+/// single summary notification and a `dnd://released` event.
+async fn run_quiet_hours(app: AppHandle) {
+  let mut was_active = None;
+  loop {
+    if let Ok(path) = index_db_path(&app) {
+      let result = tauri::async_runtime::spawn_blocking(
+        move || -> Result<(quiet_hours::DndStatus, Vec<quiet_hours::HeldNotification>), String> {
+          let conn = Connection::open(path).map_err(|e| e.to_string())?;
+          init_index_db(&conn).map_err(|e| e.to_string())?;
+          let status = quiet_hours::status(&conn)?;
+          let held = if status.active { Vec::new() } else { quiet_hours::take_held(&conn)? };
+          Ok((status, held))
+        },
+      )
+      .await
+      .map_err(|e| e.to_string())
+      .and_then(|result| result);
+      match result {
+        Ok((status, held)) => {
+          if was_active != Some(status.active) {
+            was_active = Some(status.active);
+            let _ = app.emit_all("dnd://changed", &status);
+            let _ = refresh_badge(&app).await;
+          }
+          if let Some((title, body)) = quiet_hours::summary(&held) {
+            let _ = app.emit_all("dnd://released", &held);
+            let _ = app.notification().builder().title(title).body(body).show();
+          }
+        }
+        Err(err) => tracing::warn!("Quiet hours check failed: {}", err),
+      }
+    }
+    tokio::time::sleep(std::time::Duration::from_secs(30)).await;
+  }
+}
+
+#[tauri::command]
+async fn list_notification_sounds(app: AppHandle) -> Result<Vec<notification_sounds::NotificationSound>, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<Vec<notification_sounds::NotificationSound>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    notification_sounds::list(&conn)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Validate an audio file (Ogg/Opus, WAV, or anything ffmpeg reads) and add it to the sounds.
+#[tauri::command]
+async fn import_notification_sound(app: AppHandle, path: String, name: String) -> Result<notification_sounds::NotificationSound, String> {
+  let db_path = index_db_path(&app)?;
+  let dir = sounds_dir(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<notification_sounds::NotificationSound, String> {
+    let conn = Connection::open(db_path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    notification_sounds::import(&conn, &dir, std::path::Path::new(&path), &name)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+async fn remove_notification_sound(app: AppHandle, id: String) -> Result<(), String> {
+  let path = index_db_path(&app)?;
+  let dir = sounds_dir(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<(), String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    notification_sounds::remove(&conn, &dir, &id)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Assign a sound to the global, account or room scope; `None` inherits from the next level.
+#[tauri::command]
+async fn set_notification_sound(
+  app: AppHandle,
+  scope: notification_sounds::SoundScope,
+  sound_id: Option<String>,
+) -> Result<(), String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<(), String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    notification_sounds::assign(&conn, &scope, sound_id.as_deref())
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+async fn get_notification_sound(app: AppHandle, scope: notification_sounds::SoundScope) -> Result<Option<String>, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<Option<String>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    notification_sounds::assigned(&conn, &scope)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+fn preview_notification_sound(app: AppHandle, id: String) -> Result<(), String> {
+  let samples = notification_sounds::load(&sounds_dir(&app)?, &id)?;
+  notification_sounds::play(samples);
+  Ok(())
+}
+
+/// Silence a room's notifications for `minutes`; `None` or 0 ends the snooze. Returns when it ends.
+#[tauri::command]
+async fn snooze_room(app: AppHandle, room_id: String, minutes: Option<u32>) -> Result<Option<i64>, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<Option<i64>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    snooze::snooze_room(&conn, &room_id, minutes)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+async fn snooze_account(app: AppHandle, account_key: String, minutes: Option<u32>) -> Result<Option<i64>, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<Option<i64>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    snooze::snooze_account(&conn, &account_key, minutes)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+async fn list_snoozes(app: AppHandle) -> Result<Vec<snooze::Snooze>, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<Vec<snooze::Snooze>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    snooze::list(&conn)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+async fn list_keyword_alerts(app: AppHandle, account_key: String) -> Result<Vec<keyword_alerts::KeywordAlert>, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<Vec<keyword_alerts::KeywordAlert>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    keyword_alerts::list(&conn, &account_key)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Add a highlight keyword, or a regular expression with `is_regex`.
+#[tauri::command]
+async fn add_keyword_alert(
+  app: AppHandle,
+  account_key: String,
+  pattern: String,
+  is_regex: Option<bool>,
+  case_sensitive: Option<bool>,
+) -> Result<keyword_alerts::KeywordAlert, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<keyword_alerts::KeywordAlert, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    keyword_alerts::add(
+      &conn,
+      &account_key,
+      &pattern,
+      is_regex.unwrap_or(false),
+      case_sensitive.unwrap_or(false),
+    )
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+async fn remove_keyword_alert(app: AppHandle, account_key: String, id: i64) -> Result<(), String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<(), String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    keyword_alerts::remove(&conn, &account_key, id)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+async fn get_keyword_highlights(
+  app: AppHandle,
+  account_key: String,
+  limit: Option<usize>,
+) -> Result<Vec<keyword_alerts::KeywordHit>, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<Vec<keyword_alerts::KeywordHit>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    keyword_alerts::highlights(&conn, &account_key, limit.unwrap_or(100))
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Call history for a calls screen, newest first.
+#[tauri::command]
+async fn get_call_log(
+  app: AppHandle,
+  account_key: String,
+  missed_only: Option<bool>,
+  limit: Option<usize>,
+) -> Result<Vec<call_log::CallEntry>, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<Vec<call_log::CallEntry>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    call_log::list(&conn, &account_key, missed_only.unwrap_or(false), limit.unwrap_or(100))
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Notifications shown, grouped, held or suppressed (with the reason), newest first.
+#[tauri::command]
+async fn get_notification_history(
+  app: AppHandle,
+  account_key: Option<String>,
+  room_id: Option<String>,
+  since: Option<i64>,
+  limit: Option<usize>,
+) -> Result<Vec<notification_history::NotificationRecord>, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<Vec<notification_history::NotificationRecord>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    notification_history::history(&conn, account_key.as_deref(), room_id.as_deref(), since, limit.unwrap_or(200))
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+async fn clear_notification_history(app: AppHandle) -> Result<(), String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<(), String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    notification_history::clear(&conn)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+async fn get_rich_notification_settings(app: AppHandle) -> Result<rich_notifications::RichNotificationSettings, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<rich_notifications::RichNotificationSettings, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    rich_notifications::settings(&conn)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Turning pictures off also deletes the decrypted copies already on disk.
+#[tauri::command]
+async fn set_rich_notification_settings(
+  app: AppHandle,
+  settings: rich_notifications::RichNotificationSettings,
+) -> Result<(), String> {
+  let path = index_db_path(&app)?;
+  let dir = notification_images_dir(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<(), String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    rich_notifications::set_settings(&conn, &settings)?;
+    if !settings.images || !settings.avatars {
+      rich_notifications::clear(&dir)?;
+    }
+    Ok(())
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+async fn read_digest_settings(app: &AppHandle) -> Result<email_digest::DigestSettings, String> {
+  let store = StoreBuilder::new(app, STORE_FILE)
+      .build()
+      .map_err(|e| e.to_string())?;
+  match store.get(EMAIL_DIGEST_KEY) {
+    Some(v) => serde_json::from_value(v.clone()).map_err(|e| format!("Corrupt store: {}", e)),
+    None => Ok(email_digest::DigestSettings::default()),
+  }
+}
+
+async fn write_digest_settings(app: &AppHandle, settings: &email_digest::DigestSettings) -> Result<(), String> {
+  let store = StoreBuilder::new(app, STORE_FILE)
+      .build()
+      .map_err(|e| e.to_string())?;
+  let v = serde_json::to_value(settings).map_err(|e| e.to_string())?;
+  store.set(EMAIL_DIGEST_KEY.to_string(), v);
+  store.save().map_err(|e| e.to_string())
+}
+
+async fn record_app_active(app: &AppHandle) {
+  let Ok(path) = index_db_path(app) else {
+    return;
+  };
+  let _ = tauri::async_runtime::spawn_blocking(move || -> Result<(), String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    email_digest::record_active(&conn)
+  })
+  .await;
+}
+
+/// Compile highlights newer than `since` and mail or save them. `None` when there was nothing
+/// to report.
+async fn deliver_digest(
+  app: &AppHandle,
+  settings: &email_digest::DigestSettings,
+  since: i64,
+) -> Result<Option<email_digest::DigestReport>, String> {
+  let accounts: Vec<(String, String)> = read_accounts_map(app)
+    .await?
+    .into_iter()
+    .map(|(key, creds)| (key, creds.user_id))
+    .collect();
+  let path = index_db_path(app)?;
+  let items = tauri::async_runtime::spawn_blocking(move || -> Result<Vec<email_digest::DigestItem>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    email_digest::collect(&conn, &accounts, since)
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  if items.is_empty() {
+    return Ok(None);
+  }
+  let (html, text) = email_digest::render(&items);
+  let delivered_to = match settings.delivery {
+    email_digest::DigestDelivery::Email => {
+      let smtp = settings.smtp.as_ref().ok_or("No SMTP account is configured")?;
+      email_digest::send(smtp, items.len(), html, text).await?;
+      smtp.to.clone()
+    }
+    email_digest::DigestDelivery::SaveLocally => {
+      let dir = match &settings.save_dir {
+        Some(dir) => PathBuf::from(dir),
+        None => app
+          .path_resolver()
+          .app_data_dir()
+          .map(|dir| dir.join("digests"))
+          .ok_or_else(|| "Unable to resolve application data directory".to_string())?,
+      };
+      email_digest::save(&dir, &html)?.to_string_lossy().to_string()
+    }
+  };
+  Ok(Some(email_digest::DigestReport {
+    items: items.len(),
+    delivered_to,
+  }))
+}
+
+/// Send a digest once the app has gone unopened for the configured period, then again after
+/// each further period without it being opened.
+async fn run_email_digest(app: AppHandle) {
+  record_app_active(&app).await;
+  loop {
+    tokio::time::sleep(std::time::Duration::from_secs(15 * 60)).await;
+    let Ok(settings) = read_digest_settings(&app).await else {
+      continue;
+    };
+    let Ok(path) = index_db_path(&app) else {
+      continue;
+    };
+    let check = settings.clone();
+    let due = tauri::async_runtime::spawn_blocking(move || -> Result<Option<i64>, String> {
+      let conn = Connection::open(path).map_err(|e| e.to_string())?;
+      init_index_db(&conn).map_err(|e| e.to_string())?;
+      email_digest::due_since(&conn, &check)
+    })
+    .await
+    .map_err(|e| e.to_string())
+    .and_then(|result| result);
+    let since = match due {
+      Ok(Some(since)) => since,
+      Ok(None) => continue,
+      Err(err) => {
+        tracing::warn!("Digest check failed: {}", err);
+        continue;
+      }
+    };
+    match deliver_digest(&app, &settings, since).await {
+      Ok(report) => {
+        if let Ok(path) = index_db_path(&app) {
+          let _ = tauri::async_runtime::spawn_blocking(move || -> Result<(), String> {
+            let conn = Connection::open(path).map_err(|e| e.to_string())?;
+            email_digest::mark_sent(&conn)
+          })
+          .await;
+        }
+        if let Some(report) = report {
+          let _ = app.emit_all("digest://sent", &report);
+        }
+      }
+      Err(err) => tracing::warn!("Failed to deliver digest: {}", err),
+    }
+  }
+}
+
+/// Digest settings; the SMTP password stays in the store.
+#[tauri::command]
+async fn get_digest_settings(app: AppHandle) -> Result<email_digest::DigestSettings, String> {
+  let mut settings = read_digest_settings(&app).await?;
+  if let Some(smtp) = settings.smtp.as_mut() {
+    smtp.password = None;
+  }
+  Ok(settings)
+}
+
+#[tauri::command]
+async fn set_digest_settings(app: AppHandle, settings: email_digest::DigestSettings) -> Result<(), String> {
+  let mut settings = settings;
+  let stored = read_digest_settings(&app).await?;
+  if let (Some(smtp), Some(previous)) = (settings.smtp.as_mut(), stored.smtp) {
+    if smtp.password.is_none() && smtp.host == previous.host && smtp.username == previous.username {
+      smtp.password = previous.password;
+    }
+  }
+  write_digest_settings(&app, &settings).await
+}
+
+/// Deliver a digest of the last absence period right away, e.g. to test the SMTP account.
+#[tauri::command]
+async fn send_digest_now(app: AppHandle) -> Result<Option<email_digest::DigestReport>, String> {
+  let settings = read_digest_settings(&app).await?;
+  let since = now_millis() - settings.absence_hours.max(1) as i64 * 60 * 60 * 1000;
+  deliver_digest(&app, &settings, since).await
+}
+
+/// Rebuild the tray icon, tooltip and menu when what they show has changed.
+async fn refresh_tray(app: &AppHandle, accounts: Vec<(String, String)>, summary: &badge::UnreadSummary) -> Result<(), String> {
+  let Some(tray_icon) = app.tray_by_id(tray::TRAY_ID) else {
+    return Ok(());
+  };
+  let path = index_db_path(app)?;
+  let summary = summary.clone();
+  let view = tauri::async_runtime::spawn_blocking(move || -> Result<tray::TrayView, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    let dnd = quiet_hours::status(&conn)?.manual_until.is_some_and(|until| until > now_millis());
+    tray::view(&conn, &accounts, &summary, dnd)
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  if !app.state::<tray::TrayState>().update(&view) {
+    return Ok(());
+  }
+  if let Some(base) = app.default_window_icon() {
+    tray_icon.set_icon(Some(tray::icon(base, view.status))).map_err(|e| e.to_string())?;
+  }
+  tray_icon.set_tooltip(Some(tray::tooltip(&view))).map_err(|e| e.to_string())?;
+  let menu = tray::menu(app, &view).map_err(|e| e.to_string())?;
+  tray_icon.set_menu(Some(menu)).map_err(|e| e.to_string())
+}
+
+/// Add the tray icon; left click toggles the window, the menu is filled by `refresh_tray`.
+fn install_tray(app: &AppHandle) -> Result<(), String> {
+  if app.tray_by_id(tray::TRAY_ID).is_some() {
+    return Ok(());
+  }
+  let empty = tray::TrayView {
+    status: tray::TrayStatus::Idle,
+    total: 0,
+    dnd: false,
+    rooms: Vec::new(),
+  };
+  let mut builder = tauri::tray::TrayIconBuilder::with_id(tray::TRAY_ID)
+    .tooltip(tray::tooltip(&empty))
+    .menu(&tray::menu(app, &empty).map_err(|e| e.to_string())?)
+    .menu_on_left_click(false)
+    .on_menu_event(|app, event| handle_tray_menu(app, event.id().as_ref()))
+    .on_tray_icon_event(|tray_icon, event| {
+      if let tauri::tray::TrayIconEvent::Click {
+        button: tauri::tray::MouseButton::Left,
+        button_state: tauri::tray::MouseButtonState::Up,
+        ..
+      } = event
+      {
+        let app = tray_icon.app_handle();
+        match app.get_webview_window("main") {
+          Some(window) if window.is_visible().unwrap_or(false) => {
+            let _ = window.hide();
+          }
+          _ => show_main_window(app),
+        }
+      }
+    });
+  if let Some(icon) = app.default_window_icon() {
+    builder = builder.icon(icon.clone());
+  }
+  builder.build(app).map_err(|e| e.to_string())?;
+  app.state::<tray::TrayState>().reset();
+  Ok(())
+}
+
+fn handle_tray_menu(app: &AppHandle, id: &str) {
+  match id {
+    "show" => show_main_window(app),
+    "quit" => app.exit(0),
+    "mark-all-read" => {
+      let app = app.clone();
+      tauri::async_runtime::spawn(async move {
+        if let Err(err) = mark_all_read(&app).await {
+          let _ = app.notification().builder().title("Mark all as read failed").body(err).show();
+        }
+      });
+    }
+    "dnd" => {
+      let app = app.clone();
+      tauri::async_runtime::spawn(async move {
+        let Ok(path) = index_db_path(&app) else {
+          return;
+        };
+        let manual = tauri::async_runtime::spawn_blocking(move || -> Result<bool, String> {
+          let conn = Connection::open(path).map_err(|e| e.to_string())?;
+          init_index_db(&conn).map_err(|e| e.to_string())?;
+          Ok(quiet_hours::status(&conn)?.manual_until.is_some_and(|until| until > now_millis()))
+        })
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|result| result)
+        .unwrap_or(false);
+        // Switched on from the tray it stays on until switched off again.
+        let _ = apply_dnd_manual(&app, if manual { None } else { Some(u32::MAX) }).await;
+        let _ = refresh_badge(&app).await;
+      });
+    }
+    _ => {
+      let Some(room) = id
+        .strip_prefix(tray::ROOM_ITEM_PREFIX)
+        .and_then(|index| index.parse().ok())
+        .and_then(|index| app.state::<tray::TrayState>().room(index))
+      else {
+        return;
+      };
+      show_main_window(app);
+      let target = notification_actions::NotificationTarget {
+        account_key: room.account_key,
+        room_id: room.room_id,
+        event_id: None,
+      };
+      let _ = app.emit_all("notification://open-room", &target);
+    }
+  }
+}
+
+/// Answer a call from another program on the local IPC endpoint.
+async fn handle_ipc_request(app: AppHandle, request: ipc::IpcRequest) -> Result<serde_json::Value, String> {
+  match request {
+    ipc::IpcRequest::UnreadCounts => {
+      let summary = refresh_badge(&app).await?;
+      serde_json::to_value(summary).map_err(|e| e.to_string())
+    }
+    ipc::IpcRequest::DoNotDisturb => {
+      let path = index_db_path(&app)?;
+      let status = tauri::async_runtime::spawn_blocking(move || -> Result<quiet_hours::DndStatus, String> {
+        let conn = Connection::open(path).map_err(|e| e.to_string())?;
+        init_index_db(&conn).map_err(|e| e.to_string())?;
+        quiet_hours::status(&conn)
+      })
+      .await
+      .map_err(|e| e.to_string())??;
+      Ok(json!({ "active": status.active }))
+    }
+    ipc::IpcRequest::SetDoNotDisturb { enabled } => {
+      let status = apply_dnd_manual(&app, enabled.then_some(u32::MAX)).await?;
+      let _ = refresh_badge(&app).await;
+      Ok(json!({ "active": status.active }))
+    }
+    ipc::IpcRequest::OpenRoom { room_id } => {
+      let url = format!("https://matrix.to/#/{}", matrix_api::encode(&room_id));
+      Ok(json!({ "opened": open_deep_link(&app, &url) }))
+    }
+  }
+}
+
+/// Send read markers up to the newest cached event of every room with unread notifications.
+async fn mark_all_read(app: &AppHandle) -> Result<usize, String> {
+  let accounts: Vec<(String, String)> = read_accounts_map(app)
+    .await?
+    .into_iter()
+    .map(|(key, creds)| (key, creds.user_id))
+    .collect();
+  let path = index_db_path(app)?;
+  let rooms = tauri::async_runtime::spawn_blocking(move || -> Result<Vec<tray::TrayRoom>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    let summary = badge::unread_counts(&conn, &accounts)?;
+    tray::unread_rooms(&conn, &accounts, &summary)
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  let mut clients: HashMap<String, MatrixClient> = HashMap::new();
+  let mut marked = 0;
+  for room in rooms {
+    let Some(event_id) = room.latest_event_id.as_deref() else {
+      continue;
+    };
+    if !clients.contains_key(&room.account_key) {
+      let client = MatrixClient::for_account(app, &room.account_key).await?;
+      clients.insert(room.account_key.clone(), client);
+    }
+    let client = &clients[&room.account_key];
+    notification_actions::mark_read(client, &room.room_id, event_id).await?;
+    record_own_receipt(app, &room.account_key, &room.room_id, event_id).await?;
+    app.state::<notification_dispatch::NotificationDispatcher>().clear(&room.account_key, &room.room_id);
+    marked += 1;
+  }
+  refresh_badge(app).await?;
+  let _ = app.emit_all("notification://read-all", json!({ "rooms": marked }));
+  Ok(marked)
+}
+
+#[tauri::command]
+async fn get_tray_settings(app: AppHandle) -> Result<tray::TraySettings, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<tray::TraySettings, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    tray::settings(&conn)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Store the tray settings and add or remove the tray icon to match.
+#[tauri::command]
+async fn set_tray_settings(app: AppHandle, settings: tray::TraySettings) -> Result<(), String> {
+  let path = index_db_path(&app)?;
+  let enabled = settings.enabled;
+  tauri::async_runtime::spawn_blocking(move || -> Result<(), String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    tray::set_settings(&conn, &settings)
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  if enabled {
+    install_tray(&app)?;
+    refresh_badge(&app).await?;
+  } else {
+    app.remove_tray_by_id(tray::TRAY_ID);
+  }
+  Ok(())
+}
+
+/// Mark every room with unread notifications as read; returns how many rooms were marked.
+#[tauri::command]
+async fn mark_all_rooms_read(app: AppHandle) -> Result<usize, String> {
+  mark_all_read(&app).await
+}
+
+#[tauri::command]
+fn get_autostart() -> Result<autostart::AutostartStatus, String> {
+  autostart::status()
+}
+
+/// Start with the OS through the registry Run key, a LaunchAgent or an XDG autostart entry,
+/// optionally hidden in the tray.
+#[tauri::command]
+fn set_autostart(enabled: bool, start_minimized: bool) -> Result<autostart::AutostartStatus, String> {
+  // An AppImage runs from a temporary mount; the image itself is what has to be launched.
+  let exe = match std::env::var_os("APPIMAGE") {
+    Some(image) => PathBuf::from(image),
+    None => std::env::current_exe().map_err(|e| e.to_string())?,
+  };
+  autostart::set(&exe, enabled, start_minimized)
+}
+
+fn hotkey_bindings(app: &AppHandle) -> Result<Vec<(hotkeys::HotkeyAction, Option<String>)>, String> {
+  let conn = Connection::open(index_db_path(app)?).map_err(|e| e.to_string())?;
+  init_index_db(&conn).map_err(|e| e.to_string())?;
+  hotkeys::bindings(&conn)
+}
+
+/// Register the configured shortcuts; ones another application holds are skipped.
+fn register_hotkeys(app: &AppHandle) -> Result<(), String> {
+  let state = app.state::<hotkeys::HotkeyState>();
+  for (action, accelerator) in hotkey_bindings(app)? {
+    let Some(accelerator) = accelerator else {
+      continue;
+    };
+    let shortcut = hotkeys::parse(&accelerator)?;
+    match app.global_shortcut().register(shortcut) {
+      Ok(()) => state.insert(shortcut, action),
+      Err(err) => tracing::warn!("Shortcut {} unavailable: {}", accelerator, err),
+    }
+  }
+  Ok(())
+}
+
+fn handle_hotkey(app: &AppHandle, shortcut: &tauri_plugin_global_shortcut::Shortcut) {
+  let Some(action) = app.state::<hotkeys::HotkeyState>().action_for(shortcut) else {
+    return;
+  };
+  match action {
+    hotkeys::HotkeyAction::ToggleWindow => match app.get_webview_window("main") {
+      Some(window) if window.is_visible().unwrap_or(false) && window.is_focused().unwrap_or(false) => {
+        let _ = window.hide();
+      }
+      _ => show_main_window(app),
+    },
+    hotkeys::HotkeyAction::QuickReply => {
+      let app = app.clone();
+      tauri::async_runtime::spawn(async move {
+        if let Err(err) = open_quick_reply(&app).await {
+          tracing::warn!("Quick reply unavailable: {}", err);
+        }
+      });
+    }
+    hotkeys::HotkeyAction::ToggleMute => {
+      let _ = app.emit_all("hotkey://toggle-mute", json!({}));
+    }
+    hotkeys::HotkeyAction::PanicWipe => {
+      let app = app.clone();
+      tauri::async_runtime::spawn(async move {
+        wipe_all_data(&app).await;
+      });
+    }
+  }
+}
+
+/// Show the always-on-top quick-reply popover for the latest notification; the popover
+/// sends through `handle_notification_action`.
+async fn open_quick_reply(app: &AppHandle) -> Result<(), String> {
+  let path = index_db_path(app)?;
+  let latest = tauri::async_runtime::spawn_blocking(move || -> Result<Option<notification_history::NotificationRecord>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    notification_history::latest_shown(&conn)
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  let Some(latest) = latest.filter(|record| record.room_id.is_some()) else {
+    return Ok(());
+  };
+  let window = match app.get_webview_window("quick-reply") {
+    Some(window) => window,
+    None => tauri::WebviewWindowBuilder::new(app, "quick-reply", tauri::WebviewUrl::App("index.html#/quick-reply".into()))
+      .title("Quick reply")
+      .inner_size(420.0, 180.0)
+      .resizable(false)
+      .decorations(false)
+      .always_on_top(true)
+      .skip_taskbar(true)
+      .center()
+      .build()
+      .map_err(|e| e.to_string())?,
+  };
+  let _ = window.show();
+  let _ = window.set_focus();
+  let target = notification_actions::NotificationTarget {
+    account_key: latest.account_key.clone(),
+    room_id: latest.room_id.clone().unwrap_or_default(),
+    event_id: latest.event_id.clone(),
+  };
+  app
+    .emit_all("quick-reply://open", json!({ "target": target, "title": latest.title, "body": latest.body }))
+    .map_err(|e| e.to_string())
+}
+
+fn hotkey_list(app: &AppHandle) -> Result<Vec<hotkeys::HotkeyBinding>, String> {
+  let state = app.state::<hotkeys::HotkeyState>();
+  Ok(
+    hotkey_bindings(app)?
+      .into_iter()
+      .map(|(action, accelerator)| hotkeys::HotkeyBinding {
+        registered: accelerator.is_some() && state.is_registered(action),
+        action,
+        accelerator,
+      })
+      .collect(),
+  )
+}
+
+#[tauri::command]
+fn list_hotkeys(app: AppHandle) -> Result<Vec<hotkeys::HotkeyBinding>, String> {
+  hotkey_list(&app)
+}
+
+/// Rebind `action` (or clear it with `None`) at runtime. Fails without changing anything when
+/// another action uses the combination or the OS / another application already holds it.
+#[tauri::command]
+fn set_hotkey(
+  app: AppHandle,
+  action: hotkeys::HotkeyAction,
+  accelerator: Option<String>,
+) -> Result<Vec<hotkeys::HotkeyBinding>, String> {
+  let accelerator = accelerator.map(|a| a.trim().to_string()).filter(|a| !a.is_empty());
+  let shortcut = accelerator.as_deref().map(hotkeys::parse).transpose()?;
+  let bindings = hotkey_bindings(&app)?;
+  if let Some(shortcut) = &shortcut {
+    if let Some(other) = hotkeys::conflict(&bindings, action, shortcut) {
+      return Err(format!("{} is already used for {:?}", accelerator.unwrap_or_default(), other));
+    }
+  }
+  let state = app.state::<hotkeys::HotkeyState>();
+  let previous = state.shortcut_for(action);
+  if let Some(previous) = previous {
+    let _ = app.global_shortcut().unregister(previous);
+    state.remove(action);
+  }
+  if let Some(shortcut) = shortcut {
+    if let Err(err) = app.global_shortcut().register(shortcut) {
+      if let Some(previous) = previous {
+        if app.global_shortcut().register(previous).is_ok() {
+          state.insert(previous, action);
+        }
+      }
+      return Err(format!("The shortcut is taken by the system or another application: {}", err));
+    }
+    state.insert(shortcut, action);
+  }
+  let conn = Connection::open(index_db_path(&app)?).map_err(|e| e.to_string())?;
+  init_index_db(&conn).map_err(|e| e.to_string())?;
+  hotkeys::set_binding(&conn, action, accelerator.as_deref())?;
+  hotkey_list(&app)
+}
+
+/// Bring the window forward and navigate to a `matrix:` / matrix.to link. The link is also kept
+/// until the webview takes it, for links that launched the app.
+fn open_deep_link(app: &AppHandle, url: &str) -> bool {
+  let Some(link) = deep_links::parse(url) else {
+    return false;
+  };
+  show_main_window(app);
+  app.state::<deep_links::DeepLinkState>().set_pending(link.clone());
+  let _ = app.emit_all("deep-link://open", &link);
+  true
+}
+
+#[tauri::command]
+fn parse_matrix_link(url: String) -> Option<deep_links::MatrixLink> {
+  deep_links::parse(&url)
+}
+
+#[tauri::command]
+fn take_pending_deep_link(app: AppHandle) -> Option<deep_links::MatrixLink> {
+  app.state::<deep_links::DeepLinkState>().take_pending()
+}
+
+/// Open a link clicked in the app: Matrix links navigate in place, anything else goes to the browser.
+#[tauri::command]
+fn open_link(app: AppHandle, url: String) -> Result<bool, String> {
+  if open_deep_link(&app, &url) {
+    return Ok(true);
+  }
+  app.opener().open_url(url, None::<&str>).map_err(|e| e.to_string())?;
+  Ok(false)
+}
+
+async fn read_update_settings(app: &AppHandle) -> Result<updates::UpdateSettings, String> {
+  let store = StoreBuilder::new(app, STORE_FILE)
+      .build()
+      .map_err(|e| e.to_string())?;
+  match store.get(UPDATES_KEY) {
+    Some(v) => serde_json::from_value(v.clone()).map_err(|e| format!("Corrupt store: {}", e)),
+    None => Ok(updates::UpdateSettings::default()),
+  }
+}
+
+async fn write_update_settings(app: &AppHandle, settings: &updates::UpdateSettings) -> Result<(), String> {
+  let store = StoreBuilder::new(app, STORE_FILE)
+      .build()
+      .map_err(|e| e.to_string())?;
+  let v = serde_json::to_value(settings).map_err(|e| e.to_string())?;
+  store.set(UPDATES_KEY.to_string(), v);
+  store.save().map_err(|e| e.to_string())
+}
+
+/// Query the configured channel and remember what it offers.
+async fn check_update(app: &AppHandle) -> Result<Option<updates::UpdateInfo>, String> {
+  let channel = read_update_settings(app).await?.channel;
+  let update = app
+    .updater_builder()
+    .endpoints(vec![updates::endpoint(channel)?])
+    .map_err(|e| e.to_string())?
+    .build()
+    .map_err(|e| e.to_string())?
+    .check()
+    .await
+    .map_err(|e| e.to_string())?;
+  let state = app.state::<updates::UpdateState>();
+  state.set_found(update.map(|update| (update, channel)));
+  Ok(state.info())
+}
+
+async fn run_update_checks(app: AppHandle) {
+  tokio::time::sleep(std::time::Duration::from_secs(30)).await;
+  loop {
+    let auto_check = read_update_settings(&app).await.map(|s| s.auto_check).unwrap_or(false);
+    if auto_check {
+      match check_update(&app).await {
+        Ok(Some(info)) if !info.ready => {
+          let _ = app.emit_all("updater://available", &info);
+        }
+        Ok(_) => {}
+        Err(err) => tracing::warn!("Update check failed: {}", err),
+      }
+    }
+    tokio::time::sleep(std::time::Duration::from_secs(6 * 60 * 60)).await;
+  }
+}
+
+#[tauri::command]
+async fn get_update_settings(app: AppHandle) -> Result<updates::UpdateSettings, String> {
+  read_update_settings(&app).await
+}
+
+#[tauri::command]
+async fn set_update_settings(app: AppHandle, settings: updates::UpdateSettings) -> Result<(), String> {
+  let previous = read_update_settings(&app).await?;
+  write_update_settings(&app, &settings).await?;
+  if previous.channel != settings.channel {
+    app.state::<updates::UpdateState>().set_found(None);
+  }
+  Ok(())
+}
+
+#[tauri::command]
+async fn check_for_update(app: AppHandle) -> Result<Option<updates::UpdateInfo>, String> {
+  check_update(&app).await
+}
+
+/// Download the update found by the last check, emitting `updater://progress`. The signature is
+/// verified before the package is kept.
+#[tauri::command]
+async fn download_update(app: AppHandle) -> Result<updates::UpdateInfo, String> {
+  let state = app.state::<updates::UpdateState>();
+  let (update, _) = state.found().ok_or("No update available")?;
+  let progress = app.clone();
+  let mut downloaded: u64 = 0;
+  let bytes = update
+    .download(
+      move |chunk, total| {
+        downloaded += chunk as u64;
+        let _ = progress.emit_all("updater://progress", json!({ "downloaded": downloaded, "total": total }));
+      },
+      || {},
+    )
+    .await
+    .map_err(|e| e.to_string())?;
+  state.set_downloaded(bytes);
+  let info = state.info().ok_or("No update available")?;
+  let _ = app.emit_all("updater://ready", &info);
+  Ok(info)
+}
+
+/// Install the downloaded update. Without `restart_now` it is installed when the app quits.
+#[tauri::command]
+async fn install_update(app: AppHandle, restart_now: bool) -> Result<(), String> {
+  if !restart_now {
+    return app
+      .state::<updates::UpdateState>()
+      .info()
+      .filter(|info| info.ready)
+      .map(|_| ())
+      .ok_or_else(|| "No downloaded update".to_string());
+  }
+  let (update, bytes) = app
+    .state::<updates::UpdateState>()
+    .take_downloaded()
+    .ok_or("No downloaded update")?;
+  update.install(bytes).map_err(|e| e.to_string())?;
+  app.restart();
+}
+
+fn popout_db(app: &AppHandle) -> Result<Connection, String> {
+  let conn = Connection::open(index_db_path(app)?).map_err(|e| e.to_string())?;
+  init_index_db(&conn).map_err(|e| e.to_string())?;
+  Ok(conn)
+}
+
+fn window_geometry(window: &tauri::WebviewWindow) -> Option<popout_windows::WindowGeometry> {
+  let scale = window.scale_factor().ok()?;
+  let position = window.outer_position().ok()?.to_logical::<f64>(scale);
+  let size = window.inner_size().ok()?.to_logical::<f64>(scale);
+  Some(popout_windows::WindowGeometry {
+    x: position.x,
+    y: position.y,
+    width: size.width,
+    height: size.height,
+    maximized: window.is_maximized().unwrap_or(false),
+  })
+}
+
+/// Focus the popout if it is already open, otherwise create it where it was last placed.
+fn show_popout(app: &AppHandle, popout: &popout_windows::PopoutWindow, title: &str) -> Result<(), String> {
+  if let Some(window) = app.get_webview_window(&popout.label) {
+    let _ = window.show();
+    let _ = window.unminimize();
+    let _ = window.set_focus();
+    return Ok(());
+  }
+  let mut builder = tauri::WebviewWindowBuilder::new(app, &popout.label, tauri::WebviewUrl::App(popout_windows::route(popout).into()))
+    .title(title)
+    .min_inner_size(360.0, 320.0);
+  builder = match popout.geometry {
+    Some(geometry) => builder
+      .inner_size(geometry.width, geometry.height)
+      .position(geometry.x, geometry.y)
+      .maximized(geometry.maximized),
+    None => builder.inner_size(if popout.kind == popout_windows::PopoutKind::Call { 960.0 } else { 720.0 }, 720.0),
+  };
+  builder.build().map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+fn room_title(conn: &Connection, account_key: &str, room_id: &str) -> String {
+  event_cache::get_state_event(conn, account_key, room_id, "m.room.name", "")
+    .ok()
+    .flatten()
+    .and_then(|event| event.content.get("name").and_then(|v| v.as_str()).map(|s| s.to_string()))
+    .unwrap_or_else(|| room_id.to_string())
+}
+
+fn open_popout(
+  app: &AppHandle,
+  kind: popout_windows::PopoutKind,
+  account_key: String,
+  room_id: String,
+) -> Result<popout_windows::PopoutWindow, String> {
+  let conn = popout_db(app)?;
+  let label = popout_windows::label(kind, &account_key, &room_id);
+  let popout = popout_windows::list(&conn)?
+    .into_iter()
+    .find(|window| window.label == label)
+    .unwrap_or(popout_windows::PopoutWindow {
+      label,
+      kind,
+      account_key,
+      room_id,
+      geometry: None,
+    });
+  show_popout(app, &popout, &room_title(&conn, &popout.account_key, &popout.room_id))?;
+  popout_windows::save(&conn, &popout)?;
+  let _ = app.emit_all("popout://changed", popout_windows::list(&conn)?);
+  Ok(popout)
+}
+
+/// Reopen the room windows that were open when the app last quit.
+fn restore_popouts(app: &AppHandle) -> Result<(), String> {
+  let conn = popout_db(app)?;
+  popout_windows::clear_calls(&conn)?;
+  for popout in popout_windows::list(&conn)? {
+    if let Err(err) = show_popout(app, &popout, &room_title(&conn, &popout.account_key, &popout.room_id)) {
+      tracing::warn!("Unable to reopen {}: {}", popout.room_id, err);
+      popout_windows::remove(&conn, &popout.label)?;
+    }
+  }
+  Ok(())
+}
+
+/// The user closed a popout; it is not reopened on the next start.
+fn forget_popout(app: &AppHandle, label: &str) {
+  let Ok(conn) = popout_db(app) else {
+    return;
+  };
+  let _ = popout_windows::remove(&conn, label);
+  if let Ok(open) = popout_windows::list(&conn) {
+    let _ = app.emit_all("popout://changed", open);
+  }
+}
+
+/// Remember where each open popout sits so the layout comes back on restart.
+fn save_popout_layout(app: &AppHandle) {
+  let Ok(conn) = popout_db(app) else {
+    return;
+  };
+  for (label, window) in app.webview_windows() {
+    if !popout_windows::is_popout(&label) {
+      continue;
+    }
+    if let Some(geometry) = window_geometry(&window) {
+      let _ = popout_windows::set_geometry(&conn, &label, &geometry);
+    }
+  }
+}
+
+#[tauri::command]
+fn open_room_window(app: AppHandle, account_key: String, room_id: String) -> Result<popout_windows::PopoutWindow, String> {
+  open_popout(&app, popout_windows::PopoutKind::Room, account_key, room_id)
+}
+
+#[tauri::command]
+fn open_call_window(app: AppHandle, account_key: String, room_id: String) -> Result<popout_windows::PopoutWindow, String> {
+  open_popout(&app, popout_windows::PopoutKind::Call, account_key, room_id)
+}
+
+#[tauri::command]
+fn list_popout_windows(app: AppHandle) -> Result<Vec<popout_windows::PopoutWindow>, String> {
+  popout_windows::list(&popout_db(&app)?)
+}
+
+#[tauri::command]
+fn close_popout_window(app: AppHandle, label: String) -> Result<(), String> {
+  if !popout_windows::is_popout(&label) {
+    return Err("Not a popped-out window".into());
+  }
+  forget_popout(&app, &label);
+  if let Some(window) = app.get_webview_window(&label) {
+    window.destroy().map_err(|e| e.to_string())?;
+  }
+  Ok(())
+}
+
+/// Show the frozen screen full-size on the monitor under `cursor` for the region selection.
+fn open_capture_overlay(app: &AppHandle, cursor: tauri::PhysicalPosition<f64>) -> Result<(), String> {
+  if let Some(existing) = app.get_webview_window(screenshot::OVERLAY_LABEL) {
+    let _ = existing.destroy();
+  }
+  let overlay = tauri::WebviewWindowBuilder::new(
+    app,
+    screenshot::OVERLAY_LABEL,
+    tauri::WebviewUrl::App("index.html#/screenshot-overlay".into()),
+  )
+  .title("Select a region")
+  .decorations(false)
+  .always_on_top(true)
+  .skip_taskbar(true)
+  .resizable(false)
+  .visible(false)
+  .build()
+  .map_err(|e| e.to_string())?;
+  if let Ok(Some(monitor)) = app.monitor_from_point(cursor.x, cursor.y) {
+    let _ = overlay.set_position(*monitor.position());
+    let _ = overlay.set_size(*monitor.size());
+  }
+  let _ = overlay.set_fullscreen(true);
+  let _ = overlay.show();
+  let _ = overlay.set_focus();
+  Ok(())
+}
+
+/// Capture the screen, a window or a selected region and stage the PNG for `room_id` like a
+/// dropped file. Returns `None` when the region selection is cancelled.
+#[tauri::command]
+async fn capture_screenshot(
+  app: AppHandle,
+  state: tauri::State<'_, upload_staging::StagingState>,
+  mode: screenshot::ScreenshotMode,
+  account_key: Option<String>,
+  room_id: Option<String>,
+) -> Result<Option<upload_staging::StagedUpload>, String> {
+  let cursor = app.cursor_position().map_err(|e| e.to_string())?;
+  // Keep our own windows out of screen captures.
+  let hidden: Vec<tauri::WebviewWindow> = match mode {
+    screenshot::ScreenshotMode::Window { .. } => Vec::new(),
+    _ => app
+      .webview_windows()
+      .into_values()
+      .filter(|window| window.is_visible().unwrap_or(false))
+      .collect(),
+  };
+  for window in &hidden {
+    let _ = window.hide();
+  }
+  if !hidden.is_empty() {
+    tokio::time::sleep(std::time::Duration::from_millis(250)).await;
+  }
+  let captured = {
+    let mode = mode.clone();
+    tauri::async_runtime::spawn_blocking(move || match mode {
+      screenshot::ScreenshotMode::Window { window_id } => screenshot::capture_window(window_id),
+      _ => screenshot::capture_screen(cursor.x as i32, cursor.y as i32),
+    })
+    .await
+    .map_err(|e| e.to_string())
+    .and_then(|result| result)
+  };
+  let image = match (captured, mode) {
+    (Ok(screen), screenshot::ScreenshotMode::Region) => {
+      let selected = app.state::<screenshot::ScreenshotState>().begin(screen)?;
+      if let Err(err) = open_capture_overlay(&app, cursor) {
+        let _ = app.state::<screenshot::ScreenshotState>().complete(None);
+        Err(err)
+      } else {
+        let image = selected.await.ok().flatten();
+        if let Some(overlay) = app.get_webview_window(screenshot::OVERLAY_LABEL) {
+          let _ = overlay.destroy();
+        }
+        Ok(image)
+      }
+    }
+    (captured, _) => captured.map(Some),
+  };
+  for window in &hidden {
+    let _ = window.show();
+  }
+  let Some(image) = image? else {
+    return Ok(None);
+  };
+  let dir = media_cache_dir(&app)?.join("screenshots");
+  let path = tauri::async_runtime::spawn_blocking(move || screenshot::save(&dir, &image))
+    .await
+    .map_err(|e| e.to_string())??;
+  let staged = stage_files(app, state, account_key, room_id, vec![path.display().to_string()]).await?;
+  Ok(staged.into_iter().next())
+}
+
+#[tauri::command]
+async fn list_capture_windows() -> Result<Vec<screenshot::CaptureWindow>, String> {
+  tauri::async_runtime::spawn_blocking(screenshot::windows)
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+/// The frozen screen for the region overlay's background.
+#[tauri::command]
+fn get_region_capture_preview(state: tauri::State<'_, screenshot::ScreenshotState>) -> Result<Option<String>, String> {
+  state.preview()
+}
+
+/// Called by the overlay with the selection in screen pixels, or `None` when cancelled.
+#[tauri::command]
+fn complete_region_capture(
+  state: tauri::State<'_, screenshot::ScreenshotState>,
+  region: Option<screenshot::CaptureRegion>,
+) -> Result<(), String> {
+  state.complete(region)
+}
+
+/// Shareable screens and windows, with previews unless `thumbnails` is false.
+#[tauri::command]
+async fn list_share_sources(thumbnails: Option<bool>) -> Result<Vec<screen_share::ShareSource>, String> {
+  let thumbnails = thumbnails.unwrap_or(true);
+  tauri::async_runtime::spawn_blocking(move || screen_share::sources(thumbnails))
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+/// Stream the selected source to the webview as JPEG frames over `on_frame`; the call layer
+/// paints them onto a canvas and publishes its `captureStream()` track. `screenshare://ended`
+/// is emitted when the source goes away.
+#[tauri::command]
+fn start_screen_share(
+  app: AppHandle,
+  state: tauri::State<'_, screen_share::ScreenShareState>,
+  source_id: String,
+  fps: Option<u32>,
+  max_width: Option<u32>,
+  on_frame: tauri::ipc::Channel,
+) -> Result<String, String> {
+  screen_share::capture(&source_id)?;
+  let (session_id, running) = state.start()?;
+  let interval = std::time::Duration::from_millis(1000 / fps.unwrap_or(15).clamp(1, 30) as u64);
+  let max_width = max_width.unwrap_or(1920);
+  let session = session_id.clone();
+  std::thread::spawn(move || {
+    while running.load(std::sync::atomic::Ordering::SeqCst) {
+      let started = std::time::Instant::now();
+      let frame = screen_share::capture(&source_id).and_then(|image| screen_share::encode_frame(image, max_width, 80));
+      let sent = match frame {
+        Ok(jpeg) => on_frame.send(tauri::ipc::InvokeResponseBody::Raw(jpeg)).map_err(|e| e.to_string()),
+        Err(err) => Err(err),
+      };
+      if let Err(err) = sent {
+        app.state::<screen_share::ScreenShareState>().stop(&session);
+        let _ = app.emit_all("screenshare://ended", json!({ "sessionId": session, "error": err }));
+        break;
+      }
+      std::thread::sleep(interval.saturating_sub(started.elapsed()));
+    }
+  });
+  Ok(session_id)
+}
+
+#[tauri::command]
+fn stop_screen_share(state: tauri::State<'_, screen_share::ScreenShareState>, session_id: String) -> bool {
+  state.stop(&session_id)
+}
+
+async fn read_idle_settings(app: &AppHandle) -> Result<idle::IdleSettings, String> {
+  let path = index_db_path(app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<idle::IdleSettings, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    idle::settings(&conn)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+async fn idle_status(app: &AppHandle) -> Result<idle::IdleStatus, String> {
+  let settings = read_idle_settings(app).await?;
+  let idle_seconds = tauri::async_runtime::spawn_blocking(idle::idle_seconds)
+    .await
+    .map_err(|e| e.to_string())??;
+  Ok(idle::IdleStatus {
+    idle: app.state::<idle::IdleState>().is_idle(),
+    idle_seconds,
+    suppress_read_receipts: settings.enabled && settings.suppress_read_receipts,
+  })
+}
+
+/// Switch online accounts to `unavailable`, or put back what they had before going idle.
+async fn apply_idle_presence(app: &AppHandle, idle: bool) -> Result<(), String> {
+  let state = app.state::<idle::IdleState>();
+  let accounts = read_accounts_map(app).await?;
+  if idle {
+    for (account_key, creds) in &accounts {
+      let client = MatrixClient::new(&creds.homeserver_url, &creds.user_id, &creds.access_token)?;
+      let Some(previous) = idle::presence(&client).await else {
+        continue;
+      };
+      if previous.get("presence").and_then(|v| v.as_str()) != Some("online") {
+        continue;
+      }
+      idle::set_unavailable(&client, &previous).await?;
+      state.remember(account_key, previous);
+    }
+  } else {
+    for (account_key, previous) in state.take_previous() {
+      let Some(creds) = accounts.get(&account_key) else {
+        continue;
+      };
+      let client = MatrixClient::new(&creds.homeserver_url, &creds.user_id, &creds.access_token)?;
+      idle::restore(&client, &previous).await?;
+    }
+  }
+  Ok(())
+}
+
+/// Poll the OS idle time and flip presence when the user leaves or comes back. The webview
+/// holds back automatic read receipts while `idle://changed` reports idle.
+async fn run_idle_monitor(app: AppHandle) {
+  loop {
+    let checked = async {
+      let settings = read_idle_settings(&app).await?;
+      let mut status = idle_status(&app).await?;
+      let idle = settings.enabled && status.idle_seconds >= settings.idle_minutes.max(1) as u64 * 60;
+      if app.state::<idle::IdleState>().set_idle(idle) {
+        status.idle = idle;
+        let _ = app.emit_all("idle://changed", &status);
+        apply_idle_presence(&app, idle).await?;
+      }
+      Ok::<_, String>(())
+    };
+    if let Err(err) = checked.await {
+      tracing::warn!("Idle check failed: {}", err);
+    }
+    tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+  }
+}
+
+#[tauri::command]
+async fn get_idle_settings(app: AppHandle) -> Result<idle::IdleSettings, String> {
+  read_idle_settings(&app).await
+}
+
+#[tauri::command]
+async fn set_idle_settings(app: AppHandle, settings: idle::IdleSettings) -> Result<(), String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<(), String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    idle::set_settings(&conn, &settings)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+async fn get_idle_status(app: AppHandle) -> Result<idle::IdleStatus, String> {
+  idle_status(&app).await
+}
+
+/// Swap in fresh access tokens for accounts whose token expired while offline and restart the
+/// background sync with them.
+async fn refresh_account_tokens(app: &AppHandle) -> Result<(), String> {
+  let mut accounts = read_accounts_map(app).await?;
+  let mut refreshed = false;
+  for (account_key, creds) in accounts.iter_mut() {
+    let Some(refresh_token) = creds.refresh_token.clone() else {
+      continue;
+    };
+    let client = MatrixClient::new(&creds.homeserver_url, &creds.user_id, &creds.access_token)?;
+    match connectivity::refresh_if_expired(&client, &refresh_token).await {
+      Ok(Some((access_token, rotated))) => {
+        creds.access_token = access_token;
+        if rotated.is_some() {
+          creds.refresh_token = rotated;
+        }
+        refreshed = true;
+        let _ = app.emit_all(
+          "account://token-refreshed",
+          json!({ "accountKey": account_key, "accessToken": creds.access_token, "refreshToken": creds.refresh_token }),
+        );
+      }
+      Ok(None) => {}
+      Err(err) => tracing::warn!(target: "sync", "Token refresh for {} failed: {}", account_key, err),
+    }
+  }
+  if refreshed {
+    write_accounts_map(app, &accounts).await?;
+    for account_key in accounts.keys() {
+      if app.state::<sync_engine::SyncEngineState>().is_running(account_key) {
+        spawn_sync_engine(app, account_key).await?;
+      }
+    }
+    if app.state::<background_sync::BackgroundSyncState>().stop_all() > 0 {
+      enter_background(app.clone()).await;
+    }
+  }
+  Ok(())
+}
+
+/// Park sync before sleep; on resume or a network switch reconnect right away instead of
+/// waiting out backoff timers. The webview gets the same events for its own sync.
+fn handle_connectivity(app: &AppHandle, event: connectivity::ConnectivityEvent) {
+  let signal = app.state::<connectivity::ConnectivityState>().signal.clone();
+  match event {
+    connectivity::ConnectivityEvent::Suspending => signal.suspend(),
+    connectivity::ConnectivityEvent::Resumed | connectivity::ConnectivityEvent::NetworkChanged => {
+      signal.wake();
+      let app = app.clone();
+      tauri::async_runtime::spawn(async move {
+        let status = proxy::refresh().await;
+        let _ = app.emit_all("proxy://changed", &status);
+        if let Err(err) = refresh_account_tokens(&app).await {
+          tracing::warn!(target: "sync", "Token refresh failed: {}", err);
+        }
+      });
+    }
+    connectivity::ConnectivityEvent::Offline => {}
+  }
+  let _ = app.emit_all("network://changed", json!({ "event": event }));
+}
+
+/// Watch for a changed default route and for wall clock jumps that give away a resume on
+/// platforms without suspend notifications.
+async fn run_connectivity_monitor(app: AppHandle) {
+  const INTERVAL_MS: i64 = 5_000;
+  let mut last_tick = now_millis();
+  loop {
+    tokio::time::sleep(std::time::Duration::from_millis(INTERVAL_MS as u64)).await;
+    let now = now_millis();
+    let slept = now - last_tick > INTERVAL_MS + connectivity::RESUME_GAP_MS;
+    last_tick = now;
+    let route = tauri::async_runtime::spawn_blocking(connectivity::default_route).await.ok().flatten();
+    let changed = app.state::<connectivity::ConnectivityState>().update_route(route);
+    if slept {
+      handle_connectivity(&app, connectivity::ConnectivityEvent::Resumed);
+    } else if let Some(event) = changed {
+      handle_connectivity(&app, event);
+    }
+  }
+}
+
+fn crash_dir(app: &AppHandle) -> Result<PathBuf, String> {
+  app
+    .path_resolver()
+    .app_data_dir()
+    .map(|dir| dir.join("crashes"))
+    .ok_or_else(|| "Unable to resolve application data directory".to_string())
+}
+
+async fn read_crash_settings(app: &AppHandle) -> Result<diagnostics::CrashSettings, String> {
+  let store = StoreBuilder::new(app, STORE_FILE)
+      .build()
+      .map_err(|e| e.to_string())?;
+  match store.get(CRASH_REPORTS_KEY) {
+    Some(v) => serde_json::from_value(v.clone()).map_err(|e| format!("Corrupt store: {}", e)),
+    None => Ok(diagnostics::CrashSettings::default()),
+  }
+}
+
+#[tauri::command]
+async fn get_crash_settings(app: AppHandle) -> Result<diagnostics::CrashSettings, String> {
+  read_crash_settings(&app).await
+}
+
+#[tauri::command]
+async fn set_crash_settings(app: AppHandle, settings: diagnostics::CrashSettings) -> Result<(), String> {
+  let store = StoreBuilder::new(&app, STORE_FILE)
+      .build()
+      .map_err(|e| e.to_string())?;
+  let v = serde_json::to_value(&settings).map_err(|e| e.to_string())?;
+  store.set(CRASH_REPORTS_KEY.to_string(), v);
+  store.save().map_err(|e| e.to_string())?;
+  diagnostics::set_enabled(settings.enabled);
+  if !settings.enabled {
+    diagnostics::clear_reports(&crash_dir(&app)?)?;
+  }
+  Ok(())
+}
+
+#[tauri::command]
+fn list_crash_reports(app: AppHandle) -> Result<Vec<diagnostics::CrashReport>, String> {
+  diagnostics::reports(&crash_dir(&app)?)
+}
+
+#[tauri::command]
+fn clear_crash_reports(app: AppHandle) -> Result<(), String> {
+  diagnostics::clear_reports(&crash_dir(&app)?)
+}
+
+/// Zip version info, index statistics, recent logs and crash reports for a bug report. Everything is scrubbed
+/// of tokens, IDs and message content; returns the path of the bundle.
+#[tauri::command]
+async fn create_diagnostics_bundle(app: AppHandle, dest_path: Option<String>) -> Result<String, String> {
+  let db_path = index_db_path(&app)?;
+  let stats = tauri::async_runtime::spawn_blocking(move || -> Result<serde_json::Value, String> {
+    let conn = Connection::open(&db_path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    diagnostics::index_stats(&conn, &db_path)
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  let accounts = read_accounts_map(&app).await?;
+  let mut entries = vec![
+    ("version.json".to_string(), diagnostics::version_info(tauri::VERSION).to_string()),
+    ("index-stats.json".to_string(), stats.to_string()),
+    (
+      "accounts.json".to_string(),
+      json!({
+        "count": accounts.len(),
+        "withRefreshToken": accounts.values().filter(|creds| creds.refresh_token.is_some()).count(),
+        "withPush": accounts.values().filter(|creds| creds.push_subscription.is_some()).count(),
+      })
+      .to_string(),
+    ),
+  ];
+  if let Ok(network) = fs::read_to_string(network_report_path(&app)?) {
+    entries.push(("network.json".to_string(), network));
+  }
+  for (path, tail) in logging::tails(&log_dir(&app)?, 256 * 1024) {
+    if let Some(name) = path.file_name() {
+      entries.push((format!("logs/{}", name.to_string_lossy()), tail));
+    }
+  }
+  for report in diagnostics::reports(&crash_dir(&app)?)? {
+    let value = serde_json::to_value(&report).map_err(|e| e.to_string())?;
+    entries.push((format!("crashes/{}.json", report.id), diagnostics::scrub_json(&value).to_string()));
+  }
+  let path = match dest_path {
+    Some(path) => PathBuf::from(path),
+    None => app
+      .path_resolver()
+      .app_data_dir()
+      .ok_or_else(|| "Unable to resolve application data directory".to_string())?
+      .join("diagnostics")
+      .join(format!("diagnostics-{}.zip", now_millis())),
+  };
+  let bundle = path.clone();
+  tauri::async_runtime::spawn_blocking(move || diagnostics::write_bundle(&bundle, entries))
+    .await
+    .map_err(|e| e.to_string())??;
+  Ok(path.display().to_string())
+}
+
+/// The last network report, included in diagnostics bundles.
+fn network_report_path(app: &AppHandle) -> Result<PathBuf, String> {
+  app
+    .path_resolver()
+    .app_data_dir()
+    .map(|dir| dir.join("diagnostics").join("network.json"))
+    .ok_or_else(|| "Unable to resolve application data directory".to_string())
+}
+
+/// Measure reachability, TLS, `/versions` latency, sync round trip and media throughput for
+/// the account's homeserver, along with the proxy in use.
+#[tauri::command]
+async fn run_network_diagnostics(app: AppHandle, account_key: String) -> Result<network_diagnostics::NetworkReport, String> {
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  let avatar = client
+    .get(&format!("/profile/{}/avatar_url", matrix_api::encode(&client.user_id)), &[])
+    .await
+    .ok()
+    .and_then(|profile| profile.get("avatar_url").and_then(|v| v.as_str()).map(|s| s.to_string()));
+  let sample_mxc = match avatar {
+    Some(mxc) => Some(mxc),
+    None => with_index_db(&app, move |conn| {
+      Ok(
+        conn
+          .query_row(
+            "SELECT mxc_url FROM media_index WHERE account_key = ?1 AND mxc_url LIKE 'mxc://%' ORDER BY timestamp DESC LIMIT 1",
+            [account_key],
+            |row| row.get::<_, String>(0),
+          )
+          .ok(),
+      )
+    })
+    .await
+    .ok()
+    .flatten(),
+  };
+  let report = network_diagnostics::run(&client, sample_mxc).await?;
+  let path = network_report_path(&app)?;
+  if let Some(dir) = path.parent() {
+    fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+  }
+  let scrubbed = diagnostics::scrub_json(&serde_json::to_value(&report).map_err(|e| e.to_string())?);
+  fs::write(&path, scrubbed.to_string()).map_err(|e| e.to_string())?;
+  Ok(report)
+}
+
+fn log_dir(app: &AppHandle) -> Result<PathBuf, String> {
+  app
+    .path_resolver()
+    .app_log_dir()
+    .ok_or_else(|| "Unable to resolve application log directory".to_string())
+}
+
+async fn read_log_levels(app: &AppHandle) -> Result<std::collections::BTreeMap<String, String>, String> {
+  let store = StoreBuilder::new(app, STORE_FILE)
+      .build()
+      .map_err(|e| e.to_string())?;
+  match store.get(LOG_LEVELS_KEY) {
+    Some(v) => serde_json::from_value(v.clone()).map_err(|e| format!("Corrupt store: {}", e)),
+    None => Ok(Default::default()),
+  }
+}
+
+#[tauri::command]
+fn get_recent_logs(filter: Option<logging::LogFilter>) -> Vec<logging::LogEntry> {
+  logging::recent(&filter.unwrap_or_default())
+}
+
+#[tauri::command]
+fn get_log_levels() -> std::collections::BTreeMap<String, String> {
+  logging::levels()
+}
+
+/// Set the level for a subsystem (`sync`, `crypto`, `deployment`, `index`, `app`) or a module
+/// target; `None` goes back to the default. Kept across restarts.
+#[tauri::command]
+async fn set_log_level(
+  app: AppHandle,
+  module: String,
+  level: Option<String>,
+) -> Result<std::collections::BTreeMap<String, String>, String> {
+  let levels = logging::set_level(&module, level.as_deref())?;
+  let store = StoreBuilder::new(&app, STORE_FILE)
+      .build()
+      .map_err(|e| e.to_string())?;
+  let v = serde_json::to_value(&levels).map_err(|e| e.to_string())?;
+  store.set(LOG_LEVELS_KEY.to_string(), v);
+  store.save().map_err(|e| e.to_string())?;
+  Ok(levels)
+}
+
+/// Endpoints the homeserver has rate-limited and how long requests to them are held back.
+#[tauri::command]
+fn get_rate_limit_state() -> Vec<rate_limit::ThrottleState> {
+  rate_limit::snapshot()
+}
+
+#[tauri::command]
+fn get_proxy_status() -> Option<proxy::ProxyStatus> {
+  proxy::status()
+}
+
+/// Read the system proxy settings again, e.g. after the user changed them.
+#[tauri::command]
+async fn redetect_proxy(app: AppHandle) -> Result<proxy::ProxyStatus, String> {
+  let status = proxy::refresh().await;
+  let _ = app.emit_all("proxy://changed", &status);
+  Ok(status)
+}
+
+fn read_element_export(path: &str) -> Result<element_import::ElementExport, String> {
+  let text = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+  element_import::parse_export(&text)
+}
+
+/// The pickle key given by the user, or Element Desktop's from the OS keychain.
+fn element_pickle_key(session: &element_import::ElementSession, pickle_key: Option<String>) -> Option<String> {
+  pickle_key
+    .filter(|key| !key.trim().is_empty())
+    .or_else(|| element_import::desktop_pickle_key(&session.user_id, &session.device_id))
+}
+
+/// What an Element export contains, before anything is imported.
+#[tauri::command]
+async fn preview_element_import(
+  path: String,
+  pickle_key: Option<String>,
+) -> Result<element_import::ElementImportPreview, String> {
+  tauri::async_runtime::spawn_blocking(move || {
+    let export = read_element_export(&path)?;
+    let session = element_import::session_info(&export)?;
+    let pickle_key = element_pickle_key(&session, pickle_key);
+    element_import::preview(&export, pickle_key.as_deref())
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Take over an Element Web / Desktop session: the same device ID and tokens, so other
+/// devices keep trusting it, plus its crypto state and optionally an exported room key file.
+/// The crypto state is held until the webview takes it with `take_element_crypto_import`.
+#[tauri::command]
+async fn import_element_session(
+  app: AppHandle,
+  path: String,
+  pickle_key: Option<String>,
+  room_keys_path: Option<String>,
+  room_keys_passphrase: Option<String>,
+) -> Result<StoredAccount, String> {
+  let (session, mut crypto) = tauri::async_runtime::spawn_blocking(move || {
+    let export = read_element_export(&path)?;
+    let pickle_key = element_pickle_key(&element_import::session_info(&export)?, pickle_key);
+    let session = element_import::session(&export, pickle_key.as_deref())?;
+    Ok::<_, String>((session, element_import::crypto(&export, pickle_key.as_deref())))
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+
+  if let Some(keys_path) = room_keys_path {
+    let armored = fs::read_to_string(&keys_path).map_err(|e| format!("Failed to read {}: {}", keys_path, e))?;
+    let passphrase = room_keys_passphrase.ok_or("The room key export needs its passphrase")?;
+    crypto.exported_room_keys =
+      tauri::async_runtime::spawn_blocking(move || element_import::decrypt_key_export(&armored, &passphrase))
+        .await
+        .map_err(|e| e.to_string())??;
+  }
+
+  let client = matrix_api::MatrixClient::new(&session.homeserver_url, &session.user_id, &session.access_token)?;
+  let whoami = client
+    .get("/account/whoami", &[])
+    .await
+    .map_err(|e| format!("The Element session is no longer valid: {}", e))?;
+  if whoami.get("device_id").and_then(|v| v.as_str()).is_some_and(|id| id != session.device_id) {
+    return Err("The access token belongs to a different device than the export".into());
+  }
+
+  let creds = Credentials {
+    homeserver_url: session.homeserver_url.clone(),
+    user_id: session.user_id.clone(),
+    access_token: session.access_token.clone(),
+    refresh_token: session.refresh_token.clone(),
+    device_id: Some(session.device_id.clone()),
+    push_subscription: None,
+  };
+  let key = make_key(&creds.homeserver_url, &creds.user_id);
+  let mut map = read_accounts_map(&app).await?;
+  map.insert(key.clone(), creds.clone());
+  write_accounts_map(&app, &map).await?;
+  tracing::info!(
+    "Imported Element session with {} Megolm sessions and {} exported room keys",
+    crypto.inbound_group_sessions.len(),
+    crypto.exported_room_keys.len()
+  );
+  app.state::<element_import::ElementImportState>().stage(&key, crypto);
+
+  Ok(StoredAccount {
+    key,
+    homeserver_url: creds.homeserver_url,
+    user_id: creds.user_id,
+    access_token: creds.access_token,
+    refresh_token: creds.refresh_token,
+    device_id: creds.device_id,
+    push_subscription: None,
+  })
+}
+
+/// The imported crypto state for the webview's crypto store; returned once.
+#[tauri::command]
+fn take_element_crypto_import(app: AppHandle, account_key: String) -> Option<element_import::ElementCrypto> {
+  app.state::<element_import::ElementImportState>().take(&account_key)
+}
+
+fn imports_dir(app: &AppHandle) -> Result<PathBuf, String> {
+  app
+    .path_resolver()
+    .app_data_dir()
+    .map(|dir| dir.join("imports"))
+    .ok_or_else(|| "Unable to resolve application data directory".to_string())
+}
+
+/// Import a Telegram (JSON) or WhatsApp (txt or zip) chat export into `target_room`, either
+/// posted as attributed messages with their media or, in `local` mode, kept in the search index
+/// only. Emits `import://progress`.
+#[tauri::command]
+async fn import_chat_archive(
+  app: AppHandle,
+  account_key: String,
+  path: String,
+  target_room: String,
+  mode: Option<chat_import::ImportMode>,
+  import_id: Option<String>,
+) -> Result<chat_import::ImportResult, String> {
+  let import_id = import_id.unwrap_or_else(|| format!("chat-import-{}", now_millis()));
+  let mode = mode.unwrap_or_default();
+  let work_dir = imports_dir(&app)?.join(&import_id);
+  let source_path = PathBuf::from(&path);
+  let extract_dir = work_dir.join("archive");
+  let archive = tauri::async_runtime::spawn_blocking(move || chat_import::open(&source_path, &extract_dir))
+    .await
+    .map_err(|e| e.to_string())??;
+  let mut progress = chat_import::ImportProgress {
+    import_id: import_id.clone(),
+    room_id: target_room,
+    completed: 0,
+    failed: 0,
+    total: archive.messages.len(),
+    done: false,
+  };
+  let _ = app.emit_all("import://progress", &progress);
+  let result = match mode {
+    chat_import::ImportMode::Post => post_chat_archive(&app, &account_key, &archive, &mut progress).await,
+    chat_import::ImportMode::Local => index_chat_archive(&app, &account_key, &archive, &work_dir, &mut progress).await,
+  };
+  // Unpacked zips are only needed until the media is posted or copied.
+  let _ = fs::remove_dir_all(work_dir.join("archive"));
+  if mode == chat_import::ImportMode::Post {
+    let _ = fs::remove_dir_all(&work_dir);
+  }
+  progress.done = true;
+  let _ = app.emit_all("import://progress", &progress);
+  Ok(chat_import::ImportResult {
+    import_id,
+    source: archive.source,
+    chat_name: archive.name,
+    messages: progress.completed,
+    media_files: result?,
+    failed: progress.failed,
+  })
+}
+
+async fn send_imported_event(client: &MatrixClient, room_id: &str, content: &serde_json::Value) -> Result<String, String> {
+  let mut attempt = 0;
+  loop {
+    match client.send_event(room_id, "m.room.message", content).await {
+      Ok(event_id) => return Ok(event_id),
+      Err(err) => match media_upload::retry_delay(&err, attempt) {
+        Some(delay) if attempt < 5 => {
+          attempt += 1;
+          tokio::time::sleep(delay).await;
+        }
+        _ => return Err(err.to_string()),
+      },
+    }
+  }
+}
+
+/// Post one archived message (its attachment first, then its text); returns the media count.
+async fn post_archived_message(
+  client: &MatrixClient,
+  archive: &chat_import::ChatArchive,
+  room_id: &str,
+  message: &chat_import::ArchivedMessage,
+) -> Result<usize, String> {
+  let mut media = 0;
+  if let Some(attachment) = &message.attachment {
+    let file = archive.root.join(attachment);
+    match fs::read(&file) {
+      Ok(bytes) => {
+        let (kind, mime) = chat_import::media_kind(&file);
+        let name = file.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        let size = bytes.len() as u64;
+        let mxc_url = client.upload(bytes, &mime, Some(&name)).await?;
+        let content = chat_import::media_content(archive.source, message, kind, &mime, &mxc_url, &name, size);
+        send_imported_event(client, room_id, &content).await?;
+        media += 1;
+      }
+      Err(err) => tracing::warn!("Attachment {} of the chat export is missing: {}", attachment, err),
+    }
+  }
+  if !message.text.is_empty() {
+    send_imported_event(client, room_id, &chat_import::text_content(archive.source, message)).await?;
+  }
+  Ok(media)
+}
+
+async fn post_chat_archive(
+  app: &AppHandle,
+  account_key: &str,
+  archive: &chat_import::ChatArchive,
+  progress: &mut chat_import::ImportProgress,
+) -> Result<usize, String> {
+  let client = MatrixClient::for_account(app, account_key).await?;
+  // Room encryption happens in the webview, so history can't be posted from here.
+  if room_is_encrypted(app, account_key, &client, &progress.room_id).await {
+    return Err("History can't be posted into an encrypted room; import it as a local archive instead".into());
+  }
+  let mut media_files = 0;
+  for message in &archive.messages {
+    match post_archived_message(&client, archive, &progress.room_id, message).await {
+      Ok(media) => {
+        media_files += media;
+        progress.completed += 1;
+      }
+      Err(err) => {
+        tracing::warn!("Failed to post imported message {}: {}", message.id, err);
+        progress.failed += 1;
+      }
+    }
+    let _ = app.emit_all("import://progress", &*progress);
+  }
+  Ok(media_files)
+}
+
+/// Keep the history on this device: media is copied under `work_dir/media` and every message
+/// goes into the search index under the target room.
+async fn index_chat_archive(
+  app: &AppHandle,
+  account_key: &str,
+  archive: &chat_import::ChatArchive,
+  work_dir: &std::path::Path,
+  progress: &mut chat_import::ImportProgress,
+) -> Result<usize, String> {
+  const BATCH: usize = 500;
+  let path = index_db_path(app)?;
+  let media_dir = work_dir.join("media");
+  let mut media_files = 0;
+  for chunk in archive.messages.chunks(BATCH) {
+    let mut payload = IndexUpsertPayload {
+      room_id: progress.room_id.clone(),
+      messages: Vec::new(),
+      media_items: Vec::new(),
+    };
+    for message in chunk {
+      let event_id = chat_import::local_event_id(archive.source, &archive.name, message);
+      let mut media_types = Vec::new();
+      if let Some(file) = message.attachment.as_ref().map(|a| archive.root.join(a)).filter(|f| f.exists()) {
+        fs::create_dir_all(&media_dir).map_err(|e| e.to_string())?;
+        let name = file.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        let target = media_export::unique_path(&media_dir, &name);
+        match fs::copy(&file, &target) {
+          Ok(size) => {
+            let (kind, mime) = chat_import::media_kind(&file);
+            let media_type = match kind {
+              "m.image" => "image",
+              "m.video" => "video",
+              _ => "file",
+            };
+            media_types.push(media_type.to_string());
+            payload.media_items.push(MediaItemRecord {
+              id: format!("{}-media", event_id),
+              event_id: event_id.clone(),
+              room_id: progress.room_id.clone(),
+              media_type: media_type.to_string(),
+              mxc_url: None,
+              thumbnail_mxc: None,
+              file_name: Some(name),
+              size: Some(size as i64),
+              mimetype: Some(mime),
+              sender: message.sender.clone(),
+              timestamp: message.timestamp,
+              body: Some(message.text.clone()).filter(|text| !text.is_empty()),
+              url: Some(target.to_string_lossy().to_string()),
+            });
+            media_files += 1;
+          }
+          Err(err) => tracing::warn!("Failed to copy attachment {}: {}", file.display(), err),
+        }
+      }
+      payload.messages.push(IndexedMessageRecord {
+        event_id,
+        room_id: progress.room_id.clone(),
+        sender: message.sender.clone(),
+        timestamp: message.timestamp,
+        body: Some(message.text.clone()),
+        tokens: chat_import::tokenize(&message.text, &message.sender),
+        tags: vec![format!("imported:{}", archive.source.as_str())],
+        reactions: Vec::new(),
+        has_media: !media_types.is_empty(),
+        media_types,
+        relates_to: None,
+        thread_root: None,
+      });
+    }
+    let db_path = path.clone();
+    let account_key = account_key.to_string();
+    tauri::async_runtime::spawn_blocking(move || -> Result<(), String> {
+      let conn = Connection::open(db_path).map_err(|e| e.to_string())?;
+      init_index_db(&conn).map_err(|e| e.to_string())?;
+      insert_index_records(&conn, &account_key, &payload)
+    })
+    .await
+    .map_err(|e| e.to_string())??;
+    progress.completed += chunk.len();
+    let _ = app.emit_all("import://progress", &*progress);
+  }
+  Ok(media_files)
+}
+
+/// Calendar invites and date mentions among `event_ids`, keyed by event ID.
+#[tauri::command]
+async fn detect_calendar_events(
+  app: AppHandle,
+  account_key: String,
+  room_id: String,
+  event_ids: Vec<String>,
+) -> Result<HashMap<String, calendar::CalendarCandidate>, String> {
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<HashMap<String, calendar::CalendarCandidate>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    let mut found = HashMap::new();
+    for event_id in event_ids {
+      if let Some(candidate) = event_cache::get_event(&conn, &account_key, &room_id, &event_id)?.as_ref().and_then(calendar::detect) {
+        found.insert(event_id, candidate);
+      }
+    }
+    Ok(found)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Write an .ics for a message: the attached invite as sent, or an event built from the date
+/// in the text. Without `dest_path` the file goes to the cache; `add_to_calendar` hands it to
+/// the OS calendar app. Returns the file path.
+#[tauri::command]
+async fn export_event_to_ics(
+  app: AppHandle,
+  account_key: String,
+  event_id: String,
+  dest_path: Option<String>,
+  add_to_calendar: Option<bool>,
+) -> Result<String, String> {
+  let path = index_db_path(&app)?;
+  let (lookup_account, lookup_id) = (account_key.clone(), event_id.clone());
+  let event = tauri::async_runtime::spawn_blocking(move || -> Result<Option<event_cache::CachedEvent>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    event_cache::find_event(&conn, &lookup_account, &lookup_id)
+  })
+  .await
+  .map_err(|e| e.to_string())??
+  .ok_or_else(|| format!("Event {} is not cached", event_id))?;
+  let candidate = calendar::detect(&event).ok_or("No calendar invite or date in this message")?;
+  let bytes = match candidate.kind {
+    calendar::CandidateKind::Invite => {
+      let file: Option<attachment_crypto::EncryptedFile> =
+        event.content.get("file").and_then(|f| serde_json::from_value(f.clone()).ok());
+      let mxc_url = file
+        .as_ref()
+        .map(|f| f.url.clone())
+        .or_else(|| event.content.get("url").and_then(|v| v.as_str()).map(|s| s.to_string()))
+        .ok_or("The invite has no attachment")?;
+      load_media(&app, &account_key, &mxc_url, file.as_ref(), false)
+        .await?
+        .map(|(_, bytes)| bytes)
+        .ok_or("The invite attachment is unavailable")?
+    }
+    calendar::CandidateKind::DateMention => calendar::to_ics(&event, &candidate, now_millis())?.into_bytes(),
+  };
+  let target = match dest_path {
+    Some(dest) => PathBuf::from(dest),
+    None => {
+      let dir = app
+        .path_resolver()
+        .app_cache_dir()
+        .ok_or_else(|| "Unable to resolve application cache directory".to_string())?
+        .join("calendar");
+      fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+      let name = media_download::sanitize_file_name(event.event_id.trim_start_matches('$'));
+      dir.join(format!("{}.ics", name))
+    }
+  };
+  fs::write(&target, bytes).map_err(|e| e.to_string())?;
+  let target = target.to_string_lossy().to_string();
+  if add_to_calendar.unwrap_or(false) {
+    app.opener().open_path(target.clone(), None::<&str>).map_err(|e| e.to_string())?;
+  }
+  Ok(target)
+}
+
+async fn read_webhook_settings(app: &AppHandle) -> Result<webhook::WebhookSettings, String> {
+  let store = StoreBuilder::new(app, STORE_FILE)
+      .build()
+      .map_err(|e| e.to_string())?;
+  match store.get(WEBHOOK_KEY) {
+    Some(v) => serde_json::from_value(v.clone()).map_err(|e| format!("Corrupt store: {}", e)),
+    None => Ok(Default::default()),
+  }
+}
+
+/// The account the webhook sends from: the configured one or the first saved account.
+async fn webhook_account(app: &AppHandle, settings: &webhook::WebhookSettings) -> Result<String, String> {
+  if let Some(key) = &settings.account_key {
+    return Ok(key.clone());
+  }
+  let mut keys: Vec<String> = read_accounts_map(app).await?.into_keys().collect();
+  keys.sort();
+  keys.into_iter().next().ok_or_else(|| "No account is signed in".to_string())
+}
+
+async fn handle_webhook_request(
+  app: AppHandle,
+  settings: std::sync::Arc<webhook::WebhookSettings>,
+  request: webhook::WebhookRequest,
+) -> webhook::WebhookResponse {
+  use webhook::WebhookResponse;
+  let account_key = match webhook_account(&app, &settings).await {
+    Ok(key) => key,
+    Err(err) => return WebhookResponse::error(500, err),
+  };
+  let client = match MatrixClient::for_account(&app, &account_key).await {
+    Ok(client) => client,
+    Err(err) => return WebhookResponse::error(500, err),
+  };
+  let allowed = |room_id: &str| settings.allowed_rooms.is_empty() || settings.allowed_rooms.iter().any(|r| r == room_id);
+  let segments: Vec<&str> = request.path.iter().map(|s| s.as_str()).collect();
+  match (request.method.as_str(), segments.as_slice()) {
+    ("GET", ["rooms"]) => match client.get("/joined_rooms", &[]).await {
+      Ok(body) => {
+        let rooms: Vec<&str> = body
+          .get("joined_rooms")
+          .and_then(|v| v.as_array())
+          .map(|rooms| rooms.iter().filter_map(|r| r.as_str()).filter(|r| allowed(r)).collect())
+          .unwrap_or_default();
+        WebhookResponse::ok(json!({ "rooms": rooms }))
+      }
+      Err(err) => WebhookResponse::error(502, err.to_string()),
+    },
+    ("POST", ["rooms", room_id, "send"]) => {
+      if !allowed(room_id) {
+        return WebhookResponse::error(403, "Posting to this room is not allowed");
+      }
+      let send = match webhook::parse_send(&request) {
+        Ok(send) => send,
+        Err(err) => return WebhookResponse::error(400, err),
+      };
+      let content = send.content();
+      // Encryption lives in the webview, which the webhook can't reach.
+      if room_is_encrypted(&app, &account_key, &client, room_id).await {
+        return WebhookResponse::error(501, "Posting to encrypted rooms is not supported");
+      }
+      match client.send_event(room_id, "m.room.message", &content).await {
+        Ok(event_id) => WebhookResponse::ok(json!({ "eventId": event_id })),
+        Err(err) => WebhookResponse::error(502, err.to_string()),
+      }
+    }
+    (_, ["rooms"]) | (_, ["rooms", _, "send"]) => WebhookResponse::error(405, "Method not allowed"),
+    _ => WebhookResponse::error(404, "Unknown endpoint"),
+  }
+}
+
+/// Start, restart or stop the localhost listener to match `settings`.
+async fn apply_webhook_settings(app: &AppHandle, settings: webhook::WebhookSettings) -> Result<(), String> {
+  let state = app.state::<webhook::WebhookState>();
+  state.replace(None);
+  if !settings.enabled {
+    return Ok(());
+  }
+  let (port, token) = (settings.port, settings.token.clone());
+  let settings = std::sync::Arc::new(settings);
+  let handler_app = app.clone();
+  let handler: webhook::Handler = std::sync::Arc::new(move |request| {
+    Box::pin(handle_webhook_request(handler_app.clone(), settings.clone(), request))
+  });
+  let stop = webhook::start(port, token, handler).await?;
+  state.replace(Some(stop));
+  tracing::info!("Local webhook listening on 127.0.0.1:{}", port);
+  Ok(())
+}
+
+#[tauri::command]
+async fn get_webhook_settings(app: AppHandle) -> Result<webhook::WebhookSettings, String> {
+  read_webhook_settings(&app).await
+}
+
+/// Save the webhook settings and apply them; a token is generated when none is set.
+#[tauri::command]
+async fn set_webhook_settings(
+  app: AppHandle,
+  mut settings: webhook::WebhookSettings,
+) -> Result<webhook::WebhookSettings, String> {
+  if settings.token.trim().is_empty() {
+    settings.token = webhook::generate_token();
+  }
+  let store = StoreBuilder::new(&app, STORE_FILE)
+      .build()
+      .map_err(|e| e.to_string())?;
+  let v = serde_json::to_value(&settings).map_err(|e| e.to_string())?;
+  store.set(WEBHOOK_KEY.to_string(), v);
+  store.save().map_err(|e| e.to_string())?;
+  apply_webhook_settings(&app, settings.clone()).await?;
+  Ok(settings)
+}
+
+/// Compile the plugins folder again and apply the saved grants.
+async fn reload_wasm_plugins(app: &AppHandle) -> Result<Vec<wasm_plugins::PluginInfo>, String> {
+  let path = index_db_path(app)?;
+  let dir = plugins_dir(app)?;
+  let app = app.clone();
+  tauri::async_runtime::spawn_blocking(move || -> Result<Vec<wasm_plugins::PluginInfo>, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    let grants = wasm_plugins::grants(&conn)?;
+    Ok(app.state::<wasm_plugins::PluginRuntime>().reload(&dir, &grants))
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+async fn list_wasm_plugins(app: AppHandle) -> Result<Vec<wasm_plugins::PluginInfo>, String> {
+  reload_wasm_plugins(&app).await
+}
+
+/// Enable or disable a plugin and choose which of its requested permissions it gets.
+#[tauri::command]
+async fn set_wasm_plugin_permissions(
+  app: AppHandle,
+  plugin_id: String,
+  enabled: bool,
+  granted: Vec<String>,
+) -> Result<Vec<wasm_plugins::PluginInfo>, String> {
+  if let Some(unknown) = granted.iter().find(|p| !wasm_plugins::PERMISSIONS.contains(&p.as_str())) {
+    return Err(format!("Unknown permission {}", unknown));
+  }
+  let path = index_db_path(&app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<(), String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    wasm_plugins::set_grant(&conn, &plugin_id, &wasm_plugins::Grant { enabled, permissions: granted })
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  reload_wasm_plugins(&app).await
+}
+
+/// Pass outgoing message content through the enabled transformer plugins before it is sent.
+#[tauri::command]
+async fn transform_outgoing_message(
+  app: AppHandle,
+  room_id: String,
+  content: serde_json::Value,
+) -> Result<serde_json::Value, String> {
+  tauri::async_runtime::spawn_blocking(move || {
+    app.state::<wasm_plugins::PluginRuntime>().transform_message(&room_id, content)
+  })
+  .await
+  .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn list_plugin_commands(runtime: tauri::State<'_, wasm_plugins::PluginRuntime>) -> Vec<wasm_plugins::PluginCommand> {
+  runtime.commands().into_iter().map(|(_, command)| command).collect()
+}
+
+/// Run a plugin slash command. `None` means no plugin registered it.
+#[tauri::command]
+async fn run_plugin_command(
+  app: AppHandle,
+  room_id: String,
+  command: String,
+  args: String,
+) -> Result<Option<wasm_plugins::CommandResult>, String> {
+  tauri::async_runtime::spawn_blocking(move || {
+    app.state::<wasm_plugins::PluginRuntime>().run_command(&room_id, &command, &args)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Read contacts from a vCard file, or the system address book without `path`, and match them
+/// to Matrix users.
+#[tauri::command]
+async fn import_contacts(
+  app: AppHandle,
+  account_key: String,
+  path: Option<String>,
+  default_country_code: Option<String>,
+) -> Result<contacts::ContactMatchResult, String> {
+  let text = tauri::async_runtime::spawn_blocking(move || match path {
+    Some(path) => fs::read(&path)
+      .map(|bytes| String::from_utf8_lossy(&bytes).to_string())
+      .map_err(|e| format!("Failed to read {}: {}", path, e)),
+    None => contacts::read_address_book(),
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  let parsed = contacts::parse_vcards(&text);
+  if parsed.is_empty() {
+    return Err("No contacts found".into());
+  }
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  Ok(contacts::match_contacts(&client, parsed, default_country_code.as_deref()).await)
+}
+
+/// Invite suggested contacts to a room one by one, waiting out rate limits.
+#[tauri::command]
+async fn invite_contacts(
+  app: AppHandle,
+  account_key: String,
+  room_id: String,
+  user_ids: Vec<String>,
+) -> Result<Vec<contacts::InviteOutcome>, String> {
+  let client = MatrixClient::for_account(&app, &account_key).await?;
+  let path = format!("/rooms/{}/invite", matrix_api::encode(&room_id));
+  let mut outcomes = Vec::new();
+  for (index, user_id) in user_ids.into_iter().enumerate() {
+    let mut attempt = 0;
+    let result = loop {
+      match client.post(&path, &json!({ "user_id": user_id })).await {
+        Ok(_) => break Ok(()),
+        Err(err) => match media_upload::retry_delay(&err, attempt) {
+          Some(delay) if attempt < 5 => {
+            attempt += 1;
+            tokio::time::sleep(delay).await;
+          }
+          _ => break Err(err.to_string()),
+        },
+      }
+    };
+    outcomes.push(contacts::InviteOutcome {
+      user_id,
+      invited: result.is_ok(),
+      error: result.err(),
+    });
+    let _ = app.emit_all("contacts://invite-progress", json!({ "roomId": room_id, "done": index + 1 }));
+  }
+  Ok(outcomes)
+}
+
+/// Run a blocking closure against the index database.
+async fn with_index_db<T: Send + 'static>(
+  app: &AppHandle,
+  f: impl FnOnce(&Connection) -> Result<T, String> + Send + 'static,
+) -> Result<T, String> {
+  let path = index_db_path(app)?;
+  tauri::async_runtime::spawn_blocking(move || -> Result<T, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    init_index_db(&conn).map_err(|e| e.to_string())?;
+    f(&conn)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+/// Whether native code must keep plaintext out of `room_id`. A cached `m.room.encryption`
+/// settles it; otherwise the homeserver is asked, since the cache is usually empty.
+async fn room_is_encrypted(app: &AppHandle, account_key: &str, client: &MatrixClient, room_id: &str) -> bool {
+  let (cached_account, cached_room) = (account_key.to_string(), room_id.to_string());
+  let cached = with_index_db(app, move |conn| {
+    Ok(event_cache::get_state_event(conn, &cached_account, &cached_room, "m.room.encryption", "")?.is_some())
+  })
+  .await;
+  matches!(cached, Ok(true)) || client.room_may_be_encrypted(room_id).await
+}
+
+/// Fetch one subscription and post what's new. Returns the number of entries posted.
+async fn poll_feed(app: &AppHandle, subscription: &feeds::FeedSubscription, max_posts: usize) -> Result<usize, String> {
+  let outcome = feeds::fetch(
+    &subscription.url,
+    subscription.etag.as_deref(),
+    subscription.last_modified.as_deref(),
+  )
+  .await;
+  let id = subscription.id;
+  let (feed, etag, last_modified) = match outcome {
+    Ok(feeds::FetchOutcome::NotModified) => {
+      with_index_db(app, move |conn| feeds::record_check(conn, id, None, None, None)).await?;
+      return Ok(0);
+    }
+    Ok(feeds::FetchOutcome::Fetched { feed, etag, last_modified }) => (feed, etag, last_modified),
+    Err(err) => {
+      let message = err.clone();
+      with_index_db(app, move |conn| feeds::record_check(conn, id, None, None, Some(message.as_str()))).await?;
+      return Err(err);
+    }
+  };
+  let (unseen, feed) = with_index_db(app, move |conn| Ok((feeds::unseen(conn, id, &feed)?, feed))).await?;
+  let title = if feed.title.is_empty() { subscription.title.clone() } else { feed.title.clone() };
+  let skipped = unseen.len().saturating_sub(max_posts);
+  let mut seen: Vec<String> = unseen[..skipped].iter().map(|entry| entry.id.clone()).collect();
+  let mut posted = 0;
+  let mut error = None;
+  if !unseen.is_empty() {
+    let client = MatrixClient::for_account(app, &subscription.account_key).await?;
+    // Encryption lives in the webview, so entries are only ever posted as plaintext.
+    let entries = if room_is_encrypted(app, &subscription.account_key, &client, &subscription.room_id).await {
+      error = Some("Feeds can't post into encrypted rooms".to_string());
+      &unseen[..0]
+    } else {
+      &unseen[skipped..]
+    };
+    for entry in entries {
+      match send_imported_event(&client, &subscription.room_id, &feeds::entry_content(&title, entry)).await {
+        Ok(_) => {
+          seen.push(entry.id.clone());
+          posted += 1;
+        }
+        Err(err) => {
+          error = Some(err);
+          break;
+        }
+      }
+    }
+  }
+  with_index_db(app, move |conn| {
+    feeds::mark_seen(conn, id, &seen)?;
+    // Keep the old validators after a failed post so the entries are fetched again.
+    match &error {
+      Some(err) => feeds::record_check(conn, id, None, None, Some(err.as_str())),
+      None => feeds::record_check(conn, id, etag.as_deref(), last_modified.as_deref(), None),
+    }
+  })
+  .await?;
+  if posted > 0 {
+    let _ = app.emit_all("feeds://posted", json!({ "subscriptionId": id, "roomId": subscription.room_id, "count": posted }));
+  }
+  Ok(posted)
+}
+
+/// Poll due feed subscriptions every minute.
+async fn run_feeds(app: AppHandle) {
+  loop {
+    match with_index_db(&app, |conn| feeds::due(conn, now_millis())).await {
+      Ok(due) => {
+        for subscription in due {
+          if let Err(err) = poll_feed(&app, &subscription, feeds::MAX_POSTS_PER_POLL).await {
+            tracing::warn!("Feed {} failed: {}", subscription.url, err);
+          }
+        }
+      }
+      Err(err) => tracing::warn!("Feed check failed: {}", err),
+    }
+    tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+  }
+}
+
+#[tauri::command]
+async fn list_feed_subscriptions(app: AppHandle, room_id: Option<String>) -> Result<Vec<feeds::FeedSubscription>, String> {
+  with_index_db(&app, move |conn| feeds::list(conn, room_id.as_deref())).await
+}
+
+/// Subscribe a room to a feed. The feed is fetched right away to check it and its newest entry
+/// is posted; older entries are marked as seen.
+#[tauri::command]
+async fn add_feed_subscription(
+  app: AppHandle,
+  account_key: String,
+  room_id: String,
+  url: String,
+  interval_minutes: Option<u32>,
+) -> Result<feeds::FeedSubscription, String> {
+  let url = url.trim().to_string();
+  let feeds::FetchOutcome::Fetched { feed, .. } = feeds::fetch(&url, None, None).await? else {
+    return Err("The feed returned no content".into());
+  };
+  let title = if feed.title.is_empty() { url.clone() } else { feed.title.clone() };
+  let interval = interval_minutes.unwrap_or(feeds::DEFAULT_INTERVAL_MINUTES);
+  let subscription = with_index_db(&app, move |conn| {
+    let id = feeds::insert(conn, &account_key, &room_id, &url, &title, interval)?;
+    let older: Vec<String> = feed.entries.iter().skip(feeds::INITIAL_POSTS).map(|entry| entry.id.clone()).collect();
+    feeds::mark_seen(conn, id, &older)?;
+    feeds::get(conn, id)?.ok_or_else(|| "Subscription not saved".to_string())
+  })
+  .await?;
+  if let Err(err) = poll_feed(&app, &subscription, feeds::INITIAL_POSTS).await {
+    tracing::warn!("Feed {} failed: {}", subscription.url, err);
+  }
+  let id = subscription.id;
+  with_index_db(&app, move |conn| feeds::get(conn, id)?.ok_or_else(|| "Subscription not found".to_string())).await
+}
+
+#[tauri::command]
+async fn update_feed_subscription(
+  app: AppHandle,
+  id: i64,
+  enabled: bool,
+  interval_minutes: u32,
+) -> Result<Vec<feeds::FeedSubscription>, String> {
+  with_index_db(&app, move |conn| {
+    feeds::update(conn, id, enabled, interval_minutes)?;
+    feeds::list(conn, None)
+  })
+  .await
+}
+
+#[tauri::command]
+async fn remove_feed_subscription(app: AppHandle, id: i64) -> Result<(), String> {
+  with_index_db(&app, move |conn| feeds::remove(conn, id)).await
+}
+
+/// Poll a subscription now instead of waiting for its interval.
+#[tauri::command]
+async fn check_feed_now(app: AppHandle, id: i64) -> Result<usize, String> {
+  let subscription = with_index_db(&app, move |conn| feeds::get(conn, id))
+    .await?
+    .ok_or_else(|| "Subscription not found".to_string())?;
+  poll_feed(&app, &subscription, feeds::MAX_POSTS_PER_POLL).await
+}
+
+/// Send one due scheduled message. Encrypted rooms get it through the webview's outbox
+/// (`scheduled://send`), which confirms with `complete_scheduled_message`.
+async fn dispatch_scheduled_message(app: &AppHandle, message: scheduled_messages::ScheduledMessage) -> Result<(), String> {
+  let id = message.id;
+  if message.status == scheduled_messages::ScheduledStatus::HandedOff && message.attempts >= scheduled_messages::MAX_ATTEMPTS {
+    let status = with_index_db(app, move |conn| {
+      scheduled_messages::record_failure(conn, id, "The app never confirmed sending the message", false)
+    })
+    .await?;
+    let _ = app.emit_all("scheduled://changed", json!({ "id": id, "status": status }));
+    return Ok(());
+  }
+  let client = MatrixClient::for_account(app, &message.account_key).await?;
+  if room_is_encrypted(app, &message.account_key, &client, &message.room_id).await {
+    with_index_db(app, move |conn| scheduled_messages::mark_handed_off(conn, id)).await?;
+    let _ = app.emit_all(
+      "scheduled://send",
+      json!({
+        "id": id,
+        "accountKey": message.account_key,
+        "roomId": message.room_id,
+        "content": message.content,
+        "txnId": message.txn_id(),
+      }),
+    );
+    return Ok(());
+  }
+  let sent = client
+    .send_event_with_txn(&message.room_id, "m.room.message", &message.txn_id(), &message.content)
+    .await;
+  let status = match sent {
+    Ok(event_id) => {
+      with_index_db(app, move |conn| scheduled_messages::mark_sent(conn, id, &event_id)).await?;
+      scheduled_messages::ScheduledStatus::Sent
+    }
+    Err(err) => {
+      let retryable = media_upload::retry_delay(&err, 0).is_some();
+      let error = err.to_string();
+      with_index_db(app, move |conn| scheduled_messages::record_failure(conn, id, &error, retryable)).await?
+    }
+  };
+  let _ = app.emit_all("scheduled://changed", json!({ "id": id, "status": status }));
+  Ok(())
+}
+
+/// Send scheduled messages as they fall due, including ones missed while the app was closed.
+async fn run_scheduled_messages(app: AppHandle) {
+  let _ = with_index_db(&app, |conn| scheduled_messages::prune_sent(conn, 30 * 24 * 60 * 60 * 1000)).await;
+  loop {
+    match with_index_db(&app, |conn| scheduled_messages::due(conn, now_millis())).await {
+      Ok(due) => {
+        for message in due {
+          let id = message.id;
+          if let Err(err) = dispatch_scheduled_message(&app, message).await {
+            tracing::warn!("Scheduled message {} failed: {}", id, err);
+          }
+        }
+      }
+      Err(err) => tracing::warn!("Scheduled message check failed: {}", err),
+    }
+    tokio::time::sleep(std::time::Duration::from_secs(15)).await;
+  }
+}
+
+#[tauri::command]
+async fn schedule_message(
+  app: AppHandle,
+  account_key: String,
+  room_id: String,
+  content: serde_json::Value,
+  send_at: i64,
+) -> Result<scheduled_messages::ScheduledMessage, String> {
+  with_index_db(&app, move |conn| scheduled_messages::schedule(conn, &account_key, &room_id, &content, send_at)).await
+}
+
+#[tauri::command]
+async fn list_scheduled_messages(
+  app: AppHandle,
+  room_id: Option<String>,
+) -> Result<Vec<scheduled_messages::ScheduledMessage>, String> {
+  with_index_db(&app, move |conn| scheduled_messages::list_pending(conn, room_id.as_deref())).await
+}
+
+#[tauri::command]
+async fn edit_scheduled_message(
+  app: AppHandle,
+  id: i64,
+  content: Option<serde_json::Value>,
+  send_at: Option<i64>,
+) -> Result<scheduled_messages::ScheduledMessage, String> {
+  with_index_db(&app, move |conn| scheduled_messages::edit(conn, id, content.as_ref(), send_at)).await
+}
+
+#[tauri::command]
+async fn cancel_scheduled_message(app: AppHandle, id: i64) -> Result<(), String> {
+  with_index_db(&app, move |conn| scheduled_messages::cancel(conn, id)).await
+}
+
+/// Report the outcome of a `scheduled://send` hand-off from the webview.
+#[tauri::command]
+async fn complete_scheduled_message(
+  app: AppHandle,
+  id: i64,
+  event_id: Option<String>,
+  error: Option<String>,
+) -> Result<(), String> {
+  let status = with_index_db(&app, move |conn| match event_id {
+    Some(event_id) => scheduled_messages::mark_sent(conn, id, &event_id).map(|_| scheduled_messages::ScheduledStatus::Sent),
+    None => scheduled_messages::record_failure(conn, id, error.as_deref().unwrap_or("Send failed"), true),
+  })
+  .await?;
+  let _ = app.emit_all("scheduled://changed", json!({ "id": id, "status": status }));
+  Ok(())
+}
+
+/// Notify about a due reminder; clicking the notification opens the message.
+async fn fire_reminder(app: &AppHandle, reminder: reminders::Reminder) -> Result<(), String> {
+  let id = reminder.id;
+  with_index_db(app, move |conn| reminders::mark_fired(conn, id)).await?;
+  let (title, body) = reminder.describe();
+  let target = notification_actions::NotificationTarget {
+    account_key: reminder.account_key.clone(),
+    room_id: reminder.room_id.clone(),
+    event_id: Some(reminder.event_id.clone()),
+  };
+  let media = rich_notifications::NotificationMedia::default();
+  let actions = app.state::<notification_actions::NotificationActionState>();
+  if actions
+    .show(notification_actions::NotificationKind::Message, &title, &body, target, None, &media)
+    .await
+    .is_none()
+  {
+    show_plain_notification(app, &title, &body, &media);
+  }
+  let _ = app.emit_all("reminders://due", &reminder);
+  Ok(())
+}
+
+/// Fire reminders as they fall due, including ones missed while the app was closed.
+async fn run_reminders(app: AppHandle) {
+  loop {
+    match with_index_db(&app, |conn| reminders::due(conn, now_millis())).await {
+      Ok(due) => {
+        for reminder in due {
+          let id = reminder.id;
+          if let Err(err) = fire_reminder(&app, reminder).await {
+            tracing::warn!("Reminder {} failed: {}", id, err);
+          }
+        }
+      }
+      Err(err) => tracing::warn!("Reminder check failed: {}", err),
+    }
+    tokio::time::sleep(std::time::Duration::from_secs(20)).await;
+  }
+}
+
+#[tauri::command]
+async fn set_reminder(
+  app: AppHandle,
+  account_key: String,
+  room_id: String,
+  event_id: String,
+  remind_at: i64,
+  note: Option<String>,
+) -> Result<reminders::Reminder, String> {
+  with_index_db(&app, move |conn| {
+    reminders::set(conn, &account_key, &room_id, &event_id, remind_at, note.as_deref())
+  })
+  .await
+}
+
+#[tauri::command]
+async fn list_reminders(
+  app: AppHandle,
+  account_key: Option<String>,
+  include_done: Option<bool>,
+) -> Result<Vec<reminders::Reminder>, String> {
+  with_index_db(&app, move |conn| {
+    reminders::list(conn, account_key.as_deref(), include_done.unwrap_or(false))
+  })
+  .await
+}
+
+#[tauri::command]
+async fn snooze_reminder(app: AppHandle, id: i64, minutes: u32) -> Result<reminders::Reminder, String> {
+  with_index_db(&app, move |conn| reminders::snooze(conn, id, minutes)).await
+}
+
+#[tauri::command]
+async fn complete_reminder(app: AppHandle, id: i64) -> Result<(), String> {
+  with_index_db(&app, move |conn| reminders::complete(conn, id)).await
+}
+
+#[tauri::command]
+async fn delete_reminder(app: AppHandle, id: i64) -> Result<(), String> {
+  with_index_db(&app, move |conn| reminders::delete(conn, id)).await
+}
+
+/// Saves the page so it stays readable offline; relative links keep pointing at the original site.
+async fn snapshot_read_later_page(app: &AppHandle, id: i64, url: &str) -> Result<(), String> {
+  let (preview, html) = url_preview::fetch_page(url).await?;
+  let Some(mut html) = html else {
+    return Err("Only HTML pages can be saved".into());
+  };
+  let lower = html.to_ascii_lowercase();
+  let head = lower
+    .match_indices("<head")
+    .map(|(at, _)| at)
+    .find(|at| matches!(lower.as_bytes().get(at + 5), Some(b'>' | b' ' | b'\t' | b'\n' | b'\r')));
+  if let Some(head) = head {
+    if let Some(end) = html[head..].find('>') {
+      let base = format!("<base href=\"{}\">", url.replace('"', "%22"));
+      html.insert_str(head + end + 1, &base);
+    }
+  }
+  let dir = read_later_dir(app)?;
+  fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+  let path = dir.join(format!("{}.html", id)).to_string_lossy().to_string();
+  fs::write(&path, html).map_err(|e| e.to_string())?;
+  with_index_db(app, move |conn| {
+    read_later::set_snapshot(
+      conn,
+      id,
+      &path,
+      preview.title.as_deref(),
+      preview.description.as_deref().and_then(read_later::excerpt).as_deref(),
+    )
+  })
+  .await
+}
+
+/// Park a message (by `event_id`) or a link (by `url`) in the read-later list, optionally
+/// saving an offline copy of the linked page.
+#[tauri::command]
+async fn archive_item(
+  app: AppHandle,
+  account_key: String,
+  event_id: Option<String>,
+  url: Option<String>,
+  room_id: Option<String>,
+  snapshot: Option<bool>,
+) -> Result<read_later::ReadLaterItem, String> {
+  let mut item = with_index_db(&app, move |conn| {
+    let (room_id, excerpt, link) = match event_id.as_deref() {
+      Some(event_id) => match read_later::describe_event(conn, &account_key, event_id)? {
+        Some((room, excerpt, link)) => (room_id.or(Some(room)), excerpt, link),
+        None => (room_id, None, None),
+      },
+      None => (room_id, None, None),
+    };
+    read_later::add(
+      conn,
+      &account_key,
+      room_id.as_deref(),
+      event_id.as_deref(),
+      url.or(link).as_deref(),
+      None,
+      excerpt.as_deref(),
+    )
+  })
+  .await?;
+  if snapshot.unwrap_or(false) && item.snapshot_path.is_none() {
+    if let Some(url) = item.url.clone() {
+      match snapshot_read_later_page(&app, item.id, &url).await {
+        Ok(()) => {
+          let id = item.id;
+          if let Some(updated) = with_index_db(&app, move |conn| read_later::get(conn, id)).await? {
+            item = updated;
+          }
+        }
+        Err(err) => tracing::warn!(target: "read_later", "snapshot of {} failed: {}", url, err),
+      }
+    }
+  }
+  let _ = app.emit_all("read-later://changed", ());
+  Ok(item)
+}
+
+#[tauri::command]
+async fn list_read_later(
+  app: AppHandle,
+  account_key: Option<String>,
+  unread_only: Option<bool>,
+) -> Result<Vec<read_later::ReadLaterItem>, String> {
+  with_index_db(&app, move |conn| {
+    read_later::list(conn, account_key.as_deref(), unread_only.unwrap_or(false))
+  })
+  .await
+}
+
+#[tauri::command]
+async fn set_read_later_read(app: AppHandle, id: i64, read: bool) -> Result<(), String> {
+  with_index_db(&app, move |conn| read_later::set_read(conn, id, read)).await?;
+  let _ = app.emit_all("read-later://changed", ());
+  Ok(())
+}
+
+#[tauri::command]
+async fn remove_read_later(app: AppHandle, id: i64) -> Result<(), String> {
+  let removed = with_index_db(&app, move |conn| read_later::remove(conn, id)).await?;
+  if let Some(path) = removed.and_then(|item| item.snapshot_path) {
+    let _ = fs::remove_file(path);
+  }
+  let _ = app.emit_all("read-later://changed", ());
+  Ok(())
+}
+
+/// Opens the saved copy of a page in the default browser.
+#[tauri::command]
+async fn open_read_later_snapshot(app: AppHandle, id: i64) -> Result<(), String> {
+  let item = with_index_db(&app, move |conn| read_later::get(conn, id))
+    .await?
+    .ok_or_else(|| "Read-later item not found".to_string())?;
+  let path = item
+    .snapshot_path
+    .filter(|path| std::path::Path::new(path).is_file())
+    .ok_or_else(|| "No saved copy of this page".to_string())?;
+  app.opener().open_path(path, None::<&str>).map_err(|e| e.to_string())
+}
+
+async fn read_translation_config(app: &AppHandle) -> Result<Option<translation::TranslationConfig>, String> {
+  let store = StoreBuilder::new(app, STORE_FILE)
+      .build()
+      .map_err(|e| e.to_string())?;
+  Ok(store.get(TRANSLATION_KEY).and_then(|v| serde_json::from_value(v.clone()).ok()))
+}
+
+/// The configured translation backend, without its API key.
+#[tauri::command]
+async fn get_translation_config(app: AppHandle) -> Result<Option<translation::TranslationConfig>, String> {
+  Ok(read_translation_config(&app).await?.map(|config| config.redacted()))
+}
+
+/// Store the translation backend in the credential store; `None` disables translation.
+/// A missing API key keeps the stored one when the backend is unchanged.
+#[tauri::command]
+async fn set_translation_config(app: AppHandle, config: Option<translation::TranslationConfig>) -> Result<(), String> {
+  let previous = read_translation_config(&app).await?;
+  let store = StoreBuilder::new(&app, STORE_FILE)
+      .build()
+      .map_err(|e| e.to_string())?;
+  match config {
+    Some(mut config) => {
+      if let (None, Some(previous)) = (&config.api_key, previous) {
+        if previous.backend == config.backend {
+          config.api_key = previous.api_key;
+        }
+      }
+      let v = serde_json::to_value(&config).map_err(|e| e.to_string())?;
+      store.set(TRANSLATION_KEY.to_string(), v);
+    }
+    None => {
+      store.delete(TRANSLATION_KEY);
+    }
+  }
+  store.save().map_err(|e| e.to_string())
+}
+
+/// Translate a message into `target_lang`, from the cache when the text hasn't changed.
+/// `text` is the decrypted body for encrypted rooms, where the cache only has ciphertext.
+#[tauri::command]
+async fn translate_message(
+  app: AppHandle,
+  account_key: String,
+  event_id: String,
+  target_lang: String,
+  text: Option<String>,
+) -> Result<translation::Translation, String> {
+  let target_lang = translation::normalize_lang(&target_lang);
+  if target_lang.is_empty() {
+    return Err("No target language".into());
+  }
+  let lookup_event = event_id.clone();
+  let lookup_lang = target_lang.clone();
+  let (source, cached) = with_index_db(&app, move |conn| {
+    let source = match text {
+      Some(text) => text,
+      None => event_cache::find_event(conn, &account_key, &lookup_event)?
+        .and_then(|event| event.content.get("body").and_then(|v| v.as_str()).map(|s| s.to_string()))
+        .ok_or_else(|| "Message text is not available".to_string())?,
+    };
+    let hash = translation::source_hash(&source);
+    let cached = translation::cached(conn, &lookup_event, &lookup_lang, &hash)?;
+    Ok((source, cached))
+  })
+  .await?;
+  if let Some(cached) = cached {
+    return Ok(cached);
+  }
+  if source.trim().is_empty() {
+    return Err("Nothing to translate".into());
+  }
+  let config = read_translation_config(&app)
+    .await?
+    .ok_or_else(|| "No translation backend configured".to_string())?;
+  let translated = translation::translate(&config, &event_id, &source, &target_lang).await?;
+  let stored = translated.clone();
+  with_index_db(&app, move |conn| translation::store(conn, &stored, &translation::source_hash(&source))).await?;
+  Ok(translated)
+}
+
+#[tauri::command]
+async fn get_room_translation(app: AppHandle, room_id: String) -> Result<translation::RoomTranslation, String> {
+  with_index_db(&app, move |conn| translation::room_settings(conn, &room_id)).await
+}
+
+#[tauri::command]
+async fn set_room_translation(app: AppHandle, room_id: String, settings: translation::RoomTranslation) -> Result<(), String> {
+  with_index_db(&app, move |conn| translation::set_room_settings(conn, &room_id, &settings)).await
+}
+
+/// Purge local copies for rooms with a retention policy; the webview drops what it still holds.
+async fn enforce_retention(app: &AppHandle, room_id: Option<String>) -> Result<Vec<retention::RetentionReport>, String> {
+  let media_dir = media_cache_dir(app)?;
+  let reports = with_index_db(app, move |conn| {
+    let policies = match room_id {
+      Some(room_id) => retention::policy(conn, &room_id)?.into_iter().collect(),
+      None => retention::policies(conn)?,
+    };
+    policies
+      .iter()
+      .map(|policy| retention::enforce(conn, &media_dir, policy))
+      .collect::<Result<Vec<_>, String>>()
+  })
+  .await?;
+  let purged: Vec<&retention::RetentionReport> = reports.iter().filter(|report| !report.is_empty()).collect();
+  if !purged.is_empty() {
+    let _ = app.emit_all("retention://purged", &purged);
+  }
+  Ok(reports)
+}
+
+/// Apply the index-wide pruning policy and compact the database.
+async fn prune_index_store(app: &AppHandle) -> Result<retention::PruneReport, String> {
+  let report = with_index_db(app, |conn| {
+    let policy = retention::pruning_policy(conn)?;
+    retention::prune(conn, &policy)
+  })
+  .await?;
+  if report.removed() > 0 {
+    let _ = app.emit_all("index://pruned", &report);
+  }
+  Ok(report)
+}
+
+async fn run_retention(app: AppHandle) {
+  // Let startup sync settle before touching the cache.
+  tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+  if let Err(err) = prune_index_store(&app).await {
+    tracing::warn!("Index pruning failed: {}", err);
+  }
+  loop {
+    if let Err(err) = enforce_retention(&app, None).await {
+      tracing::warn!("Retention run failed: {}", err);
+    }
+    tokio::time::sleep(std::time::Duration::from_secs(60 * 60)).await;
+  }
+}
+
+#[tauri::command]
+async fn list_room_retention(app: AppHandle) -> Result<Vec<retention::RetentionPolicy>, String> {
+  with_index_db(&app, retention::policies).await
+}
+
+/// Keep local copies of the room's messages for `max_age_days`; `None` keeps them forever.
+#[tauri::command]
+async fn set_room_retention(
+  app: AppHandle,
+  room_id: String,
+  max_age_days: Option<u32>,
+) -> Result<Option<retention::RetentionPolicy>, String> {
+  with_index_db(&app, move |conn| retention::set_policy(conn, &room_id, max_age_days)).await
+}
+
+/// Enforce the room's retention policy right away.
+#[tauri::command]
+async fn apply_room_retention(app: AppHandle, room_id: String) -> Result<Option<retention::RetentionReport>, String> {
+  Ok(enforce_retention(&app, Some(room_id)).await?.into_iter().next())
+}
+
+#[tauri::command]
+async fn get_index_pruning(app: AppHandle) -> Result<retention::IndexPruningPolicy, String> {
+  with_index_db(&app, retention::pruning_policy).await
+}
+
+/// Limits for the whole search index; applied on the next startup or `prune_index`.
+#[tauri::command]
+async fn set_index_pruning(app: AppHandle, policy: retention::IndexPruningPolicy) -> Result<(), String> {
+  with_index_db(&app, move |conn| retention::set_pruning_policy(conn, &policy)).await
+}
+
+/// Prune the index by its policy right away and reclaim the freed space.
+#[tauri::command]
+async fn prune_index(app: AppHandle) -> Result<retention::PruneReport, String> {
+  prune_index_store(&app).await
+}
+
+/// End every session on its homeserver, then empty the stores and shred the data, cache and
+/// log directories (search index, event cache, media cache, credentials, seed backups).
+/// The app restarts into a clean state.
+async fn wipe_all_data(app: &AppHandle) {
+  let mut report = panic_wipe::WipeReport::default();
+  let accounts = read_accounts_map(app).await.unwrap_or_default();
+  for (key, creds) in &accounts {
+    let logout = async {
+      let client = MatrixClient::new(&creds.homeserver_url, &creds.user_id, &creds.access_token)?;
+      client.post("/logout", &json!({})).await.map_err(|e| e.to_string())
+    };
+    match tokio::time::timeout(std::time::Duration::from_secs(10), logout).await {
+      Ok(Ok(_)) => report.logged_out.push(key.clone()),
+      _ => report.logout_failed.push(key.clone()),
+    }
+  }
+  // The store plugin keeps its contents in memory and would write them back.
+  for file in [STORE_FILE, BACKUP_STORE_FILE] {
+    if let Ok(store) = StoreBuilder::new(app, file).build() {
+      store.clear();
+      let _ = store.save();
+    }
+  }
+  if let Err(err) = keychain::forget() {
+    report.errors.push(format!("OS keychain: {}", err));
+  }
+  let resolver = app.path_resolver();
+  for dir in [resolver.app_data_dir(), resolver.app_cache_dir(), resolver.app_log_dir()]
+    .into_iter()
+    .flatten()
+  {
+    panic_wipe::shred_path(&dir, &mut report);
+  }
+  if let Some(window) = app.get_webview_window("main") {
+    let _ = window.clear_all_browsing_data();
+  }
+  tracing::warn!(
+    "Wiped local data: {} sessions logged out, {} failed, {} files removed",
+    report.logged_out.len(),
+    report.logout_failed.len(),
+    report.files_removed
+  );
+  let _ = app.emit_all("wipe://completed", &report);
+  app.restart();
+}
+
+/// Panic wipe; `confirmation_phrase` must match `panic_wipe::CONFIRMATION_PHRASE`.
+#[tauri::command]
+async fn secure_wipe_all_data(app: AppHandle, confirmation_phrase: String) -> Result<(), String> {
+  if !panic_wipe::confirmed(&confirmation_phrase) {
+    return Err(format!("Type \"{}\" to confirm", panic_wipe::CONFIRMATION_PHRASE));
+  }
+  wipe_all_data(&app).await;
+  Ok(())
+}
+
+type SealedStoreValues = (HashMap<String, Credentials>, HashMap<String, EncryptedBackup>, Option<[u8; 32]>);
+
+/// Everything the store keeps encrypted at rest, read with the current key.
+async fn read_sealed_values(app: &AppHandle) -> Result<SealedStoreValues, String> {
+  let store = StoreBuilder::new(app, STORE_FILE)
+      .build()
+      .map_err(|e| e.to_string())?;
+  let media_key = match store.get(MEDIA_CACHE_KEY) {
+    Some(_) => Some(media_cache_key(app).await?),
+    None => None,
+  };
+  Ok((read_accounts_map(app).await?, read_backups_map(app).await?, media_key))
+}
+
+/// Write the values back, sealed or in plaintext depending on the enrollment state.
+async fn write_sealed_values(app: &AppHandle, values: SealedStoreValues) -> Result<(), String> {
+  let (accounts, backups, media_key) = values;
+  write_accounts_map(app, &accounts).await?;
+  write_backups_map(app, &backups).await?;
+  if let Some(media_key) = media_key {
+    let store = StoreBuilder::new(app, STORE_FILE)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let v = seal_store_value(app, json!(general_purpose::STANDARD.encode(media_key)))?;
+    store.set(MEDIA_CACHE_KEY.to_string(), v);
+    store.save().map_err(|e| e.to_string())?;
+  }
+  Ok(())
+}
+
+#[tauri::command]
+fn get_security_key_status(app: AppHandle) -> Result<security_key::SecurityKeyStatus, String> {
+  let enrollments = security_key::load(&security_keys_path(&app)?)?;
+  let unlocked = app.state::<security_key::SecurityKeyState>().key().is_some();
+  Ok(security_key::status(&enrollments, unlocked))
+}
+
+/// Require a FIDO2 security key (hmac-secret) to open the credential store and seed backups.
+/// Additional keys can be enrolled while unlocked, e.g. a spare kept somewhere safe.
+#[tauri::command]
+async fn enroll_security_key(
+  app: AppHandle,
+  pin: Option<String>,
+  label: Option<String>,
+) -> Result<security_key::SecurityKeyStatus, String> {
+  let path = security_keys_path(&app)?;
+  let mut enrollments = security_key::load(&path)?;
+  let current = app.state::<security_key::SecurityKeyState>().key();
+  if !enrollments.is_empty() && current.is_none() {
+    return Err(security_key::LOCKED.to_string());
+  }
+  let values = read_sealed_values(&app).await?;
+  let (enrollment, master_key) = tauri::async_runtime::spawn_blocking(move || {
+    security_key::enroll(pin.as_deref(), label.as_deref().unwrap_or_default(), current)
+  })
+  .await
+  .map_err(|e| e.to_string())??;
+  enrollments.push(enrollment);
+  security_key::save(&path, &enrollments)?;
+  app.state::<security_key::SecurityKeyState>().set(master_key);
+  write_sealed_values(&app, values).await?;
+  Ok(security_key::status(&enrollments, true))
+}
+
+#[tauri::command]
+async fn unlock_with_security_key(app: AppHandle, pin: Option<String>) -> Result<security_key::SecurityKeyStatus, String> {
+  let enrollments = security_key::load(&security_keys_path(&app)?)?;
+  let lookup = enrollments.clone();
+  let master_key = tauri::async_runtime::spawn_blocking(move || security_key::unlock(&lookup, pin.as_deref()))
+    .await
+    .map_err(|e| e.to_string())??;
+  app.state::<security_key::SecurityKeyState>().set(master_key);
+  let _ = app.emit_all("security-key://unlocked", ());
+  Ok(security_key::status(&enrollments, true))
+}
+
+/// Stop requiring a security key; the store goes back to plaintext. Only while unlocked.
+#[tauri::command]
+async fn remove_security_key(app: AppHandle) -> Result<security_key::SecurityKeyStatus, String> {
+  if app.state::<security_key::SecurityKeyState>().key().is_none() {
+    return Err(security_key::LOCKED.to_string());
+  }
+  let values = read_sealed_values(&app).await?;
+  security_key::save(&security_keys_path(&app)?, &[])?;
+  app.state::<security_key::SecurityKeyState>().lock();
+  write_sealed_values(&app, values).await?;
+  Ok(security_key::status(&[], true))
+}
+
+/// Store one engine `/sync` response: room state and timelines go to the event cache, plaintext
+/// messages to the search index and to-device events to the queue the webview drains. Then
+/// the webview is told what changed.
+async fn apply_sync_response(app: &AppHandle, account_key: &str, user_id: &str, response: serde_json::Value) {
+  let updates = sync_engine::room_updates(&response);
+  let to_device = sync_engine::to_device_events(&response);
+  let next_batch = response.get("next_batch").and_then(|v| v.as_str()).map(|s| s.to_string());
+  let account = account_key.to_string();
+  let rooms = updates.clone();
+  let queued = to_device.clone();
+  let position = next_batch.clone();
+  let stored = with_index_db(app, move |conn| {
+    for room in &rooms {
+      let events: Vec<serde_json::Value> = room
+        .state
+        .iter()
+        .chain(room.timeline.iter())
+        .chain(room.ephemeral.iter())
+        .cloned()
+        .collect();
+      event_cache::cache_events(conn, &account, &room.room_id, &events)?;
+      let mut payload = index_payload_from_timeline(&room.room_id, &room.timeline);
+      retain_unindexed(conn, &account, &mut payload)?;
+      insert_index_records(conn, &account, &payload)?;
+    }
+    sync_engine::queue_to_device(conn, &account, &queued)?;
+    if let Some(position) = &position {
+      sync_engine::set_position(conn, &account, position)?;
+    }
+    Ok(())
+  })
+  .await;
+  if let Err(err) = &stored {
+    tracing::warn!(target: "sync", "Failed to store sync for {}: {}", account_key, err);
+  }
+  for room in &updates {
+    let _ = app.emit_all("sync://room", json!({ "accountKey": account_key, "room": room }));
+  }
+  if !to_device.is_empty() {
+    let _ = app.emit_all("sync://to-device", json!({ "accountKey": account_key, "count": to_device.len() }));
+  }
+  let engines = app.state::<sync_engine::SyncEngineState>();
+  let status = match (&stored, next_batch.as_deref()) {
+    (Ok(()), Some(next_batch)) => engines.update(account_key, Ok(next_batch)),
+    (Err(err), _) => engines.update(account_key, Err(err.as_str())),
+    _ => None,
+  };
+  if let Some(status) = status {
+    let _ = app.emit_all("sync://status", &status);
+  }
+  if engines.in_background() {
+    let notifications = background_sync::notifications_from_sync(&response, user_id);
+    if !notifications.is_empty() {
+      deliver_background_notifications(app, account_key, notifications).await;
+    }
+  }
+}
+
+/// (Re)start the account's sync engine from its stored position, or from the webview's
+/// latest token the first time.
+async fn spawn_sync_engine(app: &AppHandle, account_key: &str) -> Result<sync_engine::SyncStatus, String> {
+  let client = MatrixClient::for_account(app, account_key).await?;
+  let key = account_key.to_string();
+  let stored = with_index_db(app, move |conn| sync_engine::position(conn, &key)).await?;
+  let since = stored.or_else(|| app.state::<background_sync::BackgroundSyncState>().token(account_key));
+  let engines = app.state::<sync_engine::SyncEngineState>();
+  let stop = engines.begin(account_key);
+  let wake = app.state::<connectivity::ConnectivityState>().signal.clone();
+  let handle_app = app.clone();
+  let failed_app = app.clone();
+  let handle_key = account_key.to_string();
+  let failed_key = account_key.to_string();
+  let user_id = client.user_id.clone();
+  tauri::async_runtime::spawn(async move {
+    sync_engine::run(
+      client,
+      since,
+      stop,
+      wake,
+      move |response| {
+        let (app, key, user_id) = (handle_app.clone(), handle_key.clone(), user_id.clone());
+        async move { apply_sync_response(&app, &key, &user_id, response).await }
+      },
+      move |error| {
+        if let Some(status) = failed_app.state::<sync_engine::SyncEngineState>().update(&failed_key, Err(error.as_str())) {
+          let _ = failed_app.emit_all("sync://status", &status);
+        }
+      },
+    )
+    .await;
+  });
+  let status = engines
+    .statuses()
+    .into_iter()
+    .find(|status| status.account_key == account_key)
+    .unwrap_or_default();
+  let _ = app.emit_all("sync://status", &status);
+  Ok(status)
+}
+
+/// Let the backend sync the account so caching, indexing and notifications keep running
+/// while the webview is idle. Timeline and state changes arrive as `sync://room`.
+#[tauri::command]
+async fn start_sync_engine(app: AppHandle, account_key: String) -> Result<sync_engine::SyncStatus, String> {
+  spawn_sync_engine(&app, &account_key).await
+}
+
+/// Stop the account's engine; `forget` also drops its sync position so the next start
+/// begins from the webview's token.
+#[tauri::command]
+async fn stop_sync_engine(app: AppHandle, account_key: String, forget: Option<bool>) -> Result<bool, String> {
+  let stopped = app.state::<sync_engine::SyncEngineState>().stop(&account_key);
+  if forget.unwrap_or(false) {
+    let key = account_key.clone();
+    with_index_db(&app, move |conn| sync_engine::clear_position(conn, &key)).await?;
+  }
+  let _ = app.emit_all(
+    "sync://status",
+    sync_engine::SyncStatus {
+      account_key,
+      running: false,
+      ..Default::default()
+    },
+  );
+  Ok(stopped)
+}
+
+#[tauri::command]
+fn get_sync_engine_status(app: AppHandle) -> Vec<sync_engine::SyncStatus> {
+  app.state::<sync_engine::SyncEngineState>().statuses()
+}
+
+/// To-device events the engine received, for the webview's crypto to process. They are
+/// kept until `ack_to_device_events` confirms it did.
+#[tauri::command]
+async fn get_queued_to_device_events(app: AppHandle, account_key: String) -> Result<Vec<sync_engine::QueuedToDevice>, String> {
+  with_index_db(&app, move |conn| sync_engine::queued_to_device(conn, &account_key)).await
+}
+
+/// Drop the queued to-device events up to `up_to_id` after the webview processed them.
+#[tauri::command]
+async fn ack_to_device_events(app: AppHandle, account_key: String, up_to_id: i64) -> Result<usize, String> {
+  with_index_db(&app, move |conn| sync_engine::ack_to_device(conn, &account_key, up_to_id)).await
+}
+
+async fn read_backups_map(app: &AppHandle) -> Result<HashMap<String, EncryptedBackup>, String> {
+  let store = StoreBuilder::new(app, BACKUP_STORE_FILE)
+    .build()
+    .map_err(|e| e.to_string())?;
+  let value = store.get(BACKUP_KEY);
+  if let Some(v) = value {
+    serde_json::from_value::<HashMap<String, EncryptedBackup>>(open_store_value(app, v.clone())?)
+      .map_err(|e| e.to_string())
+  } else {
+    Ok(HashMap::new())
+  }
+}
+
+async fn write_backups_map(app: &AppHandle, map: &HashMap<String, EncryptedBackup>) -> Result<(), String> {
+  let store = StoreBuilder::new(app, BACKUP_STORE_FILE)
+    .build()
+    .map_err(|e| e.to_string())?;
+  let v = seal_store_value(app, serde_json::to_value(map).map_err(|e| e.to_string())?)?;
   store.set(BACKUP_KEY.to_string(), v);
   store.save().map_err(|e| e.to_string())
 }
@@ -747,6 +9012,8 @@ async fn load_credentials(app: AppHandle) -> Result<Vec<StoredAccount>, String>
       homeserver_url: c.homeserver_url,
       user_id: c.user_id,
       access_token: c.access_token,
+      refresh_token: c.refresh_token,
+      device_id: c.device_id,
       push_subscription: c.push_subscription,
     })
     .collect();
@@ -761,7 +9028,10 @@ async fn clear_credentials(app: AppHandle, key: Option<String>) -> Result<(), St
     let mut map = read_accounts_map(&app).await?;
     map.remove(&k);
     write_accounts_map(&app, &map).await?;
+    app.state::<sync_engine::SyncEngineState>().stop(&k);
+    with_index_db(&app, move |conn| sync_engine::clear_position(conn, &k)).await?;
   } else {
+    app.state::<sync_engine::SyncEngineState>().stop_all();
     // Clear entire collection
     let store = StoreBuilder::new(&app, STORE_FILE)
         .build()
@@ -847,10 +9117,15 @@ async fn remove_passkey_device(app: AppHandle, account_key: String, credential_i
   Ok(())
 }
 
+/// Saving always encrypts with the current KDF, so a PBKDF2 backup is upgraded to Argon2id
+/// the next time it is saved.
 #[tauri::command]
 async fn secure_store_save_seed(app: AppHandle, label: String, payload_json: String, passphrase: String) -> Result<(), String> {
   let mut map = read_backups_map(&app).await?;
-  let entry = encrypt_payload(&passphrase, &payload_json)?;
+  // Argon2id takes a noticeable moment and a chunk of memory; keep it off the async workers.
+  let entry = tauri::async_runtime::spawn_blocking(move || encrypt_payload(&passphrase, &payload_json))
+    .await
+    .map_err(|e| e.to_string())??;
   map.insert(label, entry);
   write_backups_map(&app, &map).await
 }
@@ -858,8 +9133,10 @@ async fn secure_store_save_seed(app: AppHandle, label: String, payload_json: Str
 #[tauri::command]
 async fn secure_store_load_seed(app: AppHandle, label: String, passphrase: String) -> Result<Option<String>, String> {
   let map = read_backups_map(&app).await?;
-  if let Some(entry) = map.get(&label) {
-    let decrypted = decrypt_payload(&passphrase, entry)?;
+  if let Some(entry) = map.get(&label).cloned() {
+    let decrypted = tauri::async_runtime::spawn_blocking(move || decrypt_payload(&passphrase, &entry))
+      .await
+      .map_err(|e| e.to_string())??;
     Ok(Some(decrypted))
   } else {
     Ok(None)
@@ -868,8 +9145,12 @@ async fn secure_store_load_seed(app: AppHandle, label: String, passphrase: Strin
 
 /// Deploy Matrix Synapse server via SSH
 #[tauri::command]
-async fn deploy_matrix_server(config: DeploymentConfig) -> Result<Vec<DeploymentStatus>, String> {
-  tokio::task::spawn_blocking(move || deploy_synapse_server(config))
+async fn deploy_matrix_server(app: AppHandle, config: DeploymentConfig) -> Result<Vec<DeploymentStatus>, String> {
+  tokio::task::spawn_blocking(move || {
+    deploy_synapse_server(config, |progress| {
+      let _ = app.emit_all("deployment://progress", &progress);
+    })
+  })
     .await
     .map_err(|e| format!("Deployment task failed: {}", e))?
 }
@@ -898,11 +9179,237 @@ async fn test_ssh_connection(
 }
 
 fn main() {
+  let mut args: Vec<String> = std::env::args().skip(1).collect();
+  if let Some(position) = args.iter().position(|arg| arg == headless::FLAG) {
+    args.remove(position);
+    std::process::exit(headless::run(args));
+  }
   tauri::Builder::default()
+    .plugin(tauri_plugin_single_instance::init(|app, argv, _cwd| {
+      show_main_window(app);
+      for arg in argv.iter().skip(1) {
+        open_deep_link(app, arg);
+      }
+    }))
+    .plugin(tauri_plugin_deep_link::init())
+    .plugin(tauri_plugin_opener::init())
+    .plugin(tauri_plugin_updater::Builder::new().build())
+    .plugin(
+      tauri::plugin::Builder::<tauri::Wry>::new("matrix-links")
+        .on_navigation(|webview, url| {
+          if deep_links::parse(url.as_str()).is_none() {
+            return true;
+          }
+          open_deep_link(webview.app_handle(), url.as_str());
+          false
+        })
+        .build(),
+    )
     .plugin(tauri_plugin_store::Builder::default().build())
     .plugin(tauri_plugin_secure_storage::Plugin::new())
     .plugin(tauri_plugin_notification::init())
+    .plugin(
+      tauri_plugin_global_shortcut::Builder::new()
+        .with_handler(|app, shortcut, event| {
+          if event.state() == tauri_plugin_global_shortcut::ShortcutState::Pressed {
+            handle_hotkey(app, shortcut);
+          }
+        })
+        .build(),
+    )
+    .manage(location::LiveLocationState::default())
+    .manage(media_download::DownloadState::default())
+    .manage(room_export::RoomExportState::default())
+    .manage(media_upload::UploadState::default())
+    .manage(upload_staging::StagingState::default())
+    .manage(auto_download::NetworkState::default())
+    .manage(voice::VoiceRecorderState::default())
+    .manage(unified_push::UnifiedPushState::default())
+    .manage(background_sync::BackgroundSyncState::default())
+    .manage(notification_actions::NotificationActionState::default())
+    .manage(badge::BadgeState::default())
+    .manage(notification_dispatch::NotificationDispatcher::default())
+    .manage(push_events::PushDecryptState::default())
+    .manage(tray::TrayState::default())
+    .manage(hotkeys::HotkeyState::default())
+    .manage(deep_links::DeepLinkState::default())
+    .manage(updates::UpdateState::default())
+    .manage(screenshot::ScreenshotState::default())
+    .manage(screen_share::ScreenShareState::default())
+    .manage(idle::IdleState::default())
+    .manage(connectivity::ConnectivityState::default())
+    .manage(element_import::ElementImportState::default())
+    .manage(webhook::WebhookState::default())
+    .manage(wasm_plugins::PluginRuntime::default())
+    .manage(ipc::IpcState::default())
+    .manage(security_key::SecurityKeyState::default())
+    .manage(sync_engine::SyncEngineState::default())
+    .register_asynchronous_uri_scheme_protocol(MEDIA_SCHEME, |ctx, request, responder| {
+      let app = ctx.app_handle().clone();
+      let header = |name: &str| request.headers().get(name).and_then(|v| v.to_str().ok()).map(|v| v.to_string());
+      let (origin, range) = (header("origin"), header("range"));
+      tauri::async_runtime::spawn(async move {
+        let result = resolve_media_request(&app, request.uri()).await;
+        responder.respond(media_response(origin.as_deref(), range.as_deref(), result));
+      });
+    })
     .setup(|app| {
+      if let Ok(dir) = log_dir(app.handle()) {
+        let levels = tauri::async_runtime::block_on(read_log_levels(app.handle())).unwrap_or_default();
+        if let Err(err) = logging::init(&dir, levels) {
+          eprintln!("Logging unavailable: {}", err);
+        }
+      }
+      if let Ok(dir) = crash_dir(app.handle()) {
+        let settings = tauri::async_runtime::block_on(read_crash_settings(app.handle())).unwrap_or_default();
+        diagnostics::install(dir, settings.enabled);
+      }
+      let webhook_handle = app.handle();
+      tauri::async_runtime::spawn(async move {
+        if let Ok(settings) = read_webhook_settings(&webhook_handle).await {
+          if let Err(err) = apply_webhook_settings(&webhook_handle, settings).await {
+            tracing::warn!("Local webhook unavailable: {}", err);
+          }
+        }
+      });
+      let ipc_handle = app.handle();
+      tauri::async_runtime::spawn(async move {
+        let handler_app = ipc_handle.clone();
+        let handler: ipc::Handler =
+          std::sync::Arc::new(move |request| Box::pin(handle_ipc_request(handler_app.clone(), request)));
+        if let Err(err) = ipc_handle.state::<ipc::IpcState>().start(handler).await {
+          tracing::warn!("Local IPC unavailable: {}", err);
+        }
+      });
+      let feeds_handle = app.handle();
+      tauri::async_runtime::spawn(async move {
+        run_feeds(feeds_handle).await;
+      });
+      let scheduled_handle = app.handle();
+      tauri::async_runtime::spawn(async move {
+        run_scheduled_messages(scheduled_handle).await;
+      });
+      let reminders_handle = app.handle();
+      tauri::async_runtime::spawn(async move {
+        run_reminders(reminders_handle).await;
+      });
+      let retention_handle = app.handle();
+      tauri::async_runtime::spawn(async move {
+        run_retention(retention_handle).await;
+      });
+      let vault_handle = app.handle();
+      tauri::async_runtime::spawn(async move {
+        if let Err(err) = encrypt_plaintext_store(&vault_handle).await {
+          tracing::warn!("Credential store not encrypted: {}", err);
+        }
+      });
+      let downloads_handle = app.handle();
+      tauri::async_runtime::spawn(async move {
+        if let Err(err) = with_index_db(&downloads_handle, media_download::mark_interrupted).await {
+          tracing::warn!("Failed to update interrupted downloads: {}", err);
+        }
+      });
+      let plugins_handle = app.handle();
+      tauri::async_runtime::spawn(async move {
+        if let Err(err) = reload_wasm_plugins(&plugins_handle).await {
+          tracing::warn!("Plugins not loaded: {}", err);
+        }
+      });
+      // Corporate networks only work through the proxy, so detect it before anything connects.
+      let status = tauri::async_runtime::block_on(proxy::refresh());
+      tracing::info!("Proxy: {:?} from {}", status.config, status.source);
+      let flush_handle = app.handle();
+      tauri::async_runtime::spawn(async move {
+        let _ = flush_report_queue(flush_handle).await;
+      });
+      let push_handle = app.handle();
+      tauri::async_runtime::spawn(async move {
+        run_unified_push(push_handle).await;
+      });
+      let dispatch_handle = app.handle();
+      tauri::async_runtime::spawn(async move {
+        run_notification_dispatch(dispatch_handle).await;
+      });
+      let actions_handle = app.handle();
+      tauri::async_runtime::spawn(async move {
+        run_notification_actions(actions_handle).await;
+      });
+      let dnd_handle = app.handle();
+      tauri::async_runtime::spawn(async move {
+        run_quiet_hours(dnd_handle).await;
+      });
+      let digest_handle = app.handle();
+      tauri::async_runtime::spawn(async move {
+        run_email_digest(digest_handle).await;
+      });
+      let update_handle = app.handle().clone();
+      tauri::async_runtime::spawn(async move {
+        run_update_checks(update_handle).await;
+      });
+      let idle_handle = app.handle().clone();
+      tauri::async_runtime::spawn(async move {
+        run_idle_monitor(idle_handle).await;
+      });
+      let connectivity_handle = app.handle().clone();
+      tauri::async_runtime::spawn(async move {
+        run_connectivity_monitor(connectivity_handle).await;
+      });
+      #[cfg(target_os = "linux")]
+      {
+        let sleep_handle = app.handle().clone();
+        tauri::async_runtime::spawn(async move {
+          let result = connectivity::watch_sleep(|event| handle_connectivity(&sleep_handle, event)).await;
+          if let Err(err) = result {
+            tracing::warn!(target: "sync", "Suspend notifications unavailable: {}", err);
+          }
+        });
+      }
+      let tray_handle = app.handle().clone();
+      let tray_enabled = index_db_path(&tray_handle)
+        .and_then(|path| {
+          let conn = Connection::open(path).map_err(|e| e.to_string())?;
+          init_index_db(&conn).map_err(|e| e.to_string())?;
+          tray::settings(&conn)
+        })
+        .map(|settings| settings.enabled)
+        .unwrap_or(true);
+      if tray_enabled {
+        match install_tray(&tray_handle) {
+          Ok(()) => {
+            tauri::async_runtime::spawn(async move {
+              let _ = refresh_badge(&tray_handle).await;
+            });
+          }
+          Err(err) => tracing::warn!("Tray icon unavailable: {}", err),
+        }
+      }
+      #[cfg(any(windows, target_os = "linux"))]
+      if let Err(err) = app.deep_link().register_all() {
+        tracing::warn!("Unable to register the matrix: scheme: {}", err);
+      }
+      let link_handle = app.handle().clone();
+      app.deep_link().on_open_url(move |event| {
+        for url in event.urls() {
+          open_deep_link(&link_handle, url.as_str());
+        }
+      });
+      if let Ok(Some(urls)) = app.deep_link().get_current() {
+        for url in urls {
+          open_deep_link(app.handle(), url.as_str());
+        }
+      }
+      if let Err(err) = restore_popouts(app.handle()) {
+        tracing::warn!("Unable to restore windows: {}", err);
+      }
+      if let Err(err) = register_hotkeys(app.handle()) {
+        tracing::warn!("Global shortcuts unavailable: {}", err);
+      }
+      if autostart::launched_minimized() {
+        if let Some(window) = app.get_webview_window("main") {
+          // Without a tray icon there would be no way back to a hidden window.
+          let _ = if tray_enabled { window.hide() } else { window.minimize() };
+        }
+      }
       #[cfg(not(debug_assertions))]
       {
         let handle = app.handle();
@@ -917,9 +9424,50 @@ fn main() {
       }
       Ok(())
     })
+    .on_window_event(|window, event| match event {
+      tauri::WindowEvent::CloseRequested { .. } if popout_windows::is_popout(window.label()) => {
+        forget_popout(window.app_handle(), window.label());
+      }
+      tauri::WindowEvent::CloseRequested { api, .. } if window.label() == "main" => {
+        let app = window.app_handle().clone();
+        let (background, to_tray) = index_db_path(&app)
+          .and_then(|path| {
+            let conn = Connection::open(path).map_err(|e| e.to_string())?;
+            init_index_db(&conn).map_err(|e| e.to_string())?;
+            let tray = tray::settings(&conn)?;
+            Ok((background_sync::settings(&conn)?.enabled, tray.enabled && tray.minimize_to_tray))
+          })
+          .unwrap_or((false, false));
+        if background || to_tray {
+          api.prevent_close();
+          let _ = window.hide();
+        } else {
+          // Popped-out windows would otherwise keep the app running without its main window.
+          app.exit(0);
+        }
+        if background {
+          tauri::async_runtime::spawn(async move {
+            enter_background(app).await;
+          });
+        }
+      }
+      tauri::WindowEvent::Focused(true) => {
+        let app = window.app_handle();
+        app.state::<sync_engine::SyncEngineState>().set_background(false);
+        if app.state::<background_sync::BackgroundSyncState>().stop_all() > 0 {
+          let _ = app.emit_all("app://foreground", json!({}));
+        }
+        let app = app.clone();
+        tauri::async_runtime::spawn(async move {
+          record_app_active(&app).await;
+        });
+      }
+      _ => {}
+    })
     .invoke_handler(tauri::generate_handler![
       save_credentials,
       load_credentials,
+      get_plaintext_store_warning,
       clear_credentials,
       save_passkey_device,
       list_passkey_devices,
@@ -930,11 +9478,387 @@ fn main() {
       upsert_index_records,
       query_local_index,
       load_room_index,
+      purge_room_index,
+      clear_index_store,
       get_smart_collections,
+      cache_room_events,
+      get_threads,
+      follow_room_upgrade,
+      ignore_user,
+      unignore_user,
+      get_ignored_users,
+      report_event,
+      report_room,
+      flush_report_queue,
+      search_public_rooms,
+      search_users,
+      record_sync_invites,
+      list_pending_invites,
+      accept_invite,
+      reject_invite,
+      knock_on_room,
+      get_account_data,
+      set_account_data,
+      record_sync_account_data,
+      get_direct_rooms,
+      get_room_tags,
+      set_display_name,
+      set_avatar,
+      get_profile,
+      list_threepids,
+      request_threepid_token,
+      submit_threepid_token,
+      add_threepid,
+      remove_threepid,
+      get_server_capabilities,
+      start_live_location,
+      stop_live_location,
+      report_location,
+      list_live_location_sessions,
+      get_room_beacons,
+      create_poll,
+      respond_to_poll,
+      end_poll,
+      get_poll_results,
+      record_decryption_failures,
+      list_undecryptable_events,
+      notify_room_keys_received,
+      complete_decryption_retry,
+      get_dehydrated_device,
+      upload_dehydrated_device,
+      rehydrate_device,
+      delete_dehydrated_device,
+      get_key_share_policy,
+      set_key_share_policy,
+      request_missing_room_keys,
+      cancel_room_key_request,
+      handle_room_key_requests,
+      list_pending_key_requests,
+      resolve_key_request,
+      get_notification_counts,
+      get_cached_media,
+      clear_media_cache,
+      get_media_cache_stats,
+      set_media_cache_limit,
+      download_media,
+      cancel_media_download,
+      resume_download,
+      list_downloads,
+      open_downloaded_file,
+      generate_thumbnail,
+      upload_media,
+      get_upload_settings,
+      set_upload_settings,
+      process_video,
+      start_voice_recording,
+      stop_voice_recording,
+      cancel_voice_recording,
+      cancel_media_upload,
+      read_clipboard_image,
+      stage_files,
+      list_staged_uploads,
+      discard_staged_upload,
+      send_staged_upload,
+      get_auto_download_policy,
+      set_auto_download_policy,
+      set_network_metered,
+      get_storage_report,
+      cleanup_storage,
+      save_media_as,
+      export_room_media,
+      prefetch_adjacent_media,
+      sync_image_packs,
+      list_image_packs,
+      search_image_packs,
+      save_image_pack,
+      add_image_to_pack,
+      remove_image_from_pack,
+      send_sticker,
+      render_custom_emoji,
+      set_gif_provider,
+      get_gif_provider,
+      search_gifs,
+      fetch_gif,
+      get_url_preview_settings,
+      set_url_preview_settings,
+      get_url_preview,
+      generate_document_preview,
+      get_scan_settings,
+      set_scan_settings,
+      scan_file,
+      list_quarantine,
+      release_quarantined,
+      delete_quarantined,
+      get_audio_waveform,
+      export_room,
+      cancel_room_export,
+      register_unified_push,
+      unregister_unified_push,
+      get_unified_push_status,
+      get_background_settings,
+      set_background_settings,
+      record_sync_token,
+      get_notification_rule,
+      set_notification_rule,
+      list_notification_rules,
+      evaluate_notification,
+      get_dnd_settings,
+      set_dnd_settings,
+      set_dnd_manual,
+      get_dnd_status,
+      handle_notification_action,
+      get_unread_summary,
+      list_notification_sounds,
+      import_notification_sound,
+      remove_notification_sound,
+      set_notification_sound,
+      get_notification_sound,
+      preview_notification_sound,
+      snooze_room,
+      snooze_account,
+      list_snoozes,
+      list_keyword_alerts,
+      add_keyword_alert,
+      remove_keyword_alert,
+      get_keyword_highlights,
+      get_call_log,
+      get_notification_history,
+      clear_notification_history,
+      complete_push_decryption,
+      get_rich_notification_settings,
+      set_rich_notification_settings,
+      get_digest_settings,
+      set_digest_settings,
+      send_digest_now,
+      get_tray_settings,
+      set_tray_settings,
+      mark_all_rooms_read,
+      get_autostart,
+      set_autostart,
+      list_hotkeys,
+      set_hotkey,
+      parse_matrix_link,
+      take_pending_deep_link,
+      open_link,
+      get_update_settings,
+      set_update_settings,
+      check_for_update,
+      download_update,
+      install_update,
+      open_room_window,
+      open_call_window,
+      list_popout_windows,
+      close_popout_window,
+      capture_screenshot,
+      list_capture_windows,
+      get_region_capture_preview,
+      complete_region_capture,
+      list_share_sources,
+      start_screen_share,
+      stop_screen_share,
+      get_idle_settings,
+      set_idle_settings,
+      get_idle_status,
+      get_crash_settings,
+      set_crash_settings,
+      list_crash_reports,
+      clear_crash_reports,
+      create_diagnostics_bundle,
+      get_recent_logs,
+      get_log_levels,
+      set_log_level,
+      get_proxy_status,
+      redetect_proxy,
+      preview_element_import,
+      import_element_session,
+      take_element_crypto_import,
+      import_chat_archive,
+      detect_calendar_events,
+      export_event_to_ics,
+      get_webhook_settings,
+      set_webhook_settings,
+      list_wasm_plugins,
+      set_wasm_plugin_permissions,
+      transform_outgoing_message,
+      list_plugin_commands,
+      run_plugin_command,
+      query_plugin_collection,
+      query_thread_collection,
+      import_contacts,
+      invite_contacts,
+      list_feed_subscriptions,
+      add_feed_subscription,
+      update_feed_subscription,
+      remove_feed_subscription,
+      check_feed_now,
+      schedule_message,
+      list_scheduled_messages,
+      edit_scheduled_message,
+      cancel_scheduled_message,
+      complete_scheduled_message,
+      save_draft,
+      get_draft,
+      list_drafts,
+      clear_draft,
+      set_reminder,
+      list_reminders,
+      snooze_reminder,
+      complete_reminder,
+      delete_reminder,
+      archive_item,
+      list_read_later,
+      set_read_later_read,
+      remove_read_later,
+      open_read_later_snapshot,
+      get_translation_config,
+      set_translation_config,
+      translate_message,
+      get_room_translation,
+      set_room_translation,
+      list_room_retention,
+      set_room_retention,
+      apply_room_retention,
+      get_index_pruning,
+      set_index_pruning,
+      prune_index,
+      secure_wipe_all_data,
+      run_network_diagnostics,
+      get_rate_limit_state,
+      get_security_key_status,
+      enroll_security_key,
+      unlock_with_security_key,
+      remove_security_key,
+      start_sync_engine,
+      stop_sync_engine,
+      get_sync_engine_status,
+      get_queued_to_device_events,
+      ack_to_device_events,
       deploy_matrix_server,
       test_ssh_connection,
       ingest_bot_bridge_webhook
     ])
-    .run(tauri::generate_context!())
-    .expect("error while running tauri application");
+    .build(tauri::generate_context!())
+    .expect("error while running tauri application")
+    .run(|app, event| match event {
+      tauri::RunEvent::ExitRequested { .. } => save_popout_layout(app),
+      tauri::RunEvent::Exit => {
+        diagnostics::mark_clean_exit();
+        // A downloaded update is installed when the app quits, so the next launch runs it.
+        if let Some((update, bytes)) = app.state::<updates::UpdateState>().take_downloaded() {
+          if let Err(err) = update.install(bytes) {
+            tracing::error!("Failed to install update: {}", err);
+          }
+        }
+      }
+      #[cfg(target_os = "macos")]
+      tauri::RunEvent::Reopen { .. } => show_main_window(app),
+      _ => {}
+    });
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const SALT: &[u8] = b"0123456789abcdef";
+  const SMALL_ARGON2: BackupKdf = BackupKdf::Argon2id { memory_kib: 64, iterations: 1, lanes: 1 };
+
+  #[test]
+  fn derive_key_is_deterministic_per_kdf() {
+    let pbkdf2 = derive_key("correct horse", SALT, BackupKdf::Pbkdf2).unwrap();
+    assert_eq!(pbkdf2, derive_key("correct horse", SALT, BackupKdf::Pbkdf2).unwrap());
+    let argon2 = derive_key("correct horse", SALT, SMALL_ARGON2).unwrap();
+    assert_eq!(argon2, derive_key("correct horse", SALT, SMALL_ARGON2).unwrap());
+    assert_ne!(pbkdf2, argon2);
+  }
+
+  #[test]
+  fn derive_key_depends_on_passphrase_salt_and_params() {
+    let key = derive_key("correct horse", SALT, SMALL_ARGON2).unwrap();
+    assert_ne!(key, derive_key("correct horsf", SALT, SMALL_ARGON2).unwrap());
+    assert_ne!(key, derive_key("correct horse", b"fedcba9876543210", SMALL_ARGON2).unwrap());
+    let slower = BackupKdf::Argon2id { memory_kib: 64, iterations: 2, lanes: 1 };
+    assert_ne!(key, derive_key("correct horse", SALT, slower).unwrap());
+  }
+
+  #[test]
+  fn derive_key_rejects_oversized_params() {
+    for kdf in [
+      BackupKdf::Argon2id { memory_kib: ARGON2_MAX_MEMORY_KIB + 1, iterations: 1, lanes: 1 },
+      BackupKdf::Argon2id { memory_kib: 64, iterations: ARGON2_MAX_ITERATIONS + 1, lanes: 1 },
+      BackupKdf::Argon2id { memory_kib: 64, iterations: 1, lanes: ARGON2_MAX_LANES + 1 },
+      BackupKdf::Argon2id { memory_kib: u32::MAX, iterations: u32::MAX, lanes: u32::MAX },
+    ] {
+      assert!(derive_key("pass", SALT, kdf).is_err(), "{:?} should be rejected", kdf);
+    }
+  }
+
+  #[test]
+  fn derive_key_rejects_invalid_params() {
+    assert!(derive_key("pass", SALT, BackupKdf::Argon2id { memory_kib: 64, iterations: 0, lanes: 1 }).is_err());
+    assert!(derive_key("pass", SALT, BackupKdf::Argon2id { memory_kib: 64, iterations: 1, lanes: 0 }).is_err());
+  }
+
+  #[test]
+  fn current_kdf_is_within_limits() {
+    assert!(derive_key("pass", SALT, BackupKdf::current()).is_ok());
+  }
+
+  #[test]
+  fn backups_without_kdf_use_pbkdf2() {
+    let legacy: EncryptedBackup =
+      serde_json::from_value(json!({ "salt": "", "nonce": "", "ciphertext": "", "updated_at": 0 })).unwrap();
+    assert_eq!(legacy.kdf, BackupKdf::Pbkdf2);
+    let stored = serde_json::to_value(BackupKdf::current()).unwrap();
+    assert_eq!(stored["name"], "argon2id");
+    assert_eq!(serde_json::from_value::<BackupKdf>(stored).unwrap(), BackupKdf::current());
+  }
+
+  fn indexed_message(event_id: &str, tags: &[&str]) -> IndexedMessageRecord {
+    IndexedMessageRecord {
+      event_id: event_id.to_string(),
+      room_id: "!room:example.org".to_string(),
+      sender: "@bob:example.org".to_string(),
+      timestamp: 1_000,
+      body: Some("hello".to_string()),
+      tokens: vec!["hello".to_string()],
+      tags: tags.iter().map(|tag| tag.to_string()).collect(),
+      reactions: Vec::new(),
+      has_media: false,
+      media_types: Vec::new(),
+      relates_to: None,
+      thread_root: None,
+    }
+  }
+
+  #[test]
+  fn room_index_deltas_follow_the_change_sequence() {
+    let conn = Connection::open_in_memory().unwrap();
+    init_index_db(&conn).unwrap();
+    let upsert = |messages: Vec<IndexedMessageRecord>| {
+      let payload = IndexUpsertPayload { room_id: "!room:example.org".to_string(), messages, media_items: Vec::new() };
+      insert_index_records(&conn, "acct", &payload).unwrap();
+    };
+    let load = |since| load_room_index_from_conn(&conn, "acct", "!room:example.org", since).unwrap();
+    upsert(vec![indexed_message("$a", &[]), indexed_message("$b", &[])]);
+    let first = load(None);
+    assert!(first.full);
+    assert_eq!(first.messages.len(), 2);
+
+    // Same timestamp, changed in place: still a delta.
+    upsert(vec![indexed_message("$a", &["important"])]);
+    let edited = load(Some(first.change_seq));
+    assert!(!edited.full);
+    assert_eq!(edited.messages.iter().map(|m| m.event_id.as_str()).collect::<Vec<_>>(), ["$a"]);
+
+    // Re-sending unchanged rows doesn't move them.
+    upsert(vec![indexed_message("$b", &[])]);
+    assert!(load(Some(edited.change_seq)).messages.is_empty());
+
+    conn.execute("DELETE FROM message_index WHERE event_id = '$b'", []).unwrap();
+    let deleted = load(Some(edited.change_seq));
+    assert!(deleted.messages.is_empty());
+    assert_eq!(deleted.deleted_event_ids, ["$b"]);
+    assert!(load(Some(deleted.change_seq)).deleted_event_ids.is_empty());
+  }
 }
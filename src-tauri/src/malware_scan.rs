@@ -0,0 +1,379 @@
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::matrix_api::now_millis;
+use crate::media_download::sanitize_file_name;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(60);
+#[cfg(unix)]
+const DEFAULT_CLAMD_SOCKETS: &[&str] = &[
+  "/var/run/clamav/clamd.ctl",
+  "/run/clamav/clamd.ctl",
+  "/var/run/clamd.scan/clamd.sock",
+  "/tmp/clamd.socket",
+  "/opt/homebrew/var/run/clamav/clamd.sock",
+  "/usr/local/var/run/clamav/clamd.sock",
+];
+
+const RISKY_EXTENSIONS: &[&str] = &[
+  "exe", "msi", "msix", "bat", "cmd", "com", "scr", "pif", "cpl", "dll", "ps1", "vbs", "vbe", "js", "jse", "wsf", "hta",
+  "lnk", "jar", "apk", "dmg", "pkg", "app", "deb", "rpm", "sh", "appimage", "run", "bin", "iso", "img", "zip", "rar", "7z",
+  "tar", "gz", "tgz", "bz2", "xz", "cab", "docm", "xlsm", "pptm",
+];
+
+/// Download scanning, stored globally.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScanSettings {
+  pub enabled: bool,
+  /// clamd Unix socket path or `tcp://host:port`; well-known socket paths are tried when unset.
+  #[serde(default)]
+  pub clamd_socket: Option<String>,
+  /// Hold executables and archives in quarantine when no scanner is available.
+  #[serde(default)]
+  pub require_for_risky: bool,
+}
+
+impl Default for ScanSettings {
+  fn default() -> Self {
+    ScanSettings {
+      enabled: false,
+      clamd_socket: None,
+      require_for_risky: false,
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum ScanVerdict {
+  Clean { engine: String },
+  Infected { engine: String, signature: String },
+  /// No scanner could be reached.
+  Unavailable { reason: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QuarantinedFile {
+  pub id: String,
+  pub file_name: String,
+  /// Where the file would have been saved.
+  pub intended_path: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub mxc_url: Option<String>,
+  /// `infected` or `unscanned`.
+  pub reason: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub signature: Option<String>,
+  pub size: u64,
+  pub created_at: i64,
+}
+
+pub fn init_malware_scan_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS scan_settings (
+        scope TEXT PRIMARY KEY,
+        settings_json TEXT NOT NULL
+      );
+      CREATE TABLE IF NOT EXISTS quarantine (
+        id TEXT PRIMARY KEY,
+        file_name TEXT NOT NULL,
+        intended_path TEXT NOT NULL,
+        stored_path TEXT NOT NULL,
+        mxc_url TEXT,
+        reason TEXT NOT NULL,
+        signature TEXT,
+        size INTEGER NOT NULL,
+        created_at INTEGER NOT NULL
+      );
+    ",
+  )
+}
+
+pub fn settings(conn: &Connection) -> Result<ScanSettings, String> {
+  let stored: Option<String> = conn
+    .query_row("SELECT settings_json FROM scan_settings WHERE scope = ''", [], |row| row.get(0))
+    .optional()
+    .map_err(|e| e.to_string())?;
+  Ok(stored.and_then(|json| serde_json::from_str(&json).ok()).unwrap_or_default())
+}
+
+pub fn set_settings(conn: &Connection, value: &ScanSettings) -> Result<(), String> {
+  let json = serde_json::to_string(value).map_err(|e| e.to_string())?;
+  conn
+    .execute(
+      "INSERT INTO scan_settings (scope, settings_json) VALUES ('', ?1)
+       ON CONFLICT(scope) DO UPDATE SET settings_json = excluded.settings_json",
+      [json],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+/// Executables, scripts, installers, macro documents and archives.
+pub fn is_risky(file_name: &str) -> bool {
+  Path::new(file_name)
+    .extension()
+    .map(|e| e.to_string_lossy().to_ascii_lowercase())
+    .map(|e| RISKY_EXTENSIONS.contains(&e.as_str()))
+    .unwrap_or(false)
+}
+
+/// Stream `path` to clamd with `INSTREAM` and return its one-line reply.
+fn clamd_instream(mut stream: impl Read + Write, path: &Path) -> Result<String, String> {
+  let mut file = fs::File::open(path).map_err(|e| e.to_string())?;
+  stream.write_all(b"zINSTREAM\0").map_err(|e| e.to_string())?;
+  let mut buf = vec![0u8; CHUNK_SIZE];
+  loop {
+    let read = file.read(&mut buf).map_err(|e| e.to_string())?;
+    if read == 0 {
+      break;
+    }
+    stream.write_all(&(read as u32).to_be_bytes()).map_err(|e| e.to_string())?;
+    stream.write_all(&buf[..read]).map_err(|e| e.to_string())?;
+  }
+  stream.write_all(&0u32.to_be_bytes()).map_err(|e| e.to_string())?;
+  stream.flush().map_err(|e| e.to_string())?;
+  let mut reply = Vec::new();
+  stream.read_to_end(&mut reply).map_err(|e| e.to_string())?;
+  Ok(String::from_utf8_lossy(&reply).trim_end_matches('\0').trim().to_string())
+}
+
+fn parse_clamd_reply(reply: &str) -> Result<ScanVerdict, String> {
+  let result = reply.split_once(": ").map(|(_, result)| result).unwrap_or(reply);
+  if result == "OK" {
+    Ok(ScanVerdict::Clean { engine: "clamav".to_string() })
+  } else if let Some(signature) = result.strip_suffix(" FOUND") {
+    Ok(ScanVerdict::Infected {
+      engine: "clamav".to_string(),
+      signature: signature.to_string(),
+    })
+  } else {
+    Err(format!("clamd: {}", result))
+  }
+}
+
+fn scan_clamd(socket: &str, path: &Path) -> Result<ScanVerdict, String> {
+  let reply = if let Some(address) = socket.strip_prefix("tcp://") {
+    let stream = TcpStream::connect(address).map_err(|e| format!("clamd at {}: {}", address, e))?;
+    stream.set_read_timeout(Some(SOCKET_TIMEOUT)).map_err(|e| e.to_string())?;
+    stream.set_write_timeout(Some(SOCKET_TIMEOUT)).map_err(|e| e.to_string())?;
+    clamd_instream(stream, path)?
+  } else {
+    #[cfg(unix)]
+    {
+      let stream = std::os::unix::net::UnixStream::connect(socket).map_err(|e| format!("clamd at {}: {}", socket, e))?;
+      stream.set_read_timeout(Some(SOCKET_TIMEOUT)).map_err(|e| e.to_string())?;
+      stream.set_write_timeout(Some(SOCKET_TIMEOUT)).map_err(|e| e.to_string())?;
+      clamd_instream(stream, path)?
+    }
+    #[cfg(not(unix))]
+    {
+      return Err(format!("Unix sockets are not supported here; use tcp:// for {}", socket));
+    }
+  };
+  parse_clamd_reply(&reply)
+}
+
+/// Scan with the Antimalware Scan Interface, which forwards to the active Windows antivirus.
+#[cfg(windows)]
+fn scan_amsi(path: &Path) -> Result<ScanVerdict, String> {
+  use windows::core::HSTRING;
+  use windows::Win32::System::Antimalware::{
+    AmsiInitialize, AmsiScanBuffer, AmsiUninitialize, AMSI_RESULT_DETECTED, HAMSISESSION,
+  };
+
+  let bytes = fs::read(path).map_err(|e| e.to_string())?;
+  let name = HSTRING::from(path.display().to_string());
+  unsafe {
+    let context = AmsiInitialize(&HSTRING::from("matrix-messenger")).map_err(|e| format!("AMSI: {}", e))?;
+    let result = AmsiScanBuffer(
+      context,
+      bytes.as_ptr() as *const _,
+      bytes.len() as u32,
+      &name,
+      HAMSISESSION::default(),
+    );
+    AmsiUninitialize(context);
+    let result = result.map_err(|e| format!("AMSI: {}", e))?;
+    if result.0 >= AMSI_RESULT_DETECTED.0 {
+      Ok(ScanVerdict::Infected {
+        engine: "amsi".to_string(),
+        signature: "Detected by Windows antimalware".to_string(),
+      })
+    } else {
+      Ok(ScanVerdict::Clean { engine: "amsi".to_string() })
+    }
+  }
+}
+
+/// Scan with the configured clamd socket, the well-known ones, or AMSI on Windows.
+pub fn scan(settings: &ScanSettings, path: &Path) -> ScanVerdict {
+  let mut errors = Vec::new();
+  let mut sockets: Vec<String> = settings.clamd_socket.iter().cloned().collect();
+  #[cfg(unix)]
+  if sockets.is_empty() {
+    sockets.extend(DEFAULT_CLAMD_SOCKETS.iter().filter(|p| Path::new(p).exists()).map(|p| p.to_string()));
+  }
+  if sockets.is_empty() && cfg!(not(windows)) {
+    sockets.push("tcp://127.0.0.1:3310".to_string());
+  }
+  for socket in &sockets {
+    match scan_clamd(socket, path) {
+      Ok(verdict) => return verdict,
+      Err(err) => errors.push(err),
+    }
+  }
+  #[cfg(windows)]
+  match scan_amsi(path) {
+    Ok(verdict) => return verdict,
+    Err(err) => errors.push(err),
+  }
+  ScanVerdict::Unavailable {
+    reason: if errors.is_empty() { "No scanner configured".to_string() } else { errors.join("; ") },
+  }
+}
+
+/// Whether a file with this verdict must be quarantined instead of saved.
+pub fn must_quarantine(settings: &ScanSettings, verdict: &ScanVerdict, file_name: &str) -> bool {
+  match verdict {
+    ScanVerdict::Infected { .. } => true,
+    ScanVerdict::Unavailable { .. } => settings.require_for_risky && is_risky(file_name),
+    ScanVerdict::Clean { .. } => false,
+  }
+}
+
+/// Rename, or copy and delete when `to` is on another file system.
+pub fn move_file(from: &Path, to: &Path) -> Result<(), String> {
+  if let Some(parent) = to.parent() {
+    fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+  }
+  if fs::rename(from, to).is_ok() {
+    return Ok(());
+  }
+  fs::copy(from, to).map_err(|e| e.to_string())?;
+  fs::remove_file(from).map_err(|e| e.to_string())
+}
+
+/// Move `path` into `quarantine_dir` (without execute permission) and record it.
+pub fn quarantine(
+  conn: &Connection,
+  quarantine_dir: &Path,
+  path: &Path,
+  intended_path: &Path,
+  mxc_url: Option<&str>,
+  verdict: &ScanVerdict,
+) -> Result<QuarantinedFile, String> {
+  let file_name = intended_path
+    .file_name()
+    .map(|n| n.to_string_lossy().to_string())
+    .unwrap_or_else(|| "download".to_string());
+  let id = format!("q-{}", now_millis());
+  let stored_path = quarantine_dir.join(format!("{}-{}.quarantined", id, sanitize_file_name(&file_name)));
+  move_file(path, &stored_path)?;
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::PermissionsExt;
+    let _ = fs::set_permissions(&stored_path, fs::Permissions::from_mode(0o600));
+  }
+  let (reason, signature) = match verdict {
+    ScanVerdict::Infected { signature, .. } => ("infected", Some(signature.clone())),
+    _ => ("unscanned", None),
+  };
+  let entry = QuarantinedFile {
+    id,
+    file_name,
+    intended_path: intended_path.display().to_string(),
+    mxc_url: mxc_url.map(|s| s.to_string()),
+    reason: reason.to_string(),
+    signature,
+    size: fs::metadata(&stored_path).map(|m| m.len()).unwrap_or_default(),
+    created_at: now_millis(),
+  };
+  conn
+    .execute(
+      "INSERT INTO quarantine (id, file_name, intended_path, stored_path, mxc_url, reason, signature, size, created_at)
+       VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+      params![
+        entry.id,
+        entry.file_name,
+        entry.intended_path,
+        stored_path.display().to_string(),
+        entry.mxc_url,
+        entry.reason,
+        entry.signature,
+        entry.size as i64,
+        entry.created_at
+      ],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(entry)
+}
+
+fn quarantined_from_row(row: &Row) -> rusqlite::Result<QuarantinedFile> {
+  Ok(QuarantinedFile {
+    id: row.get(0)?,
+    file_name: row.get(1)?,
+    intended_path: row.get(2)?,
+    mxc_url: row.get(3)?,
+    reason: row.get(4)?,
+    signature: row.get(5)?,
+    size: row.get::<_, i64>(6)? as u64,
+    created_at: row.get(7)?,
+  })
+}
+
+pub fn list(conn: &Connection) -> Result<Vec<QuarantinedFile>, String> {
+  let mut stmt = conn
+    .prepare(
+      "SELECT id, file_name, intended_path, mxc_url, reason, signature, size, created_at
+       FROM quarantine ORDER BY created_at DESC",
+    )
+    .map_err(|e| e.to_string())?;
+  let rows = stmt
+    .query_map([], quarantined_from_row)
+    .map_err(|e| e.to_string())?
+    .flatten()
+    .collect();
+  Ok(rows)
+}
+
+fn take(conn: &Connection, id: &str) -> Result<(QuarantinedFile, PathBuf), String> {
+  let stored = conn
+    .query_row(
+      "SELECT id, file_name, intended_path, mxc_url, reason, signature, size, created_at, stored_path
+       FROM quarantine WHERE id = ?1",
+      [id],
+      |row| Ok((quarantined_from_row(row)?, row.get::<_, String>(8)?)),
+    )
+    .optional()
+    .map_err(|e| e.to_string())?
+    .ok_or_else(|| format!("Unknown quarantined file: {}", id))?;
+  conn
+    .execute("DELETE FROM quarantine WHERE id = ?1", [id])
+    .map_err(|e| e.to_string())?;
+  Ok((stored.0, PathBuf::from(stored.1)))
+}
+
+/// Release a file the user chose to trust to `dest` (its intended path when unset).
+pub fn release(conn: &Connection, id: &str, dest: Option<&Path>) -> Result<String, String> {
+  let (entry, stored_path) = take(conn, id)?;
+  let dest = dest.map(|d| d.to_path_buf()).unwrap_or_else(|| PathBuf::from(&entry.intended_path));
+  move_file(&stored_path, &dest)?;
+  Ok(dest.display().to_string())
+}
+
+pub fn delete(conn: &Connection, id: &str) -> Result<(), String> {
+  let (_, stored_path) = take(conn, id)?;
+  match fs::remove_file(&stored_path) {
+    Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.to_string()),
+    _ => Ok(()),
+  }
+}
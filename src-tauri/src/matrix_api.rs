@@ -0,0 +1,392 @@
+use reqwest::{Method, StatusCode};
+use serde_json::Value;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tauri::AppHandle;
+
+use crate::rate_limit;
+use crate::{norm_hs, read_accounts_map};
+
+#[derive(Debug, Clone)]
+pub enum ApiError {
+  Network(String),
+  Http {
+    status: u16,
+    errcode: Option<String>,
+    message: String,
+    body: Value,
+  },
+}
+
+impl ApiError {
+  pub fn is_network(&self) -> bool {
+    matches!(self, ApiError::Network(_))
+  }
+
+  pub fn errcode(&self) -> Option<&str> {
+    match self {
+      ApiError::Http { errcode, .. } => errcode.as_deref(),
+      ApiError::Network(_) => None,
+    }
+  }
+}
+
+impl fmt::Display for ApiError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ApiError::Network(message) => write!(f, "Network error: {}", message),
+      ApiError::Http { status, errcode: Some(code), message, .. } => write!(f, "{} ({}): {}", code, status, message),
+      ApiError::Http { status, message, .. } => write!(f, "HTTP {}: {}", status, message),
+    }
+  }
+}
+
+impl From<ApiError> for String {
+  fn from(err: ApiError) -> String {
+    err.to_string()
+  }
+}
+
+/// Authenticated client-server API access for one stored account.
+#[derive(Clone)]
+pub struct MatrixClient {
+  pub homeserver_url: String,
+  pub user_id: String,
+  access_token: String,
+  http: reqwest::Client,
+}
+
+impl MatrixClient {
+  pub fn new(homeserver_url: &str, user_id: &str, access_token: &str) -> Result<Self, String> {
+    let http = crate::proxy::apply(reqwest::Client::builder())
+      .user_agent(concat!("matrix-messenger/", env!("CARGO_PKG_VERSION")))
+      .build()
+      .map_err(|e| format!("Failed to create HTTP client: {}", e))?;
+    Ok(MatrixClient {
+      homeserver_url: norm_hs(homeserver_url),
+      user_id: user_id.to_string(),
+      access_token: access_token.to_string(),
+      http,
+    })
+  }
+
+  pub async fn for_account(app: &AppHandle, account_key: &str) -> Result<Self, String> {
+    let map = read_accounts_map(app).await?;
+    let creds = map
+      .get(account_key)
+      .ok_or_else(|| format!("Unknown account: {}", account_key))?;
+    Self::new(&creds.homeserver_url, &creds.user_id, &creds.access_token)
+  }
+
+  pub fn http(&self) -> &reqwest::Client {
+    &self.http
+  }
+
+  pub fn access_token(&self) -> &str {
+    &self.access_token
+  }
+
+  pub fn client_url(&self, path: &str) -> String {
+    format!("{}/_matrix/client/v3{}", self.homeserver_url, path)
+  }
+
+  pub async fn request(
+    &self,
+    method: Method,
+    url: &str,
+    query: &[(&str, String)],
+    body: Option<&Value>,
+  ) -> Result<Value, ApiError> {
+    let endpoint = rate_limit::endpoint(method.as_str(), url);
+    rate_limit::acquire(&self.user_id, &endpoint).await;
+    let mut builder = self
+      .http
+      .request(method, url)
+      .bearer_auth(&self.access_token)
+      .query(query);
+    if let Some(body) = body {
+      builder = builder.json(body);
+    }
+    let result = match builder.send().await {
+      Ok(response) => read_json_response(response).await,
+      Err(e) => Err(ApiError::Network(e.to_string())),
+    };
+    rate_limit::record(&self.user_id, &endpoint, &result);
+    result
+  }
+
+  pub async fn get(&self, path: &str, query: &[(&str, String)]) -> Result<Value, ApiError> {
+    self.request(Method::GET, &self.client_url(path), query, None).await
+  }
+
+  pub async fn post(&self, path: &str, body: &Value) -> Result<Value, ApiError> {
+    self.request(Method::POST, &self.client_url(path), &[], Some(body)).await
+  }
+
+  pub async fn put(&self, path: &str, body: &Value) -> Result<Value, ApiError> {
+    self.request(Method::PUT, &self.client_url(path), &[], Some(body)).await
+  }
+
+  pub async fn delete(&self, path: &str) -> Result<Value, ApiError> {
+    self.request(Method::DELETE, &self.client_url(path), &[], None).await
+  }
+
+  /// Send a room message event and return its event ID.
+  pub async fn send_event(&self, room_id: &str, event_type: &str, content: &Value) -> Result<String, ApiError> {
+    self.send_event_with_txn(room_id, event_type, &next_txn_id(), content).await
+  }
+
+  /// Send with a caller-chosen transaction ID; repeating it returns the first send's event ID.
+  pub async fn send_event_with_txn(
+    &self,
+    room_id: &str,
+    event_type: &str,
+    txn_id: &str,
+    content: &Value,
+  ) -> Result<String, ApiError> {
+    let path = format!(
+      "/rooms/{}/send/{}/{}",
+      encode(room_id),
+      encode(event_type),
+      encode(txn_id)
+    );
+    let response = self.put(&path, content).await?;
+    Ok(response.get("event_id").and_then(|v| v.as_str()).unwrap_or_default().to_string())
+  }
+
+  /// Whether the room has to be treated as encrypted. Only a definite M_NOT_FOUND for its
+  /// `m.room.encryption` state counts as unencrypted; any other failure fails closed.
+  pub async fn room_may_be_encrypted(&self, room_id: &str) -> bool {
+    match self.get(&format!("/rooms/{}/state/m.room.encryption/", encode(room_id)), &[]).await {
+      Ok(_) => true,
+      Err(err) => err.errcode() != Some("M_NOT_FOUND"),
+    }
+  }
+
+  /// Set a room state event and return its event ID.
+  pub async fn send_state(&self, room_id: &str, event_type: &str, state_key: &str, content: &Value) -> Result<String, ApiError> {
+    let path = format!(
+      "/rooms/{}/state/{}/{}",
+      encode(room_id),
+      encode(event_type),
+      encode(state_key)
+    );
+    let response = self.put(&path, content).await?;
+    Ok(response.get("event_id").and_then(|v| v.as_str()).unwrap_or_default().to_string())
+  }
+
+  pub fn media_url(&self, path: &str) -> String {
+    format!("{}/_matrix/media/v3{}", self.homeserver_url, path)
+  }
+
+  /// Upload a blob to the content repository and return its `mxc://` URI.
+  pub async fn upload(&self, bytes: Vec<u8>, content_type: &str, file_name: Option<&str>) -> Result<String, ApiError> {
+    let len = bytes.len() as u64;
+    self.upload_body(bytes.into(), len, content_type, file_name).await
+  }
+
+  /// Upload a (possibly streaming) body of `len` bytes and return its `mxc://` URI.
+  pub async fn upload_body(&self, body: reqwest::Body, len: u64, content_type: &str, file_name: Option<&str>) -> Result<String, ApiError> {
+    let mut builder = self
+      .http
+      .post(self.media_url("/upload"))
+      .bearer_auth(&self.access_token)
+      .header(reqwest::header::CONTENT_TYPE, content_type)
+      .header(reqwest::header::CONTENT_LENGTH, len)
+      .body(body);
+    if let Some(name) = file_name {
+      builder = builder.query(&[("filename", name)]);
+    }
+    let response = builder
+      .send()
+      .await
+      .map_err(|e| ApiError::Network(e.to_string()))?;
+    let value = read_json_response(response).await?;
+    value
+      .get("content_uri")
+      .and_then(|v| v.as_str())
+      .map(|s| s.to_string())
+      .ok_or_else(|| ApiError::Network("Upload response is missing content_uri".to_string()))
+  }
+
+  /// Reserve an `mxc://` URI for an asynchronous upload (`POST /media/v1/create`).
+  pub async fn create_media(&self) -> Result<String, ApiError> {
+    let response = self
+      .http
+      .post(format!("{}/_matrix/media/v1/create", self.homeserver_url))
+      .bearer_auth(&self.access_token)
+      .json(&serde_json::json!({}))
+      .send()
+      .await
+      .map_err(|e| ApiError::Network(e.to_string()))?;
+    let value = read_json_response(response).await?;
+    value
+      .get("content_uri")
+      .and_then(|v| v.as_str())
+      .map(|s| s.to_string())
+      .ok_or_else(|| ApiError::Network("Create media response is missing content_uri".to_string()))
+  }
+
+  /// Upload the content for a URI reserved with `create_media`.
+  pub async fn upload_to(
+    &self,
+    mxc: &str,
+    body: reqwest::Body,
+    len: u64,
+    content_type: &str,
+    file_name: Option<&str>,
+  ) -> Result<(), ApiError> {
+    let (server, media_id) = parse_mxc(mxc).ok_or_else(|| ApiError::Network(format!("Invalid mxc URI: {}", mxc)))?;
+    let mut builder = self
+      .http
+      .put(self.media_url(&format!("/upload/{}/{}", encode(&server), encode(&media_id))))
+      .bearer_auth(&self.access_token)
+      .header(reqwest::header::CONTENT_TYPE, content_type)
+      .header(reqwest::header::CONTENT_LENGTH, len)
+      .body(body);
+    if let Some(name) = file_name {
+      builder = builder.query(&[("filename", name)]);
+    }
+    let response = builder
+      .send()
+      .await
+      .map_err(|e| ApiError::Network(e.to_string()))?;
+    read_json_response(response).await.map(|_| ())
+  }
+
+  /// Start a media download, trying the authenticated media API first and falling back to
+  /// the legacy endpoint on servers that do not support it. `offset` requests a byte range.
+  pub async fn download_response(&self, mxc: &str, offset: Option<u64>) -> Result<reqwest::Response, ApiError> {
+    let (server, media_id) = parse_mxc(mxc).ok_or_else(|| ApiError::Network(format!("Invalid mxc URI: {}", mxc)))?;
+    let path = format!("/download/{}/{}", encode(&server), encode(&media_id));
+    let urls = [
+      format!("{}/_matrix/client/v1/media{}", self.homeserver_url, path),
+      self.media_url(&path),
+    ];
+    for (attempt, url) in urls.iter().enumerate() {
+      let mut builder = self.http.get(url).bearer_auth(&self.access_token);
+      if let Some(offset) = offset.filter(|o| *o > 0) {
+        builder = builder.header(reqwest::header::RANGE, format!("bytes={}-", offset));
+      }
+      let response = builder
+        .send()
+        .await
+        .map_err(|e| ApiError::Network(e.to_string()))?;
+      let status = response.status();
+      if status.is_success() {
+        return Ok(response);
+      }
+      let unsupported = matches!(status.as_u16(), 400 | 404 | 405);
+      if attempt == 0 && unsupported {
+        continue;
+      }
+      return Err(read_json_response(response).await.err().unwrap_or_else(|| http_error(status, Value::Null)));
+    }
+    Err(ApiError::Network(format!("Media {} is unavailable", mxc)))
+  }
+
+  /// OpenGraph data for `url` as seen by the homeserver, via the authenticated media API
+  /// when available.
+  pub async fn preview_url(&self, url: &str) -> Result<Value, ApiError> {
+    let query = [("url", url.to_string())];
+    let authenticated = format!("{}/_matrix/client/v1/media/preview_url", self.homeserver_url);
+    match self.request(Method::GET, &authenticated, &query, None).await {
+      Err(ApiError::Http { status: 404 | 405, errcode, .. }) if errcode.is_none() || errcode.as_deref() == Some("M_UNRECOGNIZED") => {
+        self.request(Method::GET, &self.media_url("/preview_url"), &query, None).await
+      }
+      result => result,
+    }
+  }
+
+  /// Download a whole media file into memory, returning the bytes and content type.
+  pub async fn download(&self, mxc: &str) -> Result<(Vec<u8>, Option<String>), ApiError> {
+    let response = self.download_response(mxc, None).await?;
+    let content_type = response
+      .headers()
+      .get(reqwest::header::CONTENT_TYPE)
+      .and_then(|v| v.to_str().ok())
+      .map(|s| s.to_string());
+    let bytes = response
+      .bytes()
+      .await
+      .map_err(|e| ApiError::Network(e.to_string()))?;
+    Ok((bytes.to_vec(), content_type))
+  }
+}
+
+/// Split `mxc://server/media_id` into its server name and media ID.
+pub fn parse_mxc(mxc: &str) -> Option<(String, String)> {
+  let rest = mxc.strip_prefix("mxc://")?;
+  let (server, media_id) = rest.split_once('/')?;
+  let media_id = media_id.split(['?', '#']).next().unwrap_or_default();
+  if server.is_empty() || media_id.is_empty() {
+    return None;
+  }
+  Some((server.to_string(), media_id.to_string()))
+}
+
+async fn read_json_response(response: reqwest::Response) -> Result<Value, ApiError> {
+  let status = response.status();
+  // Proxies and some servers only say `Retry-After: <seconds>`.
+  let retry_after = response
+    .headers()
+    .get(reqwest::header::RETRY_AFTER)
+    .and_then(|v| v.to_str().ok())
+    .and_then(|v| v.trim().parse::<u64>().ok());
+  let text = response
+    .text()
+    .await
+    .map_err(|e| ApiError::Network(e.to_string()))?;
+  let value = if text.trim().is_empty() {
+    Value::Object(Default::default())
+  } else {
+    serde_json::from_str::<Value>(&text).unwrap_or(Value::String(text))
+  };
+  if status.is_success() {
+    return Ok(value);
+  }
+  let mut value = value;
+  if let (Some(seconds), Some(body)) = (retry_after, value.as_object_mut()) {
+    body
+      .entry("retry_after_ms")
+      .or_insert_with(|| Value::from(seconds * 1000));
+  }
+  Err(http_error(status, value))
+}
+
+fn http_error(status: StatusCode, body: Value) -> ApiError {
+  let errcode = body
+    .get("errcode")
+    .and_then(|v| v.as_str())
+    .map(|s| s.to_string());
+  let message = body
+    .get("error")
+    .and_then(|v| v.as_str())
+    .map(|s| s.to_string())
+    .unwrap_or_else(|| status.canonical_reason().unwrap_or("Request failed").to_string());
+  ApiError::Http {
+    status: status.as_u16(),
+    errcode,
+    message,
+    body,
+  }
+}
+
+/// Percent-encode a single path segment (room IDs, event IDs, user IDs).
+pub fn encode(segment: &str) -> String {
+  urlencoding::encode(segment).into_owned()
+}
+
+static TXN_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Transaction IDs only need to be unique per access token; time plus a counter is enough.
+pub fn next_txn_id() -> String {
+  format!("mm{}.{}", now_millis(), TXN_COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+pub fn now_millis() -> i64 {
+  std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .map(|d| d.as_millis() as i64)
+    .unwrap_or_default()
+}
@@ -0,0 +1,244 @@
+use aes_gcm::{aead::{Aead, KeyInit}, Aes256Gcm, Nonce};
+use rand::{rngs::OsRng, RngCore};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::matrix_api::now_millis;
+
+pub const DEFAULT_CACHE_LIMIT_BYTES: i64 = 512 * 1024 * 1024;
+const NONCE_LEN: usize = 12;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MediaCacheEntry {
+  pub cache_key: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub content_type: Option<String>,
+  pub size: i64,
+  pub last_accessed: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MediaCacheStats {
+  pub entries: i64,
+  pub total_bytes: i64,
+  pub limit_bytes: i64,
+}
+
+pub fn init_media_cache_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS media_cache (
+        cache_key TEXT PRIMARY KEY,
+        file_name TEXT NOT NULL,
+        content_type TEXT,
+        size INTEGER NOT NULL,
+        created_at INTEGER NOT NULL,
+        last_accessed INTEGER NOT NULL
+      );
+      CREATE INDEX IF NOT EXISTS idx_media_cache_lru ON media_cache(last_accessed);
+      CREATE TABLE IF NOT EXISTS media_cache_settings (
+        id INTEGER PRIMARY KEY CHECK (id = 1),
+        limit_bytes INTEGER NOT NULL
+      );
+    ",
+  )
+}
+
+/// Cache keys are usually the `mxc://` URI, optionally suffixed for derived variants
+/// such as thumbnails; files are named by its hash so URIs never reach the filesystem.
+fn file_name(cache_key: &str) -> String {
+  let digest = Sha256::digest(cache_key.as_bytes());
+  digest.iter().map(|b| format!("{:02x}", b)).collect::<String>() + ".bin"
+}
+
+fn encrypt(key: &[u8; 32], plaintext: &[u8]) -> Result<Vec<u8>, String> {
+  let cipher = Aes256Gcm::new_from_slice(key).map_err(|e| e.to_string())?;
+  let mut nonce_bytes = [0u8; NONCE_LEN];
+  OsRng.fill_bytes(&mut nonce_bytes);
+  let ciphertext = cipher
+    .encrypt(Nonce::from_slice(&nonce_bytes), plaintext)
+    .map_err(|e| e.to_string())?;
+  let mut out = nonce_bytes.to_vec();
+  out.extend(ciphertext);
+  Ok(out)
+}
+
+fn decrypt(key: &[u8; 32], data: &[u8]) -> Result<Vec<u8>, String> {
+  if data.len() < NONCE_LEN {
+    return Err("Cached media file is truncated".to_string());
+  }
+  let (nonce_bytes, ciphertext) = data.split_at(NONCE_LEN);
+  let cipher = Aes256Gcm::new_from_slice(key).map_err(|e| e.to_string())?;
+  cipher
+    .decrypt(Nonce::from_slice(nonce_bytes), ciphertext)
+    .map_err(|_| "Cached media could not be decrypted".to_string())
+}
+
+pub fn limit(conn: &Connection) -> Result<i64, String> {
+  let stored: Option<i64> = conn
+    .query_row("SELECT limit_bytes FROM media_cache_settings WHERE id = 1", [], |row| row.get(0))
+    .optional()
+    .map_err(|e| e.to_string())?;
+  Ok(stored.unwrap_or(DEFAULT_CACHE_LIMIT_BYTES))
+}
+
+pub fn set_limit(conn: &Connection, dir: &Path, limit_bytes: i64) -> Result<MediaCacheStats, String> {
+  conn
+    .execute(
+      "INSERT INTO media_cache_settings (id, limit_bytes) VALUES (1, ?1)
+       ON CONFLICT(id) DO UPDATE SET limit_bytes = excluded.limit_bytes",
+      [limit_bytes.max(0)],
+    )
+    .map_err(|e| e.to_string())?;
+  evict(conn, dir)?;
+  stats(conn)
+}
+
+/// Decrypted bytes for a cached entry, refreshing its LRU position. Entries whose file has
+/// vanished or fails to decrypt are dropped.
+pub fn get(conn: &Connection, dir: &Path, key: &[u8; 32], cache_key: &str) -> Result<Option<(MediaCacheEntry, Vec<u8>)>, String> {
+  let row: Option<(String, Option<String>, i64)> = conn
+    .query_row(
+      "SELECT file_name, content_type, size FROM media_cache WHERE cache_key = ?1",
+      [cache_key],
+      |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )
+    .optional()
+    .map_err(|e| e.to_string())?;
+  let (file, content_type, size) = match row {
+    Some(row) => row,
+    None => return Ok(None),
+  };
+  let bytes = match fs::read(dir.join(&file)).map_err(|e| e.to_string()).and_then(|data| decrypt(key, &data)) {
+    Ok(bytes) => bytes,
+    Err(_) => {
+      remove(conn, dir, cache_key)?;
+      return Ok(None);
+    }
+  };
+  let last_accessed = now_millis();
+  conn
+    .execute(
+      "UPDATE media_cache SET last_accessed = ?2 WHERE cache_key = ?1",
+      params![cache_key, last_accessed],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(Some((
+    MediaCacheEntry {
+      cache_key: cache_key.to_string(),
+      content_type,
+      size,
+      last_accessed,
+    },
+    bytes,
+  )))
+}
+
+pub fn contains(conn: &Connection, cache_key: &str) -> Result<bool, String> {
+  conn
+    .query_row("SELECT 1 FROM media_cache WHERE cache_key = ?1", [cache_key], |_| Ok(()))
+    .optional()
+    .map(|found| found.is_some())
+    .map_err(|e| e.to_string())
+}
+
+/// Encrypt and store `bytes`, then evict least recently used entries over the size cap.
+pub fn put(
+  conn: &Connection,
+  dir: &Path,
+  key: &[u8; 32],
+  cache_key: &str,
+  content_type: Option<&str>,
+  bytes: &[u8],
+) -> Result<MediaCacheEntry, String> {
+  fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+  let file = file_name(cache_key);
+  fs::write(dir.join(&file), encrypt(key, bytes)?).map_err(|e| e.to_string())?;
+  let now = now_millis();
+  conn
+    .execute(
+      "INSERT INTO media_cache (cache_key, file_name, content_type, size, created_at, last_accessed)
+       VALUES (?1, ?2, ?3, ?4, ?5, ?5)
+       ON CONFLICT(cache_key) DO UPDATE SET
+         file_name = excluded.file_name,
+         content_type = excluded.content_type,
+         size = excluded.size,
+         last_accessed = excluded.last_accessed",
+      params![cache_key, file, content_type, bytes.len() as i64, now],
+    )
+    .map_err(|e| e.to_string())?;
+  evict(conn, dir)?;
+  Ok(MediaCacheEntry {
+    cache_key: cache_key.to_string(),
+    content_type: content_type.map(|s| s.to_string()),
+    size: bytes.len() as i64,
+    last_accessed: now,
+  })
+}
+
+pub fn remove(conn: &Connection, dir: &Path, cache_key: &str) -> Result<(), String> {
+  let _ = fs::remove_file(dir.join(file_name(cache_key)));
+  conn
+    .execute("DELETE FROM media_cache WHERE cache_key = ?1", [cache_key])
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+fn evict(conn: &Connection, dir: &Path) -> Result<usize, String> {
+  let cap = limit(conn)?;
+  let mut total = stats(conn)?.total_bytes;
+  if total <= cap {
+    return Ok(0);
+  }
+  let mut stmt = conn
+    .prepare("SELECT cache_key, size FROM media_cache ORDER BY last_accessed ASC")
+    .map_err(|e| e.to_string())?;
+  let candidates: Vec<(String, i64)> = stmt
+    .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+    .map_err(|e| e.to_string())?
+    .flatten()
+    .collect();
+  let mut evicted = 0;
+  for (cache_key, size) in candidates {
+    if total <= cap {
+      break;
+    }
+    remove(conn, dir, &cache_key)?;
+    total -= size;
+    evicted += 1;
+  }
+  Ok(evicted)
+}
+
+pub fn stats(conn: &Connection) -> Result<MediaCacheStats, String> {
+  let (entries, total_bytes) = conn
+    .query_row("SELECT COUNT(*), IFNULL(SUM(size), 0) FROM media_cache", [], |row| {
+      Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?))
+    })
+    .map_err(|e| e.to_string())?;
+  Ok(MediaCacheStats {
+    entries,
+    total_bytes,
+    limit_bytes: limit(conn)?,
+  })
+}
+
+/// Remove every cached file, including stray files left by an interrupted write.
+pub fn clear(conn: &Connection, dir: &Path) -> Result<usize, String> {
+  let removed = conn
+    .execute("DELETE FROM media_cache", [])
+    .map_err(|e| e.to_string())?;
+  if dir.exists() {
+    for entry in fs::read_dir(dir).map_err(|e| e.to_string())?.flatten() {
+      let path: PathBuf = entry.path();
+      if path.extension().map(|ext| ext == "bin").unwrap_or(false) {
+        let _ = fs::remove_file(path);
+      }
+    }
+  }
+  Ok(removed)
+}
@@ -0,0 +1,495 @@
+use base64::{engine::general_purpose, Engine as _};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::attachment_crypto::{self, EncryptedFile};
+use crate::malware_scan::ScanVerdict;
+use crate::matrix_api::{now_millis, parse_mxc, MatrixClient};
+
+const PROGRESS_INTERVAL_MS: i64 = 250;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadRequest {
+  pub mxc_url: String,
+  #[serde(default)]
+  pub dest: Option<String>,
+  #[serde(default)]
+  pub file_name: Option<String>,
+  #[serde(default)]
+  pub expected_size: Option<u64>,
+  /// SHA-256 of the file, hex or unpadded base64 (as in encrypted file info).
+  #[serde(default)]
+  pub expected_sha256: Option<String>,
+  #[serde(default)]
+  pub download_id: Option<String>,
+  /// Room the media was posted in, kept with the download record.
+  #[serde(default)]
+  pub room_id: Option<String>,
+  /// Encrypted file info from the event; the download is decrypted before it reaches `dest`.
+  #[serde(default)]
+  pub file: Option<EncryptedFile>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadProgress {
+  pub download_id: String,
+  pub mxc_url: String,
+  pub received: u64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub total: Option<u64>,
+  pub resumed_from: u64,
+  pub done: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadResult {
+  pub download_id: String,
+  pub path: String,
+  pub size: u64,
+  pub sha256: String,
+  /// Malware scan result when download scanning is enabled.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub scan: Option<ScanVerdict>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DownloadStatus {
+  Active,
+  Completed,
+  Failed,
+  /// Stopped by the user; the partial file is kept for resuming.
+  Cancelled,
+}
+
+impl DownloadStatus {
+  fn as_str(self) -> &'static str {
+    match self {
+      DownloadStatus::Active => "active",
+      DownloadStatus::Completed => "completed",
+      DownloadStatus::Failed => "failed",
+      DownloadStatus::Cancelled => "cancelled",
+    }
+  }
+
+  fn parse(value: &str) -> Self {
+    match value {
+      "active" => DownloadStatus::Active,
+      "completed" => DownloadStatus::Completed,
+      "cancelled" => DownloadStatus::Cancelled,
+      _ => DownloadStatus::Failed,
+    }
+  }
+}
+
+/// A download as recorded in the index database.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadRecord {
+  pub download_id: String,
+  pub account_key: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub room_id: Option<String>,
+  pub mxc_url: String,
+  pub file_name: String,
+  pub path: String,
+  pub status: DownloadStatus,
+  pub received: u64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub total: Option<u64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub sha256: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub error: Option<String>,
+  pub created_at: i64,
+  pub updated_at: i64,
+}
+
+pub fn init_downloads_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS downloads (
+        download_id TEXT PRIMARY KEY,
+        account_key TEXT NOT NULL,
+        room_id TEXT,
+        mxc_url TEXT NOT NULL,
+        file_name TEXT NOT NULL,
+        path TEXT NOT NULL,
+        status TEXT NOT NULL,
+        received INTEGER NOT NULL DEFAULT 0,
+        total INTEGER,
+        sha256 TEXT,
+        error TEXT,
+        request_json TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL
+      );
+      CREATE INDEX IF NOT EXISTS downloads_account ON downloads (account_key, created_at);
+    ",
+  )
+}
+
+const COLUMNS: &str =
+  "download_id, account_key, room_id, mxc_url, file_name, path, status, received, total, sha256, error, created_at, updated_at";
+
+fn row_to_record(row: &rusqlite::Row) -> rusqlite::Result<DownloadRecord> {
+  let status: String = row.get(6)?;
+  Ok(DownloadRecord {
+    download_id: row.get(0)?,
+    account_key: row.get(1)?,
+    room_id: row.get(2)?,
+    mxc_url: row.get(3)?,
+    file_name: row.get(4)?,
+    path: row.get(5)?,
+    status: DownloadStatus::parse(&status),
+    received: row.get::<_, i64>(7)? as u64,
+    total: row.get::<_, Option<i64>>(8)?.map(|total| total as u64),
+    sha256: row.get(9)?,
+    error: row.get(10)?,
+    created_at: row.get(11)?,
+    updated_at: row.get(12)?,
+  })
+}
+
+/// Record a download as active; starting one again (a resume) keeps its creation time.
+pub fn record_start(conn: &Connection, account_key: &str, request: &DownloadRequest, download_id: &str, dest: &Path) -> Result<(), String> {
+  let request_json = serde_json::to_string(request).map_err(|e| e.to_string())?;
+  let file_name = dest.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+  let now = now_millis();
+  conn
+    .execute(
+      "INSERT INTO downloads (download_id, account_key, room_id, mxc_url, file_name, path, status, total, request_json, created_at, updated_at)
+       VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?10)
+       ON CONFLICT(download_id) DO UPDATE SET
+         path = excluded.path, file_name = excluded.file_name, status = excluded.status,
+         error = NULL, request_json = excluded.request_json, updated_at = excluded.updated_at",
+      params![
+        download_id,
+        account_key,
+        request.room_id,
+        request.mxc_url,
+        file_name,
+        dest.display().to_string(),
+        DownloadStatus::Active.as_str(),
+        request.expected_size.map(|size| size as i64),
+        request_json,
+        now,
+      ],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+/// Record how a download ended. `progress` is the last reported progress, if any.
+pub fn record_finish(
+  conn: &Connection,
+  download_id: &str,
+  outcome: &Result<DownloadResult, String>,
+  progress: Option<&DownloadProgress>,
+) -> Result<(), String> {
+  let (status, error, sha256, received) = match outcome {
+    Ok(result) => (DownloadStatus::Completed, None, Some(result.sha256.clone()), Some(result.size)),
+    Err(err) if is_cancelled_error(err, download_id) => (DownloadStatus::Cancelled, None, None, None),
+    Err(err) => (DownloadStatus::Failed, Some(err.clone()), None, None),
+  };
+  let received = received.or(progress.map(|p| p.received)).map(|n| n as i64);
+  let total = progress.and_then(|p| p.total).map(|n| n as i64);
+  conn
+    .execute(
+      "UPDATE downloads SET status = ?2, error = ?3, sha256 = COALESCE(?4, sha256),
+         received = COALESCE(?5, received), total = COALESCE(?6, total), updated_at = ?7
+       WHERE download_id = ?1",
+      params![download_id, status.as_str(), error, sha256, received, total, now_millis()],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+pub fn get(conn: &Connection, download_id: &str) -> Result<Option<DownloadRecord>, String> {
+  conn
+    .query_row(
+      &format!("SELECT {} FROM downloads WHERE download_id = ?1", COLUMNS),
+      params![download_id],
+      row_to_record,
+    )
+    .optional()
+    .map_err(|e| e.to_string())
+}
+
+/// The request a download was started with, for resuming it.
+pub fn stored_request(conn: &Connection, download_id: &str) -> Result<Option<(String, DownloadRequest)>, String> {
+  let stored: Option<(String, String)> = conn
+    .query_row(
+      "SELECT account_key, request_json FROM downloads WHERE download_id = ?1",
+      params![download_id],
+      |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+    .optional()
+    .map_err(|e| e.to_string())?;
+  match stored {
+    Some((account_key, json)) => {
+      let request = serde_json::from_str(&json).map_err(|e| e.to_string())?;
+      Ok(Some((account_key, request)))
+    }
+    None => Ok(None),
+  }
+}
+
+/// Newest first, optionally for one account and room.
+pub fn list(conn: &Connection, account_key: Option<&str>, room_id: Option<&str>) -> Result<Vec<DownloadRecord>, String> {
+  let mut stmt = conn
+    .prepare(&format!(
+      "SELECT {} FROM downloads WHERE (?1 IS NULL OR account_key = ?1) AND (?2 IS NULL OR room_id = ?2)
+       ORDER BY created_at DESC",
+      COLUMNS
+    ))
+    .map_err(|e| e.to_string())?;
+  let rows = stmt
+    .query_map(params![account_key, room_id], row_to_record)
+    .map_err(|e| e.to_string())?;
+  Ok(rows.flatten().collect())
+}
+
+/// Downloads left active by a previous run can't still be going; they can be resumed.
+pub fn mark_interrupted(conn: &Connection) -> Result<(), String> {
+  conn
+    .execute(
+      "UPDATE downloads SET status = ?1, updated_at = ?2 WHERE status = ?3",
+      params![DownloadStatus::Cancelled.as_str(), now_millis(), DownloadStatus::Active.as_str()],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+fn cancelled_error(download_id: &str) -> String {
+  format!("Download {} was cancelled", download_id)
+}
+
+pub fn is_cancelled_error(err: &str, download_id: &str) -> bool {
+  err == cancelled_error(download_id)
+}
+
+/// Cancellation flags for downloads in flight.
+#[derive(Default)]
+pub struct DownloadState {
+  active: Mutex<HashMap<String, Arc<AtomicBool>>>,
+}
+
+impl DownloadState {
+  fn register(&self, download_id: &str) -> Arc<AtomicBool> {
+    let flag = Arc::new(AtomicBool::new(false));
+    if let Ok(mut active) = self.active.lock() {
+      active.insert(download_id.to_string(), flag.clone());
+    }
+    flag
+  }
+
+  fn finish(&self, download_id: &str) {
+    if let Ok(mut active) = self.active.lock() {
+      active.remove(download_id);
+    }
+  }
+
+  pub fn cancel(&self, download_id: &str) -> bool {
+    match self.active.lock().ok().and_then(|active| active.get(download_id).cloned()) {
+      Some(flag) => {
+        flag.store(true, Ordering::Relaxed);
+        true
+      }
+      None => false,
+    }
+  }
+}
+
+/// Resolve the destination: an explicit file path, a directory plus file name, or a folder
+/// per media ID in the fallback directory, so equally named files don't overwrite each other.
+pub fn destination(request: &DownloadRequest, fallback_dir: &Path) -> Result<PathBuf, String> {
+  let default_name = || {
+    request
+      .file_name
+      .clone()
+      .filter(|n| !n.trim().is_empty())
+      .or_else(|| parse_mxc(&request.mxc_url).map(|(_, media_id)| media_id))
+      .unwrap_or_else(|| "download".to_string())
+  };
+  let name = sanitize_file_name(&default_name());
+  match &request.dest {
+    Some(dest) => {
+      let dest = PathBuf::from(dest);
+      if dest.is_dir() {
+        Ok(dest.join(name))
+      } else {
+        Ok(dest)
+      }
+    }
+    None => {
+      let media_id = parse_mxc(&request.mxc_url)
+        .map(|(_, media_id)| media_id)
+        .unwrap_or_else(|| "download".to_string());
+      Ok(fallback_dir.join(sanitize_file_name(&media_id)).join(name))
+    }
+  }
+}
+
+pub fn sanitize_file_name(name: &str) -> String {
+  let cleaned: String = name
+    .chars()
+    .map(|c| if matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|') || c.is_control() { '_' } else { c })
+    .collect();
+  let trimmed = cleaned.trim_matches(|c: char| c == '.' || c.is_whitespace());
+  if trimmed.is_empty() {
+    "download".to_string()
+  } else {
+    trimmed.to_string()
+  }
+}
+
+pub fn sha256_file(path: &Path) -> Result<Vec<u8>, String> {
+  let mut file = fs::File::open(path).map_err(|e| e.to_string())?;
+  let mut hasher = Sha256::new();
+  let mut buf = vec![0u8; 64 * 1024];
+  loop {
+    let read = file.read(&mut buf).map_err(|e| e.to_string())?;
+    if read == 0 {
+      break;
+    }
+    hasher.update(&buf[..read]);
+  }
+  Ok(hasher.finalize().to_vec())
+}
+
+fn hash_matches(expected: &str, digest: &[u8]) -> bool {
+  let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+  let b64 = general_purpose::STANDARD_NO_PAD.encode(digest);
+  let expected = expected.trim().trim_end_matches('=');
+  expected.eq_ignore_ascii_case(&hex) || expected == b64 || expected == b64.replace('+', "-").replace('/', "_")
+}
+
+pub fn download_id(request: &DownloadRequest) -> String {
+  request
+    .download_id
+    .clone()
+    .unwrap_or_else(|| format!("dl-{}", now_millis()))
+}
+
+/// Stream `request.mxc_url` to `dest`, resuming from an existing `.part` file with a Range
+/// request. Cancelling keeps the partial file so the next call picks up where it stopped.
+pub async fn download(
+  state: &DownloadState,
+  client: &MatrixClient,
+  request: &DownloadRequest,
+  dest: PathBuf,
+  on_progress: impl Fn(&DownloadProgress),
+) -> Result<DownloadResult, String> {
+  let download_id = download_id(request);
+  let cancelled = state.register(&download_id);
+  let result = run(client, request, &download_id, &dest, &cancelled, &on_progress).await;
+  state.finish(&download_id);
+  result
+}
+
+async fn run(
+  client: &MatrixClient,
+  request: &DownloadRequest,
+  download_id: &str,
+  dest: &Path,
+  cancelled: &AtomicBool,
+  on_progress: &impl Fn(&DownloadProgress),
+) -> Result<DownloadResult, String> {
+  if let Some(parent) = dest.parent() {
+    fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+  }
+  let part_path = PathBuf::from(format!("{}.part", dest.display()));
+  let existing = fs::metadata(&part_path).map(|m| m.len()).unwrap_or(0);
+  let mut response = client.download_response(&request.mxc_url, Some(existing)).await?;
+  let resumed = existing > 0 && response.status() == reqwest::StatusCode::PARTIAL_CONTENT;
+  let offset = if resumed { existing } else { 0 };
+  let total = response
+    .content_length()
+    .map(|len| len + offset)
+    .or(request.expected_size);
+  let mut file = OpenOptions::new()
+    .create(true)
+    .write(true)
+    .append(resumed)
+    .truncate(!resumed)
+    .open(&part_path)
+    .map_err(|e| e.to_string())?;
+
+  let mut progress = DownloadProgress {
+    download_id: download_id.to_string(),
+    mxc_url: request.mxc_url.clone(),
+    received: offset,
+    total,
+    resumed_from: offset,
+    done: false,
+  };
+  on_progress(&progress);
+  let mut last_emit = now_millis();
+  while let Some(chunk) = response.chunk().await.map_err(|e| e.to_string())? {
+    if cancelled.load(Ordering::Relaxed) {
+      file.flush().map_err(|e| e.to_string())?;
+      return Err(cancelled_error(download_id));
+    }
+    file.write_all(&chunk).map_err(|e| e.to_string())?;
+    progress.received += chunk.len() as u64;
+    if now_millis() - last_emit >= PROGRESS_INTERVAL_MS {
+      on_progress(&progress);
+      last_emit = now_millis();
+    }
+  }
+  file.flush().map_err(|e| e.to_string())?;
+  drop(file);
+
+  let size = fs::metadata(&part_path).map(|m| m.len()).map_err(|e| e.to_string())?;
+  if let Some(expected) = request.expected_size.or(total) {
+    if size != expected {
+      let _ = fs::remove_file(&part_path);
+      return Err(format!("Downloaded {} bytes but expected {}", size, expected));
+    }
+  }
+  let digest = sha256_file(&part_path)?;
+  if let Some(expected) = &request.expected_sha256 {
+    if !hash_matches(expected, &digest) {
+      let _ = fs::remove_file(&part_path);
+      return Err("Downloaded file does not match the expected SHA-256 hash".to_string());
+    }
+  }
+  let size = match &request.file {
+    Some(file) => {
+      let ciphertext = fs::read(&part_path).map_err(|e| e.to_string())?;
+      let plaintext = match attachment_crypto::decrypt(&ciphertext, file) {
+        Ok(plaintext) => plaintext,
+        Err(err) => {
+          let _ = fs::remove_file(&part_path);
+          return Err(err);
+        }
+      };
+      fs::write(dest, &plaintext).map_err(|e| e.to_string())?;
+      fs::remove_file(&part_path).map_err(|e| e.to_string())?;
+      plaintext.len() as u64
+    }
+    None => {
+      fs::rename(&part_path, dest).map_err(|e| e.to_string())?;
+      size
+    }
+  };
+  progress.done = true;
+  on_progress(&progress);
+  Ok(DownloadResult {
+    download_id: download_id.to_string(),
+    path: dest.display().to_string(),
+    size,
+    sha256: digest.iter().map(|b| format!("{:02x}", b)).collect(),
+    scan: None,
+  })
+}
@@ -0,0 +1,190 @@
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::attachment_crypto::EncryptedFile;
+use crate::event_cache;
+use crate::media_download::sanitize_file_name;
+
+/// An indexed media item with what is needed to fetch and name it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportItem {
+  pub id: String,
+  pub event_id: String,
+  pub room_id: String,
+  pub media_type: String,
+  pub mxc_url: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub file_name: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub mimetype: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub size: Option<i64>,
+  pub sender: String,
+  pub timestamp: i64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub body: Option<String>,
+  #[serde(skip)]
+  pub file: Option<EncryptedFile>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportFilters {
+  /// Media types from the index (`image`, `video`, ...); all when unset.
+  #[serde(default)]
+  pub media_types: Option<Vec<String>>,
+  #[serde(default)]
+  pub senders: Option<Vec<String>>,
+  #[serde(default)]
+  pub since: Option<i64>,
+  #[serde(default)]
+  pub until: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportProgress {
+  pub export_id: String,
+  pub room_id: String,
+  pub completed: usize,
+  pub failed: usize,
+  pub total: usize,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub current: Option<String>,
+  pub done: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportedFile {
+  pub id: String,
+  pub path: String,
+  pub size: u64,
+}
+
+const ITEM_COLUMNS: &str = "id, event_id, room_id, media_type, mxc_url, file_name, mimetype, size, sender, timestamp, body";
+
+fn item_from_row(row: &Row) -> rusqlite::Result<ExportItem> {
+  Ok(ExportItem {
+    id: row.get(0)?,
+    event_id: row.get(1)?,
+    room_id: row.get(2)?,
+    media_type: row.get(3)?,
+    mxc_url: row.get(4)?,
+    file_name: row.get(5)?,
+    mimetype: row.get(6)?,
+    size: row.get(7)?,
+    sender: row.get::<_, Option<String>>(8)?.unwrap_or_default(),
+    timestamp: row.get::<_, Option<i64>>(9)?.unwrap_or_default(),
+    body: row.get(10)?,
+    file: None,
+  })
+}
+
+/// Encrypted file info from the cached event, so encrypted attachments can be decrypted.
+fn with_encryption(conn: &Connection, account_key: &str, mut item: ExportItem) -> Result<ExportItem, String> {
+  item.file = event_cache::get_event(conn, account_key, &item.room_id, &item.event_id)?
+    .and_then(|event| event.content.get("file").cloned())
+    .and_then(|file| serde_json::from_value(file).ok());
+  Ok(item)
+}
+
+pub fn item(conn: &Connection, account_key: &str, media_id: &str) -> Result<Option<ExportItem>, String> {
+  let item = conn
+    .query_row(
+      &format!(
+        "SELECT {} FROM media_index WHERE account_key = ?1 AND id = ?2 AND mxc_url IS NOT NULL",
+        ITEM_COLUMNS
+      ),
+      [account_key, media_id],
+      item_from_row,
+    )
+    .optional()
+    .map_err(|e| e.to_string())?;
+  item.map(|item| with_encryption(conn, account_key, item)).transpose()
+}
+
+/// Media an account indexed in a room matching `filters`, oldest first.
+pub fn room_items(conn: &Connection, account_key: &str, room_id: &str, filters: &ExportFilters) -> Result<Vec<ExportItem>, String> {
+  let mut stmt = conn
+    .prepare(&format!(
+      "SELECT {} FROM media_index
+       WHERE account_key = ?1 AND room_id = ?2 AND mxc_url IS NOT NULL
+         AND (?3 IS NULL OR timestamp >= ?3) AND (?4 IS NULL OR timestamp <= ?4)
+       ORDER BY timestamp ASC",
+      ITEM_COLUMNS
+    ))
+    .map_err(|e| e.to_string())?;
+  let items: Vec<ExportItem> = stmt
+    .query_map(params![account_key, room_id, filters.since, filters.until], item_from_row)
+    .map_err(|e| e.to_string())?
+    .flatten()
+    .filter(|item| {
+      filters.media_types.as_ref().map(|types| types.contains(&item.media_type)).unwrap_or(true)
+        && filters.senders.as_ref().map(|senders| senders.contains(&item.sender)).unwrap_or(true)
+    })
+    .collect();
+  items.into_iter().map(|item| with_encryption(conn, account_key, item)).collect()
+}
+
+/// The original file name, falling back to the message body and then the media ID.
+pub fn original_name(item: &ExportItem) -> String {
+  let name = item
+    .file_name
+    .clone()
+    .or_else(|| item.body.clone().filter(|body| !body.contains('\n') && body.len() <= 255))
+    .unwrap_or_else(|| item.mxc_url.rsplit('/').next().unwrap_or("media").to_string());
+  let name = sanitize_file_name(&name);
+  let has_extension = Path::new(&name).extension().is_some();
+  match item.mimetype.as_deref().and_then(|m| mime_guess::get_mime_extensions_str(m)).and_then(|e| e.first()) {
+    Some(extension) if !has_extension => format!("{}.{}", name, extension),
+    _ => name,
+  }
+}
+
+/// `dir/name`, or `dir/name (n)` when that file already exists.
+pub fn unique_path(dir: &Path, name: &str) -> PathBuf {
+  let candidate = dir.join(name);
+  if !candidate.exists() {
+    return candidate;
+  }
+  let path = Path::new(name);
+  let stem = path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+  let extension = path.extension().map(|e| format!(".{}", e.to_string_lossy())).unwrap_or_default();
+  (1..)
+    .map(|n| dir.join(format!("{} ({}){}", stem, n, extension)))
+    .find(|candidate| !candidate.exists())
+    .unwrap_or(candidate)
+}
+
+/// Write the file and a `<file>.json` sidecar with the event metadata.
+pub fn write_with_sidecar(path: &Path, bytes: &[u8], item: &ExportItem) -> Result<ExportedFile, String> {
+  if let Some(parent) = path.parent() {
+    fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+  }
+  fs::write(path, bytes).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+  let sidecar = json!({
+    "eventId": item.event_id,
+    "roomId": item.room_id,
+    "sender": item.sender,
+    "timestamp": item.timestamp,
+    "mediaType": item.media_type,
+    "mimetype": item.mimetype,
+    "size": bytes.len(),
+    "mxcUrl": item.mxc_url,
+    "fileName": item.file_name,
+    "body": item.body,
+  });
+  let sidecar_path = PathBuf::from(format!("{}.json", path.display()));
+  let json = serde_json::to_vec_pretty(&sidecar).map_err(|e| e.to_string())?;
+  fs::write(&sidecar_path, json).map_err(|e| e.to_string())?;
+  Ok(ExportedFile {
+    id: item.id.clone(),
+    path: path.display().to_string(),
+    size: bytes.len() as u64,
+  })
+}
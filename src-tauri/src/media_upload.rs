@@ -0,0 +1,449 @@
+use futures_util::stream;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::attachment_crypto::EncryptedFile;
+use crate::image_processing::{self, OutputFormat};
+use crate::matrix_api::{now_millis, ApiError, MatrixClient};
+
+/// Fallback quality steps tried when an image is still above the server's upload limit.
+const SHRINK_STEPS: [(f32, u8); 4] = [(1.0, 70), (0.75, 60), (0.5, 50), (0.35, 40)];
+
+const UPLOAD_CHUNK_SIZE: usize = 256 * 1024;
+const PROGRESS_INTERVAL_MS: i64 = 250;
+const MAX_ATTEMPTS: u32 = 4;
+
+/// Upload pipeline settings, stored globally and optionally overridden per room.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadSettings {
+  /// Drop EXIF/XMP metadata (GPS, device model, timestamps) from images before sending.
+  #[serde(default = "default_true")]
+  pub strip_metadata: bool,
+  /// Downscale and re-encode still images before upload.
+  #[serde(default)]
+  pub compress_images: bool,
+  #[serde(default = "default_max_dimension")]
+  pub max_dimension: u32,
+  #[serde(default = "default_quality")]
+  pub quality: u8,
+  #[serde(default = "default_output_format")]
+  pub output_format: OutputFormat,
+}
+
+fn default_true() -> bool {
+  true
+}
+
+fn default_max_dimension() -> u32 {
+  2048
+}
+
+fn default_quality() -> u8 {
+  82
+}
+
+fn default_output_format() -> OutputFormat {
+  OutputFormat::Auto
+}
+
+impl Default for UploadSettings {
+  fn default() -> Self {
+    UploadSettings {
+      strip_metadata: true,
+      compress_images: false,
+      max_dimension: default_max_dimension(),
+      quality: default_quality(),
+      output_format: default_output_format(),
+    }
+  }
+}
+
+pub fn init_upload_settings_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS upload_settings (
+        scope TEXT PRIMARY KEY,
+        settings_json TEXT NOT NULL
+      );
+    ",
+  )
+}
+
+fn load_scope(conn: &Connection, scope: &str) -> Result<Option<UploadSettings>, String> {
+  let stored: Option<String> = conn
+    .query_row("SELECT settings_json FROM upload_settings WHERE scope = ?1", [scope], |row| row.get(0))
+    .optional()
+    .map_err(|e| e.to_string())?;
+  Ok(stored.and_then(|json| serde_json::from_str(&json).ok()))
+}
+
+/// Room settings when present, otherwise the global ones (scope `''`).
+pub fn settings(conn: &Connection, room_id: Option<&str>) -> Result<UploadSettings, String> {
+  if let Some(room_id) = room_id {
+    if let Some(room) = load_scope(conn, room_id)? {
+      return Ok(room);
+    }
+  }
+  Ok(load_scope(conn, "")?.unwrap_or_default())
+}
+
+/// Store settings for a room, or globally when `room_id` is None; `None` settings clear a room override.
+pub fn set_settings(conn: &Connection, room_id: Option<&str>, value: Option<&UploadSettings>) -> Result<(), String> {
+  let scope = room_id.unwrap_or_default();
+  match value {
+    Some(value) => {
+      let json = serde_json::to_string(value).map_err(|e| e.to_string())?;
+      conn
+        .execute(
+          "INSERT INTO upload_settings (scope, settings_json) VALUES (?1, ?2)
+           ON CONFLICT(scope) DO UPDATE SET settings_json = excluded.settings_json",
+          params![scope, json],
+        )
+        .map_err(|e| e.to_string())?;
+    }
+    None => {
+      conn
+        .execute("DELETE FROM upload_settings WHERE scope = ?1", [scope])
+        .map_err(|e| e.to_string())?;
+    }
+  }
+  Ok(())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageMetadata {
+  pub width: u32,
+  pub height: u32,
+  pub blurhash: String,
+  pub dominant_color: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct PreparedUpload {
+  pub bytes: Vec<u8>,
+  pub file_name: String,
+  pub mimetype: String,
+  pub image: Option<ImageMetadata>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadedMedia {
+  pub content_uri: String,
+  pub file_name: String,
+  pub mimetype: String,
+  pub size: u64,
+  /// `m.image`, `m.video`, `m.audio` or `m.file`.
+  pub msgtype: String,
+  /// Ready-to-send `info` block for the message content.
+  pub info: Value,
+  /// For encrypted rooms: the `file` block to send in place of `url`.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub file: Option<EncryptedFile>,
+}
+
+/// Content type from the file's magic bytes for images, otherwise from its extension.
+pub fn guess_mimetype(path: &Path, bytes: &[u8]) -> String {
+  if let Ok(format) = image::guess_format(bytes) {
+    return format.to_mime_type().to_string();
+  }
+  mime_guess::from_path(path)
+    .first_raw()
+    .unwrap_or("application/octet-stream")
+    .to_string()
+}
+
+pub fn msgtype_for(mimetype: &str) -> &'static str {
+  match mimetype.split('/').next().unwrap_or_default() {
+    "image" => "m.image",
+    "video" => "m.video",
+    "audio" => "m.audio",
+    _ => "m.file",
+  }
+}
+
+/// Dimensions, blurhash and dominant colour for decodable images.
+pub fn image_metadata(bytes: &[u8]) -> Option<ImageMetadata> {
+  let img = image_processing::decode_oriented(bytes).ok()?;
+  Some(ImageMetadata {
+    width: img.width(),
+    height: img.height(),
+    blurhash: image_processing::blurhash(&img).ok()?,
+    dominant_color: image_processing::dominant_color(&img),
+  })
+}
+
+fn with_extension(file_name: &str, mimetype: &str) -> String {
+  let extension = match mimetype {
+    "image/jpeg" => "jpg",
+    "image/png" => "png",
+    "image/webp" => "webp",
+    _ => return file_name.to_string(),
+  };
+  let stem = Path::new(file_name)
+    .file_stem()
+    .map(|s| s.to_string_lossy().to_string())
+    .unwrap_or_else(|| "image".to_string());
+  format!("{}.{}", stem, extension)
+}
+
+/// Apply configured compression, then keep shrinking until the image fits `max_upload_size`.
+fn compress_image(bytes: Vec<u8>, mimetype: String, settings: &UploadSettings, max_upload_size: Option<u64>) -> Result<(Vec<u8>, String), String> {
+  let fits = |data: &[u8]| max_upload_size.map(|limit| data.len() as u64 <= limit).unwrap_or(true);
+  let (mut bytes, mut mimetype) = (bytes, mimetype);
+  if settings.compress_images {
+    if let Some((data, content_type)) =
+      image_processing::compress(&bytes, settings.max_dimension, settings.quality, settings.output_format)?
+    {
+      if data.len() < bytes.len() {
+        bytes = data;
+        mimetype = content_type.to_string();
+      }
+    }
+  }
+  for (scale, quality) in SHRINK_STEPS {
+    if fits(&bytes) {
+      break;
+    }
+    let dimension = (settings.max_dimension as f32 * scale) as u32;
+    let format = if settings.output_format == OutputFormat::Auto { OutputFormat::Jpeg } else { settings.output_format };
+    match image_processing::compress(&bytes, dimension, quality, format)? {
+      Some((data, content_type)) => {
+        bytes = data;
+        mimetype = content_type.to_string();
+      }
+      None => break,
+    }
+  }
+  Ok((bytes, mimetype))
+}
+
+/// Read a file and run the upload pipeline: metadata stripping, compression and the server size limit.
+pub fn prepare(path: &Path, settings: &UploadSettings, max_upload_size: Option<u64>) -> Result<PreparedUpload, String> {
+  let mut bytes = fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+  let mut file_name = path
+    .file_name()
+    .map(|n| n.to_string_lossy().to_string())
+    .unwrap_or_else(|| "file".to_string());
+  let mut mimetype = guess_mimetype(path, &bytes);
+  if settings.strip_metadata && mimetype.starts_with("image/") {
+    bytes = image_processing::strip_metadata(&bytes)?;
+  }
+  if mimetype.starts_with("image/") {
+    let original_type = mimetype.clone();
+    (bytes, mimetype) = compress_image(bytes, mimetype, settings, max_upload_size)?;
+    if mimetype != original_type {
+      file_name = with_extension(&file_name, &mimetype);
+    }
+  }
+  if let Some(limit) = max_upload_size {
+    if bytes.len() as u64 > limit {
+      return Err(format!(
+        "{} is {} bytes but the server accepts at most {} bytes",
+        file_name,
+        bytes.len(),
+        limit
+      ));
+    }
+  }
+  let image = if mimetype.starts_with("image/") { image_metadata(&bytes) } else { None };
+  Ok(PreparedUpload {
+    bytes,
+    file_name,
+    mimetype,
+    image,
+  })
+}
+
+pub fn info_json(prepared: &PreparedUpload) -> Value {
+  let mut info = json!({
+    "mimetype": prepared.mimetype,
+    "size": prepared.bytes.len(),
+  });
+  if let Some(image) = &prepared.image {
+    info["w"] = json!(image.width);
+    info["h"] = json!(image.height);
+    info["xyz.amorgan.blurhash"] = json!(image.blurhash);
+    info["dominant_color"] = json!(image.dominant_color);
+  }
+  info
+}
+
+pub fn uploaded(prepared: &PreparedUpload, content_uri: String, file: Option<EncryptedFile>) -> UploadedMedia {
+  UploadedMedia {
+    content_uri,
+    file_name: prepared.file_name.clone(),
+    mimetype: prepared.mimetype.clone(),
+    size: prepared.bytes.len() as u64,
+    msgtype: msgtype_for(&prepared.mimetype).to_string(),
+    info: info_json(prepared),
+    file,
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadProgress {
+  pub upload_id: String,
+  pub sent: u64,
+  pub total: u64,
+  pub attempt: u32,
+  pub done: bool,
+}
+
+/// Cancellation flags for uploads in flight.
+#[derive(Default)]
+pub struct UploadState {
+  active: Mutex<HashMap<String, Arc<AtomicBool>>>,
+}
+
+impl UploadState {
+  fn register(&self, upload_id: &str) -> Arc<AtomicBool> {
+    let flag = Arc::new(AtomicBool::new(false));
+    if let Ok(mut active) = self.active.lock() {
+      active.insert(upload_id.to_string(), flag.clone());
+    }
+    flag
+  }
+
+  fn finish(&self, upload_id: &str) {
+    if let Ok(mut active) = self.active.lock() {
+      active.remove(upload_id);
+    }
+  }
+
+  pub fn cancel(&self, upload_id: &str) -> bool {
+    match self.active.lock().ok().and_then(|active| active.get(upload_id).cloned()) {
+      Some(flag) => {
+        flag.store(true, Ordering::Relaxed);
+        true
+      }
+      None => false,
+    }
+  }
+}
+
+/// Network failures, rate limiting and server errors are worth another attempt.
+pub fn retry_delay(err: &ApiError, attempt: u32) -> Option<Duration> {
+  let backoff = Duration::from_millis(1000 * 2u64.pow(attempt));
+  match err {
+    ApiError::Network(_) => Some(backoff),
+    ApiError::Http { status: 429, body, .. } => Some(
+      body
+        .get("retry_after_ms")
+        .and_then(|v| v.as_u64())
+        .map(Duration::from_millis)
+        .unwrap_or(backoff),
+    ),
+    ApiError::Http { status, .. } if *status >= 500 => Some(backoff),
+    _ => None,
+  }
+}
+
+/// Body that feeds `bytes` to the server in chunks, counting what has been handed over and
+/// failing as soon as the upload is cancelled.
+fn chunked_body(bytes: Arc<Vec<u8>>, sent: Arc<AtomicU64>, cancelled: Arc<AtomicBool>) -> reqwest::Body {
+  let len = bytes.len();
+  let chunks = stream::iter((0..len).step_by(UPLOAD_CHUNK_SIZE).map(move |start| {
+    if cancelled.load(Ordering::Relaxed) {
+      return Err(std::io::Error::new(std::io::ErrorKind::Interrupted, "upload cancelled"));
+    }
+    let end = (start + UPLOAD_CHUNK_SIZE).min(len);
+    sent.store(end as u64, Ordering::Relaxed);
+    Ok(bytes[start..end].to_vec())
+  }));
+  reqwest::Body::wrap_stream(chunks)
+}
+
+/// Stream `bytes` to the content repository with progress, cancellation and retries.
+/// Uses asynchronous upload (a URI reserved up front) when the server supports it so every
+/// retry targets the same `mxc://` URI, falling back to a plain `/upload`.
+pub async fn transfer(
+  state: &UploadState,
+  client: &MatrixClient,
+  upload_id: Option<String>,
+  bytes: Vec<u8>,
+  content_type: &str,
+  file_name: Option<&str>,
+  on_progress: impl Fn(&UploadProgress),
+) -> Result<String, String> {
+  let upload_id = upload_id.unwrap_or_else(|| format!("ul-{}", now_millis()));
+  let cancelled = state.register(&upload_id);
+  let result = run(client, &upload_id, bytes, content_type, file_name, &cancelled, &on_progress).await;
+  state.finish(&upload_id);
+  result
+}
+
+async fn run(
+  client: &MatrixClient,
+  upload_id: &str,
+  bytes: Vec<u8>,
+  content_type: &str,
+  file_name: Option<&str>,
+  cancelled: &Arc<AtomicBool>,
+  on_progress: &impl Fn(&UploadProgress),
+) -> Result<String, String> {
+  let total = bytes.len() as u64;
+  let bytes = Arc::new(bytes);
+  let reserved = client.create_media().await.ok();
+  let mut progress = UploadProgress {
+    upload_id: upload_id.to_string(),
+    sent: 0,
+    total,
+    attempt: 1,
+    done: false,
+  };
+  for attempt in 1..=MAX_ATTEMPTS {
+    progress.attempt = attempt;
+    progress.sent = 0;
+    on_progress(&progress);
+    let sent = Arc::new(AtomicU64::new(0));
+    let body = chunked_body(bytes.clone(), sent.clone(), cancelled.clone());
+    let request = async {
+      match &reserved {
+        Some(mxc) => client
+          .upload_to(mxc, body, total, content_type, file_name)
+          .await
+          .map(|_| mxc.clone()),
+        None => client.upload_body(body, total, content_type, file_name).await,
+      }
+    };
+    tokio::pin!(request);
+    let outcome = loop {
+      tokio::select! {
+        outcome = &mut request => break outcome,
+        _ = tokio::time::sleep(Duration::from_millis(PROGRESS_INTERVAL_MS as u64)) => {
+          progress.sent = sent.load(Ordering::Relaxed);
+          on_progress(&progress);
+        }
+      }
+    };
+    if cancelled.load(Ordering::Relaxed) {
+      return Err(format!("Upload {} was cancelled", upload_id));
+    }
+    match outcome {
+      Ok(content_uri) => {
+        progress.sent = total;
+        progress.done = true;
+        on_progress(&progress);
+        return Ok(content_uri);
+      }
+      Err(err) => match retry_delay(&err, attempt - 1) {
+        Some(delay) if attempt < MAX_ATTEMPTS => {
+          tracing::warn!("Upload {} attempt {} failed, retrying: {}", upload_id, attempt, err);
+          tokio::time::sleep(delay).await;
+        }
+        _ => return Err(err.to_string()),
+      },
+    }
+  }
+  Err(format!("Upload {} failed after {} attempts", upload_id, MAX_ATTEMPTS))
+}
@@ -0,0 +1,153 @@
+use base64::{engine::general_purpose, Engine as _};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+
+/// bm25 weights for the indexed columns, in table order: body, sender, search_tokens, tags_json.
+pub const RELEVANCE: &str = "bm25(message_fts, 10.0, 2.0, 5.0, 3.0)";
+
+/// How `query_local_index` orders its results.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SearchOrder {
+  /// Newest first.
+  #[default]
+  Recent,
+  /// Best BM25 match first, newest first among equals; same as `Recent` without a term.
+  Relevance,
+}
+
+/// Full-text index over `message_index`, kept in sync by triggers. Existing rows are indexed
+/// once when the table is first created.
+pub fn init_message_search_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  let exists: bool = conn.query_row(
+    "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'message_fts')",
+    [],
+    |row| row.get(0),
+  )?;
+  conn.execute_batch(
+    "CREATE VIRTUAL TABLE IF NOT EXISTS message_fts USING fts5(
+        body, sender, search_tokens, tags_json,
+        content = 'message_index',
+        content_rowid = 'rowid',
+        tokenize = 'unicode61 remove_diacritics 2',
+        prefix = '2 3'
+      );
+      CREATE TRIGGER IF NOT EXISTS message_fts_insert AFTER INSERT ON message_index BEGIN
+        INSERT INTO message_fts (rowid, body, sender, search_tokens, tags_json)
+          VALUES (new.rowid, new.body, new.sender, new.search_tokens, new.tags_json);
+      END;
+      CREATE TRIGGER IF NOT EXISTS message_fts_delete AFTER DELETE ON message_index BEGIN
+        INSERT INTO message_fts (message_fts, rowid, body, sender, search_tokens, tags_json)
+          VALUES ('delete', old.rowid, old.body, old.sender, old.search_tokens, old.tags_json);
+      END;
+      DROP TRIGGER IF EXISTS message_fts_update;
+      CREATE TRIGGER IF NOT EXISTS message_fts_update_content
+        AFTER UPDATE OF body, sender, search_tokens, tags_json ON message_index
+      BEGIN
+        INSERT INTO message_fts (message_fts, rowid, body, sender, search_tokens, tags_json)
+          VALUES ('delete', old.rowid, old.body, old.sender, old.search_tokens, old.tags_json);
+        INSERT INTO message_fts (rowid, body, sender, search_tokens, tags_json)
+          VALUES (new.rowid, new.body, new.sender, new.search_tokens, new.tags_json);
+      END;
+    ",
+  )?;
+  if !exists {
+    conn.execute("INSERT INTO message_fts (message_fts) VALUES ('rebuild')", [])?;
+  }
+  Ok(())
+}
+
+/// FTS5 query for a search term: every word must match, the last one as a prefix so
+/// results show up while typing. `None` when the term has no words (e.g. only emoji),
+/// which the caller matches with LIKE instead.
+pub fn match_expression(term: &str) -> Option<String> {
+  let words: Vec<String> = term
+    .split(|c: char| !c.is_alphanumeric())
+    .filter(|word| !word.is_empty())
+    .map(|word| format!("\"{}\"", word.to_lowercase()))
+    .collect();
+  let (last, rest) = words.split_last()?;
+  let mut expression = rest.join(" ");
+  if !expression.is_empty() {
+    expression.push(' ');
+  }
+  expression.push_str(last);
+  expression.push('*');
+  Some(expression)
+}
+
+/// Where the next page of results starts. Newest-first pages continue after the last record
+/// (stable while new messages arrive); relevance-ordered pages by offset.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SearchCursor {
+  After { timestamp: i64, event_id: String },
+  Offset(usize),
+}
+
+impl SearchCursor {
+  /// Opaque token handed to the frontend.
+  pub fn encode(&self) -> String {
+    let raw = match self {
+      SearchCursor::After { timestamp, event_id } => format!("t{}:{}", timestamp, event_id),
+      SearchCursor::Offset(offset) => format!("o{}", offset),
+    };
+    general_purpose::URL_SAFE_NO_PAD.encode(raw)
+  }
+
+  pub fn decode(token: &str) -> Result<Self, String> {
+    let invalid = || "Invalid search cursor".to_string();
+    let raw = general_purpose::URL_SAFE_NO_PAD.decode(token.trim()).map_err(|_| invalid())?;
+    let raw = String::from_utf8(raw).map_err(|_| invalid())?;
+    if let Some(offset) = raw.strip_prefix('o') {
+      return offset.parse().map(SearchCursor::Offset).map_err(|_| invalid());
+    }
+    let (timestamp, event_id) = raw.strip_prefix('t').and_then(|rest| rest.split_once(':')).ok_or_else(invalid)?;
+    Ok(SearchCursor::After {
+      timestamp: timestamp.parse().map_err(|_| invalid())?,
+      event_id: event_id.to_string(),
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn cursors_round_trip() {
+    let cursors = [
+      SearchCursor::After { timestamp: 1_700_000_000_000, event_id: "$abc:example.org".into() },
+      SearchCursor::After { timestamp: 0, event_id: "$Base64+/Id".into() },
+      SearchCursor::Offset(0),
+      SearchCursor::Offset(250),
+    ];
+    for cursor in cursors {
+      let token = cursor.encode();
+      assert!(token.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+      assert_eq!(SearchCursor::decode(&token), Ok(cursor));
+    }
+  }
+
+  #[test]
+  fn decode_tolerates_surrounding_whitespace() {
+    let token = SearchCursor::Offset(7).encode();
+    assert_eq!(SearchCursor::decode(&format!(" {}\n", token)), Ok(SearchCursor::Offset(7)));
+  }
+
+  #[test]
+  fn decode_rejects_malformed_tokens() {
+    let encode = |raw: &str| general_purpose::URL_SAFE_NO_PAD.encode(raw);
+    for token in [
+      String::new(),
+      "not base64!".to_string(),
+      encode("x12"),
+      encode("o"),
+      encode("o-1"),
+      encode("t123"),
+      encode("tabc:$event"),
+      general_purpose::URL_SAFE_NO_PAD.encode([0xff, 0xfe]),
+    ] {
+      assert!(SearchCursor::decode(&token).is_err(), "{:?} should be rejected", token);
+    }
+  }
+}
@@ -0,0 +1,287 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use crate::matrix_api::{now_millis, MatrixClient};
+use crate::proxy;
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(20);
+const VERSIONS_SAMPLES: usize = 3;
+/// Enough to measure throughput without pulling a whole video.
+const MEDIA_SAMPLE_BYTES: usize = 4 * 1024 * 1024;
+
+/// One measurement: whether it worked, how long it took and what it found.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Probe<T> {
+  pub ok: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub duration_ms: Option<u64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub value: Option<T>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub error: Option<String>,
+}
+
+impl<T> Probe<T> {
+  fn skipped(reason: &str) -> Self {
+    Probe {
+      ok: false,
+      duration_ms: None,
+      value: None,
+      error: Some(reason.to_string()),
+    }
+  }
+}
+
+async fn measure<T>(future: impl Future<Output = Result<T, String>>) -> Probe<T> {
+  let started = Instant::now();
+  let result = tokio::time::timeout(PROBE_TIMEOUT, future)
+    .await
+    .unwrap_or_else(|_| Err(format!("Timed out after {}s", PROBE_TIMEOUT.as_secs())));
+  let duration_ms = Some(started.elapsed().as_millis() as u64);
+  match result {
+    Ok(value) => Probe {
+      ok: true,
+      duration_ms,
+      value: Some(value),
+      error: None,
+    },
+    Err(error) => Probe {
+      ok: false,
+      duration_ms,
+      value: None,
+      error: Some(error),
+    },
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TlsDetails {
+  pub subject: String,
+  pub issuer: String,
+  /// Seconds since epoch.
+  pub not_before: i64,
+  pub not_after: i64,
+  pub days_until_expiry: i64,
+  pub dns_names: Vec<String>,
+  /// SHA-256 of the leaf certificate, hex.
+  pub fingerprint: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VersionsInfo {
+  pub versions: Vec<String>,
+  pub samples_ms: Vec<u64>,
+  pub median_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Throughput {
+  pub bytes: usize,
+  pub bytes_per_sec: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NetworkReport {
+  pub homeserver_url: String,
+  pub generated_at: i64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub proxy: Option<proxy::ProxyStatus>,
+  /// Proxy used for the homeserver, `None` when connecting directly.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub homeserver_proxy: Option<String>,
+  /// Resolved addresses.
+  pub dns: Probe<Vec<String>>,
+  /// TCP connection to the homeserver port, through the proxy when there is one.
+  pub tcp: Probe<String>,
+  pub tls: Probe<TlsDetails>,
+  pub versions: Probe<VersionsInfo>,
+  /// Size in bytes of an empty-filtered `/sync` response.
+  pub sync: Probe<usize>,
+  pub media: Probe<Throughput>,
+}
+
+fn host_port(url: &reqwest::Url) -> Result<(String, u16), String> {
+  let host = url.host_str().ok_or("Homeserver URL has no host")?.to_string();
+  let port = url.port_or_known_default().unwrap_or(443);
+  Ok((host, port))
+}
+
+async fn resolve(host: String, port: u16) -> Result<Vec<String>, String> {
+  let addresses = tokio::net::lookup_host((host.as_str(), port))
+    .await
+    .map_err(|e| format!("DNS lookup failed: {}", e))?;
+  let mut addresses: Vec<String> = addresses.map(|address| address.ip().to_string()).collect();
+  addresses.sort();
+  addresses.dedup();
+  if addresses.is_empty() {
+    return Err("DNS lookup returned no addresses".into());
+  }
+  Ok(addresses)
+}
+
+async fn connect(host: String, port: u16, via: Option<String>) -> Result<String, String> {
+  tauri::async_runtime::spawn_blocking(move || proxy::connect_tcp(&host, port))
+    .await
+    .map_err(|e| e.to_string())??;
+  Ok(match via {
+    Some(proxy) => format!("via {}", proxy),
+    None => "direct".to_string(),
+  })
+}
+
+fn tls_details(der: &[u8]) -> Result<TlsDetails, String> {
+  let (_, cert) = x509_parser::parse_x509_certificate(der).map_err(|e| format!("Unreadable certificate: {}", e))?;
+  let validity = cert.validity();
+  let not_after = validity.not_after.timestamp();
+  let dns_names = cert
+    .subject_alternative_name()
+    .ok()
+    .flatten()
+    .map(|ext| {
+      ext
+        .value
+        .general_names
+        .iter()
+        .filter_map(|name| match name {
+          x509_parser::extensions::GeneralName::DNSName(dns) => Some(dns.to_string()),
+          _ => None,
+        })
+        .collect()
+    })
+    .unwrap_or_default();
+  Ok(TlsDetails {
+    subject: cert.subject().to_string(),
+    issuer: cert.issuer().to_string(),
+    not_before: validity.not_before.timestamp(),
+    not_after,
+    days_until_expiry: (not_after - now_millis() / 1000) / 86_400,
+    dns_names,
+    fingerprint: Sha256::digest(der).iter().map(|b| format!("{:02x}", b)).collect(),
+  })
+}
+
+/// `/versions` several times over one connection; the first sample includes the handshake.
+async fn versions(http: &reqwest::Client, homeserver_url: &str) -> (Probe<VersionsInfo>, Probe<TlsDetails>) {
+  let url = format!("{}/_matrix/client/versions", homeserver_url);
+  let mut samples = Vec::new();
+  let mut body = Value::Null;
+  let mut tls = Probe::skipped("Not an HTTPS homeserver");
+  for _ in 0..VERSIONS_SAMPLES {
+    let started = Instant::now();
+    let response = match tokio::time::timeout(PROBE_TIMEOUT, http.get(&url).send()).await {
+      Ok(Ok(response)) => response,
+      Ok(Err(err)) => return (Probe::skipped(&err.to_string()), tls),
+      Err(_) => return (Probe::skipped("Timed out"), tls),
+    };
+    if samples.is_empty() {
+      if let Some(info) = response.extensions().get::<reqwest::tls::TlsInfo>() {
+        tls = match info.peer_certificate() {
+          Some(der) => match tls_details(der) {
+            Ok(details) => Probe {
+              ok: details.days_until_expiry >= 0,
+              duration_ms: None,
+              error: (details.days_until_expiry < 0).then(|| "Certificate has expired".to_string()),
+              value: Some(details),
+            },
+            Err(err) => Probe::skipped(&err),
+          },
+          None => Probe::skipped("No peer certificate"),
+        };
+      }
+    }
+    let status = response.status();
+    body = response.json().await.unwrap_or(Value::Null);
+    samples.push(started.elapsed().as_millis() as u64);
+    if !status.is_success() {
+      return (Probe::skipped(&format!("HTTP {}", status.as_u16())), tls);
+    }
+  }
+  let mut sorted = samples.clone();
+  sorted.sort_unstable();
+  let versions = body
+    .get("versions")
+    .and_then(|v| v.as_array())
+    .map(|items| items.iter().filter_map(|v| v.as_str().map(|s| s.to_string())).collect())
+    .unwrap_or_default();
+  let median_ms = sorted[sorted.len() / 2];
+  (
+    Probe {
+      ok: true,
+      duration_ms: Some(median_ms),
+      value: Some(VersionsInfo {
+        versions,
+        samples_ms: samples,
+        median_ms,
+      }),
+      error: None,
+    },
+    tls,
+  )
+}
+
+async fn sync_round_trip(client: &MatrixClient) -> Result<usize, String> {
+  let filter = r#"{"room":{"rooms":[]},"presence":{"types":[]},"account_data":{"types":[]}}"#;
+  let response = client
+    .get("/sync", &[("timeout", "0".to_string()), ("filter", filter.to_string())])
+    .await
+    .map_err(|e| e.to_string())?;
+  Ok(response.to_string().len())
+}
+
+async fn media_throughput(client: &MatrixClient, mxc: &str) -> Result<Throughput, String> {
+  let started = Instant::now();
+  let mut response = client.download_response(mxc, None).await.map_err(|e| e.to_string())?;
+  let mut bytes = 0usize;
+  while let Some(chunk) = response.chunk().await.map_err(|e| e.to_string())? {
+    bytes += chunk.len();
+    if bytes >= MEDIA_SAMPLE_BYTES {
+      break;
+    }
+  }
+  let elapsed = started.elapsed().as_secs_f64().max(0.001);
+  Ok(Throughput {
+    bytes,
+    bytes_per_sec: (bytes as f64 / elapsed) as u64,
+  })
+}
+
+/// Run every probe against the account's homeserver. `sample_mxc` is a media file to time the
+/// download of; without one the media probe is skipped.
+pub async fn run(client: &MatrixClient, sample_mxc: Option<String>) -> Result<NetworkReport, String> {
+  let url = reqwest::Url::parse(&client.homeserver_url).map_err(|e| e.to_string())?;
+  let (host, port) = host_port(&url)?;
+  let homeserver_proxy = proxy::for_url(&url);
+  let dns = measure(resolve(host.clone(), port)).await;
+  let tcp = measure(connect(host, port, homeserver_proxy.clone())).await;
+  let http = proxy::apply(reqwest::Client::builder())
+    .user_agent(concat!("matrix-messenger/", env!("CARGO_PKG_VERSION")))
+    .tls_info(true)
+    .build()
+    .map_err(|e| format!("Failed to create HTTP client: {}", e))?;
+  let (versions, tls) = versions(&http, &client.homeserver_url).await;
+  let sync = measure(sync_round_trip(client)).await;
+  let media = match sample_mxc {
+    Some(mxc) => measure(media_throughput(client, &mxc)).await,
+    None => Probe::skipped("No media to download"),
+  };
+  Ok(NetworkReport {
+    homeserver_url: client.homeserver_url.clone(),
+    generated_at: now_millis(),
+    proxy: proxy::status(),
+    homeserver_proxy,
+    dns,
+    tcp,
+    tls,
+    versions,
+    sync,
+    media,
+  })
+}
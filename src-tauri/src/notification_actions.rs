@@ -0,0 +1,255 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Mutex;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::matrix_api::{encode, ApiError, MatrixClient};
+use crate::rich_notifications::NotificationMedia;
+
+/// What a notification action applies to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NotificationTarget {
+  pub account_key: String,
+  pub room_id: String,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub event_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "camelCase")]
+pub enum NotificationAction {
+  Reply { text: String },
+  MarkRead,
+  OpenRoom,
+  CallBack,
+  /// Closed by the user without acting on it.
+  Dismissed,
+}
+
+/// Decides which actions a notification offers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotificationKind {
+  Message,
+  MissedCall,
+}
+
+#[derive(Debug, Clone)]
+pub struct ActionEvent {
+  pub target: NotificationTarget,
+  pub action: NotificationAction,
+}
+
+/// Send a plain-text reply, threaded onto the notified event when known.
+pub async fn send_reply(client: &MatrixClient, room_id: &str, event_id: Option<&str>, text: &str) -> Result<String, ApiError> {
+  let mut content = json!({ "msgtype": "m.text", "body": text });
+  if let Some(event_id) = event_id {
+    content["m.relates_to"] = json!({ "m.in_reply_to": { "event_id": event_id } });
+  }
+  client.send_event(room_id, "m.room.message", &content).await
+}
+
+/// Move the read receipt and fully-read marker to `event_id`.
+pub async fn mark_read(client: &MatrixClient, room_id: &str, event_id: &str) -> Result<(), ApiError> {
+  client
+    .post(
+      &format!("/rooms/{}/read_markers", encode(room_id)),
+      &json!({ "m.fully_read": event_id, "m.read": event_id }),
+    )
+    .await?;
+  Ok(())
+}
+
+/// Notifications shown with actions, and the channel their callbacks arrive on.
+pub struct NotificationActionState {
+  #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+  events: UnboundedSender<ActionEvent>,
+  receiver: Mutex<Option<UnboundedReceiver<ActionEvent>>>,
+  #[cfg(target_os = "linux")]
+  dbus: tokio::sync::Mutex<Option<dbus::Notifier>>,
+}
+
+impl Default for NotificationActionState {
+  fn default() -> Self {
+    let (events, receiver) = unbounded_channel();
+    NotificationActionState {
+      events,
+      receiver: Mutex::new(Some(receiver)),
+      #[cfg(target_os = "linux")]
+      dbus: tokio::sync::Mutex::new(None),
+    }
+  }
+}
+
+impl NotificationActionState {
+  pub fn take_receiver(&self) -> Option<UnboundedReceiver<ActionEvent>> {
+    self.receiver.lock().ok().and_then(|mut receiver| receiver.take())
+  }
+
+  /// Show a notification with the actions for `kind` (reply, mark read and open for messages,
+  /// call back for missed calls) and the sender's avatar and attached picture, replacing
+  /// `replaces` when given. Returns its ID, or `None` when the platform has no notification
+  /// service that supports them.
+  #[cfg(target_os = "linux")]
+  pub async fn show(
+    &self,
+    kind: NotificationKind,
+    title: &str,
+    body: &str,
+    target: NotificationTarget,
+    replaces: Option<u32>,
+    media: &NotificationMedia,
+  ) -> Option<u32> {
+    let mut notifier = self.dbus.lock().await;
+    if notifier.is_none() {
+      match dbus::Notifier::connect(self.events.clone()).await {
+        Ok(connected) => *notifier = Some(connected),
+        Err(err) => {
+          tracing::warn!("Actionable notifications unavailable: {}", err);
+          return None;
+        }
+      }
+    }
+    match notifier.as_ref() {
+      Some(notifier) => match notifier.notify(kind, title, body, target, replaces, media).await {
+        Ok(id) => Some(id),
+        Err(err) => {
+          tracing::warn!("Failed to show notification: {}", err);
+          None
+        }
+      },
+      None => None,
+    }
+  }
+
+  #[cfg(not(target_os = "linux"))]
+  pub async fn show(
+    &self,
+    _kind: NotificationKind,
+    _title: &str,
+    _body: &str,
+    _target: NotificationTarget,
+    _replaces: Option<u32>,
+    _media: &NotificationMedia,
+  ) -> Option<u32> {
+    None
+  }
+}
+
+#[cfg(target_os = "linux")]
+mod dbus {
+  use super::{ActionEvent, NotificationAction, NotificationKind, NotificationMedia, NotificationTarget};
+  use futures_util::StreamExt;
+  use std::collections::HashMap;
+  use std::sync::{Arc, Mutex};
+  use tokio::sync::mpsc::UnboundedSender;
+  use zbus::zvariant::Value;
+
+  const MAX_PENDING: usize = 200;
+  const MESSAGE_ACTIONS: &[&str] = &["default", "Open", "open", "Open", "read", "Mark as read", "inline-reply", "Reply"];
+  const CALL_ACTIONS: &[&str] = &["default", "Open", "call-back", "Call back"];
+
+  /// org.freedesktop.Notifications client; `inline-reply` is understood by KDE and others.
+  pub struct Notifier {
+    proxy: zbus::Proxy<'static>,
+    pending: Arc<Mutex<HashMap<u32, NotificationTarget>>>,
+  }
+
+  impl Notifier {
+    pub async fn connect(events: UnboundedSender<ActionEvent>) -> Result<Self, String> {
+      let connection = zbus::Connection::session().await.map_err(|e| format!("D-Bus session unavailable: {}", e))?;
+      let proxy = zbus::Proxy::new(
+        &connection,
+        "org.freedesktop.Notifications",
+        "/org/freedesktop/Notifications",
+        "org.freedesktop.Notifications",
+      )
+      .await
+      .map_err(|e| e.to_string())?;
+      let mut invoked = proxy.receive_signal("ActionInvoked").await.map_err(|e| e.to_string())?;
+      let mut replied = proxy.receive_signal("NotificationReplied").await.map_err(|e| e.to_string())?;
+      let mut closed = proxy.receive_signal("NotificationClosed").await.map_err(|e| e.to_string())?;
+      let pending: Arc<Mutex<HashMap<u32, NotificationTarget>>> = Arc::default();
+      let targets = pending.clone();
+      tauri::async_runtime::spawn(async move {
+        loop {
+          let (id, action) = tokio::select! {
+            Some(message) = invoked.next() => {
+              let Ok((id, key)) = message.body().deserialize::<(u32, String)>() else { continue };
+              let action = match key.as_str() {
+                "read" => NotificationAction::MarkRead,
+                "default" | "open" => NotificationAction::OpenRoom,
+                "call-back" => NotificationAction::CallBack,
+                _ => continue,
+              };
+              (id, action)
+            }
+            Some(message) = replied.next() => {
+              let Ok((id, text)) = message.body().deserialize::<(u32, String)>() else { continue };
+              (id, NotificationAction::Reply { text })
+            }
+            Some(message) = closed.next() => {
+              let Ok((id, reason)) = message.body().deserialize::<(u32, u32)>() else { continue };
+              // Reason 2 means the user dismissed it; anything else just frees the entry.
+              if reason != 2 {
+                if let Ok(mut targets) = targets.lock() {
+                  targets.remove(&id);
+                }
+                continue;
+              }
+              (id, NotificationAction::Dismissed)
+            }
+            else => break,
+          };
+          let target = targets.lock().ok().and_then(|mut targets| targets.remove(&id));
+          if let Some(target) = target {
+            let _ = events.send(ActionEvent { target, action });
+          }
+        }
+      });
+      Ok(Notifier { proxy, pending })
+    }
+
+    pub async fn notify(
+      &self,
+      kind: NotificationKind,
+      title: &str,
+      body: &str,
+      target: NotificationTarget,
+      replaces: Option<u32>,
+      media: &NotificationMedia,
+    ) -> Result<u32, String> {
+      let (actions, category) = match kind {
+        NotificationKind::Message => (MESSAGE_ACTIONS, "im.received"),
+        NotificationKind::MissedCall => (CALL_ACTIONS, "call.unanswered"),
+      };
+      let mut hints: HashMap<&str, Value> = HashMap::new();
+      hints.insert("category", Value::from(category));
+      hints.insert("x-kde-reply-placeholder-text", Value::from("Reply…"));
+      let uri = |path: &std::path::Path| format!("file://{}", path.display());
+      // `image-path` wins over the icon where both are shown, so it carries the attachment
+      // when there is one and the avatar otherwise.
+      if let Some(image) = media.icon() {
+        hints.insert("image-path", Value::from(uri(image)));
+      }
+      let app_icon = media.avatar.as_deref().map(uri).unwrap_or_default();
+      let id: u32 = self
+        .proxy
+        .call(
+          "Notify",
+          &("Matrix Messenger", replaces.unwrap_or(0), app_icon.as_str(), title, body, actions, hints, -1i32),
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+      if let Ok(mut pending) = self.pending.lock() {
+        if pending.len() >= MAX_PENDING {
+          if let Some(oldest) = pending.keys().min().copied() {
+            pending.remove(&oldest);
+          }
+        }
+        pending.insert(id, target);
+      }
+      Ok(id)
+    }
+  }
+}
@@ -0,0 +1,159 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::unified_push::PushNotification;
+
+/// Minimum gap between two toasts for the same room; messages in between are folded in.
+const ROOM_INTERVAL: Duration = Duration::from_secs(10);
+/// A room's count starts over once it has been quiet this long.
+const GROUP_RESET: Duration = Duration::from_secs(5 * 60);
+const BURST_WINDOW: Duration = Duration::from_secs(60);
+const MAX_PER_BURST: usize = 8;
+
+/// What to show: the newest notification of a room and how many it stands for.
+#[derive(Debug, Clone)]
+pub struct GroupedNotification {
+  pub latest: PushNotification,
+  pub count: u32,
+  /// Desktop notification to replace, when the platform supports it.
+  pub replaces: Option<u32>,
+}
+
+impl GroupedNotification {
+  pub fn title(&self) -> String {
+    let sender = self.sender();
+    self
+      .latest
+      .room_name
+      .clone()
+      .or(sender)
+      .unwrap_or_else(|| "New message".to_string())
+  }
+
+  pub fn body(&self) -> String {
+    let sender = self.sender();
+    let latest = match (&self.latest.body, &sender, &self.latest.room_name) {
+      (Some(body), Some(sender), Some(_)) => format!("{}: {}", sender, body),
+      (Some(body), _, _) => body.clone(),
+      (None, _, _) => "You have a new message".to_string(),
+    };
+    if self.count > 1 {
+      format!("{} new messages\n{}", self.count, latest)
+    } else {
+      latest
+    }
+  }
+
+  fn sender(&self) -> Option<String> {
+    self.latest.sender_display_name.clone().or_else(|| self.latest.sender.clone())
+  }
+}
+
+#[derive(Default)]
+struct Group {
+  latest: Option<PushNotification>,
+  count: u32,
+  pending: bool,
+  last_shown: Option<Instant>,
+  last_event: Option<Instant>,
+  displayed: Option<u32>,
+}
+
+/// Folds notifications per room and caps how many toasts a burst can produce.
+#[derive(Default)]
+pub struct NotificationDispatcher {
+  groups: Mutex<HashMap<(String, String), Group>>,
+  recent: Mutex<VecDeque<Instant>>,
+}
+
+impl NotificationDispatcher {
+  fn take_slot(&self, now: Instant) -> bool {
+    let Ok(mut recent) = self.recent.lock() else {
+      return true;
+    };
+    while recent.front().is_some_and(|at| now.duration_since(*at) > BURST_WINDOW) {
+      recent.pop_front();
+    }
+    if recent.len() >= MAX_PER_BURST {
+      return false;
+    }
+    recent.push_back(now);
+    true
+  }
+
+  /// Add a notification to its room's group. Returns what to show now, or `None` when it was
+  /// folded into a summary that `due` will release later.
+  pub fn offer(&self, account_key: &str, notification: &PushNotification) -> Option<GroupedNotification> {
+    let now = Instant::now();
+    let room_id = notification.room_id.clone().unwrap_or_default();
+    let mut groups = self.groups.lock().ok()?;
+    let group = groups.entry((account_key.to_string(), room_id)).or_default();
+    if group.last_event.is_some_and(|at| now.duration_since(at) > GROUP_RESET) {
+      group.count = 0;
+    }
+    group.latest = Some(notification.clone());
+    group.count += 1;
+    group.last_event = Some(now);
+    let recently_shown = group.last_shown.is_some_and(|at| now.duration_since(at) < ROOM_INTERVAL);
+    if recently_shown || !self.take_slot(now) {
+      group.pending = true;
+      return None;
+    }
+    group.pending = false;
+    group.last_shown = Some(now);
+    Some(GroupedNotification {
+      latest: notification.clone(),
+      count: group.count,
+      replaces: group.displayed,
+    })
+  }
+
+  /// Summaries for rooms whose interval has passed with messages still folded in.
+  pub fn due(&self) -> Vec<(String, GroupedNotification)> {
+    let now = Instant::now();
+    let Ok(mut groups) = self.groups.lock() else {
+      return Vec::new();
+    };
+    let mut out = Vec::new();
+    for ((account_key, _), group) in groups.iter_mut() {
+      if !group.pending || group.last_shown.is_some_and(|at| now.duration_since(at) < ROOM_INTERVAL) {
+        continue;
+      }
+      let Some(latest) = group.latest.clone() else {
+        continue;
+      };
+      if !self.take_slot(now) {
+        break;
+      }
+      group.pending = false;
+      group.last_shown = Some(now);
+      out.push((
+        account_key.clone(),
+        GroupedNotification {
+          latest,
+          count: group.count,
+          replaces: group.displayed,
+        },
+      ));
+    }
+    groups.retain(|_, group| group.pending || group.last_event.is_some_and(|at| now.duration_since(at) < GROUP_RESET));
+    out
+  }
+
+  /// Remember the desktop notification now showing a room's group.
+  pub fn displayed(&self, account_key: &str, room_id: &str, id: u32) {
+    if let Ok(mut groups) = self.groups.lock() {
+      if let Some(group) = groups.get_mut(&(account_key.to_string(), room_id.to_string())) {
+        group.displayed = Some(id);
+      }
+    }
+  }
+
+  /// Forget a room's group once it has been read.
+  pub fn clear(&self, account_key: &str, room_id: &str) {
+    if let Ok(mut groups) = self.groups.lock() {
+      groups.remove(&(account_key.to_string(), room_id.to_string()));
+    }
+  }
+}
@@ -0,0 +1,208 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::matrix_api::now_millis;
+use crate::unified_push::PushNotification;
+
+const MAX_ENTRIES: i64 = 2_000;
+const RETENTION_MS: i64 = 30 * 24 * 60 * 60 * 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum NotificationOutcome {
+  Shown,
+  /// Folded into a room's grouped notification.
+  Grouped,
+  /// Held for the end of quiet hours.
+  Held,
+  Suppressed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum NotificationInteraction {
+  Clicked,
+  Replied,
+  MarkedRead,
+  Dismissed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NotificationRecord {
+  pub id: i64,
+  pub account_key: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub room_id: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub event_id: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub title: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub body: Option<String>,
+  pub outcome: NotificationOutcome,
+  /// Why a notification was not shown, e.g. `snoozed` or `roomRule`.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub reason: Option<String>,
+  pub created_at: i64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub interaction: Option<NotificationInteraction>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub interacted_at: Option<i64>,
+}
+
+pub fn init_notification_history_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS notification_history (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        account_key TEXT NOT NULL,
+        room_id TEXT,
+        event_id TEXT,
+        title TEXT,
+        body TEXT,
+        outcome TEXT NOT NULL,
+        reason TEXT,
+        created_at INTEGER NOT NULL,
+        interaction TEXT,
+        interacted_at INTEGER
+      );
+      CREATE INDEX IF NOT EXISTS idx_notification_history_created ON notification_history(created_at);
+      CREATE INDEX IF NOT EXISTS idx_notification_history_room ON notification_history(room_id, event_id);
+    ",
+  )
+}
+
+fn to_text<T: Serialize>(value: T) -> String {
+  serde_json::to_value(value)
+    .ok()
+    .and_then(|v| v.as_str().map(|s| s.to_string()))
+    .unwrap_or_default()
+}
+
+fn from_text<T: for<'de> Deserialize<'de>>(value: Option<String>) -> Option<T> {
+  value.and_then(|v| serde_json::from_value(serde_json::Value::String(v)).ok())
+}
+
+/// Log what happened to a notification and drop entries past the retention limits.
+pub fn record(
+  conn: &Connection,
+  account_key: &str,
+  notification: &PushNotification,
+  title: Option<&str>,
+  body: Option<&str>,
+  outcome: NotificationOutcome,
+  reason: Option<&str>,
+) -> Result<(), String> {
+  let now = now_millis();
+  conn
+    .execute(
+      "INSERT INTO notification_history (account_key, room_id, event_id, title, body, outcome, reason, created_at)
+       VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+      params![
+        account_key,
+        notification.room_id,
+        notification.event_id,
+        title,
+        body.or(notification.body.as_deref()),
+        to_text(outcome),
+        reason,
+        now
+      ],
+    )
+    .map_err(|e| e.to_string())?;
+  conn
+    .execute(
+      "DELETE FROM notification_history
+       WHERE created_at < ?1 OR id <= (SELECT MAX(id) FROM notification_history) - ?2",
+      params![now - RETENTION_MS, MAX_ENTRIES],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+/// Note an interaction on the newest shown notification for this room and event.
+pub fn record_interaction(
+  conn: &Connection,
+  account_key: &str,
+  room_id: &str,
+  event_id: Option<&str>,
+  interaction: NotificationInteraction,
+) -> Result<(), String> {
+  conn
+    .execute(
+      "UPDATE notification_history SET interaction = ?4, interacted_at = ?5
+       WHERE id = (
+         SELECT id FROM notification_history
+         WHERE account_key = ?1 AND room_id = ?2 AND (?3 IS NULL OR event_id = ?3) AND outcome = 'shown'
+         ORDER BY id DESC LIMIT 1
+       )",
+      params![account_key, room_id, event_id, to_text(interaction), now_millis()],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+const RECORD_COLUMNS: &str =
+  "id, account_key, room_id, event_id, title, body, outcome, reason, created_at, interaction, interacted_at";
+
+fn row_to_record(row: &rusqlite::Row) -> rusqlite::Result<NotificationRecord> {
+  Ok(NotificationRecord {
+    id: row.get(0)?,
+    account_key: row.get(1)?,
+    room_id: row.get(2)?,
+    event_id: row.get(3)?,
+    title: row.get(4)?,
+    body: row.get(5)?,
+    outcome: from_text(row.get(6)?).unwrap_or(NotificationOutcome::Shown),
+    reason: row.get(7)?,
+    created_at: row.get(8)?,
+    interaction: from_text(row.get(9)?),
+    interacted_at: row.get(10)?,
+  })
+}
+
+/// Newest first, optionally limited to one account or room and to entries after `since`.
+pub fn history(
+  conn: &Connection,
+  account_key: Option<&str>,
+  room_id: Option<&str>,
+  since: Option<i64>,
+  limit: usize,
+) -> Result<Vec<NotificationRecord>, String> {
+  let mut stmt = conn
+    .prepare(&format!(
+      "SELECT {} FROM notification_history
+       WHERE (?1 IS NULL OR account_key = ?1) AND (?2 IS NULL OR room_id = ?2) AND created_at >= ?3
+       ORDER BY id DESC LIMIT ?4",
+      RECORD_COLUMNS
+    ))
+    .map_err(|e| e.to_string())?;
+  let rows = stmt
+    .query_map(params![account_key, room_id, since.unwrap_or(0), limit as i64], row_to_record)
+    .map_err(|e| e.to_string())?;
+  let mut out = Vec::new();
+  for row in rows {
+    out.push(row.map_err(|e| e.to_string())?);
+  }
+  Ok(out)
+}
+
+pub fn clear(conn: &Connection) -> Result<(), String> {
+  conn.execute("DELETE FROM notification_history", []).map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+/// The most recent notification that was actually shown.
+pub fn latest_shown(conn: &Connection) -> Result<Option<NotificationRecord>, String> {
+  conn
+    .query_row(
+      &format!(
+        "SELECT {} FROM notification_history WHERE outcome = 'shown' ORDER BY id DESC LIMIT 1",
+        RECORD_COLUMNS
+      ),
+      [],
+      row_to_record,
+    )
+    .optional()
+    .map_err(|e| e.to_string())
+}
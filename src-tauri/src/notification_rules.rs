@@ -0,0 +1,148 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::event_cache;
+use crate::keyword_alerts;
+use crate::unified_push::PushNotification;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum RoomNotifyMode {
+  #[default]
+  All,
+  MentionsOnly,
+  Mute,
+}
+
+/// Local, per-room filter applied on top of the server's push rules.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NotificationRule {
+  #[serde(default)]
+  pub mode: RoomNotifyMode,
+  /// Case-insensitive words that notify in a mentions-only room.
+  #[serde(default)]
+  pub keywords: Vec<String>,
+  /// Senders that always notify, even when the room is muted.
+  #[serde(default)]
+  pub vip_senders: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomNotificationRule {
+  pub room_id: String,
+  pub rule: NotificationRule,
+}
+
+pub fn init_notification_rules_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS notification_rules (
+        room_id TEXT PRIMARY KEY,
+        rule_json TEXT NOT NULL
+      );
+    ",
+  )
+}
+
+pub fn get_rule(conn: &Connection, room_id: &str) -> Result<NotificationRule, String> {
+  let stored: Option<String> = conn
+    .query_row("SELECT rule_json FROM notification_rules WHERE room_id = ?1", [room_id], |row| row.get(0))
+    .optional()
+    .map_err(|e| e.to_string())?;
+  Ok(stored.and_then(|json| serde_json::from_str(&json).ok()).unwrap_or_default())
+}
+
+/// Store the rule for a room, or remove it when `rule` is `None`.
+pub fn set_rule(conn: &Connection, room_id: &str, rule: Option<&NotificationRule>) -> Result<(), String> {
+  match rule {
+    Some(rule) => {
+      let json = serde_json::to_string(rule).map_err(|e| e.to_string())?;
+      conn
+        .execute(
+          "INSERT INTO notification_rules (room_id, rule_json) VALUES (?1, ?2)
+           ON CONFLICT(room_id) DO UPDATE SET rule_json = excluded.rule_json",
+          params![room_id, json],
+        )
+        .map_err(|e| e.to_string())?;
+    }
+    None => {
+      conn
+        .execute("DELETE FROM notification_rules WHERE room_id = ?1", [room_id])
+        .map_err(|e| e.to_string())?;
+    }
+  }
+  Ok(())
+}
+
+pub fn list_rules(conn: &Connection) -> Result<Vec<RoomNotificationRule>, String> {
+  let mut stmt = conn
+    .prepare("SELECT room_id, rule_json FROM notification_rules ORDER BY room_id")
+    .map_err(|e| e.to_string())?;
+  let rows = stmt
+    .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))
+    .map_err(|e| e.to_string())?;
+  let mut out = Vec::new();
+  for row in rows {
+    let (room_id, json) = row.map_err(|e| e.to_string())?;
+    if let Ok(rule) = serde_json::from_str(&json) {
+      out.push(RoomNotificationRule { room_id, rule });
+    }
+  }
+  Ok(out)
+}
+
+fn contains_word(haystack: &str, needle: &str) -> bool {
+  let needle = needle.trim().to_lowercase();
+  if needle.is_empty() {
+    return false;
+  }
+  haystack.match_indices(&needle).any(|(start, _)| {
+    let before = haystack[..start].chars().next_back();
+    let after = haystack[start + needle.len()..].chars().next();
+    !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(char::is_alphanumeric)
+  })
+}
+
+/// Whether `notification` passes the rule for its room.
+pub fn should_notify(
+  rule: &NotificationRule,
+  notification: &PushNotification,
+  own_user_id: &str,
+  own_display_name: Option<&str>,
+) -> bool {
+  if let Some(sender) = &notification.sender {
+    if rule.vip_senders.iter().any(|vip| vip == sender) {
+      return true;
+    }
+  }
+  match rule.mode {
+    RoomNotifyMode::All => true,
+    RoomNotifyMode::Mute => false,
+    RoomNotifyMode::MentionsOnly => {
+      let Some(body) = notification.body.as_deref() else {
+        return false;
+      };
+      let body = body.to_lowercase();
+      contains_word(&body, own_user_id)
+        || own_display_name.is_some_and(|name| contains_word(&body, name))
+        || rule.keywords.iter().any(|keyword| contains_word(&body, keyword))
+    }
+  }
+}
+
+/// Look up the room's rule and the account's display name there, then apply `should_notify`.
+/// Keyword highlights always notify.
+pub fn evaluate(conn: &Connection, account_key: &str, notification: &PushNotification, own_user_id: &str) -> Result<bool, String> {
+  let Some(room_id) = notification.room_id.as_deref() else {
+    return Ok(true);
+  };
+  if let Some(event_id) = notification.event_id.as_deref() {
+    if keyword_alerts::is_highlighted(conn, room_id, event_id)? {
+      return Ok(true);
+    }
+  }
+  let rule = get_rule(conn, room_id)?;
+  let (display_name, _) = event_cache::member_profile(conn, account_key, room_id, own_user_id)?;
+  Ok(should_notify(&rule, notification, own_user_id, display_name.as_deref()))
+}
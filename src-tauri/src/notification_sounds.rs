@@ -0,0 +1,362 @@
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, SampleFormat, SizedSample};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::f32::consts::PI;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::audio_waveform;
+use crate::matrix_api::now_millis;
+use crate::voice;
+
+const SAMPLE_RATE: u32 = 48_000;
+const MAX_IMPORT_SIZE: u64 = 2 * 1024 * 1024;
+const MAX_DURATION_MS: u64 = 10_000;
+/// Use the platform's own notification sound.
+pub const DEFAULT_SOUND: &str = "default";
+pub const SILENT_SOUND: &str = "silent";
+const BUILTIN_SOUNDS: &[(&str, &str)] = &[("chime", "Chime"), ("ping", "Ping"), ("pop", "Pop"), ("bell", "Bell")];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NotificationSound {
+  pub id: String,
+  pub name: String,
+  pub builtin: bool,
+  pub duration_ms: u64,
+}
+
+/// Where a sound is assigned; rooms override accounts, which override the global choice.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum SoundScope {
+  Global,
+  #[serde(rename_all = "camelCase")]
+  Account { account_key: String },
+  #[serde(rename_all = "camelCase")]
+  Room { room_id: String },
+}
+
+impl SoundScope {
+  fn key(&self) -> String {
+    match self {
+      SoundScope::Global => String::new(),
+      SoundScope::Account { account_key } => format!("account:{}", account_key),
+      SoundScope::Room { room_id } => format!("room:{}", room_id),
+    }
+  }
+}
+
+pub fn init_notification_sounds_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS notification_sounds (
+        id TEXT PRIMARY KEY,
+        name TEXT NOT NULL,
+        duration_ms INTEGER NOT NULL,
+        created_at INTEGER NOT NULL
+      );
+      CREATE TABLE IF NOT EXISTS notification_sound_assignments (
+        scope TEXT PRIMARY KEY,
+        sound_id TEXT NOT NULL
+      );
+    ",
+  )
+}
+
+fn tone(samples: &mut Vec<f32>, frequency: f32, millis: u32, decay: f32, overtone: Option<(f32, f32)>) {
+  let count = SAMPLE_RATE * millis / 1000;
+  for i in 0..count {
+    let t = i as f32 / SAMPLE_RATE as f32;
+    let attack = (i as f32 / (SAMPLE_RATE as f32 * 0.005)).min(1.0);
+    let mut value = (2.0 * PI * frequency * t).sin();
+    if let Some((ratio, level)) = overtone {
+      value += level * (2.0 * PI * frequency * ratio * t).sin();
+    }
+    samples.push(0.4 * attack * (-decay * t).exp() * value);
+  }
+}
+
+fn builtin(id: &str) -> Option<Vec<f32>> {
+  let mut samples = Vec::new();
+  match id {
+    "chime" => {
+      tone(&mut samples, 880.0, 140, 12.0, None);
+      tone(&mut samples, 1318.5, 260, 9.0, None);
+    }
+    "ping" => tone(&mut samples, 1760.0, 250, 14.0, Some((2.0, 0.2))),
+    "pop" => {
+      let count = SAMPLE_RATE * 80 / 1000;
+      let mut phase = 0f32;
+      for i in 0..count {
+        let progress = i as f32 / count as f32;
+        phase += 2.0 * PI * (420.0 - 220.0 * progress) / SAMPLE_RATE as f32;
+        samples.push(0.5 * (1.0 - progress) * phase.sin());
+      }
+    }
+    "bell" => tone(&mut samples, 660.0, 700, 5.0, Some((2.76, 0.35))),
+    _ => return None,
+  }
+  Some(samples)
+}
+
+fn duration_ms(samples: &[f32]) -> u64 {
+  samples.len() as u64 * 1000 / SAMPLE_RATE as u64
+}
+
+/// Built-in sounds followed by imported ones.
+pub fn list(conn: &Connection) -> Result<Vec<NotificationSound>, String> {
+  let mut out: Vec<NotificationSound> = BUILTIN_SOUNDS
+    .iter()
+    .map(|(id, name)| NotificationSound {
+      id: id.to_string(),
+      name: name.to_string(),
+      builtin: true,
+      duration_ms: builtin(id).map(|s| duration_ms(&s)).unwrap_or_default(),
+    })
+    .collect();
+  let mut stmt = conn
+    .prepare("SELECT id, name, duration_ms FROM notification_sounds ORDER BY created_at")
+    .map_err(|e| e.to_string())?;
+  let rows = stmt
+    .query_map([], |row| {
+      Ok(NotificationSound {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        builtin: false,
+        duration_ms: row.get::<_, i64>(2)? as u64,
+      })
+    })
+    .map_err(|e| e.to_string())?;
+  for row in rows {
+    out.push(row.map_err(|e| e.to_string())?);
+  }
+  Ok(out)
+}
+
+/// Mono samples from a 16-bit PCM or 32-bit float WAV file.
+fn decode_wav(bytes: &[u8]) -> Option<(Vec<f32>, u32)> {
+  if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+    return None;
+  }
+  let mut offset = 12;
+  let mut format = None;
+  while offset + 8 <= bytes.len() {
+    let id = &bytes[offset..offset + 4];
+    let len = u32::from_le_bytes(bytes[offset + 4..offset + 8].try_into().ok()?) as usize;
+    let body = bytes.get(offset + 8..offset + 8 + len)?;
+    if id == b"fmt " && body.len() >= 16 {
+      let tag = u16::from_le_bytes([body[0], body[1]]);
+      let channels = u16::from_le_bytes([body[2], body[3]]).max(1) as usize;
+      let rate = u32::from_le_bytes(body[4..8].try_into().ok()?);
+      let bits = u16::from_le_bytes([body[14], body[15]]);
+      format = Some((tag, channels, rate, bits));
+    } else if id == b"data" {
+      let (tag, channels, rate, bits) = format?;
+      let frames: Vec<f32> = match (tag, bits) {
+        (1, 16) => body.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0).collect(),
+        (3, 32) => body.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect(),
+        _ => return None,
+      };
+      let mono = frames.chunks(channels).map(|f| f.iter().sum::<f32>() / f.len() as f32).collect();
+      return Some((mono, rate));
+    }
+    offset += 8 + len + (len & 1);
+  }
+  None
+}
+
+fn resample(samples: &[f32], from_rate: u32, to_rate: u32) -> Vec<f32> {
+  if from_rate == to_rate || samples.is_empty() {
+    return samples.to_vec();
+  }
+  let ratio = from_rate as f64 / to_rate as f64;
+  let len = (samples.len() as f64 / ratio) as usize;
+  (0..len)
+    .map(|i| {
+      let pos = i as f64 * ratio;
+      let index = pos as usize;
+      let frac = (pos - index as f64) as f32;
+      let a = samples[index.min(samples.len() - 1)];
+      let b = samples[(index + 1).min(samples.len() - 1)];
+      a + (b - a) * frac
+    })
+    .collect()
+}
+
+/// Validate and decode an audio file, then cache it as raw samples under `sounds_dir`.
+pub fn import(conn: &Connection, sounds_dir: &Path, source: &Path, name: &str) -> Result<NotificationSound, String> {
+  let size = fs::metadata(source).map_err(|e| e.to_string())?.len();
+  if size > MAX_IMPORT_SIZE {
+    return Err(format!("Sound files are limited to {} KiB", MAX_IMPORT_SIZE / 1024));
+  }
+  let bytes = fs::read(source).map_err(|e| e.to_string())?;
+  let samples = match voice::decode_ogg_opus(&bytes) {
+    Ok(samples) => samples,
+    Err(_) => match decode_wav(&bytes) {
+      Some((samples, rate)) => resample(&samples, rate, SAMPLE_RATE),
+      None => audio_waveform::decode_with_ffmpeg(&bytes, sounds_dir, SAMPLE_RATE)?.0,
+    },
+  };
+  if samples.is_empty() {
+    return Err("The sound file is empty".to_string());
+  }
+  let duration = duration_ms(&samples);
+  if duration > MAX_DURATION_MS {
+    return Err(format!("Notification sounds can be at most {} seconds long", MAX_DURATION_MS / 1000));
+  }
+  let digest = Sha256::digest(&bytes);
+  let id = format!("custom-{}", digest[..8].iter().map(|b| format!("{:02x}", b)).collect::<String>());
+  fs::create_dir_all(sounds_dir).map_err(|e| e.to_string())?;
+  let pcm: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+  fs::write(sounds_dir.join(format!("{}.pcm", id)), pcm).map_err(|e| e.to_string())?;
+  let name = if name.trim().is_empty() { "Custom sound" } else { name.trim() };
+  conn
+    .execute(
+      "INSERT INTO notification_sounds (id, name, duration_ms, created_at) VALUES (?1, ?2, ?3, ?4)
+       ON CONFLICT(id) DO UPDATE SET name = excluded.name",
+      params![id, name, duration as i64, now_millis()],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(NotificationSound {
+    id,
+    name: name.to_string(),
+    builtin: false,
+    duration_ms: duration,
+  })
+}
+
+/// Delete an imported sound; scopes that used it fall back to the next level.
+pub fn remove(conn: &Connection, sounds_dir: &Path, id: &str) -> Result<(), String> {
+  conn
+    .execute("DELETE FROM notification_sounds WHERE id = ?1", [id])
+    .map_err(|e| e.to_string())?;
+  conn
+    .execute("DELETE FROM notification_sound_assignments WHERE sound_id = ?1", [id])
+    .map_err(|e| e.to_string())?;
+  let _ = fs::remove_file(sounds_dir.join(format!("{}.pcm", id)));
+  Ok(())
+}
+
+/// Assign a sound to `scope`, or clear the assignment with `None`.
+pub fn assign(conn: &Connection, scope: &SoundScope, sound_id: Option<&str>) -> Result<(), String> {
+  match sound_id {
+    Some(sound_id) => {
+      let known = sound_id == DEFAULT_SOUND
+        || sound_id == SILENT_SOUND
+        || builtin(sound_id).is_some()
+        || conn
+          .query_row("SELECT 1 FROM notification_sounds WHERE id = ?1", [sound_id], |_| Ok(()))
+          .optional()
+          .map_err(|e| e.to_string())?
+          .is_some();
+      if !known {
+        return Err(format!("Unknown sound: {}", sound_id));
+      }
+      conn
+        .execute(
+          "INSERT INTO notification_sound_assignments (scope, sound_id) VALUES (?1, ?2)
+           ON CONFLICT(scope) DO UPDATE SET sound_id = excluded.sound_id",
+          params![scope.key(), sound_id],
+        )
+        .map_err(|e| e.to_string())?;
+    }
+    None => {
+      conn
+        .execute("DELETE FROM notification_sound_assignments WHERE scope = ?1", [scope.key()])
+        .map_err(|e| e.to_string())?;
+    }
+  }
+  Ok(())
+}
+
+pub fn assigned(conn: &Connection, scope: &SoundScope) -> Result<Option<String>, String> {
+  conn
+    .query_row(
+      "SELECT sound_id FROM notification_sound_assignments WHERE scope = ?1",
+      [scope.key()],
+      |row| row.get(0),
+    )
+    .optional()
+    .map_err(|e| e.to_string())
+}
+
+/// The sound for a notification: room, then account, then global, then the platform default.
+pub fn resolve(conn: &Connection, account_key: &str, room_id: Option<&str>) -> Result<String, String> {
+  let mut scopes = Vec::new();
+  if let Some(room_id) = room_id {
+    scopes.push(SoundScope::Room { room_id: room_id.to_string() });
+  }
+  scopes.push(SoundScope::Account { account_key: account_key.to_string() });
+  scopes.push(SoundScope::Global);
+  for scope in scopes {
+    if let Some(sound_id) = assigned(conn, &scope)? {
+      return Ok(sound_id);
+    }
+  }
+  Ok(DEFAULT_SOUND.to_string())
+}
+
+pub fn load(sounds_dir: &Path, id: &str) -> Result<Vec<f32>, String> {
+  if let Some(samples) = builtin(id) {
+    return Ok(samples);
+  }
+  let bytes = fs::read(sounds_dir.join(format!("{}.pcm", id))).map_err(|e| e.to_string())?;
+  Ok(bytes.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect())
+}
+
+fn output<T>(device: &cpal::Device, config: &cpal::StreamConfig, samples: Vec<f32>) -> Result<cpal::Stream, String>
+where
+  T: SizedSample + FromSample<f32>,
+{
+  let channels = config.channels.max(1) as usize;
+  let mut position = 0;
+  device
+    .build_output_stream(
+      config,
+      move |data: &mut [T], _| {
+        for frame in data.chunks_mut(channels) {
+          let value = samples.get(position).copied().unwrap_or(0.0);
+          position += 1;
+          for sample in frame.iter_mut() {
+            *sample = T::from_sample(value);
+          }
+        }
+      },
+      |err| tracing::warn!("Notification sound error: {}", err),
+      None,
+    )
+    .map_err(|e| format!("Failed to open audio output: {}", e))
+}
+
+/// Play samples on the default output device from a background thread, since cpal streams
+/// are not `Send`.
+pub fn play(samples: Vec<f32>) {
+  std::thread::spawn(move || {
+    let result = (|| -> Result<(), String> {
+      let device = cpal::default_host()
+        .default_output_device()
+        .ok_or("No audio output is available")?;
+      let supported = device
+        .default_output_config()
+        .map_err(|e| format!("Failed to query audio output: {}", e))?;
+      let config: cpal::StreamConfig = supported.config();
+      let samples = resample(&samples, SAMPLE_RATE, config.sample_rate.0);
+      let length = Duration::from_millis(samples.len() as u64 * 1000 / config.sample_rate.0 as u64);
+      let stream = match supported.sample_format() {
+        SampleFormat::F32 => output::<f32>(&device, &config, samples)?,
+        SampleFormat::I16 => output::<i16>(&device, &config, samples)?,
+        SampleFormat::U16 => output::<u16>(&device, &config, samples)?,
+        other => return Err(format!("Unsupported audio output format: {:?}", other)),
+      };
+      stream.play().map_err(|e| e.to_string())?;
+      std::thread::sleep(length + Duration::from_millis(150));
+      Ok(())
+    })();
+    if let Err(err) = result {
+      tracing::warn!("Failed to play notification sound: {}", err);
+    }
+  });
+}
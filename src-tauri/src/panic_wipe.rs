@@ -0,0 +1,72 @@
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Typed by the user to confirm `secure_wipe_all_data`; the global hotkey skips it.
+pub const CONFIRMATION_PHRASE: &str = "WIPE ALL DATA";
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WipeReport {
+  /// Accounts whose session the homeserver ended.
+  pub logged_out: Vec<String>,
+  /// Accounts that could not be logged out (offline, token already revoked, ...).
+  pub logout_failed: Vec<String>,
+  pub files_removed: usize,
+  /// Paths that could not be overwritten or deleted, e.g. files held open by the OS.
+  pub errors: Vec<String>,
+}
+
+pub fn confirmed(phrase: &str) -> bool {
+  phrase.trim() == CONFIRMATION_PHRASE
+}
+
+/// Overwrite a file with random bytes before unlinking it. Best effort: copy-on-write
+/// filesystems and SSD wear levelling may keep old blocks around.
+pub fn shred_file(path: &Path) -> Result<(), String> {
+  let len = fs::metadata(path).map_err(|e| e.to_string())?.len();
+  let mut file = OpenOptions::new().write(true).open(path).map_err(|e| e.to_string())?;
+  file.seek(SeekFrom::Start(0)).map_err(|e| e.to_string())?;
+  let mut chunk = vec![0u8; CHUNK_SIZE];
+  let mut remaining = len;
+  while remaining > 0 {
+    let n = remaining.min(CHUNK_SIZE as u64) as usize;
+    OsRng.fill_bytes(&mut chunk[..n]);
+    file.write_all(&chunk[..n]).map_err(|e| e.to_string())?;
+    remaining -= n as u64;
+  }
+  file.sync_all().map_err(|e| e.to_string())?;
+  drop(file);
+  fs::remove_file(path).map_err(|e| e.to_string())
+}
+
+/// Shred every file under `path` (or `path` itself), then remove the directories.
+pub fn shred_path(path: &Path, report: &mut WipeReport) {
+  let Ok(meta) = fs::symlink_metadata(path) else {
+    return;
+  };
+  if meta.is_dir() {
+    if let Ok(entries) = fs::read_dir(path) {
+      for entry in entries.flatten() {
+        shred_path(&entry.path(), report);
+      }
+    }
+    if let Err(err) = fs::remove_dir(path) {
+      report.errors.push(format!("{}: {}", path.display(), err));
+    }
+  } else if meta.file_type().is_symlink() {
+    // Never follow links out of the app's directories.
+    if let Err(err) = fs::remove_file(path) {
+      report.errors.push(format!("{}: {}", path.display(), err));
+    }
+  } else {
+    match shred_file(path) {
+      Ok(()) => report.files_removed += 1,
+      Err(err) => report.errors.push(format!("{}: {}", path.display(), err)),
+    }
+  }
+}
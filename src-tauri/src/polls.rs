@@ -0,0 +1,245 @@
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+use crate::event_cache;
+use crate::matrix_api::{ApiError, MatrixClient};
+
+pub const POLL_START_TYPE: &str = "org.matrix.msc3381.poll.start";
+pub const POLL_RESPONSE_TYPE: &str = "org.matrix.msc3381.poll.response";
+pub const POLL_END_TYPE: &str = "org.matrix.msc3381.poll.end";
+const POLL_START_TYPES: [&str; 2] = ["m.poll.start", POLL_START_TYPE];
+const POLL_RESPONSE_TYPES: [&str; 2] = ["m.poll.response", POLL_RESPONSE_TYPE];
+const POLL_END_TYPES: [&str; 2] = ["m.poll.end", POLL_END_TYPE];
+const TEXT_KEY: &str = "org.matrix.msc1767.text";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PollAnswerTally {
+  pub id: String,
+  pub text: String,
+  pub votes: usize,
+  /// Voter IDs; empty for undisclosed polls until they end.
+  pub voters: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PollResults {
+  pub poll_event_id: String,
+  pub room_id: String,
+  pub creator: String,
+  pub question: String,
+  pub disclosed: bool,
+  pub max_selections: usize,
+  pub answers: Vec<PollAnswerTally>,
+  pub total_voters: usize,
+  pub ended: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub ended_at: Option<i64>,
+  pub my_selection: Vec<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub winning_answer_ids: Option<Vec<String>>,
+}
+
+/// Read a text field that may be a plain string or an extensible-events text block.
+fn text_of(value: Option<&Value>) -> String {
+  match value {
+    Some(Value::String(s)) => s.clone(),
+    Some(Value::Object(obj)) => obj
+      .get(TEXT_KEY)
+      .or_else(|| obj.get("m.text"))
+      .and_then(|t| match t {
+        Value::String(s) => Some(s.clone()),
+        Value::Array(blocks) => blocks
+          .iter()
+          .find_map(|b| b.get("body").and_then(|v| v.as_str()).map(|s| s.to_string())),
+        _ => None,
+      })
+      .or_else(|| obj.get("body").and_then(|v| v.as_str()).map(|s| s.to_string()))
+      .unwrap_or_default(),
+    _ => String::new(),
+  }
+}
+
+/// Content block of a poll event under either its stable or MSC3381 key.
+fn poll_block<'a>(content: &'a Value, types: &[&str; 2]) -> Option<&'a Value> {
+  types.iter().find_map(|key| content.get(*key))
+}
+
+pub async fn create(
+  client: &MatrixClient,
+  room_id: &str,
+  question: &str,
+  answers: &[String],
+  disclosed: bool,
+  max_selections: Option<usize>,
+) -> Result<String, ApiError> {
+  let answer_blocks: Vec<Value> = answers
+    .iter()
+    .enumerate()
+    .map(|(i, text)| json!({ "id": format!("answer-{}", i + 1), TEXT_KEY: text }))
+    .collect();
+  let fallback = std::iter::once(question.to_string())
+    .chain(answers.iter().enumerate().map(|(i, a)| format!("{}. {}", i + 1, a)))
+    .collect::<Vec<_>>()
+    .join("\n");
+  let kind = if disclosed {
+    "org.matrix.msc3381.poll.disclosed"
+  } else {
+    "org.matrix.msc3381.poll.undisclosed"
+  };
+  let content = json!({
+    POLL_START_TYPE: {
+      "question": { TEXT_KEY: question },
+      "kind": kind,
+      "max_selections": max_selections.unwrap_or(1).max(1),
+      "answers": answer_blocks,
+    },
+    TEXT_KEY: fallback,
+  });
+  client.send_event(room_id, POLL_START_TYPE, &content).await
+}
+
+pub async fn respond(client: &MatrixClient, room_id: &str, poll_event_id: &str, answer_ids: &[String]) -> Result<String, ApiError> {
+  let content = json!({
+    "m.relates_to": { "rel_type": "m.reference", "event_id": poll_event_id },
+    POLL_RESPONSE_TYPE: { "answers": answer_ids },
+  });
+  client.send_event(room_id, POLL_RESPONSE_TYPE, &content).await
+}
+
+pub async fn end(client: &MatrixClient, room_id: &str, poll_event_id: &str) -> Result<String, ApiError> {
+  let content = json!({
+    "m.relates_to": { "rel_type": "m.reference", "event_id": poll_event_id },
+    POLL_END_TYPE: {},
+    TEXT_KEY: "Ended poll",
+  });
+  client.send_event(room_id, POLL_END_TYPE, &content).await
+}
+
+/// Tally responses from cached relations following MSC3381 rules: only each voter's latest
+/// response sent before the poll ended counts, unknown answers spoil the vote, and selections
+/// beyond `max_selections` are dropped.
+pub fn results(
+  conn: &Connection,
+  account_key: &str,
+  room_id: &str,
+  poll_event_id: &str,
+  own_user_id: &str,
+) -> Result<Option<PollResults>, String> {
+  let start = match event_cache::get_event(conn, account_key, room_id, poll_event_id)? {
+    Some(event) if POLL_START_TYPES.contains(&event.event_type.as_str()) => event,
+    _ => return Ok(None),
+  };
+  let block = match poll_block(&start.content, &POLL_START_TYPES) {
+    Some(block) => block,
+    None => return Ok(None),
+  };
+  let disclosed = !block
+    .get("kind")
+    .and_then(|v| v.as_str())
+    .map(|k| k.ends_with("undisclosed"))
+    .unwrap_or(false);
+  let max_selections = block.get("max_selections").and_then(|v| v.as_u64()).unwrap_or(1).max(1) as usize;
+  let mut answers: Vec<PollAnswerTally> = block
+    .get("answers")
+    .and_then(|v| v.as_array())
+    .map(|items| {
+      items
+        .iter()
+        .filter_map(|a| {
+          Some(PollAnswerTally {
+            id: a.get("id")?.as_str()?.to_string(),
+            text: text_of(Some(a)),
+            votes: 0,
+            voters: Vec::new(),
+          })
+        })
+        .collect()
+    })
+    .unwrap_or_default();
+
+  let relations = event_cache::get_relations(conn, account_key, room_id, poll_event_id, Some("m.reference"))?;
+  let ended_at = relations
+    .iter()
+    .filter(|e| POLL_END_TYPES.contains(&e.event_type.as_str()) && e.sender == start.sender)
+    .map(|e| e.origin_server_ts)
+    .min();
+
+  let mut latest: HashMap<String, (i64, Vec<String>)> = HashMap::new();
+  for response in relations
+    .iter()
+    .filter(|e| POLL_RESPONSE_TYPES.contains(&e.event_type.as_str()))
+    .filter(|e| ended_at.map(|end| e.origin_server_ts <= end).unwrap_or(true))
+  {
+    let selection: Vec<String> = poll_block(&response.content, &POLL_RESPONSE_TYPES)
+      .and_then(|b| b.get("answers"))
+      .and_then(|v| v.as_array())
+      .map(|ids| ids.iter().filter_map(|id| id.as_str().map(|s| s.to_string())).collect())
+      .unwrap_or_default();
+    let newer = latest
+      .get(&response.sender)
+      .map(|(ts, _)| response.origin_server_ts >= *ts)
+      .unwrap_or(true);
+    if newer {
+      latest.insert(response.sender.clone(), (response.origin_server_ts, selection));
+    }
+  }
+
+  let ended = ended_at.is_some();
+  let reveal_voters = disclosed || ended;
+  let mut total_voters = 0;
+  for (voter, (_, selection)) in &latest {
+    let valid = !selection.is_empty() && selection.iter().all(|id| answers.iter().any(|a| &a.id == id));
+    if !valid {
+      continue;
+    }
+    total_voters += 1;
+    for id in selection.iter().take(max_selections) {
+      if let Some(answer) = answers.iter_mut().find(|a| &a.id == id) {
+        answer.votes += 1;
+        if reveal_voters {
+          answer.voters.push(voter.clone());
+        }
+      }
+    }
+  }
+  if !reveal_voters {
+    for answer in answers.iter_mut() {
+      answer.votes = 0;
+    }
+  }
+  let winning_answer_ids = if ended {
+    let top = answers.iter().map(|a| a.votes).max().unwrap_or(0);
+    Some(
+      answers
+        .iter()
+        .filter(|a| top > 0 && a.votes == top)
+        .map(|a| a.id.clone())
+        .collect(),
+    )
+  } else {
+    None
+  };
+  let my_selection = latest
+    .get(own_user_id)
+    .map(|(_, selection)| selection.clone())
+    .unwrap_or_default();
+
+  Ok(Some(PollResults {
+    poll_event_id: poll_event_id.to_string(),
+    room_id: room_id.to_string(),
+    creator: start.sender.clone(),
+    question: text_of(block.get("question")),
+    disclosed,
+    max_selections,
+    answers,
+    total_voters,
+    ended,
+    ended_at,
+    my_selection,
+    winning_answer_ids,
+  }))
+}
@@ -0,0 +1,173 @@
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::matrix_api::now_millis;
+
+pub const ROOM_PREFIX: &str = "room-";
+pub const CALL_PREFIX: &str = "call-";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PopoutKind {
+  Room,
+  Call,
+}
+
+impl PopoutKind {
+  fn as_str(self) -> &'static str {
+    match self {
+      PopoutKind::Room => "room",
+      PopoutKind::Call => "call",
+    }
+  }
+
+  fn parse(value: &str) -> Option<PopoutKind> {
+    match value {
+      "room" => Some(PopoutKind::Room),
+      "call" => Some(PopoutKind::Call),
+      _ => None,
+    }
+  }
+}
+
+/// Outer position and inner size in logical pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WindowGeometry {
+  pub x: f64,
+  pub y: f64,
+  pub width: f64,
+  pub height: f64,
+  #[serde(default)]
+  pub maximized: bool,
+}
+
+/// A room or call shown in its own window, keyed by the window label.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PopoutWindow {
+  pub label: String,
+  pub kind: PopoutKind,
+  pub account_key: String,
+  pub room_id: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub geometry: Option<WindowGeometry>,
+}
+
+pub fn init_popout_windows_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS popout_windows (
+        label TEXT PRIMARY KEY,
+        kind TEXT NOT NULL,
+        account_key TEXT NOT NULL,
+        room_id TEXT NOT NULL,
+        geometry_json TEXT,
+        opened_at INTEGER NOT NULL
+      );
+    ",
+  )
+}
+
+/// A stable label for the room's window, so opening it twice focuses the existing one.
+pub fn label(kind: PopoutKind, account_key: &str, room_id: &str) -> String {
+  let digest = Sha256::digest(format!("{}|{}", account_key, room_id).as_bytes());
+  let hash: String = digest[..8].iter().map(|b| format!("{:02x}", b)).collect();
+  let prefix = match kind {
+    PopoutKind::Room => ROOM_PREFIX,
+    PopoutKind::Call => CALL_PREFIX,
+  };
+  format!("{}{}", prefix, hash)
+}
+
+pub fn is_popout(label: &str) -> bool {
+  label.starts_with(ROOM_PREFIX) || label.starts_with(CALL_PREFIX)
+}
+
+/// The hash route the webview renders in a popped-out window.
+pub fn route(window: &PopoutWindow) -> String {
+  format!(
+    "index.html#/popout/{}?account={}&room={}",
+    window.kind.as_str(),
+    urlencoding::encode(&window.account_key),
+    urlencoding::encode(&window.room_id)
+  )
+}
+
+pub fn save(conn: &Connection, window: &PopoutWindow) -> Result<(), String> {
+  let geometry = window
+    .geometry
+    .map(|geometry| serde_json::to_string(&geometry))
+    .transpose()
+    .map_err(|e| e.to_string())?;
+  conn
+    .execute(
+      "INSERT INTO popout_windows (label, kind, account_key, room_id, geometry_json, opened_at)
+       VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+       ON CONFLICT(label) DO UPDATE SET geometry_json = COALESCE(excluded.geometry_json, geometry_json)",
+      params![
+        window.label,
+        window.kind.as_str(),
+        window.account_key,
+        window.room_id,
+        geometry,
+        now_millis()
+      ],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+pub fn set_geometry(conn: &Connection, label: &str, geometry: &WindowGeometry) -> Result<(), String> {
+  let json = serde_json::to_string(geometry).map_err(|e| e.to_string())?;
+  conn
+    .execute("UPDATE popout_windows SET geometry_json = ?1 WHERE label = ?2", params![json, label])
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+pub fn remove(conn: &Connection, label: &str) -> Result<(), String> {
+  conn
+    .execute("DELETE FROM popout_windows WHERE label = ?1", [label])
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+/// Calls don't outlive a restart, so only room windows are reopened.
+pub fn clear_calls(conn: &Connection) -> Result<(), String> {
+  conn
+    .execute("DELETE FROM popout_windows WHERE kind = 'call'", [])
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+pub fn list(conn: &Connection) -> Result<Vec<PopoutWindow>, String> {
+  let mut stmt = conn
+    .prepare("SELECT label, kind, account_key, room_id, geometry_json FROM popout_windows ORDER BY opened_at")
+    .map_err(|e| e.to_string())?;
+  let rows = stmt
+    .query_map([], |row| {
+      Ok((
+        row.get::<_, String>(0)?,
+        row.get::<_, String>(1)?,
+        row.get::<_, String>(2)?,
+        row.get::<_, String>(3)?,
+        row.get::<_, Option<String>>(4)?,
+      ))
+    })
+    .map_err(|e| e.to_string())?;
+  Ok(
+    rows
+      .flatten()
+      .filter_map(|(label, kind, account_key, room_id, geometry)| {
+        Some(PopoutWindow {
+          label,
+          kind: PopoutKind::parse(&kind)?,
+          account_key,
+          room_id,
+          geometry: geometry.and_then(|json| serde_json::from_str(&json).ok()),
+        })
+      })
+      .collect(),
+  )
+}
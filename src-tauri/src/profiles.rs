@@ -0,0 +1,157 @@
+use image::{imageops::FilterType, GenericImageView, ImageFormat};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::io::Cursor;
+use std::path::Path;
+
+use crate::event_cache;
+use crate::matrix_api::{encode, now_millis, ApiError, MatrixClient};
+
+const PROFILE_TTL_MS: i64 = 60 * 60 * 1000;
+const AVATAR_MAX_DIMENSION: u32 = 512;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserProfile {
+  pub user_id: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub display_name: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub avatar_url: Option<String>,
+  pub fetched_at: i64,
+  pub stale: bool,
+}
+
+/// Profiles are cached per account: what a user shows can differ by homeserver and by
+/// what the account is allowed to see.
+pub fn init_profiles_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  event_cache::drop_unscoped(conn, &["profile_cache"])?;
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS profile_cache (
+        account_key TEXT NOT NULL,
+        user_id TEXT NOT NULL,
+        display_name TEXT,
+        avatar_url TEXT,
+        fetched_at INTEGER NOT NULL,
+        PRIMARY KEY (account_key, user_id)
+      );
+    ",
+  )
+}
+
+pub fn cached_profile(conn: &Connection, account_key: &str, user_id: &str) -> Result<Option<UserProfile>, String> {
+  conn
+    .query_row(
+      "SELECT user_id, display_name, avatar_url, fetched_at FROM profile_cache WHERE account_key = ?1 AND user_id = ?2",
+      [account_key, user_id],
+      |row| {
+        let fetched_at: i64 = row.get(3)?;
+        Ok(UserProfile {
+          user_id: row.get(0)?,
+          display_name: row.get(1)?,
+          avatar_url: row.get(2)?,
+          fetched_at,
+          stale: now_millis() - fetched_at > PROFILE_TTL_MS,
+        })
+      },
+    )
+    .optional()
+    .map_err(|e| e.to_string())
+}
+
+pub fn store_profile(conn: &Connection, account_key: &str, profile: &UserProfile) -> Result<(), String> {
+  conn
+    .execute(
+      "INSERT INTO profile_cache (account_key, user_id, display_name, avatar_url, fetched_at)
+       VALUES (?1, ?2, ?3, ?4, ?5)
+       ON CONFLICT(account_key, user_id) DO UPDATE SET
+         display_name = excluded.display_name,
+         avatar_url = excluded.avatar_url,
+         fetched_at = excluded.fetched_at",
+      params![account_key, profile.user_id, profile.display_name, profile.avatar_url, profile.fetched_at],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+/// Patch one field of a cached profile after a local edit, keeping the other as-is.
+pub fn update_cached_field(
+  conn: &Connection,
+  account_key: &str,
+  user_id: &str,
+  display_name: Option<&str>,
+  avatar_url: Option<&str>,
+) -> Result<(), String> {
+  let mut profile = cached_profile(conn, account_key, user_id)?.unwrap_or(UserProfile {
+    user_id: user_id.to_string(),
+    display_name: None,
+    avatar_url: None,
+    fetched_at: 0,
+    stale: true,
+  });
+  if let Some(name) = display_name {
+    profile.display_name = Some(name.to_string());
+  }
+  if let Some(url) = avatar_url {
+    profile.avatar_url = Some(url.to_string());
+  }
+  profile.fetched_at = now_millis();
+  store_profile(conn, account_key, &profile)
+}
+
+pub async fn fetch_profile(client: &MatrixClient, user_id: &str) -> Result<UserProfile, ApiError> {
+  let response = client.get(&format!("/profile/{}", encode(user_id)), &[]).await?;
+  Ok(UserProfile {
+    user_id: user_id.to_string(),
+    display_name: response.get("displayname").and_then(|v| v.as_str()).map(|s| s.to_string()),
+    avatar_url: response.get("avatar_url").and_then(|v| v.as_str()).map(|s| s.to_string()),
+    fetched_at: now_millis(),
+    stale: false,
+  })
+}
+
+pub async fn set_display_name(client: &MatrixClient, display_name: &str) -> Result<(), ApiError> {
+  client
+    .put(
+      &format!("/profile/{}/displayname", encode(&client.user_id)),
+      &json!({ "displayname": display_name }),
+    )
+    .await?;
+  Ok(())
+}
+
+pub async fn set_avatar_url(client: &MatrixClient, avatar_url: &str) -> Result<(), ApiError> {
+  client
+    .put(
+      &format!("/profile/{}/avatar_url", encode(&client.user_id)),
+      &json!({ "avatar_url": avatar_url }),
+    )
+    .await?;
+  Ok(())
+}
+
+/// Decode an image file, downscale it to avatar size and re-encode it.
+/// Returns the encoded bytes and their content type.
+pub fn prepare_avatar(path: &Path) -> Result<(Vec<u8>, &'static str), String> {
+  let img = image::open(path).map_err(|e| format!("Failed to read image: {}", e))?;
+  let (width, height) = img.dimensions();
+  let img = if width > AVATAR_MAX_DIMENSION || height > AVATAR_MAX_DIMENSION {
+    img.resize(AVATAR_MAX_DIMENSION, AVATAR_MAX_DIMENSION, FilterType::Lanczos3)
+  } else {
+    img
+  };
+  let mut out = Vec::new();
+  if img.color().has_alpha() {
+    img
+      .write_to(&mut Cursor::new(&mut out), ImageFormat::Png)
+      .map_err(|e| format!("Failed to encode avatar: {}", e))?;
+    Ok((out, "image/png"))
+  } else {
+    img
+      .to_rgb8()
+      .write_to(&mut Cursor::new(&mut out), ImageFormat::Jpeg)
+      .map_err(|e| format!("Failed to encode avatar: {}", e))?;
+    Ok((out, "image/jpeg"))
+  }
+}
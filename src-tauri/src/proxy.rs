@@ -0,0 +1,652 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, TcpStream, ToSocketAddrs};
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+
+use crate::matrix_api::now_millis;
+
+const PAC_TIMEOUT: Duration = Duration::from_secs(10);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "camelCase")]
+pub enum ProxyConfig {
+  #[default]
+  Direct,
+  /// Fixed proxies as `scheme://host:port`, with hosts that bypass them.
+  #[serde(rename_all = "camelCase")]
+  Manual {
+    http: Option<String>,
+    https: Option<String>,
+    socks: Option<String>,
+    bypass: Vec<String>,
+  },
+  /// A proxy auto-config script; without a URL the script is found through WPAD.
+  #[serde(rename_all = "camelCase")]
+  Pac { url: Option<String>, bypass: Vec<String> },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProxyStatus {
+  pub config: ProxyConfig,
+  /// Where the settings came from: `environment`, `gnome`, `windows`, `macos` or `none`.
+  pub source: String,
+  pub detected_at: i64,
+  /// The PAC script could not be loaded, so connections go direct.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub error: Option<String>,
+}
+
+struct Active {
+  status: ProxyStatus,
+  pac_script: Option<String>,
+  /// PAC decisions per scheme and host.
+  decisions: Mutex<HashMap<String, Option<String>>>,
+}
+
+static ACTIVE: RwLock<Option<Active>> = RwLock::new(None);
+
+fn env_proxy(names: &[&str]) -> Option<String> {
+  names
+    .iter()
+    .find_map(|name| std::env::var(name).ok().filter(|value| !value.trim().is_empty()))
+    .map(|value| with_scheme(value.trim(), "http"))
+}
+
+fn with_scheme(value: &str, scheme: &str) -> String {
+  if value.contains("://") {
+    value.to_string()
+  } else {
+    format!("{}://{}", scheme, value)
+  }
+}
+
+fn split_list(value: &str) -> Vec<String> {
+  value
+    .split([',', ';'])
+    .map(|entry| entry.trim().to_string())
+    .filter(|entry| !entry.is_empty())
+    .collect()
+}
+
+fn from_environment() -> Option<ProxyConfig> {
+  let http = env_proxy(&["http_proxy", "HTTP_PROXY"]);
+  let https = env_proxy(&["https_proxy", "HTTPS_PROXY"]);
+  let socks = env_proxy(&["all_proxy", "ALL_PROXY"]).map(|value| value.replace("socks5h://", "socks5://"));
+  if http.is_none() && https.is_none() && socks.is_none() {
+    return None;
+  }
+  let bypass = ["no_proxy", "NO_PROXY"]
+    .iter()
+    .find_map(|name| std::env::var(name).ok())
+    .map(|value| split_list(&value))
+    .unwrap_or_default();
+  Some(ProxyConfig::Manual { http, https, socks, bypass })
+}
+
+/// GNOME (and most GTK desktops) keep proxy settings in gsettings.
+#[cfg(all(unix, not(target_os = "macos")))]
+fn from_system() -> Option<(ProxyConfig, &'static str)> {
+  let get = |schema: &str, key: &str| -> Option<String> {
+    let output = std::process::Command::new("gsettings").args(["get", schema, key]).output().ok()?;
+    if !output.status.success() {
+      return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().trim_matches('\'').to_string())
+  };
+  let proxy = |kind: &str, scheme: &str| -> Option<String> {
+    let schema = format!("org.gnome.system.proxy.{}", kind);
+    let host = get(&schema, "host").filter(|host| !host.is_empty())?;
+    let port = get(&schema, "port").and_then(|port| port.parse::<u16>().ok()).filter(|port| *port > 0)?;
+    Some(format!("{}://{}:{}", scheme, host, port))
+  };
+  let bypass = || {
+    get("org.gnome.system.proxy", "ignore-hosts")
+      .map(|list| {
+        list
+          .trim_matches(|c| c == '[' || c == ']')
+          .split(',')
+          .map(|entry| entry.trim().trim_matches('\'').to_string())
+          .filter(|entry| !entry.is_empty())
+          .collect()
+      })
+      .unwrap_or_default()
+  };
+  match get("org.gnome.system.proxy", "mode")?.as_str() {
+    "manual" => Some((
+      ProxyConfig::Manual {
+        http: proxy("http", "http"),
+        https: proxy("https", "http"),
+        socks: proxy("socks", "socks5"),
+        bypass: bypass(),
+      },
+      "gnome",
+    )),
+    "auto" => Some((
+      ProxyConfig::Pac {
+        url: get("org.gnome.system.proxy", "autoconfig-url").filter(|url| !url.is_empty()),
+        bypass: bypass(),
+      },
+      "gnome",
+    )),
+    _ => None,
+  }
+}
+
+/// `scutil --proxy` prints the active network service's proxy dictionary.
+#[cfg(target_os = "macos")]
+fn from_system() -> Option<(ProxyConfig, &'static str)> {
+  let output = std::process::Command::new("scutil").arg("--proxy").output().ok()?;
+  let text = String::from_utf8_lossy(&output.stdout);
+  let mut values = HashMap::new();
+  let mut bypass = Vec::new();
+  let mut in_exceptions = false;
+  for line in text.lines() {
+    let line = line.trim();
+    if line.starts_with("ExceptionsList") {
+      in_exceptions = true;
+      continue;
+    }
+    if in_exceptions {
+      if line == "}" {
+        in_exceptions = false;
+      } else if let Some((_, host)) = line.split_once(" : ") {
+        bypass.push(host.trim().to_string());
+      }
+      continue;
+    }
+    if let Some((key, value)) = line.split_once(" : ") {
+      values.insert(key.trim().to_string(), value.trim().to_string());
+    }
+  }
+  let enabled = |key: &str| values.get(key).is_some_and(|value| value == "1");
+  if enabled("ProxyAutoConfigEnable") {
+    return Some((
+      ProxyConfig::Pac {
+        url: values.get("ProxyAutoConfigURLString").cloned(),
+        bypass,
+      },
+      "macos",
+    ));
+  }
+  if enabled("ProxyAutoDiscoveryEnable") {
+    return Some((ProxyConfig::Pac { url: None, bypass }, "macos"));
+  }
+  let proxy = |prefix: &str, scheme: &str| -> Option<String> {
+    if !enabled(&format!("{}Enable", prefix)) {
+      return None;
+    }
+    let host = values.get(&format!("{}Proxy", prefix))?;
+    let port = values.get(&format!("{}Port", prefix))?;
+    Some(format!("{}://{}:{}", scheme, host, port))
+  };
+  let (http, https, socks) = (proxy("HTTP", "http"), proxy("HTTPS", "http"), proxy("SOCKS", "socks5"));
+  if http.is_none() && https.is_none() && socks.is_none() {
+    return None;
+  }
+  Some((ProxyConfig::Manual { http, https, socks, bypass }, "macos"))
+}
+
+/// WinINet settings of the current user, as used by browsers.
+#[cfg(windows)]
+fn from_system() -> Option<(ProxyConfig, &'static str)> {
+  let bypass: Vec<String> = windows_proxy::string("ProxyOverride").map(|list| split_list(&list)).unwrap_or_default();
+  if let Some(url) = windows_proxy::string("AutoConfigURL").filter(|url| !url.is_empty()) {
+    return Some((ProxyConfig::Pac { url: Some(url), bypass }, "windows"));
+  }
+  if windows_proxy::dword("ProxyEnable") != Some(1) {
+    return windows_proxy::auto_detect().then_some((ProxyConfig::Pac { url: None, bypass }, "windows"));
+  }
+  let server = windows_proxy::string("ProxyServer")?;
+  // Either `host:port` for every scheme or `http=host:port;https=host:port;socks=host:port`.
+  if !server.contains('=') {
+    let proxy = with_scheme(&server, "http");
+    return Some((
+      ProxyConfig::Manual {
+        http: Some(proxy.clone()),
+        https: Some(proxy),
+        socks: None,
+        bypass,
+      },
+      "windows",
+    ));
+  }
+  let mut per_scheme = HashMap::new();
+  for entry in split_list(&server) {
+    if let Some((scheme, address)) = entry.split_once('=') {
+      per_scheme.insert(scheme.to_ascii_lowercase(), address.to_string());
+    }
+  }
+  Some((
+    ProxyConfig::Manual {
+      http: per_scheme.get("http").map(|address| with_scheme(address, "http")),
+      https: per_scheme.get("https").map(|address| with_scheme(address, "http")),
+      socks: per_scheme.get("socks").map(|address| with_scheme(address, "socks5")),
+      bypass,
+    },
+    "windows",
+  ))
+}
+
+#[cfg(windows)]
+mod windows_proxy {
+  use windows::core::{HSTRING, PCWSTR};
+  use windows::Win32::Foundation::{GlobalFree, HGLOBAL};
+  use windows::Win32::Networking::WinHttp::{
+    WinHttpCloseHandle, WinHttpGetProxyForUrl, WinHttpOpen, WINHTTP_ACCESS_TYPE_NAMED_PROXY,
+    WINHTTP_ACCESS_TYPE_NO_PROXY, WINHTTP_AUTOPROXY_AUTO_DETECT, WINHTTP_AUTOPROXY_CONFIG_URL,
+    WINHTTP_AUTOPROXY_OPTIONS, WINHTTP_AUTO_DETECT_TYPE_DHCP, WINHTTP_AUTO_DETECT_TYPE_DNS_A, WINHTTP_PROXY_INFO,
+  };
+  use windows::Win32::System::Registry::{RegGetValueW, HKEY_CURRENT_USER, RRF_RT_REG_DWORD, RRF_RT_REG_SZ};
+
+  const SETTINGS_KEY: &str = "Software\\Microsoft\\Windows\\CurrentVersion\\Internet Settings";
+
+  pub fn string(name: &str) -> Option<String> {
+    let mut buffer = [0u16; 2048];
+    let mut size = (buffer.len() * 2) as u32;
+    let result = unsafe {
+      RegGetValueW(
+        HKEY_CURRENT_USER,
+        &HSTRING::from(SETTINGS_KEY),
+        &HSTRING::from(name),
+        RRF_RT_REG_SZ,
+        None,
+        Some(buffer.as_mut_ptr() as *mut _),
+        Some(&mut size),
+      )
+    };
+    if result.is_err() {
+      return None;
+    }
+    let len = (size as usize / 2).saturating_sub(1);
+    Some(String::from_utf16_lossy(&buffer[..len]))
+  }
+
+  pub fn dword(name: &str) -> Option<u32> {
+    let mut value = 0u32;
+    let mut size = 4u32;
+    let result = unsafe {
+      RegGetValueW(
+        HKEY_CURRENT_USER,
+        &HSTRING::from(SETTINGS_KEY),
+        &HSTRING::from(name),
+        RRF_RT_REG_DWORD,
+        None,
+        Some(&mut value as *mut u32 as *mut _),
+        Some(&mut size),
+      )
+    };
+    result.is_ok().then_some(value)
+  }
+
+  /// "Automatically detect settings"; stored as a flag in the connection settings blob.
+  pub fn auto_detect() -> bool {
+    let key = format!("{}\\Connections", SETTINGS_KEY);
+    let mut buffer = [0u8; 1024];
+    let mut size = buffer.len() as u32;
+    let result = unsafe {
+      RegGetValueW(
+        HKEY_CURRENT_USER,
+        &HSTRING::from(key),
+        &HSTRING::from("DefaultConnectionSettings"),
+        windows::Win32::System::Registry::RRF_RT_REG_BINARY,
+        None,
+        Some(buffer.as_mut_ptr() as *mut _),
+        Some(&mut size),
+      )
+    };
+    result.is_ok() && size > 8 && buffer[8] & 0x08 != 0
+  }
+
+  /// Let WinHTTP run the PAC script (or WPAD discovery) for `url`. Returns the PAC-style
+  /// proxy list, `None` for a direct connection.
+  pub fn proxy_for_url(url: &str, pac_url: Option<&str>) -> Result<Option<String>, String> {
+    unsafe {
+      let session = WinHttpOpen(
+        &HSTRING::from("matrix-messenger"),
+        WINHTTP_ACCESS_TYPE_NO_PROXY,
+        PCWSTR::null(),
+        PCWSTR::null(),
+        0,
+      );
+      if session.is_null() {
+        return Err("WinHTTP is unavailable".into());
+      }
+      let pac = pac_url.map(HSTRING::from);
+      let mut options = WINHTTP_AUTOPROXY_OPTIONS {
+        dwFlags: if pac.is_some() { WINHTTP_AUTOPROXY_CONFIG_URL } else { WINHTTP_AUTOPROXY_AUTO_DETECT },
+        dwAutoDetectFlags: WINHTTP_AUTO_DETECT_TYPE_DHCP | WINHTTP_AUTO_DETECT_TYPE_DNS_A,
+        lpszAutoConfigUrl: pac.as_ref().map(|pac| PCWSTR(pac.as_ptr())).unwrap_or(PCWSTR::null()),
+        fAutoLogonIfChallenged: true.into(),
+        ..Default::default()
+      };
+      let mut info = WINHTTP_PROXY_INFO::default();
+      let result = WinHttpGetProxyForUrl(session, &HSTRING::from(url), &mut options, &mut info);
+      let _ = WinHttpCloseHandle(session);
+      result.map_err(|e| format!("Proxy auto-config failed: {}", e))?;
+      let proxy = (info.dwAccessType == WINHTTP_ACCESS_TYPE_NAMED_PROXY && !info.lpszProxy.is_null())
+        .then(|| info.lpszProxy.to_string().ok())
+        .flatten();
+      if !info.lpszProxy.is_null() {
+        let _ = GlobalFree(HGLOBAL(info.lpszProxy.0 as *mut _));
+      }
+      if !info.lpszProxyBypass.is_null() {
+        let _ = GlobalFree(HGLOBAL(info.lpszProxyBypass.0 as *mut _));
+      }
+      Ok(proxy.map(|list| {
+        list
+          .split([';', ' '])
+          .filter(|entry| !entry.is_empty())
+          .map(|entry| format!("PROXY {}", entry))
+          .collect::<Vec<_>>()
+          .join("; ")
+      }))
+    }
+  }
+}
+
+/// Standard PAC helpers. DNS answers are resolved natively before the call and passed in.
+#[cfg(not(windows))]
+const PAC_PRELUDE: &str = r#"
+function isPlainHostName(h) { return h.indexOf('.') < 0; }
+function dnsDomainIs(h, d) { return h.length >= d.length && h.substring(h.length - d.length) === d; }
+function localHostOrDomainIs(h, d) { return h === d || d.lastIndexOf(h + '.', 0) === 0; }
+function isResolvable(h) { return __resolve(h) !== null; }
+function dnsResolve(h) { return __resolve(h); }
+function myIpAddress() { return __myIp; }
+function dnsDomainLevels(h) { return h.split('.').length - 1; }
+function shExpMatch(s, p) {
+  var re = new RegExp('^' + p.replace(/[.+^${}()|[\]\\]/g, '\\$&').replace(/\*/g, '.*').replace(/\?/g, '.') + '$');
+  return re.test(s);
+}
+function __ip(a) { var p = a.split('.'); return ((+p[0] << 24) >>> 0) + (+p[1] << 16) + (+p[2] << 8) + (+p[3]); }
+function isInNet(h, pattern, mask) {
+  var a = /^\d+\.\d+\.\d+\.\d+$/.test(h) ? h : __resolve(h);
+  if (!a) return false;
+  return ((__ip(a) & __ip(mask)) >>> 0) === ((__ip(pattern) & __ip(mask)) >>> 0);
+}
+function weekdayRange() { return true; }
+function dateRange() { return true; }
+function timeRange() { return true; }
+"#;
+
+/// Run `FindProxyForURL` for `url` in an embedded JavaScript engine.
+#[cfg(not(windows))]
+fn evaluate_pac(script: &str, url: &reqwest::Url) -> Result<Option<String>, String> {
+  use boa_engine::{Context, Source};
+
+  let host = url.host_str().unwrap_or_default();
+  let resolved = (host, 0)
+    .to_socket_addrs()
+    .ok()
+    .and_then(|mut addrs| addrs.find(|addr| addr.is_ipv4()))
+    .map(|addr| addr.ip().to_string());
+  let my_ip = local_ipv4().unwrap_or_else(|| "127.0.0.1".to_string());
+  let bindings = format!(
+    "var __host = {host}; var __hostIp = {ip}; var __myIp = {my_ip};\n\
+     function __resolve(h) {{ return h === __host ? __hostIp : null; }}\n",
+    host = serde_json::to_string(host).unwrap_or_default(),
+    ip = resolved.map(|ip| serde_json::to_string(&ip).unwrap_or_default()).unwrap_or_else(|| "null".into()),
+    my_ip = serde_json::to_string(&my_ip).unwrap_or_default(),
+  );
+  let call = format!(
+    "FindProxyForURL({}, {})",
+    serde_json::to_string(url.as_str()).unwrap_or_default(),
+    serde_json::to_string(host).unwrap_or_default()
+  );
+  let mut context = Context::default();
+  for source in [PAC_PRELUDE, bindings.as_str(), script] {
+    context
+      .eval(Source::from_bytes(source))
+      .map_err(|e| format!("Invalid PAC script: {}", e))?;
+  }
+  let result = context
+    .eval(Source::from_bytes(&call))
+    .map_err(|e| format!("PAC evaluation failed: {}", e))?;
+  Ok(result.as_string().map(|s| s.to_std_string_escaped()))
+}
+
+#[cfg(not(windows))]
+fn local_ipv4() -> Option<String> {
+  let socket = std::net::UdpSocket::bind("0.0.0.0:0").ok()?;
+  socket.connect("192.0.2.1:9").ok()?;
+  Some(socket.local_addr().ok()?.ip().to_string())
+}
+
+/// The first usable entry of a PAC result such as `PROXY a:3128; SOCKS5 b:1080; DIRECT`.
+fn parse_pac_result(result: &str) -> Option<String> {
+  for entry in result.split(';') {
+    let mut parts = entry.split_whitespace();
+    let kind = parts.next()?.to_ascii_uppercase();
+    let address = parts.next();
+    match (kind.as_str(), address) {
+      ("DIRECT", _) => return None,
+      ("PROXY" | "HTTP", Some(address)) => return Some(format!("http://{}", address)),
+      ("HTTPS", Some(address)) => return Some(format!("https://{}", address)),
+      ("SOCKS" | "SOCKS5" | "SOCKS4", Some(address)) => return Some(format!("socks5://{}", address)),
+      _ => continue,
+    }
+  }
+  None
+}
+
+fn bypassed(bypass: &[String], host: &str) -> bool {
+  let host = host.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase();
+  if host == "localhost" || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback()) {
+    return true;
+  }
+  bypass.iter().any(|rule| {
+    let rule = rule.trim().to_ascii_lowercase();
+    match rule.as_str() {
+      "*" => true,
+      "<local>" => !host.contains('.'),
+      _ => {
+        let suffix = rule.trim_start_matches('*').trim_start_matches('.');
+        if suffix.is_empty() {
+          return false;
+        }
+        host == suffix || host.ends_with(&format!(".{}", suffix))
+      }
+    }
+  })
+}
+
+/// Read the OS (or environment) proxy settings. The environment wins, as it does for curl.
+pub fn detect() -> (ProxyConfig, &'static str) {
+  if let Some(config) = from_environment() {
+    return (config, "environment");
+  }
+  from_system().unwrap_or((ProxyConfig::Direct, "none"))
+}
+
+async fn fetch_pac(url: &str) -> Result<String, String> {
+  if let Some(path) = url.strip_prefix("file://") {
+    return std::fs::read_to_string(path).map_err(|e| e.to_string());
+  }
+  let response = reqwest::Client::builder()
+    .no_proxy()
+    .timeout(PAC_TIMEOUT)
+    .build()
+    .map_err(|e| e.to_string())?
+    .get(url)
+    .send()
+    .await
+    .map_err(|e| format!("Failed to load the PAC file: {}", e))?;
+  if !response.status().is_success() {
+    return Err(format!("Failed to load the PAC file: HTTP {}", response.status()));
+  }
+  response.text().await.map_err(|e| e.to_string())
+}
+
+/// Detect the settings again (at startup and after network changes) and use them for every
+/// connection from now on.
+pub async fn refresh() -> ProxyStatus {
+  let (config, source) = tauri::async_runtime::spawn_blocking(detect)
+    .await
+    .unwrap_or((ProxyConfig::Direct, "none"));
+  let mut error = None;
+  let mut pac_script = None;
+  if let ProxyConfig::Pac { url, .. } = &config {
+    // WinHTTP loads and runs the script itself.
+    if !cfg!(windows) {
+      match url {
+        Some(url) => match fetch_pac(url).await {
+          Ok(script) => pac_script = Some(script),
+          Err(err) => error = Some(err),
+        },
+        None => match fetch_pac("http://wpad/wpad.dat").await {
+          Ok(script) => pac_script = Some(script),
+          Err(err) => error = Some(format!("WPAD discovery failed: {}", err)),
+        },
+      }
+    }
+  }
+  let status = ProxyStatus {
+    config,
+    source: source.to_string(),
+    detected_at: now_millis(),
+    error,
+  };
+  if let Ok(mut active) = ACTIVE.write() {
+    *active = Some(Active {
+      status: status.clone(),
+      pac_script,
+      decisions: Mutex::new(HashMap::new()),
+    });
+  }
+  status
+}
+
+pub fn status() -> Option<ProxyStatus> {
+  ACTIVE.read().ok()?.as_ref().map(|active| active.status.clone())
+}
+
+/// The proxy for `url` as `scheme://host:port`, `None` to connect directly.
+pub fn for_url(url: &reqwest::Url) -> Option<String> {
+  let active = ACTIVE.read().ok()?;
+  let active = active.as_ref()?;
+  let host = url.host_str()?;
+  match &active.status.config {
+    ProxyConfig::Direct => None,
+    ProxyConfig::Manual { http, https, socks, bypass } => {
+      if bypassed(bypass, host) {
+        return None;
+      }
+      match url.scheme() {
+        "http" => http.clone().or_else(|| socks.clone()),
+        _ => https.clone().or_else(|| http.clone()).or_else(|| socks.clone()),
+      }
+    }
+    ProxyConfig::Pac { url: pac_url, bypass } => {
+      if bypassed(bypass, host) {
+        return None;
+      }
+      let key = format!("{}://{}", url.scheme(), host);
+      if let Some(decision) = active.decisions.lock().ok()?.get(&key) {
+        return decision.clone();
+      }
+      #[cfg(windows)]
+      let result = windows_proxy::proxy_for_url(url.as_str(), pac_url.as_deref());
+      #[cfg(not(windows))]
+      let result = {
+        let _ = pac_url;
+        match active.pac_script.as_deref() {
+          Some(script) => evaluate_pac(script, url),
+          None => Ok(None),
+        }
+      };
+      let decision = match result {
+        Ok(result) => result.as_deref().and_then(parse_pac_result),
+        Err(err) => {
+          tracing::warn!("{}", err);
+          None
+        }
+      };
+      if let Ok(mut decisions) = active.decisions.lock() {
+        decisions.insert(key, decision.clone());
+      }
+      decision
+    }
+  }
+}
+
+/// Route a client through the detected proxy. The decision is made per request, so clients
+/// built before a network change follow the new settings.
+pub fn apply(builder: reqwest::ClientBuilder) -> reqwest::ClientBuilder {
+  builder.proxy(reqwest::Proxy::custom(|url| for_url(url).and_then(|proxy| reqwest::Url::parse(&proxy).ok())))
+}
+
+fn read_http_status(stream: &TcpStream) -> Result<(), String> {
+  let mut reader = BufReader::new(stream);
+  let mut status = String::new();
+  reader.read_line(&mut status).map_err(|e| e.to_string())?;
+  if status.split_whitespace().nth(1) != Some("200") {
+    return Err(format!("Proxy refused the tunnel: {}", status.trim()));
+  }
+  loop {
+    let mut line = String::new();
+    if reader.read_line(&mut line).map_err(|e| e.to_string())? == 0 || line == "\r\n" {
+      return Ok(());
+    }
+  }
+}
+
+fn socks5_connect(stream: &mut TcpStream, host: &str, port: u16) -> Result<(), String> {
+  let io = |e: std::io::Error| format!("SOCKS proxy: {}", e);
+  stream.write_all(&[5, 1, 0]).map_err(io)?;
+  let mut reply = [0u8; 2];
+  stream.read_exact(&mut reply).map_err(io)?;
+  if reply != [5, 0] {
+    return Err("SOCKS proxy requires authentication".into());
+  }
+  let mut request = vec![5, 1, 0, 3, host.len() as u8];
+  request.extend_from_slice(host.as_bytes());
+  request.extend_from_slice(&port.to_be_bytes());
+  stream.write_all(&request).map_err(io)?;
+  let mut header = [0u8; 4];
+  stream.read_exact(&mut header).map_err(io)?;
+  if header[1] != 0 {
+    return Err(format!("SOCKS proxy refused the connection (code {})", header[1]));
+  }
+  let skip = match header[3] {
+    1 => 4 + 2,
+    4 => 16 + 2,
+    _ => {
+      let mut len = [0u8; 1];
+      stream.read_exact(&mut len).map_err(io)?;
+      len[0] as usize + 2
+    }
+  };
+  let mut rest = vec![0u8; skip];
+  stream.read_exact(&mut rest).map_err(io)
+}
+
+/// A TCP connection to `host:port` for non-HTTP protocols such as SSH, tunnelled through the
+/// proxy that would be used for `https://host` (HTTP CONNECT or SOCKS5).
+pub fn connect_tcp(host: &str, port: u16) -> Result<TcpStream, String> {
+  let target = reqwest::Url::parse(&format!("https://{}:{}", host, port)).map_err(|e| e.to_string())?;
+  let Some(proxy) = for_url(&target).and_then(|proxy| reqwest::Url::parse(&proxy).ok()) else {
+    return TcpStream::connect((host, port)).map_err(|e| format!("Failed to connect to {}:{} - {}", host, port, e));
+  };
+  let proxy_host = proxy.host_str().ok_or("Invalid proxy address")?;
+  let proxy_port = proxy.port_or_known_default().unwrap_or(if proxy.scheme() == "socks5" { 1080 } else { 3128 });
+  let address = (proxy_host, proxy_port)
+    .to_socket_addrs()
+    .map_err(|e| e.to_string())?
+    .next()
+    .ok_or("Proxy address did not resolve")?;
+  let mut stream = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)
+    .map_err(|e| format!("Failed to reach proxy {}:{} - {}", proxy_host, proxy_port, e))?;
+  stream.set_read_timeout(Some(CONNECT_TIMEOUT)).map_err(|e| e.to_string())?;
+  if proxy.scheme() == "socks5" {
+    socks5_connect(&mut stream, host, port)?;
+  } else {
+    write!(stream, "CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n\r\n").map_err(|e| e.to_string())?;
+    read_http_status(&stream)?;
+  }
+  stream.set_read_timeout(None).map_err(|e| e.to_string())?;
+  Ok(stream)
+}
@@ -0,0 +1,98 @@
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+use crate::matrix_api::{encode, next_txn_id, ApiError, MatrixClient};
+use crate::unified_push::PushNotification;
+
+pub const ENCRYPTED_TYPE: &str = "m.room.encrypted";
+/// How long to wait for the webview to decrypt before showing a generic notification.
+pub const DECRYPT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Decryption requests handed to the webview's crypto, waiting for the cleartext event.
+#[derive(Default)]
+pub struct PushDecryptState {
+  pending: Mutex<HashMap<String, oneshot::Sender<Option<Value>>>>,
+}
+
+impl PushDecryptState {
+  pub fn request(&self) -> (String, oneshot::Receiver<Option<Value>>) {
+    let (sender, receiver) = oneshot::channel();
+    let request_id = next_txn_id();
+    if let Ok(mut pending) = self.pending.lock() {
+      pending.insert(request_id.clone(), sender);
+    }
+    (request_id, receiver)
+  }
+
+  /// Hand back the decrypted event (or `None` when it could not be decrypted).
+  pub fn complete(&self, request_id: &str, event: Option<Value>) -> bool {
+    let sender = self.pending.lock().ok().and_then(|mut pending| pending.remove(request_id));
+    match sender {
+      Some(sender) => sender.send(event).is_ok(),
+      None => false,
+    }
+  }
+
+  pub fn cancel(&self, request_id: &str) {
+    if let Ok(mut pending) = self.pending.lock() {
+      pending.remove(request_id);
+    }
+  }
+}
+
+/// Whether the push carried only IDs (`event_id_only` pushers) or an encrypted event, so the
+/// event has to be fetched and decrypted before there is anything to show.
+pub fn needs_content(notification: &PushNotification) -> bool {
+  notification.event_id.is_some()
+    && notification.room_id.is_some()
+    && (notification.body.is_none() || notification.event_type.as_deref() == Some(ENCRYPTED_TYPE))
+}
+
+pub async fn fetch_event(client: &MatrixClient, room_id: &str, event_id: &str) -> Result<Value, ApiError> {
+  client
+    .get(&format!("/rooms/{}/event/{}", encode(room_id), encode(event_id)), &[])
+    .await
+}
+
+/// Notification text for a cleartext event, e.g. "sent an image" for attachments.
+pub fn preview(event: &Value) -> Option<String> {
+  let content = event.get("content")?;
+  let body = content
+    .pointer("/m.new_content/body")
+    .or_else(|| content.get("body"))
+    .and_then(|v| v.as_str());
+  match event.get("type").and_then(|v| v.as_str())? {
+    "m.room.message" => match content.get("msgtype").and_then(|v| v.as_str()) {
+      Some("m.image") => Some("sent an image".to_string()),
+      Some("m.video") => Some("sent a video".to_string()),
+      Some("m.audio") => Some("sent an audio message".to_string()),
+      Some("m.file") => Some(format!("sent a file: {}", body.unwrap_or("attachment"))),
+      Some("m.location") => Some("shared a location".to_string()),
+      Some("m.emote") => body.map(|body| format!("* {}", body)),
+      _ => body.map(|s| s.to_string()),
+    },
+    "m.sticker" => Some("sent a sticker".to_string()),
+    "m.call.invite" => Some("Incoming call".to_string()),
+    "m.poll.start" | "org.matrix.msc3381.poll.start" => Some("started a poll".to_string()),
+    "m.reaction" => content
+      .pointer("/m.relates_to/key")
+      .and_then(|v| v.as_str())
+      .map(|key| format!("reacted with {}", key)),
+    ENCRYPTED_TYPE => Some("Encrypted message".to_string()),
+    _ => body.map(|s| s.to_string()),
+  }
+}
+
+/// Fill in type, sender and preview from the fetched (and, where possible, decrypted) event.
+pub fn fill(notification: &mut PushNotification, event: &Value) {
+  if let Some(event_type) = event.get("type").and_then(|v| v.as_str()) {
+    notification.event_type = Some(event_type.to_string());
+  }
+  if let Some(sender) = event.get("sender").and_then(|v| v.as_str()) {
+    notification.sender = Some(sender.to_string());
+  }
+  notification.body = preview(event).or(notification.body.take());
+}
@@ -0,0 +1,485 @@
+use regex::RegexBuilder;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::account_data;
+use crate::event_cache::{self, CachedEvent};
+use crate::matrix_api::{ApiError, MatrixClient};
+
+pub const PUSH_RULES_TYPE: &str = "m.push_rules";
+const RULE_KINDS: [&str; 5] = ["override", "content", "room", "sender", "underride"];
+
+/// Per-user facts the push rule conditions are evaluated against.
+pub struct PushContext {
+  pub user_id: String,
+  pub display_name: Option<String>,
+  pub member_count: usize,
+  pub power_levels: Option<Value>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PushActions {
+  pub notify: bool,
+  pub highlight: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub sound: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub rule_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomNotificationCounts {
+  pub room_id: String,
+  pub notification_count: usize,
+  pub highlight_count: usize,
+}
+
+/// Glob match with `*` and `?`, case-insensitive.
+fn glob_match(pattern: &str, value: &str) -> bool {
+  let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+  let value: Vec<char> = value.to_lowercase().chars().collect();
+  let (mut p, mut v) = (0, 0);
+  let (mut star, mut mark) = (None, 0);
+  while v < value.len() {
+    if p < pattern.len() && (pattern[p] == '?' || pattern[p] == value[v]) {
+      p += 1;
+      v += 1;
+    } else if p < pattern.len() && pattern[p] == '*' {
+      star = Some(p);
+      mark = v;
+      p += 1;
+    } else if let Some(s) = star {
+      p = s + 1;
+      mark += 1;
+      v = mark;
+    } else {
+      return false;
+    }
+  }
+  pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Match `pattern` anywhere in `body` between word boundaries, case-insensitively. As in the
+/// spec, a boundary is the start or end of the body or any character outside `[A-Za-z0-9_]`;
+/// `*` and `?` are globs unless `literal`.
+fn word_match(pattern: &str, body: &str, literal: bool) -> bool {
+  if pattern.is_empty() {
+    return false;
+  }
+  let source: String = pattern
+    .chars()
+    .map(|c| match c {
+      '*' if !literal => ".*?".to_string(),
+      '?' if !literal => ".".to_string(),
+      c => regex::escape(c.encode_utf8(&mut [0; 4])),
+    })
+    .collect();
+  RegexBuilder::new(&format!("(?:^|[^A-Za-z0-9_])(?:{})(?:[^A-Za-z0-9_]|$)", source))
+    .case_insensitive(true)
+    .build()
+    .map(|re| re.is_match(body))
+    .unwrap_or(false)
+}
+
+/// Split a dotted key path, honouring `\.` and `\\` escapes.
+fn key_path(key: &str) -> Vec<String> {
+  let mut parts = vec![String::new()];
+  let mut chars = key.chars();
+  while let Some(c) = chars.next() {
+    match c {
+      '\\' => {
+        if let Some(next) = chars.next() {
+          parts.last_mut().unwrap().push(next);
+        }
+      }
+      '.' => parts.push(String::new()),
+      _ => parts.last_mut().unwrap().push(c),
+    }
+  }
+  parts
+}
+
+fn lookup<'a>(event: &'a Value, key: &str) -> Option<&'a Value> {
+  key_path(key).iter().try_fold(event, |value, part| value.get(part.as_str()))
+}
+
+fn sender_power(ctx: &PushContext, sender: &str) -> i64 {
+  let levels = match &ctx.power_levels {
+    Some(levels) => levels,
+    None => return 0,
+  };
+  levels
+    .get("users")
+    .and_then(|u| u.get(sender))
+    .and_then(|v| v.as_i64())
+    .or_else(|| levels.get("users_default").and_then(|v| v.as_i64()))
+    .unwrap_or(0)
+}
+
+fn member_count_matches(spec: &str, count: usize) -> bool {
+  let (op, number) = match spec.find(|c: char| c.is_ascii_digit()) {
+    Some(idx) => spec.split_at(idx),
+    None => return false,
+  };
+  let number: usize = match number.parse() {
+    Ok(number) => number,
+    Err(_) => return false,
+  };
+  match op {
+    "" | "==" => count == number,
+    "<" => count < number,
+    ">" => count > number,
+    "<=" => count <= number,
+    ">=" => count >= number,
+    _ => false,
+  }
+}
+
+fn condition_matches(condition: &Value, event: &Value, sender: &str, ctx: &PushContext) -> bool {
+  let kind = condition.get("kind").and_then(|v| v.as_str()).unwrap_or_default();
+  match kind {
+    "event_match" => {
+      let key = condition.get("key").and_then(|v| v.as_str()).unwrap_or_default();
+      let pattern = match condition.get("pattern").and_then(|v| v.as_str()) {
+        Some(pattern) => pattern,
+        None => return false,
+      };
+      match lookup(event, key).and_then(|v| v.as_str()) {
+        Some(value) if key == "content.body" => word_match(pattern, value, false),
+        Some(value) => glob_match(pattern, value),
+        None => false,
+      }
+    }
+    "event_property_is" => {
+      let key = condition.get("key").and_then(|v| v.as_str()).unwrap_or_default();
+      matches!((lookup(event, key), condition.get("value")), (Some(actual), Some(expected)) if actual == expected)
+    }
+    "event_property_contains" => {
+      let key = condition.get("key").and_then(|v| v.as_str()).unwrap_or_default();
+      match (lookup(event, key).and_then(|v| v.as_array()), condition.get("value")) {
+        (Some(items), Some(expected)) => items.contains(expected),
+        _ => false,
+      }
+    }
+    "contains_display_name" => match (&ctx.display_name, lookup(event, "content.body").and_then(|v| v.as_str())) {
+      (Some(name), Some(body)) if !name.is_empty() => word_match(name, body, true),
+      _ => false,
+    },
+    "room_member_count" => condition
+      .get("is")
+      .and_then(|v| v.as_str())
+      .map(|spec| member_count_matches(spec, ctx.member_count))
+      .unwrap_or(false),
+    "sender_notification_permission" => {
+      let key = condition.get("key").and_then(|v| v.as_str()).unwrap_or("room");
+      let required = ctx
+        .power_levels
+        .as_ref()
+        .and_then(|levels| levels.pointer(&format!("/notifications/{}", key)))
+        .and_then(|v| v.as_i64())
+        .unwrap_or(50);
+      sender_power(ctx, sender) >= required
+    }
+    _ => false,
+  }
+}
+
+fn rule_matches(kind: &str, rule: &Value, event: &CachedEvent, raw: &Value, ctx: &PushContext) -> bool {
+  let rule_id = rule.get("rule_id").and_then(|v| v.as_str()).unwrap_or_default();
+  match kind {
+    "content" => match (rule.get("pattern").and_then(|v| v.as_str()), raw.pointer("/content/body").and_then(|v| v.as_str())) {
+      (Some(pattern), Some(body)) => word_match(pattern, body, false),
+      _ => false,
+    },
+    "room" => rule_id == event.room_id,
+    "sender" => rule_id == event.sender,
+    _ => rule
+      .get("conditions")
+      .and_then(|v| v.as_array())
+      .map(|conditions| conditions.iter().all(|c| condition_matches(c, raw, &event.sender, ctx)))
+      .unwrap_or(true),
+  }
+}
+
+fn actions_of(rule: &Value) -> PushActions {
+  let mut actions = PushActions {
+    rule_id: rule.get("rule_id").and_then(|v| v.as_str()).map(|s| s.to_string()),
+    ..Default::default()
+  };
+  for action in rule.get("actions").and_then(|v| v.as_array()).into_iter().flatten() {
+    match action {
+      Value::String(name) if name == "notify" => actions.notify = true,
+      Value::Object(tweak) => match tweak.get("set_tweak").and_then(|v| v.as_str()) {
+        Some("highlight") => actions.highlight = tweak.get("value").and_then(|v| v.as_bool()).unwrap_or(true),
+        Some("sound") => actions.sound = tweak.get("value").and_then(|v| v.as_str()).map(|s| s.to_string()),
+        _ => {}
+      },
+      _ => {}
+    }
+  }
+  actions
+}
+
+/// Run the first matching enabled rule, in override/content/room/sender/underride order.
+/// `m.mentions` is honoured through the server-default `.m.rule.is_user_mention` and
+/// `.m.rule.is_room_mention` rules, which use `event_property_contains`/`event_property_is`.
+pub fn evaluate(rules: &Value, event: &CachedEvent, ctx: &PushContext) -> PushActions {
+  if event.sender == ctx.user_id {
+    return PushActions::default();
+  }
+  let raw = event.to_raw();
+  let global = rules.get("global").unwrap_or(rules);
+  for kind in RULE_KINDS {
+    for rule in global.get(kind).and_then(|v| v.as_array()).into_iter().flatten() {
+      if !rule.get("enabled").and_then(|v| v.as_bool()).unwrap_or(true) {
+        continue;
+      }
+      if rule_matches(kind, rule, event, &raw, ctx) {
+        return actions_of(rule);
+      }
+    }
+  }
+  PushActions::default()
+}
+
+pub async fn fetch_rules(client: &MatrixClient) -> Result<Value, ApiError> {
+  client.get("/pushrules/", &[]).await
+}
+
+pub fn cached_rules(conn: &Connection, account_key: &str) -> Result<Option<Value>, String> {
+  Ok(account_data::get_cached(conn, account_key, PUSH_RULES_TYPE, None)?.map(|entry| entry.content))
+}
+
+pub fn context_for_room(conn: &Connection, account_key: &str, room_id: &str, user_id: &str) -> Result<PushContext, String> {
+  Ok(PushContext {
+    user_id: user_id.to_string(),
+    display_name: event_cache::member_profile(conn, account_key, room_id, user_id)?.0,
+    member_count: event_cache::joined_member_count(conn, account_key, room_id)?,
+    power_levels: event_cache::get_state_event(conn, account_key, room_id, "m.room.power_levels", "")?.map(|e| e.content),
+  })
+}
+
+/// Timestamp of the user's newest read receipt in the room, or 0.
+fn read_up_to(conn: &Connection, account_key: &str, room_id: &str, user_id: &str) -> Result<i64, String> {
+  let mut read_up_to = 0;
+  for thread_id in ["unthreaded", "main"] {
+    if let Some(receipt) = event_cache::get_receipt(conn, account_key, room_id, user_id, thread_id)? {
+      let ts = event_cache::get_event(conn, account_key, room_id, &receipt.event_id)?
+        .map(|e| e.origin_server_ts)
+        .unwrap_or(receipt.ts);
+      read_up_to = read_up_to.max(ts);
+    }
+  }
+  Ok(read_up_to)
+}
+
+/// Unread events that the rules highlight, sent after `since`.
+pub fn unread_highlights(
+  conn: &Connection,
+  account_key: &str,
+  rules: &Value,
+  room_id: &str,
+  user_id: &str,
+  since: i64,
+) -> Result<Vec<CachedEvent>, String> {
+  let after = read_up_to(conn, account_key, room_id, user_id)?.max(since);
+  let ctx = context_for_room(conn, account_key, room_id, user_id)?;
+  Ok(
+    event_cache::events_since(conn, account_key, room_id, after)?
+      .into_iter()
+      .filter(|event| {
+        let actions = evaluate(rules, event, &ctx);
+        actions.notify && actions.highlight
+      })
+      .collect(),
+  )
+}
+
+/// Notification and highlight counts for events after the user's newest read receipt.
+pub fn room_counts(
+  conn: &Connection,
+  account_key: &str,
+  rules: &Value,
+  room_id: &str,
+  user_id: &str,
+) -> Result<RoomNotificationCounts, String> {
+  let read_up_to = read_up_to(conn, account_key, room_id, user_id)?;
+  let ctx = context_for_room(conn, account_key, room_id, user_id)?;
+  let mut counts = RoomNotificationCounts {
+    room_id: room_id.to_string(),
+    notification_count: 0,
+    highlight_count: 0,
+  };
+  for event in event_cache::events_since(conn, account_key, room_id, read_up_to)? {
+    let actions = evaluate(rules, &event, &ctx);
+    if actions.notify {
+      counts.notification_count += 1;
+      if actions.highlight {
+        counts.highlight_count += 1;
+      }
+    }
+  }
+  Ok(counts)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  fn ctx() -> PushContext {
+    PushContext {
+      user_id: "@alice:example.org".into(),
+      display_name: Some("Alice".into()),
+      member_count: 2,
+      power_levels: Some(json!({
+        "users": { "@admin:example.org": 100 },
+        "users_default": 0,
+        "notifications": { "room": 50 }
+      })),
+    }
+  }
+
+  fn event(sender: &str, content: Value) -> CachedEvent {
+    CachedEvent::from_raw(
+      "!room:example.org",
+      &json!({ "event_id": "$1", "type": "m.room.message", "sender": sender, "content": content }),
+    )
+    .unwrap()
+  }
+
+  #[test]
+  fn glob_matches_whole_value() {
+    assert!(glob_match("m.room.message", "m.room.message"));
+    assert!(glob_match("M.ROOM.*", "m.room.member"));
+    assert!(glob_match("cake*lie", "cake is a lie"));
+    assert!(glob_match("ca?e", "cake"));
+    assert!(glob_match("*", ""));
+    assert!(!glob_match("lie", "cake is a lie"));
+    assert!(!glob_match("ca?e", "cae"));
+    assert!(!glob_match("m.room.*", "m.call.invite"));
+  }
+
+  #[test]
+  fn glob_backtracks_over_stars() {
+    assert!(glob_match("a*b*c", "aXbYbZc"));
+    assert!(glob_match("*ab", "aaab"));
+    assert!(glob_match("a*?c", "abbc"));
+    assert!(!glob_match("a*b*c", "aXbYbZ"));
+    assert!(!glob_match("a*?c", "ac"));
+  }
+
+  #[test]
+  fn word_match_needs_word_boundaries() {
+    assert!(word_match("cake*lie", "The cake is a lie!", false));
+    assert!(word_match("lie", "The cake is a lie.", false));
+    assert!(!word_match("lie", "I believe you", false));
+    assert!(!word_match("foo", "foo_bar", false));
+    assert!(word_match("alice", "@alice:example.org said hi", false));
+    assert!(word_match("hello world", "oh, hello world!", false));
+    assert!(word_match("ALICE", "alice", false));
+    assert!(!word_match("", "anything", false));
+  }
+
+  #[test]
+  fn display_names_are_matched_literally() {
+    assert!(word_match("Alice", "hey alice, look", true));
+    assert!(!word_match("Alice", "Alicey", true));
+    assert!(word_match("A*ce", "ping A*ce now", true));
+    assert!(!word_match("A*ce", "ping Alice now", true));
+    assert!(word_match("Dr. Who (Bot)", "ask Dr. Who (Bot) today", true));
+  }
+
+  #[test]
+  fn member_count_operators() {
+    assert!(member_count_matches("2", 2));
+    assert!(member_count_matches("==2", 2));
+    assert!(!member_count_matches("2", 3));
+    assert!(member_count_matches("<3", 2));
+    assert!(!member_count_matches("<2", 2));
+    assert!(member_count_matches(">1", 2));
+    assert!(member_count_matches("<=2", 2));
+    assert!(member_count_matches(">=2", 2));
+    assert!(!member_count_matches(">=3", 2));
+    assert!(!member_count_matches("", 2));
+    assert!(!member_count_matches("=2", 2));
+    assert!(!member_count_matches("!=2", 3));
+  }
+
+  #[test]
+  fn key_paths_honour_escapes() {
+    assert_eq!(key_path("content.body"), vec!["content", "body"]);
+    assert_eq!(key_path("content.m\\.relates_to"), vec!["content", "m.relates_to"]);
+    assert_eq!(key_path("content.a\\\\b"), vec!["content", "a\\b"]);
+  }
+
+  #[test]
+  fn conditions_follow_the_spec() {
+    let ctx = ctx();
+    let raw = json!({
+      "type": "m.room.message",
+      "content": { "body": "hi Alice", "m.mentions": { "user_ids": ["@alice:example.org"], "room": true } }
+    });
+    let check = |condition: Value, sender: &str| condition_matches(&condition, &raw, sender, &ctx);
+    assert!(check(json!({ "kind": "event_match", "key": "type", "pattern": "m.room.message" }), "@bob:example.org"));
+    assert!(!check(json!({ "kind": "event_match", "key": "type", "pattern": "m.room" }), "@bob:example.org"));
+    assert!(!check(json!({ "kind": "event_match", "key": "content.missing", "pattern": "*" }), "@bob:example.org"));
+    assert!(check(
+      json!({ "kind": "event_property_contains", "key": "content.m\\.mentions.user_ids", "value": "@alice:example.org" }),
+      "@bob:example.org"
+    ));
+    assert!(check(json!({ "kind": "event_property_is", "key": "content.m\\.mentions.room", "value": true }), "@bob:example.org"));
+    assert!(check(json!({ "kind": "contains_display_name" }), "@bob:example.org"));
+    assert!(check(json!({ "kind": "room_member_count", "is": "2" }), "@bob:example.org"));
+    assert!(!check(json!({ "kind": "sender_notification_permission", "key": "room" }), "@bob:example.org"));
+    assert!(check(json!({ "kind": "sender_notification_permission", "key": "room" }), "@admin:example.org"));
+    assert!(!check(json!({ "kind": "unknown_condition" }), "@bob:example.org"));
+  }
+
+  #[test]
+  fn evaluate_runs_the_first_matching_rule() {
+    let rules = json!({ "global": {
+      "override": [
+        { "rule_id": ".m.rule.master", "enabled": false, "conditions": [], "actions": [] },
+        {
+          "rule_id": ".m.rule.is_user_mention",
+          "conditions": [{ "kind": "event_property_contains", "key": "content.m\\.mentions.user_ids", "value": "@alice:example.org" }],
+          "actions": ["notify", { "set_tweak": "sound", "value": "default" }, { "set_tweak": "highlight" }]
+        }
+      ],
+      "content": [
+        { "rule_id": "cake", "pattern": "cake", "actions": ["notify", { "set_tweak": "highlight", "value": false }] }
+      ],
+      "underride": [
+        {
+          "rule_id": ".m.rule.room_one_to_one",
+          "conditions": [
+            { "kind": "room_member_count", "is": "2" },
+            { "kind": "event_match", "key": "type", "pattern": "m.room.message" }
+          ],
+          "actions": ["notify", { "set_tweak": "sound", "value": "default" }]
+        }
+      ]
+    }});
+    let ctx = ctx();
+
+    let mention = evaluate(&rules, &event("@bob:example.org", json!({ "body": "hey", "m.mentions": { "user_ids": ["@alice:example.org"] } })), &ctx);
+    assert_eq!(mention.rule_id.as_deref(), Some(".m.rule.is_user_mention"));
+    assert!(mention.notify && mention.highlight);
+    assert_eq!(mention.sound.as_deref(), Some("default"));
+
+    let keyword = evaluate(&rules, &event("@bob:example.org", json!({ "body": "Cake time" })), &ctx);
+    assert_eq!(keyword.rule_id.as_deref(), Some("cake"));
+    assert!(keyword.notify && !keyword.highlight);
+
+    let direct = evaluate(&rules, &event("@bob:example.org", json!({ "body": "cupcakes" })), &ctx);
+    assert_eq!(direct.rule_id.as_deref(), Some(".m.rule.room_one_to_one"));
+    assert!(direct.notify && !direct.highlight);
+
+    let own = evaluate(&rules, &event("@alice:example.org", json!({ "body": "cake" })), &ctx);
+    assert!(!own.notify && own.rule_id.is_none());
+  }
+}
@@ -0,0 +1,210 @@
+use chrono::{DateTime, Datelike, Local, Timelike};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::matrix_api::now_millis;
+use crate::unified_push::PushNotification;
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+/// A daily quiet period; `end_minute` at or before `start_minute` runs past midnight.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QuietWindow {
+  /// 0 = Monday .. 6 = Sunday, the day the window starts on.
+  pub weekday: u8,
+  pub start_minute: u16,
+  pub end_minute: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum QuietMode {
+  /// Drop native notifications while quiet.
+  Suppress,
+  /// Hold them and show one summary when the quiet period ends.
+  #[default]
+  Batch,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DndSettings {
+  #[serde(default)]
+  pub windows: Vec<QuietWindow>,
+  #[serde(default)]
+  pub mode: QuietMode,
+  /// Manual Do Not Disturb, active until this time (ms since epoch).
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub manual_until: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DndStatus {
+  pub active: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub manual_until: Option<i64>,
+  pub held: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HeldNotification {
+  pub account_key: String,
+  pub notification: PushNotification,
+  pub held_at: i64,
+}
+
+pub fn init_quiet_hours_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS dnd_settings (
+        scope TEXT PRIMARY KEY,
+        settings_json TEXT NOT NULL
+      );
+      CREATE TABLE IF NOT EXISTS dnd_held (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        account_key TEXT NOT NULL,
+        notification_json TEXT NOT NULL,
+        held_at INTEGER NOT NULL
+      );
+    ",
+  )
+}
+
+pub fn settings(conn: &Connection) -> Result<DndSettings, String> {
+  let stored: Option<String> = conn
+    .query_row("SELECT settings_json FROM dnd_settings WHERE scope = ''", [], |row| row.get(0))
+    .optional()
+    .map_err(|e| e.to_string())?;
+  Ok(stored.and_then(|json| serde_json::from_str(&json).ok()).unwrap_or_default())
+}
+
+pub fn set_settings(conn: &Connection, value: &DndSettings) -> Result<(), String> {
+  for window in &value.windows {
+    if window.weekday > 6 || window.start_minute >= MINUTES_PER_DAY || window.end_minute > MINUTES_PER_DAY {
+      return Err("Invalid quiet hours window".to_string());
+    }
+  }
+  let json = serde_json::to_string(value).map_err(|e| e.to_string())?;
+  conn
+    .execute(
+      "INSERT INTO dnd_settings (scope, settings_json) VALUES ('', ?1)
+       ON CONFLICT(scope) DO UPDATE SET settings_json = excluded.settings_json",
+      [json],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+fn in_window(window: &QuietWindow, weekday: u8, minute: u16) -> bool {
+  let yesterday = (weekday + 6) % 7;
+  if window.start_minute < window.end_minute {
+    window.weekday == weekday && (window.start_minute..window.end_minute).contains(&minute)
+  } else {
+    (window.weekday == weekday && minute >= window.start_minute) || (window.weekday == yesterday && minute < window.end_minute)
+  }
+}
+
+/// Whether Do Not Disturb is on at `now`, manually or by schedule.
+pub fn is_quiet(settings: &DndSettings, now: DateTime<Local>) -> bool {
+  if settings.manual_until.is_some_and(|until| until > now.timestamp_millis()) {
+    return true;
+  }
+  let weekday = now.weekday().num_days_from_monday() as u8;
+  let minute = (now.hour() * 60 + now.minute()) as u16;
+  settings.windows.iter().any(|window| in_window(window, weekday, minute))
+}
+
+pub fn status(conn: &Connection) -> Result<DndStatus, String> {
+  let settings = settings(conn)?;
+  let held: i64 = conn
+    .query_row("SELECT COUNT(*) FROM dnd_held", [], |row| row.get(0))
+    .map_err(|e| e.to_string())?;
+  Ok(DndStatus {
+    active: is_quiet(&settings, Local::now()),
+    manual_until: settings.manual_until.filter(|until| *until > now_millis()),
+    held: held as usize,
+  })
+}
+
+pub fn hold(conn: &Connection, account_key: &str, notification: &PushNotification) -> Result<(), String> {
+  let json = serde_json::to_string(notification).map_err(|e| e.to_string())?;
+  conn
+    .execute(
+      "INSERT INTO dnd_held (account_key, notification_json, held_at) VALUES (?1, ?2, ?3)",
+      params![account_key, json, now_millis()],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+/// Remove and return everything held, oldest first.
+pub fn take_held(conn: &Connection) -> Result<Vec<HeldNotification>, String> {
+  let mut stmt = conn
+    .prepare("SELECT id, account_key, notification_json, held_at FROM dnd_held ORDER BY id")
+    .map_err(|e| e.to_string())?;
+  let rows = stmt
+    .query_map([], |row| {
+      Ok((
+        row.get::<_, i64>(0)?,
+        row.get::<_, String>(1)?,
+        row.get::<_, String>(2)?,
+        row.get::<_, i64>(3)?,
+      ))
+    })
+    .map_err(|e| e.to_string())?;
+  let mut out = Vec::new();
+  let mut last_id = None;
+  for row in rows {
+    let (id, account_key, json, held_at) = row.map_err(|e| e.to_string())?;
+    last_id = Some(id);
+    if let Ok(notification) = serde_json::from_str(&json) {
+      out.push(HeldNotification {
+        account_key,
+        notification,
+        held_at,
+      });
+    }
+  }
+  if let Some(last_id) = last_id {
+    conn
+      .execute("DELETE FROM dnd_held WHERE id <= ?1", [last_id])
+      .map_err(|e| e.to_string())?;
+  }
+  Ok(out)
+}
+
+/// Title and body of the notification shown when a quiet period ends.
+pub fn summary(held: &[HeldNotification]) -> Option<(String, String)> {
+  if held.is_empty() {
+    return None;
+  }
+  let mut rooms: Vec<(String, usize)> = Vec::new();
+  for item in held {
+    let name = item
+      .notification
+      .room_name
+      .clone()
+      .or_else(|| item.notification.sender_display_name.clone())
+      .or_else(|| item.notification.sender.clone())
+      .unwrap_or_else(|| "Unknown room".to_string());
+    match rooms.iter_mut().find(|(room, _)| *room == name) {
+      Some((_, count)) => *count += 1,
+      None => rooms.push((name, 1)),
+    }
+  }
+  let title = match held.len() {
+    1 => "1 notification while Do Not Disturb was on".to_string(),
+    n => format!("{} notifications while Do Not Disturb was on", n),
+  };
+  let mut parts: Vec<String> = rooms
+    .iter()
+    .take(3)
+    .map(|(room, count)| if *count > 1 { format!("{} ({})", room, count) } else { room.clone() })
+    .collect();
+  if rooms.len() > 3 {
+    parts.push(format!("and {} more", rooms.len() - 3));
+  }
+  Some((title, parts.join(", ")))
+}
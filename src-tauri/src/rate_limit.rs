@@ -0,0 +1,163 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use crate::matrix_api::{now_millis, ApiError};
+
+/// Spacing added per rate-limited response, and its ceiling.
+const MIN_INTERVAL_MS: i64 = 250;
+const MAX_INTERVAL_MS: i64 = 10_000;
+/// Successful requests shrink the spacing by this factor until it is gone.
+const RECOVERY_FACTOR: f64 = 0.8;
+/// Budgets untouched this long are forgotten.
+const IDLE_EXPIRY_MS: i64 = 10 * 60 * 1000;
+
+/// Client-side pacing for one endpoint of one account, learned from `M_LIMIT_EXCEEDED`.
+#[derive(Debug, Clone, Default)]
+struct Budget {
+  /// The server asked us to wait until then (ms since epoch).
+  blocked_until: i64,
+  /// Minimum gap between requests; grows on every 429 and decays on success.
+  interval_ms: i64,
+  /// When the next request may start.
+  next_slot: i64,
+  limited: u32,
+  last_limited_at: Option<i64>,
+  last_used_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ThrottleState {
+  pub user_id: String,
+  /// Method and path template, e.g. `PUT /rooms/{id}/send`.
+  pub endpoint: String,
+  /// How long the next request will be held back, 0 when it can go right away.
+  pub wait_ms: i64,
+  pub interval_ms: i64,
+  /// Rate-limited responses seen for this endpoint.
+  pub limited: u32,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub last_limited_at: Option<i64>,
+}
+
+fn budgets() -> &'static Mutex<HashMap<(String, String), Budget>> {
+  static BUDGETS: OnceLock<Mutex<HashMap<(String, String), Budget>>> = OnceLock::new();
+  BUDGETS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn is_id(segment: &str) -> bool {
+  ["!", "$", "@", "#", "%21", "%24", "%40", "%23"]
+    .iter()
+    .any(|sigil| segment.starts_with(sigil))
+}
+
+/// Group requests by what the server rate-limits: the path up to the action after the first
+/// ID, so every room's `/send` shares one budget and `/sync` has its own.
+pub fn endpoint(method: &str, url: &str) -> String {
+  let path = url.split("://").nth(1).and_then(|rest| rest.find('/').map(|at| &rest[at..])).unwrap_or(url);
+  let path = path.split(['?', '#']).next().unwrap_or_default();
+  let mut segments = path
+    .split('/')
+    .filter(|segment| !segment.is_empty())
+    .skip_while(|segment| matches!(*segment, "_matrix" | "client" | "media" | "v1" | "v3" | "r0" | "unstable"))
+    .peekable();
+  let mut template = Vec::new();
+  let mut seen_id = false;
+  while let Some(segment) = segments.next() {
+    if is_id(segment) {
+      template.push("{id}");
+      seen_id = true;
+      continue;
+    }
+    template.push(segment);
+    if seen_id || matches!(segment, "download" | "thumbnail" | "upload" | "create") {
+      break;
+    }
+    if segments.peek().is_none() {
+      break;
+    }
+  }
+  format!("{} /{}", method, template.join("/"))
+}
+
+/// Wait for the endpoint's budget before sending, reserving the next slot.
+pub async fn acquire(user_id: &str, endpoint: &str) {
+  let wait = {
+    let Ok(mut budgets) = budgets().lock() else {
+      return;
+    };
+    let now = now_millis();
+    budgets.retain(|_, budget| now - budget.last_used_at < IDLE_EXPIRY_MS || budget.blocked_until > now);
+    let budget = budgets.entry((user_id.to_string(), endpoint.to_string())).or_default();
+    let start = now.max(budget.blocked_until).max(budget.next_slot);
+    budget.next_slot = start + budget.interval_ms;
+    budget.last_used_at = now;
+    start - now
+  };
+  if wait > 0 {
+    tokio::time::sleep(Duration::from_millis(wait as u64)).await;
+  }
+}
+
+/// How long the server asked us to back off, from `retry_after_ms` or the `Retry-After` header
+/// (copied into the body by the HTTP layer).
+pub fn retry_after_ms(err: &ApiError) -> Option<i64> {
+  match err {
+    ApiError::Http { status, errcode, body, .. } if *status == 429 || errcode.as_deref() == Some("M_LIMIT_EXCEEDED") => Some(
+      body
+        .get("retry_after_ms")
+        .and_then(|v| v.as_i64())
+        .unwrap_or(MIN_INTERVAL_MS * 4),
+    ),
+    _ => None,
+  }
+}
+
+/// Adjust the budget from the response: back off on a rate limit, speed up again on success.
+pub fn record<T>(user_id: &str, endpoint: &str, result: &Result<T, ApiError>) {
+  let Ok(mut budgets) = budgets().lock() else {
+    return;
+  };
+  let key = (user_id.to_string(), endpoint.to_string());
+  match result.as_ref().err().and_then(retry_after_ms) {
+    Some(retry_after) => {
+      let now = now_millis();
+      let budget = budgets.entry(key).or_default();
+      budget.blocked_until = budget.blocked_until.max(now + retry_after);
+      budget.interval_ms = (budget.interval_ms * 2).clamp(MIN_INTERVAL_MS, MAX_INTERVAL_MS);
+      budget.next_slot = budget.next_slot.max(budget.blocked_until);
+      budget.limited += 1;
+      budget.last_limited_at = Some(now);
+    }
+    None => {
+      if let Some(budget) = budgets.get_mut(&key) {
+        let interval = (budget.interval_ms as f64 * RECOVERY_FACTOR) as i64;
+        budget.interval_ms = if interval < MIN_INTERVAL_MS / 2 { 0 } else { interval };
+      }
+    }
+  }
+}
+
+/// Endpoints currently paced or recently rate-limited, for the UI.
+pub fn snapshot() -> Vec<ThrottleState> {
+  let Ok(budgets) = budgets().lock() else {
+    return Vec::new();
+  };
+  let now = now_millis();
+  let mut out: Vec<ThrottleState> = budgets
+    .iter()
+    .filter(|(_, budget)| budget.limited > 0)
+    .map(|((user_id, endpoint), budget)| ThrottleState {
+      user_id: user_id.clone(),
+      endpoint: endpoint.clone(),
+      wait_ms: (budget.blocked_until.max(budget.next_slot) - now).max(0),
+      interval_ms: budget.interval_ms,
+      limited: budget.limited,
+      last_limited_at: budget.last_limited_at,
+    })
+    .collect();
+  out.sort_by(|a, b| b.wait_ms.cmp(&a.wait_ms).then_with(|| a.endpoint.cmp(&b.endpoint)));
+  out
+}
@@ -0,0 +1,219 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::event_cache;
+use crate::matrix_api::now_millis;
+
+const EXCERPT_CHARS: usize = 280;
+
+/// Token the room list uses for the read-later smart collection.
+pub const COLLECTION_TOKEN: &str = "smart:read-later";
+
+/// A message or link parked for later.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadLaterItem {
+  pub id: i64,
+  pub account_key: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub room_id: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub event_id: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub url: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub title: Option<String>,
+  /// Start of the message text, or the page description for links.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub excerpt: Option<String>,
+  pub read: bool,
+  /// Saved copy of the linked page, readable offline.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub snapshot_path: Option<String>,
+  pub created_at: i64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub read_at: Option<i64>,
+}
+
+pub fn init_read_later_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS read_later (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        account_key TEXT NOT NULL,
+        room_id TEXT,
+        event_id TEXT,
+        url TEXT,
+        title TEXT,
+        excerpt TEXT,
+        read INTEGER NOT NULL DEFAULT 0,
+        snapshot_path TEXT,
+        created_at INTEGER NOT NULL,
+        read_at INTEGER
+      );
+      CREATE INDEX IF NOT EXISTS read_later_unread ON read_later (read, created_at);
+    ",
+  )
+}
+
+const COLUMNS: &str = "id, account_key, room_id, event_id, url, title, excerpt, read, snapshot_path, created_at, read_at";
+
+fn row_to_item(row: &rusqlite::Row) -> rusqlite::Result<ReadLaterItem> {
+  let read: i64 = row.get(7)?;
+  Ok(ReadLaterItem {
+    id: row.get(0)?,
+    account_key: row.get(1)?,
+    room_id: row.get(2)?,
+    event_id: row.get(3)?,
+    url: row.get(4)?,
+    title: row.get(5)?,
+    excerpt: row.get(6)?,
+    read: read != 0,
+    snapshot_path: row.get(8)?,
+    created_at: row.get(9)?,
+    read_at: row.get(10)?,
+  })
+}
+
+pub fn excerpt(text: &str) -> Option<String> {
+  let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+  if text.is_empty() {
+    return None;
+  }
+  Some(if text.chars().count() > EXCERPT_CHARS {
+    format!("{}…", text.chars().take(EXCERPT_CHARS).collect::<String>())
+  } else {
+    text
+  })
+}
+
+/// First http(s) link in a message body.
+pub fn first_link(body: &str) -> Option<String> {
+  body
+    .split_whitespace()
+    .map(|word| word.trim_matches(|c: char| matches!(c, '<' | '>' | '(' | ')' | '"' | '\'' | ',' | '.')))
+    .find(|word| word.starts_with("https://") || word.starts_with("http://"))
+    .map(|word| word.to_string())
+}
+
+/// Room, body excerpt and first link of a message cached for the account.
+pub fn describe_event(
+  conn: &Connection,
+  account_key: &str,
+  event_id: &str,
+) -> Result<Option<(String, Option<String>, Option<String>)>, String> {
+  let Some(event) = event_cache::find_event(conn, account_key, event_id)? else {
+    return Ok(None);
+  };
+  let body = event.content.get("body").and_then(|v| v.as_str()).unwrap_or_default();
+  Ok(Some((event.room_id.clone(), excerpt(body), first_link(body))))
+}
+
+fn existing(conn: &Connection, account_key: &str, event_id: Option<&str>, url: Option<&str>) -> Result<Option<ReadLaterItem>, String> {
+  conn
+    .query_row(
+      &format!(
+        "SELECT {} FROM read_later WHERE account_key = ?1
+           AND ((?2 IS NOT NULL AND event_id = ?2) OR (?2 IS NULL AND event_id IS NULL AND url = ?3))
+         LIMIT 1",
+        COLUMNS
+      ),
+      params![account_key, event_id, url],
+      row_to_item,
+    )
+    .optional()
+    .map_err(|e| e.to_string())
+}
+
+/// Park a message or link; adding the same one again marks it unread instead of duplicating it.
+pub fn add(
+  conn: &Connection,
+  account_key: &str,
+  room_id: Option<&str>,
+  event_id: Option<&str>,
+  url: Option<&str>,
+  title: Option<&str>,
+  excerpt: Option<&str>,
+) -> Result<ReadLaterItem, String> {
+  if event_id.is_none() && url.is_none() {
+    return Err("Nothing to save: pass an event or a link".into());
+  }
+  if let Some(item) = existing(conn, account_key, event_id, url)? {
+    set_read(conn, item.id, false)?;
+    return get(conn, item.id)?.ok_or_else(|| "Read-later item not found".to_string());
+  }
+  conn
+    .execute(
+      "INSERT INTO read_later (account_key, room_id, event_id, url, title, excerpt, read, created_at)
+       VALUES (?1, ?2, ?3, ?4, ?5, ?6, 0, ?7)",
+      params![account_key, room_id, event_id, url, title, excerpt, now_millis()],
+    )
+    .map_err(|e| e.to_string())?;
+  get(conn, conn.last_insert_rowid())?.ok_or_else(|| "Read-later item not saved".to_string())
+}
+
+pub fn get(conn: &Connection, id: i64) -> Result<Option<ReadLaterItem>, String> {
+  conn
+    .query_row(
+      &format!("SELECT {} FROM read_later WHERE id = ?1", COLUMNS),
+      params![id],
+      row_to_item,
+    )
+    .optional()
+    .map_err(|e| e.to_string())
+}
+
+/// Newest first; unread ones only with `unread_only`.
+pub fn list(conn: &Connection, account_key: Option<&str>, unread_only: bool) -> Result<Vec<ReadLaterItem>, String> {
+  let mut stmt = conn
+    .prepare(&format!(
+      "SELECT {} FROM read_later WHERE (?1 IS NULL OR account_key = ?1) AND (NOT ?2 OR read = 0) ORDER BY created_at DESC",
+      COLUMNS
+    ))
+    .map_err(|e| e.to_string())?;
+  let rows = stmt
+    .query_map(params![account_key, unread_only], row_to_item)
+    .map_err(|e| e.to_string())?;
+  Ok(rows.flatten().collect())
+}
+
+pub fn unread_count(conn: &Connection, account_key: Option<&str>) -> Result<usize, String> {
+  conn
+    .query_row(
+      "SELECT COUNT(*) FROM read_later WHERE read = 0 AND (?1 IS NULL OR account_key = ?1)",
+      params![account_key],
+      |row| row.get(0),
+    )
+    .map_err(|e| e.to_string())
+}
+
+pub fn set_read(conn: &Connection, id: i64, read: bool) -> Result<(), String> {
+  let updated = conn
+    .execute(
+      "UPDATE read_later SET read = ?2, read_at = CASE WHEN ?2 THEN ?3 ELSE NULL END WHERE id = ?1",
+      params![id, read, now_millis()],
+    )
+    .map_err(|e| e.to_string())?;
+  if updated == 0 {
+    return Err("Read-later item not found".into());
+  }
+  Ok(())
+}
+
+pub fn set_snapshot(conn: &Connection, id: i64, path: &str, title: Option<&str>, excerpt: Option<&str>) -> Result<(), String> {
+  conn
+    .execute(
+      "UPDATE read_later SET snapshot_path = ?2, title = COALESCE(title, ?3), excerpt = COALESCE(excerpt, ?4) WHERE id = ?1",
+      params![id, path, title, excerpt],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+/// Delete an item, returning it so its snapshot can be removed too.
+pub fn remove(conn: &Connection, id: i64) -> Result<Option<ReadLaterItem>, String> {
+  let item = get(conn, id)?;
+  conn
+    .execute("DELETE FROM read_later WHERE id = ?1", params![id])
+    .map_err(|e| e.to_string())?;
+  Ok(item)
+}
@@ -0,0 +1,212 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::event_cache;
+use crate::matrix_api::now_millis;
+
+const PREVIEW_CHARS: usize = 140;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ReminderStatus {
+  Pending,
+  /// The notification was shown; the reminder stays open until completed or snoozed.
+  Fired,
+  Done,
+}
+
+impl ReminderStatus {
+  fn as_str(self) -> &'static str {
+    match self {
+      ReminderStatus::Pending => "pending",
+      ReminderStatus::Fired => "fired",
+      ReminderStatus::Done => "done",
+    }
+  }
+
+  fn parse(value: &str) -> Self {
+    match value {
+      "fired" => ReminderStatus::Fired,
+      "done" => ReminderStatus::Done,
+      _ => ReminderStatus::Pending,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Reminder {
+  pub id: i64,
+  pub account_key: String,
+  pub room_id: String,
+  pub event_id: String,
+  /// ms since epoch.
+  pub remind_at: i64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub note: Option<String>,
+  /// Start of the message text when the reminder was set.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub preview: Option<String>,
+  pub status: ReminderStatus,
+  pub created_at: i64,
+}
+
+impl Reminder {
+  /// Notification title and body.
+  pub fn describe(&self) -> (String, String) {
+    let title = match &self.note {
+      Some(note) if !note.trim().is_empty() => format!("Reminder: {}", note.trim()),
+      _ => "Reminder".to_string(),
+    };
+    let body = self.preview.clone().unwrap_or_else(|| "Open the message".to_string());
+    (title, body)
+  }
+}
+
+pub fn init_reminders_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS reminders (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        account_key TEXT NOT NULL,
+        room_id TEXT NOT NULL,
+        event_id TEXT NOT NULL,
+        remind_at INTEGER NOT NULL,
+        note TEXT,
+        preview TEXT,
+        status TEXT NOT NULL,
+        created_at INTEGER NOT NULL
+      );
+      CREATE INDEX IF NOT EXISTS reminders_due ON reminders (status, remind_at);
+    ",
+  )
+}
+
+const COLUMNS: &str = "id, account_key, room_id, event_id, remind_at, note, preview, status, created_at";
+
+fn row_to_reminder(row: &rusqlite::Row) -> rusqlite::Result<Reminder> {
+  let status: String = row.get(7)?;
+  Ok(Reminder {
+    id: row.get(0)?,
+    account_key: row.get(1)?,
+    room_id: row.get(2)?,
+    event_id: row.get(3)?,
+    remind_at: row.get(4)?,
+    note: row.get(5)?,
+    preview: row.get(6)?,
+    status: ReminderStatus::parse(&status),
+    created_at: row.get(8)?,
+  })
+}
+
+fn preview(conn: &Connection, account_key: &str, room_id: &str, event_id: &str) -> Result<Option<String>, String> {
+  let Some(event) = event_cache::get_event(conn, account_key, room_id, event_id)? else {
+    return Ok(None);
+  };
+  let body = event.content.get("body").and_then(|v| v.as_str()).unwrap_or_default();
+  let body = body.split_whitespace().collect::<Vec<_>>().join(" ");
+  if body.is_empty() {
+    return Ok(None);
+  }
+  Ok(Some(if body.chars().count() > PREVIEW_CHARS {
+    format!("{}…", body.chars().take(PREVIEW_CHARS).collect::<String>())
+  } else {
+    body
+  }))
+}
+
+pub fn set(
+  conn: &Connection,
+  account_key: &str,
+  room_id: &str,
+  event_id: &str,
+  remind_at: i64,
+  note: Option<&str>,
+) -> Result<Reminder, String> {
+  let preview = preview(conn, account_key, room_id, event_id)?;
+  conn
+    .execute(
+      "INSERT INTO reminders (account_key, room_id, event_id, remind_at, note, preview, status, created_at)
+       VALUES (?1, ?2, ?3, ?4, ?5, ?6, 'pending', ?7)",
+      params![account_key, room_id, event_id, remind_at, note, preview, now_millis()],
+    )
+    .map_err(|e| e.to_string())?;
+  get(conn, conn.last_insert_rowid())?.ok_or_else(|| "Reminder not saved".to_string())
+}
+
+pub fn get(conn: &Connection, id: i64) -> Result<Option<Reminder>, String> {
+  conn
+    .query_row(
+      &format!("SELECT {} FROM reminders WHERE id = ?1", COLUMNS),
+      params![id],
+      row_to_reminder,
+    )
+    .optional()
+    .map_err(|e| e.to_string())
+}
+
+/// Open reminders (and completed ones with `include_done`), soonest first.
+pub fn list(conn: &Connection, account_key: Option<&str>, include_done: bool) -> Result<Vec<Reminder>, String> {
+  let mut stmt = conn
+    .prepare(&format!(
+      "SELECT {} FROM reminders WHERE (?1 IS NULL OR account_key = ?1) AND (?2 OR status != 'done') ORDER BY remind_at",
+      COLUMNS
+    ))
+    .map_err(|e| e.to_string())?;
+  let rows = stmt
+    .query_map(params![account_key, include_done], row_to_reminder)
+    .map_err(|e| e.to_string())?;
+  Ok(rows.flatten().collect())
+}
+
+pub fn due(conn: &Connection, now: i64) -> Result<Vec<Reminder>, String> {
+  let mut stmt = conn
+    .prepare(&format!(
+      "SELECT {} FROM reminders WHERE status = 'pending' AND remind_at <= ?1 ORDER BY remind_at",
+      COLUMNS
+    ))
+    .map_err(|e| e.to_string())?;
+  let rows = stmt.query_map(params![now], row_to_reminder).map_err(|e| e.to_string())?;
+  Ok(rows.flatten().collect())
+}
+
+fn set_status(conn: &Connection, id: i64, status: ReminderStatus) -> Result<(), String> {
+  let updated = conn
+    .execute(
+      "UPDATE reminders SET status = ?2 WHERE id = ?1",
+      params![id, status.as_str()],
+    )
+    .map_err(|e| e.to_string())?;
+  if updated == 0 {
+    return Err("Reminder not found".into());
+  }
+  Ok(())
+}
+
+pub fn mark_fired(conn: &Connection, id: i64) -> Result<(), String> {
+  set_status(conn, id, ReminderStatus::Fired)
+}
+
+pub fn complete(conn: &Connection, id: i64) -> Result<(), String> {
+  set_status(conn, id, ReminderStatus::Done)
+}
+
+/// Remind again in `minutes`.
+pub fn snooze(conn: &Connection, id: i64, minutes: u32) -> Result<Reminder, String> {
+  let updated = conn
+    .execute(
+      "UPDATE reminders SET status = 'pending', remind_at = ?2 WHERE id = ?1",
+      params![id, now_millis() + minutes.max(1) as i64 * 60_000],
+    )
+    .map_err(|e| e.to_string())?;
+  if updated == 0 {
+    return Err("Reminder not found".into());
+  }
+  get(conn, id)?.ok_or_else(|| "Reminder not found".to_string())
+}
+
+pub fn delete(conn: &Connection, id: i64) -> Result<(), String> {
+  conn
+    .execute("DELETE FROM reminders WHERE id = ?1", params![id])
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
@@ -0,0 +1,143 @@
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::matrix_api::{encode, now_millis, ApiError, MatrixClient};
+
+const MAX_REPORT_ATTEMPTS: i64 = 20;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueuedReport {
+  pub id: i64,
+  pub account_key: String,
+  pub room_id: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub event_id: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub score: Option<i64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub reason: Option<String>,
+  pub queued_at: i64,
+  pub attempts: i64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub last_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReportOutcome {
+  pub delivered: bool,
+  pub queued: bool,
+}
+
+pub fn init_reports_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS report_queue (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        account_key TEXT NOT NULL,
+        room_id TEXT NOT NULL,
+        event_id TEXT,
+        score INTEGER,
+        reason TEXT,
+        queued_at INTEGER NOT NULL,
+        attempts INTEGER NOT NULL DEFAULT 0,
+        last_error TEXT
+      );
+    ",
+  )
+}
+
+/// Report a single event (`event_id` set) or a whole room (`event_id` empty).
+pub async fn send_report(
+  client: &MatrixClient,
+  room_id: &str,
+  event_id: Option<&str>,
+  score: Option<i64>,
+  reason: Option<&str>,
+) -> Result<Value, ApiError> {
+  let mut body = json!({});
+  if let Some(reason) = reason {
+    body["reason"] = json!(reason);
+  }
+  match event_id {
+    Some(event_id) => {
+      if let Some(score) = score {
+        body["score"] = json!(score.clamp(-100, 0));
+      }
+      client
+        .post(&format!("/rooms/{}/report/{}", encode(room_id), encode(event_id)), &body)
+        .await
+    }
+    None => client.post(&format!("/rooms/{}/report", encode(room_id)), &body).await,
+  }
+}
+
+pub fn enqueue(
+  conn: &Connection,
+  account_key: &str,
+  room_id: &str,
+  event_id: Option<&str>,
+  score: Option<i64>,
+  reason: Option<&str>,
+  error: &str,
+) -> Result<(), String> {
+  conn
+    .execute(
+      "INSERT INTO report_queue (account_key, room_id, event_id, score, reason, queued_at, attempts, last_error)
+       VALUES (?1, ?2, ?3, ?4, ?5, ?6, 1, ?7)",
+      params![account_key, room_id, event_id, score, reason, now_millis(), error],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+pub fn pending(conn: &Connection) -> Result<Vec<QueuedReport>, String> {
+  let mut stmt = conn
+    .prepare(
+      "SELECT id, account_key, room_id, event_id, score, reason, queued_at, attempts, last_error
+       FROM report_queue ORDER BY queued_at ASC",
+    )
+    .map_err(|e| e.to_string())?;
+  let rows = stmt
+    .query_map([], |row| {
+      Ok(QueuedReport {
+        id: row.get(0)?,
+        account_key: row.get(1)?,
+        room_id: row.get(2)?,
+        event_id: row.get(3)?,
+        score: row.get(4)?,
+        reason: row.get(5)?,
+        queued_at: row.get(6)?,
+        attempts: row.get(7)?,
+        last_error: row.get(8)?,
+      })
+    })
+    .map_err(|e| e.to_string())?;
+  let mut out = Vec::new();
+  for row in rows {
+    if let Ok(report) = row { out.push(report); }
+  }
+  Ok(out)
+}
+
+pub fn remove(conn: &Connection, id: i64) -> Result<(), String> {
+  conn
+    .execute("DELETE FROM report_queue WHERE id = ?1", [id])
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+/// Bump the attempt counter; reports that keep failing are eventually dropped.
+pub fn mark_failed(conn: &Connection, id: i64, error: &str) -> Result<(), String> {
+  conn
+    .execute(
+      "UPDATE report_queue SET attempts = attempts + 1, last_error = ?1 WHERE id = ?2",
+      params![error, id],
+    )
+    .map_err(|e| e.to_string())?;
+  conn
+    .execute("DELETE FROM report_queue WHERE attempts >= ?1", [MAX_REPORT_ATTEMPTS])
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
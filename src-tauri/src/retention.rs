@@ -0,0 +1,411 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::matrix_api::now_millis;
+use crate::media_cache;
+
+const DAY_MS: i64 = 24 * 60 * 60 * 1000;
+
+/// Events that survive retention in room `?1`: the "important" smart collection (tag or star
+/// reactions), read-later items and messages with an open reminder.
+const PROTECTED_EVENTS: &str = "SELECT event_id FROM message_index WHERE room_id = ?1
+    AND (tags_json LIKE '%\"important\"%' OR reactions_json LIKE '%\"⭐\"%' OR reactions_json LIKE '%\"🔥\"%' OR reactions_json LIKE '%\"❗\"%')
+  UNION SELECT event_id FROM read_later WHERE room_id = ?1 AND event_id IS NOT NULL
+  UNION SELECT event_id FROM reminders WHERE room_id = ?1 AND status != 'done'";
+
+/// `PROTECTED_EVENTS` across all rooms, for index-wide pruning.
+const PROTECTED_ANY_ROOM: &str = "SELECT event_id FROM message_index
+    WHERE tags_json LIKE '%\"important\"%' OR reactions_json LIKE '%\"⭐\"%' OR reactions_json LIKE '%\"🔥\"%' OR reactions_json LIKE '%\"❗\"%'
+  UNION SELECT event_id FROM read_later WHERE event_id IS NOT NULL
+  UNION SELECT event_id FROM reminders WHERE status != 'done'";
+
+/// Rounds of dropping the oldest tenth of messages before giving up on `max_db_bytes`.
+const MAX_SHRINK_ROUNDS: usize = 20;
+
+/// Local copies of a room's messages older than `max_age_days` are purged; nothing is
+/// removed from the server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RetentionPolicy {
+  pub room_id: String,
+  pub max_age_days: u32,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub last_run_at: Option<i64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RetentionReport {
+  pub room_id: String,
+  pub events: usize,
+  pub messages: usize,
+  pub media: usize,
+  pub media_files: usize,
+}
+
+impl RetentionReport {
+  pub fn is_empty(&self) -> bool {
+    self.events == 0 && self.messages == 0 && self.media == 0 && self.media_files == 0
+  }
+}
+
+/// Limits on the whole local index, evaluated on startup and by `prune_index`. Unset limits
+/// keep everything; protected messages are never pruned.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IndexPruningPolicy {
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub max_age_days: Option<u32>,
+  /// Newest messages, media items and cached events kept per room.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub max_rows_per_room: Option<u32>,
+  /// Target size of the index database file.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub max_db_bytes: Option<u64>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub last_run_at: Option<i64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PruneReport {
+  pub events: usize,
+  pub messages: usize,
+  pub media: usize,
+  pub size_before: u64,
+  pub size_after: u64,
+}
+
+impl PruneReport {
+  pub fn removed(&self) -> usize {
+    self.events + self.messages + self.media
+  }
+}
+
+pub fn init_retention_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS room_retention (
+        room_id TEXT PRIMARY KEY,
+        max_age_days INTEGER NOT NULL,
+        last_run_at INTEGER
+      );
+      CREATE TABLE IF NOT EXISTS index_pruning (
+        id INTEGER PRIMARY KEY CHECK (id = 1),
+        policy_json TEXT NOT NULL,
+        last_run_at INTEGER
+      );
+    ",
+  )
+}
+
+fn row_to_policy(row: &rusqlite::Row) -> rusqlite::Result<RetentionPolicy> {
+  Ok(RetentionPolicy {
+    room_id: row.get(0)?,
+    max_age_days: row.get(1)?,
+    last_run_at: row.get(2)?,
+  })
+}
+
+pub fn policy(conn: &Connection, room_id: &str) -> Result<Option<RetentionPolicy>, String> {
+  conn
+    .query_row(
+      "SELECT room_id, max_age_days, last_run_at FROM room_retention WHERE room_id = ?1",
+      params![room_id],
+      row_to_policy,
+    )
+    .optional()
+    .map_err(|e| e.to_string())
+}
+
+pub fn policies(conn: &Connection) -> Result<Vec<RetentionPolicy>, String> {
+  let mut stmt = conn
+    .prepare("SELECT room_id, max_age_days, last_run_at FROM room_retention ORDER BY room_id")
+    .map_err(|e| e.to_string())?;
+  let rows = stmt.query_map([], row_to_policy).map_err(|e| e.to_string())?;
+  Ok(rows.flatten().collect())
+}
+
+/// Set the room's retention in days; `None` keeps local copies forever.
+pub fn set_policy(conn: &Connection, room_id: &str, max_age_days: Option<u32>) -> Result<Option<RetentionPolicy>, String> {
+  match max_age_days {
+    Some(days) => {
+      conn
+        .execute(
+          "INSERT INTO room_retention (room_id, max_age_days) VALUES (?1, ?2)
+           ON CONFLICT(room_id) DO UPDATE SET max_age_days = excluded.max_age_days",
+          params![room_id, days.max(1)],
+        )
+        .map_err(|e| e.to_string())?;
+    }
+    None => {
+      conn
+        .execute("DELETE FROM room_retention WHERE room_id = ?1", params![room_id])
+        .map_err(|e| e.to_string())?;
+    }
+  }
+  policy(conn, room_id)
+}
+
+/// Cached media belonging to old, unprotected media in the room, thumbnails included.
+fn expired_media_keys(conn: &Connection, room_id: &str, cutoff: i64) -> Result<Vec<String>, String> {
+  let mut stmt = conn
+    .prepare(&format!(
+      "SELECT mxc_url, thumbnail_mxc FROM media_index WHERE room_id = ?1 AND timestamp < ?2 AND event_id NOT IN ({})",
+      PROTECTED_EVENTS
+    ))
+    .map_err(|e| e.to_string())?;
+  let uris: Vec<String> = stmt
+    .query_map(params![room_id, cutoff], |row| {
+      Ok([row.get::<_, Option<String>>(0)?, row.get::<_, Option<String>>(1)?])
+    })
+    .map_err(|e| e.to_string())?
+    .flatten()
+    .flatten()
+    .flatten()
+    .collect();
+  let mut keys_stmt = conn
+    .prepare("SELECT cache_key FROM media_cache WHERE cache_key = ?1 OR substr(cache_key, 1, length(?1) + 1) = ?1 || '#'")
+    .map_err(|e| e.to_string())?;
+  let mut keys = Vec::new();
+  for uri in uris {
+    let rows = keys_stmt
+      .query_map(params![uri], |row| row.get::<_, String>(0))
+      .map_err(|e| e.to_string())?;
+    keys.extend(rows.flatten());
+  }
+  keys.sort();
+  keys.dedup();
+  Ok(keys)
+}
+
+/// Purge the room's local copies older than its policy: cached events (state is kept),
+/// search index rows and cached media files.
+pub fn enforce(conn: &Connection, media_dir: &Path, policy: &RetentionPolicy) -> Result<RetentionReport, String> {
+  let now = now_millis();
+  let cutoff = now - policy.max_age_days.max(1) as i64 * DAY_MS;
+  let room_id = policy.room_id.as_str();
+  let media_keys = expired_media_keys(conn, room_id, cutoff)?;
+  let tx = conn.unchecked_transaction().map_err(|e| e.to_string())?;
+  let media = tx
+    .execute(
+      &format!(
+        "DELETE FROM media_index WHERE room_id = ?1 AND timestamp < ?2 AND event_id NOT IN ({})",
+        PROTECTED_EVENTS
+      ),
+      params![room_id, cutoff],
+    )
+    .map_err(|e| e.to_string())?;
+  let events = tx
+    .execute(
+      &format!(
+        "DELETE FROM event_cache WHERE room_id = ?1 AND origin_server_ts < ?2 AND state_key IS NULL
+           AND event_id NOT IN ({})",
+        PROTECTED_EVENTS
+      ),
+      params![room_id, cutoff],
+    )
+    .map_err(|e| e.to_string())?;
+  // Last, since the protected set is read from the message index itself.
+  let messages = tx
+    .execute(
+      &format!(
+        "DELETE FROM message_index WHERE room_id = ?1 AND timestamp < ?2 AND event_id NOT IN ({})",
+        PROTECTED_EVENTS
+      ),
+      params![room_id, cutoff],
+    )
+    .map_err(|e| e.to_string())?;
+  tx.execute(
+    "UPDATE room_retention SET last_run_at = ?2 WHERE room_id = ?1",
+    params![room_id, now],
+  )
+  .map_err(|e| e.to_string())?;
+  tx.commit().map_err(|e| e.to_string())?;
+  for cache_key in &media_keys {
+    media_cache::remove(conn, media_dir, cache_key)?;
+  }
+  Ok(RetentionReport {
+    room_id: room_id.to_string(),
+    events,
+    messages,
+    media,
+    media_files: media_keys.len(),
+  })
+}
+
+pub fn pruning_policy(conn: &Connection) -> Result<IndexPruningPolicy, String> {
+  let stored: Option<(String, Option<i64>)> = conn
+    .query_row("SELECT policy_json, last_run_at FROM index_pruning WHERE id = 1", [], |row| {
+      Ok((row.get(0)?, row.get(1)?))
+    })
+    .optional()
+    .map_err(|e| e.to_string())?;
+  Ok(match stored {
+    Some((json, last_run_at)) => IndexPruningPolicy {
+      last_run_at,
+      ..serde_json::from_str(&json).unwrap_or_default()
+    },
+    None => IndexPruningPolicy::default(),
+  })
+}
+
+pub fn set_pruning_policy(conn: &Connection, policy: &IndexPruningPolicy) -> Result<(), String> {
+  let json = serde_json::to_string(&IndexPruningPolicy {
+    last_run_at: None,
+    ..policy.clone()
+  })
+  .map_err(|e| e.to_string())?;
+  conn
+    .execute(
+      "INSERT INTO index_pruning (id, policy_json) VALUES (1, ?1)
+       ON CONFLICT(id) DO UPDATE SET policy_json = excluded.policy_json",
+      [json],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+fn db_size(conn: &Connection) -> Result<u64, String> {
+  let pages: i64 = conn.query_row("PRAGMA page_count", [], |row| row.get(0)).map_err(|e| e.to_string())?;
+  let page_size: i64 = conn.query_row("PRAGMA page_size", [], |row| row.get(0)).map_err(|e| e.to_string())?;
+  Ok((pages * page_size) as u64)
+}
+
+/// Hand freed pages back to the filesystem. The first run switches the database to
+/// incremental auto-vacuum, which takes one full VACUUM.
+fn vacuum(conn: &Connection) -> Result<(), String> {
+  let mode: i64 = conn.query_row("PRAGMA auto_vacuum", [], |row| row.get(0)).map_err(|e| e.to_string())?;
+  let sql = if mode == 2 { "PRAGMA incremental_vacuum;" } else { "PRAGMA auto_vacuum = INCREMENTAL; VACUUM;" };
+  conn.execute_batch(sql).map_err(|e| e.to_string())
+}
+
+/// Unprotected rows older than `cutoff` in every room; room state is kept.
+fn delete_older(conn: &Connection, cutoff: i64, report: &mut PruneReport) -> Result<(), String> {
+  let tx = conn.unchecked_transaction().map_err(|e| e.to_string())?;
+  report.media += tx
+    .execute(
+      &format!("DELETE FROM media_index WHERE timestamp < ?1 AND event_id NOT IN ({})", PROTECTED_ANY_ROOM),
+      params![cutoff],
+    )
+    .map_err(|e| e.to_string())?;
+  report.events += tx
+    .execute(
+      &format!(
+        "DELETE FROM event_cache WHERE origin_server_ts < ?1 AND state_key IS NULL AND event_id NOT IN ({})",
+        PROTECTED_ANY_ROOM
+      ),
+      params![cutoff],
+    )
+    .map_err(|e| e.to_string())?;
+  report.messages += tx
+    .execute(
+      &format!("DELETE FROM message_index WHERE timestamp < ?1 AND event_id NOT IN ({})", PROTECTED_ANY_ROOM),
+      params![cutoff],
+    )
+    .map_err(|e| e.to_string())?;
+  tx.commit().map_err(|e| e.to_string())
+}
+
+/// Unprotected rows past the newest `max_rows` of each room (per account in the index tables).
+fn delete_beyond_rows(conn: &Connection, max_rows: u32, report: &mut PruneReport) -> Result<(), String> {
+  let excess = |table: &str, partition: &str, order: &str, filter: &str| {
+    format!(
+      "DELETE FROM {0} WHERE rowid IN (
+         SELECT id FROM (
+           SELECT rowid AS id, ROW_NUMBER() OVER (PARTITION BY {1} ORDER BY {2} DESC) AS n FROM {0} WHERE {3}
+         ) WHERE n > ?1
+       ) AND event_id NOT IN ({4})",
+      table, partition, order, filter, PROTECTED_ANY_ROOM
+    )
+  };
+  let tx = conn.unchecked_transaction().map_err(|e| e.to_string())?;
+  report.media += tx
+    .execute(&excess("media_index", "account_key, room_id", "timestamp", "1"), params![max_rows])
+    .map_err(|e| e.to_string())?;
+  report.events += tx
+    .execute(&excess("event_cache", "room_id", "origin_server_ts", "state_key IS NULL"), params![max_rows])
+    .map_err(|e| e.to_string())?;
+  report.messages += tx
+    .execute(&excess("message_index", "account_key, room_id", "timestamp", "1"), params![max_rows])
+    .map_err(|e| e.to_string())?;
+  tx.commit().map_err(|e| e.to_string())
+}
+
+/// Drop the oldest tenth of prunable messages (and older media and events) until the
+/// database fits in `max_bytes` or nothing prunable is left.
+fn shrink_to(conn: &Connection, max_bytes: u64, report: &mut PruneReport) -> Result<(), String> {
+  for _ in 0..MAX_SHRINK_ROUNDS {
+    vacuum(conn)?;
+    if db_size(conn)? <= max_bytes {
+      break;
+    }
+    let prunable: i64 = conn
+      .query_row(
+        &format!("SELECT COUNT(*) FROM message_index WHERE event_id NOT IN ({})", PROTECTED_ANY_ROOM),
+        [],
+        |row| row.get(0),
+      )
+      .map_err(|e| e.to_string())?;
+    let cutoff: Option<i64> = conn
+      .query_row(
+        &format!(
+          "SELECT timestamp FROM message_index WHERE event_id NOT IN ({}) ORDER BY timestamp LIMIT 1 OFFSET ?1",
+          PROTECTED_ANY_ROOM
+        ),
+        params![prunable / 10],
+        |row| row.get(0),
+      )
+      .optional()
+      .map_err(|e| e.to_string())?;
+    let Some(cutoff) = cutoff else {
+      break;
+    };
+    let removed = report.removed();
+    delete_older(conn, cutoff + 1, report)?;
+    if report.removed() == removed {
+      break;
+    }
+  }
+  Ok(())
+}
+
+/// Apply the index-wide pruning policy, then compact the database. Cached media files are
+/// left to the media cache's own size limit.
+pub fn prune(conn: &Connection, policy: &IndexPruningPolicy) -> Result<PruneReport, String> {
+  let mut report = PruneReport {
+    size_before: db_size(conn)?,
+    ..Default::default()
+  };
+  if let Some(days) = policy.max_age_days {
+    delete_older(conn, now_millis() - days.max(1) as i64 * DAY_MS, &mut report)?;
+  }
+  if let Some(max_rows) = policy.max_rows_per_room {
+    delete_beyond_rows(conn, max_rows.max(1), &mut report)?;
+  }
+  if report.removed() > 0 {
+    conn
+      .execute("INSERT INTO message_fts (message_fts) VALUES ('optimize')", [])
+      .map_err(|e| e.to_string())?;
+  }
+  conn
+    .execute(
+      "DELETE FROM index_tombstones WHERE deleted_at < ?1",
+      params![now_millis() - crate::INDEX_TOMBSTONE_TTL_MS],
+    )
+    .map_err(|e| e.to_string())?;
+  // Vacuuming is only worth it when something was freed or the size cap needs checking.
+  match policy.max_db_bytes {
+    Some(max_bytes) => shrink_to(conn, max_bytes, &mut report)?,
+    None if report.removed() > 0 => vacuum(conn)?,
+    None => {}
+  }
+  conn
+    .execute(
+      "UPDATE index_pruning SET last_run_at = ?1 WHERE id = 1",
+      params![now_millis()],
+    )
+    .map_err(|e| e.to_string())?;
+  report.size_after = db_size(conn)?;
+  Ok(report)
+}
@@ -0,0 +1,178 @@
+use rusqlite::{Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::attachment_crypto::EncryptedFile;
+use crate::event_cache;
+use crate::image_processing;
+use crate::unified_push::PushNotification;
+
+const AVATAR_SIZE: u32 = 96;
+const IMAGE_SIZE: u32 = 360;
+/// Notification servers read images from disk, so decrypted copies are kept only this long.
+const MAX_FILE_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Which pictures notifications carry; images from encrypted rooms are written to disk
+/// unencrypted while the notification is shown, so each can be turned off.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RichNotificationSettings {
+  pub avatars: bool,
+  pub images: bool,
+}
+
+impl Default for RichNotificationSettings {
+  fn default() -> Self {
+    RichNotificationSettings { avatars: true, images: true }
+  }
+}
+
+/// An mxc URI to download, with the key when it is an encrypted attachment.
+#[derive(Debug, Clone)]
+pub struct MediaSource {
+  pub mxc: String,
+  pub file: Option<EncryptedFile>,
+}
+
+/// Local files to attach to a notification.
+#[derive(Debug, Clone, Default)]
+pub struct NotificationMedia {
+  pub avatar: Option<PathBuf>,
+  pub image: Option<PathBuf>,
+}
+
+impl NotificationMedia {
+  /// The single picture for platforms that only take an icon.
+  pub fn icon(&self) -> Option<&Path> {
+    self.image.as_deref().or(self.avatar.as_deref())
+  }
+}
+
+pub fn init_rich_notifications_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS rich_notification_settings (
+        scope TEXT PRIMARY KEY,
+        settings_json TEXT NOT NULL
+      );
+    ",
+  )
+}
+
+pub fn settings(conn: &Connection) -> Result<RichNotificationSettings, String> {
+  let stored: Option<String> = conn
+    .query_row("SELECT settings_json FROM rich_notification_settings WHERE scope = ''", [], |row| row.get(0))
+    .optional()
+    .map_err(|e| e.to_string())?;
+  Ok(stored.and_then(|json| serde_json::from_str(&json).ok()).unwrap_or_default())
+}
+
+pub fn set_settings(conn: &Connection, value: &RichNotificationSettings) -> Result<(), String> {
+  let json = serde_json::to_string(value).map_err(|e| e.to_string())?;
+  conn
+    .execute(
+      "INSERT INTO rich_notification_settings (scope, settings_json) VALUES ('', ?1)
+       ON CONFLICT(scope) DO UPDATE SET settings_json = excluded.settings_json",
+      [json],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+/// The sender's avatar and, for image and sticker messages, the picture (preferring the
+/// attachment's own thumbnail), looked up in the event cache.
+pub fn sources(
+  conn: &Connection,
+  account_key: &str,
+  settings: &RichNotificationSettings,
+  notification: &PushNotification,
+) -> Result<(Option<MediaSource>, Option<MediaSource>), String> {
+  let Some(room_id) = notification.room_id.as_deref() else {
+    return Ok((None, None));
+  };
+  let avatar = match (&notification.sender, settings.avatars) {
+    (Some(sender), true) => event_cache::member_profile(conn, account_key, room_id, sender)?
+      .1
+      .map(|mxc| MediaSource { mxc, file: None }),
+    _ => None,
+  };
+  let event = match (&notification.event_id, settings.images) {
+    (Some(event_id), true) => event_cache::get_event(conn, account_key, room_id, event_id)?,
+    _ => None,
+  };
+  let image = event.and_then(|event| {
+    let is_image = event.event_type == "m.sticker"
+      || event.content.get("msgtype").and_then(|v| v.as_str()) == Some("m.image");
+    if !is_image {
+      return None;
+    }
+    let encrypted = |value: Option<&serde_json::Value>| {
+      value
+        .and_then(|v| serde_json::from_value::<EncryptedFile>(v.clone()).ok())
+        .map(|file| MediaSource { mxc: file.url.clone(), file: Some(file) })
+    };
+    let plain = |value: Option<&serde_json::Value>| {
+      value
+        .and_then(|v| v.as_str())
+        .map(|mxc| MediaSource { mxc: mxc.to_string(), file: None })
+    };
+    let content = &event.content;
+    encrypted(content.pointer("/info/thumbnail_file"))
+      .or_else(|| plain(content.pointer("/info/thumbnail_url")))
+      .or_else(|| encrypted(content.get("file")))
+      .or_else(|| plain(content.get("url")))
+  });
+  Ok((avatar, image))
+}
+
+/// Downscale `bytes` and write them under `dir`, named after `cache_key`, for the
+/// notification server to read. Reuses an earlier copy when there is one.
+pub fn write_image(dir: &Path, cache_key: &str, bytes: &[u8], avatar: bool) -> Result<PathBuf, String> {
+  fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+  let size = if avatar { AVATAR_SIZE } else { IMAGE_SIZE };
+  let name: String = Sha256::digest(format!("{}#{}", cache_key, size).as_bytes())
+    .iter()
+    .take(16)
+    .map(|b| format!("{:02x}", b))
+    .collect();
+  for ext in ["jpg", "png"] {
+    let existing = dir.join(format!("{}.{}", name, ext));
+    if existing.exists() {
+      return Ok(existing);
+    }
+  }
+  let (data, content_type, _, _) = image_processing::thumbnail(bytes, size, size)?;
+  let ext = if content_type == "image/png" { "png" } else { "jpg" };
+  let path = dir.join(format!("{}.{}", name, ext));
+  fs::write(&path, data).map_err(|e| e.to_string())?;
+  Ok(path)
+}
+
+/// Delete attached pictures old enough that their notifications are gone.
+pub fn prune(dir: &Path) {
+  let Ok(entries) = fs::read_dir(dir) else {
+    return;
+  };
+  let now = SystemTime::now();
+  for entry in entries.flatten() {
+    let expired = entry
+      .metadata()
+      .and_then(|meta| meta.modified())
+      .ok()
+      .and_then(|modified| now.duration_since(modified).ok())
+      .is_some_and(|age| age > MAX_FILE_AGE);
+    if expired {
+      let _ = fs::remove_file(entry.path());
+    }
+  }
+}
+
+/// Remove every attached picture, e.g. when rich notifications are switched off.
+pub fn clear(dir: &Path) -> Result<(), String> {
+  if dir.exists() {
+    fs::remove_dir_all(dir).map_err(|e| e.to_string())?;
+  }
+  Ok(())
+}
@@ -0,0 +1,385 @@
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::attachment_crypto::EncryptedFile;
+use crate::event_cache::{self, CachedEvent};
+use crate::media_download::sanitize_file_name;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ExportFormat {
+  Html,
+  Json,
+  Ndjson,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportRange {
+  #[serde(default)]
+  pub since: Option<i64>,
+  #[serde(default)]
+  pub until: Option<i64>,
+}
+
+/// A timeline message as written to the archive, with edits applied.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportedMessage {
+  pub event_id: String,
+  pub event_type: String,
+  pub sender: String,
+  pub sender_name: String,
+  pub timestamp: i64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub msgtype: Option<String>,
+  pub body: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub reply_to: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub thread_root: Option<String>,
+  pub edited: bool,
+  /// Reaction key to count.
+  #[serde(skip_serializing_if = "HashMap::is_empty")]
+  pub reactions: HashMap<String, usize>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub media: Option<ExportedMedia>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportedMedia {
+  pub mxc_url: String,
+  pub file_name: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub mimetype: Option<String>,
+  /// Path relative to the archive, once copied.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub path: Option<String>,
+  #[serde(skip)]
+  pub file: Option<EncryptedFile>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomArchive {
+  pub room_id: String,
+  pub room_name: String,
+  pub exported_at: i64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub since: Option<i64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub until: Option<i64>,
+  pub messages: Vec<ExportedMessage>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomExportResult {
+  pub export_id: String,
+  /// The archive file (`index.html`, `messages.json`, ...).
+  pub path: String,
+  pub messages: usize,
+  pub media_files: usize,
+  pub failed_media: usize,
+}
+
+/// Cancellation flags for room exports in progress.
+#[derive(Default)]
+pub struct RoomExportState {
+  active: Mutex<HashMap<String, Arc<AtomicBool>>>,
+}
+
+impl RoomExportState {
+  pub fn register(&self, export_id: &str) -> Arc<AtomicBool> {
+    let flag = Arc::new(AtomicBool::new(false));
+    if let Ok(mut active) = self.active.lock() {
+      active.insert(export_id.to_string(), flag.clone());
+    }
+    flag
+  }
+
+  pub fn finish(&self, export_id: &str) {
+    if let Ok(mut active) = self.active.lock() {
+      active.remove(export_id);
+    }
+  }
+
+  pub fn cancel(&self, export_id: &str) -> bool {
+    match self.active.lock().ok().and_then(|active| active.get(export_id).cloned()) {
+      Some(flag) => {
+        flag.store(true, Ordering::Relaxed);
+        true
+      }
+      None => false,
+    }
+  }
+}
+
+fn text(value: Option<&Value>) -> Option<String> {
+  value.and_then(|v| v.as_str()).map(|s| s.to_string())
+}
+
+/// Human-readable body for non-message events that still belong in a transcript.
+fn describe(event: &CachedEvent, sender_name: &str) -> Option<String> {
+  let content = &event.content;
+  match event.event_type.as_str() {
+    "m.room.member" => {
+      let target = text(content.get("displayname")).or_else(|| event.state_key.clone()).unwrap_or_default();
+      match content.get("membership").and_then(|v| v.as_str()) {
+        Some("join") => Some(format!("{} joined", target)),
+        Some("leave") if event.state_key.as_deref() == Some(event.sender.as_str()) => Some(format!("{} left", sender_name)),
+        Some("leave") => Some(format!("{} removed {}", sender_name, target)),
+        Some("invite") => Some(format!("{} invited {}", sender_name, target)),
+        Some("ban") => Some(format!("{} banned {}", sender_name, target)),
+        _ => None,
+      }
+    }
+    "m.room.name" => Some(format!("{} changed the room name to {}", sender_name, text(content.get("name")).unwrap_or_default())),
+    "m.room.topic" => Some(format!("{} changed the topic to {}", sender_name, text(content.get("topic")).unwrap_or_default())),
+    "m.room.encrypted" => Some("Unable to decrypt this message".to_string()),
+    _ => None,
+  }
+}
+
+fn media_of(content: &Value) -> Option<ExportedMedia> {
+  let msgtype = content.get("msgtype").and_then(|v| v.as_str());
+  if !matches!(msgtype, Some("m.image" | "m.video" | "m.audio" | "m.file") | None) {
+    return None;
+  }
+  let file: Option<EncryptedFile> = content.get("file").and_then(|f| serde_json::from_value(f.clone()).ok());
+  let mxc_url = file
+    .as_ref()
+    .map(|f| f.url.clone())
+    .or_else(|| text(content.get("url")))
+    .filter(|url| url.starts_with("mxc://"))?;
+  let file_name = text(content.get("filename"))
+    .or_else(|| text(content.get("body")))
+    .unwrap_or_else(|| mxc_url.rsplit('/').next().unwrap_or("media").to_string());
+  Some(ExportedMedia {
+    mxc_url,
+    file_name: sanitize_file_name(&file_name),
+    mimetype: content.pointer("/info/mimetype").and_then(|v| v.as_str()).map(|s| s.to_string()),
+    path: None,
+    file,
+  })
+}
+
+/// Build the archive from the event cache: edits are folded into the original, reactions
+/// counted, redacted and unknown events left out.
+pub fn load(conn: &Connection, account_key: &str, room_id: &str, range: &ExportRange, exported_at: i64) -> Result<RoomArchive, String> {
+  let events = event_cache::events_between(conn, account_key, room_id, range.since, range.until)?;
+  let room_name = event_cache::get_state_event(conn, account_key, room_id, "m.room.name", "")?
+    .and_then(|event| text(event.content.get("name")))
+    .unwrap_or_else(|| room_id.to_string());
+  let mut names: HashMap<String, String> = HashMap::new();
+  let mut edits: HashMap<String, Value> = HashMap::new();
+  let mut reactions: HashMap<String, HashMap<String, usize>> = HashMap::new();
+  for event in &events {
+    match (event.event_type.as_str(), event.rel_type.as_deref(), &event.relates_to) {
+      (_, Some("m.replace"), Some(target)) => {
+        if let Some(new_content) = event.content.get("m.new_content") {
+          edits.insert(target.clone(), new_content.clone());
+        }
+      }
+      ("m.reaction", Some("m.annotation"), Some(target)) => {
+        if let Some(key) = event.content.pointer("/m.relates_to/key").and_then(|v| v.as_str()) {
+          *reactions.entry(target.clone()).or_default().entry(key.to_string()).or_insert(0) += 1;
+        }
+      }
+      _ => {}
+    }
+  }
+
+  let mut messages = Vec::new();
+  for event in events {
+    if event.rel_type.as_deref() == Some("m.replace") || event.event_type == "m.reaction" {
+      continue;
+    }
+    if !names.contains_key(&event.sender) {
+      let (display_name, _) = event_cache::member_profile(conn, account_key, room_id, &event.sender)?;
+      names.insert(event.sender.clone(), display_name.unwrap_or_else(|| event.sender.clone()));
+    }
+    let sender_name = names.get(&event.sender).cloned().unwrap_or_default();
+    let edited = edits.get(&event.event_id);
+    let content = edited.unwrap_or(&event.content);
+    let (body, media) = match event.event_type.as_str() {
+      "m.room.message" => match text(content.get("body")) {
+        Some(body) => (body, media_of(content)),
+        None => continue,
+      },
+      "m.sticker" => (text(content.get("body")).unwrap_or_default(), media_of(content)),
+      _ => match describe(&event, &sender_name) {
+        Some(body) => (body, None),
+        None => continue,
+      },
+    };
+    messages.push(ExportedMessage {
+      msgtype: text(content.get("msgtype")),
+      reply_to: event
+        .content
+        .pointer("/m.relates_to/m.in_reply_to/event_id")
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string()),
+      thread_root: event.thread_root.clone(),
+      edited: edited.is_some(),
+      reactions: reactions.remove(&event.event_id).unwrap_or_default(),
+      event_id: event.event_id,
+      event_type: event.event_type,
+      sender: event.sender,
+      sender_name,
+      timestamp: event.origin_server_ts,
+      body,
+      media,
+    });
+  }
+  Ok(RoomArchive {
+    room_id: room_id.to_string(),
+    room_name,
+    exported_at,
+    since: range.since,
+    until: range.until,
+    messages,
+  })
+}
+
+fn escape(value: &str) -> String {
+  value
+    .replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+    .replace('\'', "&#39;")
+}
+
+/// Relative media paths may contain characters that are special in URLs.
+fn href(path: &str) -> String {
+  path.split('/').map(|part| urlencoding::encode(part).into_owned()).collect::<Vec<_>>().join("/")
+}
+
+/// Format milliseconds since the epoch as `YYYY-MM-DD HH:MM` (UTC).
+fn format_timestamp(ms: i64) -> String {
+  let secs = ms.div_euclid(1000);
+  let days = secs.div_euclid(86_400);
+  let day_secs = secs.rem_euclid(86_400);
+  // Civil-from-days (Howard Hinnant).
+  let z = days + 719_468;
+  let era = z.div_euclid(146_097);
+  let doe = z.rem_euclid(146_097);
+  let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = doy - (153 * mp + 2) / 5 + 1;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 };
+  let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+  format!("{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, day_secs / 3600, day_secs % 3600 / 60)
+}
+
+const HTML_STYLE: &str = "body{font-family:system-ui,sans-serif;max-width:860px;margin:0 auto;padding:24px;color:#1d1d1f;background:#fafafa}
+h1{font-size:1.4em;margin-bottom:4px}.meta{color:#6e6e73;font-size:.85em;margin-bottom:24px}
+.msg{padding:8px 12px;border-radius:8px;margin:4px 0;background:#fff;box-shadow:0 1px 2px rgba(0,0,0,.06)}
+.msg.notice{background:none;box-shadow:none;color:#6e6e73;font-style:italic}
+.sender{font-weight:600}.time{color:#6e6e73;font-size:.8em;margin-left:8px}
+.body{white-space:pre-wrap;word-wrap:break-word;margin-top:2px}.reply{color:#6e6e73;font-size:.8em}
+.reactions{font-size:.85em;margin-top:4px}.reactions span{background:#eee;border-radius:10px;padding:1px 6px;margin-right:4px}
+img,video{max-width:100%;max-height:480px;border-radius:6px;margin-top:6px;display:block}";
+
+/// A single self-contained HTML page; media is referenced by relative path.
+pub fn render_html(archive: &RoomArchive) -> String {
+  let mut html = String::new();
+  html.push_str("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">");
+  html.push_str(&format!("<title>{}</title><style>{}</style></head><body>\n", escape(&archive.room_name), HTML_STYLE));
+  html.push_str(&format!(
+    "<h1>{}</h1><div class=\"meta\">{} &middot; {} messages &middot; exported {} UTC</div>\n",
+    escape(&archive.room_name),
+    escape(&archive.room_id),
+    archive.messages.len(),
+    format_timestamp(archive.exported_at)
+  ));
+  for message in &archive.messages {
+    let notice = message.event_type != "m.room.message" && message.event_type != "m.sticker";
+    html.push_str(&format!(
+      "<div class=\"msg{}\" id=\"{}\">",
+      if notice { " notice" } else { "" },
+      escape(&message.event_id)
+    ));
+    if !notice {
+      html.push_str(&format!(
+        "<span class=\"sender\" title=\"{}\">{}</span>",
+        escape(&message.sender),
+        escape(&message.sender_name)
+      ));
+    }
+    html.push_str(&format!("<span class=\"time\">{}{}</span>", format_timestamp(message.timestamp), if message.edited { " (edited)" } else { "" }));
+    if let Some(reply_to) = &message.reply_to {
+      html.push_str(&format!("<div class=\"reply\"><a href=\"#{}\">In reply to</a></div>", escape(reply_to)));
+    }
+    let media = message.media.as_ref().and_then(|media| media.path.as_ref().map(|path| (media, href(path))));
+    match (message.msgtype.as_deref(), media) {
+      (Some("m.image"), Some((media, link))) | (None, Some((media, link))) => {
+        html.push_str(&format!("<a href=\"{0}\"><img src=\"{0}\" alt=\"{1}\"></a>", link, escape(&media.file_name)));
+      }
+      (Some("m.video"), Some((_, link))) => html.push_str(&format!("<video controls src=\"{}\"></video>", link)),
+      (Some("m.audio"), Some((_, link))) => html.push_str(&format!("<audio controls src=\"{}\"></audio>", link)),
+      (_, Some((media, link))) => {
+        html.push_str(&format!("<div class=\"body\"><a href=\"{}\">{}</a></div>", link, escape(&media.file_name)));
+      }
+      (_, None) => html.push_str(&format!("<div class=\"body\">{}</div>", escape(&message.body))),
+    }
+    if !message.reactions.is_empty() {
+      let mut reactions: Vec<_> = message.reactions.iter().collect();
+      reactions.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+      html.push_str("<div class=\"reactions\">");
+      for (key, count) in reactions {
+        html.push_str(&format!("<span>{} {}</span>", escape(key), count));
+      }
+      html.push_str("</div>");
+    }
+    html.push_str("</div>\n");
+  }
+  html.push_str("</body></html>\n");
+  html
+}
+
+/// Write the archive in `format` into `dir`, returning the main file.
+pub fn write(archive: &RoomArchive, format: ExportFormat, dir: &Path) -> Result<String, String> {
+  fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+  let path = match format {
+    ExportFormat::Html => {
+      let path = dir.join("index.html");
+      fs::write(&path, render_html(archive)).map_err(|e| e.to_string())?;
+      path
+    }
+    ExportFormat::Json => {
+      let path = dir.join("messages.json");
+      let json = serde_json::to_vec_pretty(archive).map_err(|e| e.to_string())?;
+      fs::write(&path, json).map_err(|e| e.to_string())?;
+      path
+    }
+    ExportFormat::Ndjson => {
+      let path = dir.join("messages.ndjson");
+      let mut file = fs::File::create(&path).map_err(|e| e.to_string())?;
+      let header = json!({
+        "roomId": archive.room_id,
+        "roomName": archive.room_name,
+        "exportedAt": archive.exported_at,
+        "since": archive.since,
+        "until": archive.until,
+      });
+      writeln!(file, "{}", header).map_err(|e| e.to_string())?;
+      for message in &archive.messages {
+        let line = serde_json::to_string(message).map_err(|e| e.to_string())?;
+        writeln!(file, "{}", line).map_err(|e| e.to_string())?;
+      }
+      path
+    }
+  };
+  Ok(path.display().to_string())
+}
@@ -0,0 +1,184 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::matrix_api::{encode, now_millis, ApiError, MatrixClient};
+
+/// Tables whose rows are keyed by room and move to the replacement room on upgrade.
+const MIGRATED_TABLES: &[&str] = &["message_index", "media_index", "drafts", "room_translation", "room_retention"];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomUpgrade {
+  pub old_room_id: String,
+  pub replacement_room_id: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub body: Option<String>,
+  pub sender: String,
+  pub detected_at: i64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub followed_at: Option<i64>,
+}
+
+pub fn init_room_upgrade_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS room_upgrades (
+        old_room_id TEXT PRIMARY KEY,
+        replacement_room_id TEXT NOT NULL,
+        body TEXT,
+        sender TEXT NOT NULL,
+        detected_at INTEGER NOT NULL,
+        followed_at INTEGER
+      );
+      CREATE INDEX IF NOT EXISTS idx_room_upgrades_replacement ON room_upgrades(replacement_room_id);
+    ",
+  )
+}
+
+fn tombstone_from_event(room_id: &str, raw: &Value) -> Option<RoomUpgrade> {
+  if raw.get("type").and_then(|v| v.as_str()) != Some("m.room.tombstone") {
+    return None;
+  }
+  if raw.get("state_key").and_then(|v| v.as_str()) != Some("") {
+    return None;
+  }
+  let content = raw.get("content")?;
+  let replacement_room_id = content.get("replacement_room")?.as_str()?.to_string();
+  Some(RoomUpgrade {
+    old_room_id: room_id.to_string(),
+    replacement_room_id,
+    body: content.get("body").and_then(|v| v.as_str()).map(|s| s.to_string()),
+    sender: raw.get("sender").and_then(|v| v.as_str()).unwrap_or_default().to_string(),
+    detected_at: now_millis(),
+    followed_at: None,
+  })
+}
+
+fn save_upgrade(conn: &Connection, upgrade: &RoomUpgrade) -> Result<bool, String> {
+  let changed = conn
+    .execute(
+      "INSERT INTO room_upgrades (old_room_id, replacement_room_id, body, sender, detected_at)
+       VALUES (?1, ?2, ?3, ?4, ?5)
+       ON CONFLICT(old_room_id) DO UPDATE SET
+         replacement_room_id = excluded.replacement_room_id,
+         body = excluded.body,
+         sender = excluded.sender
+       WHERE room_upgrades.replacement_room_id != excluded.replacement_room_id",
+      params![
+        upgrade.old_room_id,
+        upgrade.replacement_room_id,
+        upgrade.body,
+        upgrade.sender,
+        upgrade.detected_at,
+      ],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(changed > 0)
+}
+
+/// Record tombstones found in a batch of synced events. Returns only newly detected upgrades.
+pub fn detect_tombstones(conn: &Connection, room_id: &str, events: &[Value]) -> Result<Vec<RoomUpgrade>, String> {
+  let mut out = Vec::new();
+  for raw in events {
+    if let Some(upgrade) = tombstone_from_event(room_id, raw) {
+      if save_upgrade(conn, &upgrade)? {
+        out.push(upgrade);
+      }
+    }
+  }
+  Ok(out)
+}
+
+pub fn get_upgrade(conn: &Connection, old_room_id: &str) -> Result<Option<RoomUpgrade>, String> {
+  conn
+    .query_row(
+      "SELECT old_room_id, replacement_room_id, body, sender, detected_at, followed_at
+       FROM room_upgrades WHERE old_room_id = ?1",
+      [old_room_id],
+      |row| {
+        Ok(RoomUpgrade {
+          old_room_id: row.get(0)?,
+          replacement_room_id: row.get(1)?,
+          body: row.get(2)?,
+          sender: row.get(3)?,
+          detected_at: row.get(4)?,
+          followed_at: row.get(5)?,
+        })
+      },
+    )
+    .optional()
+    .map_err(|e| e.to_string())
+}
+
+/// Ask the homeserver for the tombstone when sync did not deliver it to us.
+pub async fn fetch_tombstone(client: &MatrixClient, old_room_id: &str) -> Result<Option<RoomUpgrade>, ApiError> {
+  let path = format!("/rooms/{}/state/m.room.tombstone/", encode(old_room_id));
+  let content = match client.get(&path, &[]).await {
+    Ok(content) => content,
+    Err(err) if err.errcode() == Some("M_NOT_FOUND") => return Ok(None),
+    Err(err) => return Err(err),
+  };
+  let raw = json!({ "type": "m.room.tombstone", "state_key": "", "sender": "", "content": content });
+  Ok(tombstone_from_event(old_room_id, &raw))
+}
+
+/// Join the replacement room, routing through the server that sent the tombstone.
+pub async fn join_replacement(client: &MatrixClient, upgrade: &RoomUpgrade) -> Result<Value, ApiError> {
+  let mut query = Vec::new();
+  if let Some(server) = upgrade.sender.split_once(':').map(|(_, server)| server) {
+    query.push(("server_name", server.to_string()));
+  }
+  client
+    .request(
+      reqwest::Method::POST,
+      &client.client_url(&format!("/join/{}", encode(&upgrade.replacement_room_id))),
+      &query,
+      Some(&json!({ "reason": "Following room upgrade" })),
+    )
+    .await
+}
+
+/// Move local rows to the replacement room and link both histories for search.
+pub fn migrate_room_data(conn: &Connection, upgrade: &RoomUpgrade) -> Result<usize, String> {
+  let tx = conn.unchecked_transaction().map_err(|e| e.to_string())?;
+  let mut moved = 0;
+  for table in MIGRATED_TABLES {
+    moved += tx
+      .execute(
+        &format!("UPDATE OR IGNORE {} SET room_id = ?1 WHERE room_id = ?2", table),
+        params![upgrade.replacement_room_id, upgrade.old_room_id],
+      )
+      .map_err(|e| e.to_string())?;
+  }
+  tx.execute(
+    "UPDATE room_upgrades SET followed_at = ?1 WHERE old_room_id = ?2",
+    params![now_millis(), upgrade.old_room_id],
+  )
+  .map_err(|e| e.to_string())?;
+  tx.commit().map_err(|e| e.to_string())?;
+  Ok(moved)
+}
+
+/// The room itself plus every followed predecessor, so searches span the whole upgrade chain.
+pub fn linked_room_ids(conn: &Connection, room_id: &str) -> Result<Vec<String>, String> {
+  let mut stmt = conn
+    .prepare(
+      "WITH RECURSIVE chain(id) AS (
+          SELECT ?1
+          UNION
+          SELECT room_upgrades.old_room_id FROM room_upgrades
+          JOIN chain ON room_upgrades.replacement_room_id = chain.id
+          WHERE room_upgrades.followed_at IS NOT NULL
+        )
+        SELECT id FROM chain",
+    )
+    .map_err(|e| e.to_string())?;
+  let rows = stmt
+    .query_map([room_id], |row| row.get::<_, String>(0))
+    .map_err(|e| e.to_string())?;
+  let mut out = Vec::new();
+  for row in rows {
+    if let Ok(id) = row { out.push(id); }
+  }
+  Ok(out)
+}
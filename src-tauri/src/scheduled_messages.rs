@@ -0,0 +1,258 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::matrix_api::now_millis;
+
+/// Attempts before a scheduled message is given up on.
+pub const MAX_ATTEMPTS: u32 = 5;
+/// Encrypted messages handed to the webview are offered again when unconfirmed after this long.
+pub const HANDOFF_TIMEOUT_MS: i64 = 5 * 60 * 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ScheduledStatus {
+  Pending,
+  /// Given to the webview's outbox for an encrypted room; waiting for confirmation.
+  HandedOff,
+  Sent,
+  Failed,
+}
+
+impl ScheduledStatus {
+  fn as_str(self) -> &'static str {
+    match self {
+      ScheduledStatus::Pending => "pending",
+      ScheduledStatus::HandedOff => "handedOff",
+      ScheduledStatus::Sent => "sent",
+      ScheduledStatus::Failed => "failed",
+    }
+  }
+
+  fn parse(value: &str) -> Self {
+    match value {
+      "handedOff" => ScheduledStatus::HandedOff,
+      "sent" => ScheduledStatus::Sent,
+      "failed" => ScheduledStatus::Failed,
+      _ => ScheduledStatus::Pending,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScheduledMessage {
+  pub id: i64,
+  pub account_key: String,
+  pub room_id: String,
+  pub content: Value,
+  /// ms since epoch.
+  pub send_at: i64,
+  pub status: ScheduledStatus,
+  pub attempts: u32,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub event_id: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub last_error: Option<String>,
+  pub created_at: i64,
+  pub updated_at: i64,
+}
+
+impl ScheduledMessage {
+  /// Stable across retries and restarts, so the homeserver drops duplicate sends.
+  pub fn txn_id(&self) -> String {
+    format!("mmsched{}", self.id)
+  }
+}
+
+pub fn init_scheduled_messages_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS scheduled_messages (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        account_key TEXT NOT NULL,
+        room_id TEXT NOT NULL,
+        content_json TEXT NOT NULL,
+        send_at INTEGER NOT NULL,
+        status TEXT NOT NULL,
+        attempts INTEGER NOT NULL DEFAULT 0,
+        event_id TEXT,
+        last_error TEXT,
+        created_at INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL
+      );
+      CREATE INDEX IF NOT EXISTS scheduled_messages_due ON scheduled_messages (status, send_at);
+    ",
+  )
+}
+
+const COLUMNS: &str =
+  "id, account_key, room_id, content_json, send_at, status, attempts, event_id, last_error, created_at, updated_at";
+
+fn row_to_message(row: &rusqlite::Row) -> rusqlite::Result<ScheduledMessage> {
+  let content: String = row.get(3)?;
+  let status: String = row.get(5)?;
+  Ok(ScheduledMessage {
+    id: row.get(0)?,
+    account_key: row.get(1)?,
+    room_id: row.get(2)?,
+    content: serde_json::from_str(&content).unwrap_or(Value::Null),
+    send_at: row.get(4)?,
+    status: ScheduledStatus::parse(&status),
+    attempts: row.get(6)?,
+    event_id: row.get(7)?,
+    last_error: row.get(8)?,
+    created_at: row.get(9)?,
+    updated_at: row.get(10)?,
+  })
+}
+
+fn validate(content: &Value) -> Result<String, String> {
+  if !content.get("msgtype").is_some_and(|v| v.is_string()) || !content.get("body").is_some_and(|v| v.is_string()) {
+    return Err("Scheduled content needs a msgtype and a body".into());
+  }
+  serde_json::to_string(content).map_err(|e| e.to_string())
+}
+
+pub fn schedule(conn: &Connection, account_key: &str, room_id: &str, content: &Value, send_at: i64) -> Result<ScheduledMessage, String> {
+  let content = validate(content)?;
+  let now = now_millis();
+  conn
+    .execute(
+      "INSERT INTO scheduled_messages (account_key, room_id, content_json, send_at, status, attempts, created_at, updated_at)
+       VALUES (?1, ?2, ?3, ?4, 'pending', 0, ?5, ?5)",
+      params![account_key, room_id, content, send_at, now],
+    )
+    .map_err(|e| e.to_string())?;
+  get(conn, conn.last_insert_rowid())?.ok_or_else(|| "Scheduled message not saved".to_string())
+}
+
+pub fn get(conn: &Connection, id: i64) -> Result<Option<ScheduledMessage>, String> {
+  conn
+    .query_row(
+      &format!("SELECT {} FROM scheduled_messages WHERE id = ?1", COLUMNS),
+      params![id],
+      row_to_message,
+    )
+    .optional()
+    .map_err(|e| e.to_string())
+}
+
+/// Messages not sent yet, soonest first; failed ones are included so they can be retried or removed.
+pub fn list_pending(conn: &Connection, room_id: Option<&str>) -> Result<Vec<ScheduledMessage>, String> {
+  let mut stmt = conn
+    .prepare(&format!(
+      "SELECT {} FROM scheduled_messages WHERE status != 'sent' AND (?1 IS NULL OR room_id = ?1) ORDER BY send_at",
+      COLUMNS
+    ))
+    .map_err(|e| e.to_string())?;
+  let rows = stmt.query_map(params![room_id], row_to_message).map_err(|e| e.to_string())?;
+  Ok(rows.flatten().collect())
+}
+
+/// Change the content and/or time of a message that hasn't gone out; a failed message is
+/// rescheduled as pending.
+pub fn edit(conn: &Connection, id: i64, content: Option<&Value>, send_at: Option<i64>) -> Result<ScheduledMessage, String> {
+  let current = get(conn, id)?.ok_or_else(|| "Scheduled message not found".to_string())?;
+  if matches!(current.status, ScheduledStatus::Sent | ScheduledStatus::HandedOff) {
+    return Err("The message is already being sent".into());
+  }
+  let content = match content {
+    Some(content) => validate(content)?,
+    None => serde_json::to_string(&current.content).map_err(|e| e.to_string())?,
+  };
+  conn
+    .execute(
+      "UPDATE scheduled_messages SET content_json = ?2, send_at = ?3, status = 'pending', attempts = 0,
+         last_error = NULL, updated_at = ?4
+       WHERE id = ?1",
+      params![id, content, send_at.unwrap_or(current.send_at), now_millis()],
+    )
+    .map_err(|e| e.to_string())?;
+  get(conn, id)?.ok_or_else(|| "Scheduled message not found".to_string())
+}
+
+pub fn cancel(conn: &Connection, id: i64) -> Result<(), String> {
+  let removed = conn
+    .execute(
+      "DELETE FROM scheduled_messages WHERE id = ?1 AND status IN ('pending', 'failed')",
+      params![id],
+    )
+    .map_err(|e| e.to_string())?;
+  if removed == 0 {
+    return Err("The message was already sent or doesn't exist".into());
+  }
+  Ok(())
+}
+
+/// Pending messages whose time has come (a minute later per failed attempt), plus hand-offs the
+/// webview never confirmed.
+pub fn due(conn: &Connection, now: i64) -> Result<Vec<ScheduledMessage>, String> {
+  let mut stmt = conn
+    .prepare(&format!(
+      "SELECT {} FROM scheduled_messages
+       WHERE (status = 'pending' AND send_at <= ?1 AND updated_at + attempts * 60000 <= ?1)
+         OR (status = 'handedOff' AND updated_at <= ?2)
+       ORDER BY send_at",
+      COLUMNS
+    ))
+    .map_err(|e| e.to_string())?;
+  let rows = stmt
+    .query_map(params![now, now - HANDOFF_TIMEOUT_MS], row_to_message)
+    .map_err(|e| e.to_string())?;
+  Ok(rows.flatten().collect())
+}
+
+fn set_status(conn: &Connection, id: i64, status: ScheduledStatus, event_id: Option<&str>, error: Option<&str>) -> Result<(), String> {
+  conn
+    .execute(
+      "UPDATE scheduled_messages SET status = ?2, event_id = COALESCE(?3, event_id), last_error = ?4, updated_at = ?5
+       WHERE id = ?1",
+      params![id, status.as_str(), event_id, error, now_millis()],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+pub fn mark_sent(conn: &Connection, id: i64, event_id: &str) -> Result<(), String> {
+  set_status(conn, id, ScheduledStatus::Sent, Some(event_id), None)
+}
+
+pub fn mark_handed_off(conn: &Connection, id: i64) -> Result<(), String> {
+  conn
+    .execute(
+      "UPDATE scheduled_messages SET attempts = attempts + 1 WHERE id = ?1",
+      params![id],
+    )
+    .map_err(|e| e.to_string())?;
+  set_status(conn, id, ScheduledStatus::HandedOff, None, None)
+}
+
+/// Count a failed attempt; a retryable failure stays pending until `MAX_ATTEMPTS` is reached.
+pub fn record_failure(conn: &Connection, id: i64, error: &str, retryable: bool) -> Result<ScheduledStatus, String> {
+  conn
+    .execute(
+      "UPDATE scheduled_messages SET attempts = attempts + 1 WHERE id = ?1",
+      params![id],
+    )
+    .map_err(|e| e.to_string())?;
+  let attempts: u32 = conn
+    .query_row("SELECT attempts FROM scheduled_messages WHERE id = ?1", params![id], |row| row.get(0))
+    .map_err(|e| e.to_string())?;
+  let status = if retryable && attempts < MAX_ATTEMPTS {
+    ScheduledStatus::Pending
+  } else {
+    ScheduledStatus::Failed
+  };
+  set_status(conn, id, status, None, Some(error))?;
+  Ok(status)
+}
+
+/// Drop sent messages older than `max_age_ms`.
+pub fn prune_sent(conn: &Connection, max_age_ms: i64) -> Result<usize, String> {
+  conn
+    .execute(
+      "DELETE FROM scheduled_messages WHERE status = 'sent' AND updated_at < ?1",
+      params![now_millis() - max_age_ms],
+    )
+    .map_err(|e| e.to_string())
+}
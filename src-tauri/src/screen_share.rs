@@ -0,0 +1,145 @@
+use base64::{engine::general_purpose, Engine as _};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, RgbaImage};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use xcap::{Monitor, Window};
+
+use crate::matrix_api::now_millis;
+
+const THUMBNAIL_WIDTH: u32 = 320;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ShareSourceKind {
+  Screen,
+  Window,
+}
+
+/// A screen or window that can be shared, `id` being `screen:<n>` or `window:<n>`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShareSource {
+  pub id: String,
+  pub kind: ShareSourceKind,
+  pub name: String,
+  pub width: u32,
+  pub height: u32,
+  pub primary: bool,
+  /// JPEG data URL preview, absent when the source could not be captured.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub thumbnail: Option<String>,
+}
+
+/// Running capture loops, stopped by clearing their flag.
+#[derive(Default)]
+pub struct ScreenShareState {
+  sessions: Mutex<HashMap<String, Arc<AtomicBool>>>,
+}
+
+impl ScreenShareState {
+  pub fn start(&self) -> Result<(String, Arc<AtomicBool>), String> {
+    let session_id = format!("share-{}", now_millis());
+    let running = Arc::new(AtomicBool::new(true));
+    self
+      .sessions
+      .lock()
+      .map_err(|e| e.to_string())?
+      .insert(session_id.clone(), running.clone());
+    Ok((session_id, running))
+  }
+
+  pub fn stop(&self, session_id: &str) -> bool {
+    let Ok(mut sessions) = self.sessions.lock() else {
+      return false;
+    };
+    match sessions.remove(session_id) {
+      Some(running) => {
+        running.store(false, Ordering::SeqCst);
+        true
+      }
+      None => false,
+    }
+  }
+}
+
+/// Scale down to `max_width` and encode as JPEG.
+pub fn encode_frame(image: RgbaImage, max_width: u32, quality: u8) -> Result<Vec<u8>, String> {
+  let mut frame = DynamicImage::ImageRgba8(image);
+  if frame.width() > max_width {
+    let height = (frame.height() as u64 * max_width as u64 / frame.width() as u64).max(1) as u32;
+    frame = frame.resize_exact(max_width, height, FilterType::Triangle);
+  }
+  let mut jpeg = Vec::new();
+  JpegEncoder::new_with_quality(&mut jpeg, quality)
+    .encode_image(&frame.to_rgb8())
+    .map_err(|e| e.to_string())?;
+  Ok(jpeg)
+}
+
+fn thumbnail(image: Result<RgbaImage, xcap::XCapError>) -> Option<String> {
+  let jpeg = encode_frame(image.ok()?, THUMBNAIL_WIDTH, 70).ok()?;
+  Some(format!("data:image/jpeg;base64,{}", general_purpose::STANDARD.encode(jpeg)))
+}
+
+/// Screens first, then other applications' windows, topmost first.
+pub fn sources(with_thumbnails: bool) -> Result<Vec<ShareSource>, String> {
+  let mut sources = Vec::new();
+  for monitor in Monitor::all().map_err(|e| e.to_string())? {
+    sources.push(ShareSource {
+      id: format!("screen:{}", monitor.id()),
+      kind: ShareSourceKind::Screen,
+      name: monitor.name().to_string(),
+      width: monitor.width(),
+      height: monitor.height(),
+      primary: monitor.is_primary(),
+      thumbnail: with_thumbnails.then(|| thumbnail(monitor.capture_image())).flatten(),
+    });
+  }
+  let own = std::process::id();
+  for window in Window::all().map_err(|e| e.to_string())? {
+    if window.pid() == own || window.is_minimized() || window.width() == 0 || window.height() == 0 {
+      continue;
+    }
+    let name = match (window.app_name(), window.title()) {
+      (app, "") => app.to_string(),
+      (app, title) if app.is_empty() || title.contains(app) => title.to_string(),
+      (app, title) => format!("{} — {}", title, app),
+    };
+    sources.push(ShareSource {
+      id: format!("window:{}", window.id()),
+      kind: ShareSourceKind::Window,
+      name,
+      width: window.width(),
+      height: window.height(),
+      primary: false,
+      thumbnail: with_thumbnails.then(|| thumbnail(window.capture_image())).flatten(),
+    });
+  }
+  Ok(sources)
+}
+
+/// One frame of the source named by `source_id`.
+pub fn capture(source_id: &str) -> Result<RgbaImage, String> {
+  let (kind, id) = source_id.split_once(':').ok_or("Invalid source")?;
+  let id: u32 = id.parse().map_err(|_| "Invalid source".to_string())?;
+  let captured = match kind {
+    "screen" => Monitor::all()
+      .map_err(|e| e.to_string())?
+      .into_iter()
+      .find(|monitor| monitor.id() == id)
+      .ok_or("The screen is no longer available")?
+      .capture_image(),
+    "window" => Window::all()
+      .map_err(|e| e.to_string())?
+      .into_iter()
+      .find(|window| window.id() == id)
+      .ok_or("The window was closed")?
+      .capture_image(),
+    _ => return Err("Invalid source".into()),
+  };
+  captured.map_err(|e| format!("Capture failed: {}", e))
+}
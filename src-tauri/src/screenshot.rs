@@ -0,0 +1,159 @@
+use base64::{engine::general_purpose, Engine as _};
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, ImageFormat, RgbaImage};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tokio::sync::oneshot;
+use xcap::{Monitor, Window};
+
+use crate::matrix_api::now_millis;
+
+pub const OVERLAY_LABEL: &str = "screenshot-overlay";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "camelCase")]
+pub enum ScreenshotMode {
+  /// The screen under the cursor.
+  Fullscreen,
+  /// The given window, or the topmost window of another application.
+  #[serde(rename_all = "camelCase")]
+  Window { window_id: Option<u32> },
+  /// A rectangle the user drags out on a frozen copy of the screen under the cursor.
+  Region,
+}
+
+/// A selection in pixels of the captured screen.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CaptureRegion {
+  pub x: u32,
+  pub y: u32,
+  pub width: u32,
+  pub height: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CaptureWindow {
+  pub id: u32,
+  pub title: String,
+  pub app_name: String,
+}
+
+struct PendingRegion {
+  screen: RgbaImage,
+  done: oneshot::Sender<Option<RgbaImage>>,
+}
+
+/// The frozen screen shown by the region overlay while the user selects.
+#[derive(Default)]
+pub struct ScreenshotState {
+  pending: Mutex<Option<PendingRegion>>,
+}
+
+impl ScreenshotState {
+  /// Hold `screen` for the overlay; a selection already in progress is cancelled.
+  pub fn begin(&self, screen: RgbaImage) -> Result<oneshot::Receiver<Option<RgbaImage>>, String> {
+    let (done, receiver) = oneshot::channel();
+    let mut pending = self.pending.lock().map_err(|e| e.to_string())?;
+    if let Some(previous) = pending.replace(PendingRegion { screen, done }) {
+      let _ = previous.done.send(None);
+    }
+    Ok(receiver)
+  }
+
+  /// The frozen screen as a JPEG data URL for the overlay background.
+  pub fn preview(&self) -> Result<Option<String>, String> {
+    let pending = self.pending.lock().map_err(|e| e.to_string())?;
+    let Some(region) = pending.as_ref() else {
+      return Ok(None);
+    };
+    let rgb = DynamicImage::ImageRgba8(region.screen.clone()).to_rgb8();
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, 85)
+      .encode_image(&rgb)
+      .map_err(|e| e.to_string())?;
+    Ok(Some(format!("data:image/jpeg;base64,{}", general_purpose::STANDARD.encode(jpeg))))
+  }
+
+  /// Finish the selection with the cropped image; `None` cancels.
+  pub fn complete(&self, region: Option<CaptureRegion>) -> Result<(), String> {
+    let mut pending = self.pending.lock().map_err(|e| e.to_string())?;
+    let Some(current) = pending.as_ref() else {
+      return Err("No region capture in progress".into());
+    };
+    let cropped = region.map(|region| crop(&current.screen, region)).transpose()?;
+    if let Some(current) = pending.take() {
+      let _ = current.done.send(cropped);
+    }
+    Ok(())
+  }
+}
+
+pub fn capture_screen(x: i32, y: i32) -> Result<RgbaImage, String> {
+  let monitor = match Monitor::from_point(x, y) {
+    Ok(monitor) => monitor,
+    Err(_) => Monitor::all()
+      .map_err(|e| e.to_string())?
+      .into_iter()
+      .find(|monitor| monitor.is_primary())
+      .ok_or("No screen found")?,
+  };
+  monitor.capture_image().map_err(|e| format!("Screen capture failed: {}", e))
+}
+
+/// Windows of other applications that can be captured, topmost first.
+pub fn windows() -> Result<Vec<CaptureWindow>, String> {
+  let own = std::process::id();
+  Ok(
+    Window::all()
+      .map_err(|e| e.to_string())?
+      .into_iter()
+      .filter(|window| window.pid() != own && !window.is_minimized() && window.width() > 0 && window.height() > 0)
+      .map(|window| CaptureWindow {
+        id: window.id(),
+        title: window.title().to_string(),
+        app_name: window.app_name().to_string(),
+      })
+      .collect(),
+  )
+}
+
+pub fn capture_window(window_id: Option<u32>) -> Result<RgbaImage, String> {
+  let own = std::process::id();
+  let window = Window::all()
+    .map_err(|e| e.to_string())?
+    .into_iter()
+    .find(|window| match window_id {
+      Some(id) => window.id() == id,
+      None => window.pid() != own && !window.is_minimized() && window.width() > 0,
+    })
+    .ok_or("Window not found")?;
+  window.capture_image().map_err(|e| format!("Window capture failed: {}", e))
+}
+
+pub fn crop(screen: &RgbaImage, region: CaptureRegion) -> Result<RgbaImage, String> {
+  let x = region.x.min(screen.width());
+  let y = region.y.min(screen.height());
+  let width = region.width.min(screen.width() - x);
+  let height = region.height.min(screen.height() - y);
+  if width == 0 || height == 0 {
+    return Err("The selection is empty".into());
+  }
+  Ok(image::imageops::crop_imm(screen, x, y, width, height).to_image())
+}
+
+/// Write the capture as a PNG in `dir`, ready for staging.
+pub fn save(dir: &Path, image: &RgbaImage) -> Result<PathBuf, String> {
+  let mut png = Vec::new();
+  image
+    .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+    .map_err(|e| format!("Failed to encode image: {}", e))?;
+  fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+  let path = dir.join(format!("screenshot-{}.png", now_millis()));
+  fs::write(&path, png).map_err(|e| e.to_string())?;
+  Ok(path)
+}
@@ -0,0 +1,258 @@
+use aes_gcm::{aead::{Aead, KeyInit}, Aes256Gcm, Nonce};
+use base64::{engine::general_purpose, Engine as _};
+use ctap_hid_fido2::fidokey::get_assertion::Extension as AssertionExtension;
+use ctap_hid_fido2::fidokey::make_credential::Extension as CredentialExtension;
+use ctap_hid_fido2::fidokey::{GetAssertionArgsBuilder, MakeCredentialArgsBuilder};
+use ctap_hid_fido2::{Cfg, FidoKeyHidFactory};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::matrix_api::now_millis;
+
+/// Relying party the credentials are created for; never sent to a server.
+const RP_ID: &str = "matrix-messenger.local";
+const NONCE_LEN: usize = 12;
+
+pub const LOCKED: &str = "Locked: unlock with your security key";
+
+/// One enrolled security key. Its hmac-secret output for `salt` unwraps the master key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Enrollment {
+  pub label: String,
+  pub credential_id: String,
+  pub salt: String,
+  pub nonce: String,
+  pub wrapped_key: String,
+  pub created_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EnrolledKey {
+  pub label: String,
+  pub created_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SecurityKeyStatus {
+  pub keys: Vec<EnrolledKey>,
+  /// Sealed data can be read; always true when no key is enrolled.
+  pub unlocked: bool,
+}
+
+/// The master key while the app is unlocked.
+#[derive(Default)]
+pub struct SecurityKeyState {
+  unlocked: Mutex<Option<[u8; 32]>>,
+}
+
+impl SecurityKeyState {
+  pub fn key(&self) -> Option<[u8; 32]> {
+    self.unlocked.lock().ok().and_then(|key| *key)
+  }
+
+  pub fn set(&self, key: [u8; 32]) {
+    if let Ok(mut unlocked) = self.unlocked.lock() {
+      *unlocked = Some(key);
+    }
+  }
+
+  pub fn lock(&self) {
+    if let Ok(mut unlocked) = self.unlocked.lock() {
+      *unlocked = None;
+    }
+  }
+}
+
+pub fn load(path: &Path) -> Result<Vec<Enrollment>, String> {
+  match fs::read_to_string(path) {
+    Ok(text) => serde_json::from_str(&text).map_err(|e| format!("Corrupt security key file: {}", e)),
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+    Err(err) => Err(err.to_string()),
+  }
+}
+
+pub fn save(path: &Path, enrollments: &[Enrollment]) -> Result<(), String> {
+  if enrollments.is_empty() {
+    return match fs::remove_file(path) {
+      Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.to_string()),
+      _ => Ok(()),
+    };
+  }
+  if let Some(dir) = path.parent() {
+    fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+  }
+  let json = serde_json::to_string_pretty(enrollments).map_err(|e| e.to_string())?;
+  fs::write(path, json).map_err(|e| e.to_string())
+}
+
+pub fn status(enrollments: &[Enrollment], unlocked: bool) -> SecurityKeyStatus {
+  SecurityKeyStatus {
+    keys: enrollments
+      .iter()
+      .map(|enrollment| EnrolledKey {
+        label: enrollment.label.clone(),
+        created_at: enrollment.created_at,
+      })
+      .collect(),
+    unlocked: unlocked || enrollments.is_empty(),
+  }
+}
+
+fn decode(value: &str) -> Result<Vec<u8>, String> {
+  general_purpose::STANDARD.decode(value).map_err(|e| e.to_string())
+}
+
+fn encrypt(key: &[u8; 32], plaintext: &[u8]) -> Result<(String, String), String> {
+  let mut nonce = [0u8; NONCE_LEN];
+  OsRng.fill_bytes(&mut nonce);
+  let cipher = Aes256Gcm::new_from_slice(key).map_err(|e| e.to_string())?;
+  let ciphertext = cipher
+    .encrypt(Nonce::from_slice(&nonce), plaintext)
+    .map_err(|e| e.to_string())?;
+  Ok((general_purpose::STANDARD.encode(nonce), general_purpose::STANDARD.encode(ciphertext)))
+}
+
+fn decrypt(key: &[u8; 32], nonce: &str, ciphertext: &str) -> Result<Vec<u8>, String> {
+  let nonce = decode(nonce)?;
+  if nonce.len() != NONCE_LEN {
+    return Err("Invalid nonce".into());
+  }
+  let cipher = Aes256Gcm::new_from_slice(key).map_err(|e| e.to_string())?;
+  cipher
+    .decrypt(Nonce::from_slice(&nonce), decode(ciphertext)?.as_ref())
+    .map_err(|_| "Decryption failed".to_string())
+}
+
+fn challenge() -> [u8; 32] {
+  let mut challenge = [0u8; 32];
+  OsRng.fill_bytes(&mut challenge);
+  challenge
+}
+
+/// The key's hmac-secret output for `salt`; needs a touch, and the PIN when one is set.
+fn hmac_secret(credential_id: &[u8], salt: [u8; 32], pin: Option<&str>) -> Result<[u8; 32], String> {
+  let device = FidoKeyHidFactory::create(&Cfg::init()).map_err(|e| format!("No security key found: {}", e))?;
+  let challenge = challenge();
+  let extensions = [AssertionExtension::HmacSecret(Some(salt))];
+  let mut builder = GetAssertionArgsBuilder::new(RP_ID, &challenge)
+    .credential_id(credential_id)
+    .extensions(&extensions);
+  builder = match pin {
+    Some(pin) => builder.pin(pin),
+    None => builder.without_pin_and_uv(),
+  };
+  let assertions = device
+    .get_assertion_with_args(&builder.build())
+    .map_err(|e| format!("Security key refused: {}", e))?;
+  assertions
+    .iter()
+    .flat_map(|assertion| assertion.extensions.iter())
+    .find_map(|extension| match extension {
+      AssertionExtension::HmacSecret(Some(output)) => Some(*output),
+      _ => None,
+    })
+    .ok_or_else(|| "The security key does not support hmac-secret".to_string())
+}
+
+/// Create a credential on the key and wrap `master_key` (a fresh one when `None`) with its
+/// hmac-secret output. Returns the enrollment and the master key.
+pub fn enroll(pin: Option<&str>, label: &str, master_key: Option<[u8; 32]>) -> Result<(Enrollment, [u8; 32]), String> {
+  let device = FidoKeyHidFactory::create(&Cfg::init()).map_err(|e| format!("No security key found: {}", e))?;
+  let challenge = challenge();
+  let extensions = [CredentialExtension::HmacSecret(Some(true))];
+  let mut builder = MakeCredentialArgsBuilder::new(RP_ID, &challenge).extensions(&extensions);
+  builder = match pin {
+    Some(pin) => builder.pin(pin),
+    None => builder.without_pin_and_uv(),
+  };
+  let attestation = device
+    .make_credential_with_args(&builder.build())
+    .map_err(|e| format!("Security key refused: {}", e))?;
+  let credential_id = attestation.credential_descriptor.id.clone();
+  drop(device);
+
+  let mut salt = [0u8; 32];
+  OsRng.fill_bytes(&mut salt);
+  let wrapping_key = hmac_secret(&credential_id, salt, pin)?;
+  let master_key = master_key.unwrap_or_else(|| {
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    key
+  });
+  let (nonce, wrapped_key) = encrypt(&wrapping_key, &master_key)?;
+  let label = if label.trim().is_empty() { "Security key".to_string() } else { label.trim().to_string() };
+  Ok((
+    Enrollment {
+      label,
+      credential_id: general_purpose::STANDARD.encode(credential_id),
+      salt: general_purpose::STANDARD.encode(salt),
+      nonce,
+      wrapped_key,
+      created_at: now_millis(),
+    },
+    master_key,
+  ))
+}
+
+/// Unwrap the master key with whichever enrolled key is plugged in.
+pub fn unlock(enrollments: &[Enrollment], pin: Option<&str>) -> Result<[u8; 32], String> {
+  let mut last_error = "No security key is enrolled".to_string();
+  for enrollment in enrollments {
+    let salt: [u8; 32] = decode(&enrollment.salt)?
+      .try_into()
+      .map_err(|_| "Invalid salt".to_string())?;
+    let wrapping_key = match hmac_secret(&decode(&enrollment.credential_id)?, salt, pin) {
+      Ok(key) => key,
+      Err(err) => {
+        last_error = err;
+        continue;
+      }
+    };
+    let master_key = decrypt(&wrapping_key, &enrollment.nonce, &enrollment.wrapped_key)?;
+    return master_key.try_into().map_err(|_| "Invalid master key".to_string());
+  }
+  Err(last_error)
+}
+
+/// Wrapper field of values sealed with the security-key master key.
+const SEALED: &str = "sealed";
+
+pub fn is_sealed(value: &Value) -> bool {
+  is_sealed_as(SEALED, value)
+}
+
+/// A store value encrypted with the master key.
+pub fn seal(key: &[u8; 32], value: &Value) -> Result<Value, String> {
+  seal_as(SEALED, key, value)
+}
+
+pub fn open(key: &[u8; 32], value: &Value) -> Result<Value, String> {
+  open_as(SEALED, key, value)
+}
+
+/// Whether `value` is `{ <wrapper>: { nonce, ciphertext } }`.
+pub fn is_sealed_as(wrapper: &str, value: &Value) -> bool {
+  value.get(wrapper).is_some_and(|sealed| sealed.is_object())
+}
+
+/// Encrypt `value` under `key` inside a `wrapper` field, so values sealed with different keys
+/// can be told apart.
+pub fn seal_as(wrapper: &str, key: &[u8; 32], value: &Value) -> Result<Value, String> {
+  let plaintext = serde_json::to_vec(value).map_err(|e| e.to_string())?;
+  let (nonce, ciphertext) = encrypt(key, &plaintext)?;
+  Ok(json!({ wrapper: { "nonce": nonce, "ciphertext": ciphertext } }))
+}
+
+pub fn open_as(wrapper: &str, key: &[u8; 32], value: &Value) -> Result<Value, String> {
+  let sealed = value.get(wrapper).ok_or("Not a sealed value")?;
+  let field = |name: &str| sealed.get(name).and_then(|v| v.as_str()).ok_or_else(|| format!("Sealed value has no {}", name));
+  let plaintext = decrypt(key, field("nonce")?, field("ciphertext")?)?;
+  serde_json::from_slice(&plaintext).map_err(|e| e.to_string())
+}
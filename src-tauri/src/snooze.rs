@@ -0,0 +1,104 @@
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+
+use crate::matrix_api::now_millis;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Snooze {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub room_id: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub account_key: Option<String>,
+  /// Notifications resume at this time (ms since epoch).
+  pub until: i64,
+}
+
+pub fn init_snooze_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS notification_snoozes (
+        scope TEXT PRIMARY KEY,
+        until INTEGER NOT NULL
+      );
+    ",
+  )
+}
+
+fn room_scope(room_id: &str) -> String {
+  format!("room:{}", room_id)
+}
+
+fn account_scope(account_key: &str) -> String {
+  format!("account:{}", account_key)
+}
+
+fn set(conn: &Connection, scope: &str, minutes: Option<u32>) -> Result<Option<i64>, String> {
+  match minutes.filter(|m| *m > 0) {
+    Some(minutes) => {
+      let until = now_millis() + minutes as i64 * 60_000;
+      conn
+        .execute(
+          "INSERT INTO notification_snoozes (scope, until) VALUES (?1, ?2)
+           ON CONFLICT(scope) DO UPDATE SET until = excluded.until",
+          params![scope, until],
+        )
+        .map_err(|e| e.to_string())?;
+      Ok(Some(until))
+    }
+    None => {
+      conn
+        .execute("DELETE FROM notification_snoozes WHERE scope = ?1", [scope])
+        .map_err(|e| e.to_string())?;
+      Ok(None)
+    }
+  }
+}
+
+/// Snooze a room for `minutes`, or end the snooze with `None` or 0.
+pub fn snooze_room(conn: &Connection, room_id: &str, minutes: Option<u32>) -> Result<Option<i64>, String> {
+  set(conn, &room_scope(room_id), minutes)
+}
+
+pub fn snooze_account(conn: &Connection, account_key: &str, minutes: Option<u32>) -> Result<Option<i64>, String> {
+  set(conn, &account_scope(account_key), minutes)
+}
+
+/// Whether notifications for this account or room are snoozed right now.
+pub fn is_snoozed(conn: &Connection, account_key: &str, room_id: Option<&str>) -> Result<bool, String> {
+  let room = room_id.map(room_scope).unwrap_or_default();
+  let count: i64 = conn
+    .query_row(
+      "SELECT COUNT(*) FROM notification_snoozes WHERE scope IN (?1, ?2) AND until > ?3",
+      params![account_scope(account_key), room, now_millis()],
+      |row| row.get(0),
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(count > 0)
+}
+
+/// Active snoozes; expired ones are removed on the way.
+pub fn list(conn: &Connection) -> Result<Vec<Snooze>, String> {
+  let now = now_millis();
+  conn
+    .execute("DELETE FROM notification_snoozes WHERE until <= ?1", [now])
+    .map_err(|e| e.to_string())?;
+  let mut stmt = conn
+    .prepare("SELECT scope, until FROM notification_snoozes ORDER BY until")
+    .map_err(|e| e.to_string())?;
+  let rows = stmt
+    .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)))
+    .map_err(|e| e.to_string())?;
+  let mut out = Vec::new();
+  for row in rows {
+    let (scope, until) = row.map_err(|e| e.to_string())?;
+    let snooze = if let Some(room_id) = scope.strip_prefix("room:") {
+      Snooze { room_id: Some(room_id.to_string()), account_key: None, until }
+    } else if let Some(account_key) = scope.strip_prefix("account:") {
+      Snooze { room_id: None, account_key: Some(account_key.to_string()), until }
+    } else {
+      continue;
+    };
+    out.push(snooze);
+  }
+  Ok(out)
+}
@@ -0,0 +1,279 @@
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::matrix_api::now_millis;
+use crate::media_cache;
+
+const THUMBNAIL_MARKER: &str = "#thumbnail=";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CategoryUsage {
+  /// `media`, `thumbnails`, or the name of a working directory (`video`, `voice`, `exports`, ...).
+  pub category: String,
+  pub bytes: i64,
+  pub files: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomUsage {
+  pub room_id: String,
+  pub bytes: i64,
+  pub files: i64,
+  /// Bytes per media type (`m.image`, `m.video`, ...), thumbnails under `thumbnails`.
+  pub by_type: HashMap<String, i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageReport {
+  pub total_bytes: i64,
+  pub categories: Vec<CategoryUsage>,
+  pub rooms: Vec<RoomUsage>,
+  /// Cached media no indexed event points at (avatars, URL previews, ...).
+  pub unattributed_bytes: i64,
+  pub cache_limit_bytes: i64,
+}
+
+/// What `cleanup` removes. Filters combine: an entry must match all that are set.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CleanupPolicy {
+  /// Only entries not opened for this long; also applies to working-directory files by age.
+  #[serde(default)]
+  pub older_than_ms: Option<i64>,
+  #[serde(default)]
+  pub room_ids: Option<Vec<String>>,
+  /// Resolve `room_ids` against this account's media index only.
+  #[serde(default)]
+  pub account_key: Option<String>,
+  /// After the filters above, keep evicting least recently used media until the cache fits.
+  #[serde(default)]
+  pub target_bytes: Option<i64>,
+  /// Also keep derived thumbnails of removed media (they are removed by default).
+  #[serde(default)]
+  pub keep_thumbnails: bool,
+  /// Working directories to empty (subject to `older_than_ms`), e.g. `["video", "clipboard"]`.
+  #[serde(default)]
+  pub directories: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CleanupResult {
+  pub removed_entries: usize,
+  pub removed_files: usize,
+  pub freed_bytes: i64,
+}
+
+struct CacheRow {
+  cache_key: String,
+  size: i64,
+  last_accessed: i64,
+}
+
+fn cache_rows(conn: &Connection) -> Result<Vec<CacheRow>, String> {
+  let mut stmt = conn
+    .prepare("SELECT cache_key, size, last_accessed FROM media_cache ORDER BY last_accessed ASC")
+    .map_err(|e| e.to_string())?;
+  let rows = stmt
+    .query_map([], |row| {
+      Ok(CacheRow {
+        cache_key: row.get(0)?,
+        size: row.get(1)?,
+        last_accessed: row.get(2)?,
+      })
+    })
+    .map_err(|e| e.to_string())?
+    .flatten()
+    .collect();
+  Ok(rows)
+}
+
+/// Room and media type for every mxc URI (and thumbnail) in the media index, or only in the
+/// rows indexed by `account_key`.
+fn media_owners(conn: &Connection, account_key: Option<&str>) -> Result<HashMap<String, (String, String)>, String> {
+  let mut stmt = conn
+    .prepare("SELECT room_id, media_type, mxc_url, thumbnail_mxc FROM media_index WHERE ?1 IS NULL OR account_key = ?1")
+    .map_err(|e| e.to_string())?;
+  let mut owners = HashMap::new();
+  let rows = stmt
+    .query_map([account_key], |row| {
+      Ok((
+        row.get::<_, String>(0)?,
+        row.get::<_, String>(1)?,
+        row.get::<_, Option<String>>(2)?,
+        row.get::<_, Option<String>>(3)?,
+      ))
+    })
+    .map_err(|e| e.to_string())?;
+  for (room_id, media_type, mxc_url, thumbnail_mxc) in rows.flatten() {
+    if let Some(mxc_url) = mxc_url {
+      owners.insert(mxc_url, (room_id.clone(), media_type));
+    }
+    if let Some(thumbnail_mxc) = thumbnail_mxc {
+      owners.entry(thumbnail_mxc).or_insert((room_id, "thumbnails".to_string()));
+    }
+  }
+  Ok(owners)
+}
+
+/// The mxc URI a cache key belongs to and whether it is a derived thumbnail.
+fn split_key(cache_key: &str) -> (&str, bool) {
+  match cache_key.find(THUMBNAIL_MARKER) {
+    Some(index) => (&cache_key[..index], true),
+    None => (cache_key, false),
+  }
+}
+
+fn dir_files(dir: &Path) -> Vec<(PathBuf, fs::Metadata)> {
+  let mut files = Vec::new();
+  let mut pending = vec![dir.to_path_buf()];
+  while let Some(current) = pending.pop() {
+    let Ok(entries) = fs::read_dir(&current) else {
+      continue;
+    };
+    for entry in entries.flatten() {
+      let Ok(meta) = entry.metadata() else {
+        continue;
+      };
+      if meta.is_dir() {
+        pending.push(entry.path());
+      } else {
+        files.push((entry.path(), meta));
+      }
+    }
+  }
+  files
+}
+
+/// Disk usage of the media cache by room and type, plus the working directories in `dirs`.
+/// With `account_key` the rooms are that account's; media only other accounts index counts
+/// as unattributed.
+pub fn report(conn: &Connection, account_key: Option<&str>, dirs: &[(&str, PathBuf)]) -> Result<StorageReport, String> {
+  let owners = media_owners(conn, account_key)?;
+  let mut media = CategoryUsage { category: "media".to_string(), bytes: 0, files: 0 };
+  let mut thumbnails = CategoryUsage { category: "thumbnails".to_string(), bytes: 0, files: 0 };
+  let mut rooms: HashMap<String, RoomUsage> = HashMap::new();
+  let mut unattributed_bytes = 0;
+  for row in cache_rows(conn)? {
+    let (mxc, derived) = split_key(&row.cache_key);
+    let category = if derived { &mut thumbnails } else { &mut media };
+    category.bytes += row.size;
+    category.files += 1;
+    match owners.get(mxc) {
+      Some((room_id, media_type)) => {
+        let usage = rooms.entry(room_id.clone()).or_insert_with(|| RoomUsage {
+          room_id: room_id.clone(),
+          bytes: 0,
+          files: 0,
+          by_type: HashMap::new(),
+        });
+        usage.bytes += row.size;
+        usage.files += 1;
+        let kind = if derived { "thumbnails" } else { media_type.as_str() };
+        *usage.by_type.entry(kind.to_string()).or_insert(0) += row.size;
+      }
+      None => unattributed_bytes += row.size,
+    }
+  }
+  let mut categories = vec![media, thumbnails];
+  for (name, dir) in dirs {
+    let files = dir_files(dir);
+    categories.push(CategoryUsage {
+      category: name.to_string(),
+      bytes: files.iter().map(|(_, meta)| meta.len() as i64).sum(),
+      files: files.len() as i64,
+    });
+  }
+  let mut rooms: Vec<RoomUsage> = rooms.into_values().collect();
+  rooms.sort_by(|a, b| b.bytes.cmp(&a.bytes));
+  Ok(StorageReport {
+    total_bytes: categories.iter().map(|c| c.bytes).sum(),
+    categories,
+    rooms,
+    unattributed_bytes,
+    cache_limit_bytes: media_cache::limit(conn)?,
+  })
+}
+
+/// Evict cached media matching `policy` and empty the selected working directories.
+pub fn cleanup(conn: &Connection, cache_dir: &Path, dirs: &[(&str, PathBuf)], policy: &CleanupPolicy) -> Result<CleanupResult, String> {
+  let owners = media_owners(conn, policy.account_key.as_deref())?;
+  let cutoff = policy.older_than_ms.map(|age| now_millis() - age);
+  let rows = cache_rows(conn)?;
+  let matches = |row: &CacheRow| {
+    let (mxc, _) = split_key(&row.cache_key);
+    let old_enough = cutoff.map(|cutoff| row.last_accessed < cutoff).unwrap_or(true);
+    let in_room = match &policy.room_ids {
+      Some(room_ids) => owners.get(mxc).map(|(room_id, _)| room_ids.contains(room_id)).unwrap_or(false),
+      None => true,
+    };
+    old_enough && in_room
+  };
+  let filtered = cutoff.is_some() || policy.room_ids.is_some();
+  let mut result = CleanupResult { removed_entries: 0, removed_files: 0, freed_bytes: 0 };
+  let mut removed_mxc = Vec::new();
+  let mut remaining = Vec::new();
+  for row in rows {
+    if filtered && matches(&row) {
+      media_cache::remove(conn, cache_dir, &row.cache_key)?;
+      result.removed_entries += 1;
+      result.freed_bytes += row.size;
+      removed_mxc.push(split_key(&row.cache_key).0.to_string());
+    } else {
+      remaining.push(row);
+    }
+  }
+  if let Some(target) = policy.target_bytes {
+    let mut total: i64 = remaining.iter().map(|row| row.size).sum();
+    let mut kept = Vec::new();
+    for row in remaining {
+      if total > target {
+        media_cache::remove(conn, cache_dir, &row.cache_key)?;
+        total -= row.size;
+        result.removed_entries += 1;
+        result.freed_bytes += row.size;
+        removed_mxc.push(split_key(&row.cache_key).0.to_string());
+      } else {
+        kept.push(row);
+      }
+    }
+    remaining = kept;
+  }
+  if !policy.keep_thumbnails {
+    for row in remaining {
+      let (mxc, derived) = split_key(&row.cache_key);
+      if derived && removed_mxc.iter().any(|removed| removed == mxc) {
+        media_cache::remove(conn, cache_dir, &row.cache_key)?;
+        result.removed_entries += 1;
+        result.freed_bytes += row.size;
+      }
+    }
+  }
+
+  let file_cutoff = policy
+    .older_than_ms
+    .map(|age| SystemTime::now() - Duration::from_millis(age.max(0) as u64));
+  for (name, dir) in dirs {
+    if !policy.directories.iter().any(|selected| selected == name) {
+      continue;
+    }
+    for (path, meta) in dir_files(dir) {
+      let old_enough = match (file_cutoff, meta.modified()) {
+        (Some(cutoff), Ok(modified)) => modified < cutoff,
+        _ => true,
+      };
+      if old_enough && fs::remove_file(&path).is_ok() {
+        result.removed_files += 1;
+        result.freed_bytes += meta.len() as i64;
+      }
+    }
+  }
+  Ok(result)
+}
@@ -0,0 +1,361 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::connectivity::WakeSignal;
+use crate::matrix_api::{now_millis, MatrixClient};
+
+const LONG_POLL_TIMEOUT_MS: u64 = 30_000;
+const MAX_RETRY_DELAY_SECS: u64 = 120;
+const TIMELINE_LIMIT: u64 = 50;
+/// Undelivered to-device events kept per account before the oldest are dropped.
+const MAX_QUEUED_TO_DEVICE: i64 = 5_000;
+
+pub fn init_sync_engine_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS sync_positions (
+        account_key TEXT PRIMARY KEY,
+        next_batch TEXT NOT NULL,
+        updated_at INTEGER NOT NULL
+      );
+      CREATE TABLE IF NOT EXISTS sync_to_device (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        account_key TEXT NOT NULL,
+        event_json TEXT NOT NULL
+      );
+      CREATE INDEX IF NOT EXISTS idx_sync_to_device_account ON sync_to_device(account_key, id);
+    ",
+  )
+}
+
+/// Where the engine resumes after a restart.
+pub fn position(conn: &Connection, account_key: &str) -> Result<Option<String>, String> {
+  conn
+    .query_row(
+      "SELECT next_batch FROM sync_positions WHERE account_key = ?1",
+      params![account_key],
+      |row| row.get(0),
+    )
+    .optional()
+    .map_err(|e| e.to_string())
+}
+
+pub fn set_position(conn: &Connection, account_key: &str, next_batch: &str) -> Result<(), String> {
+  conn
+    .execute(
+      "INSERT INTO sync_positions (account_key, next_batch, updated_at) VALUES (?1, ?2, ?3)
+       ON CONFLICT(account_key) DO UPDATE SET next_batch = excluded.next_batch, updated_at = excluded.updated_at",
+      params![account_key, next_batch, now_millis()],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+pub fn clear_position(conn: &Connection, account_key: &str) -> Result<(), String> {
+  conn
+    .execute("DELETE FROM sync_positions WHERE account_key = ?1", params![account_key])
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+/// The server forgets to-device events once a later `since` is used, so the ones the engine
+/// receives are queued until the webview's crypto has processed and acknowledged them.
+pub fn queue_to_device(conn: &Connection, account_key: &str, events: &[Value]) -> Result<(), String> {
+  if events.is_empty() {
+    return Ok(());
+  }
+  let tx = conn.unchecked_transaction().map_err(|e| e.to_string())?;
+  for event in events {
+    tx.execute(
+      "INSERT INTO sync_to_device (account_key, event_json) VALUES (?1, ?2)",
+      params![account_key, event.to_string()],
+    )
+    .map_err(|e| e.to_string())?;
+  }
+  tx.execute(
+    "DELETE FROM sync_to_device WHERE account_key = ?1 AND id NOT IN (
+       SELECT id FROM sync_to_device WHERE account_key = ?1 ORDER BY id DESC LIMIT ?2
+     )",
+    params![account_key, MAX_QUEUED_TO_DEVICE],
+  )
+  .map_err(|e| e.to_string())?;
+  tx.commit().map_err(|e| e.to_string())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueuedToDevice {
+  pub id: i64,
+  pub event: Value,
+}
+
+/// The queued to-device events, oldest first. They stay queued until acknowledged.
+pub fn queued_to_device(conn: &Connection, account_key: &str) -> Result<Vec<QueuedToDevice>, String> {
+  let mut stmt = conn
+    .prepare("SELECT id, event_json FROM sync_to_device WHERE account_key = ?1 ORDER BY id")
+    .map_err(|e| e.to_string())?;
+  let rows = stmt
+    .query_map(params![account_key], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))
+    .map_err(|e| e.to_string())?;
+  Ok(
+    rows
+      .flatten()
+      .filter_map(|(id, json)| Some(QueuedToDevice { id, event: serde_json::from_str(&json).ok()? }))
+      .collect(),
+  )
+}
+
+/// Forget the queued events up to and including `up_to_id` once the crypto has processed them.
+pub fn ack_to_device(conn: &Connection, account_key: &str, up_to_id: i64) -> Result<usize, String> {
+  conn
+    .execute(
+      "DELETE FROM sync_to_device WHERE account_key = ?1 AND id <= ?2",
+      params![account_key, up_to_id],
+    )
+    .map_err(|e| e.to_string())
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncStatus {
+  pub account_key: String,
+  pub running: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub next_batch: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub last_sync_at: Option<i64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub error: Option<String>,
+}
+
+struct Engine {
+  stop: Arc<AtomicBool>,
+  status: SyncStatus,
+}
+
+/// Sync loops run by the backend, one per account. They talk to the homeserver through
+/// `MatrixClient` rather than an embedded matrix-sdk, so room state and crypto stay with the
+/// webview's client; the engine only feeds the caches and queues to-device events for it.
+#[derive(Default)]
+pub struct SyncEngineState {
+  engines: Mutex<HashMap<String, Engine>>,
+  background: AtomicBool,
+}
+
+impl SyncEngineState {
+  /// A fresh stop flag for `account_key`, stopping any engine already running for it.
+  pub fn begin(&self, account_key: &str) -> Arc<AtomicBool> {
+    let stop = Arc::new(AtomicBool::new(false));
+    if let Ok(mut engines) = self.engines.lock() {
+      let engine = Engine {
+        stop: stop.clone(),
+        status: SyncStatus {
+          account_key: account_key.to_string(),
+          running: true,
+          ..Default::default()
+        },
+      };
+      if let Some(previous) = engines.insert(account_key.to_string(), engine) {
+        previous.stop.store(true, Ordering::Relaxed);
+      }
+    }
+    stop
+  }
+
+  pub fn stop(&self, account_key: &str) -> bool {
+    match self.engines.lock().ok().and_then(|mut engines| engines.remove(account_key)) {
+      Some(engine) => {
+        engine.stop.store(true, Ordering::Relaxed);
+        true
+      }
+      None => false,
+    }
+  }
+
+  pub fn stop_all(&self) -> usize {
+    match self.engines.lock() {
+      Ok(mut engines) => {
+        let count = engines.len();
+        for (_, engine) in engines.drain() {
+          engine.stop.store(true, Ordering::Relaxed);
+        }
+        count
+      }
+      Err(_) => 0,
+    }
+  }
+
+  pub fn is_running(&self, account_key: &str) -> bool {
+    self
+      .engines
+      .lock()
+      .map(|engines| engines.contains_key(account_key))
+      .unwrap_or(false)
+  }
+
+  /// Record the outcome of one `/sync`; returns the updated status.
+  pub fn update(&self, account_key: &str, result: Result<&str, &str>) -> Option<SyncStatus> {
+    let mut engines = self.engines.lock().ok()?;
+    let engine = engines.get_mut(account_key)?;
+    match result {
+      Ok(next_batch) => {
+        engine.status.next_batch = Some(next_batch.to_string());
+        engine.status.last_sync_at = Some(now_millis());
+        engine.status.error = None;
+      }
+      Err(error) => engine.status.error = Some(error.to_string()),
+    }
+    Some(engine.status.clone())
+  }
+
+  pub fn statuses(&self) -> Vec<SyncStatus> {
+    let mut out: Vec<SyncStatus> = self
+      .engines
+      .lock()
+      .map(|engines| engines.values().map(|engine| engine.status.clone()).collect())
+      .unwrap_or_default();
+    out.sort_by(|a, b| a.account_key.cmp(&b.account_key));
+    out
+  }
+
+  /// While the window is hidden the engines also show notifications.
+  pub fn set_background(&self, background: bool) {
+    self.background.store(background, Ordering::Relaxed);
+  }
+
+  pub fn in_background(&self) -> bool {
+    self.background.load(Ordering::Relaxed)
+  }
+}
+
+/// Full room data with lazy-loaded members; presence is left to the webview.
+pub fn engine_filter() -> Value {
+  json!({
+    "presence": { "not_types": ["*"] },
+    "room": {
+      "timeline": { "limit": TIMELINE_LIMIT },
+      "state": { "lazy_load_members": true }
+    }
+  })
+}
+
+/// What changed in one room during a `/sync`, as emitted on `sync://room`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomUpdate {
+  pub room_id: String,
+  /// `join`, `invite`, `leave` or `knock`.
+  pub membership: String,
+  pub timeline: Vec<Value>,
+  pub state: Vec<Value>,
+  pub ephemeral: Vec<Value>,
+  pub account_data: Vec<Value>,
+  /// Events were skipped between the previous sync and this timeline.
+  pub limited: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub prev_batch: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub notification_count: Option<u64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub highlight_count: Option<u64>,
+}
+
+fn events_at(value: &Value, pointer: &str) -> Vec<Value> {
+  value.pointer(pointer).and_then(|v| v.as_array()).cloned().unwrap_or_default()
+}
+
+pub fn room_updates(response: &Value) -> Vec<RoomUpdate> {
+  let mut out = Vec::new();
+  for membership in ["join", "invite", "leave", "knock"] {
+    let Some(rooms) = response.pointer(&format!("/rooms/{}", membership)).and_then(|v| v.as_object()) else {
+      continue;
+    };
+    for (room_id, room) in rooms {
+      let state = match membership {
+        "invite" => events_at(room, "/invite_state/events"),
+        "knock" => events_at(room, "/knock_state/events"),
+        _ => events_at(room, "/state/events"),
+      };
+      out.push(RoomUpdate {
+        room_id: room_id.clone(),
+        membership: membership.to_string(),
+        timeline: events_at(room, "/timeline/events"),
+        state,
+        ephemeral: events_at(room, "/ephemeral/events"),
+        account_data: events_at(room, "/account_data/events"),
+        limited: room.pointer("/timeline/limited").and_then(|v| v.as_bool()).unwrap_or(false),
+        prev_batch: room.pointer("/timeline/prev_batch").and_then(|v| v.as_str()).map(|s| s.to_string()),
+        notification_count: room.pointer("/unread_notifications/notification_count").and_then(|v| v.as_u64()),
+        highlight_count: room.pointer("/unread_notifications/highlight_count").and_then(|v| v.as_u64()),
+      });
+    }
+  }
+  out
+}
+
+pub fn to_device_events(response: &Value) -> Vec<Value> {
+  events_at(response, "/to_device/events")
+}
+
+/// Long-poll `/sync` from `since` until `stop` is set, handing every response to `handle`
+/// before moving on. Errors are reported through `failed` and retried with backoff; polling
+/// is parked while `wake` is suspended.
+pub async fn run<F, Fut>(
+  client: MatrixClient,
+  mut since: Option<String>,
+  stop: Arc<AtomicBool>,
+  wake: Arc<WakeSignal>,
+  handle: F,
+  failed: impl Fn(String),
+) where
+  F: Fn(Value) -> Fut,
+  Fut: std::future::Future<Output = ()>,
+{
+  let filter = engine_filter().to_string();
+  let mut delay = 1;
+  while !stop.load(Ordering::Relaxed) {
+    if wake.is_suspended() {
+      wake.wait_resumed().await;
+      delay = 1;
+      continue;
+    }
+    let mut query = vec![("filter", filter.clone()), ("set_presence", "offline".to_string())];
+    match &since {
+      Some(since) => {
+        query.push(("since", since.clone()));
+        query.push(("timeout", LONG_POLL_TIMEOUT_MS.to_string()));
+      }
+      None => query.push(("timeout", "0".to_string())),
+    }
+    let result = tokio::select! {
+      result = client.get("/sync", &query) => result,
+      _ = wake.woken() => continue,
+    };
+    match result {
+      Ok(response) => {
+        delay = 1;
+        if stop.load(Ordering::Relaxed) {
+          return;
+        }
+        let next = response.get("next_batch").and_then(|v| v.as_str()).map(|s| s.to_string());
+        handle(response).await;
+        if next.is_some() {
+          since = next;
+        }
+      }
+      Err(err) => {
+        let message = String::from(err);
+        tracing::warn!(target: "sync", "Sync engine request failed: {}", message);
+        failed(message);
+        tokio::select! {
+          _ = tokio::time::sleep(Duration::from_secs(delay)) => delay = (delay * 2).min(MAX_RETRY_DELAY_SECS),
+          _ = wake.woken() => delay = 1,
+        }
+      }
+    }
+  }
+}
@@ -0,0 +1,277 @@
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+
+use crate::event_cache;
+use crate::ignore_list::not_ignored;
+use crate::matrix_api::{encode, ApiError, MatrixClient};
+
+const THREAD_PAGE_SIZE: u32 = 30;
+
+/// Token the room list uses for the unanswered-threads smart collection.
+pub const COLLECTION_TOKEN: &str = "smart:threads";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ThreadParticipant {
+  pub user_id: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub display_name: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub avatar_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ThreadSummary {
+  pub root_event: Value,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub latest_reply: Option<Value>,
+  pub reply_count: usize,
+  pub participants: Vec<ThreadParticipant>,
+  pub current_user_participated: bool,
+  pub unread: bool,
+}
+
+/// A thread in the local message index with replies the user has not answered yet.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnansweredThread {
+  pub room_id: String,
+  pub thread_root: String,
+  /// Replies from others since the user last wrote in the thread.
+  pub unanswered: usize,
+  pub latest_event_id: String,
+  pub latest_sender: String,
+  pub latest_timestamp: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ThreadListResponse {
+  pub threads: Vec<ThreadSummary>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub next_batch: Option<String>,
+}
+
+/// Normalizes the frontend filter onto the `include` values of `/threads`.
+pub fn include_param(filter: Option<&str>) -> &'static str {
+  match filter {
+    Some("participated") | Some("mine") => "participated",
+    _ => "all",
+  }
+}
+
+pub async fn fetch_thread_roots(
+  client: &MatrixClient,
+  room_id: &str,
+  include: &str,
+  from: Option<&str>,
+) -> Result<(Vec<Value>, Option<String>), ApiError> {
+  let mut query = vec![("include", include.to_string()), ("limit", THREAD_PAGE_SIZE.to_string())];
+  if let Some(from) = from {
+    query.push(("from", from.to_string()));
+  }
+  let response = client
+    .request(
+      reqwest::Method::GET,
+      &format!("{}/_matrix/client/v1/rooms/{}/threads", client.homeserver_url, encode(room_id)),
+      &query,
+      None,
+    )
+    .await?;
+  let roots = response
+    .get("chunk")
+    .and_then(|v| v.as_array())
+    .cloned()
+    .unwrap_or_default();
+  let next_batch = response
+    .get("next_batch")
+    .and_then(|v| v.as_str())
+    .map(|s| s.to_string());
+  Ok((roots, next_batch))
+}
+
+/// Store thread roots and their bundled latest replies so aggregation can run from the cache.
+pub fn cache_thread_roots(conn: &Connection, account_key: &str, room_id: &str, roots: &[Value]) -> Result<Vec<String>, String> {
+  let mut ids = Vec::new();
+  let mut events: Vec<Value> = Vec::new();
+  for root in roots {
+    if let Some(event_id) = root.get("event_id").and_then(|v| v.as_str()) {
+      ids.push(event_id.to_string());
+      events.push(root.clone());
+    }
+    if let Some(latest) = root.pointer("/unsigned/m.relations/m.thread/latest_event") {
+      events.push(latest.clone());
+    }
+  }
+  event_cache::cache_events(conn, account_key, room_id, &events)?;
+  Ok(ids)
+}
+
+/// Thread roots known locally, newest activity first. Used when the homeserver is unreachable.
+pub fn cached_thread_roots(conn: &Connection, account_key: &str, room_id: &str) -> Result<Vec<String>, String> {
+  let mut stmt = conn
+    .prepare(
+      &format!(
+        "SELECT thread_root FROM event_cache
+         WHERE account_key = ?1 AND room_id = ?2 AND thread_root IS NOT NULL AND {}
+         GROUP BY thread_root
+         ORDER BY MAX(origin_server_ts) DESC
+         LIMIT ?3",
+        not_ignored("event_cache")
+      ),
+    )
+    .map_err(|e| e.to_string())?;
+  let rows = stmt
+    .query_map(params![account_key, room_id, THREAD_PAGE_SIZE], |row| row.get::<_, String>(0))
+    .map_err(|e| e.to_string())?;
+  let mut out = Vec::new();
+  for row in rows {
+    if let Ok(id) = row { out.push(id); }
+  }
+  Ok(out)
+}
+
+fn bundled_count(bundled: Option<&Value>) -> usize {
+  bundled
+    .and_then(|b| b.pointer("/unsigned/m.relations/m.thread/count"))
+    .and_then(|v| v.as_u64())
+    .unwrap_or(0) as usize
+}
+
+pub fn summarize_thread(
+  conn: &Connection,
+  account_key: &str,
+  room_id: &str,
+  root_id: &str,
+  user_id: &str,
+  bundled: Option<&Value>,
+) -> Result<Option<ThreadSummary>, String> {
+  let root = match event_cache::get_event(conn, account_key, room_id, root_id)? {
+    Some(root) => root,
+    None => return Ok(None),
+  };
+  let replies = event_cache::get_relations(conn, account_key, room_id, root_id, Some("m.thread"))?;
+  let latest = replies.last();
+
+  let mut seen: HashSet<String> = HashSet::new();
+  let mut participants = Vec::new();
+  for sender in std::iter::once(&root.sender).chain(replies.iter().map(|r| &r.sender)) {
+    if sender.is_empty() || !seen.insert(sender.clone()) {
+      continue;
+    }
+    let (display_name, avatar_url) = event_cache::member_profile(conn, account_key, room_id, sender)?;
+    participants.push(ThreadParticipant {
+      user_id: sender.clone(),
+      display_name,
+      avatar_url,
+    });
+  }
+
+  let current_user_participated = seen.contains(user_id)
+    || bundled
+      .and_then(|b| b.pointer("/unsigned/m.relations/m.thread/current_user_participated"))
+      .and_then(|v| v.as_bool())
+      .unwrap_or(false);
+
+  let unread = match latest {
+    Some(reply) if reply.sender != user_id => {
+      let threaded = event_cache::get_receipt(conn, account_key, room_id, user_id, root_id)?;
+      let unthreaded = event_cache::get_receipt(conn, account_key, room_id, user_id, "unthreaded")?;
+      let read_ts = threaded
+        .map(|r| r.ts)
+        .into_iter()
+        .chain(unthreaded.map(|r| r.ts))
+        .max()
+        .unwrap_or(0);
+      reply.origin_server_ts > read_ts
+    }
+    _ => false,
+  };
+
+  Ok(Some(ThreadSummary {
+    root_event: root.to_raw(),
+    latest_reply: latest.map(|r| r.to_raw()),
+    reply_count: replies.len().max(bundled_count(bundled)),
+    participants,
+    current_user_participated,
+    unread,
+  }))
+}
+
+pub fn summarize_threads(
+  conn: &Connection,
+  account_key: &str,
+  room_id: &str,
+  user_id: &str,
+  root_ids: &[String],
+  bundled_roots: &[Value],
+  participated_only: bool,
+) -> Result<Vec<ThreadSummary>, String> {
+  let mut out = Vec::new();
+  for root_id in root_ids {
+    let bundled = bundled_roots
+      .iter()
+      .find(|r| r.get("event_id").and_then(|v| v.as_str()) == Some(root_id.as_str()));
+    if let Some(summary) = summarize_thread(conn, account_key, room_id, root_id, user_id, bundled)? {
+      if participated_only && !summary.current_user_participated {
+        continue;
+      }
+      out.push(summary);
+    }
+  }
+  Ok(out)
+}
+
+/// Threads in the local message index waiting on the user, latest activity first: ones they
+/// started or replied in, or where a reply mentions `localpart`, whose newest reply is from
+/// someone else. Only replies after the user's own last reply count as unanswered.
+pub fn unanswered_threads(
+  conn: &Connection,
+  account_key: &str,
+  user_id: &str,
+  localpart: &str,
+) -> Result<Vec<UnansweredThread>, String> {
+  let token_pattern = format!("% {} %", localpart);
+  let mention_pattern = format!("%@{}%", localpart);
+  let mut stmt = conn
+    .prepare(&format!(
+      "WITH replies AS (
+         SELECT room_id, thread_root, event_id, sender, timestamp, body, search_tokens FROM message_index
+         WHERE account_key = ?1 AND thread_root IS NOT NULL AND {}
+       ),
+       answered AS (
+         SELECT room_id, thread_root, MAX(timestamp) AS answered_at FROM replies WHERE sender = ?2
+         GROUP BY room_id, thread_root
+       )
+       SELECT r.room_id, r.thread_root, COUNT(*), r.event_id, r.sender, MAX(r.timestamp) AS latest_at
+       FROM replies r
+       LEFT JOIN answered a ON a.room_id = r.room_id AND a.thread_root = r.thread_root
+       LEFT JOIN message_index root
+         ON root.account_key = ?1 AND root.room_id = r.room_id AND root.event_id = r.thread_root
+       WHERE r.sender != ?2 AND r.timestamp > IFNULL(a.answered_at, 0)
+       GROUP BY r.room_id, r.thread_root
+       HAVING SUM(a.answered_at IS NOT NULL OR IFNULL(root.sender = ?2, 0)
+         OR IFNULL(r.search_tokens, '') LIKE ?3 OR LOWER(IFNULL(r.body, '')) LIKE ?4) > 0
+       ORDER BY latest_at DESC",
+      not_ignored("message_index")
+    ))
+    .map_err(|e| e.to_string())?;
+  // With MAX() as the only min/max aggregate SQLite takes the bare columns from the row holding the
+  // maximum, so event_id and sender belong to the newest unanswered reply.
+  let rows = stmt
+    .query_map(params![account_key, user_id, token_pattern, mention_pattern], |row| {
+      Ok(UnansweredThread {
+        room_id: row.get(0)?,
+        thread_root: row.get(1)?,
+        unanswered: row.get::<_, i64>(2)? as usize,
+        latest_event_id: row.get(3)?,
+        latest_sender: row.get(4)?,
+        latest_timestamp: row.get(5)?,
+      })
+    })
+    .map_err(|e| e.to_string())?;
+  Ok(rows.flatten().collect())
+}
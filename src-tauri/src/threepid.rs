@@ -0,0 +1,176 @@
+use rand::{distributions::Alphanumeric, rngs::OsRng, Rng};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::matrix_api::{ApiError, MatrixClient};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ThreePid {
+  pub medium: String,
+  pub address: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub validated_at: Option<i64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub added_at: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ThreePidTokenRequest {
+  /// `email` or `msisdn`.
+  pub medium: String,
+  #[serde(default)]
+  pub email: Option<String>,
+  #[serde(default)]
+  pub country: Option<String>,
+  #[serde(default)]
+  pub phone_number: Option<String>,
+  #[serde(default)]
+  pub client_secret: Option<String>,
+  #[serde(default)]
+  pub send_attempt: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ThreePidTokenSession {
+  pub sid: String,
+  pub client_secret: String,
+  pub send_attempt: u32,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub submit_url: Option<String>,
+}
+
+/// Outcome of an operation guarded by user-interactive auth.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum UiaaOutcome {
+  Completed { response: Value },
+  #[serde(rename_all = "camelCase")]
+  AuthRequired {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session: Option<String>,
+    flows: Value,
+    params: Value,
+    completed: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+  },
+}
+
+/// Turn a 401 carrying UIAA flows into an `AuthRequired` outcome; anything else stays an error.
+pub fn uiaa_outcome(result: Result<Value, ApiError>) -> Result<UiaaOutcome, ApiError> {
+  match result {
+    Ok(response) => Ok(UiaaOutcome::Completed { response }),
+    Err(ApiError::Http { status: 401, body, .. }) if body.get("flows").is_some() => Ok(UiaaOutcome::AuthRequired {
+      session: body.get("session").and_then(|v| v.as_str()).map(|s| s.to_string()),
+      flows: body.get("flows").cloned().unwrap_or(Value::Null),
+      params: body.get("params").cloned().unwrap_or_else(|| json!({})),
+      completed: body
+        .get("completed")
+        .and_then(|v| v.as_array())
+        .map(|stages| stages.iter().filter_map(|s| s.as_str().map(|s| s.to_string())).collect())
+        .unwrap_or_default(),
+      error: body.get("error").and_then(|v| v.as_str()).map(|s| s.to_string()),
+    }),
+    Err(err) => Err(err),
+  }
+}
+
+pub fn generate_client_secret() -> String {
+  OsRng.sample_iter(&Alphanumeric).take(32).map(char::from).collect()
+}
+
+pub async fn list(client: &MatrixClient) -> Result<Vec<ThreePid>, ApiError> {
+  let response = client.get("/account/3pid", &[]).await?;
+  Ok(response
+    .get("threepids")
+    .and_then(|v| v.as_array())
+    .map(|items| {
+      items
+        .iter()
+        .filter_map(|item| {
+          Some(ThreePid {
+            medium: item.get("medium")?.as_str()?.to_string(),
+            address: item.get("address")?.as_str()?.to_string(),
+            validated_at: item.get("validated_at").and_then(|v| v.as_i64()),
+            added_at: item.get("added_at").and_then(|v| v.as_i64()),
+          })
+        })
+        .collect()
+    })
+    .unwrap_or_default())
+}
+
+/// Ask the homeserver to send a validation email or SMS for a new identifier.
+pub async fn request_token(client: &MatrixClient, request: &ThreePidTokenRequest) -> Result<ThreePidTokenSession, String> {
+  let client_secret = request.client_secret.clone().unwrap_or_else(generate_client_secret);
+  let send_attempt = request.send_attempt.unwrap_or(1);
+  let (path, body) = match request.medium.as_str() {
+    "email" => {
+      let email = request.email.as_deref().ok_or("Email address is required")?;
+      (
+        "/account/3pid/email/requestToken",
+        json!({ "client_secret": client_secret, "email": email, "send_attempt": send_attempt }),
+      )
+    }
+    "msisdn" => {
+      let country = request.country.as_deref().ok_or("Country code is required")?;
+      let phone_number = request.phone_number.as_deref().ok_or("Phone number is required")?;
+      (
+        "/account/3pid/msisdn/requestToken",
+        json!({
+          "client_secret": client_secret,
+          "country": country,
+          "phone_number": phone_number,
+          "send_attempt": send_attempt,
+        }),
+      )
+    }
+    other => return Err(format!("Unsupported 3PID medium: {}", other)),
+  };
+  let response = client.post(path, &body).await?;
+  let sid = response
+    .get("sid")
+    .and_then(|v| v.as_str())
+    .ok_or("Homeserver did not return a validation session")?
+    .to_string();
+  Ok(ThreePidTokenSession {
+    sid,
+    client_secret,
+    send_attempt,
+    submit_url: response.get("submit_url").and_then(|v| v.as_str()).map(|s| s.to_string()),
+  })
+}
+
+/// Submit an SMS code to the `submit_url` returned by `request_token`.
+pub async fn submit_token(client: &MatrixClient, session: &ThreePidTokenSession, token: &str) -> Result<bool, ApiError> {
+  let submit_url = match &session.submit_url {
+    Some(url) => url.clone(),
+    None => return Err(ApiError::Network("Homeserver did not provide a submit URL".to_string())),
+  };
+  let response = client
+    .request(
+      reqwest::Method::POST,
+      &submit_url,
+      &[],
+      Some(&json!({ "sid": session.sid, "client_secret": session.client_secret, "token": token })),
+    )
+    .await?;
+  Ok(response.get("success").and_then(|v| v.as_bool()).unwrap_or(false))
+}
+
+pub async fn add(client: &MatrixClient, sid: &str, client_secret: &str, auth: Option<&Value>) -> Result<UiaaOutcome, ApiError> {
+  let mut body = json!({ "sid": sid, "client_secret": client_secret });
+  if let Some(auth) = auth {
+    body["auth"] = auth.clone();
+  }
+  uiaa_outcome(client.post("/account/3pid/add", &body).await)
+}
+
+pub async fn remove(client: &MatrixClient, medium: &str, address: &str) -> Result<Value, ApiError> {
+  client
+    .post("/account/3pid/delete", &json!({ "medium": medium, "address": address }))
+    .await
+}
@@ -0,0 +1,318 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use crate::matrix_api::now_millis;
+
+const MAX_TEXT_CHARS: usize = 5000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TranslationBackend {
+  /// A LibreTranslate instance, public or self-hosted.
+  LibreTranslate,
+  DeepL,
+  /// A local program (e.g. argos-translate) reading the text on stdin.
+  Local,
+}
+
+/// Backend settings kept in the credential store so the API key never reaches the webview.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TranslationConfig {
+  pub backend: TranslationBackend,
+  /// LibreTranslate base URL; for DeepL, overrides the API host picked from the key.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub endpoint: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub api_key: Option<String>,
+  /// Program and arguments for the local backend; `{target}` is replaced with the language code.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub command: Vec<String>,
+}
+
+impl TranslationConfig {
+  /// The settings as shown in the UI, without the API key.
+  pub fn redacted(&self) -> TranslationConfig {
+    TranslationConfig {
+      api_key: None,
+      ..self.clone()
+    }
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Translation {
+  pub event_id: String,
+  pub target_lang: String,
+  pub text: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub source_lang: Option<String>,
+  pub backend: TranslationBackend,
+  pub translated_at: i64,
+}
+
+/// Per-room auto-translate; incoming messages are translated as they arrive.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomTranslation {
+  pub auto_translate: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub target_lang: Option<String>,
+}
+
+pub fn init_translation_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS translation_cache (
+        event_id TEXT NOT NULL,
+        target_lang TEXT NOT NULL,
+        source_hash TEXT NOT NULL,
+        text TEXT NOT NULL,
+        source_lang TEXT,
+        backend TEXT NOT NULL,
+        translated_at INTEGER NOT NULL,
+        PRIMARY KEY (event_id, target_lang)
+      );
+      CREATE TABLE IF NOT EXISTS room_translation (
+        room_id TEXT PRIMARY KEY,
+        auto_translate INTEGER NOT NULL,
+        target_lang TEXT
+      );
+    ",
+  )
+}
+
+fn backend_name(backend: TranslationBackend) -> &'static str {
+  match backend {
+    TranslationBackend::LibreTranslate => "libreTranslate",
+    TranslationBackend::DeepL => "deepL",
+    TranslationBackend::Local => "local",
+  }
+}
+
+fn parse_backend(value: &str) -> TranslationBackend {
+  match value {
+    "deepL" => TranslationBackend::DeepL,
+    "local" => TranslationBackend::Local,
+    _ => TranslationBackend::LibreTranslate,
+  }
+}
+
+/// Edits change the text, so cached translations are tied to a hash of the source.
+pub fn source_hash(text: &str) -> String {
+  Sha256::digest(text.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn normalize_lang(lang: &str) -> String {
+  lang.trim().to_lowercase()
+}
+
+pub fn cached(conn: &Connection, event_id: &str, target_lang: &str, source_hash: &str) -> Result<Option<Translation>, String> {
+  conn
+    .query_row(
+      "SELECT event_id, target_lang, text, source_lang, backend, translated_at FROM translation_cache
+       WHERE event_id = ?1 AND target_lang = ?2 AND source_hash = ?3",
+      params![event_id, target_lang, source_hash],
+      |row| {
+        let backend: String = row.get(4)?;
+        Ok(Translation {
+          event_id: row.get(0)?,
+          target_lang: row.get(1)?,
+          text: row.get(2)?,
+          source_lang: row.get(3)?,
+          backend: parse_backend(&backend),
+          translated_at: row.get(5)?,
+        })
+      },
+    )
+    .optional()
+    .map_err(|e| e.to_string())
+}
+
+pub fn store(conn: &Connection, translation: &Translation, source_hash: &str) -> Result<(), String> {
+  conn
+    .execute(
+      "INSERT INTO translation_cache (event_id, target_lang, source_hash, text, source_lang, backend, translated_at)
+       VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+       ON CONFLICT(event_id, target_lang) DO UPDATE SET source_hash = excluded.source_hash, text = excluded.text,
+         source_lang = excluded.source_lang, backend = excluded.backend, translated_at = excluded.translated_at",
+      params![
+        translation.event_id,
+        translation.target_lang,
+        source_hash,
+        translation.text,
+        translation.source_lang,
+        backend_name(translation.backend),
+        translation.translated_at
+      ],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+pub fn room_settings(conn: &Connection, room_id: &str) -> Result<RoomTranslation, String> {
+  Ok(
+    conn
+      .query_row(
+        "SELECT auto_translate, target_lang FROM room_translation WHERE room_id = ?1",
+        params![room_id],
+        |row| {
+          let auto: i64 = row.get(0)?;
+          Ok(RoomTranslation {
+            auto_translate: auto != 0,
+            target_lang: row.get(1)?,
+          })
+        },
+      )
+      .optional()
+      .map_err(|e| e.to_string())?
+      .unwrap_or_default(),
+  )
+}
+
+pub fn set_room_settings(conn: &Connection, room_id: &str, settings: &RoomTranslation) -> Result<(), String> {
+  if !settings.auto_translate && settings.target_lang.is_none() {
+    conn
+      .execute("DELETE FROM room_translation WHERE room_id = ?1", params![room_id])
+      .map_err(|e| e.to_string())?;
+    return Ok(());
+  }
+  conn
+    .execute(
+      "INSERT INTO room_translation (room_id, auto_translate, target_lang) VALUES (?1, ?2, ?3)
+       ON CONFLICT(room_id) DO UPDATE SET auto_translate = excluded.auto_translate, target_lang = excluded.target_lang",
+      params![room_id, settings.auto_translate, settings.target_lang.as_deref().map(normalize_lang)],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+fn http_client() -> Result<reqwest::Client, String> {
+  crate::proxy::apply(reqwest::Client::builder())
+    .user_agent(concat!("matrix-messenger/", env!("CARGO_PKG_VERSION")))
+    .timeout(Duration::from_secs(30))
+    .build()
+    .map_err(|e| format!("Failed to create HTTP client: {}", e))
+}
+
+async fn post_json(request: reqwest::RequestBuilder, body: &Value) -> Result<Value, String> {
+  let response = request.json(body).send().await.map_err(|e| e.to_string())?;
+  let status = response.status();
+  let value: Value = response.json().await.unwrap_or(Value::Null);
+  if !status.is_success() {
+    let message = value
+      .get("error")
+      .or_else(|| value.get("message"))
+      .and_then(|v| v.as_str())
+      .map(|s| s.to_string())
+      .unwrap_or_else(|| format!("HTTP {}", status.as_u16()));
+    return Err(format!("Translation failed: {}", message));
+  }
+  Ok(value)
+}
+
+async fn libre_translate(config: &TranslationConfig, text: &str, target: &str) -> Result<(String, Option<String>), String> {
+  let endpoint = config
+    .endpoint
+    .as_deref()
+    .filter(|url| !url.trim().is_empty())
+    .ok_or_else(|| "LibreTranslate needs an endpoint URL".to_string())?;
+  let mut body = json!({ "q": text, "source": "auto", "target": target, "format": "text" });
+  if let Some(key) = config.api_key.as_deref().filter(|key| !key.is_empty()) {
+    body["api_key"] = json!(key);
+  }
+  let url = format!("{}/translate", endpoint.trim_end_matches('/'));
+  let value = post_json(http_client()?.post(url), &body).await?;
+  let translated = value
+    .get("translatedText")
+    .and_then(|v| v.as_str())
+    .ok_or_else(|| "Translation failed: empty response".to_string())?;
+  let detected = value
+    .pointer("/detectedLanguage/language")
+    .and_then(|v| v.as_str())
+    .map(normalize_lang);
+  Ok((translated.to_string(), detected))
+}
+
+async fn deepl(config: &TranslationConfig, text: &str, target: &str) -> Result<(String, Option<String>), String> {
+  let key = config
+    .api_key
+    .as_deref()
+    .filter(|key| !key.is_empty())
+    .ok_or_else(|| "DeepL needs an API key".to_string())?;
+  // Free-plan keys end in ":fx" and only work against the free API host.
+  let endpoint = config.endpoint.clone().filter(|url| !url.trim().is_empty()).unwrap_or_else(|| {
+    if key.ends_with(":fx") {
+      "https://api-free.deepl.com".to_string()
+    } else {
+      "https://api.deepl.com".to_string()
+    }
+  });
+  let url = format!("{}/v2/translate", endpoint.trim_end_matches('/'));
+  let body = json!({ "text": [text], "target_lang": target.to_uppercase() });
+  let request = http_client()?
+    .post(url)
+    .header(reqwest::header::AUTHORIZATION, format!("DeepL-Auth-Key {}", key));
+  let value = post_json(request, &body).await?;
+  let first = value
+    .pointer("/translations/0")
+    .ok_or_else(|| "Translation failed: empty response".to_string())?;
+  let translated = first.get("text").and_then(|v| v.as_str()).unwrap_or_default();
+  let detected = first
+    .get("detected_source_language")
+    .and_then(|v| v.as_str())
+    .map(normalize_lang);
+  Ok((translated.to_string(), detected))
+}
+
+fn local(config: &TranslationConfig, text: &str, target: &str) -> Result<(String, Option<String>), String> {
+  let (program, args) = config
+    .command
+    .split_first()
+    .ok_or_else(|| "No local translation command configured".to_string())?;
+  let mut child = Command::new(program)
+    .args(args.iter().map(|arg| arg.replace("{target}", target)))
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .map_err(|e| format!("Failed to start {}: {}", program, e))?;
+  if let Some(mut stdin) = child.stdin.take() {
+    stdin.write_all(text.as_bytes()).map_err(|e| e.to_string())?;
+  }
+  let output = child.wait_with_output().map_err(|e| e.to_string())?;
+  if !output.status.success() {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    return Err(format!("Translation failed: {}", stderr.trim()));
+  }
+  Ok((String::from_utf8_lossy(&output.stdout).trim().to_string(), None))
+}
+
+/// Translate `text` into `target_lang` with the configured backend.
+pub async fn translate(config: &TranslationConfig, event_id: &str, text: &str, target_lang: &str) -> Result<Translation, String> {
+  let text: String = text.chars().take(MAX_TEXT_CHARS).collect();
+  let (translated, source_lang) = match config.backend {
+    TranslationBackend::LibreTranslate => libre_translate(config, &text, target_lang).await?,
+    TranslationBackend::DeepL => deepl(config, &text, target_lang).await?,
+    TranslationBackend::Local => {
+      let config = config.clone();
+      let target = target_lang.to_string();
+      tauri::async_runtime::spawn_blocking(move || local(&config, &text, &target))
+        .await
+        .map_err(|e| e.to_string())??
+    }
+  };
+  Ok(Translation {
+    event_id: event_id.to_string(),
+    target_lang: target_lang.to_string(),
+    text: translated,
+    source_lang,
+    backend: config.backend,
+    translated_at: now_millis(),
+  })
+}
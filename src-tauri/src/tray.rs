@@ -0,0 +1,275 @@
+use rusqlite::{Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use tauri::image::Image;
+use tauri::menu::{CheckMenuItem, Menu, MenuItem, PredefinedMenuItem, Submenu};
+use tauri::{AppHandle, Runtime};
+
+use crate::badge::UnreadSummary;
+use crate::event_cache;
+
+pub const TRAY_ID: &str = "main";
+pub const ROOM_ITEM_PREFIX: &str = "room:";
+const RECENT_ROOMS: usize = 8;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TraySettings {
+  pub enabled: bool,
+  /// Closing the window hides it to the tray instead of quitting.
+  pub minimize_to_tray: bool,
+}
+
+impl Default for TraySettings {
+  fn default() -> Self {
+    TraySettings {
+      enabled: true,
+      minimize_to_tray: false,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TrayStatus {
+  Idle,
+  Unread,
+  Highlight,
+}
+
+impl TrayStatus {
+  pub fn of(summary: &UnreadSummary) -> TrayStatus {
+    if summary.highlights > 0 {
+      TrayStatus::Highlight
+    } else if summary.total > 0 {
+      TrayStatus::Unread
+    } else {
+      TrayStatus::Idle
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrayRoom {
+  pub account_key: String,
+  pub room_id: String,
+  pub name: String,
+  pub unread: u64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub latest_event_id: Option<String>,
+}
+
+/// What the tray currently shows; menu item `room:<n>` refers to `rooms[n]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrayView {
+  pub status: TrayStatus,
+  pub total: u64,
+  pub dnd: bool,
+  pub rooms: Vec<TrayRoom>,
+}
+
+#[derive(Default)]
+pub struct TrayState {
+  shown: Mutex<Option<TrayView>>,
+}
+
+impl TrayState {
+  /// Record `view` and return whether it differs from what is shown.
+  pub fn update(&self, view: &TrayView) -> bool {
+    match self.shown.lock() {
+      Ok(mut shown) if shown.as_ref() != Some(view) => {
+        *shown = Some(view.clone());
+        true
+      }
+      _ => false,
+    }
+  }
+
+  pub fn room(&self, index: usize) -> Option<TrayRoom> {
+    self.shown.lock().ok()?.as_ref()?.rooms.get(index).cloned()
+  }
+
+  /// Forget what was shown so the next refresh rebuilds the tray.
+  pub fn reset(&self) {
+    if let Ok(mut shown) = self.shown.lock() {
+      *shown = None;
+    }
+  }
+}
+
+pub fn init_tray_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS tray_settings (
+        scope TEXT PRIMARY KEY,
+        settings_json TEXT NOT NULL
+      );
+    ",
+  )
+}
+
+pub fn settings(conn: &Connection) -> Result<TraySettings, String> {
+  let stored: Option<String> = conn
+    .query_row("SELECT settings_json FROM tray_settings WHERE scope = ''", [], |row| row.get(0))
+    .optional()
+    .map_err(|e| e.to_string())?;
+  Ok(stored.and_then(|json| serde_json::from_str(&json).ok()).unwrap_or_default())
+}
+
+pub fn set_settings(conn: &Connection, value: &TraySettings) -> Result<(), String> {
+  let json = serde_json::to_string(value).map_err(|e| e.to_string())?;
+  conn
+    .execute(
+      "INSERT INTO tray_settings (scope, settings_json) VALUES ('', ?1)
+       ON CONFLICT(scope) DO UPDATE SET settings_json = excluded.settings_json",
+      [json],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+/// The room as a tray entry, with the first account that has joined it.
+fn tray_room(
+  conn: &Connection,
+  accounts: &[(String, String)],
+  summary: &UnreadSummary,
+  room_id: &str,
+) -> Result<Option<TrayRoom>, String> {
+  let mut member = None;
+  for (account_key, user_id) in accounts {
+    let joined = event_cache::get_state_event(conn, account_key, room_id, "m.room.member", user_id)?
+      .is_some_and(|event| event.content.get("membership").and_then(|v| v.as_str()) == Some("join"));
+    if joined {
+      member = Some(account_key.clone());
+      break;
+    }
+  }
+  let Some(account_key) = member else {
+    return Ok(None);
+  };
+  let name = event_cache::get_state_event(conn, &account_key, room_id, "m.room.name", "")?
+    .and_then(|event| event.content.get("name").and_then(|v| v.as_str()).map(|s| s.to_string()))
+    .unwrap_or_else(|| room_id.to_string());
+  let latest_event_id = event_cache::latest_event(conn, &account_key, room_id)?.map(|event| event.event_id);
+  Ok(Some(TrayRoom {
+    account_key,
+    room_id: room_id.to_string(),
+    name,
+    unread: summary.rooms.get(room_id).copied().unwrap_or(0),
+    latest_event_id,
+  }))
+}
+
+/// Joined rooms with the most recent activity across the accounts, up to `limit`.
+pub fn recent_rooms(
+  conn: &Connection,
+  accounts: &[(String, String)],
+  summary: &UnreadSummary,
+  limit: usize,
+) -> Result<Vec<TrayRoom>, String> {
+  let mut candidates: Vec<(i64, String)> = Vec::new();
+  for (account_key, _) in accounts {
+    for room_id in event_cache::recent_room_ids(conn, account_key, limit * 4)? {
+      if candidates.iter().any(|(_, seen)| *seen == room_id) {
+        continue;
+      }
+      let ts = event_cache::latest_event(conn, account_key, &room_id)?.map_or(0, |event| event.origin_server_ts);
+      candidates.push((ts, room_id));
+    }
+  }
+  candidates.sort_by(|a, b| b.0.cmp(&a.0));
+  let mut rooms = Vec::new();
+  for (_, room_id) in candidates {
+    if let Some(room) = tray_room(conn, accounts, summary, &room_id)? {
+      rooms.push(room);
+    }
+    if rooms.len() >= limit {
+      break;
+    }
+  }
+  Ok(rooms)
+}
+
+/// Every room with unread notifications, for "Mark all as read".
+pub fn unread_rooms(conn: &Connection, accounts: &[(String, String)], summary: &UnreadSummary) -> Result<Vec<TrayRoom>, String> {
+  let mut rooms = Vec::new();
+  for (room_id, unread) in &summary.rooms {
+    if *unread == 0 {
+      continue;
+    }
+    if let Some(room) = tray_room(conn, accounts, summary, room_id)? {
+      rooms.push(room);
+    }
+  }
+  Ok(rooms)
+}
+
+pub fn view(conn: &Connection, accounts: &[(String, String)], summary: &UnreadSummary, dnd: bool) -> Result<TrayView, String> {
+  Ok(TrayView {
+    status: TrayStatus::of(summary),
+    total: summary.total,
+    dnd,
+    rooms: recent_rooms(conn, accounts, summary, RECENT_ROOMS)?,
+  })
+}
+
+/// The app icon with a dot in the corner: blue for unread, red for highlights.
+pub fn icon(base: &Image<'_>, status: TrayStatus) -> Image<'static> {
+  let (width, height) = (base.width(), base.height());
+  let mut rgba = base.rgba().to_vec();
+  let color = match status {
+    TrayStatus::Idle => return Image::new_owned(rgba, width, height),
+    TrayStatus::Unread => [0x2F, 0x80, 0xED],
+    TrayStatus::Highlight => [0xE0, 0x24, 0x24],
+  };
+  let radius = width.min(height) as f32 * 0.22;
+  let (cx, cy) = (width as f32 - radius - 1.0, radius + 1.0);
+  for y in 0..height {
+    for x in 0..width {
+      let (dx, dy) = (x as f32 + 0.5 - cx, y as f32 + 0.5 - cy);
+      let coverage = (radius - (dx * dx + dy * dy).sqrt()).clamp(0.0, 1.0);
+      if coverage == 0.0 {
+        continue;
+      }
+      let offset = ((y * width + x) * 4) as usize;
+      for (channel, value) in color.iter().enumerate() {
+        let current = rgba[offset + channel] as f32;
+        rgba[offset + channel] = (current + (*value as f32 - current) * coverage) as u8;
+      }
+      rgba[offset + 3] = rgba[offset + 3].max((coverage * 255.0) as u8);
+    }
+  }
+  Image::new_owned(rgba, width, height)
+}
+
+pub fn tooltip(view: &TrayView) -> String {
+  match view.status {
+    TrayStatus::Idle => "Matrix Messenger".to_string(),
+    _ => format!("Matrix Messenger — {} unread", view.total),
+  }
+}
+
+/// Recent rooms, mark all read, Do Not Disturb, show and quit.
+pub fn menu<R: Runtime>(app: &AppHandle<R>, view: &TrayView) -> tauri::Result<Menu<R>> {
+  let rooms = Submenu::with_id(app, "rooms", "Recent rooms", !view.rooms.is_empty())?;
+  for (index, room) in view.rooms.iter().enumerate() {
+    let label = if room.unread > 0 { format!("{} ({})", room.name, room.unread) } else { room.name.clone() };
+    rooms.append(&MenuItem::with_id(app, format!("{}{}", ROOM_ITEM_PREFIX, index), label, true, None::<&str>)?)?;
+  }
+  let mark_all = MenuItem::with_id(app, "mark-all-read", "Mark all as read", view.total > 0, None::<&str>)?;
+  let dnd = CheckMenuItem::with_id(app, "dnd", "Do not disturb", true, view.dnd, None::<&str>)?;
+  let show = MenuItem::with_id(app, "show", "Show Matrix Messenger", true, None::<&str>)?;
+  let quit = MenuItem::with_id(app, "quit", "Quit", true, None::<&str>)?;
+  Menu::with_items(
+    app,
+    &[
+      &show,
+      &PredefinedMenuItem::separator(app)?,
+      &rooms,
+      &mark_all,
+      &dnd,
+      &PredefinedMenuItem::separator(app)?,
+      &quit,
+    ],
+  )
+}
@@ -0,0 +1,525 @@
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes128Gcm, Nonce};
+use base64::{engine::general_purpose, Engine as _};
+use hkdf::Hkdf;
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use p256::{ecdh::diffie_hellman, PublicKey, SecretKey};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+pub const APP_ID: &str = "com.matrix-messenger.desktop";
+pub const DEFAULT_NTFY_SERVER: &str = "https://ntfy.sh";
+const FALLBACK_GATEWAY: &str = "https://matrix.gateway.unifiedpush.org/_matrix/push/v1/notify";
+const MAX_RECONNECT_DELAY_SECS: u64 = 300;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DistributorChoice {
+  /// A D-Bus distributor when one is installed, otherwise the embedded connection.
+  Auto,
+  Dbus,
+  /// Subscribe to an ntfy topic from the app itself.
+  Embedded,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PusherKind {
+  /// Matrix push gateway; payloads arrive as notification JSON.
+  Http,
+  /// MSC4174 Web Push pusher; payloads are RFC 8291 encrypted.
+  WebPush,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnifiedPushConfig {
+  pub distributor: DistributorChoice,
+  /// ntfy server for the embedded connection.
+  #[serde(default)]
+  pub ntfy_server: Option<String>,
+  pub pusher_kind: PusherKind,
+  #[serde(default)]
+  pub device_name: Option<String>,
+}
+
+impl Default for UnifiedPushConfig {
+  fn default() -> Self {
+    UnifiedPushConfig {
+      distributor: DistributorChoice::Auto,
+      ntfy_server: None,
+      pusher_kind: PusherKind::Http,
+      device_name: None,
+    }
+  }
+}
+
+/// Web Push key pair; the private key never leaves the credential store.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PushKeys {
+  pub private_key: String,
+  /// Uncompressed P-256 public key, unpadded URL-safe base64.
+  pub p256dh: String,
+  pub auth: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum ActiveDistributor {
+  #[serde(rename_all = "camelCase")]
+  Dbus { name: String },
+  #[serde(rename_all = "camelCase")]
+  Embedded { server: String, topic: String },
+}
+
+/// A registration per account, kept in the credential store.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnifiedPushRegistration {
+  pub token: String,
+  pub config: UnifiedPushConfig,
+  pub distributor: ActiveDistributor,
+  #[serde(default)]
+  pub endpoint: Option<String>,
+  #[serde(default)]
+  pub gateway: Option<String>,
+  pub keys: PushKeys,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnifiedPushStatus {
+  pub registered: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub distributor: Option<ActiveDistributor>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub endpoint: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub gateway: Option<String>,
+}
+
+/// The parts of a Matrix push notification needed to show it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PushNotification {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub event_id: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub room_id: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub event_type: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub sender: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub sender_display_name: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub room_name: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub body: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub unread: Option<u64>,
+}
+
+/// Messages from distributors, routed by registration token.
+#[derive(Debug, Clone)]
+pub enum ConnectorEvent {
+  Message { token: String, payload: Vec<u8> },
+  NewEndpoint { token: String, endpoint: String },
+  Unregistered { token: String },
+}
+
+pub fn random_token() -> String {
+  let mut bytes = [0u8; 16];
+  OsRng.fill_bytes(&mut bytes);
+  bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn generate_keys() -> PushKeys {
+  let secret = SecretKey::random(&mut OsRng);
+  let public = secret.public_key().to_encoded_point(false);
+  let mut auth = [0u8; 16];
+  OsRng.fill_bytes(&mut auth);
+  PushKeys {
+    private_key: general_purpose::URL_SAFE_NO_PAD.encode(secret.to_bytes()),
+    p256dh: general_purpose::URL_SAFE_NO_PAD.encode(public.as_bytes()),
+    auth: general_purpose::URL_SAFE_NO_PAD.encode(auth),
+  }
+}
+
+fn decode_b64(value: &str) -> Result<Vec<u8>, String> {
+  let trimmed = value.trim_end_matches('=');
+  general_purpose::URL_SAFE_NO_PAD
+    .decode(trimmed)
+    .or_else(|_| general_purpose::STANDARD_NO_PAD.decode(trimmed))
+    .map_err(|e| e.to_string())
+}
+
+fn hkdf_expand(salt: &[u8], ikm: &[u8], info: &[u8], out: &mut [u8]) -> Result<(), String> {
+  Hkdf::<Sha256>::new(Some(salt), ikm)
+    .expand(info, out)
+    .map_err(|_| "HKDF expansion failed".to_string())
+}
+
+/// Decrypt an RFC 8291 (`aes128gcm`) Web Push message addressed to `keys`.
+pub fn decrypt_payload(keys: &PushKeys, body: &[u8]) -> Result<Vec<u8>, String> {
+  if body.len() < 21 {
+    return Err("Push payload is too short".to_string());
+  }
+  let salt = &body[..16];
+  let record_size = u32::from_be_bytes([body[16], body[17], body[18], body[19]]) as usize;
+  let id_len = body[20] as usize;
+  let header_len = 21 + id_len;
+  if body.len() < header_len || record_size < 18 {
+    return Err("Malformed push payload header".to_string());
+  }
+  let sender_public = PublicKey::from_sec1_bytes(&body[21..header_len]).map_err(|_| "Invalid sender key in push payload")?;
+  let secret = SecretKey::from_slice(&decode_b64(&keys.private_key)?).map_err(|_| "Invalid push private key")?;
+  let receiver_public = decode_b64(&keys.p256dh)?;
+  let auth = decode_b64(&keys.auth)?;
+  let shared = diffie_hellman(secret.to_nonzero_scalar(), sender_public.as_affine());
+
+  let mut key_info = b"WebPush: info\0".to_vec();
+  key_info.extend_from_slice(&receiver_public);
+  key_info.extend_from_slice(&body[21..header_len]);
+  let mut ikm = [0u8; 32];
+  hkdf_expand(&auth, shared.raw_secret_bytes(), &key_info, &mut ikm)?;
+  let mut cek = [0u8; 16];
+  let mut nonce = [0u8; 12];
+  hkdf_expand(salt, &ikm, b"Content-Encoding: aes128gcm\0", &mut cek)?;
+  hkdf_expand(salt, &ikm, b"Content-Encoding: nonce\0", &mut nonce)?;
+
+  let cipher = Aes128Gcm::new_from_slice(&cek).map_err(|e| e.to_string())?;
+  let mut plaintext = Vec::new();
+  for (index, record) in body[header_len..].chunks(record_size).enumerate() {
+    let mut record_nonce = nonce;
+    for (i, byte) in (index as u64).to_be_bytes().iter().enumerate() {
+      record_nonce[4 + i] ^= byte;
+    }
+    let mut decrypted = cipher
+      .decrypt(Nonce::from_slice(&record_nonce), record)
+      .map_err(|_| "Failed to decrypt push payload".to_string())?;
+    // Records end with a delimiter (2 for the last one, 1 otherwise) followed by zero padding.
+    let delimiter = decrypted.iter().rposition(|b| *b != 0).ok_or("Push record has no delimiter")?;
+    let last = decrypted[delimiter] == 2;
+    decrypted.truncate(delimiter);
+    plaintext.extend_from_slice(&decrypted);
+    if last {
+      break;
+    }
+  }
+  Ok(plaintext)
+}
+
+/// Plain gateway JSON is used as is; anything else is treated as an encrypted Web Push message.
+pub fn open_payload(keys: &PushKeys, payload: &[u8]) -> Result<Vec<u8>, String> {
+  if payload.first() == Some(&b'{') {
+    Ok(payload.to_vec())
+  } else {
+    decrypt_payload(keys, payload)
+  }
+}
+
+pub fn parse_notification(payload: &[u8]) -> Option<PushNotification> {
+  let value: Value = serde_json::from_slice(payload).ok()?;
+  let notification = value.get("notification").unwrap_or(&value);
+  let text = |key: &str| notification.get(key).and_then(|v| v.as_str()).map(|s| s.to_string());
+  Some(PushNotification {
+    event_id: text("event_id"),
+    room_id: text("room_id"),
+    event_type: text("type"),
+    sender: text("sender"),
+    sender_display_name: text("sender_display_name"),
+    room_name: text("room_name").or_else(|| text("room_alias")),
+    body: notification.pointer("/content/body").and_then(|v| v.as_str()).map(|s| s.to_string()),
+    unread: notification.pointer("/counts/unread").and_then(|v| v.as_u64()),
+  })
+}
+
+/// The Matrix gateway next to the push server (as the UnifiedPush spec suggests), or the
+/// public UnifiedPush gateway.
+pub async fn discover_gateway(endpoint: &str) -> String {
+  let Ok(url) = reqwest::Url::parse(endpoint) else {
+    return FALLBACK_GATEWAY.to_string();
+  };
+  let candidate = format!("{}://{}/_matrix/push/v1/notify", url.scheme(), url.authority());
+  let found = async {
+    let response = crate::proxy::apply(reqwest::Client::builder())
+      .build()
+      .ok()?
+      .get(&candidate)
+      .timeout(Duration::from_secs(10))
+      .send()
+      .await
+      .ok()?;
+    let body: Value = response.json().await.ok()?;
+    (body.pointer("/unifiedpush/gateway").and_then(|v| v.as_str()) == Some("matrix")).then_some(())
+  }
+  .await;
+  match found {
+    Some(()) => candidate,
+    None => FALLBACK_GATEWAY.to_string(),
+  }
+}
+
+/// `/pushers/set` body registering this device.
+pub fn pusher_body(registration: &UnifiedPushRegistration, endpoint: &str, gateway: &str) -> Value {
+  let device_name = registration.config.device_name.clone().unwrap_or_else(|| "Matrix Messenger Desktop".to_string());
+  let (kind, pushkey, data) = match registration.config.pusher_kind {
+    // Only IDs leave the homeserver; the event is fetched and decrypted locally.
+    PusherKind::Http => ("http", endpoint.to_string(), json!({ "url": gateway, "format": "event_id_only" })),
+    PusherKind::WebPush => (
+      "webpush",
+      registration.keys.p256dh.clone(),
+      json!({ "url": endpoint, "auth": registration.keys.auth, "format": "event_id_only" }),
+    ),
+  };
+  json!({
+    "kind": kind,
+    "app_id": APP_ID,
+    "pushkey": pushkey,
+    "app_display_name": "Matrix Messenger",
+    "device_display_name": device_name,
+    "lang": "en",
+    "data": data,
+    "append": true,
+  })
+}
+
+/// `/pushers/set` body deleting the pusher for `pushkey`.
+pub fn removal_body(pushkey: &str) -> Value {
+  json!({ "kind": null, "app_id": APP_ID, "pushkey": pushkey })
+}
+
+/// Payload bytes of an ntfy JSON-stream message event.
+fn ntfy_payload(line: &Value) -> Option<Vec<u8>> {
+  if line.get("event").and_then(|v| v.as_str()) != Some("message") {
+    return None;
+  }
+  let message = line.get("message").and_then(|v| v.as_str())?;
+  if line.get("encoding").and_then(|v| v.as_str()) == Some("base64") {
+    general_purpose::STANDARD.decode(message).ok()
+  } else {
+    Some(message.as_bytes().to_vec())
+  }
+}
+
+/// Follow an ntfy topic's JSON stream until `stop` is set, reconnecting with backoff and
+/// asking for messages missed while disconnected.
+async fn run_embedded(server: String, topic: String, token: String, stop: Arc<AtomicBool>, events: UnboundedSender<ConnectorEvent>) {
+  let http = crate::proxy::apply(reqwest::Client::builder()).build().unwrap_or_default();
+  let mut since: Option<String> = None;
+  let mut delay = 1;
+  while !stop.load(Ordering::Relaxed) {
+    let mut request = http.get(format!("{}/{}/json", server.trim_end_matches('/'), topic));
+    if let Some(since) = &since {
+      request = request.query(&[("since", since)]);
+    }
+    if let Ok(mut response) = request.send().await {
+      if response.status().is_success() {
+        delay = 1;
+        let mut buffer = Vec::new();
+        while let Ok(Some(chunk)) = response.chunk().await {
+          if stop.load(Ordering::Relaxed) {
+            return;
+          }
+          buffer.extend_from_slice(&chunk);
+          while let Some(newline) = buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=newline).collect();
+            let Ok(line) = serde_json::from_slice::<Value>(&line) else {
+              continue;
+            };
+            if let Some(id) = line.get("id").and_then(|v| v.as_str()) {
+              since = Some(id.to_string());
+            }
+            if let Some(payload) = ntfy_payload(&line) {
+              let _ = events.send(ConnectorEvent::Message { token: token.clone(), payload });
+            }
+          }
+        }
+      }
+    }
+    if stop.load(Ordering::Relaxed) {
+      return;
+    }
+    tokio::time::sleep(Duration::from_secs(delay)).await;
+    delay = (delay * 2).min(MAX_RECONNECT_DELAY_SECS);
+  }
+}
+
+#[cfg(target_os = "linux")]
+mod dbus {
+  use super::ConnectorEvent;
+  use tokio::sync::mpsc::UnboundedSender;
+
+  pub const SERVICE_NAME: &str = "com.matrix_messenger.Desktop";
+  pub const CONNECTOR_PATH: &str = "/org/unifiedpush/Connector";
+  const DISTRIBUTOR_PREFIX: &str = "org.unifiedpush.Distributor.";
+
+  pub struct Connector {
+    pub events: UnboundedSender<ConnectorEvent>,
+  }
+
+  #[zbus::interface(name = "org.unifiedpush.Connector1")]
+  impl Connector {
+    async fn message(&self, token: String, message: Vec<u8>, _id: String) {
+      let _ = self.events.send(ConnectorEvent::Message { token, payload: message });
+    }
+
+    async fn new_endpoint(&self, token: String, endpoint: String) {
+      let _ = self.events.send(ConnectorEvent::NewEndpoint { token, endpoint });
+    }
+
+    async fn unregistered(&self, token: String) {
+      let _ = self.events.send(ConnectorEvent::Unregistered { token });
+    }
+  }
+
+  pub async fn connect(events: UnboundedSender<ConnectorEvent>) -> Result<zbus::Connection, String> {
+    zbus::connection::Builder::session()
+      .and_then(|builder| builder.name(SERVICE_NAME))
+      .and_then(|builder| builder.serve_at(CONNECTOR_PATH, Connector { events }))
+      .map_err(|e| e.to_string())?
+      .build()
+      .await
+      .map_err(|e| format!("D-Bus session unavailable: {}", e))
+  }
+
+  /// The first installed distributor, or `preferred` when it is still on the bus.
+  pub async fn find_distributor(connection: &zbus::Connection, preferred: Option<&str>) -> Result<Option<String>, String> {
+    let proxy = zbus::fdo::DBusProxy::new(connection).await.map_err(|e| e.to_string())?;
+    let names: Vec<String> = proxy
+      .list_names()
+      .await
+      .map_err(|e| e.to_string())?
+      .into_iter()
+      .map(|name| name.to_string())
+      .filter(|name| name.starts_with(DISTRIBUTOR_PREFIX))
+      .collect();
+    Ok(match preferred {
+      Some(preferred) if names.iter().any(|name| name == preferred) => Some(preferred.to_string()),
+      _ => names.into_iter().next(),
+    })
+  }
+
+  async fn distributor(connection: &zbus::Connection, name: &str) -> Result<zbus::Proxy<'static>, String> {
+    zbus::Proxy::new(
+      connection,
+      name.to_string(),
+      "/org/unifiedpush/Distributor",
+      "org.unifiedpush.Distributor1",
+    )
+    .await
+    .map_err(|e| e.to_string())
+  }
+
+  pub async fn register(connection: &zbus::Connection, name: &str, token: &str) -> Result<(), String> {
+    let proxy = distributor(connection, name).await?;
+    let (result, reason): (String, String) = proxy
+      .call("Register", &(SERVICE_NAME, token, "Matrix Messenger"))
+      .await
+      .map_err(|e| e.to_string())?;
+    if result == "REGISTRATION_SUCCEEDED" {
+      Ok(())
+    } else {
+      Err(format!("Distributor refused registration: {}", reason))
+    }
+  }
+
+  pub async fn unregister(connection: &zbus::Connection, name: &str, token: &str) -> Result<(), String> {
+    let proxy = distributor(connection, name).await?;
+    proxy.call::<_, _, ()>("Unregister", &(token,)).await.map_err(|e| e.to_string())
+  }
+}
+
+/// Distributor connections and the channel their messages arrive on.
+pub struct UnifiedPushState {
+  events: UnboundedSender<ConnectorEvent>,
+  receiver: Mutex<Option<UnboundedReceiver<ConnectorEvent>>>,
+  streams: Mutex<HashMap<String, Arc<AtomicBool>>>,
+  #[cfg(target_os = "linux")]
+  dbus: tokio::sync::Mutex<Option<zbus::Connection>>,
+}
+
+impl Default for UnifiedPushState {
+  fn default() -> Self {
+    let (events, receiver) = mpsc::unbounded_channel();
+    UnifiedPushState {
+      events,
+      receiver: Mutex::new(Some(receiver)),
+      streams: Mutex::new(HashMap::new()),
+      #[cfg(target_os = "linux")]
+      dbus: tokio::sync::Mutex::new(None),
+    }
+  }
+}
+
+impl UnifiedPushState {
+  /// The receiving end, taken once by the task that routes push messages.
+  pub fn take_receiver(&self) -> Option<UnboundedReceiver<ConnectorEvent>> {
+    self.receiver.lock().ok().and_then(|mut receiver| receiver.take())
+  }
+
+  pub fn start_embedded(&self, server: &str, topic: &str, token: &str) {
+    self.stop_embedded(token);
+    let stop = Arc::new(AtomicBool::new(false));
+    if let Ok(mut streams) = self.streams.lock() {
+      streams.insert(token.to_string(), stop.clone());
+    }
+    tauri::async_runtime::spawn(run_embedded(
+      server.to_string(),
+      topic.to_string(),
+      token.to_string(),
+      stop,
+      self.events.clone(),
+    ));
+  }
+
+  pub fn stop_embedded(&self, token: &str) {
+    if let Some(stop) = self.streams.lock().ok().and_then(|mut streams| streams.remove(token)) {
+      stop.store(true, Ordering::Relaxed);
+    }
+  }
+
+  /// Register `token` with a D-Bus distributor and return its bus name; the endpoint arrives
+  /// later as `ConnectorEvent::NewEndpoint`.
+  #[cfg(target_os = "linux")]
+  pub async fn register_dbus(&self, token: &str, preferred: Option<&str>) -> Result<Option<String>, String> {
+    let mut guard = self.dbus.lock().await;
+    if guard.is_none() {
+      *guard = Some(dbus::connect(self.events.clone()).await?);
+    }
+    let connection = guard.as_ref().ok_or("D-Bus session unavailable")?;
+    let Some(name) = dbus::find_distributor(connection, preferred).await? else {
+      return Ok(None);
+    };
+    dbus::register(connection, &name, token).await?;
+    Ok(Some(name))
+  }
+
+  #[cfg(not(target_os = "linux"))]
+  pub async fn register_dbus(&self, _token: &str, _preferred: Option<&str>) -> Result<Option<String>, String> {
+    Ok(None)
+  }
+
+  #[cfg(target_os = "linux")]
+  pub async fn unregister_dbus(&self, name: &str, token: &str) -> Result<(), String> {
+    let guard = self.dbus.lock().await;
+    match guard.as_ref() {
+      Some(connection) => dbus::unregister(connection, name, token).await,
+      None => Ok(()),
+    }
+  }
+
+  #[cfg(not(target_os = "linux"))]
+  pub async fn unregister_dbus(&self, _name: &str, _token: &str) -> Result<(), String> {
+    Ok(())
+  }
+}
@@ -0,0 +1,117 @@
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use tauri::Url;
+use tauri_plugin_updater::Update;
+
+/// Manifests are signed with the key in `plugins.updater.pubkey`; the updater rejects
+/// packages whose signature does not verify. `{{current_version}}` lets the server hand out
+/// a smaller delta package when one exists for the installed version.
+const ENDPOINT: &str =
+  "https://updates.matrix-messenger.dev/desktop/{channel}/{{target}}/{{arch}}/{{current_version}}/latest.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum UpdateChannel {
+  #[default]
+  Stable,
+  Beta,
+}
+
+impl UpdateChannel {
+  fn as_str(self) -> &'static str {
+    match self {
+      UpdateChannel::Stable => "stable",
+      UpdateChannel::Beta => "beta",
+    }
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateSettings {
+  #[serde(default)]
+  pub channel: UpdateChannel,
+  /// Check in the background and announce new versions.
+  pub auto_check: bool,
+}
+
+impl Default for UpdateSettings {
+  fn default() -> Self {
+    UpdateSettings {
+      channel: UpdateChannel::Stable,
+      auto_check: true,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateInfo {
+  pub version: String,
+  pub current_version: String,
+  pub channel: UpdateChannel,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub notes: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub date: Option<String>,
+  /// The package is downloaded and will be installed on restart.
+  pub ready: bool,
+}
+
+/// The update found by the last check and, once downloaded, its package.
+#[derive(Default)]
+pub struct UpdateState {
+  found: Mutex<Option<(Update, UpdateChannel)>>,
+  downloaded: Mutex<Option<Vec<u8>>>,
+}
+
+impl UpdateState {
+  /// Record the result of a check; a downloaded package for another version is dropped.
+  pub fn set_found(&self, update: Option<(Update, UpdateChannel)>) {
+    let Ok(mut found) = self.found.lock() else {
+      return;
+    };
+    let same = match (found.as_ref(), update.as_ref()) {
+      (Some((old, _)), Some((new, _))) => old.version == new.version,
+      _ => false,
+    };
+    if !same {
+      if let Ok(mut downloaded) = self.downloaded.lock() {
+        *downloaded = None;
+      }
+    }
+    *found = update;
+  }
+
+  pub fn found(&self) -> Option<(Update, UpdateChannel)> {
+    self.found.lock().ok()?.clone()
+  }
+
+  pub fn set_downloaded(&self, bytes: Vec<u8>) {
+    if let Ok(mut downloaded) = self.downloaded.lock() {
+      *downloaded = Some(bytes);
+    }
+  }
+
+  pub fn take_downloaded(&self) -> Option<(Update, Vec<u8>)> {
+    let bytes = self.downloaded.lock().ok()?.take()?;
+    Some((self.found()?.0, bytes))
+  }
+
+  pub fn info(&self) -> Option<UpdateInfo> {
+    let (update, channel) = self.found()?;
+    let ready = self.downloaded.lock().map(|d| d.is_some()).unwrap_or(false);
+    Some(UpdateInfo {
+      version: update.version.clone(),
+      current_version: update.current_version.clone(),
+      channel,
+      notes: update.body.clone(),
+      date: update.date.map(|date| date.to_string()),
+      ready,
+    })
+  }
+}
+
+pub fn endpoint(channel: UpdateChannel) -> Result<Url, String> {
+  Url::parse(&ENDPOINT.replace("{channel}", channel.as_str())).map_err(|e| e.to_string())
+}
@@ -0,0 +1,141 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::image_processing;
+use crate::matrix_api::now_millis;
+use crate::media_download::sha256_file;
+use crate::media_upload::{self, ImageMetadata};
+
+const STAGED_THUMBNAIL_SIZE: u32 = 320;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StagedUpload {
+  pub staged_id: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub room_id: Option<String>,
+  pub path: String,
+  pub file_name: String,
+  pub mimetype: String,
+  pub size: u64,
+  pub sha256: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub image: Option<ImageMetadata>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub thumbnail_path: Option<String>,
+  /// The file exceeds the server limit and is not an image the upload pipeline can shrink.
+  pub too_large: bool,
+  /// Staged ID of an identical file already staged for the same room.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub duplicate_of: Option<String>,
+  pub staged_at: i64,
+}
+
+/// Files dropped onto the composer, waiting to be sent or discarded.
+#[derive(Default)]
+pub struct StagingState {
+  staged: Mutex<HashMap<String, StagedUpload>>,
+}
+
+impl StagingState {
+  /// Record an inspected file, marking it as a duplicate when the same content is already
+  /// staged for the room.
+  pub fn add(&self, mut upload: StagedUpload) -> Result<StagedUpload, String> {
+    let mut staged = self.staged.lock().map_err(|e| e.to_string())?;
+    upload.duplicate_of = staged
+      .values()
+      .filter(|other| other.room_id == upload.room_id && other.sha256 == upload.sha256)
+      .min_by_key(|other| other.staged_at)
+      .map(|other| other.staged_id.clone());
+    staged.insert(upload.staged_id.clone(), upload.clone());
+    Ok(upload)
+  }
+
+  pub fn list(&self, room_id: Option<&str>) -> Result<Vec<StagedUpload>, String> {
+    let staged = self.staged.lock().map_err(|e| e.to_string())?;
+    let mut items: Vec<StagedUpload> = staged
+      .values()
+      .filter(|upload| room_id.is_none() || upload.room_id.as_deref() == room_id)
+      .cloned()
+      .collect();
+    items.sort_by_key(|upload| upload.staged_at);
+    Ok(items)
+  }
+
+  pub fn get(&self, staged_id: &str) -> Result<Option<StagedUpload>, String> {
+    Ok(self.staged.lock().map_err(|e| e.to_string())?.get(staged_id).cloned())
+  }
+
+  /// Forget a staged file, removing the thumbnail generated for it.
+  pub fn discard(&self, staged_id: &str) -> Result<bool, String> {
+    let removed = self.staged.lock().map_err(|e| e.to_string())?.remove(staged_id);
+    match removed {
+      Some(upload) => {
+        if let Some(thumbnail) = upload.thumbnail_path {
+          let _ = fs::remove_file(thumbnail);
+        }
+        Ok(true)
+      }
+      None => Ok(false),
+    }
+  }
+}
+
+/// Hash, type and measure a dropped file, writing a preview thumbnail for images to `thumb_dir`.
+pub fn inspect(path: &Path, room_id: Option<&str>, max_upload_size: Option<u64>, thumb_dir: &Path) -> Result<StagedUpload, String> {
+  let meta = fs::metadata(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+  if !meta.is_file() {
+    return Err(format!("{} is not a file", path.display()));
+  }
+  let size = meta.len();
+  let sha256: String = sha256_file(path)?.iter().map(|b| format!("{:02x}", b)).collect();
+  let file_name = path
+    .file_name()
+    .map(|n| n.to_string_lossy().to_string())
+    .unwrap_or_else(|| "file".to_string());
+  let staged_id = format!("stage-{}-{}", now_millis(), &sha256[..12]);
+
+  let mut head = vec![0u8; 64];
+  let read = fs::File::open(path)
+    .and_then(|mut f| f.read(&mut head))
+    .unwrap_or(0);
+  head.truncate(read);
+  let mimetype = media_upload::guess_mimetype(path, &head);
+  let is_image = mimetype.starts_with("image/");
+
+  let (image, thumbnail_path) = if is_image {
+    let bytes = fs::read(path).map_err(|e| e.to_string())?;
+    let image = media_upload::image_metadata(&bytes);
+    let thumbnail_path = image_processing::thumbnail(&bytes, STAGED_THUMBNAIL_SIZE, STAGED_THUMBNAIL_SIZE)
+      .ok()
+      .and_then(|(data, content_type, _, _)| {
+        fs::create_dir_all(thumb_dir).ok()?;
+        let extension = if content_type == "image/png" { "png" } else { "jpg" };
+        let thumb = thumb_dir.join(format!("{}.{}", staged_id, extension));
+        fs::write(&thumb, data).ok()?;
+        Some(thumb.display().to_string())
+      });
+    (image, thumbnail_path)
+  } else {
+    (None, None)
+  };
+
+  Ok(StagedUpload {
+    staged_id,
+    room_id: room_id.map(|r| r.to_string()),
+    path: path.display().to_string(),
+    file_name,
+    mimetype,
+    size,
+    sha256,
+    image,
+    thumbnail_path,
+    too_large: !is_image && max_upload_size.map(|limit| size > limit).unwrap_or(false),
+    duplicate_of: None,
+    staged_at: now_millis(),
+  })
+}
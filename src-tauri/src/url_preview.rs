@@ -0,0 +1,375 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::matrix_api::now_millis;
+
+const PREVIEW_TTL_MS: i64 = 24 * 60 * 60 * 1000;
+const MAX_HTML_BYTES: usize = 512 * 1024;
+
+/// Query parameters that only identify the click, removed before previewing or sending a link.
+const TRACKING_PARAMS: &[&str] = &[
+  "fbclid", "gclid", "dclid", "gbraid", "wbraid", "msclkid", "yclid", "igshid", "mc_cid", "mc_eid",
+  "_hsenc", "_hsmi", "mkt_tok", "oly_anon_id", "oly_enc_id", "vero_id", "ref_src", "ref_url", "si",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum UrlPreviewMode {
+  /// Ask the homeserver, which fetches the page on our behalf.
+  Homeserver,
+  /// Fetch the page from this device; the site sees our IP address but the homeserver does not see the URL.
+  Direct,
+  Off,
+}
+
+/// Stored globally (scope `''`) and optionally per room, so encrypted rooms can opt out.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UrlPreviewSettings {
+  pub mode: UrlPreviewMode,
+  #[serde(default = "default_true")]
+  pub strip_trackers: bool,
+}
+
+fn default_true() -> bool {
+  true
+}
+
+impl Default for UrlPreviewSettings {
+  fn default() -> Self {
+    UrlPreviewSettings {
+      mode: UrlPreviewMode::Homeserver,
+      strip_trackers: true,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UrlPreview {
+  pub url: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub title: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub description: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub site_name: Option<String>,
+  /// `mxc://` URI from the homeserver, or the page's own image URL for direct fetches.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub image_url: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub image_width: Option<u32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub image_height: Option<u32>,
+  pub fetched_at: i64,
+  /// Older than the cache TTL; only returned when a refresh failed.
+  #[serde(default)]
+  pub stale: bool,
+}
+
+pub fn init_url_preview_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS url_preview_settings (
+        scope TEXT PRIMARY KEY,
+        settings_json TEXT NOT NULL
+      );
+      CREATE TABLE IF NOT EXISTS url_preview_cache (
+        url TEXT PRIMARY KEY,
+        preview_json TEXT NOT NULL,
+        fetched_at INTEGER NOT NULL
+      );
+    ",
+  )
+}
+
+fn load_scope(conn: &Connection, scope: &str) -> Result<Option<UrlPreviewSettings>, String> {
+  let stored: Option<String> = conn
+    .query_row("SELECT settings_json FROM url_preview_settings WHERE scope = ?1", [scope], |row| row.get(0))
+    .optional()
+    .map_err(|e| e.to_string())?;
+  Ok(stored.and_then(|json| serde_json::from_str(&json).ok()))
+}
+
+/// Room settings when present, otherwise the global ones.
+pub fn settings(conn: &Connection, room_id: Option<&str>) -> Result<UrlPreviewSettings, String> {
+  if let Some(room_id) = room_id {
+    if let Some(room) = load_scope(conn, room_id)? {
+      return Ok(room);
+    }
+  }
+  Ok(load_scope(conn, "")?.unwrap_or_default())
+}
+
+pub fn set_settings(conn: &Connection, room_id: Option<&str>, value: Option<&UrlPreviewSettings>) -> Result<(), String> {
+  let scope = room_id.unwrap_or_default();
+  match value {
+    Some(value) => {
+      let json = serde_json::to_string(value).map_err(|e| e.to_string())?;
+      conn
+        .execute(
+          "INSERT INTO url_preview_settings (scope, settings_json) VALUES (?1, ?2)
+           ON CONFLICT(scope) DO UPDATE SET settings_json = excluded.settings_json",
+          params![scope, json],
+        )
+        .map_err(|e| e.to_string())?;
+    }
+    None => {
+      conn
+        .execute("DELETE FROM url_preview_settings WHERE scope = ?1", [scope])
+        .map_err(|e| e.to_string())?;
+    }
+  }
+  Ok(())
+}
+
+/// The cached preview for `url`, with `stale` set once it is older than the TTL.
+pub fn cached(conn: &Connection, url: &str) -> Result<Option<UrlPreview>, String> {
+  let stored: Option<String> = conn
+    .query_row("SELECT preview_json FROM url_preview_cache WHERE url = ?1", [url], |row| row.get(0))
+    .optional()
+    .map_err(|e| e.to_string())?;
+  Ok(stored
+    .and_then(|json| serde_json::from_str::<UrlPreview>(&json).ok())
+    .map(|mut preview| {
+      preview.stale = now_millis() - preview.fetched_at >= PREVIEW_TTL_MS;
+      preview
+    }))
+}
+
+pub fn store(conn: &Connection, preview: &UrlPreview) -> Result<(), String> {
+  let json = serde_json::to_string(preview).map_err(|e| e.to_string())?;
+  conn
+    .execute(
+      "INSERT INTO url_preview_cache (url, preview_json, fetched_at) VALUES (?1, ?2, ?3)
+       ON CONFLICT(url) DO UPDATE SET preview_json = excluded.preview_json, fetched_at = excluded.fetched_at",
+      params![preview.url, json, preview.fetched_at],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+/// `url` without `utm_*` and other click-tracking parameters or an empty fragment.
+pub fn strip_trackers(url: &str) -> String {
+  let Ok(mut parsed) = reqwest::Url::parse(url) else {
+    return url.to_string();
+  };
+  let kept: Vec<(String, String)> = parsed
+    .query_pairs()
+    .filter(|(key, _)| {
+      let key = key.to_ascii_lowercase();
+      !key.starts_with("utm_") && !TRACKING_PARAMS.contains(&key.as_str())
+    })
+    .map(|(key, value)| (key.to_string(), value.to_string()))
+    .collect();
+  if kept.is_empty() {
+    parsed.set_query(None);
+  } else {
+    parsed.query_pairs_mut().clear().extend_pairs(kept);
+  }
+  if parsed.fragment() == Some("") {
+    parsed.set_fragment(None);
+  }
+  parsed.to_string()
+}
+
+fn text(value: Option<&Value>) -> Option<String> {
+  value
+    .and_then(|v| v.as_str())
+    .map(|s| s.trim().to_string())
+    .filter(|s| !s.is_empty())
+}
+
+fn dimension(value: Option<&Value>) -> Option<u32> {
+  value
+    .and_then(|v| v.as_u64().or_else(|| v.as_str().and_then(|s| s.trim().parse().ok())))
+    .map(|n| n as u32)
+}
+
+/// Build a preview from OpenGraph properties, as returned by `/preview_url` or parsed from HTML.
+pub fn from_og(url: &str, og: &Value) -> UrlPreview {
+  UrlPreview {
+    url: url.to_string(),
+    title: text(og.get("og:title")),
+    description: text(og.get("og:description")),
+    site_name: text(og.get("og:site_name")),
+    image_url: text(og.get("og:image")),
+    image_width: dimension(og.get("og:image:width")),
+    image_height: dimension(og.get("og:image:height")),
+    fetched_at: now_millis(),
+    stale: false,
+  }
+}
+
+fn decode_entities(value: &str) -> String {
+  let mut out = String::with_capacity(value.len());
+  let mut rest = value;
+  while let Some(start) = rest.find('&') {
+    out.push_str(&rest[..start]);
+    rest = &rest[start..];
+    let Some(end) = rest.bytes().take(10).position(|b| b == b';') else {
+      out.push('&');
+      rest = &rest[1..];
+      continue;
+    };
+    let entity = &rest[1..end];
+    let decoded = match entity {
+      "amp" => Some('&'),
+      "lt" => Some('<'),
+      "gt" => Some('>'),
+      "quot" => Some('"'),
+      "apos" => Some('\''),
+      "nbsp" => Some(' '),
+      _ => entity
+        .strip_prefix("#x")
+        .or_else(|| entity.strip_prefix("#X"))
+        .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+        .or_else(|| entity.strip_prefix('#').and_then(|dec| dec.parse().ok()))
+        .and_then(char::from_u32),
+    };
+    match decoded {
+      Some(c) => {
+        out.push(c);
+        rest = &rest[end + 1..];
+      }
+      None => {
+        out.push('&');
+        rest = &rest[1..];
+      }
+    }
+  }
+  out.push_str(rest);
+  out
+}
+
+/// Attributes of an HTML start tag body such as `meta property="og:title" content="..."`.
+fn attributes(tag: &str) -> HashMap<String, String> {
+  let mut attrs = HashMap::new();
+  let mut rest = tag;
+  while let Some(eq) = rest.find('=') {
+    let name = rest[..eq].split_whitespace().last().unwrap_or_default().to_ascii_lowercase();
+    let after = rest[eq + 1..].trim_start();
+    let (value, remaining) = match after.chars().next() {
+      Some(quote @ ('"' | '\'')) => match after[1..].find(quote) {
+        Some(end) => (&after[1..end + 1], &after[end + 2..]),
+        None => (&after[1..], ""),
+      },
+      _ => {
+        let end = after.find(char::is_whitespace).unwrap_or(after.len());
+        (&after[..end], &after[end..])
+      }
+    };
+    if !name.is_empty() {
+      attrs.insert(name, decode_entities(value));
+    }
+    rest = remaining;
+  }
+  attrs
+}
+
+/// OpenGraph properties from a page, falling back to `<title>`, the description meta tag and
+/// Twitter cards.
+pub fn parse_html(html: &str, base_url: &str) -> Value {
+  let lower = html.to_ascii_lowercase();
+  let mut og = serde_json::Map::new();
+  let mut fallback = HashMap::new();
+  let mut offset = 0;
+  while let Some(start) = lower[offset..].find("<meta") {
+    let start = offset + start;
+    let Some(end) = lower[start..].find('>') else {
+      break;
+    };
+    let attrs = attributes(&html[start + 5..start + end]);
+    offset = start + end;
+    let Some(content) = attrs.get("content") else {
+      continue;
+    };
+    let key = attrs.get("property").or_else(|| attrs.get("name")).map(|k| k.to_ascii_lowercase());
+    match key {
+      Some(key) if key.starts_with("og:") => {
+        og.entry(key).or_insert_with(|| Value::String(content.clone()));
+      }
+      Some(key) if key == "description" || key.starts_with("twitter:") => {
+        fallback.entry(key).or_insert_with(|| content.clone());
+      }
+      _ => {}
+    }
+  }
+  if !og.contains_key("og:title") {
+    let title = fallback.get("twitter:title").cloned().or_else(|| {
+      let start = lower.find("<title")?;
+      let open_end = start + lower[start..].find('>')? + 1;
+      let close = open_end + lower[open_end..].find("</title")?;
+      Some(decode_entities(html[open_end..close].trim()))
+    });
+    if let Some(title) = title {
+      og.insert("og:title".to_string(), Value::String(title));
+    }
+  }
+  if !og.contains_key("og:description") {
+    if let Some(description) = fallback.get("description").or_else(|| fallback.get("twitter:description")) {
+      og.insert("og:description".to_string(), Value::String(description.clone()));
+    }
+  }
+  if !og.contains_key("og:image") {
+    if let Some(image) = fallback.get("twitter:image") {
+      og.insert("og:image".to_string(), Value::String(image.clone()));
+    }
+  }
+  // Relative image URLs are resolved against the page.
+  if let Some(image) = og.get("og:image").and_then(|v| v.as_str()) {
+    if let Ok(absolute) = reqwest::Url::parse(base_url).and_then(|base| base.join(image)) {
+      og.insert("og:image".to_string(), Value::String(absolute.to_string()));
+    }
+  }
+  Value::Object(og)
+}
+
+/// Fetch `url` from this device and parse its OpenGraph data. Only the first part of the page
+/// is read, and non-HTML responses yield a bare preview.
+pub async fn fetch_direct(url: &str) -> Result<UrlPreview, String> {
+  fetch_page(url).await.map(|(preview, _)| preview)
+}
+
+/// Like [`fetch_direct`], also returning the (size-capped) HTML for HTML pages.
+pub async fn fetch_page(url: &str) -> Result<(UrlPreview, Option<String>), String> {
+  let parsed = reqwest::Url::parse(url).map_err(|e| e.to_string())?;
+  if !matches!(parsed.scheme(), "http" | "https") {
+    return Err("Only http and https links can be previewed".to_string());
+  }
+  let http = crate::proxy::apply(reqwest::Client::builder())
+    .user_agent(concat!("matrix-messenger/", env!("CARGO_PKG_VERSION")))
+    .timeout(Duration::from_secs(15))
+    .build()
+    .map_err(|e| format!("Failed to create HTTP client: {}", e))?;
+  let mut response = http
+    .get(parsed)
+    .header(reqwest::header::ACCEPT, "text/html,application/xhtml+xml")
+    .send()
+    .await
+    .map_err(|e| e.to_string())?;
+  if !response.status().is_success() {
+    return Err(format!("Preview fetch failed: HTTP {}", response.status().as_u16()));
+  }
+  let is_html = response
+    .headers()
+    .get(reqwest::header::CONTENT_TYPE)
+    .and_then(|v| v.to_str().ok())
+    .map(|v| v.contains("html"))
+    .unwrap_or(false);
+  if !is_html {
+    return Ok((from_og(url, &Value::Null), None));
+  }
+  let mut body = Vec::new();
+  while let Some(chunk) = response.chunk().await.map_err(|e| e.to_string())? {
+    body.extend_from_slice(&chunk);
+    if body.len() >= MAX_HTML_BYTES {
+      break;
+    }
+  }
+  let html = String::from_utf8_lossy(&body).into_owned();
+  let preview = from_og(url, &parse_html(&html, url));
+  Ok((preview, Some(html)))
+}
@@ -0,0 +1,215 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::matrix_api::now_millis;
+use crate::media_upload::{self, ImageMetadata};
+
+const POSTER_MAX_WIDTH: u32 = 800;
+const AUDIO_BITRATE_KBPS: u64 = 128;
+const MIN_VIDEO_BITRATE_KBPS: u64 = 150;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VideoInfo {
+  pub duration_ms: u64,
+  pub width: u32,
+  pub height: u32,
+  pub size: u64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub video_codec: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub audio_codec: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProcessedVideo {
+  pub path: String,
+  pub mimetype: String,
+  pub info: VideoInfo,
+  pub poster_path: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub poster: Option<ImageMetadata>,
+  pub transcoded: bool,
+}
+
+/// ffmpeg/ffprobe bundled next to the executable (Tauri sidecar layout) or found on PATH.
+pub fn find_tool(name: &str) -> Option<PathBuf> {
+  let file_name = if cfg!(windows) { format!("{}.exe", name) } else { name.to_string() };
+  if let Some(dir) = env::current_exe().ok().and_then(|exe| exe.parent().map(|p| p.to_path_buf())) {
+    let bundled = dir.join(&file_name);
+    if bundled.is_file() {
+      return Some(bundled);
+    }
+  }
+  env::var_os("PATH").and_then(|paths| {
+    env::split_paths(&paths)
+      .map(|dir| dir.join(&file_name))
+      .find(|candidate| candidate.is_file())
+  })
+}
+
+fn tool(name: &str) -> Result<PathBuf, String> {
+  find_tool(name).ok_or_else(|| format!("{} was not found; install it or bundle it with the app", name))
+}
+
+fn run(command: &mut Command) -> Result<Vec<u8>, String> {
+  let output = command.output().map_err(|e| e.to_string())?;
+  if output.status.success() {
+    Ok(output.stdout)
+  } else {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    Err(stderr.lines().last().unwrap_or("ffmpeg failed").to_string())
+  }
+}
+
+pub fn probe(path: &Path) -> Result<VideoInfo, String> {
+  let stdout = run(
+    Command::new(tool("ffprobe")?)
+      .args(["-v", "error", "-print_format", "json", "-show_format", "-show_streams"])
+      .arg(path),
+  )?;
+  let probe: Value = serde_json::from_slice(&stdout).map_err(|e| e.to_string())?;
+  let streams = probe.get("streams").and_then(|v| v.as_array()).cloned().unwrap_or_default();
+  let stream = |kind: &str| {
+    streams
+      .iter()
+      .find(|s| s.get("codec_type").and_then(|v| v.as_str()) == Some(kind))
+      .cloned()
+  };
+  let video = stream("video").ok_or("File has no video stream")?;
+  let audio = stream("audio");
+  let duration_secs = probe
+    .pointer("/format/duration")
+    .and_then(|v| v.as_str())
+    .and_then(|d| d.parse::<f64>().ok())
+    .unwrap_or_default();
+  // Phones record portrait video as landscape frames with a rotation tag.
+  let rotation = video
+    .pointer("/tags/rotate")
+    .and_then(|v| v.as_str())
+    .and_then(|r| r.parse::<i64>().ok())
+    .or_else(|| {
+      video
+        .get("side_data_list")
+        .and_then(|v| v.as_array())
+        .and_then(|list| list.iter().find_map(|d| d.get("rotation").and_then(|r| r.as_i64())))
+    })
+    .unwrap_or(0);
+  let (mut width, mut height) = (
+    video.get("width").and_then(|v| v.as_u64()).unwrap_or_default() as u32,
+    video.get("height").and_then(|v| v.as_u64()).unwrap_or_default() as u32,
+  );
+  if rotation.abs() % 180 == 90 {
+    std::mem::swap(&mut width, &mut height);
+  }
+  Ok(VideoInfo {
+    duration_ms: (duration_secs * 1000.0) as u64,
+    width,
+    height,
+    size: fs::metadata(path).map(|m| m.len()).unwrap_or_default(),
+    video_codec: video.get("codec_name").and_then(|v| v.as_str()).map(|s| s.to_string()),
+    audio_codec: audio.and_then(|a| a.get("codec_name").and_then(|v| v.as_str()).map(|s| s.to_string())),
+  })
+}
+
+/// Grab a frame a little way in (to skip black intros) as a JPEG poster.
+pub fn extract_poster(path: &Path, info: &VideoInfo, out: &Path) -> Result<(), String> {
+  let at_secs = (info.duration_ms as f64 / 1000.0 * 0.1).min(1.0);
+  run(
+    Command::new(tool("ffmpeg")?)
+      .args(["-y", "-v", "error", "-ss", &format!("{:.3}", at_secs), "-i"])
+      .arg(path)
+      .args([
+        "-frames:v",
+        "1",
+        "-vf",
+        &format!("scale='min({},iw)':-2", POSTER_MAX_WIDTH),
+        "-q:v",
+        "4",
+      ])
+      .arg(out),
+  )?;
+  Ok(())
+}
+
+/// Re-encode to H.264/AAC MP4 with a bitrate chosen so the output lands under `max_bytes`.
+pub fn transcode(path: &Path, info: &VideoInfo, max_bytes: u64, out: &Path) -> Result<(), String> {
+  let duration_secs = (info.duration_ms as f64 / 1000.0).max(1.0);
+  let budget_kbps = (max_bytes as f64 * 8.0 * 0.92 / duration_secs / 1000.0) as u64;
+  let video_kbps = budget_kbps.saturating_sub(AUDIO_BITRATE_KBPS).max(MIN_VIDEO_BITRATE_KBPS);
+  let max_height = if video_kbps < 800 { 480 } else if video_kbps < 2500 { 720 } else { 1080 };
+  run(
+    Command::new(tool("ffmpeg")?)
+      .args(["-y", "-v", "error", "-i"])
+      .arg(path)
+      .args([
+        "-c:v",
+        "libx264",
+        "-preset",
+        "veryfast",
+        "-b:v",
+        &format!("{}k", video_kbps),
+        "-maxrate",
+        &format!("{}k", video_kbps * 3 / 2),
+        "-bufsize",
+        &format!("{}k", video_kbps * 2),
+        "-vf",
+        &format!("scale=-2:'min({},ih)'", max_height),
+        "-pix_fmt",
+        "yuv420p",
+        "-c:a",
+        "aac",
+        "-b:a",
+        &format!("{}k", AUDIO_BITRATE_KBPS),
+        "-movflags",
+        "+faststart",
+      ])
+      .arg(out),
+  )?;
+  Ok(())
+}
+
+/// Probe a video, extract its poster frame and, when asked or when it exceeds
+/// `max_upload_size`, transcode it into `work_dir`.
+pub fn process(path: &Path, work_dir: &Path, transcode_requested: bool, max_upload_size: Option<u64>) -> Result<ProcessedVideo, String> {
+  fs::create_dir_all(work_dir).map_err(|e| e.to_string())?;
+  let stem = format!("video-{}", now_millis());
+  let mut info = probe(path)?;
+  let poster_path = work_dir.join(format!("{}-poster.jpg", stem));
+  extract_poster(path, &info, &poster_path)?;
+  let poster = fs::read(&poster_path).ok().and_then(|bytes| media_upload::image_metadata(&bytes));
+
+  let oversized = max_upload_size.map(|limit| info.size > limit).unwrap_or(false);
+  let mut output = path.to_path_buf();
+  let mut mimetype = media_upload::guess_mimetype(path, &[]);
+  let transcoded = transcode_requested || oversized;
+  if transcoded {
+    let target = max_upload_size.map(|limit| limit.min(info.size)).unwrap_or(info.size);
+    let out = work_dir.join(format!("{}.mp4", stem));
+    transcode(path, &info, target, &out)?;
+    info = probe(&out)?;
+    if let Some(limit) = max_upload_size {
+      if info.size > limit {
+        return Err(format!(
+          "Video is still {} bytes after transcoding; the server accepts at most {} bytes",
+          info.size, limit
+        ));
+      }
+    }
+    output = out;
+    mimetype = "video/mp4".to_string();
+  }
+  Ok(ProcessedVideo {
+    path: output.display().to_string(),
+    mimetype,
+    info,
+    poster_path: poster_path.display().to_string(),
+    poster,
+    transcoded,
+  })
+}
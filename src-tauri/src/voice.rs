@@ -0,0 +1,306 @@
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, SampleFormat, SizedSample};
+use ogg::{PacketReader, PacketWriteEndInfo, PacketWriter};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufWriter, Cursor};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use crate::matrix_api::now_millis;
+
+const OPUS_SAMPLE_RATE: u32 = 48_000;
+const FRAME_SAMPLES: usize = 960;
+const OPUS_BITRATE: i32 = 24_000;
+const WAVEFORM_POINTS: usize = 100;
+const OGG_SERIAL: u32 = 0x4d4d_5643;
+const MAX_FRAME_SAMPLES: usize = 5760;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VoiceRecording {
+  pub path: String,
+  pub mimetype: String,
+  pub size: u64,
+  pub duration_ms: u64,
+  /// MSC3246 waveform: amplitudes scaled to 0..=1024.
+  pub waveform: Vec<u16>,
+}
+
+struct Captured {
+  samples: Vec<f32>,
+  sample_rate: u32,
+}
+
+struct ActiveRecording {
+  stop: mpsc::Sender<()>,
+  handle: JoinHandle<Result<Captured, String>>,
+}
+
+/// The microphone capture in progress, if any. cpal streams are not `Send`, so capture
+/// runs on its own thread and is stopped through a channel.
+#[derive(Default)]
+pub struct VoiceRecorderState {
+  active: Mutex<Option<ActiveRecording>>,
+}
+
+fn capture<T>(device: &cpal::Device, config: &cpal::StreamConfig, buffer: Arc<Mutex<Vec<f32>>>) -> Result<cpal::Stream, String>
+where
+  T: SizedSample,
+  f32: FromSample<T>,
+{
+  let channels = config.channels.max(1) as usize;
+  device
+    .build_input_stream(
+      config,
+      move |data: &[T], _| {
+        if let Ok(mut buffer) = buffer.lock() {
+          for frame in data.chunks(channels) {
+            let sum: f32 = frame.iter().map(|s| f32::from_sample(*s)).sum();
+            buffer.push(sum / frame.len() as f32);
+          }
+        }
+      },
+      |err| tracing::warn!("Voice capture error: {}", err),
+      None,
+    )
+    .map_err(|e| format!("Failed to open microphone: {}", e))
+}
+
+fn record_until_stopped(stop: mpsc::Receiver<()>, ready: mpsc::Sender<Result<(), String>>) -> Result<Captured, String> {
+  let setup = || -> Result<(cpal::Stream, Arc<Mutex<Vec<f32>>>, u32), String> {
+    let device = cpal::default_host()
+      .default_input_device()
+      .ok_or("No microphone is available")?;
+    let supported = device
+      .default_input_config()
+      .map_err(|e| format!("Failed to query microphone: {}", e))?;
+    let sample_rate = supported.sample_rate().0;
+    let config: cpal::StreamConfig = supported.config();
+    let buffer = Arc::new(Mutex::new(Vec::new()));
+    let stream = match supported.sample_format() {
+      SampleFormat::F32 => capture::<f32>(&device, &config, buffer.clone())?,
+      SampleFormat::I16 => capture::<i16>(&device, &config, buffer.clone())?,
+      SampleFormat::U16 => capture::<u16>(&device, &config, buffer.clone())?,
+      other => return Err(format!("Unsupported microphone sample format: {:?}", other)),
+    };
+    stream.play().map_err(|e| format!("Failed to start recording: {}", e))?;
+    Ok((stream, buffer, sample_rate))
+  };
+  let (stream, buffer, sample_rate) = match setup() {
+    Ok(started) => {
+      let _ = ready.send(Ok(()));
+      started
+    }
+    Err(err) => {
+      let _ = ready.send(Err(err.clone()));
+      return Err(err);
+    }
+  };
+  let _ = stop.recv();
+  drop(stream);
+  let samples = buffer.lock().map(|b| b.clone()).unwrap_or_default();
+  Ok(Captured { samples, sample_rate })
+}
+
+impl VoiceRecorderState {
+  pub fn start(&self) -> Result<i64, String> {
+    let mut active = self.active.lock().map_err(|e| e.to_string())?;
+    if active.is_some() {
+      return Err("A voice message is already being recorded".to_string());
+    }
+    let (stop_tx, stop_rx) = mpsc::channel();
+    let (ready_tx, ready_rx) = mpsc::channel();
+    let handle = std::thread::spawn(move || record_until_stopped(stop_rx, ready_tx));
+    ready_rx
+      .recv()
+      .map_err(|_| "Recording thread exited unexpectedly".to_string())??;
+    *active = Some(ActiveRecording { stop: stop_tx, handle });
+    Ok(now_millis())
+  }
+
+  fn finish(&self) -> Result<Captured, String> {
+    let recording = self
+      .active
+      .lock()
+      .map_err(|e| e.to_string())?
+      .take()
+      .ok_or("No voice message is being recorded")?;
+    let _ = recording.stop.send(());
+    recording
+      .handle
+      .join()
+      .map_err(|_| "Recording thread panicked".to_string())?
+  }
+
+  /// Stop capturing and encode the audio to Ogg/Opus in `out_dir`.
+  pub fn stop(&self, out_dir: &Path) -> Result<VoiceRecording, String> {
+    let captured = self.finish()?;
+    encode_recording(&captured, out_dir)
+  }
+
+  pub fn cancel(&self) -> Result<bool, String> {
+    match self.finish() {
+      Ok(_) => Ok(true),
+      Err(_) => Ok(false),
+    }
+  }
+}
+
+/// Linear resampling to 48 kHz, which is all Opus accepts for our purposes.
+fn resample(samples: &[f32], from_rate: u32) -> Vec<f32> {
+  if from_rate == OPUS_SAMPLE_RATE || samples.is_empty() {
+    return samples.to_vec();
+  }
+  let ratio = from_rate as f64 / OPUS_SAMPLE_RATE as f64;
+  let out_len = (samples.len() as f64 / ratio) as usize;
+  (0..out_len)
+    .map(|i| {
+      let pos = i as f64 * ratio;
+      let idx = pos as usize;
+      let frac = (pos - idx as f64) as f32;
+      let a = samples[idx.min(samples.len() - 1)];
+      let b = samples[(idx + 1).min(samples.len() - 1)];
+      a + (b - a) * frac
+    })
+    .collect()
+}
+
+pub fn waveform(samples: &[f32]) -> Vec<u16> {
+  if samples.is_empty() {
+    return Vec::new();
+  }
+  let chunk = (samples.len() / WAVEFORM_POINTS).max(1);
+  let levels: Vec<f32> = samples
+    .chunks(chunk)
+    .take(WAVEFORM_POINTS)
+    .map(|c| (c.iter().map(|s| s * s).sum::<f32>() / c.len() as f32).sqrt())
+    .collect();
+  let peak = levels.iter().cloned().fold(0.0f32, f32::max).max(f32::EPSILON);
+  levels
+    .iter()
+    .map(|level| ((level / peak) * 1024.0).round().min(1024.0) as u16)
+    .collect()
+}
+
+fn opus_head(pre_skip: u16, input_rate: u32) -> Vec<u8> {
+  let mut head = b"OpusHead".to_vec();
+  head.push(1);
+  head.push(1);
+  head.extend_from_slice(&pre_skip.to_le_bytes());
+  head.extend_from_slice(&input_rate.to_le_bytes());
+  head.extend_from_slice(&0i16.to_le_bytes());
+  head.push(0);
+  head
+}
+
+fn opus_tags() -> Vec<u8> {
+  let vendor = concat!("matrix-messenger ", env!("CARGO_PKG_VERSION"));
+  let mut tags = b"OpusTags".to_vec();
+  tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+  tags.extend_from_slice(vendor.as_bytes());
+  tags.extend_from_slice(&0u32.to_le_bytes());
+  tags
+}
+
+/// Encode mono 48 kHz samples into an Ogg/Opus file.
+pub fn encode_ogg_opus(samples: &[f32], input_rate: u32, out: &Path) -> Result<(), String> {
+  let mut encoder = opus::Encoder::new(OPUS_SAMPLE_RATE, opus::Channels::Mono, opus::Application::Voip)
+    .map_err(|e| format!("Failed to create Opus encoder: {}", e))?;
+  encoder
+    .set_bitrate(opus::Bitrate::Bits(OPUS_BITRATE))
+    .map_err(|e| e.to_string())?;
+  let pre_skip = encoder.get_lookahead().unwrap_or(312).max(0) as u16;
+
+  let file = File::create(out).map_err(|e| e.to_string())?;
+  let mut writer = PacketWriter::new(BufWriter::new(file));
+  writer
+    .write_packet(opus_head(pre_skip, input_rate), OGG_SERIAL, PacketWriteEndInfo::EndPage, 0)
+    .map_err(|e| e.to_string())?;
+  writer
+    .write_packet(opus_tags(), OGG_SERIAL, PacketWriteEndInfo::EndPage, 0)
+    .map_err(|e| e.to_string())?;
+
+  let frames: Vec<&[f32]> = samples.chunks(FRAME_SAMPLES).collect();
+  let mut packet = vec![0u8; 4000];
+  let mut granule = pre_skip as u64;
+  for (index, frame) in frames.iter().enumerate() {
+    let mut input = frame.to_vec();
+    input.resize(FRAME_SAMPLES, 0.0);
+    let len = encoder
+      .encode_float(&input, &mut packet)
+      .map_err(|e| format!("Opus encoding failed: {}", e))?;
+    granule += FRAME_SAMPLES as u64;
+    let end = if index + 1 == frames.len() {
+      PacketWriteEndInfo::EndStream
+    } else {
+      PacketWriteEndInfo::NormalPacket
+    };
+    writer
+      .write_packet(packet[..len].to_vec(), OGG_SERIAL, end, granule)
+      .map_err(|e| e.to_string())?;
+  }
+  Ok(())
+}
+
+/// Decode an Ogg/Opus file to mono 48 kHz samples, dropping the encoder pre-skip.
+pub fn decode_ogg_opus(bytes: &[u8]) -> Result<Vec<f32>, String> {
+  let mut reader = PacketReader::new(Cursor::new(bytes));
+  let mut decoder: Option<(opus::Decoder, usize)> = None;
+  let mut pre_skip = 0;
+  let mut samples = Vec::new();
+  let mut pcm = vec![0f32; MAX_FRAME_SAMPLES * 2];
+  while let Some(packet) = reader.read_packet().map_err(|e| format!("Failed to read Ogg stream: {}", e))? {
+    if packet.data.starts_with(b"OpusHead") && packet.data.len() >= 12 {
+      let channels = packet.data[9].max(1) as usize;
+      let layout = match channels {
+        1 => opus::Channels::Mono,
+        2 => opus::Channels::Stereo,
+        _ => return Err(format!("Unsupported Opus channel count: {}", channels)),
+      };
+      pre_skip = u16::from_le_bytes([packet.data[10], packet.data[11]]) as usize;
+      let created = opus::Decoder::new(OPUS_SAMPLE_RATE, layout).map_err(|e| format!("Failed to create Opus decoder: {}", e))?;
+      decoder = Some((created, channels));
+      continue;
+    }
+    if packet.data.starts_with(b"OpusTags") {
+      continue;
+    }
+    let Some((decoder, channels)) = decoder.as_mut() else {
+      return Err("Not an Ogg/Opus file".to_string());
+    };
+    let frames = decoder
+      .decode_float(&packet.data, &mut pcm, false)
+      .map_err(|e| format!("Opus decoding failed: {}", e))?;
+    samples.extend(pcm[..frames * *channels].chunks(*channels).map(|frame| frame.iter().sum::<f32>() / *channels as f32));
+  }
+  if decoder.is_none() {
+    return Err("Not an Ogg/Opus file".to_string());
+  }
+  samples.drain(..pre_skip.min(samples.len()));
+  Ok(samples)
+}
+
+/// Duration of 48 kHz samples in milliseconds.
+pub fn duration_ms(samples: &[f32]) -> u64 {
+  samples.len() as u64 * 1000 / OPUS_SAMPLE_RATE as u64
+}
+
+fn encode_recording(captured: &Captured, out_dir: &Path) -> Result<VoiceRecording, String> {
+  if captured.samples.is_empty() {
+    return Err("The recording is empty".to_string());
+  }
+  fs::create_dir_all(out_dir).map_err(|e| e.to_string())?;
+  let samples = resample(&captured.samples, captured.sample_rate);
+  let path: PathBuf = out_dir.join(format!("voice-{}.ogg", now_millis()));
+  encode_ogg_opus(&samples, captured.sample_rate, &path)?;
+  Ok(VoiceRecording {
+    path: path.display().to_string(),
+    mimetype: "audio/ogg".to_string(),
+    size: fs::metadata(&path).map(|m| m.len()).unwrap_or_default(),
+    duration_ms: duration_ms(&samples),
+    waveform: waveform(&samples),
+  })
+}
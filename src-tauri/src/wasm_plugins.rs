@@ -0,0 +1,409 @@
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use wasmtime::{Caller, Config, Engine, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+use crate::matrix_api::now_millis;
+
+/// Version of the host API below; plugins declare the one they were built against.
+pub const API_VERSION: u32 = 1;
+pub const MANIFEST_FILE: &str = "plugin.json";
+/// Permissions a plugin can ask for; each unlocks one export.
+pub const PERMISSIONS: &[&str] = &["messages.transform", "commands", "collections"];
+
+const FUEL_PER_CALL: u64 = 50_000_000;
+const MAX_MEMORY: usize = 64 * 1024 * 1024;
+const MAX_OUTPUT: usize = 4 * 1024 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PluginCommand {
+  /// Without the leading slash.
+  pub name: String,
+  #[serde(default)]
+  pub description: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PluginCollection {
+  pub id: String,
+  pub label: String,
+  #[serde(default)]
+  pub description: String,
+}
+
+/// `plugin.json` next to the module in `<plugins dir>/<id>/`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PluginManifest {
+  pub id: String,
+  pub name: String,
+  #[serde(default)]
+  pub version: String,
+  #[serde(default)]
+  pub description: String,
+  pub api_version: u32,
+  /// Path of the `.wasm` module relative to the plugin folder.
+  pub module: String,
+  #[serde(default)]
+  pub permissions: Vec<String>,
+  #[serde(default)]
+  pub commands: Vec<PluginCommand>,
+  #[serde(default)]
+  pub collections: Vec<PluginCollection>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PluginInfo {
+  pub id: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub manifest: Option<PluginManifest>,
+  pub enabled: bool,
+  /// Requested permissions the user allowed.
+  pub granted: Vec<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CommandResult {
+  /// Shown to the user only.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub reply: Option<String>,
+  /// Message content to send to the room.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub send: Option<Value>,
+}
+
+/// A plugin's grant as stored in the index database.
+#[derive(Debug, Clone, Default)]
+pub struct Grant {
+  pub enabled: bool,
+  pub permissions: Vec<String>,
+}
+
+struct LoadedPlugin {
+  manifest: PluginManifest,
+  module: Module,
+  grant: Grant,
+}
+
+impl LoadedPlugin {
+  fn allows(&self, permission: &str) -> bool {
+    self.grant.enabled
+      && self.manifest.permissions.iter().any(|p| p == permission)
+      && self.grant.permissions.iter().any(|p| p == permission)
+  }
+}
+
+struct HostState {
+  plugin_id: String,
+  limits: StoreLimits,
+}
+
+/// Compiled plugins. Every call runs in a fresh instance with a fuel and memory budget and no
+/// access to files, network or the clock beyond the host functions.
+pub struct PluginRuntime {
+  /// Without fuel metering no plugin may run, so a failed engine is kept as its error.
+  engine: Result<Engine, String>,
+  plugins: RwLock<Vec<Arc<LoadedPlugin>>>,
+}
+
+impl Default for PluginRuntime {
+  fn default() -> Self {
+    let mut config = Config::new();
+    config.consume_fuel(true);
+    PluginRuntime {
+      engine: Engine::new(&config).map_err(|e| format!("Plugin engine unavailable: {}", e)),
+      plugins: RwLock::new(Vec::new()),
+    }
+  }
+}
+
+pub fn init_wasm_plugins_db(conn: &Connection) -> Result<(), rusqlite::Error> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS wasm_plugins (
+        id TEXT PRIMARY KEY,
+        enabled INTEGER NOT NULL,
+        permissions_json TEXT NOT NULL,
+        updated_at INTEGER NOT NULL
+      );
+    ",
+  )
+}
+
+pub fn grants(conn: &Connection) -> Result<HashMap<String, Grant>, String> {
+  let mut stmt = conn
+    .prepare("SELECT id, enabled, permissions_json FROM wasm_plugins")
+    .map_err(|e| e.to_string())?;
+  let rows = stmt
+    .query_map([], |row| {
+      let permissions: String = row.get(2)?;
+      Ok((
+        row.get::<_, String>(0)?,
+        Grant {
+          enabled: row.get::<_, i64>(1)? != 0,
+          permissions: serde_json::from_str(&permissions).unwrap_or_default(),
+        },
+      ))
+    })
+    .map_err(|e| e.to_string())?;
+  Ok(rows.flatten().collect())
+}
+
+pub fn set_grant(conn: &Connection, plugin_id: &str, grant: &Grant) -> Result<(), String> {
+  let permissions = serde_json::to_string(&grant.permissions).map_err(|e| e.to_string())?;
+  conn
+    .execute(
+      "INSERT INTO wasm_plugins (id, enabled, permissions_json, updated_at) VALUES (?1, ?2, ?3, ?4)
+       ON CONFLICT(id) DO UPDATE SET enabled = excluded.enabled, permissions_json = excluded.permissions_json,
+         updated_at = excluded.updated_at",
+      params![plugin_id, grant.enabled as i64, permissions, now_millis()],
+    )
+    .map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+fn read_manifest(dir: &Path) -> Result<PluginManifest, String> {
+  let text = fs::read_to_string(dir.join(MANIFEST_FILE)).map_err(|e| format!("No {}: {}", MANIFEST_FILE, e))?;
+  let manifest: PluginManifest = serde_json::from_str(&text).map_err(|e| format!("Invalid {}: {}", MANIFEST_FILE, e))?;
+  if manifest.api_version != API_VERSION {
+    return Err(format!("Built for host API {}, this app provides {}", manifest.api_version, API_VERSION));
+  }
+  // Ids namespace commands and collections as `<id>:<name>`.
+  if manifest.id.trim().is_empty() || manifest.id.contains(':') {
+    return Err(format!("Invalid plugin id {:?}", manifest.id));
+  }
+  if let Some(unknown) = manifest.permissions.iter().find(|p| !PERMISSIONS.contains(&p.as_str())) {
+    return Err(format!("Unknown permission {}", unknown));
+  }
+  Ok(manifest)
+}
+
+/// Where the manifest's module lives; paths that leave the plugin folder are refused.
+fn module_path(folder: &Path, module: &str) -> Result<PathBuf, String> {
+  let relative = Path::new(module);
+  let plain = relative.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+  if module.is_empty() || !plain {
+    return Err(format!("Module path {} is outside the plugin folder", module));
+  }
+  let path = folder.join(relative);
+  let root = folder.canonicalize().map_err(|e| e.to_string())?;
+  let resolved = path.canonicalize().map_err(|e| format!("No module {}: {}", module, e))?;
+  if !resolved.starts_with(&root) {
+    return Err(format!("Module path {} is outside the plugin folder", module));
+  }
+  Ok(resolved)
+}
+
+fn read_guest_string(caller: &mut Caller<'_, HostState>, ptr: i32, len: i32) -> Option<String> {
+  let memory = caller.get_export("memory")?.into_memory()?;
+  let mut bytes = vec![0u8; len.max(0) as usize];
+  memory.read(&*caller, ptr as usize, &mut bytes).ok()?;
+  String::from_utf8(bytes).ok()
+}
+
+impl PluginRuntime {
+  fn engine(&self) -> Result<&Engine, String> {
+    self.engine.as_ref().map_err(|e| e.clone())
+  }
+
+  /// Compile every plugin under `dir` and apply the stored grants. Returns one entry per
+  /// plugin folder, including the ones that failed to load.
+  pub fn reload(&self, dir: &Path, grants: &HashMap<String, Grant>) -> Vec<PluginInfo> {
+    let _ = fs::create_dir_all(dir);
+    let mut infos = Vec::new();
+    let mut loaded = Vec::new();
+    let mut folders: Vec<PathBuf> = fs::read_dir(dir)
+      .map(|entries| entries.flatten().map(|e| e.path()).filter(|p| p.is_dir()).collect())
+      .unwrap_or_default();
+    folders.sort();
+    let mut seen = HashSet::new();
+    for folder in folders {
+      let folder_id = folder.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+      let result = read_manifest(&folder).and_then(|manifest| {
+        if !seen.insert(manifest.id.clone()) {
+          return Err(format!("Another plugin already uses the id {}", manifest.id));
+        }
+        let module = Module::from_file(self.engine()?, module_path(&folder, &manifest.module)?)
+          .map_err(|e| format!("Failed to compile {}: {}", manifest.module, e))?;
+        Ok((manifest, module))
+      });
+      match result {
+        Ok((manifest, module)) => {
+          let grant = grants.get(&manifest.id).cloned().unwrap_or_default();
+          infos.push(PluginInfo {
+            id: manifest.id.clone(),
+            manifest: Some(manifest.clone()),
+            enabled: grant.enabled,
+            granted: grant.permissions.clone(),
+            error: None,
+          });
+          loaded.push(Arc::new(LoadedPlugin { manifest, module, grant }));
+        }
+        Err(err) => {
+          tracing::warn!("Plugin {} not loaded: {}", folder_id, err);
+          infos.push(PluginInfo {
+            id: folder_id,
+            manifest: None,
+            enabled: false,
+            granted: Vec::new(),
+            error: Some(err),
+          });
+        }
+      }
+    }
+    if let Ok(mut plugins) = self.plugins.write() {
+      *plugins = loaded;
+    }
+    infos
+  }
+
+  fn plugins_allowing(&self, permission: &str) -> Vec<Arc<LoadedPlugin>> {
+    self
+      .plugins
+      .read()
+      .map(|plugins| plugins.iter().filter(|p| p.allows(permission)).cloned().collect())
+      .unwrap_or_default()
+  }
+
+  /// Call `export(ptr, len) -> i64` with JSON input. The result packs the output pointer in the
+  /// high and its length in the low 32 bits; length 0 means "no result".
+  fn call(&self, plugin: &LoadedPlugin, export: &str, input: &Value) -> Result<Option<Value>, String> {
+    let engine = self.engine()?;
+    let mut store = Store::new(
+      engine,
+      HostState {
+        plugin_id: plugin.manifest.id.clone(),
+        limits: StoreLimitsBuilder::new().memory_size(MAX_MEMORY).instances(1).build(),
+      },
+    );
+    store.limiter(|state| &mut state.limits);
+    store.set_fuel(FUEL_PER_CALL).map_err(|e| e.to_string())?;
+    let mut linker: Linker<HostState> = Linker::new(engine);
+    linker
+      .func_wrap("host", "log", |mut caller: Caller<'_, HostState>, level: i32, ptr: i32, len: i32| {
+        let message = read_guest_string(&mut caller, ptr, len).unwrap_or_default();
+        let plugin = caller.data().plugin_id.clone();
+        match level {
+          0 => tracing::error!(target: "plugins", "[{}] {}", plugin, message),
+          1 => tracing::warn!(target: "plugins", "[{}] {}", plugin, message),
+          _ => tracing::info!(target: "plugins", "[{}] {}", plugin, message),
+        }
+      })
+      .map_err(|e| e.to_string())?;
+    linker
+      .func_wrap("host", "now", || -> i64 { now_millis() })
+      .map_err(|e| e.to_string())?;
+    let instance = linker
+      .instantiate(&mut store, &plugin.module)
+      .map_err(|e| format!("{}: {}", plugin.manifest.id, e))?;
+    let Ok(func) = instance.get_typed_func::<(i32, i32), i64>(&mut store, export) else {
+      return Ok(None);
+    };
+    let memory = instance
+      .get_memory(&mut store, "memory")
+      .ok_or_else(|| format!("{} exports no memory", plugin.manifest.id))?;
+    let alloc = instance
+      .get_typed_func::<i32, i32>(&mut store, "alloc")
+      .map_err(|_| format!("{} exports no alloc", plugin.manifest.id))?;
+    let bytes = serde_json::to_vec(input).map_err(|e| e.to_string())?;
+    let ptr = alloc.call(&mut store, bytes.len() as i32).map_err(|e| e.to_string())?;
+    memory.write(&mut store, ptr as usize, &bytes).map_err(|e| e.to_string())?;
+    let packed = func
+      .call(&mut store, (ptr, bytes.len() as i32))
+      .map_err(|e| format!("{} failed in {}: {}", plugin.manifest.id, export, e))?;
+    let (out_ptr, out_len) = ((packed as u64 >> 32) as usize, (packed as u64 & 0xffff_ffff) as usize);
+    if out_len == 0 {
+      return Ok(None);
+    }
+    if out_len > MAX_OUTPUT {
+      return Err(format!("{} returned too much data", plugin.manifest.id));
+    }
+    let mut out = vec![0u8; out_len];
+    memory.read(&store, out_ptr, &mut out).map_err(|e| e.to_string())?;
+    serde_json::from_slice(&out).map(Some).map_err(|e| format!("{} returned invalid JSON: {}", plugin.manifest.id, e))
+  }
+
+  /// Run outgoing message content through every transformer, in plugin order. A plugin that
+  /// fails or returns something that isn't message content is skipped.
+  pub fn transform_message(&self, room_id: &str, content: Value) -> Value {
+    self.plugins_allowing("messages.transform").iter().fold(content, |content, plugin| {
+      match self.call(plugin, "transform_message", &json!({ "roomId": room_id, "content": content })) {
+        Ok(Some(next)) if next.get("msgtype").is_some() && next.get("body").is_some_and(|b| b.is_string()) => next,
+        Ok(_) => content,
+        Err(err) => {
+          tracing::warn!(target: "plugins", "{}", err);
+          content
+        }
+      }
+    })
+  }
+
+  /// Slash commands of enabled plugins, as `(plugin id, command)`.
+  pub fn commands(&self) -> Vec<(String, PluginCommand)> {
+    self
+      .plugins_allowing("commands")
+      .iter()
+      .flat_map(|p| p.manifest.commands.iter().map(|c| (p.manifest.id.clone(), c.clone())))
+      .collect()
+  }
+
+  /// Run `/name args` if a plugin registered it; `None` when no plugin handles it.
+  pub fn run_command(&self, room_id: &str, name: &str, args: &str) -> Result<Option<CommandResult>, String> {
+    let name = name.trim_start_matches('/');
+    let Some(plugin) = self
+      .plugins_allowing("commands")
+      .into_iter()
+      .find(|p| p.manifest.commands.iter().any(|c| c.name == name))
+    else {
+      return Ok(None);
+    };
+    let output = self.call(&plugin, "run_command", &json!({ "roomId": room_id, "command": name, "args": args }))?;
+    Ok(Some(match output {
+      Some(value) => serde_json::from_value(value).map_err(|e| format!("{}: invalid command result: {}", plugin.manifest.id, e))?,
+      None => CommandResult::default(),
+    }))
+  }
+
+  /// Collections declared by enabled providers, as `(plugin id, collection)`.
+  pub fn collections(&self) -> Vec<(String, PluginCollection)> {
+    self
+      .plugins_allowing("collections")
+      .iter()
+      .flat_map(|p| p.manifest.collections.iter().map(|c| (p.manifest.id.clone(), c.clone())))
+      .collect()
+  }
+
+  /// Ask a provider which of `messages` belong to its collection; returns their event IDs.
+  pub fn collection_members(&self, plugin_id: &str, collection_id: &str, messages: &[Value]) -> Result<Vec<String>, String> {
+    let Some(plugin) = self
+      .plugins_allowing("collections")
+      .into_iter()
+      .find(|p| p.manifest.id == plugin_id)
+    else {
+      return Ok(Vec::new());
+    };
+    let output = self.call(&plugin, "collect", &json!({ "collectionId": collection_id, "messages": messages }))?;
+    Ok(output
+      .and_then(|v| v.get("eventIds").cloned())
+      .and_then(|ids| serde_json::from_value(ids).ok())
+      .unwrap_or_default())
+  }
+}
+
+/// `smart:plugin:<plugin>:<collection>`
+pub fn collection_token(plugin_id: &str, collection_id: &str) -> String {
+  format!("smart:plugin:{}:{}", plugin_id, collection_id)
+}
+
+pub fn parse_collection_token(token: &str) -> Option<(&str, &str)> {
+  token.strip_prefix("smart:plugin:")?.split_once(':')
+}
@@ -0,0 +1,286 @@
+use futures_util::future::BoxFuture;
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+
+const MAX_BODY: usize = 1024 * 1024;
+const MAX_HEADERS: usize = 64;
+const MAX_HEAD_LINE: u64 = 8 * 1024;
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookSettings {
+  pub enabled: bool,
+  /// Listens on 127.0.0.1 only.
+  pub port: u16,
+  /// Sent by callers as `Authorization: Bearer <token>`.
+  pub token: String,
+  /// Account messages are sent from; the first account when unset.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub account_key: Option<String>,
+  /// Rooms callers may post to; any joined room when empty.
+  #[serde(default)]
+  pub allowed_rooms: Vec<String>,
+}
+
+impl Default for WebhookSettings {
+  fn default() -> Self {
+    WebhookSettings {
+      enabled: false,
+      port: 8737,
+      token: String::new(),
+      account_key: None,
+      allowed_rooms: Vec::new(),
+    }
+  }
+}
+
+/// Body of `POST /rooms/{roomId}/send`. A `text/plain` body is taken as `text`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SendRequest {
+  pub text: String,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub html: Option<String>,
+  /// `m.text` or `m.notice`; bots usually want notices.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub msgtype: Option<String>,
+}
+
+impl SendRequest {
+  pub fn content(&self) -> Value {
+    let msgtype = match self.msgtype.as_deref() {
+      Some("m.notice") => "m.notice",
+      _ => "m.text",
+    };
+    let mut content = json!({ "msgtype": msgtype, "body": self.text });
+    if let Some(html) = &self.html {
+      content["format"] = json!("org.matrix.custom.html");
+      content["formatted_body"] = json!(html);
+    }
+    content
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct WebhookRequest {
+  pub method: String,
+  pub path: Vec<String>,
+  pub content_type: String,
+  pub body: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct WebhookResponse {
+  pub status: u16,
+  pub body: Value,
+}
+
+impl WebhookResponse {
+  pub fn ok(body: Value) -> Self {
+    WebhookResponse { status: 200, body }
+  }
+
+  pub fn error(status: u16, message: impl Into<String>) -> Self {
+    WebhookResponse { status, body: json!({ "error": message.into() }) }
+  }
+}
+
+pub type Handler = Arc<dyn Fn(WebhookRequest) -> BoxFuture<'static, WebhookResponse> + Send + Sync>;
+
+/// The running listener; replacing or dropping the sender stops it.
+#[derive(Default)]
+pub struct WebhookState {
+  stop: Mutex<Option<watch::Sender<bool>>>,
+}
+
+impl WebhookState {
+  pub fn replace(&self, stop: Option<watch::Sender<bool>>) {
+    if let Ok(mut current) = self.stop.lock() {
+      if let Some(previous) = current.take() {
+        let _ = previous.send(true);
+      }
+      *current = stop;
+    }
+  }
+}
+
+pub fn generate_token() -> String {
+  let mut bytes = [0u8; 24];
+  OsRng.fill_bytes(&mut bytes);
+  bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn tokens_match(given: &str, expected: &str) -> bool {
+  given.len() == expected.len() && given.bytes().zip(expected.bytes()).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn reason(status: u16) -> &'static str {
+  match status {
+    200 => "OK",
+    202 => "Accepted",
+    400 => "Bad Request",
+    401 => "Unauthorized",
+    403 => "Forbidden",
+    404 => "Not Found",
+    405 => "Method Not Allowed",
+    413 => "Payload Too Large",
+    431 => "Request Header Fields Too Large",
+    501 => "Not Implemented",
+    _ => "Internal Server Error",
+  }
+}
+
+async fn respond(stream: &mut TcpStream, response: &WebhookResponse) {
+  let body = response.body.to_string();
+  let head = format!(
+    "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+    response.status,
+    reason(response.status),
+    body.len()
+  );
+  let _ = stream.write_all(head.as_bytes()).await;
+  let _ = stream.write_all(body.as_bytes()).await;
+  let _ = stream.shutdown().await;
+}
+
+fn decode_path(target: &str) -> Vec<String> {
+  target
+    .split('?')
+    .next()
+    .unwrap_or_default()
+    .split('/')
+    .filter(|segment| !segment.is_empty())
+    .map(|segment| urlencoding::decode(segment).map(|s| s.into_owned()).unwrap_or_else(|_| segment.to_string()))
+    .collect()
+}
+
+/// Read one line of the request head into `line`, refusing lines longer than `MAX_HEAD_LINE`.
+async fn read_head_line(reader: &mut BufReader<&mut TcpStream>, line: &mut String) -> Result<(), WebhookResponse> {
+  line.clear();
+  let read = reader
+    .take(MAX_HEAD_LINE)
+    .read_line(line)
+    .await
+    .map_err(|_| WebhookResponse::error(400, "Malformed request"))?;
+  if read as u64 >= MAX_HEAD_LINE && !line.ends_with('\n') {
+    return Err(WebhookResponse::error(431, "Request line or header too long"));
+  }
+  Ok(())
+}
+
+/// Parse one request and check its token before anything else happens.
+async fn read_request(stream: &mut TcpStream, token: &str) -> Result<WebhookRequest, WebhookResponse> {
+  let mut reader = BufReader::new(stream);
+  let mut line = String::new();
+  read_head_line(&mut reader, &mut line).await?;
+  let mut parts = line.split_whitespace();
+  let method = parts.next().unwrap_or_default().to_ascii_uppercase();
+  let target = parts.next().unwrap_or_default().to_string();
+  let mut content_length = 0usize;
+  let mut content_type = String::new();
+  let mut authorized = target
+    .split_once('?')
+    .map(|(_, query)| {
+      query
+        .split('&')
+        .any(|pair| pair.strip_prefix("access_token=").is_some_and(|given| tokens_match(given, token)))
+    })
+    .unwrap_or(false);
+  for _ in 0..MAX_HEADERS {
+    read_head_line(&mut reader, &mut line).await?;
+    let header = line.trim_end();
+    if header.is_empty() {
+      break;
+    }
+    let Some((name, value)) = header.split_once(':') else {
+      continue;
+    };
+    let value = value.trim();
+    match name.trim().to_ascii_lowercase().as_str() {
+      "content-length" => content_length = value.parse().unwrap_or(0),
+      "content-type" => content_type = value.to_ascii_lowercase(),
+      "authorization" => {
+        authorized |= value.strip_prefix("Bearer ").is_some_and(|given| tokens_match(given.trim(), token));
+      }
+      // Browsers attach an Origin; web pages must not be able to drive the endpoint.
+      "origin" => return Err(WebhookResponse::error(403, "Cross-origin requests are not allowed")),
+      _ => {}
+    }
+  }
+  if !authorized {
+    return Err(WebhookResponse::error(401, "Missing or wrong token"));
+  }
+  if content_length > MAX_BODY {
+    return Err(WebhookResponse::error(413, "Body too large"));
+  }
+  let mut body = vec![0u8; content_length];
+  reader.read_exact(&mut body).await.map_err(|_| WebhookResponse::error(400, "Truncated body"))?;
+  Ok(WebhookRequest {
+    method,
+    path: decode_path(&target),
+    content_type,
+    body,
+  })
+}
+
+async fn serve_connection(mut stream: TcpStream, token: Arc<String>, handler: Handler) {
+  let response = match tokio::time::timeout(READ_TIMEOUT, read_request(&mut stream, &token)).await {
+    Ok(Ok(request)) => handler(request).await,
+    Ok(Err(response)) => response,
+    Err(_) => WebhookResponse::error(400, "Request timed out"),
+  };
+  respond(&mut stream, &response).await;
+}
+
+/// Bind to localhost and serve until the returned sender is used or dropped.
+pub async fn start(port: u16, token: String, handler: Handler) -> Result<watch::Sender<bool>, String> {
+  if token.len() < 16 {
+    return Err("The webhook token must be at least 16 characters".into());
+  }
+  let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, port)))
+    .await
+    .map_err(|e| format!("Can't listen on 127.0.0.1:{}: {}", port, e))?;
+  let (stop, mut stopped) = watch::channel(false);
+  let token = Arc::new(token);
+  tokio::spawn(async move {
+    loop {
+      tokio::select! {
+        accepted = listener.accept() => {
+          if let Ok((stream, _)) = accepted {
+            tokio::spawn(serve_connection(stream, token.clone(), handler.clone()));
+          }
+        }
+        changed = stopped.changed() => {
+          if changed.is_err() || *stopped.borrow() {
+            break;
+          }
+        }
+      }
+    }
+  });
+  Ok(stop)
+}
+
+/// `SendRequest` from a JSON or plain text body.
+pub fn parse_send(request: &WebhookRequest) -> Result<SendRequest, String> {
+  if request.content_type.starts_with("application/json") {
+    let send: SendRequest = serde_json::from_slice(&request.body).map_err(|e| format!("Invalid JSON body: {}", e))?;
+    if send.text.trim().is_empty() {
+      return Err("`text` is required".into());
+    }
+    return Ok(send);
+  }
+  let text = String::from_utf8(request.body.clone()).map_err(|_| "Body is not UTF-8".to_string())?;
+  if text.trim().is_empty() {
+    return Err("Empty message".into());
+  }
+  Ok(SendRequest { text, ..Default::default() })
+}
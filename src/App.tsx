@@ -1,10 +1,11 @@
-import React, { useEffect, useMemo } from 'react';
+import React, { useEffect, useMemo, useState } from 'react';
 import LoginPage from './components/LoginPage';
 import ChatPage from './components/ChatPage';
 import AppErrorBoundary from './components/AppErrorBoundary';
 import { AccountProvider, useAccountStore } from './services/accountManager';
 import { bootstrapStoredPlugins } from './services/pluginHost';
 import SecureCloudAdminApp from './components/SecureCloudAdminApp';
+import { getPlaintextStoreWarning, onPlaintextStoreWarning } from './services/credentialStoreWarning';
 
 const AppContent: React.FC = () => {
   const boot = useAccountStore(state => state.boot);
@@ -17,6 +18,7 @@ const AppContent: React.FC = () => {
   const setUniversalMode = useAccountStore(state => state.setUniversalMode);
   const aggregatedUnread = useAccountStore(state => state.aggregatedUnread);
   const aggregatedRooms = useAccountStore(state => state.aggregatedRooms);
+  const [plaintextStoreWarning, setPlaintextStoreWarning] = useState<string | null>(null);
 
   const view = useMemo(() => {
     if (typeof window === 'undefined') {
@@ -38,6 +40,13 @@ const AppContent: React.FC = () => {
     void bootstrapStoredPlugins();
   }, []);
 
+  useEffect(() => {
+    if (isBooting) return;
+    void getPlaintextStoreWarning().then(setPlaintextStoreWarning);
+  }, [isBooting, accounts]);
+
+  useEffect(() => onPlaintextStoreWarning(setPlaintextStoreWarning), []);
+
   const active = activeKey ? accounts[activeKey] : null;
   const canUseUniversal = active && (Object.keys(accounts).length > 1 || aggregatedRooms.length > 0);
 
@@ -61,6 +70,15 @@ const AppContent: React.FC = () => {
 
   return (
     <div className="h-screen w-screen bg-bg-primary text-text-primary font-sans">
+      {plaintextStoreWarning && (
+        <div className="fixed bottom-4 left-1/2 -translate-x-1/2 z-50 max-w-xl rounded-md border border-amber-500/40 bg-amber-500/15 px-4 py-3 text-sm text-amber-100 shadow-lg backdrop-blur">
+          <p className="font-semibold text-amber-200">Учётные данные сохранены без шифрования</p>
+          <p className="text-amber-100/80">
+            Системное хранилище ключей недоступно ({plaintextStoreWarning}), поэтому токены доступа записаны на диск в открытом виде.
+            Включите хранилище ключей ОС или настройте ключ безопасности.
+          </p>
+        </div>
+      )}
       {canUseUniversal && (
         <div className="fixed top-4 right-4 z-40 flex items-center gap-2 bg-bg-primary/90 border border-border-secondary rounded-full px-3 py-1 shadow-lg backdrop-blur">
           <button
@@ -115,7 +115,7 @@ import {
     generateRoomDigest,
     DEFAULT_DIGEST_ACCOUNT_KEY,
 } from '../services/digestService';
-import { purgeRoomsFromIndex } from '../services/localIndexStore';
+import { purgeRoomsFromIndex, setIndexAccount } from '../services/localIndexStore';
 
 interface ChatPageProps {
     client?: MatrixClient;
@@ -2137,6 +2137,7 @@ const ChatPage: React.FC<ChatPageProps> = ({ client: providedClient, onLogout, s
 
     useEffect(() => {
         setActiveDigestAccount(activeAccountKey ?? null);
+        setIndexAccount(activeAccountKey ?? null);
         if (activeAccountKey) {
             void hydrateDigestsForAccount(activeAccountKey).catch(error => {
                 console.debug('Failed to hydrate digests for account', error);
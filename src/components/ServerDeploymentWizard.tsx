@@ -1,5 +1,6 @@
 import React, { useState, useEffect } from 'react';
 import { invoke } from '@tauri-apps/api/core';
+import { listen } from '@tauri-apps/api/event';
 
 interface DeploymentConfig {
     server_ip: string;
@@ -8,6 +9,7 @@ interface DeploymentConfig {
     domain?: string;
     admin_username: string;
     admin_password: string;
+    install_turn: boolean;
 }
 
 interface DeploymentStatus {
@@ -17,6 +19,12 @@ interface DeploymentStatus {
     success: boolean;
 }
 
+type DeploymentProgress =
+    | ({ kind: 'status' } & DeploymentStatus)
+    | { kind: 'output'; step: string; line: string };
+
+const MAX_LOG_LINES = 500;
+
 interface ServerDeploymentWizardProps {
     onClose: () => void;
     onDeploymentComplete: (homeserverUrl: string, username: string, password: string) => void;
@@ -37,14 +45,16 @@ const ServerDeploymentWizard: React.FC<ServerDeploymentWizardProps> = ({ onClose
         domain: '',
         admin_username: 'admin',
         admin_password: '',
+        install_turn: false,
     });
 
     const [deploymentStatuses, setDeploymentStatuses] = useState<DeploymentStatus[]>([]);
+    const [deploymentLog, setDeploymentLog] = useState<string[]>([]);
     const [isTestingConnection, setIsTestingConnection] = useState(false);
     const [connectionTestResult, setConnectionTestResult] = useState<string | null>(null);
     const [error, setError] = useState<string | null>(null);
 
-    const handleInputChange = (field: keyof DeploymentConfig, value: string) => {
+    const handleInputChange = (field: Exclude<keyof DeploymentConfig, 'install_turn'>, value: string) => {
         let cleanValue = value;
 
         // Clean server IP from protocol
@@ -106,6 +116,17 @@ const ServerDeploymentWizard: React.FC<ServerDeploymentWizardProps> = ({ onClose
         setCurrentStep('deploying');
         setError(null);
         setDeploymentStatuses([]);
+        setDeploymentLog([]);
+
+        const unlisten = await listen<DeploymentProgress>('deployment://progress', event => {
+            const progress = event.payload;
+            if (progress.kind === 'output') {
+                setDeploymentLog(prev => [...prev, progress.line].slice(-MAX_LOG_LINES));
+            } else {
+                const { kind: _kind, ...status } = progress;
+                setDeploymentStatuses(prev => [...prev, status]);
+            }
+        });
 
         try {
             const statuses = await invoke<DeploymentStatus[]>('deploy_matrix_server', { config });
@@ -121,6 +142,8 @@ const ServerDeploymentWizard: React.FC<ServerDeploymentWizardProps> = ({ onClose
             const errorMessage = typeof err === 'string' ? err : (err as Error).message || 'Deployment failed';
             setError(errorMessage);
             setCurrentStep('config');
+        } finally {
+            unlisten();
         }
     };
 
@@ -225,6 +248,21 @@ const ServerDeploymentWizard: React.FC<ServerDeploymentWizardProps> = ({ onClose
                     </p>
                 </div>
 
+                <label className="flex items-start gap-3 cursor-pointer">
+                    <input
+                        type="checkbox"
+                        checked={config.install_turn}
+                        onChange={(e) => setConfig(prev => ({ ...prev, install_turn: e.target.checked }))}
+                        className="mt-1"
+                    />
+                    <span>
+                        <span className="block text-sm font-medium text-text-primary">Install TURN server (coturn)</span>
+                        <span className="block text-text-secondary text-xs mt-1">
+                            Needed for voice and video calls behind NAT. Opens port 3478 and UDP ports 49160-49200.
+                        </span>
+                    </span>
+                </label>
+
                 <div className="border-t border-border-secondary pt-4 mt-6">
                     <h4 className="text-sm font-medium text-text-primary mb-4">Matrix Admin Account</h4>
 
@@ -291,9 +329,6 @@ const ServerDeploymentWizard: React.FC<ServerDeploymentWizardProps> = ({ onClose
                 <p className="text-text-secondary text-sm">
                     This process may take 5-10 minutes. Please don't close this window.
                 </p>
-                <p className="text-yellow-500 text-xs mt-2">
-                    💡 Check the terminal where you ran 'npm run tauri dev' for detailed logs
-                </p>
             </div>
 
             <div className="space-y-3 max-h-96 overflow-y-auto">
@@ -326,6 +361,12 @@ const ServerDeploymentWizard: React.FC<ServerDeploymentWizardProps> = ({ onClose
                 ))}
             </div>
 
+            {deploymentLog.length > 0 && (
+                <pre className="text-xs text-text-secondary whitespace-pre-wrap overflow-y-auto max-h-64 bg-bg-secondary p-3 rounded-lg font-mono">
+                    {deploymentLog.join('\n')}
+                </pre>
+            )}
+
             {error && (
                 <div className="bg-red-500/10 border border-red-500/50 rounded-lg p-4">
                     <p className="text-red-500 text-sm font-bold mb-2">❌ Deployment Error</p>
@@ -27,6 +27,10 @@ import {
   startAutomationRuntime,
 } from './schedulerService';
 import { getSuspiciousEvents } from './secureCloudService';
+import { attachDecryptionRetryBridge } from './decryptionRetryBridge';
+import { attachScheduledSendBridge } from './scheduledSendBridge';
+import { attachSyncTokenHandOff } from './syncTokenBridge';
+import { attachToDeviceBridge } from './toDeviceBridge';
 import { bindCallStateStore, CallSessionState, getCallSessionForAccount, subscribeCallState } from './matrixService';
 
 const RESTORE_ERROR_MESSAGE = 'Не удалось восстановить сессии. Авторизуйтесь заново.';
@@ -370,6 +374,10 @@ export const createAccountStore = () => {
       const detachCallStateSubscription = attachCallStateListeners(account.key);
       let detachCallStateBinding: (() => void) | null = null;
       let detachAutomationRuntime: (() => void) | null = null;
+      const detachScheduledSend = attachScheduledSendBridge(account.key, session.client);
+      const detachToDevice = attachToDeviceBridge(account.key, session.client);
+      const detachSyncToken = attachSyncTokenHandOff(account.key, session.client);
+      const detachDecryptionRetry = attachDecryptionRetryBridge(account.key, session.client);
       try {
         detachCallStateBinding = bindCallStateStore(session.client);
       } catch (error) {
@@ -386,6 +394,10 @@ export const createAccountStore = () => {
         try { detachCallStateSubscription(); } catch (error) { console.warn('call state subscription detach failed', error); }
         try { detachCallStateBinding?.(); } catch (error) { console.warn('call state detach failed', error); }
         try { detachAutomationRuntime?.(); } catch (error) { console.warn('automation runtime detach failed', error); }
+        try { detachScheduledSend(); } catch (error) { console.warn('scheduled send detach failed', error); }
+        try { detachToDevice(); } catch (error) { console.warn('to-device detach failed', error); }
+        try { detachSyncToken(); } catch (error) { console.warn('sync token detach failed', error); }
+        try { detachDecryptionRetry(); } catch (error) { console.warn('decryption retry detach failed', error); }
         try { session.dispose(); } catch (error) { console.warn('dispose failed', error); }
         try { session.client.stopClient?.(); } catch (error) { console.warn('stopClient failed', error); }
       });
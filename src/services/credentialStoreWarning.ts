@@ -0,0 +1,31 @@
+import { invoke } from '@tauri-apps/api/core';
+import { listen } from '@tauri-apps/api/event';
+
+const PLAINTEXT_STORE_EVENT = 'security://plaintext-store';
+
+const isTauri = () =>
+    typeof window !== 'undefined' && typeof (window as any).__TAURI_INTERNALS__ !== 'undefined';
+
+/** Why saved credentials are not encrypted at rest (no OS keychain), or null when they are. */
+export const getPlaintextStoreWarning = async (): Promise<string | null> => {
+    if (!isTauri()) return null;
+    try {
+        return (await invoke<string | null>('get_plaintext_store_warning')) ?? null;
+    } catch (error) {
+        console.warn('Failed to read credential store status', error);
+        return null;
+    }
+};
+
+export const onPlaintextStoreWarning = (listener: (reason: string) => void): (() => void) => {
+    if (!isTauri()) return () => {};
+    const unlistenPromise = listen<{ reason: string }>(PLAINTEXT_STORE_EVENT, ({ payload }) => listener(payload.reason)).catch(
+        error => {
+            console.warn('Failed to attach credential store listener', error);
+            return null;
+        },
+    );
+    return () => {
+        void unlistenPromise.then(unlisten => unlisten?.());
+    };
+};
@@ -0,0 +1,141 @@
+import { invoke } from '@tauri-apps/api/core';
+import { listen } from '@tauri-apps/api/event';
+import { ClientEvent, MatrixEvent, MatrixEventEvent } from 'matrix-js-sdk';
+import type { MatrixClient } from '../types';
+import { indexEntriesFor } from './mediaIndexService';
+
+const RETRY_DECRYPTION_EVENT = 'crypto://retry-decryption';
+const ROOM_KEY_TYPES = new Set(['m.room_key', 'm.forwarded_room_key']);
+
+interface UndecryptableEvent {
+    roomId: string;
+    eventId: string;
+    sessionId: string;
+    event: Record<string, unknown>;
+}
+
+interface RetryPayload {
+    accountKey: string;
+    source?: string | null;
+    events: UndecryptableEvent[];
+}
+
+const isTauri = () =>
+    typeof window !== 'undefined' && typeof (window as any).__TAURI_INTERNALS__ !== 'undefined';
+
+const attachedAccounts = new WeakMap<MatrixClient, string>();
+
+const reportRoomKeys = (accountKey: string, keys: { roomId: string; sessionId: string }[], source: string) => {
+    if (!keys.length) return;
+    invoke('notify_room_keys_received', { accountKey, keys, source }).catch(error => {
+        console.warn('Failed to report received room keys', error);
+    });
+};
+
+/** Clear retried events from the queue and index them; the backend then emits `crypto://decrypted`. */
+const completeRetry = async (accountKey: string, client: MatrixClient, roomId: string, events: MatrixEvent[]) => {
+    if (!events.length) return;
+    const room = client.getRoom(roomId) ?? null;
+    await invoke('complete_decryption_retry', {
+        accountKey,
+        roomId,
+        events: events.map(event => event.getEffectiveEvent()),
+        index: indexEntriesFor(roomId, room, events),
+    });
+};
+
+/** Decrypt the queued events again now that their session may be known. */
+const retryEvents = async (accountKey: string, client: MatrixClient, queued: UndecryptableEvent[]) => {
+    const decrypted = new Map<string, MatrixEvent[]>();
+    for (const item of queued) {
+        const event = client.getRoom(item.roomId)?.findEventById(item.eventId) ?? new MatrixEvent(item.event as any);
+        try {
+            await (client as any).decryptEventIfNeeded(event, { isRetry: true });
+        } catch (error) {
+            console.warn('Decryption retry failed', item.eventId, error);
+            continue;
+        }
+        if (event.isDecryptionFailure() || event.getType() === 'm.room.encrypted') continue;
+        decrypted.set(item.roomId, [...(decrypted.get(item.roomId) ?? []), event]);
+    }
+    for (const [roomId, events] of decrypted) {
+        await completeRetry(accountKey, client, roomId, events);
+    }
+};
+
+/**
+ * Keeps the native decryption retry queue in step with the client's crypto: failures are
+ * recorded, arriving room keys (from the client's sync or the native to-device queue) are
+ * reported so waiting events come back over `crypto://retry-decryption`, and events the
+ * crypto layer later decrypts on its own (e.g. after a key backup restore) are resolved.
+ */
+export const attachDecryptionRetryBridge = (accountKey: string, client: MatrixClient): (() => void) => {
+    if (!isTauri()) {
+        return () => {};
+    }
+    const failed = new Set<string>();
+
+    const onDecrypted = (event: MatrixEvent) => {
+        const roomId = event.getRoomId();
+        const eventId = event.getId();
+        if (!roomId || !eventId) return;
+        if (event.isDecryptionFailure()) {
+            if (failed.has(eventId)) return;
+            failed.add(eventId);
+            // `event.event` is still the wire `m.room.encrypted` event; the effective one is the placeholder.
+            const failure = { event: event.event, error: event.decryptionFailureReason ?? null };
+            invoke('record_decryption_failures', { accountKey, roomId, failures: [failure] }).catch(error => {
+                failed.delete(eventId);
+                console.warn('Failed to record decryption failure', error);
+            });
+            return;
+        }
+        if (!failed.delete(eventId)) return;
+        completeRetry(accountKey, client, roomId, [event]).catch(error => {
+            console.warn('Failed to store late decrypted event', error);
+        });
+    };
+
+    const onToDevice = (event: MatrixEvent) => {
+        if (!ROOM_KEY_TYPES.has(event.getType())) return;
+        const content = event.getContent();
+        if (typeof content.room_id !== 'string' || typeof content.session_id !== 'string') return;
+        reportRoomKeys(accountKey, [{ roomId: content.room_id, sessionId: content.session_id }], 'to_device');
+    };
+
+    attachedAccounts.set(client, accountKey);
+    (client as any).on(MatrixEventEvent.Decrypted, onDecrypted);
+    (client as any).on(ClientEvent.ToDeviceEvent, onToDevice);
+    const unlistenPromise = listen<RetryPayload>(RETRY_DECRYPTION_EVENT, ({ payload }) => {
+        if (payload.accountKey !== accountKey) return;
+        retryEvents(accountKey, client, payload.events).catch(error => {
+            console.warn('Failed to retry decryption', error);
+        });
+    }).catch(error => {
+        console.warn('Failed to attach decryption retry listener', error);
+        return null;
+    });
+    return () => {
+        attachedAccounts.delete(client);
+        (client as any).removeListener(MatrixEventEvent.Decrypted, onDecrypted);
+        (client as any).removeListener(ClientEvent.ToDeviceEvent, onToDevice);
+        void unlistenPromise.then(unlisten => unlisten?.());
+    };
+};
+
+/** Report the sessions of an imported key export so events waiting on them are retried. */
+export const reportImportedRoomKeys = (client: MatrixClient, exportJson: string): void => {
+    const accountKey = attachedAccounts.get(client);
+    if (!accountKey) return;
+    let exported: any[];
+    try {
+        exported = JSON.parse(exportJson);
+    } catch {
+        return;
+    }
+    if (!Array.isArray(exported)) return;
+    const keys = exported
+        .filter(key => typeof key?.room_id === 'string' && typeof key?.session_id === 'string')
+        .map(key => ({ roomId: key.room_id as string, sessionId: key.session_id as string }));
+    reportRoomKeys(accountKey, keys, 'backup');
+};
@@ -15,7 +15,7 @@ const MIN_DIGEST_REFRESH_INTERVAL = 60 * 1000;
 const DAILY_NOTIFICATION_INTERVAL = 6 * 60 * 60 * 1000;
 
 const isBrowser = typeof window !== 'undefined';
-const isTauri = isBrowser && typeof (window as any).__TAURI_IPC__ !== 'undefined';
+const isTauri = isBrowser && typeof (window as any).__TAURI_INTERNALS__ !== 'undefined';
 
 type DigestFrequency = 'never' | 'daily' | 'weekly' | 'hourly';
 
@@ -16,6 +16,10 @@ export interface IndexedMessageRecord {
   reactions: string[];
   hasMedia: boolean;
   mediaTypes: string[];
+  /** Event this one relates to via `m.relates_to`. */
+  relatesTo?: string;
+  /** Root of the thread this message replies in. */
+  threadRoot?: string;
   transcriptText?: string;
   transcriptStatus?: string;
   transcriptLanguage?: string;
@@ -28,6 +32,18 @@ export interface IndexedMessageRecord {
 export interface PersistedRoomIndex {
   media: MediaItem[];
   messages: IndexedMessageRecord[];
+  /** Removed since the requested point (incremental loads only). */
+  deletedEventIds?: string[];
+  deletedMediaIds?: string[];
+  /** Whole room history rather than a delta; replaces a cached copy. */
+  full?: boolean;
+  /** Change sequence this response is complete up to (desktop index only). */
+  changeSeq?: number;
+}
+
+/** Change sequence a caller is up to date with; `loadRoomIndex` then returns only what changed after it. */
+export interface RoomIndexCursor {
+  changeSeq: number;
 }
 
 export interface SmartCollectionSummary {
@@ -47,9 +63,18 @@ export interface LocalSearchQuery {
   hasMedia?: boolean;
   limit?: number;
   mediaTypes?: string[];
+  /** "relevance" ranks term matches by BM25 (desktop index only); default is newest first. */
+  order?: "recent" | "relevance";
+  /** `nextCursor` of the previous page; needs `limit`. */
+  cursor?: string;
 }
 
-const isTauri = typeof window !== "undefined" && (window as any).__TAURI_IPC__;
+export interface LocalSearchPage {
+  records: IndexedMessageRecord[];
+  nextCursor?: string;
+}
+
+const isTauri = typeof window !== "undefined" && typeof (window as any).__TAURI_INTERNALS__ !== "undefined";
 
 // -----------------------------
 // IndexedDB implementation
@@ -308,11 +333,22 @@ async function idbClearAll(): Promise<void> {
 // Public API
 // -----------------------------
 
+// The desktop index keeps each account's rows apart; every call below is scoped to this one.
+let indexAccountKey: string | null = null;
+
+export function setIndexAccount(accountKey: string | null): void {
+  indexAccountKey = accountKey;
+}
+
+export function getIndexAccount(): string | null {
+  return indexAccountKey;
+}
+
 export async function upsertIndexEntries(roomId: string, messages: IndexedMessageRecord[], mediaItems: MediaItem[]): Promise<void> {
   if (!messages.length && !mediaItems.length) return;
   if (isTauri) {
     try {
-      await invoke("upsert_index_records", { payload: { roomId, messages, mediaItems } });
+      await invoke("upsert_index_records", { accountKey: indexAccountKey, payload: { roomId, messages, mediaItems } });
       return;
     } catch (error) {
       console.warn("Failed to persist index via Tauri", error);
@@ -321,34 +357,45 @@ export async function upsertIndexEntries(roomId: string, messages: IndexedMessag
   await idbUpsert(messages, mediaItems);
 }
 
-export async function loadRoomIndex(roomId: string): Promise<PersistedRoomIndex | null> {
+export async function loadRoomIndex(roomId: string, since?: RoomIndexCursor): Promise<PersistedRoomIndex | null> {
   if (isTauri) {
     try {
-      const result = await invoke<PersistedRoomIndex | null>("load_room_index", { roomId });
+      const result = await invoke<PersistedRoomIndex | null>("load_room_index", {
+        accountKey: indexAccountKey,
+        roomId,
+        sinceSeq: since?.changeSeq,
+      });
       if (result) return result;
     } catch (error) {
       console.warn("Failed to load index via Tauri", error);
     }
   }
-  return idbLoadRoom(roomId);
+  const room = await idbLoadRoom(roomId);
+  return room ? { ...room, full: true } : null;
 }
 
-export async function queryLocalMessages(query: LocalSearchQuery, mentionTarget?: string): Promise<IndexedMessageRecord[]> {
+export async function queryLocalMessagesPage(query: LocalSearchQuery, mentionTarget?: string): Promise<LocalSearchPage> {
   if (isTauri) {
     try {
-      const result = await invoke<IndexedMessageRecord[]>("query_local_index", { query, mentionTarget });
-      if (Array.isArray(result)) return result;
+      const result = await invoke<LocalSearchPage>("query_local_index", { accountKey: indexAccountKey, query, mentionTarget });
+      if (result && Array.isArray(result.records)) return result;
     } catch (error) {
       console.warn("Local sqlite query failed", error);
     }
   }
-  return idbQuery(query, mentionTarget);
+  // The IndexedDB fallback has no cursor: the first page is all there is.
+  if (query.cursor) return { records: [] };
+  return { records: await idbQuery(query, mentionTarget) };
+}
+
+export async function queryLocalMessages(query: LocalSearchQuery, mentionTarget?: string): Promise<IndexedMessageRecord[]> {
+  return (await queryLocalMessagesPage(query, mentionTarget)).records;
 }
 
 export async function getSmartCollections(userId: string): Promise<SmartCollectionSummary[]> {
   if (isTauri) {
     try {
-      const result = await invoke<SmartCollectionSummary[]>("get_smart_collections", { userId });
+      const result = await invoke<SmartCollectionSummary[]>("get_smart_collections", { accountKey: indexAccountKey, userId });
       if (Array.isArray(result)) return result;
     } catch (error) {
       console.warn("Fetching smart collections via Tauri failed", error);
@@ -357,11 +404,23 @@ export async function getSmartCollections(userId: string): Promise<SmartCollecti
   return idbSmartCollections(userId);
 }
 
+/** Newest unanswered reply of each thread waiting on `userId` (desktop index only). */
+export async function queryThreadCollection(userId: string): Promise<IndexedMessageRecord[]> {
+  if (!isTauri) return [];
+  try {
+    const result = await invoke<IndexedMessageRecord[]>("query_thread_collection", { accountKey: indexAccountKey, userId });
+    return Array.isArray(result) ? result : [];
+  } catch (error) {
+    console.warn("Fetching unanswered threads via Tauri failed", error);
+    return [];
+  }
+}
+
 export async function purgeRoomIndex(roomId: string): Promise<void> {
   if (!roomId) return;
   if (isTauri) {
     try {
-      await invoke("purge_room_index", { roomId });
+      await invoke("purge_room_index", { accountKey: indexAccountKey, roomId });
       return;
     } catch (error) {
       console.warn("Failed to purge room index via Tauri", error);
@@ -378,7 +437,7 @@ export async function purgeRoomsFromIndex(roomIds: string[]): Promise<void> {
 export async function clearLocalIndex(): Promise<void> {
   if (isTauri) {
     try {
-      await invoke("clear_index_store");
+      await invoke("clear_index_store", { accountKey: indexAccountKey });
       return;
     } catch (error) {
       console.warn("Failed to clear index via Tauri", error);
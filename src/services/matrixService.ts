@@ -6,6 +6,7 @@ import type { VideoEffectsConfiguration } from './videoEffectsService';
 import { buildGeoUri, buildStaticMapUrl, buildExternalNavigationUrl, MAP_ZOOM_DEFAULT, STATIC_MAP_HEIGHT, STATIC_MAP_WIDTH, sanitizeZoom } from '../utils/location';
 import { setTravelModeNotificationState } from './notificationService';
 import { setTravelModePushState } from './pushService';
+import { reportImportedRoomKeys } from './decryptionRetryBridge';
 import {
     GROUP_CALL_CONTROL_EVENT_TYPE,
     GROUP_CALL_PARTICIPANTS_EVENT_TYPE,
@@ -3242,6 +3243,7 @@ export const restoreRoomKeysFromBackup = async (client: MatrixClient, passphrase
     const payload = await loadEncryptedSeed(KEY_BACKUP_LABEL, passphrase);
     if (!payload) return false;
     await importRoomKeysFromJson(client, payload);
+    reportImportedRoomKeys(client, payload);
     return true;
 };
 
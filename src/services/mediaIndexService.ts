@@ -7,12 +7,15 @@ import { RelationType, EventType } from "matrix-js-sdk";
 import * as matrixService from "./matrixService";
 import type { MessageTranscript } from "./transcriptionService";
 import {
+  getIndexAccount,
   getSmartCollections as loadSmartCollections,
   loadRoomIndex as loadRoomFromStore,
   queryLocalMessages,
+  queryThreadCollection,
   upsertIndexEntries,
   type IndexedMessageRecord,
   type LocalSearchQuery,
+  type RoomIndexCursor,
   type SmartCollectionSummary,
 } from "./localIndexStore";
 export type { LocalSearchQuery } from "./localIndexStore";
@@ -53,48 +56,63 @@ type RoomIndex = {
   messages: IndexedMessageMetadata[];
   lastEventTs?: number;
   complete?: boolean; // true when fully backfilled to room start for current session
+  persistedCursor?: RoomIndexCursor; // change sequence last hydrated from the persistent store
 };
 
+// Keyed by account and room so one account's rows and cursors never show up under another.
 const inMemory: Map<string, RoomIndex> = new Map();
 
-const key = (roomId: string) => `mediaIndex:${roomId}`;
+const accountPrefix = () => `${getIndexAccount() ?? ""}|`;
+const entryKey = (roomId: string) => `${accountPrefix()}${roomId}`;
+const storageKey = (entry: string) => `mediaIndex:${entry}`;
 
 function load(roomId: string): RoomIndex {
-  if (inMemory.has(roomId)) return inMemory.get(roomId)!;
+  const entry = entryKey(roomId);
+  if (inMemory.has(entry)) return inMemory.get(entry)!;
   try {
-    const raw = typeof localStorage !== "undefined" ? localStorage.getItem(key(roomId)) : null;
+    const raw = typeof localStorage !== "undefined" ? localStorage.getItem(storageKey(entry)) : null;
     if (raw) {
       const parsed: RoomIndex = JSON.parse(raw);
       parsed.messages = parsed.messages ?? [];
-      inMemory.set(roomId, parsed);
+      inMemory.set(entry, parsed);
       void hydrateFromPersistent(roomId);
       return parsed;
     }
   } catch {}
   const empty: RoomIndex = { items: [], messages: [], complete: false };
-  inMemory.set(roomId, empty);
+  inMemory.set(entry, empty);
   void hydrateFromPersistent(roomId);
   return empty;
 }
 
-function persist(roomId: string) {
+function persist(roomId: string, entry = entryKey(roomId)) {
   try {
-    const data = inMemory.get(roomId);
+    const data = inMemory.get(entry);
     if (!data) return;
     if (typeof localStorage !== "undefined") {
-      localStorage.setItem(key(roomId), JSON.stringify(data));
+      localStorage.setItem(storageKey(entry), JSON.stringify(data));
     }
   } catch {}
 }
 
 async function hydrateFromPersistent(roomId: string) {
+  // The account may change while the store answers; keep writing to the entry we started on.
+  const entry = entryKey(roomId);
   try {
-    const persisted = await loadRoomFromStore(roomId);
+    const cursor = inMemory.get(entry)?.persistedCursor;
+    const persisted = await loadRoomFromStore(roomId, cursor);
     if (!persisted) return;
-    const existing = inMemory.get(roomId) ?? { items: [], messages: [], complete: false };
-    existing.items = dedupeById([...existing.items, ...persisted.media]).sort((a, b) => a.timestamp - b.timestamp);
-    existing.messages = dedupeMessages([...existing.messages, ...persisted.messages]).sort((a, b) => a.timestamp - b.timestamp);
-    inMemory.set(roomId, existing);
+    const existing = inMemory.get(entry) ?? { items: [], messages: [], complete: false };
+    const deletedEvents = new Set(persisted.deletedEventIds ?? []);
+    const deletedMedia = new Set(persisted.deletedMediaIds ?? []);
+    // A full load is the whole stored history; cached rows it lacks were pruned or deleted.
+    const keptItems = persisted.full ? [] : existing.items.filter(item => !deletedMedia.has(item.id));
+    const keptMessages = persisted.full ? [] : existing.messages.filter(message => !deletedEvents.has(message.eventId));
+    existing.items = dedupeById([...keptItems, ...persisted.media]).sort((a, b) => a.timestamp - b.timestamp);
+    existing.messages = dedupeMessages([...keptMessages, ...persisted.messages]).sort((a, b) => a.timestamp - b.timestamp);
+    existing.persistedCursor = persisted.changeSeq !== undefined ? { changeSeq: persisted.changeSeq } : undefined;
+    inMemory.set(entry, existing);
+    persist(roomId, entry);
   } catch (error) {
     console.warn("Failed to hydrate index", error);
   }
@@ -315,6 +333,9 @@ function intoMetadata(roomId: string, ev: MatrixEvent, mediaItems: MediaItem[],
   const relationKey = ev.getRelation?.()?.key;
   if (relationKey === TRANSCRIPT_RELATION_KEY) return null;
   const body = typeof content?.body === "string" ? content.body : undefined;
+  const relation = content?.["m.relates_to"];
+  const relatesTo = typeof relation?.event_id === "string" ? relation.event_id : undefined;
+  const threadRoot = relation?.rel_type === RelationType.Thread ? relatesTo : undefined;
   let tokens = Array.from(new Set([...tokenize(body), sender.toLowerCase()]));
   const tags = extractTags(content);
   const reactions = collectReactions(room, ev);
@@ -338,6 +359,8 @@ function intoMetadata(roomId: string, ev: MatrixEvent, mediaItems: MediaItem[],
     reactions,
     hasMedia,
     mediaTypes,
+    relatesTo,
+    threadRoot,
     transcriptText: transcript?.text,
     transcriptStatus: transcript?.status,
     transcriptLanguage: transcript?.language,
@@ -409,7 +432,7 @@ export async function indexRoom(roomId: string, opts: { backfillLimit?: number;
   idx.messages = dedupeMessages(idx.messages).sort((a, b) => a.timestamp - b.timestamp);
   idx.lastEventTs = Date.now();
   idx.complete = scanned >= backfillLimit ? false : !room.canPaginate("b", live);
-  inMemory.set(roomId, idx);
+  inMemory.set(entryKey(roomId), idx);
   persist(roomId);
   if (messageBatch.length || mediaBatch.length) {
     void upsertIndexEntries(roomId, messageBatch, mediaBatch);
@@ -457,7 +480,7 @@ export function applyTranscriptUpdate(roomId: string, eventId: string, transcrip
   target.transcriptError = transcript.error;
   target.transcriptDurationMs = transcript.durationMs;
   idx.messages = dedupeMessages(idx.messages).sort((a, b) => a.timestamp - b.timestamp);
-  inMemory.set(roomId, idx);
+  inMemory.set(entryKey(roomId), idx);
   persist(roomId);
   void upsertIndexEntries(roomId, [target], []);
 }
@@ -500,11 +523,29 @@ export function recordCallCaption(roomId: string, caption: CaptionRecord): void
     ...idx.messages.filter(m => m.eventId !== caption.id),
     metadata,
   ]).sort((a, b) => a.timestamp - b.timestamp);
-  inMemory.set(roomId, idx);
+  inMemory.set(entryKey(roomId), idx);
   persist(roomId);
   void upsertIndexEntries(roomId, [metadata], []);
 }
 
+/** Index records for events decrypted after the fact, in the shape `upsert_index_records` takes. */
+export function indexEntriesFor(
+  roomId: string,
+  room: Room | null,
+  events: MatrixEvent[],
+): { roomId: string; messages: IndexedMessageRecord[]; mediaItems: MediaItem[] } {
+  const messages: IndexedMessageRecord[] = [];
+  const mediaItems: MediaItem[] = [];
+  for (const ev of events) {
+    if (ev.getType() !== "m.room.message") continue;
+    const items = intoItems(roomId, ev);
+    const metadata = intoMetadata(roomId, ev, items, room);
+    mediaItems.push(...items);
+    if (metadata) messages.push(metadata);
+  }
+  return { roomId, messages, mediaItems };
+}
+
 export function startLiveIndexing(roomId: string) {
   const client = matrixService.getClient?.() as MatrixClient | undefined;
   if (!client) return;
@@ -530,7 +571,7 @@ export function startLiveIndexing(roomId: string) {
       idx.messages.push(metadata);
       idx.messages = dedupeMessages(idx.messages).sort((a, b) => a.timestamp - b.timestamp);
     }
-    inMemory.set(roomId, idx);
+    inMemory.set(entryKey(roomId), idx);
     persist(roomId);
     if (items.length || metadata) {
       void upsertIndexEntries(roomId, metadata ? [metadata] : [], items);
@@ -560,8 +601,9 @@ export function query(roomId: string, q: MediaQuery = {}): MediaItem[] {
 }
 
 export function clear(roomId: string) {
-  inMemory.delete(roomId);
-  try { localStorage.removeItem(key(roomId)); } catch {}
+  const entry = entryKey(roomId);
+  inMemory.delete(entry);
+  try { localStorage.removeItem(storageKey(entry)); } catch {}
 }
 
 export function mxcPreview(item: MediaItem, size = 256) {
@@ -583,7 +625,10 @@ function buildMentionTarget(userId?: string | null) {
 }
 
 function inMemorySearch(query: LocalSearchQuery, mentionTarget?: string): IndexedMessageMetadata[] {
-  const all = Array.from(inMemory.values()).flatMap(idx => idx.messages);
+  const prefix = accountPrefix();
+  const all = Array.from(inMemory.entries())
+    .filter(([entry]) => entry.startsWith(prefix))
+    .flatMap(([, idx]) => idx.messages);
   const filtered = all.filter(record => {
     if (query.roomId && record.roomId !== query.roomId) return false;
     if (query.senders && query.senders.length && !query.senders.includes(record.sender)) return false;
@@ -626,6 +671,9 @@ function resolveSmartQuery(token: string, mentionTarget?: string): Partial<Local
 
 export async function searchLocalMessages(query: LocalSearchQuery, userId?: string | null): Promise<IndexedMessageMetadata[]> {
   const mentionTarget = buildMentionTarget(userId);
+  if (query.term === "smart:threads") {
+    return userId ? queryThreadCollection(userId) : [];
+  }
   let effectiveQuery: LocalSearchQuery = { ...query };
   if (effectiveQuery.term && effectiveQuery.term.startsWith("smart:")) {
     const resolved = resolveSmartQuery(effectiveQuery.term, mentionTarget);
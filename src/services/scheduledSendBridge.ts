@@ -0,0 +1,49 @@
+import { invoke } from '@tauri-apps/api/core';
+import { listen } from '@tauri-apps/api/event';
+import type { MatrixClient } from '../types';
+
+const SCHEDULED_SEND_EVENT = 'scheduled://send';
+
+interface ScheduledSendPayload {
+    id: number;
+    accountKey: string;
+    roomId: string;
+    content: Record<string, unknown>;
+    txnId: string;
+}
+
+const isTauri = () =>
+    typeof window !== 'undefined' && typeof (window as any).__TAURI_INTERNALS__ !== 'undefined';
+
+const sendScheduledMessage = async (client: MatrixClient, payload: ScheduledSendPayload): Promise<void> => {
+    try {
+        // The native transaction ID keeps a retried hand-off from posting the message twice.
+        const result = await client.sendEvent(payload.roomId, 'm.room.message' as any, payload.content as any, payload.txnId);
+        await invoke('complete_scheduled_message', { id: payload.id, eventId: result.event_id });
+    } catch (error) {
+        const message = error instanceof Error ? error.message : String(error);
+        await invoke('complete_scheduled_message', { id: payload.id, error: message }).catch(reportError => {
+            console.warn('Failed to report scheduled message result', reportError);
+        });
+    }
+};
+
+/**
+ * Sends the scheduled messages the native scheduler hands off for encrypted rooms of this
+ * account and reports each outcome back with `complete_scheduled_message`.
+ */
+export const attachScheduledSendBridge = (accountKey: string, client: MatrixClient): (() => void) => {
+    if (!isTauri()) {
+        return () => {};
+    }
+    const unlistenPromise = listen<ScheduledSendPayload>(SCHEDULED_SEND_EVENT, ({ payload }) => {
+        if (payload.accountKey !== accountKey) return;
+        void sendScheduledMessage(client, payload);
+    }).catch(error => {
+        console.warn('Failed to attach scheduled send listener', error);
+        return null;
+    });
+    return () => {
+        void unlistenPromise.then(unlisten => unlisten?.());
+    };
+};
@@ -0,0 +1,31 @@
+import { invoke } from '@tauri-apps/api/core';
+import { ClientEvent } from 'matrix-js-sdk';
+import type { MatrixClient } from '../types';
+
+const isTauri = () =>
+    typeof window !== 'undefined' && typeof (window as any).__TAURI_INTERNALS__ !== 'undefined';
+
+/**
+ * Hands the client's latest `next_batch` to the backend after every sync so the background
+ * loop (window closed) and the sync engine resume from it instead of a full initial sync.
+ */
+export const attachSyncTokenHandOff = (accountKey: string, client: MatrixClient): (() => void) => {
+    if (!isTauri()) {
+        return () => {};
+    }
+    let recorded: string | null = null;
+    const onSync = (state: string) => {
+        if (state !== 'PREPARED' && state !== 'SYNCING') return;
+        const nextBatch: string | null = (client as any).store?.getSyncToken?.() ?? null;
+        if (!nextBatch || nextBatch === recorded) return;
+        recorded = nextBatch;
+        invoke('record_sync_token', { accountKey, nextBatch }).catch(error => {
+            recorded = null;
+            console.warn('Failed to hand off sync token', error);
+        });
+    };
+    (client as any).on(ClientEvent.Sync, onSync);
+    return () => {
+        (client as any).removeListener(ClientEvent.Sync, onSync);
+    };
+};
@@ -0,0 +1,87 @@
+import { invoke } from '@tauri-apps/api/core';
+import { listen } from '@tauri-apps/api/event';
+import { ClientEvent, MatrixEvent } from 'matrix-js-sdk';
+import type { MatrixClient } from '../types';
+
+const TO_DEVICE_EVENT = 'sync://to-device';
+
+interface ToDevicePayload {
+    accountKey: string;
+    count: number;
+}
+
+const isTauri = () =>
+    typeof window !== 'undefined' && typeof (window as any).__TAURI_INTERNALS__ !== 'undefined';
+
+interface QueuedToDevice {
+    id: number;
+    event: any;
+}
+
+/**
+ * Feed to-device events the native sync (engine or background loop) received into the
+ * client's crypto, as its own sync would, then announce them with `ClientEvent.ToDeviceEvent`.
+ * The native side acknowledges them to the server, so this is the only place room keys and
+ * verification requests sent in that window reach the webview. Returns false when there is
+ * no crypto backend to take them yet.
+ */
+const processToDeviceEvents = async (client: MatrixClient, events: any[]): Promise<boolean> => {
+    const crypto: any = (client as any).getCrypto?.();
+    if (typeof crypto?.preprocessToDeviceMessages !== 'function') {
+        return false;
+    }
+    const processed = await crypto.preprocessToDeviceMessages(events);
+    for (const event of processed) {
+        (client as any).emit(ClientEvent.ToDeviceEvent, new MatrixEvent(event));
+    }
+    return true;
+};
+
+/**
+ * Drains the account's native to-device queue on attach (events queued while the window
+ * was closed), once the client's first sync is prepared (its crypto may not have been
+ * ready before) and whenever `sync://to-device` reports more. Processed events are
+ * acknowledged so the backend drops them. Drains run one at a time so Olm messages are
+ * decrypted in the order they arrived.
+ */
+export const attachToDeviceBridge = (accountKey: string, client: MatrixClient): (() => void) => {
+    if (!isTauri()) {
+        return () => {};
+    }
+    let disposed = false;
+    let pending: Promise<void> = Promise.resolve();
+    const drain = () => {
+        pending = pending
+            .then(async () => {
+                if (disposed) return;
+                const queued = (await invoke<QueuedToDevice[]>('get_queued_to_device_events', { accountKey })) ?? [];
+                if (!queued.length) return;
+                // Rows are only dropped once the crypto took them; otherwise the next drain retries.
+                if (!(await processToDeviceEvents(client, queued.map(item => item.event)))) {
+                    console.warn('No crypto backend yet; native to-device events stay queued');
+                    return;
+                }
+                await invoke('ack_to_device_events', { accountKey, upToId: queued[queued.length - 1].id });
+            })
+            .catch(error => {
+                console.warn('Failed to drain native to-device events', error);
+            });
+    };
+    drain();
+    const onSync = (state: string) => {
+        if (state === 'PREPARED') drain();
+    };
+    (client as any).on(ClientEvent.Sync, onSync);
+    const unlistenPromise = listen<ToDevicePayload>(TO_DEVICE_EVENT, ({ payload }) => {
+        if (payload.accountKey !== accountKey) return;
+        drain();
+    }).catch(error => {
+        console.warn('Failed to attach to-device listener', error);
+        return null;
+    });
+    return () => {
+        disposed = true;
+        (client as any).removeListener(ClientEvent.Sync, onSync);
+        void unlistenPromise.then(unlisten => unlisten?.());
+    };
+};